@@ -22,13 +22,14 @@ class LazyBag(dict):
 		return self[name]
 
 class NetworkInterfaceHelper():
-	def __init__(self, iface_name, fakenet_subprocess_stdout):
+	def __init__(self, iface_name, fakenet_subprocess_stdout, status):
 		self.interface = resolve_iface(iface_name)
 		# Keep one socket so we don't miss packets between sniff calls.
 		self.l2socket = self.interface.l2listen()(type = ETH_P_ALL, iface = iface_name)
 		self.fakenet_subprocess_stdout = fakenet_subprocess_stdout
 		self.stdout_poll = select.poll()
 		self.stdout_poll.register(self.fakenet_subprocess_stdout, select.POLLIN)
+		self.status = status
 		self.last_status = {}
 	
 	def assert_packets(self, count, lfilter, timeout = 5):
@@ -41,7 +42,7 @@ class NetworkInterfaceHelper():
 		return self.assert_packets(1, lfilter, timeout)[0]
 
 	def _read_status_update(self):
-		self.status = json.loads(self.fakenet_subprocess_stdout.readline())
+		self.status = read_status_line(self.fakenet_subprocess_stdout.readline(), self.status)
 
 	def assert_status(self, pred, timeout = 5):
 		deadline = datetime.now() + timedelta(seconds = timeout)
@@ -55,6 +56,28 @@ class NetworkInterfaceHelper():
 
 		assert(pred(LazyBag(self.status)))
 
+def apply_merge_patch(target, patch):
+	if not isinstance(patch, dict):
+		return patch
+	if not isinstance(target, dict):
+		target = {}
+
+	for key, value in patch.items():
+		if value is None:
+			target.pop(key, None)
+		else:
+			target[key] = apply_merge_patch(target.get(key), value)
+
+	return target
+
+def read_status_line(line, status):
+	parsed = json.loads(line)
+	assert(parsed["schema_version"] == 1)
+
+	if parsed["type"] == "delta":
+		return apply_merge_patch(status, parsed["status"])
+	return parsed["status"]
+
 @pytest.fixture
 def iface(pytestconfig, request):
 	base_dir = path.abspath(path.join(path.dirname(__file__), ".."))
@@ -69,12 +92,13 @@ def iface(pytestconfig, request):
 		text = True,
 	)
 
-	status = json.loads(fakenet_subprocess.stdout.readline())
+	status = read_status_line(fakenet_subprocess.stdout.readline(), None)
 	assert("interface" in status and "name" in status["interface"])
 
 	yield NetworkInterfaceHelper(
 		status["interface"]["name"],
 		fakenet_subprocess.stdout,
+		status,
 	)
 
 	fakenet_subprocess.kill()
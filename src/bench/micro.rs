@@ -0,0 +1,244 @@
+//! Micro benchmarks for encoding and parsing representative packets.
+//!
+//! Every case is checked against a golden encoding before it's timed, so a benchmark run doubles
+//! as a check that optimizations haven't changed the wire format.
+
+use anyhow::{bail, Result as AHResult};
+use serde::Serialize;
+use std::fmt::Debug;
+use std::hint::black_box;
+use std::time::Instant;
+
+use crate::protocols::ipv6::icmpv6;
+use crate::protocols::{ether, ipv4, ipv6};
+
+#[derive(Debug, Serialize)]
+pub struct Measurement {
+    pub name: &'static str,
+    pub encode_nanos: f64,
+    pub parse_nanos: f64,
+}
+
+struct Case<T> {
+    name: &'static str,
+    golden: &'static str,
+    value: fn() -> T,
+    encode: fn(&T) -> Vec<u8>,
+    parse: fn(&[u8]) -> AHResult<T>,
+}
+
+trait Benchmark {
+    fn name(&self) -> &'static str;
+    fn check(&self) -> AHResult<()>;
+    fn time_encode(&self, iterations: u32) -> f64;
+    fn time_parse(&self, iterations: u32) -> AHResult<f64>;
+}
+
+/// Average nanoseconds per call of `f`, after a short warmup.
+fn time(iterations: u32, mut f: impl FnMut()) -> f64 {
+    for _ in 0..iterations / 10 {
+        f();
+    }
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+
+    start.elapsed().as_nanos() as f64 / iterations as f64
+}
+
+impl<T: Debug + PartialEq> Benchmark for Case<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn check(&self) -> AHResult<()> {
+        let golden = hex::decode(self.golden)?;
+        let encoded = (self.encode)(&(self.value)());
+
+        if encoded != golden {
+            bail!(
+                "{}: encoding doesn't match golden vector, got {}",
+                self.name,
+                hex::encode(encoded)
+            );
+        }
+
+        let parsed = (self.parse)(&golden)?;
+        if parsed != (self.value)() {
+            bail!(
+                "{}: parsing golden vector gave unexpected {:?}",
+                self.name,
+                parsed
+            );
+        }
+
+        Ok(())
+    }
+
+    fn time_encode(&self, iterations: u32) -> f64 {
+        let value = (self.value)();
+
+        time(iterations, || {
+            black_box((self.encode)(black_box(&value)));
+        })
+    }
+
+    fn time_parse(&self, iterations: u32) -> AHResult<f64> {
+        let golden = hex::decode(self.golden)?;
+
+        Ok(time(iterations, || {
+            let _ = black_box((self.parse)(black_box(&golden)));
+        }))
+    }
+}
+
+fn link_local(last: u16) -> ipv6::Address {
+    ipv6::Address([0xfe80, 0, 0, 0, 0, 0, 0, last])
+}
+
+fn udp_packet() -> ipv6::Packet {
+    ipv6::Packet::builder()
+        .protocol(ipv4::ProtocolNumber::Udp)
+        .hop_limit(64)
+        .src(link_local(1))
+        .dest(link_local(2))
+        .payload(b"\xc0\x00\x00\x35\x00\x0c\x00\x00ping".to_vec())
+        .build()
+}
+
+fn mld_report() -> icmpv6::Packet {
+    icmpv6::Packet::MldV2Report(vec![icmpv6::MldV2AddressRecord::new(
+        icmpv6::Mldv2AddressRecordType::ChangeToExcludeMode,
+        "ff02::1:ff00:1".parse().unwrap(),
+    )])
+}
+
+// Built without parsing, so timings only cover the code under test.
+const ALL_MLDV2_ROUTERS: ipv6::Address = ipv6::Address([0xff02, 0, 0, 0, 0, 0, 0, 0x16]);
+const SOLICITED_TARGET: ipv6::Address =
+    ipv6::Address([0xfe80, 0, 0, 0, 0x396d, 0xf664, 0x97e1, 0x64f3]);
+
+fn icmpv6_pseudo_header() -> icmpv6::PseudoHeader {
+    icmpv6::PseudoHeader {
+        src: link_local(1),
+        dest: ALL_MLDV2_ROUTERS,
+        length: 0,
+    }
+}
+
+fn icmpv6_parse(input: &[u8]) -> AHResult<icmpv6::Packet> {
+    icmpv6::packet(
+        input,
+        icmpv6::PseudoHeader {
+            length: input.len() as u32,
+            ..icmpv6_pseudo_header()
+        },
+    )
+}
+
+fn cases() -> Vec<Box<dyn Benchmark>> {
+    vec![
+        Box::new(Case {
+            name: "ether_frame",
+            golden: "33330000001602000000000186dd600000000014ff40fe800000000000000000000000000001fe80000000000000000000000000000200000000000000000000000000000000000000",
+            value: || ether::Frame {
+                dest: "33:33:00:00:00:16".parse().unwrap(),
+                src: "02:00:00:00:00:01".parse().unwrap(),
+                ethertype: ether::Type::Ipv6,
+                payload: hex::decode("600000000014ff40fe800000000000000000000000000001fe80000000000000000000000000000200000000000000000000000000000000000000").unwrap(),
+                received: None,
+            },
+            encode: ether::Frame::encode,
+            parse: ether::frame,
+        }),
+        Box::new(Case {
+            name: "ipv6_udp",
+            golden: "60000000000c1140fe800000000000000000000000000001fe800000000000000000000000000002c0000035000c000070696e67",
+            value: udp_packet,
+            encode: ipv6::Packet::encode,
+            parse: ipv6::packet,
+        }),
+        Box::new(Case {
+            name: "ipv6_hop_by_hop",
+            golden: "6000000000100001fe800000000000000000000000000001ff0200000000000000000000000000163a000502000001008f00000000000000",
+            value: || {
+                ipv6::Packet::builder()
+                    .protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+                    .hop_limit(1)
+                    .src(link_local(1))
+                    .dest(ALL_MLDV2_ROUTERS)
+                    .extension_header(ipv6::ExtensionHeader::HopByHopOptions(vec![
+                        ipv6::HopByHopOption::RouterAlert(ipv6::RouterAlertType::Mld),
+                    ]))
+                    .payload(hex::decode("8f00000000000000").unwrap())
+                    .build()
+            },
+            encode: ipv6::Packet::encode,
+            parse: ipv6::packet,
+        }),
+        Box::new(Case {
+            name: "icmpv6_neighbor_solicitation",
+            golden: "870003ca00000000fe80000000000000396df66497e164f30e01d8d14717f0a0",
+            value: || icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+                dest: SOLICITED_TARGET,
+                options: vec![icmpv6::NeighborSolicitationOption::Nonce(
+                    hex::decode("d8d14717f0a0").unwrap(),
+                )],
+            }),
+            encode: |packet| {
+                packet.encode(icmpv6::PseudoHeader {
+                    src: ipv6::Address::default(),
+                    dest: SOLICITED_TARGET,
+                    length: 0,
+                })
+            },
+            parse: |input| {
+                icmpv6::packet(
+                    input,
+                    icmpv6::PseudoHeader {
+                        src: ipv6::Address::default(),
+                        dest: SOLICITED_TARGET,
+                        length: input.len() as u32,
+                    },
+                )
+            },
+        }),
+        Box::new(Case {
+            name: "icmpv6_mldv2_report",
+            golden: "8f0071070000000104000000ff0200000000000000000001ff000001",
+            value: mld_report,
+            encode: |packet| packet.encode(icmpv6_pseudo_header()),
+            parse: icmpv6_parse,
+        }),
+    ]
+}
+
+/// Check and time every case, averaging over `iterations` runs.
+pub fn run(iterations: u32) -> AHResult<Vec<Measurement>> {
+    cases()
+        .iter()
+        .map(|case| {
+            case.check()?;
+
+            Ok(Measurement {
+                name: case.name(),
+                encode_nanos: case.time_encode(iterations),
+                parse_nanos: case.time_parse(iterations)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_vectors_match() {
+        for case in cases() {
+            case.check().unwrap();
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Self-benchmark: pushes UDP packets between two in-memory interfaces through the full
+//! encode/dispatch/parse path and measures throughput and latency.
+
+use anyhow::Result as AHResult;
+use crossbeam::channel;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+use crate::protocols::utils::KeyedDispatcher;
+use crate::protocols::{ether, ipv4, ipv6, neighbor, udp};
+
+pub mod micro;
+
+/// Packets allowed in flight before waiting for one to arrive.
+const WINDOW: usize = 256;
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+const PORT: u16 = 9;
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub packets_sent: usize,
+    pub packets_received: usize,
+    pub elapsed_secs: f64,
+    pub packets_per_second: f64,
+    /// Ethernet frame bits per second, excluding padding.
+    pub bits_per_second: f64,
+    pub latency_micros: Percentiles,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Percentiles {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Percentiles {
+    fn new(mut samples: Vec<u64>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_unstable();
+
+        let at = |q: f64| samples[((samples.len() - 1) as f64 * q).round() as usize];
+
+        Self {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: at(1.0),
+        }
+    }
+}
+
+/// Send `packets` UDP packets carrying `payload_len` bytes each from one in-memory node to
+/// another.
+pub fn run(packets: usize, payload_len: usize) -> AHResult<Report> {
+    let payload_len = payload_len.max(8);
+    let a_ether: ether::Address = "02:00:00:00:00:0a".parse()?;
+    let b_ether: ether::Address = "02:00:00:00:00:0b".parse()?;
+    let a_address: ipv6::Address = "fe80::a".parse()?;
+    let b_address: ipv6::Address = "fe80::b".parse()?;
+
+    let (mut a_eth, mut b_eth) = ether::MemoryInterface::pair(a_ether, b_ether);
+    // Neither address is really the node's own, so neither would answer a solicitation.
+    let a_neighbors = neighbor::Table::new("bench-a");
+    a_neighbors.learn(b_address.into(), b_ether, true);
+    let mut a_ipv6 = ipv6::Server::new(&mut a_eth, a_neighbors)?;
+    let mut b_ipv6 = ipv6::Server::new(&mut b_eth, neighbor::Table::new("bench-b"))?;
+
+    let (received_sender, received) = channel::unbounded();
+    b_ipv6.register(
+        ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Udp),
+        received_sender,
+    );
+    a_ipv6.start();
+    b_ipv6.start();
+
+    let writer = a_ipv6.writer();
+    let frame_bits = (14 + 40 + 8 + payload_len) as f64 * 8.0;
+    let mut sent_at = HashMap::new();
+    let mut latencies = Vec::with_capacity(packets);
+    let start = Instant::now();
+
+    while latencies.len() < packets {
+        while sent_at.len() < WINDOW && latencies.len() + sent_at.len() < packets {
+            let sequence = (latencies.len() + sent_at.len()) as u64;
+            let mut payload = vec![0u8; payload_len];
+            payload[..8].copy_from_slice(&sequence.to_be_bytes());
+
+            let pseudo_header = ipv6::PseudoHeader {
+                src: a_address,
+                dest: b_address,
+                length: 0,
+            };
+            writer.send(
+                ipv6::Packet::builder()
+                    .protocol(ipv4::ProtocolNumber::Udp)
+                    .hop_limit(64)
+                    .src(a_address)
+                    .dest(b_address)
+                    .payload(
+                        udp::Packet {
+                            src_port: PORT,
+                            dest_port: PORT,
+                            payload,
+                        }
+                        .encode(pseudo_header),
+                    )
+                    .build(),
+            )?;
+            sent_at.insert(sequence, Instant::now());
+        }
+
+        let packet = match received.recv_timeout(RECEIVE_TIMEOUT) {
+            Ok(packet) => packet,
+            // Anything still outstanding is lost.
+            Err(_) => break,
+        };
+        let datagram = udp::packet(
+            &packet.payload,
+            ipv6::PseudoHeader {
+                src: packet.src,
+                dest: packet.dest,
+                length: 0,
+            },
+        )?;
+        let sequence = u64::from_be_bytes(datagram.payload[..8].try_into().unwrap());
+
+        if let Some(sent) = sent_at.remove(&sequence) {
+            latencies.push(sent.elapsed().as_micros() as u64);
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let packets_received = latencies.len();
+
+    Ok(Report {
+        packets_sent: packets_received + sent_at.len(),
+        packets_received,
+        elapsed_secs: elapsed,
+        packets_per_second: packets_received as f64 / elapsed,
+        bits_per_second: packets_received as f64 * frame_bits / elapsed,
+        latency_micros: Percentiles::new(latencies),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_pick_nearest_rank() {
+        let percentiles = Percentiles::new((1..=100).rev().collect());
+
+        assert_eq!(percentiles.p50, 51);
+        assert_eq!(percentiles.p99, 99);
+        assert_eq!(percentiles.max, 100);
+    }
+
+    #[test]
+    fn run_delivers_every_packet() {
+        let report = run(500, 64).unwrap();
+
+        assert_eq!(report.packets_sent, 500);
+        assert_eq!(report.packets_received, 500);
+    }
+}
@@ -0,0 +1,268 @@
+//! The command line: a subcommand and its arguments, with flags that apply to all of them.
+
+use anyhow::{anyhow, bail, Result as AHResult};
+
+use crate::log::Level;
+use crate::overrides::Override;
+
+pub const USAGE: &str = "\
+usage: fakenet [FLAGS] [run] NETWORK_CONFIG
+       fakenet [FLAGS] send NETWORK_CONFIG FRAME_SPEC_JSON
+       fakenet [FLAGS] generate NETWORK_CONFIG STREAMS_CONFIG
+       fakenet [FLAGS] wake NETWORK_CONFIG TARGET_ETHER_ADDRESS
+       fakenet [FLAGS] ping NETWORK_CONFIG IPV6_ADDRESS[%ZONE] [COUNT]
+       fakenet [FLAGS] dump NETWORK_CONFIG [FILTER]
+       fakenet [FLAGS] validate-config NETWORK_CONFIG
+       fakenet [FLAGS] topology TOPOLOGY_CONFIG
+       fakenet [FLAGS] bench [PACKETS PAYLOAD_LEN]
+       fakenet [FLAGS] bench micro [ITERATIONS]
+
+flags:
+       --capture PATH        write the node's traffic to a pcap file
+       --log-level LEVEL     error, warn (the default), info or debug
+       --set PATH=VALUE      override a config value, e.g. node.ipv4_address=192.0.2.5; also
+                             taken from variables like FAKENET_NODE__IPV4_ADDRESS
+       --status-socket PATH  send status updates to clients of a Unix socket, not stdout";
+
+/// Flags that apply to every subcommand.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub capture: Option<String>,
+    pub log_level: Level,
+    /// Applied after any from the environment, so these win.
+    pub overrides: Vec<Override>,
+    pub status_socket: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            capture: None,
+            log_level: Level::Warn,
+            overrides: Vec::new(),
+            status_socket: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Run {
+        config: String,
+    },
+    /// Inject frames described in a spec file, then exit.
+    Send {
+        config: String,
+        spec: String,
+    },
+    /// Send generated streams of traffic for load testing.
+    Generate {
+        config: String,
+        streams: String,
+    },
+    /// Broadcast a Wake-on-LAN magic packet.
+    Wake {
+        config: String,
+        target: String,
+    },
+    Ping {
+        config: String,
+        dest: String,
+        count: usize,
+    },
+    /// Print frames seen on the node's interface.
+    Dump {
+        config: String,
+        filter: String,
+    },
+    ValidateConfig {
+        config: String,
+    },
+    /// Run several nodes joined by switches.
+    Topology {
+        config: String,
+    },
+    Bench {
+        packets: usize,
+        payload_len: usize,
+    },
+    BenchMicro {
+        iterations: u32,
+    },
+    Help,
+}
+
+const DEFAULT_PING_COUNT: usize = 4;
+
+/// Parse arguments, not including the program name; flags can come anywhere before `--`.
+pub fn parse(args: impl IntoIterator<Item = String>) -> AHResult<(Options, Command)> {
+    let mut options = Options::default();
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            positional.extend(&mut args);
+            break;
+        }
+
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => {
+                positional.push(arg);
+                continue;
+            }
+        };
+        if flag == "help" {
+            return Ok((options, Command::Help));
+        }
+
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, value.to_string()),
+            None => (
+                flag,
+                args.next()
+                    .ok_or_else(|| anyhow!("--{} needs a value", flag))?,
+            ),
+        };
+        match name {
+            "capture" => options.capture = Some(value),
+            "log-level" => options.log_level = value.parse()?,
+            "set" => options.overrides.push(value.parse()?),
+            "status-socket" => options.status_socket = Some(value),
+            _ => bail!("unknown flag --{}", name),
+        }
+    }
+
+    let positional: Vec<_> = positional.iter().map(String::as_str).collect();
+    let command = match positional[..] {
+        ["run", config] => Command::Run {
+            config: config.to_string(),
+        },
+        ["send", config, spec] => Command::Send {
+            config: config.to_string(),
+            spec: spec.to_string(),
+        },
+        ["generate", config, streams] => Command::Generate {
+            config: config.to_string(),
+            streams: streams.to_string(),
+        },
+        ["wake", config, target] => Command::Wake {
+            config: config.to_string(),
+            target: target.to_string(),
+        },
+        ["ping", config, dest] => Command::Ping {
+            config: config.to_string(),
+            dest: dest.to_string(),
+            count: DEFAULT_PING_COUNT,
+        },
+        ["ping", config, dest, count] => Command::Ping {
+            config: config.to_string(),
+            dest: dest.to_string(),
+            count: count.parse()?,
+        },
+        ["dump", config, ref filter @ ..] => Command::Dump {
+            config: config.to_string(),
+            filter: filter.join(" "),
+        },
+        ["validate-config", config] => Command::ValidateConfig {
+            config: config.to_string(),
+        },
+        ["topology", config] => Command::Topology {
+            config: config.to_string(),
+        },
+        ["bench"] => Command::Bench {
+            packets: 10000,
+            payload_len: 64,
+        },
+        ["bench", "micro"] => Command::BenchMicro { iterations: 100000 },
+        ["bench", "micro", iterations] => Command::BenchMicro {
+            iterations: iterations.parse()?,
+        },
+        ["bench", packets, payload_len] => Command::Bench {
+            packets: packets.parse()?,
+            payload_len: payload_len.parse()?,
+        },
+        ["help"] => Command::Help,
+        // From before there were subcommands.
+        [config] => Command::Run {
+            config: config.to_string(),
+        },
+        _ => bail!("unrecognized arguments: {}", positional.join(" ")),
+    };
+
+    Ok((options, command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> AHResult<(Options, Command)> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn a_bare_config_is_run() {
+        let run = Command::Run {
+            config: "node.toml".to_string(),
+        };
+
+        assert_eq!(
+            parse_str("node.toml").unwrap(),
+            (Options::default(), run.clone())
+        );
+        assert_eq!(parse_str("run node.toml").unwrap().1, run);
+        assert!(parse_str("").is_err());
+    }
+
+    #[test]
+    fn flags_go_anywhere() {
+        let (options, command) =
+            parse_str("--log-level debug dump node.toml --capture=out.pcap --set node.tap_queues=2 udp and port 53")
+                .unwrap();
+
+        assert_eq!(
+            options,
+            Options {
+                capture: Some("out.pcap".to_string()),
+                log_level: Level::Debug,
+                overrides: vec!["node.tap_queues=2".parse().unwrap()],
+                status_socket: None,
+            }
+        );
+        assert_eq!(
+            command,
+            Command::Dump {
+                config: "node.toml".to_string(),
+                filter: "udp and port 53".to_string(),
+            }
+        );
+
+        assert!(parse_str("--log-level loud node.toml").is_err());
+        assert!(parse_str("node.toml --status-socket").is_err());
+    }
+
+    #[test]
+    fn subcommands_take_defaults() {
+        assert_eq!(
+            parse_str("ping node.toml 2001:db8::1").unwrap().1,
+            Command::Ping {
+                config: "node.toml".to_string(),
+                dest: "2001:db8::1".to_string(),
+                count: DEFAULT_PING_COUNT,
+            }
+        );
+        assert_eq!(
+            parse_str("bench").unwrap().1,
+            Command::Bench {
+                packets: 10000,
+                payload_len: 64,
+            }
+        );
+        assert_eq!(
+            parse_str("bench micro").unwrap().1,
+            Command::BenchMicro { iterations: 100000 }
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! The node's own idea of the time, which can be set off from the host's and run fast or slow, for
+//! the timestamps it puts in what it sends.
+
+use anyhow::{bail, Result as AHResult};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub struct Config {
+    /// Seconds to set the clock ahead of the host's, or behind when negative.
+    #[serde(default)]
+    pub offset: f64,
+    /// Parts per million the clock runs fast by, or slow by when negative.
+    #[serde(default)]
+    pub drift_ppm: f64,
+}
+
+/// Furthest the clock can be set from the host's, about 31 years, so it stays after the epoch.
+const MAX_OFFSET: f64 = 1e9;
+
+impl Config {
+    pub fn validate(&self) -> AHResult<()> {
+        if !self.offset.is_finite() || self.offset.abs() > MAX_OFFSET {
+            bail!(
+                "offset must be a number of seconds, at most {} either way, not {}",
+                MAX_OFFSET,
+                self.offset
+            );
+        }
+        if !self.drift_ppm.is_finite() || self.drift_ppm.abs() >= 1e6 {
+            bail!(
+                "drift_ppm must be less than a million parts per million either way, not {}",
+                self.drift_ppm
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Clock {
+    config: Config,
+    /// Host time the clock was set, which drift accumulates from.
+    set_at: SystemTime,
+}
+
+impl Clock {
+    /// Set the clock at host time `set_at`.
+    pub fn new(config: Config, set_at: SystemTime) -> Self {
+        Self { config, set_at }
+    }
+
+    /// Our time when the host's is `host`.
+    pub fn at(&self, host: SystemTime) -> SystemTime {
+        let elapsed = host.duration_since(self.set_at).unwrap_or_default();
+        let skew = self.config.offset + elapsed.as_secs_f64() * self.config.drift_ppm / 1e6;
+
+        if skew >= 0.0 {
+            host + Duration::from_secs_f64(skew)
+        } else {
+            host - Duration::from_secs_f64(-skew)
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(Config::default(), SystemTime::UNIX_EPOCH)
+    }
+}
+
+lazy_static! {
+    static ref CLOCK: RwLock<Clock> = RwLock::new(Clock::default());
+}
+
+/// Set the node's clock to `config`, starting from the host's time now.
+pub fn set(config: Config) {
+    *CLOCK.write().unwrap() = Clock::new(config, SystemTime::now());
+}
+
+/// The node's time now.
+pub fn now() -> SystemTime {
+    at(SystemTime::now())
+}
+
+/// The node's time when the host's was `host`, e.g. when a frame was received.
+pub fn at(host: SystemTime) -> SystemTime {
+    CLOCK.read().unwrap().at(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_and_drift_are_applied() {
+        let set_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let clock = Clock::new(
+            Config {
+                offset: -2.5,
+                drift_ppm: 100.0,
+            },
+            set_at,
+        );
+
+        assert_eq!(clock.at(set_at), set_at - Duration::from_millis(2500));
+        // A hundred parts per million over ten thousand seconds gains a second.
+        let later = set_at + Duration::from_secs(10_000);
+        assert_eq!(clock.at(later), later - Duration::from_millis(1500));
+
+        assert_eq!(Clock::default().at(set_at), set_at);
+    }
+
+    #[test]
+    fn unusable_settings_are_rejected() {
+        Config {
+            offset: -2.5,
+            drift_ppm: 100.0,
+        }
+        .validate()
+        .unwrap();
+
+        for (offset, drift_ppm) in [
+            (f64::NAN, 0.0),
+            (f64::INFINITY, 0.0),
+            (-1e10, 0.0),
+            (0.0, f64::NAN),
+            (0.0, -1e6),
+        ] {
+            assert!(Config { offset, drift_ppm }.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,1319 @@
+//! The node's config file: what to open, which protocols to run and how, checked as a whole so
+//! every problem is reported at once.
+
+use anyhow::{anyhow, bail, Context, Result as AHResult};
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddrV6;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use crate::overrides::Override;
+use crate::{
+    clock, config_check, device, identity, netns, personality, privileges, protocols, resources,
+    services, socket_device, status, supervisor, watchdog,
+};
+
+#[derive(Deserialize)]
+pub struct Network {
+    /// Path to listen for control commands on.
+    pub control_socket: Option<String>,
+    /// Address to serve Prometheus metrics over HTTP on, like `127.0.0.1:9464`.
+    pub metrics_address: Option<String>,
+    pub node: Node,
+    /// Attach the node to a software bridge with these other ports, instead of its own device.
+    pub bridge: Option<Bridge>,
+    /// Attach the node to a veth pair leading into a new network namespace, instead of a tap.
+    pub netns: Option<netns::Config>,
+    /// Give up root and capabilities once the device is open, before handling any frames.
+    pub privileges: Option<privileges::Config>,
+    #[serde(default)]
+    pub watchdog: watchdog::Config,
+    /// Caps on what the node can pile up, reported in status when exceeded.
+    #[serde(default)]
+    pub limits: resources::Config,
+    /// How protocol threads that panic are restarted.
+    #[serde(default)]
+    pub restart: supervisor::Policy,
+    /// Threads shared by the protocols that run as actors.
+    #[serde(default = "default_actor_threads")]
+    pub actor_threads: usize,
+    /// How often and how fully status is written out.
+    #[serde(default)]
+    pub status: status::OutputPolicy,
+}
+
+fn default_actor_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get().min(4))
+}
+
+#[derive(Deserialize)]
+pub struct Bridge {
+    /// Seconds before forgetting where an address was seen.
+    #[serde(default = "default_bridge_aging")]
+    pub aging: f64,
+    pub ports: Vec<BridgePort>,
+    /// Port that gets a copy of all traffic, e.g. a tap to capture on.
+    pub mirror: Option<BridgePort>,
+    /// Flood multicast to every port, like a switch without MLD and IGMP snooping.
+    #[serde(default)]
+    pub flood_multicast: bool,
+}
+
+fn default_bridge_aging() -> f64 {
+    protocols::bridge::DEFAULT_AGING.as_secs_f64()
+}
+
+#[derive(Deserialize)]
+pub struct BridgePort {
+    #[serde(flatten)]
+    pub backend: BridgeBackend,
+    /// Seconds to hold back frames sent out this port.
+    #[serde(default)]
+    pub delay: f64,
+    /// Chance, from 0 to 1, of dropping each frame sent out this port.
+    #[serde(default)]
+    pub loss: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BridgeBackend {
+    Tap,
+    Socket(Socket),
+}
+
+impl BridgePort {
+    pub fn open(&self) -> AHResult<(Box<dyn device::Device>, protocols::bridge::Impairments)> {
+        if !(0.0..=1.0).contains(&self.loss) {
+            bail!(
+                "bridge port loss must be between 0 and 1, not {}",
+                self.loss
+            );
+        }
+
+        let device = match &self.backend {
+            BridgeBackend::Tap => device::open(1)?,
+            BridgeBackend::Socket(socket) => socket.open()?,
+        };
+
+        Ok((
+            device,
+            protocols::bridge::Impairments {
+                delay: Duration::from_secs_f64(self.delay),
+                loss: self.loss,
+            },
+        ))
+    }
+}
+
+impl Bridge {
+    /// Open the bridge's ports and bring them up, returning it unstarted along with the node's
+    /// connection to it.
+    pub fn open(&self) -> AHResult<(protocols::bridge::Bridge, Box<dyn device::Device>)> {
+        let mut bridge = protocols::bridge::Bridge::new(Duration::from_secs_f64(self.aging));
+        bridge.set_snooping(!self.flood_multicast);
+        let (node_end, bridge_end) = UnixStream::pair()?;
+        bridge.add_port(
+            Box::new(socket_device::SocketDevice::from_stream(
+                "bridge", bridge_end,
+            )),
+            Default::default(),
+        );
+
+        for port in &self.ports {
+            let (device, impairments) = port.open()?;
+            bridge.add_port(device, impairments);
+        }
+        if let Some(mirror) = &self.mirror {
+            let (device, impairments) = mirror.open()?;
+            bridge.add_mirror(device, impairments);
+        }
+        bridge.up()?;
+
+        Ok((
+            bridge,
+            Box::new(socket_device::SocketDevice::from_stream("bridge", node_end)),
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Node {
+    /// Generated randomly (under `ether_oui`, if given) when not set.
+    pub ether_address: Option<String>,
+    pub ether_oui: Option<String>,
+    /// Bare or fully qualified; made up from the ether address when not set.
+    pub hostname: Option<String>,
+    /// Kind of machine to pass for.
+    #[serde(default)]
+    pub personality: personality::Personality,
+    /// Of everything sent besides NDP, until a router advertises another; the personality's by
+    /// default.
+    pub hop_limit: Option<u8>,
+    /// Of IPv4 packets sent; the hop limit by default.
+    pub ttl: Option<u8>,
+    /// How far off the host's the clock used for timestamps is, and how fast it drifts.
+    #[serde(default)]
+    pub clock: clock::Config,
+    /// Exchange frames over a Unix socket instead of a tap.
+    pub socket: Option<Socket>,
+    /// Queues to open on the tap, each read by its own thread.
+    #[serde(default = "default_tap_queues")]
+    pub tap_queues: usize,
+    pub ipv4_address: Option<String>,
+    #[serde(default)]
+    pub admin_state: protocols::ether::AdminState,
+    /// Also set the kernel side of the tap down while the node is down.
+    #[serde(default)]
+    pub set_link_down: bool,
+    /// Leave the tap up when exiting, instead of restoring it to down.
+    #[serde(default)]
+    pub keep_up_on_exit: bool,
+    /// Give the kernel side of the tap the node's ether address, instead of its own random one.
+    #[serde(default)]
+    pub set_kernel_ether_address: bool,
+    #[serde(default)]
+    pub own_frames: OwnFrames,
+    #[serde(default)]
+    pub power_schedule: PowerSchedule,
+    pub shaping: Option<protocols::shaping::ShapingConfig>,
+    #[serde(default)]
+    pub misbehavior: Misbehavior,
+    #[serde(default)]
+    pub ipv6_addresses: Vec<Ipv6Address>,
+    /// How the interface identifiers of link-local addresses are picked, on the node's interface
+    /// and its tunnels.
+    #[serde(default)]
+    pub interface_ids: protocols::ipv6::InterfaceIds,
+    /// Limits on the rate of ICMP errors sent, to each peer and overall.
+    #[serde(default)]
+    pub icmp_rate_limit: protocols::icmp_limit::Config,
+    /// How ARP requests and neighbor solicitations for particular addresses are answered, like
+    /// late, to only some requesters or not every time.
+    #[serde(default)]
+    pub answer_policies: Vec<protocols::answer_policy::Policy>,
+    /// Don't announce IPv6 addresses with unsolicited neighbor advertisements once they're
+    /// configured or the interface comes back up, leaving neighbors' caches to go stale.
+    #[serde(default)]
+    pub quiet_neighbor_caches: bool,
+    #[serde(default)]
+    pub services: Vec<services::Service>,
+    #[serde(default)]
+    pub ports: Ports,
+    /// Limits on connections waiting on each TCP listener, and what happens past them.
+    #[serde(default)]
+    pub tcp_backlog: protocols::tcp::Backlog,
+    /// Keep-alives and timeouts for TCP connections that go quiet.
+    #[serde(default)]
+    pub tcp_idle: protocols::tcp::Idle,
+    /// SCTP ports to accept associations on, acknowledging and dropping whatever is sent.
+    #[serde(default, deserialize_with = "protocols::names::deserialize_ports")]
+    pub sctp_ports: Vec<u16>,
+    pub flow_export: Option<FlowExport>,
+    /// Record every host seen on the segment in status, passively.
+    pub inventory: Option<protocols::inventory::Config>,
+    pub dns: Option<Dns>,
+    pub pppoe: Option<Pppoe>,
+    #[serde(default)]
+    pub tunnels: Vec<Tunnel>,
+    pub wireguard: Option<WireGuard>,
+    /// Exchanges with servers to record from traffic seen, for replay services.
+    #[serde(default)]
+    pub recordings: Vec<Recording>,
+}
+
+fn default_tap_queues() -> usize {
+    1
+}
+
+/// Unix stream socket with a single peer, such as QEMU's `-netdev stream`.
+#[derive(Deserialize)]
+pub struct Socket {
+    pub path: String,
+    /// Wait for the peer to connect, rather than connecting to it.
+    #[serde(default)]
+    pub listen: bool,
+}
+
+impl Socket {
+    pub fn open(&self) -> AHResult<Box<dyn device::Device>> {
+        Ok(Box::new(if self.listen {
+            socket_device::SocketDevice::listen(&self.path)?
+        } else {
+            socket_device::SocketDevice::connect(&self.path)?
+        }))
+    }
+}
+
+/// Frames received from the node's own ether address, as a bridge or replayed capture can loop
+/// back, or from `addresses`.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OwnFrames {
+    #[serde(default)]
+    pub action: protocols::ether::OwnFrames,
+    /// Other addresses the node's frames come from, like a host it's standing in for.
+    #[serde(default)]
+    pub addresses: Vec<protocols::ether::Address>,
+}
+
+/// How ports without a service respond to scans.
+#[derive(Default, Deserialize)]
+pub struct Ports {
+    #[serde(default)]
+    pub tcp: protocols::port_policy::PortPolicy,
+    #[serde(default)]
+    pub udp: protocols::port_policy::PortPolicy,
+    #[serde(default)]
+    pub sctp: protocols::port_policy::PortPolicy,
+}
+
+#[derive(Deserialize)]
+pub struct Ipv6Address {
+    /// An address, or with `eui64`, a /64 prefix in `address/length` form.
+    pub address: String,
+    /// Complete the prefix with the modified EUI-64 of the interface's ether address, as
+    /// classic stateless autoconfiguration does.
+    #[serde(default)]
+    pub eui64: bool,
+    pub preferred_lifetime: Option<u64>,
+    pub valid_lifetime: Option<u64>,
+}
+
+impl Ipv6Address {
+    pub fn eui64_prefix(&self) -> AHResult<protocols::ipv6::Address> {
+        let (prefix, length) = parse_prefix(&self.address)?;
+        if length != 64 {
+            bail!("EUI-64 addresses need a /64 prefix, not /{}", length);
+        }
+
+        Ok(prefix)
+    }
+
+    /// EUI-64 addresses are left out of `parsed`, as the ether address might be random.
+    fn check(
+        &self,
+        checker: &mut config_check::Checker,
+        path: String,
+        parsed: &mut Vec<(String, String, protocols::ipv6::Address)>,
+    ) {
+        if self.eui64 {
+            checker.check(&path, &self.address, self.eui64_prefix());
+        } else {
+            check_parse(checker, path, &self.address, parsed);
+        }
+    }
+
+    /// The address on an interface with `ether_address`.
+    pub fn address(
+        &self,
+        ether_address: protocols::ether::Address,
+    ) -> AHResult<protocols::ipv6::Address> {
+        if !self.eui64 {
+            return self.address.parse();
+        }
+
+        Ok(protocols::ipv6::Address::from_eui64(
+            ether_address,
+            self.eui64_prefix()?,
+        ))
+    }
+
+    pub fn lifetimes(&self) -> protocols::ipv6::Lifetimes {
+        protocols::ipv6::Lifetimes {
+            preferred: self.preferred_lifetime.map(Duration::from_secs),
+            valid: self.valid_lifetime.map(Duration::from_secs),
+        }
+    }
+}
+
+/// Another interface, exchanging frames with a remote endpoint through a tunnel.
+#[derive(Deserialize)]
+pub struct Tunnel {
+    #[serde(flatten)]
+    pub encapsulation: TunnelEncapsulation,
+    /// Outer addresses, the local one being one of the node's own. A zone on the remote one must
+    /// be the node's interface, which encapsulated frames go out of. Tunnels over IPv4 have IPv4
+    /// addresses, the local one being `ipv4_address` and the remote one on-link.
+    pub local: String,
+    pub remote: String,
+    /// Random when not set.
+    pub ether_address: Option<String>,
+    #[serde(default)]
+    pub ipv6_addresses: Vec<Ipv6Address>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TunnelEncapsulation {
+    Gre {
+        key: Option<u32>,
+    },
+    Vxlan {
+        vni: u32,
+        #[serde(
+            default = "default_vxlan_port",
+            deserialize_with = "protocols::names::deserialize_port"
+        )]
+        port: u16,
+    },
+    #[serde(rename = "6in4")]
+    SixInFour,
+    Ipip,
+}
+
+impl TunnelEncapsulation {
+    pub fn protocol(&self) -> protocols::tunnel::Encapsulation {
+        match *self {
+            TunnelEncapsulation::Gre { key } => protocols::tunnel::Encapsulation::Gre { key },
+            TunnelEncapsulation::Vxlan { vni, port } => {
+                protocols::tunnel::Encapsulation::Vxlan { vni, port }
+            }
+            TunnelEncapsulation::SixInFour => protocols::tunnel::Encapsulation::SixInFour,
+            TunnelEncapsulation::Ipip => protocols::tunnel::Encapsulation::Ipip,
+        }
+    }
+}
+
+fn default_vxlan_port() -> u16 {
+    protocols::tunnel::VXLAN_PORT
+}
+
+impl Tunnel {
+    /// The outer addresses, IPv4-mapped for tunnels over IPv4.
+    pub fn endpoints(
+        &self,
+        if_name: &str,
+    ) -> AHResult<(protocols::ipv6::Address, protocols::ipv6::Address)> {
+        if self.encapsulation.protocol().is_over_ipv4() {
+            return Ok((
+                self.local
+                    .parse::<protocols::ipv4::Address>()?
+                    .to_ipv6_mapped(),
+                self.remote
+                    .parse::<protocols::ipv4::Address>()?
+                    .to_ipv6_mapped(),
+            ));
+        }
+
+        Ok((
+            self.local.parse()?,
+            self.remote
+                .parse::<protocols::ipv6::ScopedAddress>()?
+                .on(if_name)?,
+        ))
+    }
+
+    pub fn start(
+        &self,
+        name: &str,
+        if_name: &str,
+        interface_ids: &protocols::ipv6::InterfaceIds,
+        underlay: &mut protocols::tunnel::Underlay<impl protocols::ether::Server>,
+    ) -> AHResult<(protocols::tunnel::TunnelInterface, protocols::ipv6::Server)> {
+        let hw_address = match &self.ether_address {
+            Some(address) => address.parse()?,
+            None => protocols::ether::Address::random_local(&mut rand::thread_rng()),
+        };
+        let (local, remote) = self.endpoints(if_name)?;
+
+        let mut interface = protocols::tunnel::TunnelInterface::new(
+            hw_address,
+            protocols::tunnel::Tunnel {
+                local,
+                remote,
+                encapsulation: self.encapsulation.protocol(),
+            },
+            underlay,
+        )?;
+
+        let mut inner_server =
+            protocols::ipv6::Server::new(&mut interface, protocols::neighbor::Table::new(name))?;
+        for ipv6_address in &self.ipv6_addresses {
+            inner_server.add_address(ipv6_address.address(hw_address)?, ipv6_address.lifetimes());
+        }
+        inner_server.set_interface_ids(interface_ids.clone());
+        inner_server.set_hop_limit(underlay.ipv6_server.hop_limit().get());
+        inner_server.start();
+        interface.start();
+
+        Ok((interface, inner_server))
+    }
+}
+
+impl Node {
+    pub fn hop_limit(&self) -> u8 {
+        self.hop_limit
+            .unwrap_or_else(|| self.personality.hop_limit())
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.ttl.unwrap_or_else(|| self.hop_limit())
+    }
+
+    pub fn ether_address(&self) -> AHResult<protocols::ether::Address> {
+        let mut rng = rand::thread_rng();
+
+        Ok(match (&self.ether_address, &self.ether_oui) {
+            (Some(address), _) => address.parse()?,
+            (None, Some(oui)) => {
+                let prefix: protocols::ether::Address = format!("{}:00:00:00", oui)
+                    .parse()
+                    .with_context(|| format!("invalid OUI {}", oui))?;
+
+                protocols::ether::Address::random_with_oui(
+                    prefix.0[..3].try_into().unwrap(),
+                    &mut rng,
+                )
+            }
+            (None, None) => protocols::ether::Address::random_local(&mut rng),
+        })
+    }
+}
+
+/// Deliberately hostile behavior; see `protocols::ipv6::Misbehavior`.
+#[derive(Default, Deserialize)]
+pub struct Misbehavior {
+    /// Extra IPv4 addresses to answer ARP requests for.
+    #[serde(default)]
+    pub arp_claim: Vec<String>,
+    #[serde(default)]
+    pub arp_answer_all: bool,
+    #[serde(default)]
+    pub claim_dad: bool,
+    /// IPv4 or IPv6 prefixes to answer ARP requests or neighbor solicitations for every address
+    /// in, as if a host were at each one.
+    #[serde(default)]
+    pub sweep: Vec<String>,
+    pub rogue_router: Option<RogueRouter>,
+    pub tcp_interference: Option<TcpInterference>,
+}
+
+/// Interrupting TCP connections once they've carried enough data.
+#[derive(Deserialize)]
+pub struct TcpInterference {
+    pub interruption: protocols::tcp::Interruption,
+    /// Only connections with either end on one of these ports; any when empty.
+    #[serde(default, deserialize_with = "protocols::names::deserialize_ports")]
+    pub ports: Vec<u16>,
+    #[serde(default)]
+    pub after_bytes: u64,
+    /// Also interrupt connections between other nodes that we see, such as over a bridge.
+    #[serde(default)]
+    pub observed: bool,
+}
+
+impl TcpInterference {
+    pub fn policy(&self) -> protocols::tcp::interference::Policy {
+        protocols::tcp::interference::Policy {
+            interruption: self.interruption,
+            ports: self.ports.clone(),
+            after_bytes: self.after_bytes,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RogueRouter {
+    #[serde(default = "default_rogue_router_interval")]
+    pub interval: f64,
+    #[serde(default = "default_rogue_router_lifetime")]
+    pub router_lifetime: u16,
+    /// In `address/length` form.
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+}
+
+fn default_rogue_router_interval() -> f64 {
+    10.0
+}
+
+fn default_rogue_router_lifetime() -> u16 {
+    1800
+}
+
+/// An IPv6 prefix in `address/length` form.
+fn parse_prefix(prefix: &str) -> AHResult<(protocols::ipv6::Address, u8)> {
+    let (address, length) = prefix
+        .split_once('/')
+        .ok_or_else(|| anyhow!("prefix {} is missing a length", prefix))?;
+    let length = length.parse()?;
+    if length > 128 {
+        bail!("prefix length {} is longer than an address", length);
+    }
+
+    Ok((address.parse()?, length))
+}
+
+impl Misbehavior {
+    pub fn ipv6(&self) -> AHResult<protocols::ipv6::Misbehavior> {
+        let rogue_router = match &self.rogue_router {
+            Some(r) => Some(protocols::ipv6::RogueRouter {
+                interval: Duration::from_secs_f64(r.interval),
+                router_lifetime: r.router_lifetime,
+                prefixes: r
+                    .prefixes
+                    .iter()
+                    .map(|p| parse_prefix(p))
+                    .collect::<AHResult<_>>()?,
+            }),
+            None => None,
+        };
+
+        Ok(protocols::ipv6::Misbehavior {
+            claim_dad: self.claim_dad,
+            rogue_router,
+            sweep: self
+                .sweep
+                .iter()
+                .filter(|p| p.contains(':'))
+                .map(|p| parse_prefix(p))
+                .collect::<AHResult<_>>()?,
+        })
+    }
+
+    pub fn ipv4_sweep(&self) -> AHResult<Vec<(protocols::ipv4::Address, u8)>> {
+        self.sweep
+            .iter()
+            .filter(|p| !p.contains(':'))
+            .map(|p| protocols::ipv4::parse_prefix(p))
+            .collect()
+    }
+}
+
+/// NetFlow/IPFIX export of sampled traffic on the node's interface.
+#[derive(Deserialize)]
+pub struct FlowExport {
+    /// In `[address]:port` form.
+    pub collector: String,
+    /// Address to send exports from.
+    pub source: protocols::ipv6::Address,
+    #[serde(default)]
+    pub format: protocols::flow_export::Format,
+    /// Sample one in this many frames.
+    #[serde(default = "default_flow_export_sample_rate")]
+    pub sample_rate: u32,
+    /// Only frames matching this filter expression are sampled.
+    #[serde(default)]
+    pub filter: protocols::filter::Filter,
+    /// Seconds between exports.
+    #[serde(default = "default_flow_export_interval")]
+    pub interval: f64,
+}
+
+fn default_flow_export_sample_rate() -> u32 {
+    1
+}
+
+fn default_flow_export_interval() -> f64 {
+    10.0
+}
+
+impl FlowExport {
+    pub fn config(&self) -> AHResult<protocols::flow_export::Config> {
+        let collector: SocketAddrV6 = self
+            .collector
+            .parse()
+            .with_context(|| format!("invalid collector {}", self.collector))?;
+
+        Ok(protocols::flow_export::Config {
+            collector: (*collector.ip()).into(),
+            collector_port: collector.port(),
+            source: self.source,
+            format: self.format,
+            sample_rate: self.sample_rate,
+            filter: self.filter.clone(),
+            interval: Duration::from_secs_f64(self.interval),
+        })
+    }
+}
+
+/// PPPoE access concentrator, handing clients addresses from a pool.
+#[derive(Deserialize)]
+pub struct Pppoe {
+    #[serde(default = "default_pppoe_ac_name")]
+    pub ac_name: String,
+    /// Only clients asking for this service, or any, are answered.
+    #[serde(default)]
+    pub service_name: String,
+    pub local_address: String,
+    pub pool_start: String,
+    #[serde(default = "default_pppoe_pool_size")]
+    pub pool_size: u32,
+    #[serde(default)]
+    pub dns_servers: Vec<protocols::ipv4::Address>,
+}
+
+fn default_pppoe_ac_name() -> String {
+    "fakenet".to_string()
+}
+
+fn default_pppoe_pool_size() -> u32 {
+    100
+}
+
+impl Pppoe {
+    pub fn config(&self) -> AHResult<protocols::pppoe::Config> {
+        Ok(protocols::pppoe::Config {
+            ac_name: self.ac_name.clone(),
+            service_name: self.service_name.clone(),
+            local_address: self.local_address.parse()?,
+            pool_start: self.pool_start.parse()?,
+            pool_size: self.pool_size,
+            dns_servers: self.dns_servers.clone(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Recording {
+    pub protocol: services::replay::Protocol,
+    #[serde(deserialize_with = "protocols::names::deserialize_port")]
+    pub port: u16,
+    pub path: String,
+}
+
+/// WireGuard handshake responder, for testing initiators; no data is passed.
+#[derive(Deserialize)]
+pub struct WireGuard {
+    /// Keys are base64, as in `wg` configuration.
+    pub private_key: String,
+    pub preshared_key: Option<String>,
+    /// Initiators' public keys to accept; any are when empty.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    #[serde(
+        default = "default_wireguard_port",
+        deserialize_with = "protocols::names::deserialize_port"
+    )]
+    pub port: u16,
+    #[serde(default)]
+    pub behavior: protocols::wireguard::Behavior,
+}
+
+fn default_wireguard_port() -> u16 {
+    protocols::wireguard::PORT
+}
+
+fn parse_wireguard_key(key: &str) -> AHResult<protocols::wireguard::Key> {
+    base64::decode(key)?
+        .try_into()
+        .map_err(|_| anyhow!("wireguard key {} isn't 32 bytes", key))
+}
+
+impl WireGuard {
+    pub fn config(&self) -> AHResult<protocols::wireguard::Config> {
+        Ok(protocols::wireguard::Config {
+            private_key: parse_wireguard_key(&self.private_key)?,
+            preshared_key: match &self.preshared_key {
+                Some(key) => parse_wireguard_key(key)?,
+                None => [0; 32],
+            },
+            peers: self
+                .peers
+                .iter()
+                .map(|key| parse_wireguard_key(key))
+                .collect::<AHResult<_>>()?,
+            behavior: self.behavior,
+        })
+    }
+}
+
+/// Resolver for the node's own lookups, made through the control socket.
+#[derive(Deserialize)]
+pub struct Dns {
+    /// Address to send queries from.
+    pub source: protocols::ipv6::Address,
+    /// Tried before any servers learned from router advertisements. Link-local ones can have the
+    /// node's interface as their zone, like `fe80::53%eth0`.
+    #[serde(default)]
+    pub servers: Vec<protocols::ipv6::ScopedAddress>,
+    /// Seconds to wait for each answer.
+    #[serde(default = "default_dns_timeout")]
+    pub timeout: f64,
+    #[serde(default = "default_dns_attempts")]
+    pub attempts: u32,
+}
+
+fn default_dns_timeout() -> f64 {
+    2.0
+}
+
+fn default_dns_attempts() -> u32 {
+    3
+}
+
+impl Dns {
+    /// Servers are asked through `if_name`, so any zones they have must be that.
+    pub fn config(&self, if_name: &str) -> AHResult<protocols::dns::Config> {
+        Ok(protocols::dns::Config {
+            source: self.source,
+            servers: self
+                .servers
+                .iter()
+                .map(|s| s.on(if_name))
+                .collect::<AHResult<_>>()?,
+            timeout: Duration::from_secs_f64(self.timeout),
+            attempts: self.attempts,
+        })
+    }
+}
+
+/// Administrative state changes to apply after startup, e.g. to simulate a flapping link.
+#[derive(Default, Deserialize)]
+pub struct PowerSchedule {
+    #[serde(default)]
+    pub steps: Vec<PowerStep>,
+    /// Start over from the first step after the last one.
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PowerStep {
+    /// Seconds since the previous step.
+    pub after: f64,
+    pub state: protocols::ether::AdminState,
+}
+
+pub fn read_file(path: &str) -> AHResult<String> {
+    let mut contents = String::new();
+
+    if path == "-" {
+        std::io::stdin().read_to_string(&mut contents)?;
+    } else {
+        File::open(path)
+            .with_context(|| format!("failed to open {}", path))?
+            .read_to_string(&mut contents)?;
+    }
+
+    Ok(contents)
+}
+
+/// Parse the value of the setting at `path`, keeping it along with where it came from.
+fn check_parse<T: FromStr<Err = anyhow::Error>>(
+    checker: &mut config_check::Checker,
+    path: String,
+    value: &str,
+    parsed: &mut Vec<(String, String, T)>,
+) {
+    if let Some(result) = checker.check(&path, value, value.parse()) {
+        parsed.push((path, value.to_string(), result));
+    }
+}
+
+fn ipv6_bits(address: protocols::ipv6::Address) -> u128 {
+    address
+        .0
+        .iter()
+        .fold(0, |bits, &segment| bits << 16 | segment as u128)
+}
+
+fn ipv4_bits(address: protocols::ipv4::Address) -> u32 {
+    u32::from_be_bytes(address.0)
+}
+
+impl Network {
+    /// Check everything that can be without opening anything, so all problems are found at once.
+    fn check(&self, checker: &mut config_check::Checker) {
+        let node = &self.node;
+
+        if let Some(netns) = &self.netns {
+            checker.check("netns.name", &netns.name, netns.validate());
+            if self.bridge.is_some() || node.socket.is_some() {
+                checker.report(
+                    "netns",
+                    "",
+                    "a network namespace can't be used along with a bridge or socket",
+                );
+            }
+        }
+        checker.check("node.clock", "", node.clock.validate());
+        if self.privileges.is_some() {
+            if node.set_link_down {
+                checker.report(
+                    "node.set_link_down",
+                    "true",
+                    "set_link_down needs the privileges that are dropped",
+                );
+            }
+            if self.netns.as_ref().is_some_and(|netns| !netns.keep) {
+                checker.report(
+                    "netns.keep",
+                    "",
+                    "a network namespace can't be torn down after dropping privileges; set keep",
+                );
+            }
+        }
+
+        if self.watchdog.timeout <= watchdog::INTERVAL.as_secs_f64() {
+            checker.report(
+                "watchdog.timeout",
+                &self.watchdog.timeout.to_string(),
+                format!(
+                    "timeout must be longer than the {:?} between heartbeats",
+                    watchdog::INTERVAL
+                ),
+            );
+        }
+
+        if !self.status.max_rate.is_finite() || self.status.max_rate < 0.0 {
+            checker.report(
+                "status.max_rate",
+                &self.status.max_rate.to_string(),
+                "must be a number of lines a second, 0 or more",
+            );
+        }
+
+        if !self.restart.healthy_after.is_finite() || self.restart.healthy_after < 0.0 {
+            checker.report(
+                "restart.healthy_after",
+                &self.restart.healthy_after.to_string(),
+                "must be a number of seconds, at least 0",
+            );
+        }
+
+        for (field, backoff) in &[
+            ("initial_backoff", self.restart.initial_backoff),
+            ("max_backoff", self.restart.max_backoff),
+        ] {
+            if !backoff.is_finite() || *backoff < 0.0 {
+                checker.report(
+                    &format!("restart.{}", field),
+                    &backoff.to_string(),
+                    "backoff must be a number of seconds, at least 0",
+                );
+            }
+        }
+
+        if let Some(bridge) = &self.bridge {
+            if !bridge.aging.is_finite() || bridge.aging <= 0.0 {
+                checker.report(
+                    "bridge.aging",
+                    &bridge.aging.to_string(),
+                    "aging must be a number of seconds, more than 0",
+                );
+            }
+
+            let ports = bridge.ports.iter().enumerate();
+            let mirror = bridge
+                .mirror
+                .iter()
+                .map(|port| ("mirror".to_string(), port));
+            for (path, port) in ports
+                .map(|(i, port)| (format!("ports[{}]", i), port))
+                .chain(mirror)
+            {
+                if !(0.0..=1.0).contains(&port.loss) {
+                    checker.report(
+                        &format!("bridge.{}.loss", path),
+                        &port.loss.to_string(),
+                        "loss must be between 0 and 1",
+                    );
+                }
+                if !port.delay.is_finite() || port.delay < 0.0 {
+                    checker.report(
+                        &format!("bridge.{}.delay", path),
+                        &port.delay.to_string(),
+                        "delay must be a number of seconds, at least 0",
+                    );
+                }
+            }
+        }
+
+        self.check_addresses(checker);
+        self.check_ports(checker);
+    }
+
+    fn check_addresses(&self, checker: &mut config_check::Checker) {
+        let node = &self.node;
+
+        let mut ether_addresses: Vec<(_, _, protocols::ether::Address)> = Vec::new();
+        match (&node.ether_address, &node.ether_oui) {
+            (Some(address), _) => check_parse(
+                checker,
+                "node.ether_address".to_string(),
+                address,
+                &mut ether_addresses,
+            ),
+            (None, Some(oui)) => {
+                checker.check("node.ether_oui", oui, node.ether_address());
+            }
+            (None, None) => {}
+        }
+        if let Some(hostname) = &node.hostname {
+            checker.check("node.hostname", hostname, identity::Identity::new(hostname));
+        }
+
+        if let protocols::ipv6::InterfaceIds::Stable { secret_key } = &node.interface_ids {
+            if secret_key.is_empty() {
+                checker.report(
+                    "node.interface_ids.secret_key",
+                    "",
+                    "stable interface identifiers need a secret key",
+                );
+            }
+        }
+
+        if let Some(shaping) = &node.shaping {
+            checker.check("node.shaping", "", shaping.validate());
+        }
+
+        let limits = &node.icmp_rate_limit;
+        for (name, rate) in [("per_peer", limits.per_peer), ("global", limits.global)] {
+            if !rate.per_second.is_finite() || rate.per_second <= 0.0 {
+                checker.report(
+                    &format!("node.icmp_rate_limit.{}.per_second", name),
+                    &rate.per_second.to_string(),
+                    "rate must be a number of errors a second, more than 0",
+                );
+            }
+            if !rate.burst.is_finite() || rate.burst < 1.0 {
+                checker.report(
+                    &format!("node.icmp_rate_limit.{}.burst", name),
+                    &rate.burst.to_string(),
+                    "burst must be at least 1 error",
+                );
+            }
+        }
+
+        for (i, step) in node.power_schedule.steps.iter().enumerate() {
+            if !step.after.is_finite() || step.after < 0.0 {
+                checker.report(
+                    &format!("node.power_schedule.steps[{}].after", i),
+                    &step.after.to_string(),
+                    "delay must be a number of seconds, 0 or more",
+                );
+            }
+        }
+
+        let mut ipv6_addresses: Vec<(_, _, protocols::ipv6::Address)> = Vec::new();
+        for (i, address) in node.ipv6_addresses.iter().enumerate() {
+            address.check(
+                checker,
+                format!("node.ipv6_addresses[{}].address", i),
+                &mut ipv6_addresses,
+            );
+        }
+        for (i, tunnel) in node.tunnels.iter().enumerate() {
+            let path = format!("node.tunnels[{}]", i);
+            if let Some(address) = &tunnel.ether_address {
+                check_parse(
+                    checker,
+                    format!("{}.ether_address", path),
+                    address,
+                    &mut ether_addresses,
+                );
+            }
+            if tunnel.encapsulation.protocol().is_over_ipv4() {
+                let local = tunnel.local.parse::<protocols::ipv4::Address>();
+                if local.is_ok() && node.ipv4_address.as_ref() != Some(&tunnel.local) {
+                    checker.report(
+                        &format!("{}.local", path),
+                        &tunnel.local,
+                        "tunnels over ipv4 must be from the node's ipv4_address",
+                    );
+                }
+                checker.check(&format!("{}.local", path), &tunnel.local, local);
+                checker.check(
+                    &format!("{}.remote", path),
+                    &tunnel.remote,
+                    tunnel.remote.parse::<protocols::ipv4::Address>(),
+                );
+            } else {
+                checker.check(
+                    &format!("{}.local", path),
+                    &tunnel.local,
+                    tunnel.local.parse::<protocols::ipv6::Address>(),
+                );
+                checker.check(
+                    &format!("{}.remote", path),
+                    &tunnel.remote,
+                    tunnel.remote.parse::<protocols::ipv6::ScopedAddress>(),
+                );
+            }
+            for (j, address) in tunnel.ipv6_addresses.iter().enumerate() {
+                address.check(
+                    checker,
+                    format!("{}.ipv6_addresses[{}].address", path, j),
+                    &mut ipv6_addresses,
+                );
+            }
+        }
+        checker.unique("ether address", ether_addresses);
+        checker.unique("address", ipv6_addresses);
+
+        let mut ipv4_addresses: Vec<(_, _, protocols::ipv4::Address)> = Vec::new();
+        if let Some(address) = &node.ipv4_address {
+            check_parse(
+                checker,
+                "node.ipv4_address".to_string(),
+                address,
+                &mut ipv4_addresses,
+            );
+        }
+        for (i, address) in node.misbehavior.arp_claim.iter().enumerate() {
+            check_parse(
+                checker,
+                format!("node.misbehavior.arp_claim[{}]", i),
+                address,
+                &mut ipv4_addresses,
+            );
+        }
+
+        if let Some(pppoe) = &node.pppoe {
+            let mut pool_start: Vec<(_, _, protocols::ipv4::Address)> = Vec::new();
+            check_parse(
+                checker,
+                "node.pppoe.pool_start".to_string(),
+                &pppoe.pool_start,
+                &mut pool_start,
+            );
+            let mut local_address: Vec<(_, _, protocols::ipv4::Address)> = Vec::new();
+            check_parse(
+                checker,
+                "node.pppoe.local_address".to_string(),
+                &pppoe.local_address,
+                &mut local_address,
+            );
+
+            if let Some((_, _, start)) = pool_start.first() {
+                let pool =
+                    ipv4_bits(*start) as u64..ipv4_bits(*start) as u64 + pppoe.pool_size as u64;
+                for (path, value, address) in local_address.iter().chain(&ipv4_addresses) {
+                    if pool.contains(&(ipv4_bits(*address) as u64)) {
+                        checker.report(
+                            "node.pppoe.pool_start",
+                            &pppoe.pool_start,
+                            format!("pool includes {}, used by {}", value, path),
+                        );
+                    }
+                }
+            }
+        }
+        checker.unique("address", ipv4_addresses);
+
+        for (i, prefix) in node.misbehavior.sweep.iter().enumerate() {
+            let path = format!("node.misbehavior.sweep[{}]", i);
+            if prefix.contains(':') {
+                checker.check(&path, prefix, parse_prefix(prefix));
+            } else {
+                checker.check(&path, prefix, protocols::ipv4::parse_prefix(prefix));
+            }
+        }
+
+        if let Some(rogue_router) = &node.misbehavior.rogue_router {
+            let mut prefixes: Vec<(String, &String, (u128, u8))> = Vec::new();
+            for (i, prefix) in rogue_router.prefixes.iter().enumerate() {
+                let path = format!("node.misbehavior.rogue_router.prefixes[{}]", i);
+                if let Some((address, length)) = checker.check(&path, prefix, parse_prefix(prefix))
+                {
+                    let bits = ipv6_bits(address);
+                    if let Some((other_path, _, _)) =
+                        prefixes.iter().find(|(_, _, (other, other_len))| {
+                            config_check::prefixes_overlap(bits, length, *other, *other_len)
+                        })
+                    {
+                        checker.report(
+                            &path,
+                            prefix,
+                            format!("prefix {} overlaps {}", prefix, other_path),
+                        );
+                    }
+                    prefixes.push((path, prefix, (bits, length)));
+                }
+            }
+
+            if !rogue_router.interval.is_finite() || rogue_router.interval <= 0.0 {
+                checker.report(
+                    "node.misbehavior.rogue_router.interval",
+                    &rogue_router.interval.to_string(),
+                    "interval must be a number of seconds, more than 0",
+                );
+            }
+        }
+
+        if let Some(flow_export) = &node.flow_export {
+            checker.check(
+                "node.flow_export.collector",
+                &flow_export.collector,
+                flow_export
+                    .collector
+                    .parse::<SocketAddrV6>()
+                    .map_err(anyhow::Error::from),
+            );
+            if !flow_export.interval.is_finite() || flow_export.interval <= 0.0 {
+                checker.report(
+                    "node.flow_export.interval",
+                    &flow_export.interval.to_string(),
+                    "interval must be a number of seconds, more than 0",
+                );
+            }
+        }
+
+        if let Some(wireguard) = &node.wireguard {
+            let keys = std::iter::once(("private_key".to_string(), &wireguard.private_key))
+                .chain(
+                    wireguard
+                        .preshared_key
+                        .iter()
+                        .map(|key| ("preshared_key".to_string(), key)),
+                )
+                .chain(
+                    wireguard
+                        .peers
+                        .iter()
+                        .enumerate()
+                        .map(|(i, key)| (format!("peers[{}]", i), key)),
+                );
+            for (path, key) in keys {
+                checker.check(
+                    &format!("node.wireguard.{}", path),
+                    key,
+                    parse_wireguard_key(key),
+                );
+            }
+        }
+    }
+
+    fn check_ports(&self, checker: &mut config_check::Checker) {
+        let node = &self.node;
+        let mut ports = Vec::new();
+
+        for (i, service) in node.services.iter().enumerate() {
+            let path = format!("node.services[{}]", i);
+            if let Some(dscp) = service.dscp.filter(|dscp| *dscp > 63) {
+                checker.report(
+                    &format!("{}.dscp", path),
+                    &dscp.to_string(),
+                    "dscp must be below 64",
+                );
+            }
+
+            let (protocol, port) = match &service.config {
+                services::Config::Banner(banner) => ("tcp", banner.port),
+                services::Config::Replay(replay) => {
+                    let recorded = node.recordings.iter().any(|recording| {
+                        recording.path == replay.path
+                            && recording.protocol == replay.protocol
+                            && recording.port == replay.port
+                    });
+                    if !recorded && !Path::new(&replay.path).exists() {
+                        checker.report(
+                            &format!("{}.path", path),
+                            &replay.path,
+                            format!(
+                                "no recording at {}, and none is made by node.recordings",
+                                replay.path
+                            ),
+                        );
+                    }
+
+                    match replay.protocol {
+                        services::replay::Protocol::Tcp => ("tcp", replay.port),
+                        services::replay::Protocol::Udp => ("udp", replay.port),
+                    }
+                }
+                services::Config::Twamp(twamp) => {
+                    if !twamp.delay.is_finite() || twamp.delay < 0.0 {
+                        checker.report(
+                            &format!("{}.delay", path),
+                            &twamp.delay.to_string(),
+                            "delay must be a number of seconds, at least 0",
+                        );
+                    }
+
+                    ("udp", twamp.port)
+                }
+            };
+            if let Some(dedup) = &service.dedup {
+                if protocol != "udp" {
+                    checker.report(
+                        &format!("{}.dedup", path),
+                        protocol,
+                        "dedup only applies to udp services",
+                    );
+                }
+                if !dedup.window.is_finite() || dedup.window <= 0.0 {
+                    checker.report(
+                        &format!("{}.dedup.window", path),
+                        &dedup.window.to_string(),
+                        "window must be a number of seconds, more than 0",
+                    );
+                }
+            }
+            ports.push((format!("{}.port", path), port.to_string(), (protocol, port)));
+        }
+        if let Some(wireguard) = &node.wireguard {
+            ports.push((
+                "node.wireguard.port".to_string(),
+                wireguard.port.to_string(),
+                ("udp", wireguard.port),
+            ));
+        }
+
+        checker.unique("port", ports);
+    }
+}
+
+/// Read and check a config, failing with every problem found.
+pub fn read_network(path: &str, overrides: &[Override]) -> AHResult<Network> {
+    let source = read_file(path)?;
+    let (network, problems) = check_network(&source, overrides);
+
+    match network {
+        Some(network) if problems.is_empty() => Ok(network),
+        _ => bail!(
+            "invalid config {}:\n{}",
+            path,
+            problems
+                .iter()
+                .map(|problem| format!("  {}", problem))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    }
+}
+
+fn parse_network(source: &str, overrides: &[Override]) -> Result<Network, config_check::Problem> {
+    if overrides.is_empty() {
+        return toml::from_str(source).map_err(|e| config_check::Problem::from_toml(&e));
+    }
+
+    let mut config: toml::Value =
+        toml::from_str(source).map_err(|e| config_check::Problem::from_toml(&e))?;
+    for o in overrides {
+        o.apply(&mut config).map_err(|e| config_check::Problem {
+            path: o.path.join("."),
+            message: format!("can't override: {}", e),
+            line: None,
+            column: None,
+        })?;
+    }
+
+    // Overridden values aren't in the source, so there's nowhere to point to for these errors.
+    config
+        .try_into()
+        .map_err(|e| config_check::Problem::from_toml(&e))
+}
+
+pub fn check_network(
+    source: &str,
+    overrides: &[Override],
+) -> (Option<Network>, Vec<config_check::Problem>) {
+    let network = match parse_network(source, overrides) {
+        Ok(network) => network,
+        Err(problem) => return (None, vec![problem]),
+    };
+
+    let mut checker = config_check::Checker::new(source);
+    network.check(&mut checker);
+
+    (Some(network), checker.into_problems())
+}
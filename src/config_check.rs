@@ -0,0 +1,235 @@
+//! Finding everything wrong with a config file at once, with where each problem is in it, rather
+//! than stopping at the first thing startup trips over.
+
+use anyhow::Result as AHResult;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Problem {
+    /// Dotted path to the setting, e.g. `node.tunnels[1].local`; empty when it isn't known.
+    pub path: String,
+    pub message: String,
+    /// Both 1-based; missing when the setting couldn't be found in the source.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl Problem {
+    /// The file didn't parse, or didn't have the expected shape.
+    pub fn from_toml(error: &toml::de::Error) -> Self {
+        let (line, column) = match error.line_col() {
+            Some((line, column)) => (Some(line + 1), Some(column + 1)),
+            None => (None, None),
+        };
+
+        Self {
+            path: String::new(),
+            message: error.to_string(),
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "line {}, column {}: ", line, column)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+
+        f.write_str(&self.message)
+    }
+}
+
+/// Collects problems with settings in `source`, finding each one by its value.
+pub struct Checker<'a> {
+    source: &'a str,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            problems: Vec::new(),
+        }
+    }
+
+    /// Offset of the `nth` place `value` appears as a string or bare value.
+    fn find(&self, value: &str, nth: usize) -> Option<usize> {
+        if value.is_empty() {
+            return None;
+        }
+
+        let candidates = [
+            (format!("\"{}\"", value), 0),
+            (format!("'{}'", value), 0),
+            (format!("= {}", value), 2),
+        ];
+        let mut offsets: Vec<_> = candidates
+            .iter()
+            .flat_map(|(needle, skip)| {
+                self.source
+                    .match_indices(needle.as_str())
+                    .map(move |(offset, _)| offset + skip)
+            })
+            .collect();
+        offsets.sort_unstable();
+
+        offsets.get(nth).copied()
+    }
+
+    fn report_nth(&mut self, path: &str, value: &str, nth: usize, message: String) {
+        let (line, column) = match self.find(value, nth) {
+            Some(offset) => {
+                let before = &self.source[..offset];
+                let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+                (
+                    Some(before.matches('\n').count() + 1),
+                    Some(before[line_start..].chars().count() + 1),
+                )
+            }
+            None => (None, None),
+        };
+
+        self.problems.push(Problem {
+            path: path.to_string(),
+            message,
+            line,
+            column,
+        });
+    }
+
+    /// Note a problem with the setting at `path`, whose value is `value`.
+    pub fn report(&mut self, path: &str, value: &str, message: impl fmt::Display) {
+        self.report_nth(path, value, 0, message.to_string());
+    }
+
+    /// Report `result`'s error, if any, against the setting at `path`.
+    pub fn check<T>(&mut self, path: &str, value: &str, result: AHResult<T>) -> Option<T> {
+        result
+            .map_err(|e| self.report(path, value, format!("{:#}", e)))
+            .ok()
+    }
+
+    /// Report every setting whose key was already used by an earlier one, given as `(path, value,
+    /// key)`.
+    pub fn unique<K: Eq + Hash>(&mut self, what: &str, settings: Vec<(String, String, K)>) {
+        let mut first_paths: HashMap<&K, &str> = HashMap::new();
+        let mut times_seen: HashMap<&str, usize> = HashMap::new();
+        let mut duplicates = Vec::new();
+
+        for (path, value, key) in &settings {
+            let nth = times_seen.entry(value).or_default();
+            match first_paths.get(key) {
+                Some(first_path) => duplicates.push((
+                    path,
+                    value,
+                    *nth,
+                    format!("{} {} is already used by {}", what, value, first_path),
+                )),
+                None => {
+                    first_paths.insert(key, path);
+                }
+            }
+            *nth += 1;
+        }
+
+        for (path, value, nth, message) in duplicates {
+            self.report_nth(path, value, nth, message);
+        }
+    }
+
+    pub fn into_problems(self) -> Vec<Problem> {
+        self.problems
+    }
+}
+
+/// Whether two IPv6 prefixes, as integers with their lengths, have any addresses in common.
+pub fn prefixes_overlap(a: u128, a_len: u8, b: u128, b_len: u8) -> bool {
+    let len = a_len.min(b_len).min(128);
+    let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+
+    a & mask == b & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "[node]
+ipv4_address = \"192.0.2.1\"
+ipv6_addresses = [
+    { address = \"2001:db8::1\" },
+    { address = '2001:db8::1' },
+]
+";
+
+    #[test]
+    fn problems_point_at_values() {
+        let mut checker = Checker::new(SOURCE);
+        checker.report("node.ipv4_address", "192.0.2.1", "unreachable");
+        checker.report("node.ether_address", "", "missing");
+        checker.unique(
+            "address",
+            (0..2)
+                .map(|i| {
+                    (
+                        format!("node.ipv6_addresses[{}].address", i),
+                        "2001:db8::1".to_string(),
+                        1,
+                    )
+                })
+                .collect(),
+        );
+
+        let problems = checker.into_problems();
+        assert_eq!(
+            problems
+                .iter()
+                .map(|p| (p.line, p.column))
+                .collect::<Vec<_>>(),
+            [(Some(2), Some(16)), (None, None), (Some(5), Some(17))]
+        );
+        assert_eq!(
+            problems[2].to_string(),
+            "line 5, column 17: node.ipv6_addresses[1].address: address 2001:db8::1 is already \
+             used by node.ipv6_addresses[0].address"
+        );
+    }
+
+    #[test]
+    fn toml_errors_are_one_based() {
+        let error = toml::from_str::<toml::Value>("[node]\nipv4_address = \n").unwrap_err();
+        let problem = Problem::from_toml(&error);
+
+        assert_eq!(problem.line, Some(2));
+        assert!(problem.column.is_some());
+    }
+
+    #[test]
+    fn prefixes_overlap_up_to_the_shorter_length() {
+        let documentation = 0x2001_0db8_u128 << 96;
+
+        assert!(prefixes_overlap(
+            documentation,
+            32,
+            documentation | 1 << 64,
+            64
+        ));
+        assert!(!prefixes_overlap(
+            documentation,
+            64,
+            documentation | 1 << 64,
+            64
+        ));
+        assert!(prefixes_overlap(documentation, 0, 0, 128));
+    }
+}
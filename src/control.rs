@@ -0,0 +1,129 @@
+//! Line-based control socket: each line is a command, answered with one line of JSON.
+
+use anyhow::{anyhow, Result as AHResult};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+
+use crate::{debug, warn};
+
+type Handler = Box<dyn Fn(&[&str]) -> AHResult<serde_json::Value> + Send + Sync>;
+
+#[derive(Default)]
+pub struct Server {
+    handlers: HashMap<String, Handler>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a command, which may be several words long; any words after it are passed to the
+    /// handler as arguments.
+    pub fn add(
+        &mut self,
+        command: impl Into<String>,
+        handler: impl Fn(&[&str]) -> AHResult<serde_json::Value> + Send + Sync + 'static,
+    ) {
+        self.handlers.insert(command.into(), Box::new(handler));
+    }
+
+    fn handle(&self, line: &str) -> serde_json::Value {
+        let words: Vec<_> = line.split_whitespace().collect();
+
+        let result = (1..=words.len())
+            .rev()
+            .find_map(|i| {
+                self.handlers
+                    .get(&words[..i].join(" "))
+                    .map(|handler| handler(&words[i..]))
+            })
+            .unwrap_or_else(|| Err(anyhow!("unknown command: {}", line.trim())));
+
+        match result {
+            Ok(value) => json!({ "result": value }),
+            Err(e) => json!({ "error": e.to_string() }),
+        }
+    }
+
+    fn serve(&self, stream: UnixStream) -> AHResult<()> {
+        let mut writer = stream.try_clone()?;
+
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+            debug!("control command: {}", line.trim());
+
+            serde_json::to_writer(&mut writer, &self.handle(&line))?;
+            writeln!(writer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Listen on a Unix socket at `path`, replacing any stale socket left there.
+    pub fn start(self, path: &str) -> AHResult<()> {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        let server = Arc::new(self);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let server = Arc::clone(&server);
+
+                match stream {
+                    Ok(stream) => {
+                        thread::spawn(move || {
+                            if let Err(e) = server.serve(stream) {
+                                warn!("control connection failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("accepting control connection failed: {}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> Server {
+        let mut server = Server::new();
+        server.add("show", |_| Ok(json!("everything")));
+        server.add("show neighbors", |args| Ok(json!(args)));
+
+        server
+    }
+
+    #[test]
+    fn handle_picks_longest_command() {
+        assert_eq!(
+            server().handle("show neighbors tap0"),
+            json!({ "result": ["tap0"] })
+        );
+        assert_eq!(
+            server().handle("show routes"),
+            json!({ "result": "everything" })
+        );
+    }
+
+    #[test]
+    fn handle_reports_unknown_commands() {
+        assert_eq!(
+            server().handle("reboot"),
+            json!({ "error": "unknown command: reboot" })
+        );
+    }
+}
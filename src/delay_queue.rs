@@ -2,10 +2,18 @@ use crossbeam::channel;
 use std::collections::BTreeMap;
 use std::time::{Duration, Instant};
 
+use crate::resources;
+
 pub struct DelayQueue<T> {
     items: BTreeMap<Instant, T>,
 }
 
+impl<T> Default for DelayQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> DelayQueue<T> {
     pub fn new() -> Self {
         Self {
@@ -14,24 +22,25 @@ impl<T> DelayQueue<T> {
     }
 
     pub fn push_at(&mut self, t: Instant, i: T) {
-        self.items.insert(t, i);
+        if self.items.insert(t, i).is_none() {
+            resources::TIMERS.add(1);
+        }
     }
 
     pub fn push_after(&mut self, d: Duration, i: T) {
         self.push_at(Instant::now() + d, i);
     }
 
-    pub fn pop_at(&mut self, t: Instant) -> Option<T>
-    where
-        T: Default,
-    {
-        self.items.remove(&t)
+    pub fn pop_at(&mut self, t: Instant) -> Option<T> {
+        let item = self.items.remove(&t);
+        if item.is_some() {
+            resources::TIMERS.remove(1);
+        }
+
+        item
     }
 
-    pub fn pop(&mut self) -> Option<T>
-    where
-        T: Default,
-    {
+    pub fn pop(&mut self) -> Option<T> {
         let first_key = match self.items.keys().next() {
             Some(k) => *k,
             None => return None,
@@ -48,6 +57,12 @@ impl<T> DelayQueue<T> {
     }
 }
 
+impl<T> Drop for DelayQueue<T> {
+    fn drop(&mut self) {
+        resources::TIMERS.remove(self.items.len());
+    }
+}
+
 #[macro_export]
 macro_rules! select_queues_internal {
     ( ($($munched:tt)*) (recv_queue($r:expr) -> $x:pat => $handler:block, $($rest:tt)*) ) => {
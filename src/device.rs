@@ -0,0 +1,55 @@
+//! Layer 2 devices that `ether::TapInterface` exchanges frames with, one backend per platform.
+
+use anyhow::Result as AHResult;
+use std::fs::File;
+use std::os::unix::io::RawFd;
+use std::time::SystemTime;
+
+/// Largest frame read or written, without a frame check sequence.
+pub const FRAME_SIZE: usize = 1514;
+
+pub trait Device: Send + Sync {
+    fn up(&mut self) -> AHResult<()>;
+    fn down(&mut self) -> AHResult<()>;
+
+    /// Name of the interface the host sees.
+    fn if_name(&self) -> AHResult<String>;
+    fn if_hwaddr(&self) -> AHResult<[u8; 6]>;
+    /// Set the host side's hardware address, which can only be done while it's down.
+    fn set_if_hwaddr(&mut self, address: [u8; 6]) -> AHResult<()>;
+
+    /// Read once, calling `f` with each frame read and when it arrived; some backends deliver
+    /// several at a time. The time is the kernel's where it records one, and otherwise taken
+    /// straight after the read.
+    fn read_frames(&mut self, f: &mut dyn FnMut(&[u8], SystemTime)) -> AHResult<()>;
+    fn write(&mut self, buf: &[u8]) -> AHResult<()>;
+    /// Readable when `read_frames` won't block.
+    fn rawfd(&self) -> RawFd;
+
+    /// Handles for reading from the queues after the first, each of which needs its own reader.
+    fn extra_queues(&self) -> AHResult<Vec<File>> {
+        Ok(Vec::new())
+    }
+
+    /// Whether to restore the host side when dropped, so nothing is left configured on it.
+    fn set_down_on_drop(&mut self, down_on_drop: bool);
+    fn down_on_drop(&self) -> bool;
+}
+
+/// Open this platform's device, with `queues` queues where that's supported.
+#[cfg(target_os = "linux")]
+pub fn open(queues: usize) -> AHResult<Box<dyn Device>> {
+    Ok(Box::new(crate::tap_device::TapDevice::open_with_queues(
+        queues,
+    )?))
+}
+
+/// Open this platform's device, with `queues` queues where that's supported.
+#[cfg(target_os = "macos")]
+pub fn open(queues: usize) -> AHResult<Box<dyn Device>> {
+    if queues > 1 {
+        anyhow::bail!("multiple queues are only supported on Linux");
+    }
+
+    Ok(Box::new(crate::feth_device::FethDevice::open()?))
+}
@@ -0,0 +1,276 @@
+//! A fixed pool of threads for message-driven actors to run on, instead of a thread each.
+//!
+//! Each actor is pinned to one thread, chosen by a key, so its messages are handled in order and
+//! never at the same time; actors sharing a key share a thread.
+
+use crossbeam::channel::{self, Select};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use crate::{supervisor, warn, watchdog};
+
+/// Messages handled per wakeup before other actors on the thread get a turn.
+const BATCH: usize = 64;
+
+pub trait Actor: Send + 'static {
+    type Message: Send + 'static;
+
+    fn handle(&mut self, message: Self::Message);
+}
+
+/// An actor with where its messages come from, as its thread sees it.
+trait Mailbox: Send {
+    fn watch<'a>(&'a self, select: &mut Select<'a>) -> usize;
+    /// Handle what's waiting, returning whether there could be more to come.
+    fn handle_ready(&mut self) -> bool;
+}
+
+struct Running<A: Actor> {
+    name: String,
+    receiver: channel::Receiver<A::Message>,
+    actor: A,
+    /// Panics in a row, forgotten once the actor's handled messages healthily for a while.
+    panics: u32,
+    last_panic: Instant,
+}
+
+impl<A: Actor> Mailbox for Running<A> {
+    fn watch<'a>(&'a self, select: &mut Select<'a>) -> usize {
+        select.recv(&self.receiver)
+    }
+
+    fn handle_ready(&mut self) -> bool {
+        for _ in 0..BATCH {
+            let message = match self.receiver.try_recv() {
+                Ok(message) => message,
+                Err(channel::TryRecvError::Empty) => return true,
+                Err(channel::TryRecvError::Disconnected) => return false,
+            };
+
+            supervisor::handling(&[]);
+            let actor = &mut self.actor;
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| actor.handle(message))) {
+                let policy = supervisor::policy();
+                if policy.is_healthy(self.last_panic.elapsed()) {
+                    self.panics = 0;
+                }
+                self.last_panic = Instant::now();
+
+                let restarting = self.panics < policy.max_restarts;
+                supervisor::report_panic(&self.name, &*payload, restarting);
+                if !restarting {
+                    warn!(
+                        "{} panicked {} times; leaving it stopped",
+                        self.name,
+                        self.panics + 1
+                    );
+                    return false;
+                }
+                self.panics += 1;
+            }
+        }
+
+        true
+    }
+}
+
+/// Run the actors sent over `added`, until they've all stopped and no more can be added.
+fn run_shard(name: &str, added: channel::Receiver<Box<dyn Mailbox>>) {
+    let heartbeat = watchdog::register(name);
+    let beats = channel::tick(watchdog::INTERVAL);
+    let mut mailboxes: Vec<Box<dyn Mailbox>> = Vec::new();
+    let mut adding = true;
+
+    while adding || !mailboxes.is_empty() {
+        let ready = {
+            let mut select = Select::new();
+            let beat_index = select.recv(&beats);
+            let added_index = adding.then(|| select.recv(&added));
+            let indices: Vec<_> = mailboxes.iter().map(|m| m.watch(&mut select)).collect();
+
+            let index = select.ready();
+            if index == beat_index {
+                None
+            } else if Some(index) == added_index {
+                match added.try_recv() {
+                    Ok(mailbox) => mailboxes.push(mailbox),
+                    Err(channel::TryRecvError::Disconnected) => adding = false,
+                    Err(channel::TryRecvError::Empty) => {}
+                }
+                None
+            } else {
+                indices.iter().position(|&i| i == index)
+            }
+        };
+
+        heartbeat.beat();
+        if let Some(i) = ready {
+            if !mailboxes[i].handle_ready() {
+                mailboxes.swap_remove(i);
+            }
+        }
+    }
+}
+
+/// Where to send an actor's messages.
+pub type Address<M> = channel::Sender<M>;
+
+#[derive(Clone)]
+pub struct Executor {
+    shards: Arc<Vec<channel::Sender<Box<dyn Mailbox>>>>,
+}
+
+impl Executor {
+    /// Start `threads` threads, named after `name` in the watchdog.
+    pub fn new(name: &str, threads: usize) -> Self {
+        let shards = (0..threads.max(1))
+            .map(|i| {
+                let (sender, receiver) = channel::unbounded();
+                let name = format!("{} {}", name, i);
+                thread::spawn(move || run_shard(&name, receiver));
+
+                sender
+            })
+            .collect();
+
+        Self {
+            shards: Arc::new(shards),
+        }
+    }
+
+    fn shard(&self, key: &impl Hash) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    /// Start `actor` on the thread for `key`, handling whatever arrives on `receiver` until every
+    /// sender is gone.
+    pub fn start<A: Actor>(
+        &self,
+        name: impl Into<String>,
+        key: impl Hash,
+        receiver: channel::Receiver<A::Message>,
+        actor: A,
+    ) {
+        let mailbox = Running {
+            name: name.into(),
+            receiver,
+            actor,
+            panics: 0,
+            last_panic: Instant::now(),
+        };
+
+        // Shard threads only stop once the executor is gone.
+        let _ = self.shards[self.shard(&key)].send(Box::new(mailbox));
+    }
+
+    /// Start `actor` on the thread for `key`, returning where to send its messages.
+    pub fn spawn<A: Actor>(
+        &self,
+        name: impl Into<String>,
+        key: impl Hash,
+        actor: A,
+    ) -> Address<A::Message> {
+        let (sender, receiver) = channel::unbounded();
+        self.start(name, key, receiver, actor);
+
+        sender
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    struct Recorder {
+        key: u32,
+        results: channel::Sender<(u32, u32, thread::ThreadId)>,
+    }
+
+    impl Actor for Recorder {
+        type Message = u32;
+
+        fn handle(&mut self, message: u32) {
+            if message == 0 {
+                panic!("deliberately");
+            }
+            self.results
+                .send((self.key, message, thread::current().id()))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn messages_for_each_key_are_handled_in_order_on_one_thread() {
+        let executor = Executor::new("executor-test", 3);
+        let (results, received) = channel::unbounded();
+
+        let addresses: Vec<_> = (0..8)
+            .map(|key| {
+                let actor = Recorder {
+                    key,
+                    results: results.clone(),
+                };
+                executor.spawn("executor-test", key, actor)
+            })
+            .collect();
+        for message in 1..=50 {
+            for address in &addresses {
+                address.send(message).unwrap();
+            }
+        }
+
+        let mut last = vec![(0, None); addresses.len()];
+        for _ in 0..addresses.len() * 50 {
+            let (key, message, thread) = received.recv_timeout(Duration::from_secs(5)).unwrap();
+            let (last_message, last_thread) = &mut last[key as usize];
+            assert_eq!(message, *last_message + 1);
+            assert!(last_thread.is_none() || *last_thread == Some(thread));
+            *last_message = message;
+            *last_thread = Some(thread);
+        }
+    }
+
+    #[test]
+    fn actors_keep_going_after_a_panic() {
+        let executor = Executor::new("executor-test-panics", 1);
+        let (results, received) = channel::unbounded();
+        let address = executor.spawn("executor-test-panics", 0, Recorder { key: 0, results });
+
+        address.send(0).unwrap();
+        address.send(1).unwrap();
+        let (_, message, _) = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(message, 1);
+    }
+
+    #[test]
+    fn panics_are_forgotten_after_a_healthy_while() {
+        let policy = supervisor::policy();
+        let (results, _received) = channel::unbounded();
+        let (sender, receiver) = channel::unbounded();
+        let mut running = Running {
+            name: "executor-test-healthy".to_string(),
+            receiver,
+            actor: Recorder { key: 0, results },
+            panics: policy.max_restarts,
+            last_panic: Instant::now(),
+        };
+
+        // Out of restarts, but a healthy while since the last panic makes up for it.
+        running.last_panic -= Duration::from_secs_f64(policy.healthy_after + 1.0);
+        sender.send(0).unwrap();
+        assert!(running.handle_ready());
+        assert_eq!(running.panics, 1);
+
+        running.panics = policy.max_restarts;
+        sender.send(0).unwrap();
+        assert!(!running.handle_ready());
+    }
+}
@@ -0,0 +1,354 @@
+//! Expectations about traffic, so a test harness can use fakenet as a network mock that verifies
+//! what it saw: each is a predicate over decoded packets with a deadline, waited on until it's
+//! met or fails with the traffic seen around it.
+
+use anyhow::{bail, Result as AHResult};
+use crossbeam::channel;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::protocols::filter::Filter;
+use crate::protocols::{dns, ether, ipv4, ipv6, tcp, udp};
+
+/// How many recent frames are kept to explain failures.
+const HISTORY_LEN: usize = 64;
+/// How long before an expectation was registered its failure report starts.
+const NEARBY: Duration = Duration::from_secs(1);
+
+/// A frame, decoded as far as we understand it.
+#[derive(Clone, Debug)]
+pub struct Packet {
+    pub frame: ether::Frame,
+    pub ipv6: Option<ipv6::Packet>,
+    pub udp: Option<udp::Packet>,
+    pub tcp: Option<tcp::Segment>,
+}
+
+impl Packet {
+    pub fn decode(frame: ether::Frame) -> Self {
+        let ipv6 = match frame.ethertype {
+            ether::Type::Ipv6 => ipv6::packet(&frame.payload).ok(),
+            _ => None,
+        };
+
+        let (mut udp, mut tcp) = (None, None);
+        if let Some(packet) = &ipv6 {
+            let pseudo_header = ipv6::PseudoHeader {
+                src: packet.src,
+                dest: packet.dest,
+                length: 0,
+            };
+
+            match packet.next_header {
+                ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Udp) => {
+                    udp = udp::packet(&packet.payload, pseudo_header).ok()
+                }
+                ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Tcp) => {
+                    tcp = tcp::segment(&packet.payload, pseudo_header).ok()
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            frame,
+            ipv6,
+            udp,
+            tcp,
+        }
+    }
+
+    /// The DNS query this carries, if it's one sent to port 53.
+    pub fn dns_query(&self) -> Option<dns::Query> {
+        self.udp
+            .as_ref()
+            .filter(|udp| udp.dest_port == 53)
+            .and_then(|udp| dns::query(&udp.payload).ok())
+    }
+
+    /// One line about the packet, for failure reports.
+    pub fn summary(&self) -> String {
+        let mut result = format!("{} > {}", self.frame.src, self.frame.dest);
+
+        match (&self.ipv6, &self.udp, &self.tcp) {
+            (Some(ipv6), Some(udp), _) => write!(
+                result,
+                " [{}]:{} > [{}]:{} udp {} bytes",
+                ipv6.src,
+                udp.src_port,
+                ipv6.dest,
+                udp.dest_port,
+                udp.payload.len()
+            ),
+            (Some(ipv6), _, Some(tcp)) => write!(
+                result,
+                " [{}]:{} > [{}]:{} tcp flags {:#04x} seq {} {} bytes",
+                ipv6.src,
+                tcp.src_port,
+                ipv6.dest,
+                tcp.dest_port,
+                tcp.flags,
+                tcp.seq,
+                tcp.payload.len()
+            ),
+            (Some(ipv6), _, _) => write!(
+                result,
+                " {} > {} {} {} bytes",
+                ipv6.src,
+                ipv6.dest,
+                ipv6.next_header,
+                ipv6.payload.len()
+            ),
+            _ => write!(
+                result,
+                " {} {} bytes",
+                self.frame.ethertype,
+                self.frame.payload.len()
+            ),
+        }
+        .unwrap();
+
+        if let Some(ipv6) = self.ipv6.as_ref().filter(|ipv6| ipv6.traffic_class != 0) {
+            write!(result, " dscp {} ecn {}", ipv6.dscp(), ipv6.ecn()).unwrap();
+        }
+
+        if let Some(query) = self.dns_query() {
+            write!(result, " dns query {} {:?}", query.name, query.record_type).unwrap();
+        }
+
+        result
+    }
+}
+
+pub type Predicate = Box<dyn Fn(&Packet) -> bool + Send>;
+
+/// A predicate that matches frames like a capture filter.
+pub fn filter(filter: Filter) -> Predicate {
+    Box::new(move |packet| filter.matches(&packet.frame))
+}
+
+struct Pending {
+    id: u64,
+    predicate: Predicate,
+    sender: channel::Sender<Packet>,
+}
+
+#[derive(Default)]
+struct Shared {
+    history: VecDeque<(Instant, String)>,
+    pending: Vec<Pending>,
+    next_id: u64,
+}
+
+/// Watches traffic for registered expectations.
+#[derive(Clone)]
+pub struct Expectations {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Expectations {
+    /// Watch all of an interface's traffic, from now on.
+    pub fn new(observer: &ether::ObserveHandle) -> Self {
+        Self::from_receiver(observer.observe(1, Filter::All))
+    }
+
+    pub fn from_receiver(frames: channel::Receiver<ether::Frame>) -> Self {
+        let expectations = Self {
+            shared: Arc::new(Mutex::new(Shared::default())),
+        };
+        let shared = Arc::clone(&expectations.shared);
+
+        thread::spawn(move || {
+            for frame in frames {
+                let packet = Packet::decode(frame);
+                let mut shared = shared.lock().unwrap();
+
+                if shared.history.len() == HISTORY_LEN {
+                    shared.history.pop_front();
+                }
+                shared.history.push_back((Instant::now(), packet.summary()));
+
+                shared.pending.retain(|pending| {
+                    if (pending.predicate)(&packet) {
+                        let _ = pending.sender.try_send(packet.clone());
+                        return false;
+                    }
+
+                    true
+                });
+            }
+        });
+
+        expectations
+    }
+
+    /// Expect a packet matching `predicate` within `within` from now.
+    pub fn expect(
+        &self,
+        description: impl Into<String>,
+        within: Duration,
+        predicate: Predicate,
+    ) -> Expectation {
+        let (sender, receiver) = channel::bounded(1);
+        let now = Instant::now();
+
+        let mut shared = self.shared.lock().unwrap();
+        let id = shared.next_id;
+        shared.next_id += 1;
+        shared.pending.push(Pending {
+            id,
+            predicate,
+            sender,
+        });
+
+        Expectation {
+            id,
+            description: description.into(),
+            registered: now,
+            deadline: now + within,
+            receiver,
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+/// A registered expectation, given up on when dropped.
+pub struct Expectation {
+    id: u64,
+    description: String,
+    registered: Instant,
+    deadline: Instant,
+    receiver: channel::Receiver<Packet>,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Expectation {
+    /// Wait for the first matching packet, failing with the traffic seen around the expectation
+    /// if none comes by the deadline.
+    pub fn wait(self) -> AHResult<Packet> {
+        if let Ok(packet) = self.receiver.recv_deadline(self.deadline) {
+            return Ok(packet);
+        }
+
+        let shared = self.shared.lock().unwrap();
+        let nearby: Vec<_> = shared
+            .history
+            .iter()
+            .filter(|(seen, _)| *seen + NEARBY >= self.registered)
+            .map(|(seen, summary)| {
+                let offset = seen
+                    .saturating_duration_since(self.registered)
+                    .as_secs_f64()
+                    - self
+                        .registered
+                        .saturating_duration_since(*seen)
+                        .as_secs_f64();
+
+                format!("  {:+.3}s {}", offset, summary)
+            })
+            .collect();
+
+        bail!(
+            "expected {} within {:.3}s, but saw {}",
+            self.description,
+            (self.deadline - self.registered).as_secs_f64(),
+            if nearby.is_empty() {
+                "no traffic".to_string()
+            } else {
+                format!("only:\n{}", nearby.join("\n"))
+            }
+        )
+    }
+}
+
+impl Drop for Expectation {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.pending.retain(|pending| pending.id != self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp_frame(dest_port: u16, payload: Vec<u8>) -> ether::Frame {
+        let src = "fe80::1".parse().unwrap();
+        let dest = "fe80::2".parse().unwrap();
+
+        ether::Frame::builder()
+            .dest(ether::Address([2, 0, 0, 0, 0, 2]))
+            .src(ether::Address([2, 0, 0, 0, 0, 1]))
+            .ethertype(ether::Type::Ipv6)
+            .payload(
+                ipv6::Packet::builder()
+                    .protocol(ipv4::ProtocolNumber::Udp)
+                    .hop_limit(64)
+                    .src(src)
+                    .dest(dest)
+                    .payload(
+                        udp::Packet {
+                            src_port: 40000,
+                            dest_port,
+                            payload,
+                        }
+                        .encode(ipv6::PseudoHeader {
+                            src,
+                            dest,
+                            length: 0,
+                        }),
+                    )
+                    .build()
+                    .encode(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn expectations_match_decoded_packets() {
+        let (sender, receiver) = channel::unbounded();
+        let expectations = Expectations::from_receiver(receiver);
+
+        let expectation = expectations.expect(
+            "a dns query for example.com",
+            Duration::from_secs(5),
+            Box::new(|packet| packet.dns_query().is_some_and(|q| q.name == "example.com")),
+        );
+
+        sender.send(udp_frame(123, vec![0; 48])).unwrap();
+        let query = dns::Query {
+            id: 1,
+            name: "example.com".to_string(),
+            record_type: dns::RecordType::Aaaa,
+        };
+        sender.send(udp_frame(53, query.encode())).unwrap();
+
+        assert_eq!(expectation.wait().unwrap().dns_query(), Some(query));
+    }
+
+    #[test]
+    fn failures_show_nearby_traffic() {
+        let (sender, receiver) = channel::unbounded();
+        let expectations = Expectations::from_receiver(receiver);
+
+        sender.send(udp_frame(123, vec![0; 48])).unwrap();
+        let error = expectations
+            .expect(
+                "anything to port 53",
+                Duration::from_millis(50),
+                filter("udp and port 53".parse().unwrap()),
+            )
+            .wait()
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.starts_with("expected anything to port 53 within 0.050s, but saw only:"));
+        assert!(error.contains("[fe80::1]:40000 > [fe80::2]:123 udp 48 bytes"));
+        assert!(expectations.shared.lock().unwrap().pending.is_empty());
+    }
+}
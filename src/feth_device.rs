@@ -0,0 +1,246 @@
+//! macOS backend: a pair of fake ethernet (feth) interfaces, one left for the host and the other
+//! read and written through BPF.
+
+use anyhow::{bail, Context, Result as AHResult};
+use libc::{c_char, c_uint};
+use nix::{ioctl_readwrite, ioctl_write_ptr};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::device::{Device, FRAME_SIZE};
+use crate::warn;
+
+const IFNAMSIZ: usize = 16;
+const MAX_UNITS: u32 = 256;
+const BPF_BUFFER_LEN: c_uint = 65536;
+// struct bpf_hdr: 8 byte timestamp, caplen, datalen, then hdrlen
+const BPF_HEADER_LEN: usize = 18;
+const BPF_ALIGNMENT: usize = 4;
+
+#[repr(C)]
+struct IfReq {
+    name: [c_char; IFNAMSIZ],
+    data: [u8; 16],
+}
+
+// Ref: https://opensource.apple.com/source/xnu/xnu-7195.81.3/bsd/net/bpf.h
+ioctl_readwrite!(biocsblen, b'B', 102, c_uint);
+ioctl_write_ptr!(biocsetif, b'B', 108, IfReq);
+ioctl_write_ptr!(biocimmediate, b'B', 112, c_uint);
+ioctl_write_ptr!(biocshdrcmplt, b'B', 117, c_uint);
+ioctl_write_ptr!(biocsseesent, b'B', 119, c_uint);
+
+fn ifconfig(args: &[&str]) -> AHResult<()> {
+    let output = Command::new("ifconfig")
+        .args(args)
+        .output()
+        .context("running ifconfig failed")?;
+
+    if !output.status.success() {
+        bail!(
+            "ifconfig {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// A feth interface we created, destroyed when dropped unless kept.
+struct Feth {
+    name: String,
+    keep: bool,
+}
+
+impl Feth {
+    fn create() -> AHResult<Self> {
+        for unit in 0..MAX_UNITS {
+            let name = format!("feth{}", unit);
+
+            if ifconfig(&[name.as_str(), "create"]).is_ok() {
+                return Ok(Self { name, keep: false });
+            }
+        }
+
+        bail!("no free feth units")
+    }
+}
+
+impl Drop for Feth {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+
+        if let Err(e) = ifconfig(&[self.name.as_str(), "destroy"]) {
+            warn!("{}", e);
+        }
+    }
+}
+
+fn open_bpf() -> AHResult<File> {
+    for unit in 0..MAX_UNITS {
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/dev/bpf{}", unit))
+        {
+            Ok(file) => return Ok(file),
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    bail!("no free bpf devices")
+}
+
+/// We created the interfaces, so restoring the host on drop means destroying them.
+pub struct FethDevice {
+    /// The end the host configures and captures on.
+    host: Feth,
+    /// The end we read and write through `bpf`.
+    peer: Feth,
+    bpf: File,
+    buffer: Vec<u8>,
+}
+
+impl FethDevice {
+    pub fn open() -> AHResult<Self> {
+        // Either interface is destroyed again if anything after it fails.
+        let host = Feth::create()?;
+        let peer = Feth::create()?;
+        let mut device = Self {
+            host,
+            peer,
+            bpf: open_bpf()?,
+            buffer: vec![0; BPF_BUFFER_LEN as usize],
+        };
+
+        let mtu = (FRAME_SIZE - 6 - 6 - 2).to_string();
+        ifconfig(&[device.host.name.as_str(), "peer", device.peer.name.as_str()])?;
+        ifconfig(&[device.host.name.as_str(), "mtu", mtu.as_str()])?;
+        ifconfig(&[device.peer.name.as_str(), "mtu", mtu.as_str(), "up"])?;
+
+        let fd = device.bpf.as_raw_fd();
+        let mut ifr = IfReq {
+            name: [0; IFNAMSIZ],
+            data: [0; 16],
+        };
+        for (i, byte) in device.peer.name.bytes().enumerate() {
+            ifr.name[i] = byte as c_char;
+        }
+
+        unsafe {
+            // Must be set before attaching to an interface.
+            let mut buffer_len = BPF_BUFFER_LEN;
+            biocsblen(fd, &mut buffer_len)?;
+            device.buffer.resize(buffer_len as usize, 0);
+
+            biocsetif(fd, &ifr)?;
+            // Deliver frames as they arrive, rather than when the buffer fills.
+            biocimmediate(fd, &1)?;
+            // We fill in source addresses ourselves.
+            biocshdrcmplt(fd, &1)?;
+            biocsseesent(fd, &0)?;
+        }
+
+        Ok(device)
+    }
+}
+
+impl Device for FethDevice {
+    fn up(&mut self) -> AHResult<()> {
+        ifconfig(&[self.host.name.as_str(), "up"])
+    }
+
+    fn down(&mut self) -> AHResult<()> {
+        ifconfig(&[self.host.name.as_str(), "down"])
+    }
+
+    fn if_name(&self) -> AHResult<String> {
+        Ok(self.host.name.clone())
+    }
+
+    fn if_hwaddr(&self) -> AHResult<[u8; 6]> {
+        for ifaddr in nix::ifaddrs::getifaddrs()? {
+            if ifaddr.interface_name != self.host.name {
+                continue;
+            }
+
+            if let Some(nix::sys::socket::SockAddr::Link(link)) = ifaddr.address {
+                return Ok(link.addr());
+            }
+        }
+
+        bail!("{} has no hardware address", self.host.name)
+    }
+
+    fn set_if_hwaddr(&mut self, address: [u8; 6]) -> AHResult<()> {
+        let formatted = address
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        ifconfig(&[self.host.name.as_str(), "lladdr", formatted.as_str()])?;
+
+        let set = self.if_hwaddr()?;
+        if set != address {
+            bail!(
+                "kernel kept hardware address {:02x?} instead of {:02x?}",
+                set,
+                address
+            );
+        }
+
+        Ok(())
+    }
+
+    fn read_frames(&mut self, f: &mut dyn FnMut(&[u8], SystemTime)) -> AHResult<()> {
+        let num_read = self.bpf.read(&mut self.buffer)?;
+        let mut offset = 0;
+
+        while offset + BPF_HEADER_LEN <= num_read {
+            let header = &self.buffer[offset..num_read];
+            let caplen = u32::from_ne_bytes([header[8], header[9], header[10], header[11]]);
+            let hdrlen = u16::from_ne_bytes([header[16], header[17]]) as usize;
+            let end = hdrlen + caplen as usize;
+
+            if end > header.len() {
+                bail!("truncated bpf record");
+            }
+
+            // The record's `bh_tstamp`, a 32 bit `timeval` taken as bpf captured the frame.
+            let seconds = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+            let micros = u32::from_ne_bytes([header[4], header[5], header[6], header[7]]);
+            let received = UNIX_EPOCH
+                + Duration::from_secs(seconds as u64)
+                + Duration::from_micros(micros as u64);
+
+            f(&header[hdrlen..end], received);
+            offset += (end + BPF_ALIGNMENT - 1) & !(BPF_ALIGNMENT - 1);
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> AHResult<()> {
+        Ok(self.bpf.write_all(buf)?)
+    }
+
+    fn rawfd(&self) -> RawFd {
+        self.bpf.as_raw_fd()
+    }
+
+    fn set_down_on_drop(&mut self, down_on_drop: bool) {
+        self.host.keep = !down_on_drop;
+        self.peer.keep = !down_on_drop;
+    }
+
+    fn down_on_drop(&self) -> bool {
+        !self.host.keep
+    }
+}
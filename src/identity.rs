@@ -0,0 +1,128 @@
+//! The name the node goes by and the kind of machine it passes for, kept in one place so every
+//! service that describes the machine agrees.
+
+use anyhow::{bail, Result as AHResult};
+use lazy_static::lazy_static;
+use std::sync::RwLock;
+
+use crate::personality::Personality;
+use crate::protocols::ether;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub hostname: String,
+    pub domain: Option<String>,
+    pub personality: Personality,
+}
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc1123#section-2.1
+fn check_label(label: &str) -> AHResult<()> {
+    if label.is_empty() || label.len() > 63 {
+        bail!("\"{}\" must be between 1 and 63 characters", label);
+    }
+    if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        bail!("\"{}\" can only have letters, digits and hyphens", label);
+    }
+    if label.starts_with('-') || label.ends_with('-') {
+        bail!("\"{}\" can't start or end with a hyphen", label);
+    }
+
+    Ok(())
+}
+
+impl Identity {
+    /// From a bare hostname or a fully qualified one, like `printer.example.com`.
+    pub fn new(name: &str) -> AHResult<Self> {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        if name.len() > 253 {
+            bail!("hostname {} is longer than 253 characters", name);
+        }
+        for label in name.split('.') {
+            check_label(label)?;
+        }
+
+        let (hostname, domain) = match name.split_once('.') {
+            Some((hostname, domain)) => (hostname, Some(domain.to_string())),
+            None => (name, None),
+        };
+
+        Ok(Self {
+            hostname: hostname.to_string(),
+            domain,
+            personality: Personality::default(),
+        })
+    }
+
+    /// A name for a node that wasn't given one, distinguished by its ether address.
+    pub fn from_ether_address(address: ether::Address) -> Self {
+        Self {
+            hostname: format!("fakenet-{}", hex::encode(&address.0[3..])),
+            domain: None,
+            personality: Personality::default(),
+        }
+    }
+
+    pub fn fqdn(&self) -> String {
+        match &self.domain {
+            Some(domain) => format!("{}.{}", self.hostname, domain),
+            None => self.hostname.clone(),
+        }
+    }
+
+    /// `text` with `{hostname}` and `{fqdn}` filled in.
+    pub fn expand(&self, text: &str) -> String {
+        text.replace("{hostname}", &self.hostname)
+            .replace("{fqdn}", &self.fqdn())
+    }
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self {
+            hostname: "fakenet".to_string(),
+            domain: None,
+            personality: Personality::default(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref IDENTITY: RwLock<Identity> = RwLock::new(Identity::default());
+}
+
+/// Go by `identity` from now on.
+pub fn set(identity: Identity) {
+    *IDENTITY.write().unwrap() = identity;
+}
+
+pub fn get() -> Identity {
+    IDENTITY.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_split_and_checked() {
+        let identity = Identity::new("printer.example.com.").unwrap();
+        assert_eq!(identity.hostname, "printer");
+        assert_eq!(identity.domain.as_deref(), Some("example.com"));
+        assert_eq!(identity.fqdn(), "printer.example.com");
+
+        assert_eq!(Identity::new("nas").unwrap().fqdn(), "nas");
+        assert!(Identity::new("-nas").is_err());
+        assert!(Identity::new("nas..example.com").is_err());
+        assert!(Identity::new("nas_1").is_err());
+    }
+
+    #[test]
+    fn unnamed_nodes_get_a_name_from_their_address() {
+        let identity = Identity::from_ether_address("02:00:00:a1:b2:c3".parse().unwrap());
+
+        assert_eq!(
+            identity.expand("Welcome to {hostname} ({fqdn})"),
+            "Welcome to fakenet-a1b2c3 (fakenet-a1b2c3)"
+        );
+    }
+}
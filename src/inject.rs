@@ -0,0 +1,369 @@
+//! Crafting arbitrary frames from a JSON description.
+//!
+//! A spec looks like:
+//!
+//! ```json
+//! {
+//!   "ether": { "dest": "33:33:00:00:00:01" },
+//!   "ipv6": { "src": "fe80::1", "dest": "ff02::1", "hop_limit": 1 },
+//!   "udp": { "src_port": 1234, "dest_port": "mdns" },
+//!   "payload": "deadbeef"
+//! }
+//! ```
+//!
+//! Each layer is optional; whatever is present is encoded with the normal packet builders, and
+//! the innermost layer carries the hex payload. Ports and protocols can be given by name.
+//!
+//! Instead of `udp`, an `ndp` layer builds a neighbor discovery message, optionally with
+//! deliberate defects for testing how other nodes validate what they receive:
+//!
+//! ```json
+//! {
+//!   "ether": { "dest": "33:33:ff:00:00:02" },
+//!   "ipv6": { "src": "fe80::1", "dest": "ff02::1:ff00:2", "hop_limit": 64 },
+//!   "ndp": {
+//!     "type": "neighbor_solicitation",
+//!     "target": "fe80::2",
+//!     "malformations": { "bad_checksum": true, "code": 1 }
+//!   }
+//! }
+//! ```
+//!
+//! NDP messages get a hop limit of 255 unless one is given, as any other is invalid.
+
+use anyhow::{bail, Context, Result as AHResult};
+use serde::Deserialize;
+use std::convert::TryFrom;
+
+use crate::protocols::ipv6::icmpv6;
+use crate::protocols::{ether, ipv4, ipv6, names, udp};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EtherSpec {
+    pub dest: String,
+    pub src: Option<String>,
+    pub ethertype: Option<u16>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ipv6Spec {
+    pub src: String,
+    pub dest: String,
+    pub hop_limit: Option<u8>,
+    #[serde(default)]
+    pub traffic_class: u8,
+    #[serde(default)]
+    pub flow_label: u32,
+    /// A number or protocol name, like `udp`.
+    #[serde(default, deserialize_with = "names::deserialize_protocol")]
+    pub next_header: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpSpec {
+    #[serde(deserialize_with = "names::deserialize_port")]
+    pub src_port: u16,
+    #[serde(deserialize_with = "names::deserialize_port")]
+    pub dest_port: u16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NdpMessage {
+    RouterSolicitation,
+    RouterAdvertisement {
+        #[serde(default)]
+        router_lifetime: u16,
+        mtu: Option<u32>,
+    },
+    NeighborSolicitation {
+        target: String,
+        /// Hex.
+        nonce: Option<String>,
+    },
+    NeighborAdvertisement {
+        target: String,
+        #[serde(default)]
+        router: bool,
+        #[serde(default)]
+        solicited: bool,
+        #[serde(default)]
+        override_flag: bool,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NdpSpec {
+    #[serde(flatten)]
+    pub message: NdpMessage,
+    #[serde(default)]
+    pub malformations: icmpv6::Malformations,
+}
+
+impl NdpSpec {
+    fn packet(&self) -> AHResult<icmpv6::Packet> {
+        Ok(match self.message {
+            NdpMessage::RouterSolicitation => icmpv6::Packet::RouterSolicitation,
+            NdpMessage::RouterAdvertisement {
+                router_lifetime,
+                mtu,
+            } => icmpv6::Packet::RouterAdvertisement {
+                cur_hop_limit: 0,
+                managed: false,
+                other: false,
+                router_lifetime,
+                reachable_time: 0,
+                retrans_timer: 0,
+                options: mtu
+                    .map(icmpv6::NeighborSolicitationOption::Mtu)
+                    .into_iter()
+                    .collect(),
+            },
+            NdpMessage::NeighborSolicitation {
+                ref target,
+                ref nonce,
+            } => icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+                dest: target.parse()?,
+                options: match nonce {
+                    Some(nonce) => vec![icmpv6::NeighborSolicitationOption::Nonce(
+                        hex::decode(nonce).context("nonce must be hex")?,
+                    )],
+                    None => vec![],
+                },
+            }),
+            NdpMessage::NeighborAdvertisement {
+                ref target,
+                router,
+                solicited,
+                override_flag,
+            } => icmpv6::Packet::NeighborAdvertisement(icmpv6::NeighborAdvertisement {
+                router,
+                solicited,
+                override_flag,
+                src: target.parse()?,
+                options: vec![],
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FrameSpec {
+    pub ether: EtherSpec,
+    pub ipv6: Option<Ipv6Spec>,
+    pub udp: Option<UdpSpec>,
+    pub ndp: Option<NdpSpec>,
+    #[serde(default)]
+    pub payload: String,
+}
+
+impl FrameSpec {
+    /// Build the described frame, using `default_src` if the spec does not give a source MAC.
+    pub fn build(&self, default_src: ether::Address) -> AHResult<ether::Frame> {
+        let mut payload = hex::decode(&self.payload).context("payload must be hex")?;
+
+        if self.udp.is_some() && self.ipv6.is_none() {
+            bail!("udp requires an ipv6 layer");
+        }
+
+        if self.ndp.is_some() {
+            if self.ipv6.is_none() {
+                bail!("ndp requires an ipv6 layer");
+            }
+            if self.udp.is_some() {
+                bail!("only one of udp and ndp can be given");
+            }
+            if !payload.is_empty() {
+                bail!("ndp messages are built from their fields, and can't carry a payload");
+            }
+        }
+
+        let mut ethertype = self.ether.ethertype;
+
+        if let Some(ref ipv6_spec) = self.ipv6 {
+            let src: ipv6::Address = ipv6_spec.src.parse()?;
+            let dest: ipv6::Address = ipv6_spec.dest.parse()?;
+
+            let mut protocol = ipv6_spec
+                .next_header
+                .map(ipv4::ProtocolNumber::try_from)
+                .transpose()?;
+
+            if let Some(ref udp_spec) = self.udp {
+                payload = udp::Packet {
+                    src_port: udp_spec.src_port,
+                    dest_port: udp_spec.dest_port,
+                    payload,
+                }
+                .encode(ipv6::PseudoHeader {
+                    src,
+                    dest,
+                    length: 0,
+                });
+                protocol = protocol.or(Some(ipv4::ProtocolNumber::Udp));
+            }
+
+            if let Some(ref ndp_spec) = self.ndp {
+                payload = ndp_spec.packet()?.encode_raw(
+                    icmpv6::PseudoHeader {
+                        src,
+                        dest,
+                        length: 0,
+                    },
+                    &ndp_spec.malformations,
+                );
+                protocol = protocol.or(Some(ipv4::ProtocolNumber::Ipv6Icmp));
+            }
+
+            let protocol = match protocol {
+                Some(p) => p,
+                None => bail!("ipv6 layer needs a next_header or an inner layer"),
+            };
+
+            payload = ipv6::Packet::builder()
+                .traffic_class(ipv6_spec.traffic_class)
+                .flow_label(ipv6_spec.flow_label)
+                .protocol(protocol)
+                .hop_limit(ipv6_spec.hop_limit.unwrap_or(match self.ndp {
+                    Some(_) => 255,
+                    None => 64,
+                }))
+                .src(src)
+                .dest(dest)
+                .payload(payload)
+                .build()
+                .encode();
+            ethertype = ethertype.or(Some(ether::Type::Ipv6 as u16));
+        }
+
+        let ethertype = match ethertype {
+            Some(t) => ether::Type::try_from(t)?,
+            None => bail!("ether layer needs an ethertype or an inner layer"),
+        };
+
+        ether::Frame::builder()
+            .dest(self.ether.dest.parse()?)
+            .src(match self.ether.src {
+                Some(ref src) => src.parse()?,
+                None => default_src,
+            })
+            .ethertype(ethertype)
+            .payload(payload)
+            .build()
+    }
+}
+
+/// Parse either a single frame spec or an array of them.
+pub fn parse_specs(input: &str) -> AHResult<Vec<FrameSpec>> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(FrameSpec),
+        Many(Vec<FrameSpec>),
+    }
+
+    Ok(match serde_json::from_str(input)? {
+        OneOrMany::One(spec) => vec![spec],
+        OneOrMany::Many(specs) => specs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hexstring(s: &str) -> Vec<u8> {
+        hex::decode(s).unwrap()
+    }
+
+    fn src() -> ether::Address {
+        "02:00:00:00:00:01".parse().unwrap()
+    }
+
+    #[test]
+    fn raw_ether_spec_builds() {
+        let specs = parse_specs(
+            r#"{"ether": {"dest": "ff:ff:ff:ff:ff:ff", "ethertype": 2054}, "payload": "0001"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            specs[0].build(src()).unwrap(),
+            ether::Frame {
+                dest: "ff:ff:ff:ff:ff:ff".parse().unwrap(),
+                src: src(),
+                ethertype: ether::Type::Arp,
+                payload: hexstring("0001"),
+                received: None,
+            }
+        );
+    }
+
+    #[test]
+    fn udp_spec_builds_full_stack() {
+        let specs = parse_specs(
+            r#"[{
+                "ether": {"dest": "33:33:00:00:00:01", "src": "02:00:00:00:00:02"},
+                "ipv6": {"src": "fe80::1", "dest": "fe80::2", "hop_limit": 1},
+                "udp": {"src_port": 1234, "dest_port": "domain"},
+                "payload": "616263"
+            }]"#,
+        )
+        .unwrap();
+
+        let frame = specs[0].build(src()).unwrap();
+        assert_eq!(frame.src, "02:00:00:00:00:02".parse().unwrap());
+        assert_eq!(frame.ethertype, ether::Type::Ipv6);
+
+        let packet = ipv6::packet(&frame.payload).unwrap();
+        assert_eq!(packet.hop_limit, 1);
+        assert_eq!(
+            packet.next_header,
+            ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Udp)
+        );
+        assert_eq!(packet.payload, hexstring("04d20035000b396a616263"));
+    }
+
+    #[test]
+    fn ndp_spec_builds_with_malformations() {
+        let specs = parse_specs(
+            r#"{
+                "ether": {"dest": "33:33:ff:00:00:02"},
+                "ipv6": {"src": "fe80::1", "dest": "ff02::1:ff00:2"},
+                "ndp": {
+                    "type": "neighbor_solicitation",
+                    "target": "fe80::2",
+                    "nonce": "010203040506",
+                    "malformations": {"option_length": 0}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let packet = ipv6::packet(&specs[0].build(src()).unwrap().payload).unwrap();
+        assert_eq!(packet.hop_limit, 255);
+        assert_eq!(
+            packet.next_header,
+            ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+        );
+        assert_eq!(
+            packet.payload,
+            hexstring("8700668d00000000fe8000000000000000000000000000020e00010203040506")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "requires an ipv6 layer")]
+    fn udp_without_ipv6_fails() {
+        parse_specs(
+            r#"{"ether": {"dest": "ff:ff:ff:ff:ff:ff"}, "udp": {"src_port": 1, "dest_port": 2}}"#,
+        )
+        .unwrap()[0]
+            .build(src())
+            .unwrap();
+    }
+}
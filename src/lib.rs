@@ -0,0 +1,38 @@
+pub mod bench;
+pub mod cli;
+pub mod clock;
+pub mod config;
+pub mod config_check;
+pub mod control;
+pub mod delay_queue;
+pub mod device;
+pub mod executor;
+pub mod expect;
+#[cfg(target_os = "macos")]
+pub mod feth_device;
+pub mod identity;
+pub mod inject;
+pub mod log;
+pub mod metrics;
+pub mod net;
+pub mod netns;
+pub mod node;
+pub mod overrides;
+#[cfg(target_os = "linux")]
+pub mod packet_device;
+pub mod pcap;
+pub mod personality;
+pub mod privileges;
+pub mod protocols;
+pub mod resources;
+pub mod services;
+pub mod socket_device;
+pub mod stats;
+pub mod status;
+pub mod subcommands;
+pub mod supervisor;
+#[cfg(target_os = "linux")]
+pub mod tap_device;
+pub mod topology;
+pub mod trafficgen;
+pub mod watchdog;
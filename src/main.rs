@@ -1,57 +1,67 @@
-use anyhow::Result as AHResult;
-use serde::Deserialize;
+use anyhow::{bail, Result as AHResult};
 use std::env;
-use std::fs::File;
-use std::io::Read;
-use std::thread;
 
-mod delay_queue;
-mod protocols;
-mod status;
-mod tap_device;
-
-#[derive(Deserialize)]
-struct Network {
-    node: Node,
-}
-
-#[derive(Deserialize)]
-struct Node {
-    ether_address: String,
-    ipv4_address: Option<String>,
-}
+use fakenet::config::read_network;
+use fakenet::{cli, log, node, overrides, status, subcommands};
 
 fn main() -> AHResult<()> {
-    let mut network_config = String::new();
-    File::open(
-        env::args()
-            .nth(1)
-            .expect("expected a network config file as an argument"),
-    )?
-    .read_to_string(&mut network_config)?;
-    let network: Network = toml::from_str(&network_config)?;
-
-    let mut eth = protocols::ether::TapInterface::open(network.node.ether_address.parse()?)?;
-    status::update()
-        .child("interface")
-        .field("name", eth.if_name()?)
-        .write();
+    let (options, command) = match cli::parse(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    if let Some(ipv4_address) = network.node.ipv4_address {
-        let arp_server = protocols::arp::Server::new(&mut eth)?;
-        arp_server.add(ipv4_address.parse()?);
-        arp_server.start();
+    log::set_level(options.log_level);
+    if options.status_socket.is_some() && matches!(command, cli::Command::Topology { .. }) {
+        bail!("--status-socket doesn't apply to topology, whose status comes from its nodes");
+    }
+    if let Some(path) = &options.status_socket {
+        status::serve(path)?;
+    }
+    let captures = matches!(
+        command,
+        cli::Command::Run { .. } | cli::Command::Ping { .. } | cli::Command::Dump { .. }
+    );
+    if options.capture.is_some() && !captures {
+        bail!("--capture only applies to run, ping and dump");
     }
 
-    let mut ipv6_server = protocols::ipv6::Server::new(&mut eth)?;
-    ipv6_server.start();
-
-    let udp_server = protocols::udp::Server::new(&mut ipv6_server)?;
-    udp_server.start();
-
-    eth.start()?;
+    let mut overrides = overrides::from_env(env::vars());
+    overrides.extend(options.overrides.iter().cloned());
 
-    loop {
-        thread::park();
+    match command {
+        cli::Command::Run { config } => node::run(read_network(&config, &overrides)?, &options),
+        cli::Command::Send { config, spec } => {
+            subcommands::send(read_network(&config, &overrides)?, &spec)
+        }
+        cli::Command::Generate { config, streams } => {
+            subcommands::generate(read_network(&config, &overrides)?, &streams)
+        }
+        cli::Command::Wake { config, target } => {
+            subcommands::wake(read_network(&config, &overrides)?, &target)
+        }
+        cli::Command::Ping {
+            config,
+            dest,
+            count,
+        } => subcommands::ping(read_network(&config, &overrides)?, &options, &dest, count),
+        cli::Command::Dump { config, filter } => {
+            subcommands::dump(read_network(&config, &overrides)?, &options, &filter)
+        }
+        cli::Command::ValidateConfig { config } => {
+            subcommands::validate_config(&config, &overrides)
+        }
+        cli::Command::Topology { config } => subcommands::run_topology(&config, &options),
+        cli::Command::Bench {
+            packets,
+            payload_len,
+        } => subcommands::run_bench(packets, payload_len),
+        cli::Command::BenchMicro { iterations } => subcommands::run_micro_bench(iterations),
+        cli::Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,323 @@
+//! Counters from status in the Prometheus text exposition format, served over HTTP so long-running
+//! nodes can be scraped like any other service.
+
+use anyhow::{Context, Result as AHResult};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write as _};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::status::{self, Status};
+use crate::warn;
+use crate::watchdog::Health;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Exposition(String);
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP fakenet_{} {}", name, help).unwrap();
+        writeln!(self.0, "# TYPE fakenet_{} {}", name, kind).unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        write!(self.0, "fakenet_{}", name).unwrap();
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+                .collect();
+            write!(self.0, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.0, " {}", value).unwrap();
+    }
+
+    /// A family with a single, unlabeled sample.
+    fn single(&mut self, name: &str, kind: &str, help: &str, value: impl Display) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+}
+
+/// Metrics for everything counted in `status`.
+pub fn render(status: &Status) -> String {
+    let mut out = Exposition(String::new());
+    let interface = &status.interface;
+
+    out.single(
+        "frames_received_total",
+        "counter",
+        "Frames received on the node's interface.",
+        interface.counters.frames_received,
+    );
+    out.single(
+        "frames_sent_total",
+        "counter",
+        "Frames sent on the node's interface.",
+        interface.counters.frames_sent,
+    );
+    out.single(
+        "frames_dropped_total",
+        "counter",
+        "Frames not sent or received because the interface was down.",
+        interface.counters.frames_dropped,
+    );
+    out.single(
+        "own_frames_received_total",
+        "counter",
+        "Frames received from one of the node's own addresses.",
+        interface.counters.own_frames_received,
+    );
+
+    out.family(
+        "queue_frames_received_total",
+        "counter",
+        "Frames received on each queue of a multi-queue tap.",
+    );
+    for (queue, frames) in interface.queue_frames_received.iter().enumerate() {
+        out.sample(
+            "queue_frames_received_total",
+            &[("queue", &queue.to_string())],
+            frames,
+        );
+    }
+
+    out.family(
+        "dispatch_drops_total",
+        "counter",
+        "Items dropped because a receiver fell behind.",
+    );
+    for (key, drops) in &status.dispatch_drops {
+        let (dispatcher, key) = key.split_once('/').unwrap_or((key, ""));
+        out.sample(
+            "dispatch_drops_total",
+            &[("dispatcher", dispatcher), ("key", key)],
+            drops,
+        );
+    }
+
+    out.family(
+        "ndp_drops_total",
+        "counter",
+        "Received NDP messages discarded by validation.",
+    );
+    for (reason, drops) in &status.ndp_drops {
+        out.sample("ndp_drops_total", &[("reason", reason)], drops);
+    }
+
+    out.family(
+        "icmp_error_drops_total",
+        "counter",
+        "ICMP errors not sent for being over a rate limit.",
+    );
+    for (limit, drops) in &status.icmp_error_drops {
+        out.sample("icmp_error_drops_total", &[("limit", limit)], drops);
+    }
+
+    out.family(
+        "tcp_listen_overflows_total",
+        "counter",
+        "Connections a TCP listener's backlog had no room for.",
+    );
+    for (queue, overflows) in &status.tcp_listen_overflows {
+        out.sample("tcp_listen_overflows_total", &[("queue", queue)], overflows);
+    }
+
+    out.family(
+        "tcp_reaped_total",
+        "counter",
+        "TCP connections reset for being idle or not answering keep-alives.",
+    );
+    for (reason, reaped) in &status.tcp_reaped {
+        out.sample("tcp_reaped_total", &[("reason", reason)], reaped);
+    }
+
+    out.single(
+        "addresses",
+        "gauge",
+        "IPv6 addresses on the interface.",
+        interface.addresses.len(),
+    );
+    out.single(
+        "neighbors",
+        "gauge",
+        "Entries in the neighbor cache.",
+        interface.neighbors.len(),
+    );
+    out.single(
+        "default_routers",
+        "gauge",
+        "Routers in the default router list.",
+        interface.default_routers.len(),
+    );
+
+    let mut flows: BTreeMap<&str, usize> = BTreeMap::new();
+    for flow in &status.flows {
+        *flows.entry(&flow.protocol).or_default() += 1;
+    }
+    out.family(
+        "flows",
+        "gauge",
+        "The node's own TCP connections and UDP flows.",
+    );
+    for (protocol, count) in flows {
+        out.sample("flows", &[("protocol", protocol)], count);
+    }
+
+    out.family(
+        "thread_healthy",
+        "gauge",
+        "Whether each watched thread is keeping up its heartbeat.",
+    );
+    for (thread, health) in &status.threads {
+        out.sample(
+            "thread_healthy",
+            &[("thread", thread)],
+            (*health == Health::Healthy) as u8,
+        );
+    }
+
+    if let Some(resources) = &status.resources {
+        out.single(
+            "memory_bytes",
+            "gauge",
+            "Resident memory of the node's process.",
+            resources.memory_bytes,
+        );
+        out.single(
+            "threads",
+            "gauge",
+            "Threads in the node's process.",
+            resources.threads,
+        );
+        out.single(
+            "channels",
+            "gauge",
+            "Receivers registered with dispatchers.",
+            resources.channels,
+        );
+        out.single(
+            "timers",
+            "gauge",
+            "Entries waiting in delay queues.",
+            resources.timers,
+        );
+    }
+    if let Some(wol) = &status.wake_on_lan {
+        out.single(
+            "wake_on_lan_packets_total",
+            "counter",
+            "Wake-on-LAN magic packets received.",
+            wol.magic_packets,
+        );
+    }
+    if let Some(wireguard) = &status.wireguard {
+        out.single(
+            "wireguard_handshakes_completed_total",
+            "counter",
+            "WireGuard handshakes answered.",
+            wireguard.handshakes_completed,
+        );
+        out.single(
+            "wireguard_handshakes_failed_total",
+            "counter",
+            "WireGuard handshake initiations rejected.",
+            wireguard.handshakes_failed,
+        );
+    }
+
+    out.0
+}
+
+fn respond(stream: TcpStream) -> AHResult<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    // Whatever was asked for, the metrics are the only thing here; the headers aren't needed.
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let body = render(&status::snapshot());
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+        CONTENT_TYPE,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+/// Serve metrics over HTTP at `address`, like `127.0.0.1:9464`.
+pub fn serve(address: &str) -> AHResult<()> {
+    let listener = TcpListener::bind(address).with_context(|| format!("binding {}", address))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.map_err(Into::into).and_then(respond) {
+                warn!("serving metrics failed: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_exposed() {
+        let mut status = Status::default();
+        status.interface.counters.frames_received = 7;
+        status.dispatch_drops.insert("ipv6/udp".to_string(), 2);
+        status
+            .threads
+            .insert("ipv6 on tap0".to_string(), Health::Unresponsive);
+
+        let text = render(&status);
+        assert!(text.contains(
+            "# TYPE fakenet_frames_received_total counter\nfakenet_frames_received_total 7\n"
+        ));
+        assert!(text.contains("fakenet_dispatch_drops_total{dispatcher=\"ipv6\",key=\"udp\"} 2\n"));
+        assert!(text.contains("fakenet_thread_healthy{thread=\"ipv6 on tap0\"} 0\n"));
+        assert!(!text.contains("wireguard"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape("a \"b\"\\\n"), "a \\\"b\\\"\\\\\\n");
+    }
+
+    #[test]
+    fn scrapes_get_a_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || respond(listener.accept().unwrap().0).unwrap());
+
+        let mut client = TcpStream::connect(address).unwrap();
+        write!(client, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut client, &mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("fakenet_frames_sent_total"));
+    }
+}
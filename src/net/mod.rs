@@ -0,0 +1,413 @@
+//! Sockets shaped like `std::net`'s over the fake stack, so embedders can write test servers the
+//! familiar way while everything they send and receive goes through the node's interface.
+//!
+//! These block, each waiting on the channel the stack delivers to. With the `async` feature,
+//! `nonblocking` has the same sockets as futures over the same channels, for async test code.
+//!
+//! Only IPv6 addresses are supported, as that's all the stack speaks.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, SocketAddrV6};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crossbeam::channel::{self, RecvTimeoutError};
+
+use crate::protocols::{ipv6, tcp, udp};
+
+#[cfg(feature = "async")]
+pub mod nonblocking;
+
+fn ipv6_only(addr: SocketAddr) -> io::Result<SocketAddrV6> {
+    match addr {
+        SocketAddr::V6(addr) => Ok(addr),
+        SocketAddr::V4(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} isn't an IPv6 address, which is all the stack supports",
+                addr
+            ),
+        )),
+    }
+}
+
+/// Only `address` when it's given, otherwise any of the node's.
+fn only(address: ipv6::Address) -> Option<ipv6::Prefix> {
+    Some(ipv6::Prefix { address, len: 128 }).filter(|_| address != ipv6::Address::default())
+}
+
+/// Wait for the next thing on `receiver`, like a socket read with `timeout`; `None` when it's
+/// disconnected.
+fn recv<T>(receiver: &channel::Receiver<T>, timeout: Option<Duration>) -> io::Result<Option<T>> {
+    match timeout {
+        Some(timeout) => match receiver.recv_timeout(timeout) {
+            Ok(item) => Ok(Some(item)),
+            Err(RecvTimeoutError::Timeout) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(RecvTimeoutError::Disconnected) => Ok(None),
+        },
+        None => Ok(receiver.recv().ok()),
+    }
+}
+
+/// Like `std::net::UdpSocket`.
+pub struct FakeUdpSocket {
+    socket: udp::Socket,
+    address: ipv6::Address,
+    /// What replies go out from when bound to the unspecified address.
+    last_received_at: Mutex<Option<ipv6::Address>>,
+    read_timeout: Option<Duration>,
+}
+
+impl FakeUdpSocket {
+    /// Bind `addr`, receiving only datagrams sent to its address unless that's unspecified. A
+    /// port of 0 is picked from the ephemeral range, and takes datagrams for any address.
+    pub fn bind(server: &udp::Server, addr: SocketAddr) -> io::Result<Self> {
+        let addr = ipv6_only(addr)?;
+        let address = ipv6::Address::from(*addr.ip());
+
+        let socket = match (addr.port(), only(address)) {
+            (0, _) => server.bind_ephemeral(),
+            (port, Some(prefix)) => server.bind_prefix(port, prefix),
+            (port, None) => server.bind(port),
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::AddrInUse, e.to_string()))?;
+
+        Ok(Self {
+            socket,
+            address,
+            last_received_at: Mutex::new(None),
+            read_timeout: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        SocketAddrV6::new(self.address.into(), self.socket.port(), 0, 0).into()
+    }
+
+    /// Make `recv_from` fail with `WouldBlock` after waiting this long; it waits forever by
+    /// default.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Receive a datagram into `buf`, cutting off whatever doesn't fit, as `std` does.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let datagram = recv(self.socket.receiver(), self.read_timeout)?
+            .ok_or_else(|| io::Error::other("udp server has stopped"))?;
+
+        Ok(self.take(datagram, buf))
+    }
+
+    fn take(&self, datagram: udp::Datagram, buf: &mut [u8]) -> (usize, SocketAddr) {
+        *self.last_received_at.lock().unwrap() = Some(datagram.dest);
+
+        let payload = &datagram.packet.payload;
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload[..len]);
+
+        let src = SocketAddrV6::new(datagram.src.into(), datagram.packet.src_port, 0, 0);
+        (len, src.into())
+    }
+
+    /// Send `buf` to `addr`, from the bound address, or the one the latest datagram arrived at
+    /// when bound to the unspecified address.
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let addr = ipv6_only(addr)?;
+        let src = match only(self.address) {
+            Some(_) => self.address,
+            None => self.last_received_at.lock().unwrap().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "no address to send from until a datagram is received; bind to one instead",
+                )
+            })?,
+        };
+
+        self.socket
+            .send_to(src, (*addr.ip()).into(), addr.port(), buf.to_vec())
+            .map_err(io::Error::other)?;
+
+        Ok(buf.len())
+    }
+}
+
+/// Like `std::net::TcpListener`.
+pub struct FakeTcpListener {
+    listener: tcp::Listener,
+    addr: SocketAddrV6,
+}
+
+impl FakeTcpListener {
+    /// Listen on `addr`, taking only connections to its address unless that's unspecified.
+    pub fn bind(server: &tcp::Server, addr: SocketAddr) -> io::Result<Self> {
+        let addr = ipv6_only(addr)?;
+        if addr.port() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "listening on an ephemeral port isn't supported",
+            ));
+        }
+
+        let listener = match only(ipv6::Address::from(*addr.ip())) {
+            Some(prefix) => server.listen_prefix(addr.port(), prefix),
+            None => server.listen(addr.port()),
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::AddrInUse, e.to_string()))?;
+
+        Ok(Self { listener, addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr.into()
+    }
+
+    pub fn accept(&self) -> io::Result<(FakeTcpStream, SocketAddr)> {
+        let connection = self.listener.accept().map_err(io::Error::other)?;
+
+        Ok(FakeTcpStream::new(connection))
+    }
+
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<FakeTcpStream>> + '_ {
+        std::iter::repeat_with(move || self.accept().map(|(stream, _)| stream))
+    }
+}
+
+/// Like `std::net::TcpStream`, for connections accepted by a `FakeTcpListener`; dropping it
+/// closes the connection.
+pub struct FakeTcpStream {
+    connection: tcp::Connection,
+    /// What's left of the data last received after a short read.
+    pending: Vec<u8>,
+    read_timeout: Option<Duration>,
+}
+
+impl FakeTcpStream {
+    fn new(connection: tcp::Connection) -> (Self, SocketAddr) {
+        let remote = connection.remote().into();
+
+        (
+            Self {
+                connection,
+                pending: Vec::new(),
+                read_timeout: None,
+            },
+            remote,
+        )
+    }
+
+    /// Read what's left of the data last received.
+    fn read_pending(&mut self, buf: &mut [u8]) -> usize {
+        let len = self.pending.len().min(buf.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+
+        len
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.connection.local().into()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.remote().into()
+    }
+
+    /// Make reads fail with `WouldBlock` after waiting this long; they wait forever by default.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+}
+
+impl Read for FakeTcpStream {
+    /// Reads 0 bytes once the peer has closed its side.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match recv(self.connection.receiver(), self.read_timeout)? {
+                Some(data) => self.pending = data,
+                None => return Ok(0),
+            }
+        }
+
+        Ok(self.read_pending(buf))
+    }
+}
+
+impl Write for FakeTcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.connection.send(buf).map_err(io::Error::other)?;
+
+        Ok(buf.len())
+    }
+
+    /// Everything written has already been handed to the stack.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+    use crate::protocols::utils::KeyedDispatcher;
+    use crate::protocols::{ether, ipv4, neighbor};
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+    /// Two nodes, fe80::a and fe80::b, on a link of their own.
+    pub(super) fn nodes() -> (ipv6::Server, ipv6::Server) {
+        let a_ether = "02:00:00:00:00:0a".parse().unwrap();
+        let b_ether = "02:00:00:00:00:0b".parse().unwrap();
+        let (mut a_eth, mut b_eth) = ether::MemoryInterface::pair(a_ether, b_ether);
+
+        // Neither address is really the node's own, so neither would answer a solicitation.
+        let a_neighbors = neighbor::Table::new("a");
+        a_neighbors.learn("fe80::b".parse().unwrap(), b_ether, true);
+        let b_neighbors = neighbor::Table::new("b");
+        b_neighbors.learn("fe80::a".parse().unwrap(), a_ether, true);
+
+        (
+            ipv6::Server::new(&mut a_eth, a_neighbors).unwrap(),
+            ipv6::Server::new(&mut b_eth, b_neighbors).unwrap(),
+        )
+    }
+
+    #[test]
+    fn udp_sockets_exchange_datagrams() {
+        let (mut a_ipv6, mut b_ipv6) = nodes();
+        let a_udp = udp::Server::new(&mut a_ipv6).unwrap();
+        let b_udp = udp::Server::new(&mut b_ipv6).unwrap();
+        a_ipv6.start();
+        b_ipv6.start();
+        let executor = Executor::new("net-test", 1);
+        a_udp.start(&executor);
+        b_udp.start(&executor);
+
+        let mut server = FakeUdpSocket::bind(&b_udp, "[::]:7".parse().unwrap()).unwrap();
+        server.set_read_timeout(TIMEOUT);
+        let mut client = FakeUdpSocket::bind(&a_udp, "[fe80::a]:0".parse().unwrap()).unwrap();
+        client.set_read_timeout(TIMEOUT);
+
+        // Nothing's been received to reply from yet.
+        assert_eq!(
+            server
+                .send_to(b"early", client.local_addr())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::AddrNotAvailable
+        );
+
+        client
+            .send_to(b"ping", "[fe80::b]:7".parse().unwrap())
+            .unwrap();
+        let mut buf = [0; 16];
+        let (len, src) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(src, client.local_addr());
+
+        server.send_to(b"pong", src).unwrap();
+        let mut short = [0; 2];
+        let (len, src) = client.recv_from(&mut short).unwrap();
+        assert_eq!(&short[..len], b"po");
+        assert_eq!(src, "[fe80::b]:7".parse().unwrap());
+    }
+
+    #[test]
+    fn tcp_streams_read_and_write() {
+        let (mut a_ipv6, mut b_ipv6) = nodes();
+        let mut a_tcp = tcp::Server::new(&mut a_ipv6).unwrap();
+        let (segment_sender, segments) = channel::unbounded();
+        b_ipv6.register(
+            ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Tcp),
+            segment_sender,
+        );
+        let b_writer = b_ipv6.writer();
+        a_ipv6.start();
+        b_ipv6.start();
+
+        let listener = FakeTcpListener::bind(&a_tcp, "[::]:23".parse().unwrap()).unwrap();
+        a_tcp.start();
+
+        let a: ipv6::Address = "fe80::a".parse().unwrap();
+        let b: ipv6::Address = "fe80::b".parse().unwrap();
+        let send = |seq, ack, flags, payload: &[u8]| {
+            let segment = tcp::Segment {
+                src_port: 40000,
+                dest_port: 23,
+                seq,
+                ack,
+                flags,
+                window: 0xffff,
+                payload: payload.to_vec(),
+                ..Default::default()
+            };
+            b_writer
+                .send(
+                    ipv6::Packet::builder()
+                        .protocol(ipv4::ProtocolNumber::Tcp)
+                        .hop_limit(64)
+                        .src(b)
+                        .dest(a)
+                        .payload(segment.encode(ipv6::PseudoHeader {
+                            src: b,
+                            dest: a,
+                            length: 0,
+                        }))
+                        .build(),
+                )
+                .unwrap();
+        };
+        let receive = || {
+            let packet: ipv6::Packet = segments.recv_timeout(TIMEOUT.unwrap()).unwrap();
+            tcp::segment(
+                &packet.payload,
+                ipv6::PseudoHeader {
+                    src: packet.src,
+                    dest: packet.dest,
+                    length: 0,
+                },
+            )
+            .unwrap()
+        };
+
+        send(1000, 0, tcp::Segment::SYN, b"");
+        let iss = receive().seq;
+        send(1001, iss.wrapping_add(1), tcp::Segment::ACK, b"hello");
+
+        let (mut stream, remote) = listener.accept().unwrap();
+        stream.set_read_timeout(TIMEOUT);
+        assert_eq!(remote, "[fe80::b]:40000".parse().unwrap());
+        let mut buf = [0; 3];
+        assert_eq!(stream.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+
+        stream.write_all(b"hi").unwrap();
+        let reply = std::iter::repeat_with(receive)
+            .find(|segment| !segment.payload.is_empty())
+            .unwrap();
+        assert_eq!(reply.payload, b"hi");
+
+        send(
+            1006,
+            iss.wrapping_add(3),
+            tcp::Segment::ACK | tcp::Segment::FIN,
+            b"",
+        );
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn tcp_ports_are_listened_on_once() {
+        let (mut a_ipv6, _) = nodes();
+        let a_tcp = tcp::Server::new(&mut a_ipv6).unwrap();
+
+        let listener = FakeTcpListener::bind(&a_tcp, "[::]:23".parse().unwrap()).unwrap();
+        let again = FakeTcpListener::bind(&a_tcp, "[fe80::a]:23".parse().unwrap());
+        assert_eq!(again.err().unwrap().kind(), io::ErrorKind::AddrInUse);
+
+        drop(listener);
+        FakeTcpListener::bind(&a_tcp, "[::]:23".parse().unwrap()).unwrap();
+    }
+}
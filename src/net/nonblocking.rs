@@ -0,0 +1,237 @@
+//! The `net` sockets as futures, which any executor can run, tokio's included. They wait on the
+//! same channels as the blocking ones, and the stack wakes each one's task as it delivers to it.
+
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+
+use crossbeam::channel::{self, TryRecvError};
+
+use super::{FakeTcpListener, FakeTcpStream, FakeUdpSocket};
+use crate::protocols::utils::WakerSlot;
+use crate::protocols::{tcp, udp};
+
+/// The next thing on `receiver`, or `None` once it's disconnected, leaving the task in `waker`
+/// for the stack to wake when there's one to take if there isn't yet.
+fn poll_recv<T>(
+    receiver: &channel::Receiver<T>,
+    waker: &WakerSlot,
+    cx: &mut Context<'_>,
+) -> Poll<Option<T>> {
+    // Set before looking, so anything delivered once we have still wakes the task.
+    waker.set(cx.waker());
+
+    match receiver.try_recv() {
+        Ok(item) => Poll::Ready(Some(item)),
+        Err(TryRecvError::Disconnected) => Poll::Ready(None),
+        Err(TryRecvError::Empty) => Poll::Pending,
+    }
+}
+
+/// Like `FakeUdpSocket`, waiting on futures instead.
+pub struct AsyncFakeUdpSocket {
+    socket: FakeUdpSocket,
+}
+
+impl AsyncFakeUdpSocket {
+    pub fn bind(server: &udp::Server, addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            socket: FakeUdpSocket::bind(server, addr)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr()
+    }
+
+    pub fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        poll_recv(
+            self.socket.socket.receiver(),
+            self.socket.socket.waker(),
+            cx,
+        )
+        .map(|datagram| match datagram {
+            Some(datagram) => Ok(self.socket.take(datagram, buf)),
+            None => Err(io::Error::other("udp server has stopped")),
+        })
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        poll_fn(|cx| self.poll_recv_from(cx, buf)).await
+    }
+
+    /// Sending never waits, so this is the same as `FakeUdpSocket::send_to`.
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, addr)
+    }
+}
+
+impl Drop for AsyncFakeUdpSocket {
+    fn drop(&mut self) {
+        self.socket.socket.waker().clear();
+    }
+}
+
+/// Like `FakeTcpListener`, waiting on futures instead.
+pub struct AsyncFakeTcpListener {
+    listener: FakeTcpListener,
+}
+
+impl AsyncFakeTcpListener {
+    pub fn bind(server: &tcp::Server, addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            listener: FakeTcpListener::bind(server, addr)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr()
+    }
+
+    pub fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(AsyncFakeTcpStream, SocketAddr)>> {
+        poll_recv(
+            self.listener.listener.receiver(),
+            self.listener.listener.waker(),
+            cx,
+        )
+        .map(|connection| match connection {
+            Some(connection) => {
+                let (stream, remote) = FakeTcpStream::new(connection);
+                Ok((AsyncFakeTcpStream { stream }, remote))
+            }
+            None => Err(io::Error::other("tcp server has stopped")),
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<(AsyncFakeTcpStream, SocketAddr)> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+}
+
+impl Drop for AsyncFakeTcpListener {
+    fn drop(&mut self) {
+        self.listener.listener.waker().clear();
+    }
+}
+
+/// Like `FakeTcpStream`, waiting on futures instead.
+pub struct AsyncFakeTcpStream {
+    stream: FakeTcpStream,
+}
+
+impl AsyncFakeTcpStream {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.stream.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.stream.peer_addr()
+    }
+
+    /// Reads 0 bytes once the peer has closed its side.
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.stream.pending.is_empty() {
+            match poll_recv(
+                self.stream.connection.receiver(),
+                self.stream.connection.waker(),
+                cx,
+            ) {
+                Poll::Ready(Some(data)) => self.stream.pending = data,
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(Ok(self.stream.read_pending(buf)))
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    /// Writing never waits, as everything written is handed straight to the stack.
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        io::Write::write_all(&mut self.stream, buf)
+    }
+}
+
+impl Drop for AsyncFakeTcpStream {
+    fn drop(&mut self) {
+        self.stream.connection.waker().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+    use crate::net::tests::nodes;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+    use std::thread;
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Run `future` on this thread, parking it until woken.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn futures_are_woken_by_the_stack() {
+        let (mut a_ipv6, mut b_ipv6) = nodes();
+        let a_udp = udp::Server::new(&mut a_ipv6).unwrap();
+        let b_udp = udp::Server::new(&mut b_ipv6).unwrap();
+        a_ipv6.start();
+        b_ipv6.start();
+        let executor = Executor::new("nonblocking-test", 1);
+        a_udp.start(&executor);
+        b_udp.start(&executor);
+
+        let server = AsyncFakeUdpSocket::bind(&b_udp, "[::]:7".parse().unwrap()).unwrap();
+        let client = FakeUdpSocket::bind(&a_udp, "[fe80::a]:0".parse().unwrap()).unwrap();
+        let client_addr = client.local_addr();
+
+        let mut buf = [0; 16];
+        let (len, src) = {
+            let mut recv_from = pin!(server.recv_from(&mut buf));
+
+            // Sent only once the server is already waiting.
+            let waker = Waker::from(Arc::new(Unpark(thread::current())));
+            let mut cx = Context::from_waker(&waker);
+            assert!(recv_from.as_mut().poll(&mut cx).is_pending());
+            client
+                .send_to(b"ping", "[fe80::b]:7".parse().unwrap())
+                .unwrap();
+
+            block_on(recv_from).unwrap()
+        };
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(src, client_addr);
+    }
+}
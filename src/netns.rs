@@ -0,0 +1,246 @@
+//! An isolated network for the node to talk to: a new network namespace, joined to the host by a
+//! veth pair whose host end the node attaches to, so tests don't need their own setup scripts.
+
+use anyhow::{bail, Context, Result as AHResult};
+use serde::Deserialize;
+use std::fs;
+use std::process::Command;
+
+use crate::warn;
+
+/// Longest interface name the kernel takes, without the terminating nul.
+const MAX_IF_NAME_LEN: usize = 15;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    /// Name of the namespace, as `ip netns` knows it.
+    pub name: String,
+    /// The end of the veth pair left on the host, which the node attaches to.
+    #[serde(default = "default_host_interface")]
+    pub host_interface: String,
+    /// The end of the veth pair moved into the namespace.
+    #[serde(default = "default_peer_interface")]
+    pub peer_interface: String,
+    /// Addresses, with prefix lengths, given to the peer end.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// Leave the namespace and veth pair behind on exit, e.g. to poke at them afterwards.
+    #[serde(default)]
+    pub keep: bool,
+}
+
+fn default_host_interface() -> String {
+    "fakenet0".to_string()
+}
+
+fn default_peer_interface() -> String {
+    "eth0".to_string()
+}
+
+impl Config {
+    pub fn validate(&self) -> AHResult<()> {
+        if self.name.is_empty() || self.name.contains('/') {
+            bail!("invalid network namespace name \"{}\"", self.name);
+        }
+
+        for if_name in &[&self.host_interface, &self.peer_interface] {
+            if if_name.is_empty() || if_name.len() > MAX_IF_NAME_LEN {
+                bail!(
+                    "interface name \"{}\" must be 1 to {} bytes long",
+                    if_name,
+                    MAX_IF_NAME_LEN
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `ip` invocations that set up the namespace, in order.
+    fn setup_commands(&self) -> Vec<Vec<String>> {
+        let mut commands = vec![
+            vec!["netns", "add", &self.name],
+            vec![
+                "link",
+                "add",
+                &self.host_interface,
+                "type",
+                "veth",
+                "peer",
+                "name",
+                &self.peer_interface,
+                "netns",
+                &self.name,
+            ],
+            vec!["-n", &self.name, "link", "set", "lo", "up"],
+        ];
+        for address in &self.addresses {
+            commands.push(vec![
+                "-n",
+                &self.name,
+                "addr",
+                "add",
+                address,
+                "dev",
+                &self.peer_interface,
+            ]);
+        }
+        commands.push(vec![
+            "-n",
+            &self.name,
+            "link",
+            "set",
+            &self.peer_interface,
+            "up",
+        ]);
+
+        commands
+            .into_iter()
+            .map(|command| command.into_iter().map(String::from).collect())
+            .collect()
+    }
+}
+
+fn ip<S: AsRef<str>>(args: &[S]) -> AHResult<()> {
+    let args: Vec<_> = args.iter().map(AsRef::as_ref).collect();
+    let output = Command::new("ip")
+        .args(&args)
+        .output()
+        .context("failed to run ip")?;
+
+    if !output.status.success() {
+        bail!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// A namespace set up from a `Config`, torn down again when dropped unless it's kept.
+pub struct Namespace {
+    config: Config,
+    keep: bool,
+}
+
+impl Namespace {
+    pub fn create(config: Config) -> AHResult<Self> {
+        if cfg!(not(target_os = "linux")) {
+            bail!("network namespaces are only supported on Linux");
+        }
+        config.validate()?;
+
+        // A namespace that's already there is someone else's, so only ours gets torn down.
+        let commands = config.setup_commands();
+        ip(&commands[0])?;
+
+        // Torn down on failure, so a half-made namespace doesn't get in the next attempt's way.
+        let mut namespace = Self {
+            keep: false,
+            config,
+        };
+        for command in &commands[1..] {
+            ip(command)?;
+        }
+
+        // The host end only carries the node's frames, so the host shouldn't talk on it itself.
+        let disable_ipv6 = format!(
+            "/proc/sys/net/ipv6/conf/{}/disable_ipv6",
+            namespace.config.host_interface
+        );
+        if let Err(e) = fs::write(&disable_ipv6, "1") {
+            warn!("writing {} failed: {}", disable_ipv6, e);
+        }
+
+        namespace.keep = namespace.config.keep;
+        Ok(namespace)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// The end of the veth pair to attach the node to.
+    pub fn host_interface(&self) -> &str {
+        &self.config.host_interface
+    }
+
+    /// Open the host end of the veth pair, for the node to exchange frames over.
+    #[cfg(target_os = "linux")]
+    pub fn open_device(&self) -> AHResult<Box<dyn crate::device::Device>> {
+        Ok(Box::new(crate::packet_device::PacketDevice::open(
+            &self.config.host_interface,
+        )?))
+    }
+
+    /// Open the host end of the veth pair, for the node to exchange frames over.
+    #[cfg(not(target_os = "linux"))]
+    pub fn open_device(&self) -> AHResult<Box<dyn crate::device::Device>> {
+        bail!("network namespaces are only supported on Linux")
+    }
+}
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+
+        // Deleting either end of a veth pair deletes both; the namespace might not have got one.
+        let _ = ip(&["link", "del", &self.config.host_interface]);
+        if let Err(e) = ip(&["netns", "del", &self.config.name]) {
+            warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+                name = "fakenet-test"
+                addresses = ["2001:db8::2/64", "192.0.2.2/24"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn setup_moves_the_peer_in_and_configures_it() {
+        let commands: Vec<_> = config()
+            .setup_commands()
+            .into_iter()
+            .map(|command| command.join(" "))
+            .collect();
+
+        assert_eq!(
+            commands,
+            [
+                "netns add fakenet-test",
+                "link add fakenet0 type veth peer name eth0 netns fakenet-test",
+                "-n fakenet-test link set lo up",
+                "-n fakenet-test addr add 2001:db8::2/64 dev eth0",
+                "-n fakenet-test addr add 192.0.2.2/24 dev eth0",
+                "-n fakenet-test link set eth0 up",
+            ]
+        );
+    }
+
+    #[test]
+    fn names_are_checked() {
+        assert!(config().validate().is_ok());
+
+        let mut long_name = config();
+        long_name.host_interface = "fakenet-host-end".to_string();
+        assert!(long_name.validate().is_err());
+
+        let mut path = config();
+        path.name = "../etc".to_string();
+        assert!(path.validate().is_err());
+    }
+}
@@ -0,0 +1,508 @@
+//! Running a node: opening its interface, starting the protocols its config asks for, and serving
+//! control commands until told to stop.
+
+use anyhow::{anyhow, bail, Result as AHResult};
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{SigSet, Signal};
+
+use crate::config::Network;
+use crate::{
+    cli, clock, control, device, executor, expect, identity, info, metrics, netns, pcap,
+    privileges, protocols, resources, services, stats, status, supervisor, watchdog,
+};
+
+pub fn shutdown_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+
+    signals
+}
+
+pub fn run(network: Network, options: &cli::Options) -> AHResult<()> {
+    // Blocked before any threads start, so they all inherit it and only the wait below sees them.
+    shutdown_signals().thread_block()?;
+
+    supervisor::set_policy(network.restart.clone());
+    status::set_output(network.status.clone());
+    clock::set(network.node.clock);
+    let executor = executor::Executor::new("actors", network.actor_threads);
+    let hw_address = network.node.ether_address()?;
+    // Kept until exit, then torn down along with the veth pair.
+    let namespace = match &network.netns {
+        Some(config) => Some(netns::Namespace::create(config.clone())?),
+        None => None,
+    };
+    // Started only once privileges are dropped, as it parses the frames it forwards.
+    let mut bridge = None;
+    let device = match (&network.bridge, &network.node.socket, &namespace) {
+        (Some(config), _, _) => {
+            let (opened, device) = config.open()?;
+            bridge = Some(opened);
+            device
+        }
+        (None, Some(socket), _) => socket.open()?,
+        (None, None, Some(namespace)) => namespace.open_device()?,
+        (None, None, None) => device::open(network.node.tap_queues)?,
+    };
+    let mut eth = protocols::ether::TapInterface::with_device(hw_address, device)?;
+    eth.set_down_on_drop(!network.node.keep_up_on_exit);
+    if network.node.set_kernel_ether_address {
+        eth.set_kernel_address()?;
+    }
+    let if_name = eth.if_name()?;
+    info!("node {} attached to {}", hw_address, if_name);
+    let neighbors = protocols::neighbor::Table::new(&if_name);
+    let mut identity = match &network.node.hostname {
+        Some(hostname) => identity::Identity::new(hostname)?,
+        None => identity::Identity::from_ether_address(hw_address),
+    };
+    identity.personality = network.node.personality;
+    status::update(|s| {
+        s.hostname = Some(identity.fqdn());
+        s.interface.name = Some(if_name.clone());
+        s.interface.ether_address = Some(hw_address);
+        s.interface.netns = namespace.as_ref().map(|n| n.name().to_string());
+    });
+    identity::set(identity);
+
+    eth.set_own_frames(network.node.own_frames.action);
+    for &address in &network.node.own_frames.addresses {
+        eth.add_own_address(address);
+    }
+
+    if let Some(shaping) = network.node.shaping {
+        eth.set_shaper(protocols::shaping::Shaper::new(shaping, device::FRAME_SIZE));
+    }
+
+    let admin = eth.admin();
+    let set_link = network.node.set_link_down;
+    // Bringing the tap up in `start()` would undo taking the link down, so that waits until
+    // afterwards.
+    admin.set(network.node.admin_state, false)?;
+
+    let wol_server = protocols::wol::Server::new(&mut eth)?;
+    let wake_receiver = wol_server.start(&executor);
+    {
+        let admin = admin.clone();
+        thread::spawn(move || {
+            for _ in wake_receiver {
+                if admin.get() == protocols::ether::AdminState::Sleeping {
+                    admin
+                        .set(protocols::ether::AdminState::Up, set_link)
+                        .unwrap();
+                }
+            }
+        });
+    }
+
+    let (hop_limit, ttl) = (network.node.hop_limit(), network.node.ttl());
+    let schedule = network.node.power_schedule;
+    if !schedule.steps.is_empty() {
+        let admin = admin.clone();
+        thread::spawn(move || loop {
+            for step in &schedule.steps {
+                thread::sleep(Duration::from_secs_f64(step.after));
+                admin.set(step.state, set_link).unwrap();
+            }
+
+            if !schedule.repeat {
+                break;
+            }
+        });
+    }
+
+    let misbehavior = &network.node.misbehavior;
+    let mut arp_server = None;
+    if network.node.ipv4_address.is_some()
+        || !misbehavior.arp_claim.is_empty()
+        || misbehavior.arp_answer_all
+        || !misbehavior.ipv4_sweep()?.is_empty()
+    {
+        let server = protocols::arp::Server::new(&mut eth, neighbors.clone())?;
+        for address in network
+            .node
+            .ipv4_address
+            .iter()
+            .chain(&misbehavior.arp_claim)
+        {
+            server.add(address.parse()?);
+        }
+        server.set_answer_all(misbehavior.arp_answer_all);
+        server.set_sweep(misbehavior.ipv4_sweep()?);
+        for policy in &network.node.answer_policies {
+            if policy.address.is_ipv4() {
+                server.set_answer_policy(policy.clone());
+            }
+        }
+        server.start(&executor);
+        arp_server = Some(server);
+    }
+
+    for recording in &network.node.recordings {
+        services::replay::record(
+            &eth.observe_handle(),
+            recording.protocol,
+            recording.port,
+            &recording.path,
+        )?;
+    }
+
+    if let Some(pppoe) = &network.node.pppoe {
+        protocols::pppoe::Server::new(&mut eth, pppoe.config()?)?.start();
+    }
+
+    let mut ipv6_server = protocols::ipv6::Server::new(&mut eth, neighbors.clone())?;
+    for ipv6_address in &network.node.ipv6_addresses {
+        ipv6_server.add_address(ipv6_address.address(hw_address)?, ipv6_address.lifetimes());
+    }
+    ipv6_server.set_misbehavior(misbehavior.ipv6()?);
+    ipv6_server.set_interface_ids(network.node.interface_ids.clone());
+    ipv6_server.set_error_rate_limit(network.node.icmp_rate_limit);
+    ipv6_server.set_hop_limit(hop_limit);
+    status::update(|s| s.interface.ttl = Some(ttl));
+    for prefix in network.node.services.iter().filter_map(|s| s.bind) {
+        ipv6_server.add_prefix(prefix);
+    }
+    for policy in &network.node.answer_policies {
+        if policy.address.is_ipv6() {
+            ipv6_server.set_answer_policy(policy.clone());
+        }
+    }
+    ipv6_server.set_unsolicited_advertisements(!network.node.quiet_neighbor_caches);
+    ipv6_server.watch_admin_state(admin.watch());
+    ipv6_server.start();
+
+    let flow_table = protocols::conntrack::FlowTable::new();
+
+    let mut udp_server = protocols::udp::Server::new(&mut ipv6_server)?;
+    udp_server.set_port_policy(network.node.ports.udp);
+    udp_server.set_flow_table(flow_table.clone());
+    for (protocol, port, dscp) in network.node.services.iter().filter_map(|s| s.marking()) {
+        if protocol == services::replay::Protocol::Udp {
+            udp_server.set_port_dscp(port, dscp);
+        }
+    }
+    udp_server.start(&executor);
+
+    // Kept until exit, along with the stacks running on them.
+    let mut tunnels = Vec::new();
+    let mut underlay = protocols::tunnel::Underlay {
+        eth: &mut eth,
+        arp_server: arp_server.as_ref(),
+        ipv6_server: &mut ipv6_server,
+        udp_server: &udp_server,
+        ttl,
+    };
+    for (i, tunnel) in network.node.tunnels.iter().enumerate() {
+        tunnels.push(tunnel.start(
+            &format!("tunnel{}", i),
+            &if_name,
+            &network.node.interface_ids,
+            &mut underlay,
+        )?);
+    }
+
+    if let Some(wireguard) = &network.node.wireguard {
+        protocols::wireguard::Server::new(wireguard.config()?, &udp_server, wireguard.port)?
+            .start();
+    }
+
+    if let Some(flow_export) = &network.node.flow_export {
+        protocols::flow_export::Exporter::new(flow_export.config()?, &eth, &udp_server).start();
+    }
+
+    if let Some(inventory) = &network.node.inventory {
+        protocols::inventory::Inventory::new(hw_address).start(inventory.clone(), &eth);
+    }
+
+    let resolver = match &network.node.dns {
+        Some(dns) => Some(protocols::dns::Resolver::new(
+            dns.config(&if_name)?,
+            &udp_server,
+            Some(ipv6_server.dns()),
+        )?),
+        None => None,
+    };
+
+    let mut tcp_server = protocols::tcp::Server::new(&mut ipv6_server)?;
+    tcp_server.set_port_policy(network.node.ports.tcp);
+    tcp_server.set_fingerprint(network.node.personality.tcp());
+    tcp_server.set_backlog(network.node.tcp_backlog);
+    tcp_server.set_idle(network.node.tcp_idle);
+    tcp_server.add_flow_hook(flow_table.tcp_hook());
+    for (protocol, port, dscp) in network.node.services.iter().filter_map(|s| s.marking()) {
+        if protocol == services::replay::Protocol::Tcp {
+            tcp_server.set_port_dscp(port, dscp);
+        }
+    }
+    if let Some(interference) = &misbehavior.tcp_interference {
+        tcp_server.add_flow_hook(interference.policy().hook());
+    }
+    for service in network.node.services {
+        services::start(service, &tcp_server, &udp_server)?;
+    }
+    tcp_server.start();
+    flow_table.start(tcp_server.flows());
+
+    let mut sctp_server = protocols::sctp::Server::new(&mut ipv6_server)?;
+    sctp_server.set_port_policy(network.node.ports.sctp);
+    for port in &network.node.sctp_ports {
+        sctp_server.listen(*port);
+    }
+    sctp_server.start(&executor);
+
+    let observed_policy = misbehavior
+        .tcp_interference
+        .as_ref()
+        .filter(|i| i.observed)
+        .map(|i| i.policy());
+    let interferer = if observed_policy.is_some() || network.control_socket.is_some() {
+        let interferer = protocols::tcp::interference::Interferer::new(&eth, observed_policy)?;
+        interferer.start(&eth.observe_handle());
+        Some(interferer)
+    } else {
+        None
+    };
+
+    if let Some(path) = network.control_socket {
+        let mut control_server = control::Server::new();
+        add_control_commands(
+            &mut control_server,
+            neighbors,
+            eth.observe_handle(),
+            arp_server,
+            resolver,
+        );
+        add_tcp_commands(
+            &mut control_server,
+            tcp_server.flows(),
+            flow_table,
+            interferer,
+        );
+        control_server.start(&path)?;
+    }
+
+    if let Some(address) = &network.metrics_address {
+        metrics::serve(address)?;
+    }
+
+    if let Some(path) = &options.capture {
+        pcap::capture(path, &eth.observe_handle())?;
+    }
+    if let Some(privileges) = &network.privileges {
+        // Bringing the device up needs privileges too, so `start()` finds it already up.
+        eth.up()?;
+        privileges::drop(privileges)?;
+        info!("switched to user {}", privileges.user);
+        eth.set_down_on_drop(false);
+    }
+    if let Some(bridge) = bridge {
+        bridge.start()?;
+    }
+    eth.start()?;
+    if set_link {
+        admin.set(network.node.admin_state, true)?;
+    }
+    watchdog::start(network.watchdog.clone());
+    resources::start(network.limits.clone());
+    stats::start();
+
+    shutdown_signals().wait()?;
+    status::drain();
+
+    // Taking the device down needs the privileges that were dropped.
+    if network.node.keep_up_on_exit || network.privileges.is_some() {
+        return Ok(());
+    }
+
+    eth.close()
+}
+
+/// Longest a `capture` command waits for the frames it asked for.
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn add_control_commands(
+    server: &mut control::Server,
+    neighbors: protocols::neighbor::Table,
+    observer: protocols::ether::ObserveHandle,
+    arp_server: Option<protocols::arp::Server>,
+    resolver: Option<protocols::dns::Resolver>,
+) {
+    server.add("show status", |_| {
+        Ok(serde_json::to_value(status::snapshot())?)
+    });
+
+    server.add("show neighbors", move |_| {
+        Ok(neighbors
+            .neighbors()
+            .into_iter()
+            .map(|n| {
+                serde_json::json!({
+                    "address": n.address,
+                    "ether_address": n.ether_address.to_string(),
+                    "state": n.state,
+                    "last_seen_secs_ago": n.last_seen.elapsed().map_or(0.0, |d| d.as_secs_f64()),
+                    "interface": n.interface,
+                })
+            })
+            .collect())
+    });
+
+    let expectations = expect::Expectations::new(&observer);
+    server.add("expect", move |args| {
+        let (within, filter) = match args {
+            [within, filter @ ..] => (within.parse::<f64>()?, filter.join(" ")),
+            _ => bail!("usage: expect SECONDS [FILTER]"),
+        };
+        if !within.is_finite() || within < 0.0 {
+            bail!("SECONDS must be 0 or more, not {}", within);
+        }
+
+        let packet = expectations
+            .expect(
+                format!("a frame matching \"{}\"", filter),
+                Duration::from_secs_f64(within),
+                expect::filter(filter.parse()?),
+            )
+            .wait()?;
+
+        Ok(serde_json::json!({
+            "summary": packet.summary(),
+            "frame": hex::encode(packet.frame.encode()),
+        }))
+    });
+
+    server.add("capture", move |args| {
+        let (count, filter) = match args {
+            [count, filter @ ..] => (count.parse::<usize>()?, filter.join(" ").parse()?),
+            _ => bail!("usage: capture COUNT [FILTER]"),
+        };
+
+        let receiver = observer.observe(1, filter);
+        let deadline = std::time::Instant::now() + CAPTURE_TIMEOUT;
+
+        Ok(std::iter::from_fn(|| receiver.recv_deadline(deadline).ok())
+            .take(count)
+            .map(|frame| {
+                serde_json::json!({
+                    "src": frame.src.to_string(),
+                    "dest": frame.dest.to_string(),
+                    "ethertype": frame.ethertype.to_string(),
+                    "payload": hex::encode(&frame.payload),
+                })
+            })
+            .collect())
+    });
+
+    if let Some(arp_server) = arp_server {
+        let adder = arp_server.clone();
+        server.add("arp add", move |args| match args {
+            [address] => {
+                adder.add(address.parse()?);
+                Ok(serde_json::Value::Null)
+            }
+            _ => bail!("usage: arp add IPV4_ADDRESS"),
+        });
+
+        server.add("arp remove", move |args| match args {
+            [address] => {
+                arp_server.remove(address.parse()?);
+                Ok(serde_json::Value::Null)
+            }
+            _ => bail!("usage: arp remove IPV4_ADDRESS"),
+        });
+    }
+
+    if let Some(resolver) = resolver {
+        server.add("resolve", move |args| {
+            let (name, record_type) = match args {
+                [name] | [name, "aaaa"] => (name, protocols::dns::RecordType::Aaaa),
+                [name, "a"] => (name, protocols::dns::RecordType::A),
+                _ => bail!("usage: resolve NAME [a|aaaa]"),
+            };
+
+            Ok(serde_json::to_value(resolver.resolve(name, record_type)?)?)
+        });
+    }
+}
+
+/// Commands for listing the node's flows, and interrupting TCP connections.
+fn add_tcp_commands(
+    server: &mut control::Server,
+    tcp_flows: protocols::tcp::FlowHandle,
+    flow_table: protocols::conntrack::FlowTable,
+    interferer: Option<protocols::tcp::interference::Interferer>,
+) {
+    let table_tcp_flows = tcp_flows.clone();
+    server.add("show flows", move |_| {
+        Ok(serde_json::to_value(flow_table.snapshot(&table_tcp_flows))?)
+    });
+
+    let connections = tcp_flows.clone();
+    server.add("tcp connections", move |_| {
+        Ok(connections
+            .connections()?
+            .into_iter()
+            .map(|(key, state)| {
+                serde_json::json!({
+                    "local": key.local.to_string(),
+                    "remote": key.remote.to_string(),
+                    "state": state,
+                })
+            })
+            .collect())
+    });
+
+    if let Some(interferer) = &interferer {
+        let interferer = interferer.clone();
+        server.add("tcp flows", move |_| {
+            Ok(interferer
+                .flows()
+                .into_iter()
+                .map(|flow| {
+                    serde_json::json!({
+                        "local": flow.key.local.to_string(),
+                        "remote": flow.key.remote.to_string(),
+                        "bytes": flow.bytes,
+                    })
+                })
+                .collect())
+        });
+    }
+
+    for (command, interruption) in [
+        ("tcp reset", protocols::tcp::Interruption::Reset),
+        ("tcp fin", protocols::tcp::Interruption::Fin),
+    ] {
+        let tcp_flows = tcp_flows.clone();
+        let interferer = interferer.clone();
+
+        server.add(command, move |args| {
+            let key = match args {
+                [local, remote] => protocols::tcp::ConnectionKey {
+                    local: local.parse()?,
+                    remote: remote.parse()?,
+                },
+                _ => bail!(
+                    "usage: {} [LOCAL_ADDRESS]:PORT [REMOTE_ADDRESS]:PORT",
+                    command
+                ),
+            };
+
+            // Our own connections are interrupted by the TCP server, to keep its state right.
+            if tcp_flows.connections()?.iter().any(|(k, _)| *k == key) {
+                tcp_flows.interrupt(key, interruption)?;
+            } else {
+                interferer
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("not tracking observed flows"))?
+                    .interrupt(key, interruption)?;
+            }
+
+            Ok(serde_json::Value::Null)
+        });
+    }
+}
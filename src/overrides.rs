@@ -0,0 +1,166 @@
+//! Changing single config values from the command line or environment, applied to the parsed file
+//! before it's turned into a config, so one address can change without templating the whole file.
+
+use anyhow::{anyhow, bail, Result as AHResult};
+use std::str::FromStr;
+use toml::Value;
+
+const ENV_PREFIX: &str = "FAKENET_";
+
+/// A value to put at a dotted path, like `node.ipv4_address=192.0.2.5`; numbers in the path index
+/// into arrays.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Override {
+    pub path: Vec<String>,
+    pub value: Value,
+}
+
+/// A TOML value, or the text as a string if it isn't one, so addresses don't need quoting.
+fn parse_value(text: &str) -> Value {
+    format!("value = {}", text)
+        .parse::<Value>()
+        .ok()
+        .and_then(|mut table| table.as_table_mut()?.remove("value"))
+        .unwrap_or_else(|| Value::String(text.to_string()))
+}
+
+impl FromStr for Override {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("override {} should be in path=value form", s))?;
+        let path: Vec<_> = path.trim().split('.').map(String::from).collect();
+        if path.iter().any(String::is_empty) {
+            bail!("override path {} has an empty key", s);
+        }
+
+        Ok(Self {
+            path,
+            value: parse_value(value.trim()),
+        })
+    }
+}
+
+/// Overrides from variables like `FAKENET_NODE__IPV4_ADDRESS`, with `__` between keys.
+pub fn from_env(vars: impl IntoIterator<Item = (String, String)>) -> Vec<Override> {
+    vars.into_iter()
+        .filter_map(|(name, value)| {
+            let path = name.strip_prefix(ENV_PREFIX)?;
+
+            Some(Override {
+                path: path.split("__").map(str::to_ascii_lowercase).collect(),
+                value: parse_value(&value),
+            })
+        })
+        .collect()
+}
+
+impl Override {
+    /// Put the value in `config`, creating any tables along the way.
+    pub fn apply(&self, config: &mut Value) -> AHResult<()> {
+        let mut current = config;
+
+        for (i, key) in self.path.iter().enumerate() {
+            let here = &self.path[..i];
+            let last = i == self.path.len() - 1;
+
+            current = match current {
+                Value::Table(table) => {
+                    if last {
+                        table.insert(key.clone(), self.value.clone());
+                        return Ok(());
+                    }
+
+                    table
+                        .entry(key.clone())
+                        .or_insert_with(|| Value::Table(Default::default()))
+                }
+                Value::Array(array) => {
+                    let index: usize = key
+                        .parse()
+                        .map_err(|_| anyhow!("{} is an array, not a table", here.join(".")))?;
+                    let len = array.len();
+                    let element = array
+                        .get_mut(index)
+                        .ok_or_else(|| anyhow!("{} only has {} elements", here.join("."), len))?;
+                    if last {
+                        *element = self.value.clone();
+                        return Ok(());
+                    }
+
+                    element
+                }
+                _ => bail!("{} isn't a table", here.join(".")),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_toml_or_strings() {
+        let address: Override = "node.ipv4_address=192.0.2.5".parse().unwrap();
+        assert_eq!(address.path, ["node", "ipv4_address"]);
+        assert_eq!(address.value, Value::String("192.0.2.5".to_string()));
+
+        let queues: Override = "node.tap_queues = 4".parse().unwrap();
+        assert_eq!(queues.value, Value::Integer(4));
+
+        assert!("node.tap_queues".parse::<Override>().is_err());
+        assert!("node..tap_queues=4".parse::<Override>().is_err());
+    }
+
+    #[test]
+    fn env_names_are_split_on_double_underscores() {
+        let overrides = from_env(vec![
+            ("HOME".to_string(), "/root".to_string()),
+            (
+                "FAKENET_NODE__IPV4_ADDRESS".to_string(),
+                "192.0.2.5".to_string(),
+            ),
+        ]);
+
+        assert_eq!(
+            overrides,
+            [Override {
+                path: vec!["node".to_string(), "ipv4_address".to_string()],
+                value: Value::String("192.0.2.5".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn applying_creates_tables_and_indexes_arrays() {
+        let mut config: Value = "[node]\nipv6_addresses = [{ address = \"2001:db8::1\" }]"
+            .parse()
+            .unwrap();
+
+        for text in &[
+            "node.ipv6_addresses.0.address=2001:db8::2",
+            "node.dns.source=2001:db8::2",
+        ] {
+            text.parse::<Override>()
+                .unwrap()
+                .apply(&mut config)
+                .unwrap();
+        }
+        assert_eq!(
+            config["node"]["ipv6_addresses"][0]["address"].as_str(),
+            Some("2001:db8::2")
+        );
+        assert_eq!(
+            config["node"]["dns"]["source"].as_str(),
+            Some("2001:db8::2")
+        );
+
+        let out_of_range: Override = "node.ipv6_addresses.1.address=2001:db8::3".parse().unwrap();
+        assert!(out_of_range.apply(&mut config).is_err());
+    }
+}
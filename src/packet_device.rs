@@ -0,0 +1,265 @@
+//! Backend for an existing interface, such as the host end of a veth pair: frames are exchanged
+//! through an `AF_PACKET` socket bound to it.
+
+use anyhow::{bail, Result as AHResult};
+use libc::c_char;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::device::{Device, FRAME_SIZE};
+use crate::tap_device::tun_sys;
+use crate::warn;
+
+/// `sll_pkttype` of frames the interface sent, rather than received.
+const PACKET_OUTGOING: u8 = 4;
+/// Gets the receive time of the last frame read from a socket, as a `timespec`.
+const SIOCGSTAMPNS: libc::c_ulong = 0x8907;
+
+pub struct PacketDevice {
+    if_name: String,
+    socket_fd: RawFd,
+    ctl_sock_fd: RawFd,
+    buffer: Vec<u8>,
+    down_on_drop: bool,
+}
+
+fn check(result: libc::c_int) -> AHResult<libc::c_int> {
+    if result == -1 {
+        bail!(io::Error::last_os_error());
+    }
+
+    Ok(result)
+}
+
+impl PacketDevice {
+    /// Open a socket on the interface named `if_name`, receiving everything that arrives on it.
+    pub fn open(if_name: &str) -> AHResult<Self> {
+        let c_name = CString::new(if_name)?;
+        let if_index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+        if if_index == 0 {
+            bail!("no interface named {}", if_name);
+        }
+
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let socket_fd =
+            check(unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol as i32) })?;
+        let ctl_sock_fd = check(unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) })
+            .inspect_err(|_| unsafe {
+                libc::close(socket_fd);
+            })?;
+
+        // Closes both sockets if anything below fails, leaving the interface alone.
+        let mut device = Self {
+            if_name: if_name.to_string(),
+            socket_fd,
+            ctl_sock_fd,
+            buffer: Vec::new(),
+            down_on_drop: false,
+        };
+
+        unsafe {
+            let mut address: libc::sockaddr_ll = mem::zeroed();
+            address.sll_family = libc::AF_PACKET as u16;
+            address.sll_protocol = protocol;
+            address.sll_ifindex = if_index as i32;
+            check(libc::bind(
+                socket_fd,
+                &address as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as u32,
+            ))?;
+
+            // Frames for the node's own address wouldn't otherwise get past the interface.
+            let mut membership: libc::packet_mreq = mem::zeroed();
+            membership.mr_ifindex = if_index as i32;
+            membership.mr_type = libc::PACKET_MR_PROMISC as u16;
+            check(libc::setsockopt(
+                socket_fd,
+                libc::SOL_PACKET,
+                libc::PACKET_ADD_MEMBERSHIP,
+                &membership as *const _ as *const libc::c_void,
+                mem::size_of::<libc::packet_mreq>() as u32,
+            ))?;
+
+            // Have the kernel stamp frames as they arrive, for `last_timestamp`.
+            let on: libc::c_int = 1;
+            check(libc::setsockopt(
+                socket_fd,
+                libc::SOL_SOCKET,
+                libc::SO_TIMESTAMPNS,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as u32,
+            ))?;
+        }
+        device.down_on_drop = true;
+
+        Ok(device)
+    }
+
+    fn new_ifreq(&self) -> tun_sys::IfReq {
+        let mut ifr: tun_sys::IfReq = unsafe { mem::zeroed() };
+
+        // `if_nametoindex` already checked the name fits.
+        for (i, byte) in self.if_name.bytes().enumerate() {
+            unsafe { ifr.ifrn.name[i] = byte as c_char };
+        }
+
+        ifr
+    }
+
+    /// When the kernel received the frame last read, or now if it can't say.
+    fn last_timestamp(&self) -> SystemTime {
+        let mut stamp: libc::timespec = unsafe { mem::zeroed() };
+        if unsafe { libc::ioctl(self.socket_fd, SIOCGSTAMPNS, &mut stamp) } == -1 {
+            return SystemTime::now();
+        }
+
+        UNIX_EPOCH + Duration::new(stamp.tv_sec as u64, stamp.tv_nsec as u32)
+    }
+
+    fn set_flag(&self, up: bool) -> AHResult<()> {
+        unsafe {
+            let mut flags_ifr = self.new_ifreq();
+
+            tun_sys::siocgifflags(self.ctl_sock_fd, &mut flags_ifr)?;
+            // Setting flags needs privileges even when nothing changes, which might be gone.
+            if (flags_ifr.ifru.flags & tun_sys::IFF_UP != 0) == up {
+                return Ok(());
+            }
+            if up {
+                flags_ifr.ifru.flags |= tun_sys::IFF_UP;
+            } else {
+                flags_ifr.ifru.flags &= !tun_sys::IFF_UP;
+            }
+            tun_sys::siocsifflags(self.ctl_sock_fd, &flags_ifr)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Device for PacketDevice {
+    fn up(&mut self) -> AHResult<()> {
+        self.set_flag(true)
+    }
+
+    fn down(&mut self) -> AHResult<()> {
+        self.set_flag(false)
+    }
+
+    fn if_name(&self) -> AHResult<String> {
+        Ok(self.if_name.clone())
+    }
+
+    fn if_hwaddr(&self) -> AHResult<[u8; 6]> {
+        unsafe {
+            let mut hwaddr_ifr = self.new_ifreq();
+
+            tun_sys::siocgifhwaddr(self.ctl_sock_fd, &mut hwaddr_ifr)?;
+
+            if hwaddr_ifr.ifru.addr.sa_family != tun_sys::ARPHRD_ETHER {
+                bail!(
+                    "unknown hardware address type {}",
+                    hwaddr_ifr.ifru.addr.sa_family
+                );
+            }
+
+            let d = hwaddr_ifr.ifru.addr.sa_data;
+            Ok([
+                d[0] as u8, d[1] as u8, d[2] as u8, d[3] as u8, d[4] as u8, d[5] as u8,
+            ])
+        }
+    }
+
+    fn set_if_hwaddr(&mut self, address: [u8; 6]) -> AHResult<()> {
+        unsafe {
+            let mut hwaddr_ifr = self.new_ifreq();
+
+            hwaddr_ifr.ifru.hwaddr.sa_family = tun_sys::ARPHRD_ETHER;
+            for (i, byte) in address.iter().enumerate() {
+                hwaddr_ifr.ifru.hwaddr.sa_data[i] = *byte as c_char;
+            }
+            tun_sys::siocsifhwaddr(self.ctl_sock_fd, &hwaddr_ifr)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_frames(&mut self, f: &mut dyn FnMut(&[u8], SystemTime)) -> AHResult<()> {
+        self.buffer.resize(FRAME_SIZE, 0);
+
+        let mut address: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        let mut address_len = mem::size_of::<libc::sockaddr_ll>() as u32;
+        let num_read = unsafe {
+            libc::recvfrom(
+                self.socket_fd,
+                self.buffer.as_mut_ptr() as *mut libc::c_void,
+                self.buffer.len(),
+                0,
+                &mut address as *mut _ as *mut libc::sockaddr,
+                &mut address_len,
+            )
+        };
+        if num_read == -1 {
+            let error = io::Error::last_os_error();
+            // Reported once each time the interface goes down, which isn't the socket's problem.
+            if error.raw_os_error() == Some(libc::ENETDOWN) {
+                return Ok(());
+            }
+
+            bail!(error);
+        }
+
+        // The kernel's own traffic out the interface isn't for the node.
+        if address.sll_pkttype != PACKET_OUTGOING {
+            f(&self.buffer[..num_read as usize], self.last_timestamp());
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> AHResult<()> {
+        let num_written = unsafe {
+            libc::send(
+                self.socket_fd,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if num_written == -1 {
+            bail!(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn rawfd(&self) -> RawFd {
+        self.socket_fd
+    }
+
+    fn set_down_on_drop(&mut self, down_on_drop: bool) {
+        self.down_on_drop = down_on_drop;
+    }
+
+    fn down_on_drop(&self) -> bool {
+        self.down_on_drop
+    }
+}
+
+impl Drop for PacketDevice {
+    fn drop(&mut self) {
+        if self.down_on_drop {
+            if let Err(e) = self.down() {
+                warn!("taking {} down failed: {}", self.if_name, e);
+            }
+        }
+
+        unsafe {
+            libc::close(self.socket_fd);
+            libc::close(self.ctl_sock_fd);
+        }
+    }
+}
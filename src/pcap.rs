@@ -0,0 +1,109 @@
+//! Writing frames to pcap files, for looking at a node's traffic in Wireshark or tcpdump later.
+
+use anyhow::{Context, Result as AHResult};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::protocols::ether;
+use crate::protocols::filter::Filter;
+use crate::warn;
+
+/// For records timestamped to the nanosecond, rather than the microsecond.
+const MAGIC: u32 = 0xa1b2_3c4d;
+const LINKTYPE_ETHERNET: u32 = 1;
+const SNAPLEN: u32 = 65535;
+
+pub struct Writer<W: Write> {
+    output: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut output: W) -> AHResult<Self> {
+        output.write_all(&MAGIC.to_le_bytes())?;
+        output.write_all(&2u16.to_le_bytes())?; // Major version
+        output.write_all(&4u16.to_le_bytes())?; // Minor version
+        output.write_all(&0i32.to_le_bytes())?; // Time zone offset
+        output.write_all(&0u32.to_le_bytes())?; // Timestamp accuracy
+        output.write_all(&SNAPLEN.to_le_bytes())?;
+        output.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+
+        Ok(Self { output })
+    }
+
+    pub fn write(&mut self, frame: &[u8], time: SystemTime) -> AHResult<()> {
+        let since_epoch = time.duration_since(UNIX_EPOCH)?;
+
+        self.output
+            .write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.output
+            .write_all(&since_epoch.subsec_nanos().to_le_bytes())?;
+        self.output.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.output.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.output.write_all(frame)?;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> AHResult<()> {
+        Ok(self.output.flush()?)
+    }
+}
+
+/// Write every frame the node sends or receives to a new pcap file at `path`, with received
+/// frames stamped when they were read rather than when they're written out.
+pub fn capture(path: &str, observer: &ether::ObserveHandle) -> AHResult<()> {
+    let file = File::create(path).with_context(|| format!("creating {}", path))?;
+    let mut writer = Writer::new(BufWriter::new(file))?;
+    let frames = observer.observe(1, Filter::All);
+    let path = path.to_string();
+
+    thread::spawn(move || {
+        for frame in frames {
+            // Flushed each time, so the file is usable while we're still running.
+            if let Err(e) = writer
+                .write(
+                    &frame.encode(),
+                    frame.received.unwrap_or_else(SystemTime::now),
+                )
+                .and_then(|_| writer.flush())
+            {
+                warn!("capturing to {} failed: {}", path, e);
+                return;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn records_follow_the_global_header() {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        writer
+            .write(
+                &[0xff; 14],
+                UNIX_EPOCH + Duration::from_nanos(1_000_002_500_000_001),
+            )
+            .unwrap();
+
+        let output = writer.output;
+        assert_eq!(output.len(), 24 + 16 + 14);
+        assert_eq!(output[..4], [0x4d, 0x3c, 0xb2, 0xa1]);
+        assert_eq!(output[20..24], [1, 0, 0, 0]);
+        assert_eq!(
+            output[24..40],
+            [
+                0x42, 0x42, 0x0f, 0x00, // 1000002 seconds
+                0x01, 0x65, 0xcd, 0x1d, // 500000001 nanoseconds
+                14, 0, 0, 0, 14, 0, 0, 0,
+            ]
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! Profiles of common kinds of machine, so tools that guess the OS from how a host answers take a
+//! node for one of them.
+
+use serde::Deserialize;
+
+use crate::protocols::tcp::{Fingerprint, SynOption};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Personality {
+    /// Our own stack's defaults, which don't resemble anything in particular.
+    #[default]
+    Generic,
+    Linux,
+    Windows,
+    Macos,
+    /// A network printer's embedded stack.
+    Printer,
+}
+
+impl Personality {
+    /// For everything the node sends besides NDP, unless the node sets its own.
+    pub fn hop_limit(self) -> u8 {
+        match self {
+            Self::Generic | Self::Linux | Self::Macos => 64,
+            Self::Windows => 128,
+            Self::Printer => 255,
+        }
+    }
+
+    pub fn tcp(self) -> Fingerprint {
+        let (window, syn_options) = match self {
+            Self::Generic => return Fingerprint::default(),
+            Self::Linux => (
+                65160,
+                vec![
+                    SynOption::Mss,
+                    SynOption::SackPermitted,
+                    SynOption::Timestamps,
+                    SynOption::Nop,
+                    SynOption::WindowScale(7),
+                ],
+            ),
+            Self::Windows => (
+                65535,
+                vec![
+                    SynOption::Mss,
+                    SynOption::Nop,
+                    SynOption::WindowScale(8),
+                    SynOption::Nop,
+                    SynOption::Nop,
+                    SynOption::SackPermitted,
+                ],
+            ),
+            Self::Macos => (
+                65535,
+                vec![
+                    SynOption::Mss,
+                    SynOption::Nop,
+                    SynOption::WindowScale(6),
+                    SynOption::Nop,
+                    SynOption::Nop,
+                    SynOption::Timestamps,
+                    SynOption::SackPermitted,
+                ],
+            ),
+            Self::Printer => (8760, vec![SynOption::Mss]),
+        };
+
+        Fingerprint {
+            window,
+            syn_options,
+        }
+    }
+
+    /// What a telnet login greets connections with, with `{hostname}` left to fill in.
+    pub fn login_banner(self) -> &'static str {
+        match self {
+            Self::Generic => "login: ",
+            Self::Linux => "Ubuntu 22.04.4 LTS\r\n{hostname} login: ",
+            Self::Windows => "Welcome to Microsoft Telnet Service \r\n\r\nlogin: ",
+            Self::Macos => "\r\nDarwin/BSD ({hostname}) (ttys000)\r\n\r\nlogin: ",
+            Self::Printer => "HP JetDirect\r\n\r\nPassword: ",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generic_keeps_the_stack_defaults() {
+        assert_eq!(Personality::default().tcp(), Fingerprint::default());
+        assert_eq!(
+            Personality::Generic.hop_limit(),
+            crate::protocols::ipv6::DEFAULT_HOP_LIMIT
+        );
+    }
+
+    #[test]
+    fn profiles_are_consistent() {
+        for personality in [
+            Personality::Linux,
+            Personality::Windows,
+            Personality::Macos,
+            Personality::Printer,
+        ] {
+            let fingerprint = personality.tcp();
+            assert_eq!(fingerprint.syn_options[0], SynOption::Mss);
+        }
+    }
+}
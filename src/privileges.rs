@@ -0,0 +1,90 @@
+//! Giving up the privileges needed to open and configure devices before any frames are parsed, so
+//! a bug in a parser can't be turned into control of the host.
+
+use anyhow::{anyhow, bail, Result as AHResult};
+use nix::unistd::{self, Gid, Group, Uid, User};
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    /// Unprivileged user to switch to.
+    pub user: String,
+    /// Group to switch to, instead of the user's primary group.
+    pub group: Option<String>,
+}
+
+impl Config {
+    fn ids(&self) -> AHResult<(Uid, Gid)> {
+        let user =
+            User::from_name(&self.user)?.ok_or_else(|| anyhow!("no user named {}", self.user))?;
+
+        let gid = match &self.group {
+            Some(name) => {
+                Group::from_name(name)?
+                    .ok_or_else(|| anyhow!("no group named {}", name))?
+                    .gid
+            }
+            None => user.gid,
+        };
+
+        Ok((user.uid, gid))
+    }
+}
+
+/// Switch to the configured user for good, which also clears all capabilities.
+///
+/// The C library makes every thread switch, so this is safe to call after starting threads, as
+/// long as none of them have handled any frames yet.
+#[cfg(target_os = "linux")]
+pub fn drop(config: &Config) -> AHResult<()> {
+    let (uid, gid) = config.ids()?;
+    if uid.is_root() {
+        bail!("switching to {} wouldn't drop any privileges", config.user);
+    }
+
+    unistd::setgroups(&[gid]).map_err(|e| anyhow!("setting groups failed: {}", e))?;
+    unistd::setgid(gid).map_err(|e| anyhow!("switching to gid {} failed: {}", gid, e))?;
+    unistd::setuid(uid).map_err(|e| anyhow!("switching to uid {} failed: {}", uid, e))?;
+
+    if unistd::setuid(Uid::from_raw(0)).is_ok() {
+        bail!("still able to switch back to root after dropping privileges");
+    }
+
+    Ok(())
+}
+
+/// Switch to the configured user for good, which also clears all capabilities.
+#[cfg(not(target_os = "linux"))]
+pub fn drop(_config: &Config) -> AHResult<()> {
+    // The feth backend shells out to ifconfig to bring its interfaces up, which needs root.
+    bail!("dropping privileges is only supported on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_are_looked_up() {
+        let root = Config {
+            user: "root".to_string(),
+            group: None,
+        };
+        assert_eq!(root.ids().unwrap(), (Uid::from_raw(0), Gid::from_raw(0)));
+
+        let missing = Config {
+            user: "no-such-fakenet-user".to_string(),
+            group: None,
+        };
+        assert!(missing.ids().is_err());
+    }
+
+    #[test]
+    fn group_overrides_the_users() {
+        let missing = Config {
+            user: "root".to_string(),
+            group: Some("no-such-fakenet-group".to_string()),
+        };
+        assert!(missing.ids().is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! How ARP requests and neighbor solicitations for an address get answered, for reproducing bugs
+//! with slow or flaky neighbors.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// Requests for this address are answered by the policy.
+    pub address: IpAddr,
+    /// Milliseconds to hold each answer back.
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Only answer requests from these addresses; everyone's when empty.
+    #[serde(default)]
+    pub requesters: Vec<IpAddr>,
+    /// Ignore every this-many-th request answered, counting from the first; none when 0.
+    #[serde(default)]
+    pub drop_every: u32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Answer {
+    Now,
+    After(Duration),
+    Ignore,
+}
+
+struct Entry {
+    policy: Policy,
+    /// Requests that would have been answered so far, for `drop_every`.
+    requests: u32,
+}
+
+/// Policies by address, shared by every clone. Addresses without one are answered right away.
+#[derive(Clone, Default)]
+pub struct Policies(Arc<Mutex<HashMap<IpAddr, Entry>>>);
+
+impl Policies {
+    pub fn set(&self, policy: Policy) {
+        self.0.lock().unwrap().insert(
+            policy.address,
+            Entry {
+                policy,
+                requests: 0,
+            },
+        );
+    }
+
+    /// How to answer `requester` asking for `address`, counting the request.
+    pub fn answer(&self, address: IpAddr, requester: IpAddr) -> Answer {
+        let mut entries = self.0.lock().unwrap();
+        let entry = match entries.get_mut(&address) {
+            Some(entry) => entry,
+            None => return Answer::Now,
+        };
+        let policy = &entry.policy;
+
+        if !policy.requesters.is_empty() && !policy.requesters.contains(&requester) {
+            return Answer::Ignore;
+        }
+
+        entry.requests += 1;
+        if policy.drop_every != 0 && entry.requests % policy.drop_every == 0 {
+            return Answer::Ignore;
+        }
+
+        match policy.delay_ms {
+            0 => Answer::Now,
+            delay_ms => Answer::After(Duration::from_millis(delay_ms)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn requests_are_answered_by_policy() {
+        let policies = Policies::default();
+        policies.set(Policy {
+            address: ip("192.0.2.1"),
+            delay_ms: 250,
+            requesters: vec![ip("192.0.2.7")],
+            drop_every: 3,
+        });
+
+        let delayed = Answer::After(Duration::from_millis(250));
+        let answers: Vec<_> = (0..6)
+            .map(|_| policies.answer(ip("192.0.2.1"), ip("192.0.2.7")))
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                delayed,
+                delayed,
+                Answer::Ignore,
+                delayed,
+                delayed,
+                Answer::Ignore
+            ]
+        );
+
+        // Other requesters are ignored without counting towards `drop_every`.
+        assert_eq!(
+            policies.answer(ip("192.0.2.1"), ip("192.0.2.8")),
+            Answer::Ignore
+        );
+        assert_eq!(policies.answer(ip("192.0.2.1"), ip("192.0.2.7")), delayed);
+
+        assert_eq!(
+            policies.answer(ip("192.0.2.2"), ip("192.0.2.8")),
+            Answer::Now
+        );
+    }
+}
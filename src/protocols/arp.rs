@@ -1,135 +1,462 @@
-use anyhow::{anyhow, Result as AHResult};
+use anyhow::Result as AHResult;
 use crossbeam::channel;
 use nom::{
-    combinator::{map_res, verify},
+    combinator::map_res,
     number::complete::{be_u16, be_u8},
 };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::sync::{Arc, RwLock};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use super::encdec::EncodeTo;
-use super::{ether, ipv4};
-use crate::{encode, proto_enum, try_parse};
+use super::answer_policy::{self, Answer};
+use super::utils::Backpressure;
+use super::{ether, ipv4, neighbor};
+use crate::delay_queue::DelayQueue;
+use crate::executor::{Actor, Executor};
+use crate::{encode, packet_layout, proto_enum, try_parse};
+use crate::{status, supervisor};
+
+/// Requests sent for a neighbor before giving up on it.
+// Ref: https://datatracker.ietf.org/doc/html/rfc1122#section-2.3.2.1
+const MAX_REQUESTS: u32 = 3;
+/// Wait after the first request for a neighbor, doubled after each one after that.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// How long resolving a neighbor that never answered fails straight away, before it's tried again.
+const FAILURE_HOLD: Duration = Duration::from_secs(20);
+/// Requests a second across all neighbors, and how many can go out at once.
+const RATE_LIMIT: f64 = 10.0;
+const RATE_BURST: f64 = 5.0;
+/// How often pending resolutions are checked for requests to retry.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
 
 proto_enum!(PacketOpcode, u16, {
     Request = 1,
     Reply = 2,
 });
 
-#[derive(Debug, PartialEq)]
-pub struct Packet {
-    pub opcode: PacketOpcode,
-    pub src_ether: ether::Address,
-    pub src_ipv4: ipv4::Address,
-    pub dest_ether: ether::Address,
-    pub dest_ipv4: ipv4::Address,
+packet_layout! {
+    #[derive(Debug, PartialEq)]
+    pub struct Packet {
+        const 1u16 => be_u16, // Hardware type: Ethernet
+        const ether::Type::Ipv4 as u16 => be_u16,
+        const 6u8 => be_u8, // Hardware address length
+        const 4u8 => be_u8, // Protocol address length
+        pub opcode: PacketOpcode => map_res(be_u16, PacketOpcode::try_from),
+        pub src_ether: ether::Address => ether::address,
+        pub src_ipv4: ipv4::Address => ipv4::address,
+        pub dest_ether: ether::Address => ether::address,
+        pub dest_ipv4: ipv4::Address => ipv4::address,
+    }
 }
 
 impl Packet {
     pub fn encode(&self) -> Vec<u8> {
-        encode!(
-            1u16,
-            ether::Type::Ipv4 as u16,
-            6u8,
-            4u8,
-            self.opcode as u16,
-            self.src_ether,
-            self.src_ipv4,
-            self.dest_ether,
-            self.dest_ipv4,
-        )
+        encode!(self)
     }
 }
 
 pub fn packet(input: &[u8]) -> AHResult<Packet> {
     try_parse!(
-        {
-            let (input, _) = verify(be_u16, |hrd| *hrd == 1)(input)?;
-            let (input, _) = verify(be_u16, |pro| *pro == ether::Type::Ipv4 as u16)(input)?;
-            let (input, _) = verify(be_u8, |hln| *hln == 6)(input)?;
-            let (input, _) = verify(be_u8, |pln| *pln == 4)(input)?;
-            let (input, opcode) = map_res(be_u16, PacketOpcode::try_from)(input)?;
-            let (input, src_ether) = ether::address(input)?;
-            let (input, src_ipv4) = ipv4::address(input)?;
-            let (input, dest_ether) = ether::address(input)?;
-            let (input, dest_ipv4) = ipv4::address(input)?;
-
-            Ok((
-                input,
-                Packet {
-                    opcode,
-                    src_ether,
-                    src_ipv4,
-                    dest_ether,
-                    dest_ipv4,
-                },
-            ))
-        },
+        input,
+        { Packet::parse(input) },
         "parsing arp packet failed: {}"
     )
 }
 
+/// Whether `address` is a host in one of `prefixes`, leaving out the network and broadcast
+/// addresses of prefixes that have them.
+fn swept(prefixes: &[(ipv4::Address, u8)], address: ipv4::Address) -> bool {
+    prefixes.iter().any(|&(prefix, length)| {
+        let network = prefix.prefix(length as usize);
+        let host_bits = (!0u32).checked_shr(length as u32).unwrap_or(0);
+        let broadcast = ipv4::Address((u32::from_be_bytes(network.0) | host_bits).to_be_bytes());
+
+        address.prefix(length as usize) == network
+            && (length >= 31 || (address != network && address != broadcast))
+    })
+}
+
+/// The made-up ether address we answer for `address` at when sweeping, distinct per node.
+pub fn swept_ether_address(
+    ether_address: ether::Address,
+    address: ipv4::Address,
+) -> ether::Address {
+    ether::Address::derived(&[&ether_address.0[..], &address.0[..]].concat())
+}
+
+struct Pending {
+    waiters: Vec<channel::Sender<ether::Address>>,
+    requests: u32,
+    next_request: Instant,
+}
+
+/// Neighbors being resolved, with everyone waiting on each sharing one series of requests.
+#[derive(Default)]
+struct Resolutions {
+    pending: HashMap<ipv4::Address, Pending>,
+    /// When each neighbor that never answered was given up on.
+    failed: HashMap<ipv4::Address, Instant>,
+    tokens: f64,
+    refilled: Option<Instant>,
+}
+
+impl Resolutions {
+    /// Hand `waiter` the ether address of `address` once it's known; it's dropped instead if
+    /// `address` failed to resolve too recently to try again.
+    fn wait(
+        &mut self,
+        address: ipv4::Address,
+        waiter: channel::Sender<ether::Address>,
+        now: Instant,
+    ) {
+        if self
+            .failed
+            .get(&address)
+            .is_some_and(|&failed| now < failed + FAILURE_HOLD)
+        {
+            return;
+        }
+        self.failed.remove(&address);
+
+        self.pending
+            .entry(address)
+            .or_insert_with(|| Pending {
+                waiters: Vec::new(),
+                requests: 0,
+                next_request: now,
+            })
+            .waiters
+            .push(waiter);
+    }
+
+    /// Addresses to send a request for now, within the rate limit; neighbors that have had all
+    /// their requests go unanswered are given up on, dropping their waiters.
+    fn due(&mut self, now: Instant) -> Vec<ipv4::Address> {
+        self.tokens = match self.refilled {
+            Some(refilled) => {
+                (self.tokens + (now - refilled).as_secs_f64() * RATE_LIMIT).min(RATE_BURST)
+            }
+            None => RATE_BURST,
+        };
+        self.refilled = Some(now);
+
+        let mut due = Vec::new();
+        let mut given_up = Vec::new();
+        for (address, pending) in &mut self.pending {
+            if pending.next_request > now {
+                continue;
+            }
+
+            if pending.requests == MAX_REQUESTS {
+                given_up.push(*address);
+            } else if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                pending.next_request = now + RETRY_BACKOFF * (1 << pending.requests);
+                pending.requests += 1;
+                due.push(*address);
+            }
+        }
+
+        for address in given_up {
+            self.pending.remove(&address);
+            self.failed.insert(address, now);
+        }
+
+        due
+    }
+
+    /// Hand `ether_address` to everyone waiting on `address`.
+    fn resolved(&mut self, address: ipv4::Address, ether_address: ether::Address) {
+        self.failed.remove(&address);
+        if let Some(pending) = self.pending.remove(&address) {
+            for waiter in pending.waiters {
+                let _ = waiter.send(ether_address);
+            }
+        }
+    }
+
+    fn is_pending(&self, address: ipv4::Address) -> bool {
+        self.pending.contains_key(&address)
+    }
+}
+
+#[derive(Clone)]
 pub struct Server {
     receiver: channel::Receiver<ether::Frame>,
     write_sender: channel::Sender<ether::Frame>,
     ether_address: ether::Address,
     addresses: Arc<RwLock<HashSet<ipv4::Address>>>,
+    neighbors: neighbor::Table,
+    answer_all: Arc<AtomicBool>,
+    sweep: Arc<RwLock<Vec<(ipv4::Address, u8)>>>,
+    answer_policies: answer_policy::Policies,
+    /// Answers held back by their policy, sent by the retry thread once due.
+    delayed_answers: Arc<Mutex<DelayQueue<ether::Frame>>>,
+    /// Wakes the retry thread for an answer due sooner than what it's waiting for.
+    delayed_sender: channel::Sender<()>,
+    delayed_receiver: channel::Receiver<()>,
+    resolutions: Arc<Mutex<Resolutions>>,
 }
 
 impl Server {
-    pub fn new(interface: &mut impl ether::Server) -> AHResult<Self> {
+    pub fn new(interface: &mut impl ether::Server, neighbors: neighbor::Table) -> AHResult<Self> {
         let (sender, receiver) = channel::bounded(1024);
-        interface.register(ether::Type::Arp, sender);
+        let (delayed_sender, delayed_receiver) = channel::unbounded();
+        interface.register_with_backpressure(
+            ether::Type::Arp,
+            sender,
+            Backpressure::DropOldest(receiver.clone()),
+        );
 
         Ok(Self {
             receiver,
             write_sender: interface.writer(),
             ether_address: interface.if_hwaddr()?,
             addresses: Arc::new(RwLock::new(HashSet::new())),
+            neighbors,
+            answer_all: Arc::new(AtomicBool::new(false)),
+            sweep: Arc::new(RwLock::new(Vec::new())),
+            answer_policies: answer_policy::Policies::default(),
+            delayed_answers: Arc::new(Mutex::new(DelayQueue::new())),
+            delayed_sender,
+            delayed_receiver,
+            resolutions: Arc::new(Mutex::new(Resolutions::default())),
         })
     }
 
-    pub fn start(&self) {
-        let receiver = self.receiver.clone();
-        let write_sender = self.write_sender.clone();
-        let src_ether = self.ether_address;
-        let addresses = self.addresses.clone();
+    pub fn start(&self, executor: &Executor) {
+        executor.start("arp", "arp", self.receiver.clone(), self.clone());
 
+        let server = self.clone();
+        let retries = channel::tick(RETRY_INTERVAL);
         thread::spawn(move || loop {
-            let frame = receiver.recv().unwrap();
-
-            let packet = packet(&frame.payload).unwrap();
-
-            if addresses.read().unwrap().contains(&packet.dest_ipv4) {
-                let frame = ether::Frame {
-                    dest: packet.src_ether,
-                    src: src_ether,
-                    ethertype: ether::Type::Arp,
-                    payload: Packet {
-                        opcode: PacketOpcode::Reply,
-                        src_ether,
-                        src_ipv4: packet.dest_ipv4,
-                        dest_ether: packet.src_ether,
-                        dest_ipv4: packet.src_ipv4,
+            let answer_due = server.delayed_answers.lock().unwrap().receiver();
+            channel::select! {
+                recv(retries) -> _ => server.send_due_requests(),
+                recv(answer_due) -> due => {
+                    let answer = server.delayed_answers.lock().unwrap().pop_at(due.unwrap());
+                    if let Some(answer) = answer {
+                        let _ = server.write_sender.send(answer);
                     }
-                    .encode(),
-                };
-
-                write_sender.send(frame).unwrap();
+                },
+                recv(server.delayed_receiver) -> _ => {},
             }
         });
     }
 
+    /// The ether address of `address`, sent once it's known.
+    ///
+    /// Resolutions of the same address share requests, which back off while the neighbor is
+    /// silent and are rate limited across all neighbors. The channel is closed without an answer
+    /// once the neighbor is given up on.
+    pub fn resolve(&self, address: ipv4::Address) -> channel::Receiver<ether::Address> {
+        let (sender, receiver) = channel::bounded(1);
+
+        match self.neighbors.lookup(IpAddr::from(address)) {
+            Some(ether_address) => {
+                let _ = sender.send(ether_address);
+            }
+            None => {
+                self.resolutions
+                    .lock()
+                    .unwrap()
+                    .wait(address, sender, Instant::now());
+                self.send_due_requests();
+            }
+        }
+
+        receiver
+    }
+
+    fn send_due_requests(&self) {
+        let due = self.resolutions.lock().unwrap().due(Instant::now());
+        if due.is_empty() {
+            return;
+        }
+
+        let src_ipv4 = self
+            .addresses
+            .read()
+            .unwrap()
+            .iter()
+            .min()
+            .copied()
+            .unwrap_or(ipv4::Address([0; 4]));
+        for dest_ipv4 in due {
+            let frame = ether::Frame {
+                dest: ether::Address::BROADCAST,
+                src: self.ether_address,
+                ethertype: ether::Type::Arp,
+                payload: Packet {
+                    opcode: PacketOpcode::Request,
+                    src_ether: self.ether_address,
+                    src_ipv4,
+                    dest_ether: ether::Address([0; 6]),
+                    dest_ipv4,
+                }
+                .encode(),
+                received: None,
+            };
+
+            self.write_sender.send(frame).unwrap();
+        }
+    }
+
+    /// Start answering for `address`, announcing it to the network.
     pub fn add(&self, address: ipv4::Address) {
-        self.addresses.write().unwrap().insert(address);
+        if self.addresses.write().unwrap().insert(address) {
+            self.announce(address, self.ether_address);
+            self.report();
+        }
+    }
+
+    /// Stop answering for `address`.
+    ///
+    /// ARP can't withdraw an address, so this announces it at the all-zeroes hardware address,
+    /// replacing our entry in any neighbor caches that take the update with an unreachable one.
+    pub fn remove(&self, address: ipv4::Address) {
+        if self.addresses.write().unwrap().remove(&address) {
+            self.announce(address, ether::Address([0; 6]));
+            self.report();
+        }
+    }
+
+    /// Addresses we currently answer for.
+    pub fn addresses(&self) -> Vec<ipv4::Address> {
+        self.addresses.read().unwrap().iter().copied().collect()
+    }
+
+    // Ref: https://datatracker.ietf.org/doc/html/rfc5227#section-2.3
+    fn announce(&self, address: ipv4::Address, ether_address: ether::Address) {
+        let frame = ether::Frame {
+            dest: ether::Address::BROADCAST,
+            src: self.ether_address,
+            ethertype: ether::Type::Arp,
+            payload: Packet {
+                opcode: PacketOpcode::Request,
+                src_ether: ether_address,
+                src_ipv4: address,
+                dest_ether: ether::Address([0; 6]),
+                dest_ipv4: address,
+            }
+            .encode(),
+            received: None,
+        };
+
+        self.write_sender.send(frame).unwrap();
+    }
+
+    fn report(&self) {
+        let addresses = self.addresses().into_iter().collect();
+
+        status::update(|s| s.interface.arp_addresses = addresses);
+    }
+
+    /// Misbehave by answering requests for every address, claiming them all as ours.
+    pub fn set_answer_all(&self, answer_all: bool) {
+        self.answer_all.store(answer_all, Ordering::Relaxed);
+    }
+
+    /// Answer requests for every host in these prefixes, each at its own made-up ether address, so
+    /// scans see them populated.
+    pub fn set_sweep(&self, prefixes: Vec<(ipv4::Address, u8)>) {
+        *self.sweep.write().unwrap() = prefixes;
+    }
+
+    /// Answer requests for the policy's address as it says, rather than right away.
+    pub fn set_answer_policy(&self, policy: answer_policy::Policy) {
+        self.answer_policies.set(policy);
+    }
+}
+
+impl Actor for Server {
+    type Message = ether::Frame;
+
+    fn handle(&mut self, frame: ether::Frame) {
+        supervisor::handling(&frame.payload);
+
+        let packet = packet(&frame.payload).unwrap();
+        let for_us = self.addresses.read().unwrap().contains(&packet.dest_ipv4);
+
+        // Ref: https://datatracker.ietf.org/doc/html/rfc826 ("Packet Reception")
+        let src_ipv4 = IpAddr::from(packet.src_ipv4);
+        let mut resolutions = self.resolutions.lock().unwrap();
+        let resolving = resolutions.is_pending(packet.src_ipv4);
+        if for_us || resolving || self.neighbors.lookup(src_ipv4).is_some() {
+            self.neighbors.learn(
+                src_ipv4,
+                packet.src_ether,
+                (for_us || resolving) && packet.opcode == PacketOpcode::Reply,
+            );
+            resolutions.resolved(packet.src_ipv4, packet.src_ether);
+        }
+        drop(resolutions);
+
+        if packet.opcode != PacketOpcode::Request {
+            return;
+        }
+
+        // Sweeping leaves probes and announcements alone, so real hosts can still join,
+        // along with addresses we've seen a real host use.
+        let answer_as = if for_us || self.answer_all.load(Ordering::Relaxed) {
+            self.ether_address
+        } else if packet.src_ipv4 != ipv4::Address([0; 4])
+            && packet.src_ipv4 != packet.dest_ipv4
+            && swept(&self.sweep.read().unwrap(), packet.dest_ipv4)
+            && self
+                .neighbors
+                .lookup(IpAddr::from(packet.dest_ipv4))
+                .is_none()
+        {
+            swept_ether_address(self.ether_address, packet.dest_ipv4)
+        } else {
+            return;
+        };
+
+        let frame = ether::Frame {
+            dest: packet.src_ether,
+            src: answer_as,
+            ethertype: ether::Type::Arp,
+            payload: Packet {
+                opcode: PacketOpcode::Reply,
+                src_ether: answer_as,
+                src_ipv4: packet.dest_ipv4,
+                dest_ether: packet.src_ether,
+                dest_ipv4: packet.src_ipv4,
+            }
+            .encode(),
+            received: None,
+        };
+
+        match self
+            .answer_policies
+            .answer(IpAddr::from(packet.dest_ipv4), src_ipv4)
+        {
+            Answer::Now => self.write_sender.send(frame).unwrap(),
+            Answer::After(delay) => {
+                self.delayed_answers
+                    .lock()
+                    .unwrap()
+                    .push_after(delay, frame);
+                let _ = self.delayed_sender.send(());
+            }
+            Answer::Ignore => {}
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocols::encdec::EncodeTo;
+    use crate::protocols::ether::Server as _;
+    use crate::protocols::utils::KeyedDispatcher;
+    use proptest::prelude::*;
+    use std::time::Duration;
 
     fn hexstring(s: &str) -> Vec<u8> {
         hex::decode(s).unwrap()
@@ -152,6 +479,196 @@ mod tests {
         );
     }
 
+    #[test]
+    fn packet_round_trips() {
+        let encoded = hexstring("0001080006040002b827ebb38fcf0a00012204d9f5f844e80a000168");
+
+        assert_eq!(packet(&encoded).unwrap().encode(), encoded);
+    }
+
+    #[test]
+    fn add_and_remove_announce() {
+        let address = "10.0.0.2".parse().unwrap();
+        let (mut a_eth, mut b_eth) = ether::MemoryInterface::pair(
+            ether::Address([2, 0, 0, 0, 0, 1]),
+            ether::Address([2, 0, 0, 0, 0, 2]),
+        );
+        let (sender, receiver) = channel::unbounded();
+        b_eth.register(ether::Type::Arp, sender);
+
+        let server = Server::new(&mut a_eth, neighbor::Table::new("test")).unwrap();
+        server.add(address);
+        server.add(address);
+        server.remove(address);
+
+        let announcement = packet(&receiver.recv().unwrap().payload).unwrap();
+        assert_eq!(announcement.src_ipv4, address);
+        assert_eq!(announcement.dest_ipv4, address);
+        assert_eq!(announcement.src_ether, ether::Address([2, 0, 0, 0, 0, 1]));
+
+        let withdrawal = packet(&receiver.recv().unwrap().payload).unwrap();
+        assert_eq!(withdrawal.src_ether, ether::Address([0; 6]));
+        assert!(receiver.try_recv().is_err());
+        assert!(server.addresses().is_empty());
+    }
+
+    #[test]
+    fn sweeps_answer_for_hosts_at_their_own_addresses() {
+        let (mut a_eth, mut b_eth) = ether::MemoryInterface::pair(
+            ether::Address([2, 0, 0, 0, 0, 1]),
+            ether::Address([2, 0, 0, 0, 0, 2]),
+        );
+        let (sender, receiver) = channel::unbounded();
+        b_eth.register(ether::Type::Arp, sender);
+        let b_writer = b_eth.writer();
+
+        let server = Server::new(&mut a_eth, neighbor::Table::new("test")).unwrap();
+        server.set_sweep(vec![("10.0.5.0".parse().unwrap(), 24)]);
+        server.start(&Executor::new("arp-test", 1));
+
+        let request = |dest_ipv4: &str| {
+            let frame = ether::Frame {
+                dest: ether::Address::BROADCAST,
+                src: ether::Address([2, 0, 0, 0, 0, 2]),
+                ethertype: ether::Type::Arp,
+                payload: Packet {
+                    opcode: PacketOpcode::Request,
+                    src_ether: ether::Address([2, 0, 0, 0, 0, 2]),
+                    src_ipv4: "10.0.5.1".parse().unwrap(),
+                    dest_ether: ether::Address([0; 6]),
+                    dest_ipv4: dest_ipv4.parse().unwrap(),
+                }
+                .encode(),
+                received: None,
+            };
+            b_writer.send(frame).unwrap();
+        };
+
+        for dest in ["10.0.6.7", "10.0.5.255", "10.0.5.7", "10.0.5.8"] {
+            request(dest);
+        }
+
+        let first = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        let second = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        let (first_reply, second_reply) = (
+            packet(&first.payload).unwrap(),
+            packet(&second.payload).unwrap(),
+        );
+        assert_eq!(first_reply.src_ipv4, "10.0.5.7".parse().unwrap());
+        assert_eq!(second_reply.src_ipv4, "10.0.5.8".parse().unwrap());
+        assert_eq!(first.src, first_reply.src_ether);
+        assert_eq!(
+            first_reply.src_ether,
+            swept_ether_address(ether::Address([2, 0, 0, 0, 0, 1]), first_reply.src_ipv4)
+        );
+        assert_ne!(first_reply.src_ether, second_reply.src_ether);
+        assert!(first_reply.src_ether.is_local() && !first_reply.src_ether.is_multicast());
+    }
+
+    #[test]
+    fn answers_follow_their_policy() {
+        let address = "10.0.0.2".parse().unwrap();
+        let (mut a_eth, mut b_eth) = ether::MemoryInterface::pair(
+            ether::Address([2, 0, 0, 0, 0, 1]),
+            ether::Address([2, 0, 0, 0, 0, 2]),
+        );
+        let (sender, receiver) = channel::unbounded();
+        b_eth.register(ether::Type::Arp, sender);
+
+        let mut server = Server::new(&mut a_eth, neighbor::Table::new("test")).unwrap();
+        server.start(&Executor::new("arp-policy-test", 1));
+        server.add(address);
+        server.set_answer_policy(answer_policy::Policy {
+            address: IpAddr::from(address),
+            delay_ms: 50,
+            requesters: Vec::new(),
+            drop_every: 2,
+        });
+        receiver.recv().unwrap();
+
+        let sent = Instant::now();
+        for _ in 0..3 {
+            server.handle(ether::Frame {
+                dest: ether::Address::BROADCAST,
+                src: ether::Address([2, 0, 0, 0, 0, 2]),
+                ethertype: ether::Type::Arp,
+                payload: Packet {
+                    opcode: PacketOpcode::Request,
+                    src_ether: ether::Address([2, 0, 0, 0, 0, 2]),
+                    src_ipv4: "10.0.0.3".parse().unwrap(),
+                    dest_ether: ether::Address([0; 6]),
+                    dest_ipv4: address,
+                }
+                .encode(),
+                received: None,
+            });
+        }
+
+        for _ in 0..2 {
+            let reply = packet(
+                &receiver
+                    .recv_timeout(Duration::from_secs(5))
+                    .unwrap()
+                    .payload,
+            );
+            assert_eq!(reply.unwrap().opcode, PacketOpcode::Reply);
+        }
+        assert!(sent.elapsed() >= Duration::from_millis(50));
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn resolutions_share_requests_and_back_off() {
+        let address = "10.0.0.9".parse().unwrap();
+        let start = Instant::now();
+        let mut resolutions = Resolutions::default();
+        let (first, first_receiver) = channel::bounded(1);
+        let (second, second_receiver) = channel::bounded(1);
+
+        resolutions.wait(address, first, start);
+        resolutions.wait(address, second, start);
+        assert_eq!(resolutions.due(start), [address]);
+        assert!(resolutions.due(start).is_empty());
+
+        let mut requests = 1;
+        let mut now = start;
+        while resolutions.is_pending(address) {
+            now += Duration::from_millis(100);
+            requests += resolutions.due(now).len();
+        }
+        assert_eq!(requests, MAX_REQUESTS as usize);
+        assert_eq!(now - start, Duration::from_secs(7));
+        assert!(first_receiver.recv().is_err() && second_receiver.recv().is_err());
+
+        // Given up on, it isn't tried again for a while
+        let (third, third_receiver) = channel::bounded(1);
+        resolutions.wait(address, third, now);
+        assert!(third_receiver.recv().is_err());
+        assert!(resolutions.due(now + FAILURE_HOLD).is_empty());
+    }
+
+    #[test]
+    fn requests_are_rate_limited() {
+        let now = Instant::now();
+        let mut resolutions = Resolutions::default();
+        let mut receivers = Vec::new();
+        for host in 1..=20 {
+            let (sender, receiver) = channel::bounded(1);
+            resolutions.wait(ipv4::Address([10, 0, 1, host]), sender, now);
+            receivers.push(receiver);
+        }
+
+        assert_eq!(resolutions.due(now).len(), RATE_BURST as usize);
+        assert_eq!(resolutions.due(now + Duration::from_millis(500)).len(), 5);
+        assert_eq!(resolutions.due(now + Duration::from_millis(600)).len(), 1);
+
+        resolutions.resolved(
+            ipv4::Address([10, 0, 1, 1]),
+            ether::Address([2, 0, 0, 0, 0, 9]),
+        );
+        assert_eq!(receivers[0].recv(), Ok(ether::Address([2, 0, 0, 0, 0, 9])));
+    }
+
     #[test]
     fn request_packet_decodes() {
         assert_eq!(
@@ -168,4 +685,33 @@ mod tests {
             }
         );
     }
+
+    fn arb_packet() -> impl Strategy<Value = Packet> {
+        (
+            prop::sample::select(vec![PacketOpcode::Request, PacketOpcode::Reply]),
+            any::<[u8; 6]>(),
+            any::<[u8; 4]>(),
+            any::<[u8; 6]>(),
+            any::<[u8; 4]>(),
+        )
+            .prop_map(
+                |(opcode, src_ether, src_ipv4, dest_ether, dest_ipv4)| Packet {
+                    opcode,
+                    src_ether: ether::Address(src_ether),
+                    src_ipv4: ipv4::Address(src_ipv4),
+                    dest_ether: ether::Address(dest_ether),
+                    dest_ipv4: ipv4::Address(dest_ipv4),
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_packet_round_trips(packet_in in arb_packet()) {
+            let encoded = packet_in.encode();
+
+            prop_assert_eq!(encoded.len(), packet_in.encoded_len());
+            prop_assert_eq!(packet(&encoded).unwrap(), packet_in);
+        }
+    }
 }
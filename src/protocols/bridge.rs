@@ -0,0 +1,575 @@
+//! Software bridge, forwarding frames between devices like a learning switch, with optional
+//! per-port impairments and mirror ports, and multicast kept to listeners by MLD and IGMP
+//! snooping.
+
+use anyhow::Result as AHResult;
+use crossbeam::channel;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::ether;
+use super::snooping::{self, Groups};
+use crate::device::Device;
+use crate::warn;
+
+// Ref: IEEE 802.1D-2004, 7.9.2
+pub const DEFAULT_AGING: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug)]
+struct FdbEntry {
+    port: usize,
+    last_seen: Instant,
+}
+
+/// Filtering database: which port each source address was last seen on.
+#[derive(Debug)]
+pub struct Fdb {
+    entries: HashMap<ether::Address, FdbEntry>,
+    aging: Duration,
+}
+
+impl Fdb {
+    pub fn new(aging: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            aging,
+        }
+    }
+
+    pub fn learn(&mut self, address: ether::Address, port: usize, now: Instant) {
+        // Group addresses are never sources, whatever frames claim.
+        if !address.is_multicast() {
+            self.entries.insert(
+                address,
+                FdbEntry {
+                    port,
+                    last_seen: now,
+                },
+            );
+        }
+    }
+
+    pub fn lookup(&self, address: ether::Address, now: Instant) -> Option<usize> {
+        self.entries
+            .get(&address)
+            .filter(|e| now < e.last_seen + self.aging)
+            .map(|e| e.port)
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let aging = self.aging;
+        self.entries.retain(|_, e| now < e.last_seen + aging);
+    }
+
+    pub fn forget_port(&mut self, port: usize) {
+        self.entries.retain(|_, e| e.port != port);
+    }
+}
+
+/// Per-port egress impairments, applied to frames as they leave through the port.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Impairments {
+    pub delay: Duration,
+    /// Chance, from 0 to 1, of dropping each frame.
+    pub loss: f64,
+}
+
+struct Port {
+    device: Mutex<Box<dyn Device>>,
+    impairments: Impairments,
+    /// Gets a copy of every frame the bridge receives, and forwards nothing it receives itself.
+    mirror: bool,
+}
+
+pub struct Bridge {
+    ports: Vec<Port>,
+    fdb: Fdb,
+    snooping: bool,
+}
+
+/// A plugged-in port, with where to queue frames leaving through it.
+struct Slot {
+    port: Arc<Port>,
+    egress: channel::Sender<(Instant, Vec<u8>)>,
+}
+
+/// State shared by the threads of a started bridge.
+struct Running {
+    /// Indexed by port number; unplugged ports leave a gap, so numbers stay the same.
+    ports: RwLock<Vec<Option<Slot>>>,
+    fdb: Mutex<Fdb>,
+    /// Unset when multicast is flooded like broadcast.
+    groups: Option<Mutex<Groups>>,
+}
+
+/// A started bridge, which ports can still be plugged into and unplugged from.
+#[derive(Clone)]
+pub struct Handle(Arc<Running>);
+
+impl Bridge {
+    pub fn new(aging: Duration) -> Self {
+        Self {
+            ports: Vec::new(),
+            fdb: Fdb::new(aging),
+            snooping: true,
+        }
+    }
+
+    /// Whether to only send multicast to ports with listeners or routers behind them, as learned
+    /// from MLD and IGMP, rather than flooding it; on by default.
+    pub fn set_snooping(&mut self, snooping: bool) {
+        self.snooping = snooping;
+    }
+
+    /// Add a port, returning its number.
+    pub fn add_port(&mut self, device: Box<dyn Device>, impairments: Impairments) -> usize {
+        self.push_port(device, impairments, false)
+    }
+
+    /// Add a mirror port, returning its number.
+    pub fn add_mirror(&mut self, device: Box<dyn Device>, impairments: Impairments) -> usize {
+        self.push_port(device, impairments, true)
+    }
+
+    fn push_port(
+        &mut self,
+        device: Box<dyn Device>,
+        impairments: Impairments,
+        mirror: bool,
+    ) -> usize {
+        self.ports.push(Port {
+            device: Mutex::new(device),
+            impairments,
+            mirror,
+        });
+
+        self.ports.len() - 1
+    }
+
+    /// Bring every port up ahead of `start()`, e.g. while there are still the privileges to.
+    pub fn up(&mut self) -> AHResult<()> {
+        for port in &mut self.ports {
+            port.device.get_mut().unwrap().up()?;
+        }
+
+        Ok(())
+    }
+
+    /// Bring every port up and start forwarding between them.
+    pub fn start(self) -> AHResult<Handle> {
+        let interval = self.fdb.aging / 2;
+        let running = Arc::new(Running {
+            ports: RwLock::new(Vec::new()),
+            fdb: Mutex::new(self.fdb),
+            groups: self.snooping.then(|| Mutex::new(Groups::default())),
+        });
+
+        for port in self.ports {
+            Running::plug(&running, port)?;
+        }
+
+        let expiring = Arc::clone(&running);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            expiring.fdb.lock().unwrap().expire(Instant::now());
+            if let Some(groups) = &expiring.groups {
+                groups.lock().unwrap().expire(Instant::now());
+            }
+        });
+
+        Ok(Handle(running))
+    }
+}
+
+impl Handle {
+    /// Add a port to the running bridge, returning its number.
+    pub fn add_port(&self, device: Box<dyn Device>, impairments: Impairments) -> AHResult<usize> {
+        Running::plug(
+            &self.0,
+            Port {
+                device: Mutex::new(device),
+                impairments,
+                mirror: false,
+            },
+        )
+    }
+
+    /// Stop forwarding to and from port `i`, forgetting what was learned on it.
+    ///
+    /// The port's device is closed once its peer goes away.
+    pub fn remove_port(&self, i: usize) {
+        if let Some(slot) = self.0.ports.write().unwrap().get_mut(i) {
+            *slot = None;
+        }
+        self.0.fdb.lock().unwrap().forget_port(i);
+        if let Some(groups) = &self.0.groups {
+            groups.lock().unwrap().forget_port(i);
+        }
+    }
+}
+
+impl Running {
+    fn plug(running: &Arc<Self>, port: Port) -> AHResult<usize> {
+        port.device.lock().unwrap().up()?;
+
+        let port = Arc::new(port);
+        let (egress, receiver) = channel::unbounded();
+        let i = {
+            let mut ports = running.ports.write().unwrap();
+            ports.push(Some(Slot {
+                port: Arc::clone(&port),
+                egress,
+            }));
+            ports.len() - 1
+        };
+
+        let ingress = Arc::clone(running);
+        let ingress_port = Arc::clone(&port);
+        thread::spawn(move || {
+            // Unplugged ports are expected to stop.
+            if let Err(e) = ingress.run_port(i, &ingress_port) {
+                if ingress.is_plugged(i) {
+                    warn!("bridge port {} stopped: {}", i, e);
+                }
+            }
+        });
+
+        thread::spawn(move || Self::run_egress(i, &port, receiver));
+
+        Ok(i)
+    }
+
+    fn is_plugged(&self, i: usize) -> bool {
+        matches!(self.ports.read().unwrap().get(i), Some(Some(_)))
+    }
+
+    fn run_port(&self, i: usize, port: &Port) -> AHResult<()> {
+        let fd = port.device.lock().unwrap().rawfd();
+
+        loop {
+            // Wait without the lock, so frames can be written to this port meanwhile.
+            poll(&mut [PollFd::new(fd, PollFlags::POLLIN)], -1)?;
+
+            let mut frames = Vec::new();
+            port.device
+                .lock()
+                .unwrap()
+                .read_frames(&mut |frame, received| frames.push((frame.to_vec(), received)))?;
+
+            if port.mirror || !self.is_plugged(i) {
+                continue;
+            }
+
+            for (frame, received) in frames {
+                self.forward(i, &frame, received);
+            }
+        }
+    }
+
+    /// Write out frames sent to port `i` once they're due, in order, until it's unplugged.
+    fn run_egress(i: usize, port: &Port, receiver: channel::Receiver<(Instant, Vec<u8>)>) {
+        for (due, frame) in receiver {
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+
+            if let Err(e) = port.device.lock().unwrap().write(&frame) {
+                warn!("forwarding to bridge port {} failed: {}", i, e);
+            }
+        }
+    }
+
+    /// Where a frame from `from` should go, after learning its source.
+    fn destinations(&self, ports: &[Option<Slot>], from: usize, frame: &[u8]) -> Vec<usize> {
+        if frame.len() < 14 {
+            return Vec::new();
+        }
+
+        let dest = ether::Address(frame[0..6].try_into().unwrap());
+        let src = ether::Address(frame[6..12].try_into().unwrap());
+        let now = Instant::now();
+
+        let mut fdb = self.fdb.lock().unwrap();
+        fdb.learn(src, from, now);
+
+        let listening = |port: &usize| {
+            *port != from && matches!(ports.get(*port), Some(Some(slot)) if !slot.port.mirror)
+        };
+        match fdb.lookup(dest, now) {
+            // Already on the segment it's for.
+            Some(port) if port == from => Vec::new(),
+            Some(port) if !dest.is_multicast() => vec![port],
+            _ => match self.group_ports(from, dest, frame, now) {
+                Some(group_ports) => group_ports.into_iter().filter(listening).collect(),
+                None => (0..ports.len()).filter(listening).collect(),
+            },
+        }
+    }
+
+    /// Ports with listeners for the group `frame` is sent to, after learning from it, or `None` if
+    /// it's flooded. Membership messages themselves are flooded, so every switch along the way
+    /// hears them.
+    fn group_ports(
+        &self,
+        from: usize,
+        dest: ether::Address,
+        frame: &[u8],
+        now: Instant,
+    ) -> Option<Vec<usize>> {
+        let mut groups = self.groups.as_ref()?.lock().unwrap();
+        if !dest.is_multicast() {
+            return None;
+        }
+
+        let memberships = ether::frame(frame).map_or_else(|_| Vec::new(), |f| snooping::snoop(&f));
+        if !memberships.is_empty() {
+            for membership in memberships {
+                groups.learn(from, membership, now);
+            }
+            return None;
+        }
+
+        groups.ports(dest, now)
+    }
+
+    /// Delays count from when the frame was `received`, so time spent reading and forwarding it
+    /// isn't added on top.
+    fn forward(&self, from: usize, frame: &[u8], received: SystemTime) {
+        let ports = self.ports.read().unwrap();
+        let mirrors = ports
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| matches!(slot, Some(slot) if slot.port.mirror))
+            .map(|(port, _)| port);
+
+        let waited = received.elapsed().unwrap_or_default();
+
+        for port in self
+            .destinations(&ports, from, frame)
+            .into_iter()
+            .chain(mirrors)
+        {
+            let slot = match &ports[port] {
+                Some(slot) => slot,
+                None => continue,
+            };
+            let impairments = slot.port.impairments;
+            if impairments.loss > 0.0 && rand::random::<f64>() < impairments.loss {
+                continue;
+            }
+
+            // Only fails once the egress thread has stopped, which it never does while plugged.
+            let due = Instant::now() + impairments.delay.saturating_sub(waited);
+            let _ = slot.egress.send((due, frame.to_vec()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::{ipv4, ipv6};
+    use crate::socket_device::SocketDevice;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    fn ethera(last: u8) -> ether::Address {
+        ether::Address([2, 0, 0, 0, 0, last])
+    }
+
+    #[test]
+    fn fdb_entries_age_out() {
+        let now = Instant::now();
+        let mut fdb = Fdb::new(Duration::from_secs(10));
+
+        fdb.learn(ethera(1), 3, now);
+        fdb.learn(ether::Address::BROADCAST, 3, now);
+        assert_eq!(fdb.lookup(ethera(1), now), Some(3));
+        assert_eq!(fdb.lookup(ether::Address::BROADCAST, now), None);
+
+        assert_eq!(fdb.lookup(ethera(1), now + Duration::from_secs(10)), None);
+        fdb.expire(now + Duration::from_secs(10));
+        assert!(fdb.entries.is_empty());
+    }
+
+    fn frame(dest: ether::Address, src: ether::Address) -> Vec<u8> {
+        ether::Frame {
+            dest,
+            src,
+            ethertype: ether::Type::Ipv6,
+            payload: vec![0; 46],
+            received: None,
+        }
+        .encode()
+    }
+
+    /// Read one length-prefixed frame, if any arrives in time.
+    fn recv(peer: &mut UnixStream) -> Option<Vec<u8>> {
+        peer.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        let mut length = [0; 4];
+        peer.read_exact(&mut length).ok()?;
+        let mut frame = vec![0; u32::from_be_bytes(length) as usize];
+        peer.read_exact(&mut frame).unwrap();
+
+        Some(frame)
+    }
+
+    fn send(peer: &mut UnixStream, frame: &[u8]) {
+        let mut device = SocketDevice::from_stream("peer", peer.try_clone().unwrap());
+        device.write(frame).unwrap();
+    }
+
+    #[test]
+    fn floods_unknown_then_forwards_learned() {
+        let mut bridge = Bridge::new(DEFAULT_AGING);
+        let mut peers: Vec<_> = (0..3)
+            .map(|i| {
+                let (ours, theirs) = UnixStream::pair().unwrap();
+                bridge.add_port(
+                    Box::new(SocketDevice::from_stream(format!("port{}", i), ours)),
+                    Impairments::default(),
+                );
+
+                theirs
+            })
+            .collect();
+        bridge.start().unwrap();
+
+        let request = frame(ethera(2), ethera(0));
+        send(&mut peers[0], &request);
+        assert_eq!(recv(&mut peers[1]), Some(request.clone()));
+        assert_eq!(recv(&mut peers[2]), Some(request));
+
+        let reply = frame(ethera(0), ethera(2));
+        send(&mut peers[2], &reply);
+        assert_eq!(recv(&mut peers[0]), Some(reply));
+        assert_eq!(recv(&mut peers[1]), None);
+    }
+
+    #[test]
+    fn impairments_apply_and_mirrors_see_everything() {
+        let mut bridge = Bridge::new(DEFAULT_AGING);
+        let mut add = |impairments, mirror| {
+            let (ours, theirs) = UnixStream::pair().unwrap();
+            let device = Box::new(SocketDevice::from_stream("port", ours));
+            if mirror {
+                bridge.add_mirror(device, impairments);
+            } else {
+                bridge.add_port(device, impairments);
+            }
+
+            theirs
+        };
+
+        let mut sender = add(Impairments::default(), false);
+        let mut lossy = add(
+            Impairments {
+                loss: 1.0,
+                ..Default::default()
+            },
+            false,
+        );
+        let mut slow = add(
+            Impairments {
+                delay: Duration::from_millis(50),
+                ..Default::default()
+            },
+            false,
+        );
+        let mut mirror = add(Impairments::default(), true);
+        bridge.start().unwrap();
+
+        let request = frame(ethera(2), ethera(0));
+        let sent = Instant::now();
+        send(&mut sender, &request);
+        assert_eq!(recv(&mut mirror), Some(request.clone()));
+        assert_eq!(recv(&mut slow), Some(request.clone()));
+        assert!(sent.elapsed() >= Duration::from_millis(50));
+        assert_eq!(recv(&mut lossy), None);
+
+        // Nothing sent into the mirror goes anywhere.
+        send(&mut mirror, &request);
+        assert_eq!(recv(&mut sender), None);
+    }
+
+    #[test]
+    fn multicast_only_goes_to_listeners_unless_flooded() {
+        let group: ipv6::Address = "ff3e::8000:1".parse().unwrap();
+        let mut report = vec![131, 0, 0, 0, 0, 0, 0, 0];
+        report.extend_from_slice(&u128::from(group).to_be_bytes());
+        let report = ether::Frame {
+            dest: group.multicast_ether_dest(),
+            src: ethera(2),
+            ethertype: ether::Type::Ipv6,
+            payload: ipv6::Packet::builder()
+                .protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+                .src("fe80::2".parse().unwrap())
+                .dest(group)
+                .payload(report)
+                .build()
+                .encode(),
+            received: None,
+        }
+        .encode();
+        let data = frame(group.multicast_ether_dest(), ethera(0));
+
+        for snooping in [true, false] {
+            let mut bridge = Bridge::new(DEFAULT_AGING);
+            bridge.set_snooping(snooping);
+            let mut peers: Vec<_> = (0..3)
+                .map(|i| {
+                    let (ours, theirs) = UnixStream::pair().unwrap();
+                    bridge.add_port(
+                        Box::new(SocketDevice::from_stream(format!("port{}", i), ours)),
+                        Impairments::default(),
+                    );
+
+                    theirs
+                })
+                .collect();
+            bridge.start().unwrap();
+
+            send(&mut peers[2], &report);
+            assert_eq!(recv(&mut peers[0]), Some(report.clone()));
+            assert_eq!(recv(&mut peers[1]), Some(report.clone()));
+
+            send(&mut peers[0], &data);
+            assert_eq!(recv(&mut peers[2]), Some(data.clone()));
+            assert_eq!(recv(&mut peers[1]).is_some(), !snooping);
+        }
+    }
+
+    #[test]
+    fn ports_can_be_plugged_in_and_out_while_running() {
+        let (ours, mut first) = UnixStream::pair().unwrap();
+        let mut bridge = Bridge::new(DEFAULT_AGING);
+        bridge.add_port(
+            Box::new(SocketDevice::from_stream("first", ours)),
+            Impairments::default(),
+        );
+        let handle = bridge.start().unwrap();
+
+        let (ours, mut second) = UnixStream::pair().unwrap();
+        let port = handle
+            .add_port(
+                Box::new(SocketDevice::from_stream("second", ours)),
+                Impairments::default(),
+            )
+            .unwrap();
+        assert_eq!(port, 1);
+
+        let hello = frame(ethera(0), ethera(1));
+        send(&mut second, &hello);
+        assert_eq!(recv(&mut first), Some(hello.clone()));
+
+        handle.remove_port(port);
+        send(&mut second, &hello);
+        assert_eq!(recv(&mut first), None);
+        send(&mut first, &frame(ethera(1), ethera(0)));
+        assert_eq!(recv(&mut second), None);
+    }
+}
@@ -0,0 +1,295 @@
+//! Central table of the node's own TCP and UDP flows, like conntrack, published to status so test
+//! harnesses can check what traffic went where.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::ipv4;
+use super::tcp::{self, Direction, Endpoint};
+use crate::{status, warn};
+
+// The same timeouts as Linux's conntrack.
+const UDP_UNREPLIED_TIMEOUT: Duration = Duration::from_secs(30);
+const UDP_REPLIED_TIMEOUT: Duration = Duration::from_secs(180);
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct FlowKey {
+    pub protocol: ipv4::ProtocolNumber,
+    pub local: Endpoint,
+    pub remote: Endpoint,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UdpState {
+    /// Only seen going one way so far.
+    Unreplied,
+    Replied,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum FlowState {
+    Udp(UdpState),
+    Tcp(tcp::State),
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FlowCounters {
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Clone, Debug)]
+struct Flow {
+    state: FlowState,
+    /// Which way the first packet went, so replies can be told apart.
+    opened: Direction,
+    counters: FlowCounters,
+    last_seen: Instant,
+}
+
+/// Flows shared between the stacks that record them and whatever reports on them.
+#[derive(Clone, Default)]
+pub struct FlowTable {
+    flows: Arc<Mutex<HashMap<FlowKey, Flow>>>,
+}
+
+impl FlowTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a packet; UDP flows are created by their first packet, TCP ones by the TCP server.
+    pub fn record(&self, key: FlowKey, direction: Direction, bytes: usize, now: Instant) {
+        let mut flows = self.flows.lock().unwrap();
+
+        let flow = flows.entry(key).or_insert_with(|| Flow {
+            state: match key.protocol {
+                ipv4::ProtocolNumber::Tcp => FlowState::Tcp(tcp::State::SynReceived),
+                _ => FlowState::Udp(UdpState::Unreplied),
+            },
+            opened: direction,
+            counters: FlowCounters::default(),
+            last_seen: now,
+        });
+
+        if direction != flow.opened && flow.state == FlowState::Udp(UdpState::Unreplied) {
+            flow.state = FlowState::Udp(UdpState::Replied);
+        }
+
+        let counters = &mut flow.counters;
+        match direction {
+            Direction::Inbound => {
+                counters.packets_received += 1;
+                counters.bytes_received += bytes as u64;
+            }
+            Direction::Outbound => {
+                counters.packets_sent += 1;
+                counters.bytes_sent += bytes as u64;
+            }
+        }
+        flow.last_seen = now;
+    }
+
+    /// A hook counting the TCP server's segments.
+    pub fn tcp_hook(&self) -> tcp::FlowHook {
+        let table = self.clone();
+
+        Box::new(move |key, direction, segment| {
+            table.record(
+                FlowKey {
+                    protocol: ipv4::ProtocolNumber::Tcp,
+                    local: key.local,
+                    remote: key.remote,
+                },
+                direction,
+                segment.payload.len(),
+                Instant::now(),
+            );
+
+            None
+        })
+    }
+
+    /// Bring TCP flows in line with the TCP server's connections, and forget idle UDP flows.
+    fn refresh(&self, connections: &[(tcp::ConnectionKey, tcp::State)], now: Instant) {
+        let mut flows = self.flows.lock().unwrap();
+        let states: HashMap<_, _> = connections
+            .iter()
+            .map(|(key, state)| {
+                (
+                    FlowKey {
+                        protocol: ipv4::ProtocolNumber::Tcp,
+                        local: key.local,
+                        remote: key.remote,
+                    },
+                    *state,
+                )
+            })
+            .collect();
+
+        flows.retain(|key, flow| match flow.state {
+            FlowState::Tcp(_) => states.contains_key(key),
+            FlowState::Udp(state) => {
+                let timeout = match state {
+                    UdpState::Unreplied => UDP_UNREPLIED_TIMEOUT,
+                    UdpState::Replied => UDP_REPLIED_TIMEOUT,
+                };
+
+                now.saturating_duration_since(flow.last_seen) < timeout
+            }
+        });
+
+        for (key, state) in states {
+            flows
+                .entry(key)
+                .or_insert_with(|| Flow {
+                    state: FlowState::Tcp(state),
+                    opened: Direction::Inbound,
+                    counters: FlowCounters::default(),
+                    last_seen: now,
+                })
+                .state = FlowState::Tcp(state);
+        }
+    }
+
+    pub fn flows(&self) -> Vec<status::FlowStatus> {
+        let mut result: Vec<_> = self
+            .flows
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, flow)| status::FlowStatus {
+                protocol: match key.protocol {
+                    ipv4::ProtocolNumber::Tcp => "tcp".to_string(),
+                    _ => "udp".to_string(),
+                },
+                local: key.local.to_string(),
+                remote: key.remote.to_string(),
+                state: flow.state,
+                counters: flow.counters,
+            })
+            .collect();
+        result.sort_by(|a, b| {
+            (&a.protocol, &a.local, &a.remote).cmp(&(&b.protocol, &b.local, &b.remote))
+        });
+
+        result
+    }
+
+    /// Current flows, after catching up with the TCP server.
+    pub fn snapshot(&self, tcp_flows: &tcp::FlowHandle) -> Vec<status::FlowStatus> {
+        match tcp_flows.connections() {
+            Ok(connections) => self.refresh(&connections, Instant::now()),
+            Err(e) => warn!("listing tcp connections failed: {}", e),
+        }
+
+        self.flows()
+    }
+
+    /// Keep the status up to date, writing it out whenever flows come, go or change state.
+    pub fn start(&self, tcp_flows: tcp::FlowHandle) {
+        let table = self.clone();
+
+        thread::spawn(move || {
+            let mut published = Vec::new();
+
+            loop {
+                thread::sleep(REFRESH_INTERVAL);
+
+                let flows = table.snapshot(&tcp_flows);
+                let summary: Vec<_> = flows
+                    .iter()
+                    .map(|f| {
+                        (
+                            f.protocol.clone(),
+                            f.local.clone(),
+                            f.remote.clone(),
+                            f.state,
+                        )
+                    })
+                    .collect();
+
+                if summary != published {
+                    published = summary;
+                    status::update(|s| s.flows = flows);
+                } else {
+                    status::record(|s| s.flows = flows);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(protocol: ipv4::ProtocolNumber) -> FlowKey {
+        FlowKey {
+            protocol,
+            local: "[2001:db8::1]:53".parse().unwrap(),
+            remote: "[2001:db8::2]:40000".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn udp_flows_are_replied_to_then_expire() {
+        let now = Instant::now();
+        let table = FlowTable::new();
+        let udp = key(ipv4::ProtocolNumber::Udp);
+
+        table.record(udp, Direction::Inbound, 30, now);
+        assert_eq!(table.flows()[0].state, FlowState::Udp(UdpState::Unreplied));
+
+        table.record(udp, Direction::Outbound, 100, now);
+        let flows = table.flows();
+        assert_eq!(flows[0].state, FlowState::Udp(UdpState::Replied));
+        assert_eq!(
+            flows[0].counters,
+            FlowCounters {
+                packets_sent: 1,
+                packets_received: 1,
+                bytes_sent: 100,
+                bytes_received: 30,
+            }
+        );
+
+        table.refresh(&[], now + UDP_UNREPLIED_TIMEOUT);
+        assert_eq!(table.flows().len(), 1);
+        table.refresh(&[], now + UDP_REPLIED_TIMEOUT);
+        assert!(table.flows().is_empty());
+    }
+
+    #[test]
+    fn tcp_flows_follow_connections() {
+        let now = Instant::now();
+        let table = FlowTable::new();
+        let tcp = key(ipv4::ProtocolNumber::Tcp);
+        let connection = tcp::ConnectionKey {
+            local: tcp.local,
+            remote: tcp.remote,
+        };
+
+        table.record(tcp, Direction::Inbound, 5, now);
+        table.refresh(&[(connection, tcp::State::Established)], now);
+        let flows = table.flows();
+        assert_eq!(flows[0].state, FlowState::Tcp(tcp::State::Established));
+        assert_eq!(flows[0].counters.bytes_received, 5);
+        assert_eq!(
+            serde_json::to_value(&flows[0]).unwrap()["state"],
+            "established"
+        );
+
+        table.refresh(&[], now);
+        assert!(table.flows().is_empty());
+    }
+}
@@ -0,0 +1,484 @@
+//! DNS names and messages, and a stub resolver for the node's own lookups.
+
+use anyhow::{anyhow, bail, Result as AHResult};
+use nom::{
+    bytes::complete::{tag, take},
+    combinator::{map, map_res, verify},
+    multi::{length_data, many_till},
+    number::complete::{be_u16, be_u32, be_u8},
+};
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::encdec::{BIResult, EncodeTo};
+use super::{ipv4, ipv6, limits, udp};
+use crate::{encode, proto_enum_with_unknown, try_parse, warn};
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc1035
+
+pub const PORT: u16 = 53;
+
+const RESPONSE: u16 = 0x8000;
+const RECURSION_DESIRED: u16 = 0x0100;
+const CLASS_IN: u16 = 1;
+
+proto_enum_with_unknown!(RecordType, u16, {
+    A = 1,
+    Cname = 5,
+    Aaaa = 28,
+});
+
+proto_enum_with_unknown!(ResponseCode, u8, {
+    NoError = 0,
+    FormatError = 1,
+    ServerFailure = 2,
+    NameError = 3,
+    NotImplemented = 4,
+    Refused = 5,
+});
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc1035#section-2.3.4
+const MAX_LABEL_LEN: usize = 63;
+
+fn labels(name: &str) -> impl Iterator<Item = &str> {
+    name.split('.').filter(|label| !label.is_empty())
+}
+
+/// A domain name, encoded as uncompressed labels. Encoding panics on labels too long to encode,
+/// rather than truncating them.
+// Ref: https://datatracker.ietf.org/doc/html/rfc1035#section-3.1
+#[derive(Clone, Copy, Debug)]
+pub struct Name<'a>(pub &'a str);
+
+impl EncodeTo for Name<'_> {
+    fn encoded_len(&self) -> usize {
+        labels(self.0).map(|label| label.len() + 1).sum::<usize>() + 1
+    }
+
+    fn encode_to(&self, buf: &mut [u8]) {
+        let mut i = 0;
+
+        for label in labels(self.0) {
+            assert!(
+                label.len() <= MAX_LABEL_LEN,
+                "dns label too long: {:?}",
+                label
+            );
+            buf[i] = label.len() as u8;
+            buf[i + 1..i + 1 + label.len()].copy_from_slice(label.as_bytes());
+            i += label.len() + 1;
+        }
+
+        buf[i] = 0;
+    }
+}
+
+/// Parse an uncompressed name.
+pub fn name<'a>(input: &'a [u8]) -> BIResult<'a, String> {
+    let label = map_res(
+        length_data(verify(be_u8, |len| (1..=MAX_LABEL_LEN as u8).contains(len))),
+        std::str::from_utf8,
+    );
+
+    map(many_till(label, tag([0])), |(labels, _)| labels.join("."))(input)
+}
+
+/// Skip over a possibly-compressed name.
+// Ref: https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.4
+fn skip_name<'a>(mut input: &'a [u8]) -> BIResult<'a, ()> {
+    loop {
+        let (rest, len) = be_u8(input)?;
+
+        match len {
+            0 => return Ok((rest, ())),
+            // A pointer ends the name
+            0xc0..=0xff => return map(be_u8, |_| ())(rest),
+            _ => input = take(len)(rest)?.0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    pub id: u16,
+    pub name: String,
+    pub record_type: RecordType,
+}
+
+impl Query {
+    pub fn encode(&self) -> Vec<u8> {
+        encode!(
+            self.id,
+            RECURSION_DESIRED,
+            1u16, // Questions
+            0u16, // Answers
+            0u16, // Authority records
+            0u16, // Additional records
+            Name(&self.name),
+            self.record_type,
+            CLASS_IN,
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RecordData {
+    A(ipv4::Address),
+    Aaaa(ipv6::Address),
+    Other(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub record_type: RecordType,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub id: u16,
+    pub code: ResponseCode,
+    pub answers: Vec<Record>,
+}
+
+impl Response {
+    /// Addresses among the answers, in the order given.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.answers
+            .iter()
+            .filter_map(|record| match record.data {
+                RecordData::A(address) => Some(IpAddr::from(address)),
+                RecordData::Aaaa(address) => Some(IpAddr::from(address)),
+                RecordData::Other(_) => None,
+            })
+            .collect()
+    }
+}
+
+fn question<'a>(input: &'a [u8]) -> BIResult<'a, ()> {
+    let (input, _) = skip_name(input)?;
+    // ignore type and class
+    map(take(4usize), |_| ())(input)
+}
+
+fn record<'a>(input: &'a [u8]) -> BIResult<'a, Record> {
+    let (input, _) = skip_name(input)?;
+    let (input, record_type) = map_res(be_u16, RecordType::try_from)(input)?;
+    let (input, class) = be_u16(input)?;
+    let (input, ttl) = be_u32(input)?;
+    let (input, data) = length_data(be_u16)(input)?;
+
+    let data = match (record_type, class, data.len()) {
+        (RecordType::A, CLASS_IN, 4) => RecordData::A(ipv4::address(data)?.1),
+        (RecordType::Aaaa, CLASS_IN, 16) => RecordData::Aaaa(ipv6::address(data)?.1),
+        _ => RecordData::Other(data.to_vec()),
+    };
+
+    Ok((
+        input,
+        Record {
+            record_type,
+            ttl,
+            data,
+        },
+    ))
+}
+
+/// Parse a response, ignoring everything but its answers.
+pub fn response(input: &[u8]) -> AHResult<Response> {
+    try_parse!(
+        input,
+        {
+            let (input, id) = be_u16(input)?;
+            let (input, flags) = verify(be_u16, |flags| flags & RESPONSE != 0)(input)?;
+            let (input, questions) = be_u16(input)?;
+            let (input, answers) = be_u16(input)?;
+            // ignore authority and additional records
+            let (input, _) = take(4usize)(input)?;
+            // At least a root name, type and class each, and a TTL and data length for answers.
+            let (input, _) = limits::count(question, questions as usize, 5)(input)?;
+            let (input, answers) = limits::count(record, answers as usize, 11)(input)?;
+
+            Ok((
+                input,
+                Response {
+                    id,
+                    code: ResponseCode::try_from((flags & 0xf) as u8).unwrap(),
+                    answers,
+                },
+            ))
+        },
+        "parsing dns response failed: {}"
+    )
+}
+
+/// Encode a response answering `query` with `addresses`, for simulated servers.
+pub fn answer(query: &Query, ttl: u32, addresses: &[IpAddr]) -> Vec<u8> {
+    let records: Vec<_> = addresses
+        .iter()
+        .map(|address| {
+            let (record_type, data) = match address {
+                IpAddr::V4(a) => (RecordType::A, a.octets().to_vec()),
+                IpAddr::V6(a) => (RecordType::Aaaa, a.octets().to_vec()),
+            };
+
+            encode!(
+                0xc00cu16, // Pointer to the question's name
+                record_type,
+                CLASS_IN,
+                ttl,
+                data.len() as u16,
+                data,
+            )
+        })
+        .collect();
+
+    encode!(
+        query.id,
+        RESPONSE | RECURSION_DESIRED,
+        1u16,
+        records.len() as u16,
+        0u16,
+        0u16,
+        Name(&query.name),
+        query.record_type,
+        CLASS_IN,
+        records.concat(),
+    )
+}
+
+/// Parse a query, e.g. for a simulated server to answer.
+pub fn query(input: &[u8]) -> AHResult<Query> {
+    try_parse!(
+        input,
+        {
+            let (input, id) = be_u16(input)?;
+            let (input, _) = verify(be_u16, |flags| flags & RESPONSE == 0)(input)?;
+            let (input, _) = verify(be_u16, |questions| *questions == 1)(input)?;
+            let (input, _) = take(6usize)(input)?;
+            let (input, name) = name(input)?;
+            let (input, record_type) = map_res(be_u16, RecordType::try_from)(input)?;
+            // ignore class, and any additional records
+            let (input, _) = be_u16(input)?;
+
+            Ok((
+                input,
+                Query {
+                    id,
+                    name,
+                    record_type,
+                },
+            ))
+        },
+        "parsing dns query failed: {}"
+    )
+}
+
+pub struct Config {
+    /// Address to send queries from.
+    pub source: ipv6::Address,
+    /// Tried in order, before any servers learned from router advertisements.
+    pub servers: Vec<ipv6::Address>,
+    /// How long to wait for each server to answer.
+    pub timeout: Duration,
+    /// Times to go through the list of servers before giving up.
+    pub attempts: u32,
+}
+
+/// Stub resolver, which asks recursive servers and waits for their answers.
+pub struct Resolver {
+    config: Config,
+    learned: Option<ipv6::DnsHandle>,
+    // Lookups take turns, so one can't consume another's responses.
+    socket: Mutex<udp::Socket>,
+}
+
+impl Resolver {
+    pub fn new(
+        config: Config,
+        udp_server: &udp::Server,
+        learned: Option<ipv6::DnsHandle>,
+    ) -> AHResult<Self> {
+        Ok(Self {
+            config,
+            learned,
+            socket: Mutex::new(udp_server.bind_ephemeral()?),
+        })
+    }
+
+    fn servers(&self) -> Vec<ipv6::Address> {
+        let mut servers = self.config.servers.clone();
+
+        for server in self.learned.iter().flat_map(|l| l.servers()) {
+            if !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+
+        servers
+    }
+
+    /// Look up `name`, returning the addresses of the given type it resolves to.
+    ///
+    /// Servers that fail or don't answer in time are skipped, but a name that doesn't exist is
+    /// reported as an error straight away.
+    pub fn resolve(&self, name: &str, record_type: RecordType) -> AHResult<Vec<IpAddr>> {
+        let servers = self.servers();
+        if servers.is_empty() {
+            bail!("no dns servers configured or learned");
+        }
+
+        let socket = self.socket.lock().unwrap();
+
+        for _ in 0..self.config.attempts {
+            for &server in &servers {
+                let query = Query {
+                    id: rand::random(),
+                    name: name.to_string(),
+                    record_type,
+                };
+                socket.send_to(self.config.source, server, PORT, query.encode())?;
+
+                let response = match self.wait(&socket, server, query.id) {
+                    Some(response) => response,
+                    None => continue,
+                };
+
+                match response.code {
+                    ResponseCode::NoError => return Ok(response.addresses()),
+                    ResponseCode::NameError => bail!("{} does not exist", name),
+                    code => warn!("dns server {} answered {}", server, code),
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "no answer for {} after {} attempts",
+            name,
+            self.config.attempts
+        ))
+    }
+
+    fn wait(&self, socket: &udp::Socket, server: ipv6::Address, id: u16) -> Option<Response> {
+        let deadline = Instant::now() + self.config.timeout;
+
+        loop {
+            let datagram = socket.receiver().recv_deadline(deadline).ok()?;
+
+            if datagram.src != server || datagram.packet.src_port != PORT {
+                continue;
+            }
+
+            match response(&datagram.packet.payload) {
+                Ok(response) if response.id == id => return Some(response),
+                // Late answers to earlier queries, or junk
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+    use crate::protocols::{ether, neighbor};
+
+    fn hexstring(s: &str) -> Vec<u8> {
+        hex::decode(s).unwrap()
+    }
+
+    #[test]
+    fn query_encodes() {
+        assert_eq!(
+            Query {
+                id: 0x1234,
+                name: "example.net".to_string(),
+                record_type: RecordType::Aaaa,
+            }
+            .encode(),
+            hexstring("123401000001000000000000076578616d706c65036e657400001c0001")
+        );
+    }
+
+    #[test]
+    fn response_with_cname_decodes() {
+        let response = response(&hexstring(concat!(
+            "12348180000100020000000003777777076578616d706c65036e657400001c0001",
+            "c00c000500010000012c0002c010",
+            "c010001c00010000012c001020010db8000000000000000000000001",
+        )))
+        .unwrap();
+
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(response.code, ResponseCode::NoError);
+        assert_eq!(
+            response.answers[0].data,
+            RecordData::Other(vec![0xc0, 0x10])
+        );
+        assert_eq!(
+            response.addresses(),
+            vec!["2001:db8::1".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn resolver_retries_until_answered() {
+        let a_ether = "02:00:00:00:00:0a".parse().unwrap();
+        let b_ether = "02:00:00:00:00:0b".parse().unwrap();
+        let (mut a_eth, mut b_eth) = ether::MemoryInterface::pair(a_ether, b_ether);
+        // Neither address is really the node's own, so neither would answer a solicitation.
+        let a_neighbors = neighbor::Table::new("a");
+        a_neighbors.learn("fe80::b".parse().unwrap(), b_ether, true);
+        let b_neighbors = neighbor::Table::new("b");
+        b_neighbors.learn("fe80::a".parse().unwrap(), a_ether, true);
+        let mut a_ipv6 = ipv6::Server::new(&mut a_eth, a_neighbors).unwrap();
+        let mut b_ipv6 = ipv6::Server::new(&mut b_eth, b_neighbors).unwrap();
+        let a_udp = udp::Server::new(&mut a_ipv6).unwrap();
+        let b_udp = udp::Server::new(&mut b_ipv6).unwrap();
+        a_ipv6.start();
+        b_ipv6.start();
+        let executor = Executor::new("dns-test", 1);
+        a_udp.start(&executor);
+        b_udp.start(&executor);
+
+        let server_address = "fe80::b".parse().unwrap();
+        let socket = b_udp.bind(PORT).unwrap();
+        std::thread::spawn(move || {
+            // Ignore the first query, so the resolver has to try again.
+            let _ = socket.receiver().recv();
+
+            let datagram = socket.receiver().recv().unwrap();
+            let query = query(&datagram.packet.payload).unwrap();
+            let answer = answer(&query, 60, &["2001:db8::1".parse().unwrap()]);
+            socket
+                .send_to(
+                    server_address,
+                    datagram.src,
+                    datagram.packet.src_port,
+                    answer,
+                )
+                .unwrap();
+        });
+
+        let resolver = Resolver::new(
+            Config {
+                source: "fe80::a".parse().unwrap(),
+                servers: vec![server_address],
+                timeout: Duration::from_millis(200),
+                attempts: 2,
+            },
+            &a_udp,
+            Some(a_ipv6.dns()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            resolver.resolve("example.net", RecordType::Aaaa).unwrap(),
+            vec!["2001:db8::1".parse::<IpAddr>().unwrap()]
+        );
+    }
+}
@@ -1,13 +1,61 @@
-use byteorder::{ByteOrder, NetworkEndian};
-use nom::IResult;
-use std::fmt::Write;
+use nom::error::ErrorKind;
+use nom::{AsBytes, IResult};
+use std::fmt::{Display, Formatter, Write};
 
 pub type BIResult<'a, O> = IResult<&'a [u8], O>;
 pub type SIResult<'a, O> = IResult<&'a str, O>;
 
+/// An enum of protocol values, with conversion from and encoding to `$type`, and Display and
+/// FromStr using each variant's name or, if given after `=>`, its usual spelling:
+/// `proto_enum!(Protocol, u8, { Ipv6Icmp = 58 => "IPv6-ICMP", })`.
 #[macro_export]
 macro_rules! proto_enum {
-    ($name:ident, $type:ty, { $($variant_name:ident = $variant_disc:expr,)+ } $(,)?) => {
+    (@name $variant_name:ident $display:literal) => {
+        $display
+    };
+    (@name $variant_name:ident) => {
+        std::stringify!($variant_name)
+    };
+    // Shared by both macros: listing, and parsing names case-insensitively or values as numbers.
+    (@common $name:ident, $type:ty, { $($variant_name:ident $($display:literal)?,)+ }) => {
+        impl $name {
+            /// Every named variant, in the order they're declared.
+            pub const ALL: &'static [$name] = &[ $( $name::$variant_name, )+ ];
+
+            pub fn iter() -> impl Iterator<Item = $name> {
+                Self::ALL.iter().copied()
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = anyhow::Error;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                $(
+                    if s.eq_ignore_ascii_case($crate::proto_enum!(@name $variant_name $($display)?))
+                        || s.eq_ignore_ascii_case(std::stringify!($variant_name))
+                    {
+                        return Ok($name::$variant_name);
+                    }
+                )+
+
+                match s.parse::<$type>() {
+                    Ok(value) => <$name as std::convert::TryFrom<$type>>::try_from(value),
+                    Err(_) => anyhow::bail!(
+                        "unknown {} {:?}; expected a number or one of: {}",
+                        std::stringify!($name),
+                        s,
+                        Self::iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", "),
+                    ),
+                }
+            }
+        }
+    };
+    (
+        $name:ident,
+        $type:ty,
+        { $($variant_name:ident = $variant_disc:expr $(=> $display:literal)?,)+ } $(,)?
+    ) => {
         #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
         pub enum $name {
             $( $variant_name = $variant_disc, )+
@@ -19,7 +67,7 @@ macro_rules! proto_enum {
             fn try_from(value: $type) -> Result<Self, Self::Error> {
                 match value {
                     $( $variant_disc => Ok($name::$variant_name), )+
-                    _ => { anyhow::bail!("unknown {}: {}", std::stringify!($ident), value) }
+                    _ => { anyhow::bail!("unknown {}: {}", std::stringify!($name), value) }
                 }
             }
         }
@@ -27,12 +75,14 @@ macro_rules! proto_enum {
         impl std::fmt::Display for $name {
             fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
                 write!(f, "{}", match self {
-                    $( $name::$variant_name => std::stringify!($variant_name), )+
+                    $( $name::$variant_name => $crate::proto_enum!(@name $variant_name $($display)?), )+
                 })
             }
         }
 
-        impl crate::protocols::encdec::EncodeTo for $name {
+        $crate::proto_enum!(@common $name, $type, { $($variant_name $($display)?,)+ });
+
+        impl $crate::protocols::encdec::EncodeTo for $name {
             fn encoded_len(&self) -> usize {
                 std::mem::size_of::<$type>()
             }
@@ -46,9 +96,15 @@ macro_rules! proto_enum {
     };
 }
 
+/// Like `proto_enum!`, but keeping values without a variant as `Unknown`, which displays as
+/// `Unknown(value)` and parses from the bare value.
 #[macro_export]
 macro_rules! proto_enum_with_unknown {
-    ($name:ident, $type:ty, { $($variant_name:ident = $variant_disc:expr,)+ } $(,)?) => {
+    (
+        $name:ident,
+        $type:ty,
+        { $($variant_name:ident = $variant_disc:expr $(=> $display:literal)?,)+ } $(,)?
+    ) => {
         #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
         pub enum $name {
             $( $variant_name, )+
@@ -66,16 +122,33 @@ macro_rules! proto_enum_with_unknown {
             }
         }
 
+        impl From<$name> for $type {
+            fn from(value: $name) -> Self {
+                match value {
+                    $( $name::$variant_name => $variant_disc, )+
+                    $name::Unknown(value) => value,
+                }
+            }
+        }
+
         impl std::fmt::Display for $name {
             fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
                 match self {
-                    $( $name::$variant_name => write!(f, "{}", std::stringify!($variant_name)), )+
+                    $(
+                        $name::$variant_name => write!(
+                            f,
+                            "{}",
+                            $crate::proto_enum!(@name $variant_name $($display)?),
+                        ),
+                    )+
                     $name::Unknown(value) => write!(f, "Unknown({})", value),
                 }
             }
         }
 
-        impl crate::protocols::encdec::EncodeTo for $name {
+        $crate::proto_enum!(@common $name, $type, { $($variant_name $($display)?,)+ });
+
+        impl $crate::protocols::encdec::EncodeTo for $name {
             fn encoded_len(&self) -> usize {
                 std::mem::size_of::<$type>()
             }
@@ -104,7 +177,7 @@ pub fn hexdump(data: &[u8]) -> Result<String, std::fmt::Error> {
             while i < 16 && i < chunk.len() {
                 write!(&mut result, "{:02x} ", chunk[i])?;
 
-                i = i + 1;
+                i += 1;
             }
 
             write!(&mut result, "{}", "   ".repeat(16 - i))?;
@@ -120,11 +193,11 @@ pub fn hexdump(data: &[u8]) -> Result<String, std::fmt::Error> {
                     write!(&mut result, ".")?;
                 }
 
-                i = i + 1;
+                i += 1;
             }
         }
 
-        write!(&mut result, "\n")?;
+        writeln!(&mut result)?;
     }
 
     Ok(result)
@@ -145,33 +218,52 @@ impl<T: EncodeTo> EncodeTo for &T {
     }
 }
 
-impl EncodeTo for u8 {
-    fn encoded_len(&self) -> usize {
-        1
-    }
+/// Serialize and deserialize `$name` as its `Display` and `FromStr` forms, as addresses are
+/// written in config and status.
+#[macro_export]
+macro_rules! serde_via_str {
+    ($name:ty) => {
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
 
-    fn encode_to(&self, buf: &mut [u8]) {
-        buf[0] = *self;
-    }
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                <String as serde::Deserialize>::deserialize(deserializer)?
+                    .parse()
+                    .map_err(serde::de::Error::custom)
+            }
+        }
+    };
 }
 
-impl EncodeTo for u16 {
-    fn encoded_len(&self) -> usize {
-        2
-    }
+macro_rules! impl_encode_to_for_int {
+    ($($type:ty),+) => {
+        $(
+            impl EncodeTo for $type {
+                fn encoded_len(&self) -> usize {
+                    std::mem::size_of::<$type>()
+                }
 
-    fn encode_to(&self, buf: &mut [u8]) {
-        NetworkEndian::write_u16(buf, *self);
-    }
+                fn encode_to(&self, buf: &mut [u8]) {
+                    buf[..std::mem::size_of::<$type>()].copy_from_slice(&self.to_be_bytes());
+                }
+            }
+        )+
+    };
 }
 
-impl EncodeTo for u32 {
+impl_encode_to_for_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl<const N: usize> EncodeTo for [u8; N] {
     fn encoded_len(&self) -> usize {
-        4
+        N
     }
 
     fn encode_to(&self, buf: &mut [u8]) {
-        NetworkEndian::write_u32(buf, *self);
+        buf[..N].copy_from_slice(self);
     }
 }
 
@@ -181,7 +273,7 @@ impl EncodeTo for &[u8] {
     }
 
     fn encode_to(&self, buf: &mut [u8]) {
-        (&mut buf[..self.len()]).copy_from_slice(self);
+        buf[..self.len()].copy_from_slice(self);
     }
 }
 
@@ -194,7 +286,7 @@ impl<T: EncodeTo> EncodeTo for Vec<T> {
         let mut output = buf;
 
         for part in self.iter() {
-            part.encode_to(&mut output);
+            part.encode_to(output);
             output = &mut output[part.encoded_len()..];
         }
     }
@@ -206,8 +298,11 @@ macro_rules! encode_to {
         {
             let mut buf = $buf.as_mut();
             $(
-                $val.encode_to(&mut buf);
-                buf = &mut buf[$val.encoded_len()..];
+                // Bind each value once, so expressions that build values aren't evaluated again.
+                let val = &$val;
+                let len = $crate::protocols::encdec::EncodeTo::encoded_len(val);
+                $crate::protocols::encdec::EncodeTo::encode_to(val, &mut buf);
+                buf = &mut buf[len..];
             )+
             let _ = buf;
         }
@@ -215,35 +310,282 @@ macro_rules! encode_to {
 }
 #[macro_export]
 macro_rules! encode {
-    ( $($val:expr $(,)?)+ ) => {
+    // Bind each value once, so expressions that build values aren't evaluated again.
+    (@bind [$($bound:ident)*] $val:expr, $($rest:expr,)*) => {
         {
-            let mut result = Vec::new();
-            result.resize(
-                $($val.encoded_len() + )+ 0,
-                0u8,
-            );
-
-            crate::encode_to!(&mut result[..], $($val,)+);
+            let part = &$val;
+            $crate::encode!(@bind [$($bound)* part] $($rest,)*)
+        }
+    };
+    (@bind [$($bound:ident)*]) => {
+        {
+            let mut result =
+                vec![0u8; $($crate::protocols::encdec::EncodeTo::encoded_len($bound) + )* 0];
+            $crate::encode_to!(&mut result[..], $($bound,)*);
 
             result
         }
-    }
+    };
+    ( $($val:expr $(,)?)+ ) => {
+        $crate::encode!(@bind [] $($val,)+)
+    };
 }
 
+/// Pack booleans into a bitfield, most significant bit first: `flags!(u8, managed => 7, other => 6)`.
+#[macro_export]
+macro_rules! flags {
+    ($type:ty, $($flag:expr => $bit:expr),+ $(,)?) => {
+        0 $( | (($flag as $type) << $bit) )+
+    };
+}
+
+/// Whether bit `bit` (counting from the least significant) of `value` is set.
+pub fn flag(value: impl Into<u128>, bit: u32) -> bool {
+    value.into() & (1 << bit) != 0
+}
+
+/// Define a struct that's encoded as each of its fields in order.
+#[macro_export]
+macro_rules! encodable_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $( $(#[$field_meta:meta])* $field_vis:vis $field:ident : $type:ty ),+ $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $( $(#[$field_meta])* $field_vis $field: $type, )+
+        }
+
+        impl $crate::protocols::encdec::EncodeTo for $name {
+            fn encoded_len(&self) -> usize {
+                0 $( + $crate::protocols::encdec::EncodeTo::encoded_len(&self.$field) )+
+            }
+
+            fn encode_to(&self, buf: &mut [u8]) {
+                $crate::encode_to!(buf, $( self.$field, )+);
+            }
+        }
+    };
+}
+
+/// Define a struct from its wire layout, getting both an `EncodeTo` impl and a nom parser
+/// (`Name::parse`) that read and write the items in order.
+///
+/// Each item is one of:
+///
+/// * `const VALUE => parser`: always encoded as `VALUE`, and parsing fails if it doesn't match.
+/// * `reserved VALUE => parser`: encoded as `VALUE`, and ignored when parsing.
+/// * `flags(type) { field => bit, ... } => parser`: boolean fields packed into one bitfield.
+/// * `field: Type => parser`: an ordinary field.
+#[macro_export]
+macro_rules! packet_layout {
+    (@parse $input:ident, { const $val:expr => $parser:expr }) => {
+        let ($input, _) = nom::combinator::verify($parser, |v| *v == $val)($input)?;
+    };
+    (@parse $input:ident, { reserved $val:expr => $parser:expr }) => {
+        let ($input, _) = $parser($input)?;
+    };
+    (@parse $input:ident, { flags($type:ty) { $($field:ident => $bit:expr),+ } => $parser:expr }) => {
+        let ($input, bits) = $parser($input)?;
+        $( let $field = $crate::protocols::encdec::flag(bits, $bit); )+
+    };
+    (@parse $input:ident, { field $field:ident => $parser:expr }) => {
+        let ($input, $field) = $parser($input)?;
+    };
+
+    (@encode { const $val:expr => $parser:expr }) => { $val };
+    (@encode { reserved $val:expr => $parser:expr }) => { $val };
+    (@encode { flags($type:ty) { $($field:ident => $bit:expr),+ } => $parser:expr }) => {
+        $crate::flags!($type, $( *$field => $bit ),+)
+    };
+    (@encode { field $field:ident => $parser:expr }) => { $field };
+
+    (
+        @munch $header:tt [$($fields:tt)*] [$($items:tt)*] [$($names:ident)*]
+        const $val:expr => $parser:expr $(, $($rest:tt)*)?
+    ) => {
+        $crate::packet_layout!(
+            @munch $header [$($fields)*] [$($items)* { const $val => $parser }] [$($names)*]
+            $($($rest)*)?
+        );
+    };
+    (
+        @munch $header:tt [$($fields:tt)*] [$($items:tt)*] [$($names:ident)*]
+        reserved $val:expr => $parser:expr $(, $($rest:tt)*)?
+    ) => {
+        $crate::packet_layout!(
+            @munch $header [$($fields)*] [$($items)* { reserved $val => $parser }] [$($names)*]
+            $($($rest)*)?
+        );
+    };
+    (
+        @munch $header:tt [$($fields:tt)*] [$($items:tt)*] [$($names:ident)*]
+        flags($type:ty) { $( $(#[$field_meta:meta])* $field_vis:vis $field:ident => $bit:expr ),+ $(,)? }
+            => $parser:expr $(, $($rest:tt)*)?
+    ) => {
+        $crate::packet_layout!(
+            @munch $header
+            [$($fields)* $( $(#[$field_meta])* $field_vis $field: bool, )+]
+            [$($items)* { flags($type) { $($field => $bit),+ } => $parser }]
+            [$($names)* $($field)+]
+            $($($rest)*)?
+        );
+    };
+    (
+        @munch $header:tt [$($fields:tt)*] [$($items:tt)*] [$($names:ident)*]
+        $(#[$field_meta:meta])* $field_vis:vis $field:ident : $type:ty => $parser:expr
+            $(, $($rest:tt)*)?
+    ) => {
+        $crate::packet_layout!(
+            @munch $header
+            [$($fields)* $(#[$field_meta])* $field_vis $field: $type,]
+            [$($items)* { field $field => $parser }]
+            [$($names)* $field]
+            $($($rest)*)?
+        );
+    };
+    (
+        @munch { $(#[$meta:meta])* $vis:vis struct $name:ident }
+        [$($fields:tt)*] [$($items:tt)*] [$($names:ident)*]
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($fields)*
+        }
+
+        impl $crate::protocols::encdec::EncodeTo for $name {
+            fn encoded_len(&self) -> usize {
+                let $name { $($names),* } = self;
+                0 $( + $crate::protocols::encdec::EncodeTo::encoded_len(
+                    &$crate::packet_layout!(@encode $items)
+                ) )+
+            }
+
+            fn encode_to(&self, buf: &mut [u8]) {
+                let $name { $($names),* } = self;
+                $crate::encode_to!(buf, $( $crate::packet_layout!(@encode $items), )+);
+            }
+        }
+
+        impl $name {
+            pub fn parse(input: &[u8]) -> $crate::protocols::encdec::BIResult<'_, Self> {
+                $( $crate::packet_layout!(@parse input, $items); )+
+
+                Ok((input, $name { $($names),* }))
+            }
+        }
+    };
+
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident { $($body:tt)* }
+    ) => {
+        $crate::packet_layout!(@munch { $(#[$meta])* $vis struct $name } [] [] [] $($body)*);
+    };
+}
+
+/// Run a nom parser block over `$input`, turning its failure into a `ParseError` described by
+/// `$error_template`, whose `{}` is filled with where and why it failed.
 #[macro_export]
 macro_rules! try_parse {
-    ($block:tt, $error_template:expr) => {
+    ($input:expr, $block:tt, $error_template:expr) => {
         {
+            let original = $input;
+            #[allow(clippy::redundant_closure_call)]
             let result = || -> nom::IResult<_, _> $block ();
 
             match result {
                 Ok((_, output)) => Ok(output),
-                Err(e) => Err(anyhow!($error_template, e.to_string())),
+                Err(e) => Err(anyhow::Error::new(
+                    $crate::protocols::encdec::ParseError::new(original, e, |detail| {
+                        format!($error_template, detail)
+                    }),
+                )),
             }
         }
     };
 }
 
+/// Bytes of the input shown from where parsing failed.
+const EXCERPT_LEN: usize = 16;
+
+/// A parser failing, with where and what it was looking for.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseError {
+    pub description: String,
+    /// Bytes into the input the failing parser started at, or `None` if it was parsing something
+    /// other than the input, like a copy.
+    pub offset: Option<usize>,
+    /// The failing parser, or `None` if the input ended before it could tell.
+    pub kind: Option<ErrorKind>,
+    /// What the failing parser was given, up to `EXCERPT_LEN` bytes of it.
+    pub excerpt: Vec<u8>,
+}
+
+/// Where `part` starts in `whole`, if it's a slice of it.
+fn offset_within(whole: &[u8], part: &[u8]) -> Option<usize> {
+    let start = whole.as_ptr() as usize;
+    let part_start = part.as_ptr() as usize;
+
+    (part_start >= start && part_start + part.len() <= start + whole.len())
+        .then(|| part_start - start)
+}
+
+impl ParseError {
+    pub fn new<I: AsBytes>(
+        original: I,
+        error: nom::Err<nom::error::Error<I>>,
+        describe: impl FnOnce(String) -> String,
+    ) -> Self {
+        let original = original.as_bytes();
+        let (offset, excerpt, truncated, kind) = match error {
+            nom::Err::Error(e) | nom::Err::Failure(e) => {
+                let rest = e.input.as_bytes();
+                // Parsers of part of the input, like an option's body, fail partway through it.
+                (
+                    offset_within(original, rest),
+                    rest[..rest.len().min(EXCERPT_LEN)].to_vec(),
+                    rest.len() > EXCERPT_LEN,
+                    Some(e.code),
+                )
+            }
+            nom::Err::Incomplete(_) => (Some(original.len()), Vec::new(), false, None),
+        };
+
+        let mut detail = match kind {
+            Some(kind) => format!("expected {}", kind.description()),
+            None => "input ended".to_string(),
+        };
+        if let Some(offset) = offset {
+            write!(detail, " at offset {}", offset).unwrap();
+        }
+        if !excerpt.is_empty() {
+            write!(detail, " ({}", hex::encode(&excerpt)).unwrap();
+            if truncated {
+                detail.push_str("..");
+            }
+            detail.push(')');
+        }
+
+        Self {
+            description: describe(detail),
+            offset,
+            kind,
+            excerpt,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str(&self.description)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 pub fn round_up_to_next<
     T: Copy + std::ops::Rem<Output = T> + std::ops::Add<Output = T> + std::ops::Sub<Output = T>,
 >(
@@ -253,9 +595,203 @@ pub fn round_up_to_next<
     val + (step - val % step) % step
 }
 
+/// What a type-length-value option's length field counts.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TlvLength {
+    /// Octets of the value alone.
+    Value,
+    /// Octets of the whole option, type and length fields included.
+    Whole,
+    /// Units of 8 octets of the whole option, which is zero padded to fill the last.
+    Whole8,
+    /// Octets of the whole option, leaving out the zero padding that fills its last 4.
+    Whole4,
+}
+
+/// A type-length-value option format, as shared by many protocols' option lists.
+#[derive(Clone, Copy, Debug)]
+pub struct Tlv {
+    pub type_len: usize,
+    pub length_len: usize,
+    pub length: TlvLength,
+}
+
+impl Tlv {
+    // Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-4.6
+    pub const NDP: Tlv = Tlv {
+        type_len: 1,
+        length_len: 1,
+        length: TlvLength::Whole8,
+    };
+    // Ref: https://datatracker.ietf.org/doc/html/rfc2516#appendix-A
+    pub const PPPOE: Tlv = Tlv {
+        type_len: 2,
+        length_len: 2,
+        length: TlvLength::Value,
+    };
+    // Ref: https://datatracker.ietf.org/doc/html/rfc1661#section-6
+    pub const PPP: Tlv = Tlv {
+        type_len: 1,
+        length_len: 1,
+        length: TlvLength::Whole,
+    };
+    // Ref: https://datatracker.ietf.org/doc/html/rfc9260#section-3.2.1
+    pub const SCTP: Tlv = Tlv {
+        type_len: 2,
+        length_len: 2,
+        length: TlvLength::Whole4,
+    };
+
+    fn header_len(&self) -> usize {
+        self.type_len + self.length_len
+    }
+
+    /// Length of an option with `value_len` octets of value, padding included.
+    pub fn encoded_len(&self, value_len: usize) -> usize {
+        match self.length {
+            TlvLength::Value | TlvLength::Whole => self.header_len() + value_len,
+            TlvLength::Whole8 => round_up_to_next(self.header_len() + value_len, 8),
+            TlvLength::Whole4 => round_up_to_next(self.header_len() + value_len, 4),
+        }
+    }
+
+    /// Write an option, padding its value as the format needs.
+    pub fn encode_to(&self, buf: &mut [u8], option_type: impl EncodeTo, value: impl EncodeTo) {
+        debug_assert_eq!(option_type.encoded_len(), self.type_len);
+        let value_len = value.encoded_len();
+        let len = self.encoded_len(value_len);
+        let length_field = match self.length {
+            TlvLength::Value => value_len,
+            TlvLength::Whole => len,
+            TlvLength::Whole8 => len / 8,
+            TlvLength::Whole4 => self.header_len() + value_len,
+        } as u64;
+
+        option_type.encode_to(buf);
+        buf[self.type_len..self.header_len()]
+            .copy_from_slice(&length_field.to_be_bytes()[8 - self.length_len..]);
+        value.encode_to(&mut buf[self.header_len()..]);
+        buf[self.header_len() + value_len..len].fill(0);
+    }
+
+    pub fn encode(&self, option_type: impl EncodeTo, value: impl EncodeTo) -> Vec<u8> {
+        let mut result = vec![0; self.encoded_len(value.encoded_len())];
+        self.encode_to(&mut result, option_type, value);
+
+        result
+    }
+
+    /// Parse one option into its type and value, the value including any padding counted by the
+    /// length field.
+    pub fn parse<'a>(&self, input: &'a [u8]) -> BIResult<'a, (u64, &'a [u8])> {
+        let field = |input: &'a [u8], len: usize| -> BIResult<'a, u64> {
+            let (input, bytes) = nom::bytes::complete::take(len)(input)?;
+            Ok((input, bytes.iter().fold(0, |n, b| n << 8 | *b as u64)))
+        };
+        let (input, option_type) = field(input, self.type_len)?;
+        let (rest, length) = field(input, self.length_len)?;
+
+        let value_len = match self.length {
+            TlvLength::Value => Some(length as usize),
+            TlvLength::Whole => (length as usize).checked_sub(self.header_len()),
+            // A zero length would never move on to the next option.
+            TlvLength::Whole8 if length == 0 => None,
+            TlvLength::Whole8 => (length as usize * 8).checked_sub(self.header_len()),
+            TlvLength::Whole4 => (length as usize).checked_sub(self.header_len()),
+        };
+        let value_len = value_len.ok_or_else(|| {
+            nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Verify))
+        })?;
+        let (rest, value) = nom::bytes::complete::take(value_len)(rest)?;
+        let rest = match self.length {
+            // Padding missing after the last option is tolerated.
+            TlvLength::Whole4 => {
+                let padding = round_up_to_next(length as usize, 4) - length as usize;
+                &rest[padding.min(rest.len())..]
+            }
+            _ => rest,
+        };
+
+        Ok((rest, (option_type, value)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use nom::{
+        bytes::complete::take,
+        combinator::map,
+        number::complete::{be_u16, be_u8},
+    };
+
+    encodable_struct! {
+        struct Header {
+            version: u8,
+            sequence: u64,
+            cookie: [u8; 3],
+        }
+    }
+
+    #[test]
+    fn integers_and_arrays_encode_big_endian() {
+        assert_eq!(
+            encode!(0x0102030405060708u64, -2i16, [9u8, 10]),
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 0xff, 0xfe, 9, 10]
+        );
+        assert_eq!(1u128.encoded_len(), 16);
+    }
+
+    #[test]
+    fn encodable_struct_encodes_fields_in_order() {
+        let header = Header {
+            version: 2,
+            sequence: 1,
+            cookie: *b"abc",
+        };
+
+        assert_eq!(header.encoded_len(), 12);
+        assert_eq!(encode!(header), b"\x02\0\0\0\0\0\0\0\x01abc".to_vec());
+    }
+
+    packet_layout! {
+        #[derive(Debug, PartialEq)]
+        struct Message {
+            const 2u8 => be_u8, // Version
+            flags(u8) { urgent => 7, final_part => 0 } => be_u8,
+            reserved 0u16 => be_u16,
+            length: u16 => be_u16,
+            cookie: [u8; 2] => map(take(2usize), |c: &[u8]| [c[0], c[1]]),
+        }
+    }
+
+    #[test]
+    fn packet_layout_round_trips() {
+        let message = Message {
+            urgent: true,
+            final_part: true,
+            length: 3,
+            cookie: *b"ab",
+        };
+        let encoded = encode!(message);
+
+        assert_eq!(encoded, b"\x02\x81\0\0\0\x03ab".to_vec());
+        assert_eq!(Message::parse(&encoded), Ok((&b""[..], message)));
+    }
+
+    #[test]
+    fn packet_layout_checks_constants() {
+        assert!(Message::parse(b"\x03\x81\0\0\0\x03ab").is_err());
+    }
+
+    #[test]
+    fn flags_round_trip() {
+        let bits = flags!(u8, true => 7, false => 6, true => 5);
+
+        assert_eq!(bits, 0xa0);
+        assert!(flag(bits, 7));
+        assert!(!flag(bits, 6));
+    }
 
     #[test]
     fn round_up_to_next_preserves_multiples() {
@@ -263,9 +799,141 @@ mod tests {
         assert_eq!(round_up_to_next(63, 9), 63);
     }
 
+    crate::proto_enum!(Shape, u8, {
+        Circle = 1,
+        RoundedSquare = 2 => "rounded-square",
+    });
+
+    crate::proto_enum_with_unknown!(Color, u16, {
+        Red = 1 => "RED",
+        Green = 2,
+    });
+
+    #[test]
+    fn proto_enums_list_display_and_parse_their_names() {
+        assert_eq!(Shape::ALL, [Shape::Circle, Shape::RoundedSquare]);
+        assert_eq!(
+            Color::iter().collect::<Vec<_>>(),
+            [Color::Red, Color::Green]
+        );
+
+        for shape in Shape::iter() {
+            assert_eq!(shape.to_string().parse::<Shape>().unwrap(), shape);
+        }
+        assert_eq!(Shape::RoundedSquare.to_string(), "rounded-square");
+        assert_eq!(
+            "roundedsquare".parse::<Shape>().unwrap(),
+            Shape::RoundedSquare
+        );
+        assert_eq!("2".parse::<Shape>().unwrap(), Shape::RoundedSquare);
+        let error = "square".parse::<Shape>().unwrap_err().to_string();
+        assert!(error.contains("Circle, rounded-square"), "{}", error);
+        assert!("3".parse::<Shape>().is_err());
+
+        assert_eq!(Color::Red.to_string(), "RED");
+        assert_eq!("red".parse::<Color>().unwrap(), Color::Red);
+        assert_eq!("7".parse::<Color>().unwrap(), Color::Unknown(7));
+    }
+
     #[test]
     fn round_up_to_next_returns_multiples() {
         assert_eq!(round_up_to_next(15, 8), 16);
         assert_eq!(round_up_to_next(60, 9), 63);
     }
+
+    #[test]
+    fn tlvs_round_trip_in_each_length_form() {
+        let ndp = Tlv::NDP.encode(14u8, &b"\x01\x02\x03"[..]);
+        assert_eq!(ndp, b"\x0e\x01\x01\x02\x03\x00\x00\x00");
+        assert_eq!(Tlv::NDP.encoded_len(6), 8);
+        assert_eq!(Tlv::NDP.encoded_len(7), 16);
+
+        let pppoe = Tlv::PPPOE.encode(0x0101u16, &b"isp"[..]);
+        assert_eq!(pppoe, b"\x01\x01\x00\x03isp");
+        let ppp = Tlv::PPP.encode(1u8, 1492u16);
+        assert_eq!(ppp, b"\x01\x04\x05\xd4");
+        let sctp = Tlv::SCTP.encode(7u16, &b"abcde"[..]);
+        assert_eq!(sctp, b"\x00\x07\x00\x09abcde\x00\x00\x00");
+
+        for (format, encoded) in [(Tlv::NDP, &ndp), (Tlv::PPPOE, &pppoe), (Tlv::PPP, &ppp)] {
+            let (rest, (option_type, value)) = format.parse(encoded).unwrap();
+            assert!(rest.is_empty());
+            assert!(option_type > 0);
+            assert_eq!(encoded[encoded.len() - value.len()..], *value);
+        }
+        for encoded in [&sctp[..], &sctp[..9]] {
+            assert_eq!(
+                Tlv::SCTP.parse(encoded).unwrap(),
+                (&[][..], (7, &b"abcde"[..]))
+            );
+        }
+    }
+
+    fn two_then_tag(input: &[u8]) -> anyhow::Result<&[u8]> {
+        try_parse!(
+            input,
+            {
+                let (input, _) = be_u16(input)?;
+                nom::bytes::complete::tag(&b"\xca\xfe"[..])(input)
+            },
+            "parsing test failed: {}"
+        )
+    }
+
+    #[test]
+    fn parse_errors_report_where_they_failed() {
+        let input = [0u8; 24];
+        let error = two_then_tag(&input).unwrap_err();
+        let error = error.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(error.offset, Some(2));
+        assert_eq!(error.kind, Some(ErrorKind::Tag));
+        assert_eq!(error.excerpt, vec![0; EXCERPT_LEN]);
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "parsing test failed: expected Tag at offset 2 ({}..)",
+                "00".repeat(EXCERPT_LEN)
+            )
+        );
+
+        let error = two_then_tag(&[0xca]).unwrap_err();
+        let error = error.downcast_ref::<ParseError>().unwrap();
+        assert_eq!((error.offset, error.kind), (Some(0), Some(ErrorKind::Eof)));
+        assert!(two_then_tag(&[0, 0, 0xca, 0xfe]).is_ok());
+    }
+
+    #[test]
+    fn parse_errors_inside_part_of_the_input_report_where_they_failed() {
+        let input = [0u8, 0, 0xff, 0xff, 0, 0, 0, 0];
+        let error = try_parse!(
+            &input[..],
+            {
+                let (rest, body) = nom::bytes::complete::take(4usize)(&input[..])?;
+                nom::bytes::complete::tag(&b"\xca\xfe"[..])(&body[2..])?;
+                Ok((rest, ()))
+            },
+            "parsing test failed: {}"
+        )
+        .unwrap_err();
+        let error = error.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(error.offset, Some(2));
+        assert_eq!(error.excerpt, vec![0xff, 0xff]);
+
+        let copy = input.to_vec();
+        let error = try_parse!(
+            &input[..],
+            { nom::bytes::complete::tag(&b"\xca\xfe"[..])(&copy[..]) },
+            "parsing test failed: {}"
+        )
+        .unwrap_err();
+        assert_eq!(error.downcast_ref::<ParseError>().unwrap().offset, None);
+    }
+
+    #[test]
+    fn tlvs_with_impossible_lengths_are_rejected() {
+        assert!(Tlv::NDP.parse(b"\x0e\x00\x00\x00\x00\x00\x00\x00").is_err());
+        assert!(Tlv::NDP.parse(b"\x0e\x02\x00\x00\x00\x00\x00\x00").is_err());
+        assert!(Tlv::PPP.parse(b"\x01\x01").is_err());
+        assert!(Tlv::PPPOE.parse(b"\x01\x01\x00\x00").is_ok());
+    }
 }
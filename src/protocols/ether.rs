@@ -1,20 +1,75 @@
-use anyhow::{anyhow, bail, Context, Result as AHResult};
+use anyhow::{bail, Context, Result as AHResult};
+use blake2::{Blake2s256, Digest};
 use crossbeam::channel;
+use nix::sys::time::{TimeVal, TimeValLike};
 use nom::{bytes::complete::take, combinator::map_res, number::complete::be_u16};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::convert::{TryFrom, TryInto};
 use std::fmt::{Display, Formatter};
 use std::os::unix::io as unix_io;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Instant, SystemTime};
 
 use super::encdec::{hexdump, BIResult, EncodeTo};
+use super::filter::Filter;
+use super::limits::Limit;
+use super::shaping::Shaper;
 use super::utils::{DispatchKeyed, KeyedDispatcher, RecvSenderMap};
-use crate::tap_device;
-use crate::{encode, proto_enum, try_parse};
+use crate::device::{self, Device};
+use crate::{debug, encode, proto_enum, serde_via_str, stats, status, try_parse, warn, watchdog};
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+/// Largest payload that fits in a frame on our tap devices.
+pub const MTU: usize = device::FRAME_SIZE - 6 - 6 - 2;
+
+/// Shortest frame on the wire, without the FCS; shorter ones are padded with zeroes.
+pub const MIN_LEN: usize = 60;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct Address(pub [u8; 6]);
 
+impl Address {
+    pub const BROADCAST: Address = Address([0xff; 6]);
+
+    /// Generate a random unicast address with the locally-administered bit set.
+    pub fn random_local(rng: &mut impl rand::Rng) -> Self {
+        let mut result = Self(rng.gen());
+        result.0[0] = (result.0[0] | 0x02) & !0x01;
+
+        result
+    }
+
+    /// Generate a random address under the given OUI (vendor prefix).
+    pub fn random_with_oui(oui: [u8; 3], rng: &mut impl rand::Rng) -> Self {
+        let nic: [u8; 3] = rng.gen();
+
+        Self([oui[0], oui[1], oui[2], nic[0], nic[1], nic[2]])
+    }
+
+    /// A unicast address with the locally-administered bit set that's always the same for the same
+    /// `seed`.
+    pub fn derived(seed: &[u8]) -> Self {
+        let hash = Blake2s256::digest(seed);
+        let mut result = Self(hash[..6].try_into().unwrap());
+        result.0[0] = (result.0[0] | 0x02) & !0x01;
+
+        result
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+}
+
 impl Display for Address {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         for (i, part) in self.0.iter().enumerate() {
@@ -53,8 +108,10 @@ impl std::str::FromStr for Address {
     }
 }
 
+serde_via_str!(Address);
+
 pub fn address<'a>(input: &'a [u8]) -> BIResult<'a, Address> {
-    take(6 as usize)(input).map(|(i, x)| (i, Address(x.try_into().unwrap())))
+    take(6_usize)(input).map(|(i, x)| (i, Address(x.try_into().unwrap())))
 }
 
 impl EncodeTo for Address {
@@ -68,17 +125,23 @@ impl EncodeTo for Address {
 }
 
 proto_enum!(Type, u16, {
-    Arp = 0x0806,
-    Ipv4 = 0x0800,
-    Ipv6 = 0x86DD,
+    Arp = 0x0806 => "ARP",
+    Ipv4 = 0x0800 => "IPv4",
+    Ipv6 = 0x86DD => "IPv6",
+    WakeOnLan = 0x0842,
+    PppoeDiscovery = 0x8863,
+    PppoeSession = 0x8864,
 });
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Frame {
     pub dest: Address,
     pub src: Address,
     pub ethertype: Type,
     pub payload: Vec<u8>,
+    /// Host time the frame was read from the device, as precisely as the device can tell; `None`
+    /// for frames built or parsed here.
+    pub received: Option<SystemTime>,
 }
 
 impl Display for Frame {
@@ -97,33 +160,136 @@ impl Display for Frame {
 }
 
 impl Frame {
-    pub fn encode(&self) -> Vec<u8> {
-        let mut result = encode!(self.dest, self.src, self.ethertype as u16);
+    pub fn builder() -> FrameBuilder {
+        FrameBuilder {
+            dest: None,
+            src: None,
+            ethertype: None,
+            payload: Vec::new(),
+            mtu: MTU,
+        }
+    }
 
-        result.extend_from_slice(&self.payload);
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = encode!(
+            self.dest,
+            self.src,
+            self.ethertype as u16,
+            &self.payload[..]
+        );
 
-        if result.len() < 60 {
-            result.resize(60, 0u8);
+        if result.len() < MIN_LEN {
+            result.resize(MIN_LEN, 0u8);
         }
 
         result
     }
 }
 
-pub fn frame<'a>(input: &'a [u8]) -> AHResult<Frame> {
+/// Builds a frame, checking it's complete and fits. Short frames are padded when encoded.
+pub struct FrameBuilder {
+    dest: Option<Address>,
+    src: Option<Address>,
+    ethertype: Option<Type>,
+    payload: Vec<u8>,
+    mtu: usize,
+}
+
+impl FrameBuilder {
+    pub fn dest(self, dest: Address) -> Self {
+        Self {
+            dest: Some(dest),
+            ..self
+        }
+    }
+
+    pub fn src(self, src: Address) -> Self {
+        Self {
+            src: Some(src),
+            ..self
+        }
+    }
+
+    pub fn ethertype(self, ethertype: Type) -> Self {
+        Self {
+            ethertype: Some(ethertype),
+            ..self
+        }
+    }
+
+    pub fn payload(self, payload: Vec<u8>) -> Self {
+        Self { payload, ..self }
+    }
+
+    /// Largest payload to allow, `MTU` unless set.
+    pub fn mtu(self, mtu: usize) -> Self {
+        Self { mtu, ..self }
+    }
+
+    pub fn build(self) -> AHResult<Frame> {
+        if self.payload.len() > self.mtu {
+            bail!(
+                "{} byte payload exceeds mtu {}",
+                self.payload.len(),
+                self.mtu
+            );
+        }
+
+        Ok(Frame {
+            dest: self.dest.context("frame needs a destination")?,
+            src: self.src.context("frame needs a source")?,
+            ethertype: self.ethertype.context("frame needs an ethertype")?,
+            payload: self.payload,
+            received: None,
+        })
+    }
+}
+
+/// Length of what `payload` carries, by the length its own header gives, if it has one.
+fn declared_len(ethertype: Type, payload: &[u8]) -> Option<usize> {
+    let field = |i: usize| Some(u16::from_be_bytes([*payload.get(i)?, *payload.get(i + 1)?]));
+
+    match ethertype {
+        // Ref: https://datatracker.ietf.org/doc/html/rfc826
+        Type::Arp => Some(8 + 2 * (*payload.get(4)? as usize + *payload.get(5)? as usize)),
+        // Ref: https://datatracker.ietf.org/doc/html/rfc791#section-3.1
+        Type::Ipv4 => Some(field(2)? as usize),
+        // Jumbograms, with a zero payload length, never fit in a minimum size frame.
+        // Ref: https://datatracker.ietf.org/doc/html/rfc8200#section-3
+        Type::Ipv6 => Some(40 + field(4)? as usize),
+        // Ref: https://datatracker.ietf.org/doc/html/rfc2516#section-4
+        Type::PppoeDiscovery | Type::PppoeSession => Some(6 + field(4)? as usize),
+        Type::WakeOnLan => None,
+    }
+}
+
+/// Parse a frame, trimming what a minimum size frame was padded with from its payload.
+pub fn frame(input: &[u8]) -> AHResult<Frame> {
+    Limit::PacketSize.check(input.len())?;
+    let padded = input.len() <= MIN_LEN;
+
     try_parse!(
+        input,
         {
             let (input, dest) = address(input)?;
             let (input, src) = address(input)?;
             let (input, ethertype) = map_res(be_u16, Type::try_from)(input)?;
 
+            let mut payload = input.to_vec();
+            if padded {
+                if let Some(len) = declared_len(ethertype, &payload) {
+                    payload.truncate(len);
+                }
+            }
+
             Ok((
                 input,
                 Frame {
                     dest,
                     src,
                     ethertype,
-                    payload: input.to_vec(),
+                    payload,
+                    received: None,
                 },
             ))
         },
@@ -139,20 +305,222 @@ impl DispatchKeyed for Frame {
     }
 }
 
+/// Administrative state of a simulated interface.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminState {
+    #[default]
+    Up,
+    /// Drops all traffic, as if the host had disappeared.
+    Down,
+    /// Drops all traffic except Wake-on-LAN magic packets.
+    Sleeping,
+}
+
+impl AdminState {
+    fn accepts(&self, frame: &Frame) -> bool {
+        match self {
+            AdminState::Up => true,
+            AdminState::Down => false,
+            AdminState::Sleeping => frame.ethertype == Type::WakeOnLan,
+        }
+    }
+}
+
+/// What to do with frames received from one of the interface's own addresses, which a bridge
+/// looping them back or a replayed capture hands us, and which would otherwise look like a peer
+/// using our addresses to DAD and ARP.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OwnFrames {
+    #[default]
+    Drop,
+    /// Show them to observers, like recordings and captures, but not to the stack.
+    Observe,
+    /// Handle them like any other frame.
+    Accept,
+}
+
+/// Frames received from these source addresses are the interface's own.
+struct OwnAddresses {
+    addresses: HashSet<Address>,
+    action: OwnFrames,
+}
+
+/// Handle for changing the administrative state of a `TapInterface` at runtime.
+#[derive(Clone)]
+pub struct AdminHandle {
+    state: Arc<RwLock<AdminState>>,
+    tap_dev: Arc<RwLock<Box<dyn Device>>>,
+    watchers: Arc<Mutex<Vec<channel::Sender<AdminState>>>>,
+}
+
+impl AdminHandle {
+    pub fn get(&self) -> AdminState {
+        *self.state.read().unwrap()
+    }
+
+    /// Change state, optionally also taking the kernel side of the tap down while `Down`.
+    pub fn set(&self, state: AdminState, set_link: bool) -> AHResult<()> {
+        let previous = std::mem::replace(&mut *self.state.write().unwrap(), state);
+
+        if set_link {
+            let mut tap_dev = self.tap_dev.write().unwrap();
+
+            if state == AdminState::Down {
+                tap_dev.down()?;
+            } else {
+                tap_dev.up()?;
+            }
+        }
+
+        status::update(|s| s.interface.admin_state = Some(state));
+
+        if state != previous {
+            self.watchers
+                .lock()
+                .unwrap()
+                .retain(|watcher| watcher.send(state).is_ok());
+        }
+
+        Ok(())
+    }
+
+    /// Receive every state the interface changes to from now on.
+    pub fn watch(&self) -> channel::Receiver<AdminState> {
+        let (sender, receiver) = channel::unbounded();
+        self.watchers.lock().unwrap().push(sender);
+
+        receiver
+    }
+}
+
+/// Receives a copy of sampled frames in both directions.
+struct Observer {
+    filter: Filter,
+    sample_rate: u32,
+    seen: u32,
+    sender: channel::Sender<Frame>,
+}
+
+fn notify_observers(observers: &Mutex<Vec<Observer>>, frame: &Frame) {
+    observers.lock().unwrap().retain_mut(|observer| {
+        if !observer.filter.matches(frame) {
+            return true;
+        }
+
+        observer.seen += 1;
+
+        if observer.seen >= observer.sample_rate {
+            observer.seen = 0;
+            // Observers must never hold up the interface, so they miss frames when behind.
+            if let Err(channel::TrySendError::Disconnected(_)) =
+                observer.sender.try_send(frame.clone())
+            {
+                return false;
+            }
+        }
+
+        true
+    });
+}
+
+/// Handle for observing a `TapInterface`'s traffic, e.g. from control commands.
+#[derive(Clone)]
+pub struct ObserveHandle(Arc<Mutex<Vec<Observer>>>);
+
+impl ObserveHandle {
+    /// Receive a copy of one in every `sample_rate` frames sent or received that match `filter`,
+    /// until the receiver is dropped.
+    pub fn observe(&self, sample_rate: u32, filter: Filter) -> channel::Receiver<Frame> {
+        let (sender, receiver) = channel::bounded(1024);
+
+        self.0.lock().unwrap().push(Observer {
+            filter,
+            sample_rate: sample_rate.max(1),
+            seen: 0,
+            sender,
+        });
+
+        receiver
+    }
+}
+
 pub struct TapInterface {
     hw_address: Address,
-    tap_dev: Arc<RwLock<tap_device::TapDevice>>,
+    tap_dev: Arc<RwLock<Box<dyn Device>>>,
+    admin_state: Arc<RwLock<AdminState>>,
+    admin_watchers: Arc<Mutex<Vec<channel::Sender<AdminState>>>>,
+    shaper: Arc<Mutex<Shaper>>,
     recv_map: Arc<RecvSenderMap<Frame>>,
+    observers: Arc<Mutex<Vec<Observer>>>,
+    own_addresses: Arc<RwLock<OwnAddresses>>,
     write_sender: channel::Sender<Frame>,
     write_receiver: channel::Receiver<Frame>,
     write_alert_read_fd: unix_io::RawFd,
     write_alert_write_fd: unix_io::RawFd,
 }
 
+/// Hand a frame read from `queue` at `received` to everyone who wants it, if the interface is
+/// accepting them.
+fn receive(
+    buffer: &[u8],
+    received: SystemTime,
+    queue: usize,
+    admin_state: &RwLock<AdminState>,
+    own_addresses: &RwLock<OwnAddresses>,
+    observers: &Mutex<Vec<Observer>>,
+    recv_map: &RecvSenderMap<Frame>,
+) {
+    // Only runts too short for a header fail to parse.
+    let frame = match frame(buffer) {
+        Ok(frame) => Frame {
+            received: Some(received),
+            ..frame
+        },
+        Err(_) => {
+            stats::FRAMES_DROPPED.increment();
+            return;
+        }
+    };
+
+    if !admin_state.read().unwrap().accepts(&frame) {
+        stats::FRAMES_DROPPED.increment();
+        return;
+    }
+
+    stats::FRAMES_RECEIVED.increment();
+    stats::QUEUE_FRAMES_RECEIVED.increment(queue);
+
+    let own = own_addresses.read().unwrap();
+    if own.action != OwnFrames::Accept && own.addresses.contains(&frame.src) {
+        stats::OWN_FRAMES_RECEIVED.increment();
+        if own.action == OwnFrames::Observe {
+            notify_observers(observers, &frame);
+        }
+        return;
+    }
+    drop(own);
+
+    notify_observers(observers, &frame);
+    if let Err(e) = recv_map.dispatch(frame) {
+        stats::FRAMES_DROPPED.increment();
+        debug!("dropping frame: {}", e);
+    }
+}
+
 impl TapInterface {
     pub fn open(hw_address: Address) -> AHResult<Self> {
-        let tap_dev = tap_device::TapDevice::open()?;
+        Self::open_with_queues(hw_address, 1)
+    }
 
+    /// Open a tap with `queues` queues, each read by its own thread.
+    pub fn open_with_queues(hw_address: Address, queues: usize) -> AHResult<Self> {
+        Self::with_device(hw_address, device::open(queues)?)
+    }
+
+    /// Use another kind of device than this platform's tap, e.g. a `SocketDevice`.
+    pub fn with_device(hw_address: Address, tap_dev: Box<dyn Device>) -> AHResult<Self> {
         let (write_sender, write_receiver) = channel::bounded(1024);
 
         let (write_alert_read_fd, write_alert_write_fd) = nix::unistd::pipe()?;
@@ -160,7 +528,15 @@ impl TapInterface {
         Ok(Self {
             hw_address,
             tap_dev: Arc::new(RwLock::new(tap_dev)),
-            recv_map: Arc::new(RecvSenderMap::new()),
+            admin_state: Arc::new(RwLock::new(AdminState::Up)),
+            admin_watchers: Arc::new(Mutex::new(Vec::new())),
+            shaper: Arc::new(Mutex::new(Shaper::default())),
+            recv_map: Arc::new(RecvSenderMap::new("ether")),
+            observers: Arc::new(Mutex::new(Vec::new())),
+            own_addresses: Arc::new(RwLock::new(OwnAddresses {
+                addresses: std::iter::once(hw_address).collect(),
+                action: OwnFrames::default(),
+            })),
             write_sender,
             write_receiver,
             write_alert_read_fd,
@@ -171,14 +547,45 @@ impl TapInterface {
     pub fn start(&self) -> AHResult<()> {
         let tap_dev = Arc::clone(&self.tap_dev);
         let recv_map = Arc::clone(&self.recv_map);
+        let observers = Arc::clone(&self.observers);
+        let admin_state = Arc::clone(&self.admin_state);
+        let own_addresses = Arc::clone(&self.own_addresses);
         let write_alert_read_fd = self.write_alert_read_fd;
         let write_receiver = self.write_receiver.clone();
 
-        self.tap_dev.write().unwrap().up()?;
+        self.up()?;
+
+        let extra_queues = self.tap_dev.read().unwrap().extra_queues()?;
+        if !extra_queues.is_empty() {
+            stats::QUEUE_FRAMES_RECEIVED.set_queues(extra_queues.len() + 1);
+        }
+
+        for (i, mut queue) in extra_queues.into_iter().enumerate() {
+            let recv_map = Arc::clone(&self.recv_map);
+            let observers = Arc::clone(&self.observers);
+            let admin_state = Arc::clone(&self.admin_state);
+            let own_addresses = Arc::clone(&self.own_addresses);
+
+            thread::spawn(move || {
+                let mut buffer = vec![0; device::FRAME_SIZE];
+
+                loop {
+                    let num_read = std::io::Read::read(&mut queue, &mut buffer).unwrap();
+                    receive(
+                        &buffer[..num_read],
+                        SystemTime::now(),
+                        i + 1,
+                        &admin_state,
+                        &own_addresses,
+                        &observers,
+                        &recv_map,
+                    );
+                }
+            });
+        }
 
         thread::spawn(move || {
-            let mut buffer = Vec::new();
-            buffer.resize(tap_device::TapDevice::FRAME_SIZE, 0u8);
+            let mut alert = [0; 1];
 
             let tap_dev_fd = tap_dev.read().unwrap().rawfd();
             let mut fd_set = nix::sys::select::FdSet::new();
@@ -188,27 +595,51 @@ impl TapInterface {
             let mut write_alert_read =
                 unsafe { <std::fs::File as unix_io::FromRawFd>::from_raw_fd(write_alert_read_fd) };
 
+            let heartbeat = watchdog::register("ether");
             loop {
-                let mut fd_set = fd_set.clone();
-                nix::sys::select::select(None, Some(&mut fd_set), None, None, None).unwrap();
+                let mut fd_set = fd_set;
+                // Woken at least this often when idle, so it can beat.
+                let mut timeout = TimeVal::milliseconds(watchdog::INTERVAL.as_millis() as i64);
+                nix::sys::select::select(None, Some(&mut fd_set), None, None, Some(&mut timeout))
+                    .unwrap();
+                heartbeat.beat();
 
                 if fd_set.contains(tap_dev_fd) {
-                    let num_read = tap_dev.write().unwrap().read(&mut buffer).unwrap();
-                    let frame = frame(&buffer[..num_read])
-                        .map_err(|e| anyhow!("parsing ethernet frame failed: {}", e.to_string()))
+                    // Collected first, so the device isn't locked while receivers catch up.
+                    let mut frames = Vec::new();
+                    tap_dev
+                        .write()
+                        .unwrap()
+                        .read_frames(&mut |frame, received| frames.push((frame.to_vec(), received)))
                         .unwrap();
 
-                    recv_map.dispatch(frame).unwrap();
+                    for (frame, received) in frames {
+                        receive(
+                            &frame,
+                            received,
+                            0,
+                            &admin_state,
+                            &own_addresses,
+                            &observers,
+                            &recv_map,
+                        );
+                    }
                 }
 
                 if fd_set.contains(write_alert_read_fd) {
                     // Read only one character, in case we have multiple frames backed up.
-                    <std::fs::File as std::io::Read>::read(&mut write_alert_read, &mut buffer[..1])
+                    <std::fs::File as std::io::Read>::read(&mut write_alert_read, &mut alert)
                         .unwrap();
 
                     let frame = write_receiver.recv().unwrap();
 
-                    tap_dev.write().unwrap().write(&frame.encode()).unwrap();
+                    if *admin_state.read().unwrap() == AdminState::Up {
+                        tap_dev.write().unwrap().write(&frame.encode()).unwrap();
+                        stats::FRAMES_SENT.increment();
+                        notify_observers(&observers, &frame);
+                    } else {
+                        stats::FRAMES_DROPPED.increment();
+                    }
                 }
             }
         });
@@ -216,11 +647,97 @@ impl TapInterface {
         Ok(())
     }
 
+    pub fn up(&self) -> AHResult<()> {
+        self.tap_dev.write().unwrap().up()
+    }
+
+    pub fn down(&self) -> AHResult<()> {
+        self.tap_dev.write().unwrap().down()
+    }
+
+    /// Whether to take the tap down when this is dropped; on by default.
+    pub fn set_down_on_drop(&self, down_on_drop: bool) {
+        self.tap_dev.write().unwrap().set_down_on_drop(down_on_drop);
+    }
+
+    /// Take the tap down, reporting errors that dropping it would ignore.
+    pub fn close(self) -> AHResult<()> {
+        let mut tap_dev = self.tap_dev.write().unwrap();
+        tap_dev.down()?;
+        // The reader thread keeps the device itself alive until exit.
+        tap_dev.set_down_on_drop(false);
+
+        Ok(())
+    }
+
+    /// Give the kernel side of the tap our hardware address, so bridges and captures on the host
+    /// agree with the frames we send. Must be called before `start()`.
+    pub fn set_kernel_address(&self) -> AHResult<()> {
+        self.tap_dev
+            .write()
+            .unwrap()
+            .set_if_hwaddr(self.hw_address.0)
+    }
+
+    pub fn admin(&self) -> AdminHandle {
+        AdminHandle {
+            state: Arc::clone(&self.admin_state),
+            tap_dev: Arc::clone(&self.tap_dev),
+            watchers: Arc::clone(&self.admin_watchers),
+        }
+    }
+
+    /// Set what to do with frames received from our own addresses; they're dropped by default.
+    pub fn set_own_frames(&self, action: OwnFrames) {
+        self.own_addresses.write().unwrap().action = action;
+    }
+
+    /// Count frames from `address` as our own too, like ones sent from it on our behalf.
+    pub fn add_own_address(&self, address: Address) {
+        self.own_addresses
+            .write()
+            .unwrap()
+            .addresses
+            .insert(address);
+    }
+
+    /// Limit the rate of frames sent by all writers, including ones that already exist.
+    pub fn set_shaper(&self, shaper: Shaper) {
+        *self.shaper.lock().unwrap() = shaper;
+    }
+
+    /// Receive a copy of one in every `sample_rate` frames sent or received that match `filter`.
+    pub fn observe(&self, sample_rate: u32, filter: Filter) -> channel::Receiver<Frame> {
+        self.observe_handle().observe(sample_rate, filter)
+    }
+
+    pub fn observe_handle(&self) -> ObserveHandle {
+        ObserveHandle(Arc::clone(&self.observers))
+    }
+
+    /// Write a frame straight to the tap, bypassing the writer threads.
+    pub fn send(&self, frame: &Frame) -> AHResult<()> {
+        self.tap_dev.write().unwrap().write(&frame.encode())
+    }
+
     pub fn if_name(&self) -> AHResult<String> {
         self.tap_dev.read().unwrap().if_name()
     }
 }
 
+impl Drop for TapInterface {
+    fn drop(&mut self) {
+        let mut tap_dev = self.tap_dev.write().unwrap();
+
+        if tap_dev.down_on_drop() {
+            if let Err(e) = tap_dev.down() {
+                warn!("taking tap down failed: {}", e);
+            }
+            tap_dev.set_down_on_drop(false);
+        }
+    }
+}
+
 impl KeyedDispatcher for TapInterface {
     type Item = Frame;
 
@@ -244,11 +761,19 @@ impl Server for TapInterface {
             <std::fs::File as unix_io::FromRawFd>::from_raw_fd(self.write_alert_write_fd)
         };
         let sender = self.write_sender.clone();
+        let shaper = Arc::clone(&self.shaper);
 
         let (alerter_sender, alerter_receiver) = crossbeam::channel::bounded(1024);
 
         thread::spawn(move || loop {
-            let frame = alerter_receiver.recv().unwrap();
+            let frame: Frame = alerter_receiver.recv().unwrap();
+
+            let delay = shaper
+                .lock()
+                .unwrap()
+                .reserve(frame.encode().len(), Instant::now());
+            thread::sleep(delay);
+
             sender.send(frame).unwrap();
             <std::fs::File as std::io::Write>::write(&mut write_alert_write, &[1u8]).unwrap();
         });
@@ -257,9 +782,71 @@ impl Server for TapInterface {
     }
 }
 
+/// Interface wired directly to a peer in the same process, for benchmarks and tests.
+pub struct MemoryInterface {
+    hw_address: Address,
+    recv_map: Arc<RecvSenderMap<Frame>>,
+    peer_recv_map: Arc<RecvSenderMap<Frame>>,
+}
+
+impl MemoryInterface {
+    /// Create two interfaces, each receiving what the other sends.
+    pub fn pair(a: Address, b: Address) -> (Self, Self) {
+        let a_recv_map = Arc::new(RecvSenderMap::new("ether"));
+        let b_recv_map = Arc::new(RecvSenderMap::new("ether"));
+
+        (
+            Self {
+                hw_address: a,
+                recv_map: Arc::clone(&a_recv_map),
+                peer_recv_map: Arc::clone(&b_recv_map),
+            },
+            Self {
+                hw_address: b,
+                recv_map: b_recv_map,
+                peer_recv_map: a_recv_map,
+            },
+        )
+    }
+}
+
+impl KeyedDispatcher for MemoryInterface {
+    type Item = Frame;
+
+    fn recv_map(&self) -> &RecvSenderMap<Frame> {
+        &self.recv_map
+    }
+}
+
+impl Server for MemoryInterface {
+    fn if_hwaddr(&self) -> AHResult<Address> {
+        Ok(self.hw_address)
+    }
+
+    fn writer(&self) -> crossbeam::channel::Sender<Frame> {
+        let peer_recv_map = Arc::clone(&self.peer_recv_map);
+        let (sender, receiver) = crossbeam::channel::bounded(1024);
+
+        thread::spawn(move || {
+            for outgoing in receiver {
+                // Go through the wire format, so both ends pay the same costs as with a tap.
+                let encoded = Frame::encode(&outgoing);
+                if let Err(e) = peer_recv_map.dispatch(frame(&encoded).unwrap()) {
+                    stats::FRAMES_DROPPED.increment();
+                    debug!("dropping frame: {}", e);
+                }
+            }
+        });
+
+        sender
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocols::{arp, ipv4, ipv6};
+    use proptest::prelude::*;
 
     #[test]
     fn frame_decodes() {
@@ -270,7 +857,273 @@ mod tests {
                 src: Address(*b"abcdef"),
                 ethertype: Type::Ipv4,
                 payload: b"payload".to_vec(),
+                received: None,
             }
         );
     }
+
+    #[test]
+    fn frames_are_built_and_checked() {
+        let builder = || {
+            Frame::builder()
+                .dest(Address::BROADCAST)
+                .src(Address(*b"abcdef"))
+                .ethertype(Type::Arp)
+        };
+
+        let frame = builder().payload(b"short".to_vec()).build().unwrap();
+        assert_eq!(frame.encode().len(), MIN_LEN);
+        assert_eq!(super::frame(&frame.encode()).unwrap().ethertype, Type::Arp);
+
+        assert!(builder().payload(vec![0; MTU]).build().is_ok());
+        assert!(builder().payload(vec![0; MTU + 1]).build().is_err());
+        assert!(builder().mtu(1280).payload(vec![0; 1281]).build().is_err());
+        assert!(Frame::builder().dest(Address::BROADCAST).build().is_err());
+    }
+
+    #[test]
+    fn sleeping_interfaces_only_accept_wake_on_lan() {
+        let mut frame = Frame {
+            dest: Address::BROADCAST,
+            src: Address(*b"abcdef"),
+            ethertype: Type::Arp,
+            payload: Vec::new(),
+            received: None,
+        };
+
+        assert!(!AdminState::Sleeping.accepts(&frame));
+        assert!(!AdminState::Down.accepts(&frame));
+
+        frame.ethertype = Type::WakeOnLan;
+        assert!(AdminState::Sleeping.accepts(&frame));
+    }
+
+    #[test]
+    fn admin_watchers_see_changes() {
+        let (ours, _peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let interface = TapInterface::with_device(
+            Address(*b"abcdef"),
+            Box::new(crate::socket_device::SocketDevice::from_stream(
+                "test", ours,
+            )),
+        )
+        .unwrap();
+        let admin = interface.admin();
+        let changes = admin.watch();
+
+        admin.set(AdminState::Up, false).unwrap();
+        admin.set(AdminState::Down, false).unwrap();
+        admin.set(AdminState::Up, false).unwrap();
+
+        assert_eq!(
+            changes.try_iter().collect::<Vec<_>>(),
+            [AdminState::Down, AdminState::Up]
+        );
+    }
+
+    #[test]
+    fn received_frames_are_stamped_when_read() {
+        use std::io::Write;
+
+        let (ours, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let interface = TapInterface::with_device(
+            Address(*b"abcdef"),
+            Box::new(crate::socket_device::SocketDevice::from_stream(
+                "test", ours,
+            )),
+        )
+        .unwrap();
+        let frames = interface.observe(1, Filter::All);
+        interface.start().unwrap();
+
+        let before = SystemTime::now();
+        let encoded = frame(b"abcdef123456\x08\x06").unwrap().encode();
+        peer.write_all(&(encoded.len() as u32).to_be_bytes())
+            .unwrap();
+        peer.write_all(&encoded).unwrap();
+
+        let received = frames
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap()
+            .received
+            .unwrap();
+        assert!(before <= received && received <= SystemTime::now());
+    }
+
+    #[test]
+    fn own_frames_are_kept_from_the_stack() {
+        use std::io::Write;
+
+        let (ours, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut interface = TapInterface::with_device(
+            Address(*b"abcdef"),
+            Box::new(crate::socket_device::SocketDevice::from_stream(
+                "test", ours,
+            )),
+        )
+        .unwrap();
+        interface.set_own_frames(OwnFrames::Observe);
+        let observed = interface.observe(1, Filter::All);
+        let (sender, received) = channel::unbounded();
+        interface.register(Type::Arp, sender);
+        interface.start().unwrap();
+
+        let own_before = stats::OWN_FRAMES_RECEIVED.get();
+        for input in [
+            &b"\xff\xff\xff\xff\xff\xffabcdef\x08\x06"[..],
+            b"abcdef123456\x08\x06",
+        ] {
+            let encoded = frame(input).unwrap().encode();
+            peer.write_all(&(encoded.len() as u32).to_be_bytes())
+                .unwrap();
+            peer.write_all(&encoded).unwrap();
+        }
+
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap().src,
+            Address(*b"123456")
+        );
+        assert_eq!(
+            observed.recv_timeout(timeout).unwrap().src,
+            Address(*b"abcdef")
+        );
+        assert_eq!(stats::OWN_FRAMES_RECEIVED.get(), own_before + 1);
+    }
+
+    #[test]
+    fn runt_frames_are_dropped() {
+        let recv_map = RecvSenderMap::new("ether");
+        let (sender, received) = channel::unbounded();
+        recv_map.register(Type::Arp, sender);
+
+        let dropped_before = stats::FRAMES_DROPPED.get();
+        receive(
+            b"\xff\xff\xff\xff\xff\xffabcdef\x08",
+            SystemTime::now(),
+            0,
+            &RwLock::new(AdminState::Up),
+            &RwLock::new(OwnAddresses {
+                addresses: HashSet::new(),
+                action: OwnFrames::default(),
+            }),
+            &Mutex::new(Vec::new()),
+            &recv_map,
+        );
+
+        assert!(stats::FRAMES_DROPPED.get() > dropped_before);
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn random_local_is_local_unicast() {
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..100 {
+            let addr = Address::random_local(&mut rng);
+            assert!(addr.is_local());
+            assert!(!addr.is_multicast());
+        }
+    }
+
+    #[test]
+    fn random_with_oui_keeps_prefix() {
+        let addr = Address::random_with_oui([0x00, 0x1b, 0x63], &mut rand::thread_rng());
+
+        assert_eq!(addr.0[..3], [0x00, 0x1b, 0x63]);
+        assert!(!addr.is_local());
+    }
+
+    #[test]
+    fn address_classes() {
+        assert!(Address::BROADCAST.is_broadcast());
+        assert!(Address::BROADCAST.is_multicast());
+        assert!(Address([0x33, 0x33, 0, 0, 0, 1]).is_multicast());
+        assert!(!Address([0x33, 0x33, 0, 0, 0, 1]).is_broadcast());
+        assert!(!Address([0x02, 0, 0, 0, 0, 1]).is_multicast());
+    }
+
+    fn arb_address() -> impl Strategy<Value = Address> {
+        any::<[u8; 6]>().prop_map(Address)
+    }
+
+    fn arb_frame(
+        payload_len: std::ops::Range<usize>,
+        ethertypes: &[Type],
+    ) -> impl Strategy<Value = Frame> {
+        (
+            arb_address(),
+            arb_address(),
+            prop::sample::select(ethertypes.to_vec()),
+            prop::collection::vec(any::<u8>(), payload_len),
+        )
+            .prop_map(|(dest, src, ethertype, payload)| Frame {
+                dest,
+                src,
+                ethertype,
+                payload,
+                received: None,
+            })
+    }
+
+    #[test]
+    fn padding_is_trimmed_to_the_declared_length() {
+        let padded = |ethertype: Type, payload: &[u8]| {
+            let frame_in = Frame {
+                dest: Address::BROADCAST,
+                src: Address(*b"abcdef"),
+                ethertype,
+                payload: payload.to_vec(),
+                received: None,
+            };
+            frame(&frame_in.encode()).unwrap().payload
+        };
+
+        let request = arp::Packet {
+            opcode: arp::PacketOpcode::Request,
+            src_ether: Address(*b"abcdef"),
+            src_ipv4: ipv4::Address([10, 0, 0, 1]),
+            dest_ether: Address([0; 6]),
+            dest_ipv4: ipv4::Address([10, 0, 0, 2]),
+        }
+        .encode();
+        assert_eq!(padded(Type::Arp, &request), request);
+
+        let mut ipv4 = vec![0x45, 0, 0, 28, 0, 0, 0, 0, 64, 17, 0, 0];
+        ipv4.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2, 0, 1, 0, 2, 0, 8, 0, 0]);
+        assert_eq!(padded(Type::Ipv4, &ipv4), ipv4);
+
+        let ipv6 = ipv6::Packet::builder()
+            .protocol(ipv4::ProtocolNumber::Udp)
+            .src("fe80::1".parse().unwrap())
+            .dest("fe80::2".parse().unwrap())
+            .payload(vec![0, 1, 0, 2, 0, 8, 0, 0])
+            .build()
+            .encode();
+        assert_eq!(padded(Type::Ipv6, &ipv6), ipv6);
+
+        // Without anything to trim to, the padding stays.
+        assert_eq!(padded(Type::WakeOnLan, b"magic").len(), MIN_LEN - 14);
+    }
+
+    proptest! {
+        #[test]
+        // Frames any shorter are the minimum size, and their payloads trimmed to what they declare.
+        fn frame_round_trips(frame_in in arb_frame(47..MTU, Type::ALL)) {
+            let encoded = frame_in.encode();
+
+            prop_assert_eq!(encoded.len(), 14 + frame_in.payload.len());
+            prop_assert_eq!(frame(&encoded).unwrap(), frame_in);
+        }
+
+        #[test]
+        fn short_frame_is_padded(frame_in in arb_frame(0..46, &[Type::WakeOnLan])) {
+            let encoded = frame_in.encode();
+            let parsed = frame(&encoded).unwrap();
+
+            prop_assert_eq!(encoded.len(), 60);
+            prop_assert_eq!(&parsed.payload[..frame_in.payload.len()], &frame_in.payload[..]);
+            prop_assert!(parsed.payload[frame_in.payload.len()..].iter().all(|b| *b == 0));
+        }
+    }
 }
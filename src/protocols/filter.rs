@@ -0,0 +1,407 @@
+//! Frame filter expressions, in a small subset of the pcap filter language.
+//!
+//! Primitives are:
+//!
+//! * `arp`, `ip`, `ip6`, `wol`, `pppoed`, `pppoes` or `ether proto NUMBER|NAME`
+//! * `ether host ADDRESS`, `ether src ADDRESS`, `ether dst ADDRESS`
+//! * `host ADDRESS`, `src host ADDRESS`, `dst host ADDRESS`, for IPv4 or IPv6 addresses
+//! * `tcp`, `udp`, `icmp6` or `proto NUMBER|NAME`, like `proto sctp`
+//! * `port PORT`, `src port PORT`, `dst port PORT`, by number or service name like `domain`
+//!
+//! They can be combined with `and`, `or`, `not` and parentheses, e.g.
+//! `ip6 and not (icmp6 or port 22)`. An empty expression matches everything.
+
+use anyhow::{anyhow, bail, Result as AHResult};
+use serde::{de, Deserialize, Deserializer};
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use super::{arp, ether, ipv4, ipv6, names};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Src,
+    Dest,
+    Either,
+}
+
+impl Direction {
+    fn matches<T: PartialEq>(&self, src: T, dest: T, wanted: T) -> bool {
+        match self {
+            Direction::Src => src == wanted,
+            Direction::Dest => dest == wanted,
+            Direction::Either => src == wanted || dest == wanted,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Filter {
+    #[default]
+    All,
+    EtherType(ether::Type),
+    EtherAddress(Direction, ether::Address),
+    Host(Direction, IpAddr),
+    Protocol(ipv4::ProtocolNumber),
+    Port(Direction, u16),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+/// The parts of a frame filters look at, pulled out once per frame.
+#[derive(Default)]
+struct Fields {
+    hosts: Option<(IpAddr, IpAddr)>,
+    protocol: Option<ipv4::ProtocolNumber>,
+    ports: Option<(u16, u16)>,
+}
+
+impl Fields {
+    fn new(frame: &ether::Frame) -> Self {
+        let mut fields = Fields::default();
+
+        let payload = match frame.ethertype {
+            ether::Type::Arp => {
+                if let Ok(packet) = arp::packet(&frame.payload) {
+                    fields.hosts = Some((
+                        IpAddr::from(packet.src_ipv4),
+                        IpAddr::from(packet.dest_ipv4),
+                    ));
+                }
+                return fields;
+            }
+            ether::Type::Ipv4 => {
+                let header = &frame.payload;
+                let header_len = match header.first() {
+                    Some(first) if header.len() >= 20 => (first & 0xf) as usize * 4,
+                    _ => return fields,
+                };
+                let address =
+                    |i: usize| IpAddr::from(<[u8; 4]>::try_from(&header[i..i + 4]).unwrap());
+
+                fields.hosts = Some((address(12), address(16)));
+                fields.protocol = ipv4::ProtocolNumber::try_from(header[9]).ok();
+                header.get(header_len..).unwrap_or_default().to_vec()
+            }
+            ether::Type::Ipv6 => {
+                let packet = match ipv6::packet(&frame.payload) {
+                    Ok(packet) => packet,
+                    Err(_) => return fields,
+                };
+
+                fields.hosts = Some((packet.src.0.into(), packet.dest.0.into()));
+                if let ipv6::NextHeader::Protocol(protocol) = packet.next_header {
+                    fields.protocol = Some(protocol);
+                }
+                packet.payload
+            }
+            ether::Type::WakeOnLan | ether::Type::PppoeDiscovery | ether::Type::PppoeSession => {
+                return fields
+            }
+        };
+
+        if let (
+            Some(ipv4::ProtocolNumber::Tcp | ipv4::ProtocolNumber::Udp),
+            [src_high, src_low, dest_high, dest_low, ..],
+        ) = (fields.protocol, &payload[..])
+        {
+            fields.ports = Some((
+                u16::from_be_bytes([*src_high, *src_low]),
+                u16::from_be_bytes([*dest_high, *dest_low]),
+            ));
+        }
+
+        fields
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, frame: &ether::Frame) -> bool {
+        if *self == Filter::All {
+            return true;
+        }
+
+        self.matches_fields(frame, &Fields::new(frame))
+    }
+
+    fn matches_fields(&self, frame: &ether::Frame, fields: &Fields) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::EtherType(ethertype) => frame.ethertype == *ethertype,
+            Filter::EtherAddress(direction, address) => {
+                direction.matches(frame.src, frame.dest, *address)
+            }
+            Filter::Host(direction, address) => fields
+                .hosts
+                .is_some_and(|(src, dest)| direction.matches(src, dest, *address)),
+            Filter::Protocol(protocol) => fields.protocol == Some(*protocol),
+            Filter::Port(direction, port) => fields
+                .ports
+                .is_some_and(|(src, dest)| direction.matches(src, dest, *port)),
+            Filter::Not(inner) => !inner.matches_fields(frame, fields),
+            Filter::And(a, b) => a.matches_fields(frame, fields) && b.matches_fields(frame, fields),
+            Filter::Or(a, b) => a.matches_fields(frame, fields) || b.matches_fields(frame, fields),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Vec<String> {
+    s.replace('(', " ( ")
+        .replace(')', " ) ")
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> AHResult<&str> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .ok_or_else(|| anyhow!("unexpected end of filter"))?;
+        self.position += 1;
+
+        Ok(token)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.peek() == Some(token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value<T: FromStr>(&mut self, what: &str) -> AHResult<T> {
+        let token = self.next()?;
+
+        token
+            .parse()
+            .map_err(|_| anyhow!("expected {}, got {}", what, token))
+    }
+
+    fn port(&mut self) -> AHResult<u16> {
+        let token = self.next()?;
+        names::port(token)
+    }
+
+    fn or(&mut self) -> AHResult<Filter> {
+        let mut result = self.and()?;
+
+        while self.eat("or") {
+            result = Filter::Or(Box::new(result), Box::new(self.and()?));
+        }
+
+        Ok(result)
+    }
+
+    fn and(&mut self) -> AHResult<Filter> {
+        let mut result = self.not()?;
+
+        while self.eat("and") {
+            result = Filter::And(Box::new(result), Box::new(self.not()?));
+        }
+
+        Ok(result)
+    }
+
+    fn not(&mut self) -> AHResult<Filter> {
+        if self.eat("not") {
+            return Ok(Filter::Not(Box::new(self.not()?)));
+        }
+
+        if self.eat("(") {
+            let result = self.or()?;
+            if !self.eat(")") {
+                bail!("missing )");
+            }
+
+            return Ok(result);
+        }
+
+        self.primitive()
+    }
+
+    fn direction(&mut self) -> Direction {
+        if self.eat("src") {
+            Direction::Src
+        } else if self.eat("dst") {
+            Direction::Dest
+        } else {
+            Direction::Either
+        }
+    }
+
+    fn primitive(&mut self) -> AHResult<Filter> {
+        let token = self.next()?.to_string();
+
+        Ok(match token.as_str() {
+            "arp" => Filter::EtherType(ether::Type::Arp),
+            "ip" => Filter::EtherType(ether::Type::Ipv4),
+            "ip6" => Filter::EtherType(ether::Type::Ipv6),
+            "wol" => Filter::EtherType(ether::Type::WakeOnLan),
+            "pppoed" => Filter::EtherType(ether::Type::PppoeDiscovery),
+            "pppoes" => Filter::EtherType(ether::Type::PppoeSession),
+            "tcp" => Filter::Protocol(ipv4::ProtocolNumber::Tcp),
+            "udp" => Filter::Protocol(ipv4::ProtocolNumber::Udp),
+            "icmp6" => Filter::Protocol(ipv4::ProtocolNumber::Ipv6Icmp),
+            "proto" => Filter::Protocol(names::protocol(self.next()?)?),
+            "ether" => {
+                if self.eat("proto") {
+                    Filter::EtherType(self.next()?.parse()?)
+                } else {
+                    let direction = match self.direction() {
+                        Direction::Either if !self.eat("host") => {
+                            bail!("expected proto, host, src or dst after ether")
+                        }
+                        direction => direction,
+                    };
+
+                    Filter::EtherAddress(direction, self.value("ethernet address")?)
+                }
+            }
+            "host" => Filter::Host(Direction::Either, self.value("ip address")?),
+            "port" => Filter::Port(Direction::Either, self.port()?),
+            "src" | "dst" => {
+                self.position -= 1;
+                let direction = self.direction();
+
+                match self.next()?.to_string().as_str() {
+                    "host" => Filter::Host(direction, self.value("ip address")?),
+                    "port" => Filter::Port(direction, self.port()?),
+                    token => bail!("expected host or port, got {}", token),
+                }
+            }
+            _ => bail!("unknown filter primitive {}", token),
+        })
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> AHResult<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(s),
+            position: 0,
+        };
+
+        if parser.peek().is_none() {
+            return Ok(Filter::All);
+        }
+
+        let result = parser.or()?;
+        if let Some(token) = parser.peek() {
+            bail!("unexpected {} in filter", token);
+        }
+
+        Ok(result)
+    }
+}
+
+impl<'de> Deserialize<'de> for Filter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::udp;
+
+    fn udp_frame(src_port: u16, dest_port: u16) -> ether::Frame {
+        let src = "fe80::1".parse().unwrap();
+        let dest = "fe80::2".parse().unwrap();
+
+        ether::Frame::builder()
+            .dest("02:00:00:00:00:02".parse().unwrap())
+            .src("02:00:00:00:00:01".parse().unwrap())
+            .ethertype(ether::Type::Ipv6)
+            .payload(
+                ipv6::Packet::builder()
+                    .protocol(ipv4::ProtocolNumber::Udp)
+                    .hop_limit(64)
+                    .src(src)
+                    .dest(dest)
+                    .payload(
+                        udp::Packet {
+                            src_port,
+                            dest_port,
+                            payload: vec![],
+                        }
+                        .encode(ipv6::PseudoHeader {
+                            src,
+                            dest,
+                            length: 0,
+                        }),
+                    )
+                    .build()
+                    .encode(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    fn matches(filter: &str, frame: &ether::Frame) -> bool {
+        filter.parse::<Filter>().unwrap().matches(frame)
+    }
+
+    #[test]
+    fn primitives_match() {
+        let frame = udp_frame(1234, 53);
+
+        assert!(matches("", &frame));
+        assert!(matches("ip6", &frame));
+        assert!(!matches("arp", &frame));
+        assert!(matches("udp and dst port 53", &frame));
+        assert!(!matches("src port 53", &frame));
+        assert!(matches("host fe80::2", &frame));
+        assert!(!matches("src host fe80::2", &frame));
+        assert!(matches("ether src 02:00:00:00:00:01", &frame));
+    }
+
+    #[test]
+    fn combinations_follow_precedence() {
+        let frame = udp_frame(1234, 53);
+
+        assert!(matches("tcp or udp and port 53", &frame));
+        assert!(!matches("(tcp or udp) and port 22", &frame));
+        assert!(matches("not (icmp6 or port 22)", &frame));
+        assert!(!matches("not udp", &frame));
+    }
+
+    #[test]
+    fn protocols_and_ports_can_be_named() {
+        let frame = udp_frame(1234, 53);
+
+        assert!(matches("proto UDP and dst port domain", &frame));
+        assert!(matches("ether proto ipv6 and not proto icmpv6", &frame));
+        assert!(!matches("port dns and proto 6", &frame));
+
+        let error = "port gopher".parse::<Filter>().unwrap_err().to_string();
+        assert!(error.contains("domain"), "{}", error);
+    }
+
+    #[test]
+    fn bad_filters_fail_to_parse() {
+        assert!("port".parse::<Filter>().is_err());
+        assert!("(udp".parse::<Filter>().is_err());
+        assert!("udp tcp".parse::<Filter>().is_err());
+        assert!("ether 02:00:00:00:00:01".parse::<Filter>().is_err());
+    }
+}
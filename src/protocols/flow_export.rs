@@ -0,0 +1,502 @@
+use anyhow::Result as AHResult;
+use crossbeam::channel;
+use crossbeam::select;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::IpAddr;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::filter::Filter;
+use super::{ether, ipv4, ipv6, udp};
+use crate::{clock, encode, warn};
+
+// Ref: https://www.cisco.com/c/en/us/td/docs/net_mgmt/netflow_collection_engine/3-6/user/guide/format.html
+// Ref: https://datatracker.ietf.org/doc/html/rfc7011
+
+const NETFLOW5_MAX_RECORDS: usize = 30;
+// Keeps IPFIX messages, with templates, under the minimum IPv6 MTU.
+const IPFIX_MAX_RECORDS: usize = 16;
+const IPFIX_TEMPLATE_IPV4: u16 = 256;
+const IPFIX_TEMPLATE_IPV6: u16 = 257;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// IPv4 flows only.
+    Netflow5,
+    #[default]
+    Ipfix,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub collector: ipv6::Address,
+    pub collector_port: u16,
+    pub source: ipv6::Address,
+    pub format: Format,
+    pub sample_rate: u32,
+    /// Only frames matching this are sampled.
+    pub filter: Filter,
+    /// How often to export (and forget) all flows seen so far.
+    pub interval: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct FlowKey {
+    src: IpAddr,
+    dest: IpAddr,
+    protocol: ipv4::ProtocolNumber,
+    src_port: u16,
+    dest_port: u16,
+    tos: u8,
+}
+
+#[derive(Clone, Debug)]
+struct Flow {
+    packets: u32,
+    bytes: u32,
+    tcp_flags: u8,
+    first: SystemTime,
+    last: SystemTime,
+}
+
+fn ports_and_flags(protocol: ipv4::ProtocolNumber, payload: &[u8]) -> (u16, u16, u8) {
+    let port = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+
+    match protocol {
+        ipv4::ProtocolNumber::Tcp if payload.len() >= 14 => (port(0), port(2), payload[13] & 0x3f),
+        ipv4::ProtocolNumber::Udp if payload.len() >= 4 => (port(0), port(2), 0),
+        _ => (0, 0, 0),
+    }
+}
+
+/// Flow key, IP length and TCP flags for a frame, if it carries IP.
+fn classify(frame: &ether::Frame) -> Option<(FlowKey, u32, u8)> {
+    match frame.ethertype {
+        ether::Type::Ipv4 => {
+            let header = &frame.payload;
+            let header_len = (*header.first()? & 0xf) as usize * 4;
+            if header.len() < 20 || header.len() < header_len {
+                return None;
+            }
+
+            let protocol = ipv4::ProtocolNumber::try_from(header[9]).unwrap();
+            let (src_port, dest_port, tcp_flags) = ports_and_flags(protocol, &header[header_len..]);
+            let src: [u8; 4] = header[12..16].try_into().unwrap();
+            let dest: [u8; 4] = header[16..20].try_into().unwrap();
+
+            Some((
+                FlowKey {
+                    src: src.into(),
+                    dest: dest.into(),
+                    protocol,
+                    src_port,
+                    dest_port,
+                    tos: header[1],
+                },
+                u16::from_be_bytes([header[2], header[3]]) as u32,
+                tcp_flags,
+            ))
+        }
+        ether::Type::Ipv6 => {
+            let packet = ipv6::packet(&frame.payload).ok()?;
+            let protocol = match packet.next_header {
+                ipv6::NextHeader::Protocol(protocol) => protocol,
+                _ => return None,
+            };
+            let (src_port, dest_port, tcp_flags) = ports_and_flags(protocol, &packet.payload);
+
+            Some((
+                FlowKey {
+                    src: packet.src.0.into(),
+                    dest: packet.dest.0.into(),
+                    protocol,
+                    src_port,
+                    dest_port,
+                    tos: packet.traffic_class,
+                },
+                frame.payload.len() as u32,
+                tcp_flags,
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+/// Samples frames from an interface, aggregates them into flows and periodically sends them to a
+/// collector.
+pub struct Exporter {
+    config: Config,
+    frames: channel::Receiver<ether::Frame>,
+    udp_sender: udp::Sender,
+    src_port: u16,
+    flows: HashMap<FlowKey, Flow>,
+    started: SystemTime,
+    /// Flows (NetFlow) or data records (IPFIX) exported so far.
+    sequence: u32,
+}
+
+impl Exporter {
+    pub fn new(config: Config, interface: &ether::TapInterface, udp_server: &udp::Server) -> Self {
+        let frames = interface.observe(config.sample_rate, config.filter.clone());
+
+        Self {
+            config,
+            frames,
+            udp_sender: udp_server.sender(),
+            src_port: rand::random::<u16>() | 0xc000,
+            flows: HashMap::new(),
+            started: clock::now(),
+            sequence: 0,
+        }
+    }
+
+    fn observe(&mut self, frame: &ether::Frame, seen: SystemTime) {
+        let (key, bytes, tcp_flags) = match classify(frame) {
+            Some(classified) => classified,
+            None => return,
+        };
+
+        let flow = self.flows.entry(key).or_insert(Flow {
+            packets: 0,
+            bytes: 0,
+            tcp_flags: 0,
+            first: seen,
+            last: seen,
+        });
+        flow.packets += 1;
+        flow.bytes += bytes;
+        flow.tcp_flags |= tcp_flags;
+        flow.last = seen;
+    }
+
+    fn uptime_millis(&self, time: SystemTime) -> u32 {
+        time.duration_since(self.started)
+            .unwrap_or_default()
+            .as_millis() as u32
+    }
+
+    fn netflow5_messages(&mut self, flows: &[(FlowKey, Flow)], now: SystemTime) -> Vec<Vec<u8>> {
+        let records: Vec<_> = flows
+            .iter()
+            .filter_map(|(key, flow)| match (key.src, key.dest) {
+                (IpAddr::V4(src), IpAddr::V4(dest)) => Some(encode!(
+                    ipv4::Address::from(src),
+                    ipv4::Address::from(dest),
+                    0u32, // Next hop
+                    0u16, // Input interface
+                    0u16, // Output interface
+                    flow.packets,
+                    flow.bytes,
+                    self.uptime_millis(flow.first),
+                    self.uptime_millis(flow.last),
+                    key.src_port,
+                    key.dest_port,
+                    0u8, // Padding
+                    flow.tcp_flags,
+                    key.protocol,
+                    key.tos,
+                    0u16, // Source AS
+                    0u16, // Destination AS
+                    0u8,  // Source mask
+                    0u8,  // Destination mask
+                    0u16, // Padding
+                )),
+                _ => None,
+            })
+            .collect();
+
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        // Top two bits are the sampling mode; 1 is deterministic.
+        let sampling = 0x4000 | (self.config.sample_rate.min(0x3fff) as u16);
+
+        records
+            .chunks(NETFLOW5_MAX_RECORDS)
+            .map(|chunk| {
+                let message = encode!(
+                    5u16,
+                    chunk.len() as u16,
+                    self.uptime_millis(now),
+                    since_epoch.as_secs() as u32,
+                    since_epoch.subsec_nanos(),
+                    self.sequence,
+                    0u8, // Engine type
+                    0u8, // Engine ID
+                    sampling,
+                    chunk.concat(),
+                );
+                self.sequence = self.sequence.wrapping_add(chunk.len() as u32);
+
+                message
+            })
+            .collect()
+    }
+
+    fn ipfix_template(id: u16, address_ie: (u16, u16), address_len: u16) -> Vec<u8> {
+        let fields: Vec<u16> = vec![
+            address_ie.0,
+            address_len,
+            address_ie.1,
+            address_len,
+            7, // sourceTransportPort
+            2,
+            11, // destinationTransportPort
+            2,
+            4, // protocolIdentifier
+            1,
+            5, // ipClassOfService
+            1,
+            6, // tcpControlBits, reduced-size
+            1,
+            2, // packetDeltaCount, reduced-size
+            4,
+            1, // octetDeltaCount, reduced-size
+            4,
+            150, // flowStartSeconds
+            4,
+            151, // flowEndSeconds
+            4,
+        ];
+
+        encode!(id, (fields.len() / 2) as u16, fields)
+    }
+
+    fn ipfix_messages(&mut self, flows: &[(FlowKey, Flow)], now: SystemTime) -> Vec<Vec<u8>> {
+        let templates = [
+            Self::ipfix_template(IPFIX_TEMPLATE_IPV4, (8, 12), 4),
+            Self::ipfix_template(IPFIX_TEMPLATE_IPV6, (27, 28), 16),
+        ]
+        .concat();
+        let template_set = encode!(2u16, (4 + templates.len()) as u16, templates);
+
+        flows
+            .chunks(IPFIX_MAX_RECORDS)
+            .map(|chunk| {
+                let mut sets = template_set.clone();
+
+                for &template_id in &[IPFIX_TEMPLATE_IPV4, IPFIX_TEMPLATE_IPV6] {
+                    let records: Vec<u8> = chunk
+                        .iter()
+                        .filter_map(|(key, flow)| {
+                            let addresses = match (key.src, key.dest, template_id) {
+                                (IpAddr::V4(src), IpAddr::V4(dest), IPFIX_TEMPLATE_IPV4) => {
+                                    [&src.octets()[..], &dest.octets()[..]].concat()
+                                }
+                                (IpAddr::V6(src), IpAddr::V6(dest), IPFIX_TEMPLATE_IPV6) => {
+                                    [&src.octets()[..], &dest.octets()[..]].concat()
+                                }
+                                _ => return None,
+                            };
+
+                            Some(encode!(
+                                addresses,
+                                key.src_port,
+                                key.dest_port,
+                                key.protocol,
+                                key.tos,
+                                flow.tcp_flags,
+                                flow.packets,
+                                flow.bytes,
+                                unix_secs(flow.first),
+                                unix_secs(flow.last),
+                            ))
+                        })
+                        .flatten()
+                        .collect();
+
+                    if !records.is_empty() {
+                        sets.extend(encode!(template_id, (4 + records.len()) as u16, records));
+                    }
+                }
+
+                let message = encode!(
+                    10u16,
+                    (16 + sets.len()) as u16,
+                    unix_secs(now),
+                    self.sequence,
+                    0u32, // Observation domain
+                    sets,
+                );
+                self.sequence = self.sequence.wrapping_add(chunk.len() as u32);
+
+                message
+            })
+            .collect()
+    }
+
+    fn export(&mut self, now: SystemTime) -> Vec<Vec<u8>> {
+        let flows: Vec<_> = self.flows.drain().collect();
+
+        match self.config.format {
+            Format::Netflow5 => self.netflow5_messages(&flows, now),
+            Format::Ipfix => self.ipfix_messages(&flows, now),
+        }
+    }
+
+    fn send(&self, message: Vec<u8>) -> AHResult<()> {
+        self.udp_sender.send(
+            self.config.source,
+            self.config.collector,
+            &udp::Packet {
+                src_port: self.src_port,
+                dest_port: self.config.collector_port,
+                payload: message,
+            },
+        )
+    }
+
+    pub fn start(mut self) {
+        let ticker = channel::tick(self.config.interval);
+
+        thread::spawn(move || loop {
+            select! {
+                recv(self.frames) -> frame => {
+                    let frame = frame.unwrap();
+                    // Stamped on receipt, as frames can wait a while for their observer.
+                    let seen = frame.received.map_or_else(clock::now, clock::at);
+                    self.observe(&frame, seen);
+                }
+                recv(ticker) -> _ => {
+                    for message in self.export(clock::now()) {
+                        if let Err(e) = self.send(message) {
+                            warn!("flow export failed: {}", e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exporter(format: Format) -> Exporter {
+        let (ipv6_sender, _) = channel::unbounded();
+
+        Exporter {
+            config: Config {
+                collector: "fd00::1".parse().unwrap(),
+                collector_port: 2055,
+                source: "fd00::2".parse().unwrap(),
+                format,
+                sample_rate: 1,
+                filter: Filter::All,
+                interval: Duration::from_secs(10),
+            },
+            frames: channel::never(),
+            udp_sender: udp::Sender {
+                ipv6_sender,
+                flow_table: None,
+                hop_limit: ipv6::HopLimitHandle::new(64),
+                dscp: 0,
+            },
+            src_port: 50000,
+            flows: HashMap::new(),
+            started: UNIX_EPOCH,
+            sequence: 0,
+        }
+    }
+
+    fn ipv4_udp_frame() -> ether::Frame {
+        ether::Frame::builder()
+            .dest(ether::Address::BROADCAST)
+            .src("02:00:00:00:00:01".parse().unwrap())
+            .ethertype(ether::Type::Ipv4)
+            .payload(
+                hex::decode("4500001c00000000401100000a0000010a000002d431003500080000").unwrap(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    fn ipv6_tcp_frame() -> ether::Frame {
+        let segment = crate::protocols::tcp::Segment {
+            src_port: 49152,
+            dest_port: 23,
+            flags: crate::protocols::tcp::Segment::SYN,
+            ..Default::default()
+        };
+        let pseudo_header = ipv6::PseudoHeader {
+            src: "fd00::2".parse().unwrap(),
+            dest: "fd00::3".parse().unwrap(),
+            length: 0,
+        };
+
+        ether::Frame::builder()
+            .dest("02:00:00:00:00:02".parse().unwrap())
+            .src("02:00:00:00:00:01".parse().unwrap())
+            .ethertype(ether::Type::Ipv6)
+            .payload(
+                ipv6::Packet::builder()
+                    .protocol(ipv4::ProtocolNumber::Tcp)
+                    .hop_limit(64)
+                    .src(pseudo_header.src)
+                    .dest(pseudo_header.dest)
+                    .payload(segment.encode(pseudo_header))
+                    .build()
+                    .encode(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn frames_aggregate_into_flows() {
+        let mut exporter = exporter(Format::Ipfix);
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        exporter.observe(&ipv4_udp_frame(), now);
+        exporter.observe(&ipv4_udp_frame(), now);
+        exporter.observe(&ipv6_tcp_frame(), now);
+
+        assert_eq!(exporter.flows.len(), 2);
+        let (key, flow) = exporter
+            .flows
+            .iter()
+            .find(|(key, _)| key.protocol == ipv4::ProtocolNumber::Udp)
+            .unwrap();
+        assert_eq!((key.src_port, key.dest_port), (54321, 53));
+        assert_eq!((flow.packets, flow.bytes), (2, 56));
+    }
+
+    #[test]
+    fn netflow5_exports_ipv4_flows() {
+        let mut exporter = exporter(Format::Netflow5);
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        exporter.observe(&ipv4_udp_frame(), now);
+        exporter.observe(&ipv6_tcp_frame(), now);
+
+        let messages = exporter.export(now);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].len(), 24 + 48);
+        assert_eq!(messages[0][..4], [0, 5, 0, 1]);
+        assert_eq!(exporter.sequence, 1);
+    }
+
+    #[test]
+    fn ipfix_message_lengths_match() {
+        let mut exporter = exporter(Format::Ipfix);
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        exporter.observe(&ipv4_udp_frame(), now);
+        exporter.observe(&ipv6_tcp_frame(), now);
+
+        let messages = exporter.export(now);
+        assert_eq!(messages.len(), 1);
+        let message = &messages[0];
+        assert_eq!(
+            u16::from_be_bytes([message[2], message[3]]) as usize,
+            message.len()
+        );
+        // Header, template set, then one data set per address family.
+        assert_eq!(message.len(), 16 + (4 + 2 * 48) + (4 + 31) + (4 + 55));
+    }
+}
@@ -0,0 +1,163 @@
+//! Rate limits on the ICMP errors we send, to each peer and overall, as real stacks have; they
+//! also keep misbehaving scenarios from setting off storms of errors.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4443#section-2.4
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::time::Instant;
+
+use super::shaping::TokenBucket;
+use crate::stats;
+
+/// Peers remembered before those that have gone quiet are forgotten.
+const MAX_PEERS: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    /// Errors that can be sent at once after a quiet spell.
+    pub burst: f64,
+}
+
+/// Defaults follow Linux: an error a second to each peer after a burst of six, and a thousand a
+/// second overall after a burst of fifty.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub per_peer: Rate,
+    pub global: Rate,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            per_peer: Rate {
+                per_second: 1.0,
+                burst: 6.0,
+            },
+            global: Rate {
+                per_second: 1000.0,
+                burst: 50.0,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Limit {
+    Peer,
+    Global,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Limit::Peer => write!(f, "peer"),
+            Limit::Global => write!(f, "global"),
+        }
+    }
+}
+
+pub struct Limiter {
+    config: Config,
+    global: TokenBucket,
+    peers: HashMap<IpAddr, TokenBucket>,
+}
+
+impl Limiter {
+    pub fn new(config: Config, now: Instant) -> Self {
+        Self {
+            config,
+            global: TokenBucket::new(config.global.per_second, config.global.burst, now),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Take an error to `peer` out of both limits, or count it as dropped by the one it's over.
+    pub fn check(&mut self, peer: IpAddr, now: Instant) -> Result<(), Limit> {
+        if self.peers.len() >= MAX_PEERS {
+            let burst = self.config.per_peer.burst;
+            self.peers.retain(|_, bucket| bucket.tokens(now) < burst);
+        }
+
+        let per_peer = self.config.per_peer;
+        let peer_bucket = self
+            .peers
+            .entry(peer)
+            .or_insert_with(|| TokenBucket::new(per_peer.per_second, per_peer.burst, now));
+
+        let result = if peer_bucket.tokens(now) < 1.0 {
+            Err(Limit::Peer)
+        } else if !self.global.try_take(1.0, now) {
+            Err(Limit::Global)
+        } else {
+            peer_bucket.try_take(1.0, now);
+            Ok(())
+        };
+
+        if let Err(limit) = result {
+            stats::ICMP_ERROR_DROPS.increment(&limit.to_string());
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter(now: Instant) -> Limiter {
+        Limiter::new(
+            Config {
+                per_peer: Rate {
+                    per_second: 1.0,
+                    burst: 2.0,
+                },
+                global: Rate {
+                    per_second: 10.0,
+                    burst: 3.0,
+                },
+            },
+            now,
+        )
+    }
+
+    #[test]
+    fn peers_are_limited_separately() {
+        let now = Instant::now();
+        let mut limiter = limiter(now);
+        let (a, b) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]));
+
+        assert_eq!(limiter.check(a, now), Ok(()));
+        assert_eq!(limiter.check(a, now), Ok(()));
+        assert_eq!(limiter.check(a, now), Err(Limit::Peer));
+        assert_eq!(limiter.check(b, now), Ok(()));
+
+        assert_eq!(limiter.check(a, now + Duration::from_secs(1)), Ok(()));
+    }
+
+    #[test]
+    fn everyone_shares_the_global_limit() {
+        let now = Instant::now();
+        let mut limiter = limiter(now);
+
+        for i in 0..3 {
+            assert_eq!(limiter.check(IpAddr::from([192, 0, 2, i]), now), Ok(()));
+        }
+        assert_eq!(
+            limiter.check(IpAddr::from([192, 0, 2, 3]), now),
+            Err(Limit::Global)
+        );
+        // Turned away by the global limit, so the peer's own allowance is untouched.
+        let later = now + Duration::from_millis(100);
+        assert_eq!(limiter.check(IpAddr::from([192, 0, 2, 3]), later), Ok(()));
+        assert_eq!(
+            limiter.check(IpAddr::from([192, 0, 2, 3]), later),
+            Err(Limit::Global)
+        );
+    }
+}
@@ -0,0 +1,359 @@
+//! Passive inventory: every host seen on the segment, by ether address, with the IP addresses it
+//! was seen using in ARP, NDP and whatever else it sent. The hosts can also be written out as
+//! topology nodes mimicking them, for building fixtures from real networks.
+
+use crossbeam::channel;
+use crossbeam::select;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::filter::Filter;
+use super::ipv6::icmpv6;
+use super::{arp, ether, ipv4, ipv6};
+use crate::status::{self, Key, LearnedHostStatus};
+use crate::warn;
+
+/// Hosts remembered; ones seen after this are ignored.
+pub const MAX_HOSTS: usize = 1024;
+/// Addresses remembered for each host, as a router sends from every address it forwards for.
+pub const MAX_ADDRESSES: usize = 16;
+/// How often `last_seen` times in status are brought up to date.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub shadow: Option<Shadow>,
+}
+
+/// Topology nodes to write for the hosts seen, each with the host's addresses.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Shadow {
+    /// Rewritten as hosts and addresses are learned, with a `[[nodes]]` table for each.
+    pub path: String,
+    /// Node config the shadow nodes are run with, their addresses set over it.
+    pub config: String,
+    /// Switch they're plugged into.
+    pub switch: String,
+}
+
+/// A topology node, as written for shadow nodes.
+#[derive(Serialize)]
+struct ShadowNode {
+    name: String,
+    config: String,
+    switch: String,
+    set: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ShadowNodes {
+    nodes: Vec<ShadowNode>,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Ether addresses in `frame`, each with the IP address it was using, if the frame said.
+fn sightings(frame: &ether::Frame) -> Vec<(ether::Address, Option<IpAddr>)> {
+    let mut result = vec![(frame.src, None)];
+
+    match frame.ethertype {
+        ether::Type::Arp => {
+            if let Ok(packet) = arp::packet(&frame.payload) {
+                // Probes come from 0.0.0.0.
+                let src = Some(packet.src_ipv4)
+                    .filter(|&a| a != ipv4::Address([0; 4]))
+                    .map(|a| IpAddr::from(a.0));
+                result = vec![(packet.src_ether, src)];
+            }
+        }
+        ether::Type::Ipv4 => {
+            if let Ok(packet) = ipv4::packet(&frame.payload) {
+                if packet.src != ipv4::Address([0; 4]) && !packet.src.is_multicast() {
+                    result = vec![(frame.src, Some(IpAddr::from(packet.src.0)))];
+                }
+            }
+        }
+        ether::Type::Ipv6 => {
+            let packet = match ipv6::packet(&frame.payload) {
+                Ok(packet) => packet,
+                Err(_) => return result,
+            };
+            // DAD probes come from the unspecified address.
+            if packet.src != ipv6::Address::default() && !packet.src.is_multicast() {
+                result = vec![(frame.src, Some(IpAddr::from(packet.src.0)))];
+            }
+
+            if packet.next_header != ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Ipv6Icmp) {
+                return result;
+            }
+            let message = icmpv6::packet(
+                &packet.payload,
+                icmpv6::PseudoHeader {
+                    src: packet.src,
+                    dest: packet.dest,
+                    length: packet.payload.len() as u32,
+                },
+            );
+            // Advertisements can be for someone else's address, like a proxy's.
+            if let Ok(icmpv6::Packet::NeighborAdvertisement(advertisement)) = message {
+                for option in &advertisement.options {
+                    if let icmpv6::NeighborSolicitationOption::TargetLinkLayerAddress(address) =
+                        option
+                    {
+                        result.push((*address, Some(IpAddr::from(advertisement.src.0))));
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+
+    result
+}
+
+/// The hosts seen so far, besides the node itself.
+pub struct Inventory {
+    own_address: ether::Address,
+    hosts: BTreeMap<ether::Address, LearnedHostStatus>,
+}
+
+impl Inventory {
+    pub fn new(own_address: ether::Address) -> Self {
+        Self {
+            own_address,
+            hosts: BTreeMap::new(),
+        }
+    }
+
+    /// Record the hosts in `frame`, returning whether any host or address is new.
+    pub fn learn(&mut self, frame: &ether::Frame, seen: SystemTime) -> bool {
+        let seen = unix_secs(seen);
+        let mut learned = false;
+
+        for (address, ip_address) in sightings(frame) {
+            if address == self.own_address || address.is_multicast() || address.0 == [0; 6] {
+                continue;
+            }
+            if !self.hosts.contains_key(&address) && self.hosts.len() >= MAX_HOSTS {
+                continue;
+            }
+
+            let host = self.hosts.entry(address).or_insert_with(|| {
+                learned = true;
+                LearnedHostStatus {
+                    first_seen: seen,
+                    ..Default::default()
+                }
+            });
+            host.last_seen = seen;
+
+            match ip_address {
+                Some(IpAddr::V4(ip_address)) => {
+                    let ip_address = ipv4::Address(ip_address.octets());
+                    if host.ipv4_addresses.len() < MAX_ADDRESSES {
+                        learned |= host.ipv4_addresses.insert(ip_address);
+                    }
+                }
+                Some(IpAddr::V6(ip_address)) => {
+                    let ip_address = ipv6::Address::from(ip_address);
+                    if host.ipv6_addresses.len() < MAX_ADDRESSES {
+                        learned |= host.ipv6_addresses.insert(ip_address);
+                    }
+                }
+                None => {}
+            }
+        }
+
+        learned
+    }
+
+    fn publish(&self) {
+        status::update(|s| {
+            s.learned_hosts = self
+                .hosts
+                .iter()
+                .map(|(&address, host)| (Key(address), host.clone()))
+                .collect();
+        });
+    }
+
+    /// A topology `[[nodes]]` table for each host, setting its ether address and the first IPv4
+    /// address it used, plus any IPv6 addresses beyond link-local ones, which nodes make their own.
+    pub fn shadow_nodes(&self, shadow: &Shadow) -> String {
+        let nodes = self
+            .hosts
+            .iter()
+            .map(|(address, host)| {
+                let mut set = vec![format!("node.ether_address={}", address)];
+                if let Some(ipv4_address) = host.ipv4_addresses.iter().next() {
+                    set.push(format!("node.ipv4_address={}", ipv4_address));
+                }
+                let ipv6_addresses: Vec<_> = host
+                    .ipv6_addresses
+                    .iter()
+                    .filter(|a| a.scope() > 0x2)
+                    .map(|a| format!("{{ address = \"{}\" }}", a))
+                    .collect();
+                if !ipv6_addresses.is_empty() {
+                    set.push(format!(
+                        "node.ipv6_addresses=[{}]",
+                        ipv6_addresses.join(", ")
+                    ));
+                }
+
+                ShadowNode {
+                    name: format!("shadow-{}", address.to_string().replace(':', "")),
+                    config: shadow.config.clone(),
+                    switch: shadow.switch.clone(),
+                    set,
+                }
+            })
+            .collect();
+
+        toml::to_string(&ShadowNodes { nodes }).unwrap()
+    }
+
+    /// Learn from every frame sent or received on `interface`, keeping status and the shadow
+    /// nodes up to date.
+    pub fn start(mut self, config: Config, interface: &ether::TapInterface) {
+        let frames = interface.observe(1, Filter::All);
+        let ticker = channel::tick(PUBLISH_INTERVAL);
+        let mut seen_since_publish = false;
+
+        thread::spawn(move || loop {
+            select! {
+                recv(frames) -> frame => {
+                    let frame = frame.unwrap();
+                    let seen = frame.received.unwrap_or_else(SystemTime::now);
+                    seen_since_publish = true;
+                    if !self.learn(&frame, seen) {
+                        continue;
+                    }
+
+                    self.publish();
+                    seen_since_publish = false;
+                    if let Some(shadow) = &config.shadow {
+                        if let Err(e) = fs::write(&shadow.path, self.shadow_nodes(shadow)) {
+                            warn!("writing shadow nodes to {} failed: {}", shadow.path, e);
+                        }
+                    }
+                }
+                recv(ticker) -> _ => {
+                    if seen_since_publish {
+                        self.publish();
+                        seen_since_publish = false;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWN: ether::Address = ether::Address([0x02, 0, 0, 0, 0, 1]);
+    const HOST: ether::Address = ether::Address([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+
+    fn frame(src: ether::Address, ethertype: ether::Type, payload: Vec<u8>) -> ether::Frame {
+        ether::Frame {
+            dest: ether::Address::BROADCAST,
+            src,
+            ethertype,
+            payload,
+            received: None,
+        }
+    }
+
+    fn arp_request(src_ipv4: &str) -> ether::Frame {
+        let packet = arp::Packet {
+            opcode: arp::PacketOpcode::Request,
+            src_ether: HOST,
+            src_ipv4: src_ipv4.parse().unwrap(),
+            dest_ether: ether::Address([0; 6]),
+            dest_ipv4: "192.0.2.1".parse().unwrap(),
+        };
+        frame(HOST, ether::Type::Arp, packet.encode())
+    }
+
+    #[test]
+    fn hosts_are_learned_from_arp_and_ip() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut inventory = Inventory::new(OWN);
+
+        // Probes show the host, but not an address.
+        assert!(inventory.learn(&arp_request("0.0.0.0"), now));
+        assert!(inventory.learn(&arp_request("192.0.2.7"), now));
+        assert!(!inventory.learn(&arp_request("192.0.2.7"), now));
+
+        let packet = ipv6::Packet::builder()
+            .src("2001:db8::7".parse().unwrap())
+            .dest("2001:db8::1".parse().unwrap())
+            .protocol(ipv4::ProtocolNumber::Udp)
+            .payload(vec![0; 8])
+            .build()
+            .encode();
+        let later = now + Duration::from_secs(5);
+        assert!(inventory.learn(&frame(HOST, ether::Type::Ipv6, packet), later));
+
+        // The node's own frames aren't anyone else's.
+        assert!(!inventory.learn(&frame(OWN, ether::Type::Arp, vec![]), now));
+
+        let host = &inventory.hosts[&HOST];
+        assert_eq!(inventory.hosts.len(), 1);
+        assert_eq!(host.first_seen, 1000);
+        assert_eq!(host.last_seen, 1005);
+        assert_eq!(
+            host.ipv4_addresses.iter().collect::<Vec<_>>(),
+            [&"192.0.2.7".parse::<ipv4::Address>().unwrap()]
+        );
+        assert_eq!(
+            host.ipv6_addresses.iter().collect::<Vec<_>>(),
+            [&"2001:db8::7".parse::<ipv6::Address>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn shadow_nodes_mimic_learned_hosts() {
+        let mut inventory = Inventory::new(OWN);
+        inventory.hosts.insert(
+            HOST,
+            LearnedHostStatus {
+                ipv4_addresses: vec!["192.0.2.7".parse().unwrap()].into_iter().collect(),
+                ipv6_addresses: vec!["fe80::7".parse().unwrap(), "2001:db8::7".parse().unwrap()]
+                    .into_iter()
+                    .collect(),
+                first_seen: 0,
+                last_seen: 0,
+            },
+        );
+
+        let shadow = Shadow {
+            path: "shadow.toml".to_string(),
+            config: "host.toml".to_string(),
+            switch: "lan".to_string(),
+        };
+        let nodes: toml::Value = toml::from_str(&inventory.shadow_nodes(&shadow)).unwrap();
+        let node = &nodes["nodes"][0];
+
+        assert_eq!(node["name"].as_str(), Some("shadow-525400123456"));
+        assert_eq!(node["switch"].as_str(), Some("lan"));
+        assert_eq!(
+            node["set"],
+            toml::Value::Array(vec![
+                "node.ether_address=52:54:00:12:34:56".into(),
+                "node.ipv4_address=192.0.2.7".into(),
+                "node.ipv6_addresses=[{ address = \"2001:db8::7\" }]".into(),
+            ])
+        );
+    }
+}
@@ -1,41 +1,117 @@
-use anyhow::{anyhow, bail};
+use anyhow::{anyhow, bail, Result as AHResult};
 use nom::{
     branch::alt,
     bytes::complete::{tag, take},
     character::complete::{digit0, one_of},
-    combinator::{eof, map_res, recognize},
+    combinator::{eof, map, map_res, recognize, verify},
     multi::separated_list1,
+    number::complete::{be_u16, be_u8},
     sequence::{pair, terminated},
 };
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr};
 use std::str::FromStr;
 
 use super::encdec::{BIResult, EncodeTo, SIResult};
-use crate::{proto_enum_with_unknown, try_parse};
+use super::{ether, ipv6};
+use crate::{encode, proto_enum, proto_enum_with_unknown, serde_via_str, try_parse};
 
 // Ref: https://www.iana.org/assignments/protocol-numbers/protocol-numbers.xhtml
 proto_enum_with_unknown!(ProtocolNumber, u8, {
-    Udp = 17,
-    Ipv6Icmp = 58,
+    Igmp = 2 => "IGMP",
+    Ipv4 = 4 => "IPv4",
+    Tcp = 6 => "TCP",
+    Udp = 17 => "UDP",
+    Ipv6 = 41 => "IPv6",
+    Ipv6Frag = 44 => "IPv6-Frag",
+    Gre = 47 => "GRE",
+    Ipv6Icmp = 58 => "IPv6-ICMP",
+    Sctp = 132 => "SCTP",
 });
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+// Explicit congestion notification codepoints, the low two bits of the IPv4 type of service and
+// the IPv6 traffic class.
+// Ref: https://datatracker.ietf.org/doc/html/rfc3168#section-5
+proto_enum!(Ecn, u8, {
+    NotEct = 0,
+    Ect1 = 1,
+    Ect0 = 2,
+    Ce = 3,
+});
+
+impl Ecn {
+    pub fn of(traffic_class: u8) -> Self {
+        match traffic_class & 0x3 {
+            0 => Ecn::NotEct,
+            1 => Ecn::Ect1,
+            2 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+}
+
+/// Differentiated services codepoint of a type of service or traffic class, its high six bits.
+// Ref: https://datatracker.ietf.org/doc/html/rfc2474#section-3
+pub fn dscp(traffic_class: u8) -> u8 {
+    traffic_class >> 2
+}
+
+/// Type of service or traffic class marked with `dscp` and `ecn`.
+pub fn traffic_class(dscp: u8, ecn: Ecn) -> u8 {
+    (dscp << 2) | ecn as u8
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Address(pub [u8; 4]);
 
-impl Display for Address {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-        for (i, part) in self.0.iter().enumerate() {
-            write!(f, "{:02x}", part)?;
-            if i != 3 {
-                write!(f, ":")?;
-            }
+impl Address {
+    pub fn prefix(&self, len: usize) -> Self {
+        if len == 0 {
+            return Self([0; 4]);
         }
+        let mask = (!0u32) << (32 - len);
 
-        Ok(())
+        Self((u32::from_be_bytes(self.0) & mask).to_be_bytes())
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0xf0 == 0xe0
+    }
+
+    /// This address as an IPv6 one, `::ffff:a.b.c.d`.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc4291#section-2.5.5.2
+    pub fn to_ipv6_mapped(&self) -> ipv6::Address {
+        let [a, b, c, d] = self.0;
+
+        ipv6::Address([
+            0,
+            0,
+            0,
+            0,
+            0,
+            0xffff,
+            u16::from_be_bytes([a, b]),
+            u16::from_be_bytes([c, d]),
+        ])
+    }
+
+    /// The ether address frames to this multicast address go to, from its lowest 23 bits.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc1112#section-6.4
+    pub fn multicast_ether_dest(&self) -> ether::Address {
+        ether::Address([0x01, 0x00, 0x5e, self.0[1] & 0x7f, self.0[2], self.0[3]])
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
     }
 }
 
+serde_via_str!(Address);
+
 fn address_part<'a>(input: &'a str) -> SIResult<'a, u8> {
     map_res(
         alt((tag("0"), recognize(pair(one_of("123456789"), digit0)))),
@@ -47,7 +123,8 @@ impl FromStr for Address {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts = try_parse!(
-            { terminated(separated_list1(tag("."), address_part), eof)(&s) },
+            s,
+            { terminated(separated_list1(tag("."), address_part), eof)(s) },
             "parsing ipv4 address failed: {}"
         )?;
 
@@ -59,6 +136,24 @@ impl FromStr for Address {
     }
 }
 
+impl From<Ipv4Addr> for Address {
+    fn from(address: Ipv4Addr) -> Self {
+        Self(address.octets())
+    }
+}
+
+impl From<Address> for Ipv4Addr {
+    fn from(address: Address) -> Self {
+        Ipv4Addr::from(address.0)
+    }
+}
+
+impl From<Address> for IpAddr {
+    fn from(address: Address) -> Self {
+        IpAddr::V4(address.into())
+    }
+}
+
 impl EncodeTo for Address {
     fn encoded_len(&self) -> usize {
         4
@@ -69,14 +164,211 @@ impl EncodeTo for Address {
     }
 }
 
+/// An IPv4 prefix in `address/length` form.
+pub fn parse_prefix(prefix: &str) -> anyhow::Result<(Address, u8)> {
+    let (address, length) = prefix
+        .split_once('/')
+        .ok_or_else(|| anyhow!("prefix {} is missing a length", prefix))?;
+    let length = length.parse()?;
+    if length > 32 {
+        bail!("prefix length {} is longer than an address", length);
+    }
+
+    Ok((address.parse()?, length))
+}
+
 pub fn address<'a>(input: &'a [u8]) -> BIResult<'a, Address> {
-    take(4 as usize)(input).map(|(i, x)| (i, Address(x.try_into().unwrap())))
+    take(4_usize)(input).map(|(i, x)| (i, Address(x.try_into().unwrap())))
+}
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc791#section-3.1
+
+const MIN_HEADER_LEN: usize = 20;
+const DONT_FRAGMENT: u16 = 0x4000;
+
+/// An IPv4 packet. Options are skipped over, and fragments refused rather than reassembled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    /// Type of service; see `dscp` and `Ecn::of`.
+    pub tos: u8,
+    pub ttl: u8,
+    pub protocol: ProtocolNumber,
+    pub src: Address,
+    pub dest: Address,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Encode without options, with don't fragment set.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = encode!(
+            0x45u8,
+            self.tos,
+            (MIN_HEADER_LEN + self.payload.len()) as u16,
+            0u16, // Identification, which only matters to fragments
+            DONT_FRAGMENT,
+            self.ttl,
+            self.protocol,
+            0u16,
+            self.src,
+            self.dest,
+            &self.payload[..],
+        );
+        let checksum = checksum(&result[..MIN_HEADER_LEN]);
+        result[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        result
+    }
+}
+
+/// One's complement checksum of `header`, which is 0 over a header with a correct checksum.
+fn checksum(header: &[u8]) -> u16 {
+    let mut checksum = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+
+    while checksum > 0xffff {
+        checksum = (checksum & 0xffff) + (checksum >> 16);
+    }
+
+    !(checksum as u16)
+}
+
+pub fn packet(input: &[u8]) -> AHResult<Packet> {
+    try_parse!(
+        input,
+        {
+            let (_, header_len) = map(
+                verify(be_u8, |b| {
+                    b >> 4 == 4 && (b & 0xf) as usize * 4 >= MIN_HEADER_LEN
+                }),
+                |b| (b & 0xf) as usize * 4,
+            )(input)?;
+            let (_, _) = verify(take(header_len), |header: &[u8]| checksum(header) == 0)(input)?;
+
+            let (input, _) = be_u8(input)?;
+            let (input, tos) = be_u8(input)?;
+            let (input, total_len) = verify(be_u16, |len| *len as usize >= header_len)(input)?;
+            let (input, _) = be_u16(input)?;
+            // Neither more fragments nor a fragment offset.
+            let (input, _) = verify(be_u16, |flags| flags & !DONT_FRAGMENT == 0)(input)?;
+            let (input, ttl) = be_u8(input)?;
+            let (input, protocol) = map_res(be_u8, ProtocolNumber::try_from)(input)?;
+            let (input, _) = be_u16(input)?;
+            let (input, src) = address(input)?;
+            let (input, dest) = address(input)?;
+            let (input, _) = take(header_len - MIN_HEADER_LEN)(input)?;
+            // Anything after the packet is padding from the frame it came in.
+            let (input, payload) = take(total_len as usize - header_len)(input)?;
+
+            Ok((
+                input,
+                Packet {
+                    tos,
+                    ttl,
+                    protocol,
+                    src,
+                    dest,
+                    payload: payload.to_vec(),
+                },
+            ))
+        },
+        "parsing ipv4 packet failed: {}"
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn addresses_serialize_dotted() {
+        let address: Address = "192.0.2.1".parse().unwrap();
+        assert_eq!(address.to_string(), "192.0.2.1");
+        assert_eq!(serde_json::to_string(&address).unwrap(), "\"192.0.2.1\"");
+        assert_eq!(
+            serde_json::from_str::<Address>("\"192.0.2.1\"").unwrap(),
+            address
+        );
+        assert!(serde_json::from_str::<Address>("\"192.0.2\"").is_err());
+    }
+
+    #[test]
+    fn std_addresses_convert_both_ways() {
+        let address: Address = "192.0.2.1".parse().unwrap();
+        assert_eq!(Address::from(Ipv4Addr::new(192, 0, 2, 1)), address);
+        assert_eq!(Ipv4Addr::from(address), Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(IpAddr::from(address), IpAddr::from([192, 0, 2, 1]));
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let sent = Packet {
+            tos: 0,
+            ttl: 64,
+            protocol: ProtocolNumber::Ipv6,
+            src: "192.0.2.1".parse().unwrap(),
+            dest: "192.0.2.2".parse().unwrap(),
+            payload: vec![0x60, 0, 0, 0],
+        };
+        let encoded = sent.encode();
+
+        assert_eq!(
+            hex::encode(&encoded),
+            "45000018000040004029b6b9c0000201c000020260000000"
+        );
+        assert_eq!(packet(&encoded).unwrap(), sent);
+
+        // Frame padding is left off.
+        let mut padded = encoded.clone();
+        padded.extend_from_slice(&[0; 22]);
+        assert_eq!(packet(&padded).unwrap(), sent);
+
+        let mut corrupted = encoded.clone();
+        corrupted[8] = 1;
+        assert!(packet(&corrupted).is_err());
+    }
+
+    #[test]
+    fn fragments_are_refused() {
+        let mut fragment = Packet {
+            tos: 0,
+            ttl: 64,
+            protocol: ProtocolNumber::Udp,
+            src: "192.0.2.1".parse().unwrap(),
+            dest: "192.0.2.2".parse().unwrap(),
+            payload: vec![0; 8],
+        }
+        .encode();
+        // More fragments, with the checksum fixed up to match.
+        fragment[6] |= 0x20;
+        fragment[10..12].fill(0);
+        let checksum = checksum(&fragment[..MIN_HEADER_LEN]);
+        fragment[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        assert!(packet(&fragment).is_err());
+    }
+
+    #[test]
+    fn mapped_addresses_embed_the_ipv4_address() {
+        let address: Address = "192.0.2.1".parse().unwrap();
+        assert_eq!(address.to_ipv6_mapped().to_string(), "::ffff:192.0.2.1");
+        assert_eq!(address.to_ipv6_mapped().to_ipv4_mapped(), Some(address));
+        assert_eq!(ipv6::Address::default().to_ipv4_mapped(), None);
+    }
+
+    #[test]
+    fn multicast_ether_dest_keeps_the_lowest_23_bits() {
+        let group: Address = "239.129.2.3".parse().unwrap();
+        assert!(group.is_multicast());
+        assert_eq!(
+            group.multicast_ether_dest(),
+            ether::Address([0x01, 0x00, 0x5e, 0x01, 0x02, 0x03])
+        );
+        assert!(!"10.0.0.1".parse::<Address>().unwrap().is_multicast());
+    }
+
     #[test]
     fn nonzero_address_decodes() {
         assert_eq!("1.2.3.4".parse::<Address>().unwrap(), Address([1, 2, 3, 4]));
@@ -89,4 +381,23 @@ mod tests {
             Address([10, 0, 3, 0])
         );
     }
+
+    #[test]
+    fn traffic_classes_split_into_dscp_and_ecn() {
+        // Expedited forwarding, congestion experienced
+        assert_eq!(dscp(0xbb), 46);
+        assert_eq!(Ecn::of(0xbb), Ecn::Ce);
+        assert_eq!(traffic_class(46, Ecn::Ce), 0xbb);
+        assert_eq!(traffic_class(0, Ecn::Ect0), 0x02);
+    }
+
+    #[test]
+    fn prefixes_mask_the_host_bits() {
+        let address: Address = "10.0.5.77".parse().unwrap();
+
+        assert_eq!(address.prefix(24), Address([10, 0, 5, 0]));
+        assert_eq!(address.prefix(30), Address([10, 0, 5, 76]));
+        assert_eq!(address.prefix(0), Address([0; 4]));
+        assert_eq!(address.prefix(32), address);
+    }
 }
@@ -1,4 +1,4 @@
-use anyhow::{anyhow, bail};
+use anyhow::{bail, Result as AHResult};
 use nom::{
     bytes,
     combinator::{eof, map_res, opt},
@@ -7,18 +7,19 @@ use nom::{
     sequence::{terminated, tuple},
 };
 use std::convert::TryInto;
+use std::net::{IpAddr, Ipv6Addr};
 use std::str::FromStr;
 
 use crate::protocols::encdec::{BIResult, EncodeTo, SIResult};
-use crate::protocols::ether;
+use crate::protocols::{ether, ipv4};
 
-use crate::try_parse;
+use crate::{serde_via_str, try_parse};
 
-#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct Address(pub [u16; 8]);
 
 fn is_hex_digit(c: char) -> bool {
-    c.is_digit(16)
+    c.is_ascii_hexdigit()
 }
 
 fn address_part<'a>(input: &'a str) -> SIResult<'a, u16> {
@@ -31,7 +32,25 @@ fn address_part<'a>(input: &'a str) -> SIResult<'a, u16> {
 impl FromStr for Address {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The last 32 bits can be written as an IPv4 address, as for IPv4-mapped addresses.
+        // Ref: https://datatracker.ietf.org/doc/html/rfc4291#section-2.2
+        let with_ipv4;
+        let s = match s.rsplit_once(':') {
+            Some((head, ipv4)) if ipv4.contains('.') => {
+                let [a, b, c, d] = ipv4::Address::from_str(ipv4)?.0;
+                with_ipv4 = format!(
+                    "{}:{:x}:{:x}",
+                    head,
+                    u16::from_be_bytes([a, b]),
+                    u16::from_be_bytes([c, d])
+                );
+                &with_ipv4[..]
+            }
+            _ => s,
+        };
+
         let (maybe_head, placeholder, maybe_tail) = try_parse!(
+            s,
             {
                 terminated(
                     tuple((
@@ -40,7 +59,7 @@ impl FromStr for Address {
                         opt(separated_list1(bytes::complete::tag(":"), address_part)),
                     )),
                     eof,
-                )(&s)
+                )(s)
             },
             "parsing ipv6 address failed: {}"
         )?;
@@ -66,58 +85,51 @@ impl FromStr for Address {
     }
 }
 
-impl std::fmt::Display for Address {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        let mut longest_zeroes_start = 8;
-        let mut longest_zeroes_len = 0;
-        let mut i = 0;
-
-        while i < 8 {
-            let start = i;
-            let mut len = 0;
+fn write_groups(f: &mut std::fmt::Formatter<'_>, groups: &[u16]) -> std::fmt::Result {
+    for (i, group) in groups.iter().enumerate() {
+        if i != 0 {
+            write!(f, ":")?;
+        }
+        write!(f, "{:x}", group)?;
+    }
 
-            while i < 8 && self.0[i] == 0 {
-                len += 1;
-                i += 1;
-            }
+    Ok(())
+}
 
-            if len > longest_zeroes_len {
-                longest_zeroes_start = start;
-                longest_zeroes_len = len;
-            }
+// Ref: https://datatracker.ietf.org/doc/html/rfc5952#section-4
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        // IPv4-mapped addresses end in the IPv4 address, as it's usually written.
+        // Ref: https://datatracker.ietf.org/doc/html/rfc5952#section-5
+        if let Some(ipv4) = self.to_ipv4_mapped() {
+            return write!(f, "::ffff:{}", ipv4);
+        }
 
-            if start == i {
-                i += 1;
+        // The longest run of zero groups, or the first of the longest, is shortened to "::", but
+        // only if it's more than one group.
+        let (mut start, mut len) = (0, 0);
+        let mut run_start = 0;
+        for (i, group) in self.0.iter().enumerate() {
+            if *group != 0 {
+                run_start = i + 1;
+            } else if i + 1 - run_start > len {
+                start = run_start;
+                len = i + 1 - run_start;
             }
         }
 
-        let mut i = 0;
-        while i < 8 {
-            if i == longest_zeroes_start {
-                write!(f, ":")?;
-
-                while i < longest_zeroes_start + longest_zeroes_len {
-                    if i == 0 || i == 7 {
-                        write!(f, ":")?;
-                    }
-
-                    i += 1;
-                }
-            } else {
-                write!(f, "{:x}", self.0[i])?;
-
-                if i != 7 {
-                    write!(f, ":")?;
-                }
-
-                i += 1;
-            }
+        if len < 2 {
+            return write_groups(f, &self.0);
         }
 
-        Ok(())
+        write_groups(f, &self.0[..start])?;
+        write!(f, "::")?;
+        write_groups(f, &self.0[start + len..])
     }
 }
 
+serde_via_str!(Address);
+
 impl EncodeTo for Address {
     fn encoded_len(&self) -> usize {
         16
@@ -156,6 +168,24 @@ impl std::convert::From<u128> for Address {
     }
 }
 
+impl From<Ipv6Addr> for Address {
+    fn from(address: Ipv6Addr) -> Self {
+        Self(address.segments())
+    }
+}
+
+impl From<Address> for Ipv6Addr {
+    fn from(address: Address) -> Self {
+        Ipv6Addr::from(address.0)
+    }
+}
+
+impl From<Address> for IpAddr {
+    fn from(address: Address) -> Self {
+        IpAddr::V6(address.into())
+    }
+}
+
 impl Address {
     pub fn combine_subnet(&self, subnet: &Address) -> Address {
         let subnet_bits: u128 = (*subnet).into();
@@ -187,12 +217,50 @@ impl Address {
         (u128::from(*self) & mask).into()
     }
 
+    /// The address in the /64 `prefix` whose interface identifier is the modified EUI-64 of
+    /// `ether_address`, as classic stateless autoconfiguration makes.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc4291#appendix-A
+    pub fn from_eui64(ether_address: ether::Address, prefix: Address) -> Self {
+        let [a, b, c, d, e, f] = ether_address.0;
+        // The universal/local bit is inverted, so locally administered addresses give small
+        // identifiers that are easy to type.
+        let id = u64::from_be_bytes([a ^ 0x02, b, c, 0xff, 0xfe, d, e, f]);
+
+        Address::from(id as u128).combine_subnet(&prefix.prefix(64))
+    }
+
+    /// The IPv4 address this is mapped from, if it's `::ffff:a.b.c.d`.
+    pub fn to_ipv4_mapped(&self) -> Option<ipv4::Address> {
+        if self.0[..6] != [0, 0, 0, 0, 0, 0xffff] {
+            return None;
+        }
+
+        let [a, b] = self.0[6].to_be_bytes();
+        let [c, d] = self.0[7].to_be_bytes();
+        Some(ipv4::Address([a, b, c, d]))
+    }
+
     pub fn random(rng: &mut impl rand::Rng) -> Self {
         let full: u128 = rng.gen();
 
         Address::from(full)
     }
 
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0xff00 == 0xff00
+    }
+
+    // Ref: https://datatracker.ietf.org/doc/html/rfc6724#section-3.1
+    pub fn scope(&self) -> u8 {
+        if self.is_multicast() {
+            (self.0[0] & 0xf) as u8
+        } else if self.0[0] & 0xffc0 == 0xfe80 || *self == Address([0, 0, 0, 0, 0, 0, 0, 1]) {
+            0x2
+        } else {
+            0xe
+        }
+    }
+
     pub fn solicited_nodes_multicast(&self) -> Self {
         self.suffix(24)
             .combine_subnet(&("ff02::1:ff00:0".parse().unwrap()))
@@ -212,6 +280,118 @@ impl Address {
     }
 }
 
+/// The addresses sharing their first `len` bits with `address`, written `fd00::/64`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Prefix {
+    pub address: Address,
+    pub len: u8,
+}
+
+impl Prefix {
+    pub fn contains(&self, address: Address) -> bool {
+        address.prefix(self.len as usize) == self.address.prefix(self.len as usize)
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, len) = match s.split_once('/') {
+            Some(parts) => parts,
+            None => bail!("prefix {} is missing a length", s),
+        };
+        let len = len.parse()?;
+        if len > 128 {
+            bail!("prefix length {} is longer than an address", len);
+        }
+
+        Ok(Self {
+            address: address.parse()?,
+            len,
+        })
+    }
+}
+
+impl std::fmt::Display for Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}/{}", self.address, self.len)
+    }
+}
+
+serde_via_str!(Prefix);
+
+/// An address with the zone it's in, written `fe80::1%eth0`, naming which interface a link-local
+/// address is reached through when a node has several.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4007#section-11
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ScopedAddress {
+    pub address: Address,
+    pub zone: Option<String>,
+}
+
+impl ScopedAddress {
+    /// The address, if it can be reached through the interface named `if_name`. Nodes only have
+    /// the one interface, so any other zone can't be reached at all.
+    pub fn on(&self, if_name: &str) -> AHResult<Address> {
+        match &self.zone {
+            Some(zone) if zone != if_name => bail!(
+                "{} is reached through interface {}, but the node's only interface is {}",
+                self,
+                zone,
+                if_name
+            ),
+            _ => Ok(self.address),
+        }
+    }
+}
+
+impl From<Address> for ScopedAddress {
+    fn from(address: Address) -> Self {
+        Self {
+            address,
+            zone: None,
+        }
+    }
+}
+
+impl FromStr for ScopedAddress {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, zone) = match s.split_once('%') {
+            Some((address, zone)) => (address.parse::<Address>()?, Some(zone)),
+            None => (s.parse()?, None),
+        };
+
+        if let Some(zone) = zone {
+            if zone.is_empty() {
+                bail!("zone of {} is empty", s);
+            }
+            // Global addresses are the same whichever interface they're reached through.
+            if address.scope() >= 0xe {
+                bail!("{} is global, so can't have a zone", address);
+            }
+        }
+
+        Ok(Self {
+            address,
+            zone: zone.map(str::to_string),
+        })
+    }
+}
+
+serde_via_str!(ScopedAddress);
+
+impl std::fmt::Display for ScopedAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.address)?;
+        if let Some(zone) = &self.zone {
+            write!(f, "%{}", zone)?;
+        }
+
+        Ok(())
+    }
+}
+
 pub fn address<'a>(input: &'a [u8]) -> BIResult<'a, Address> {
     many_m_n(8, 8, be_u16)(input).map(|(i, x)| (i, Address(x.try_into().unwrap())))
 }
@@ -219,6 +399,7 @@ pub fn address<'a>(input: &'a [u8]) -> BIResult<'a, Address> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use std::convert::From;
     use std::fmt::Write;
 
@@ -276,11 +457,11 @@ mod tests {
         );
         assert_eq!(
             u128::from(ipv6a("::1")),
-            00000000000000000000000000000001u128,
+            0x00000000000000000000000000000001u128,
         );
         assert_eq!(
             u128::from(ipv6a("::")),
-            00000000000000000000000000000000u128,
+            0x00000000000000000000000000000000u128,
         );
         assert_eq!(
             u128::from(ipv6a("fedc:ba98:7654:3210:fedc:ba98:7654:3210")),
@@ -386,6 +567,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn scope_matches_address_type() {
+        assert_eq!(ipv6a("ff05::1:3").scope(), 0x5);
+        assert_eq!(ipv6a("fe80::1").scope(), 0x2);
+        assert_eq!(ipv6a("::1").scope(), 0x2);
+        assert_eq!(ipv6a("fd00::1").scope(), 0xe);
+        assert_eq!(ipv6a("2001:db8::1").scope(), 0xe);
+    }
+
     #[test]
     fn multicast_ether_dest_preserves_lowest_bits() {
         assert_eq!(
@@ -402,6 +592,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn ipv4_suffixes_parse() {
+        assert_eq!(
+            ipv6a("::ffff:192.0.2.1"),
+            Address([0, 0, 0, 0, 0, 0xffff, 0xc000, 0x0201])
+        );
+        assert_eq!(ipv6a("64:ff9b::198.51.100.7"), ipv6a("64:ff9b::c633:6407"));
+        assert!("::ffff:192.0.2".parse::<Address>().is_err());
+    }
+
     #[test]
     fn display_shows_full_addresses() {
         let mut buffer = String::new();
@@ -435,4 +635,130 @@ mod tests {
             assert_eq!(buffer, "fedc:0:0:3210::3210");
         }
     }
+
+    #[test]
+    fn display_follows_rfc_5952() {
+        for (address, expected) in [
+            // Ref: https://datatracker.ietf.org/doc/html/rfc5952#section-4.2.2
+            ("2001:db8:0:1:1:1:1:1", "2001:db8:0:1:1:1:1:1"),
+            // Ref: https://datatracker.ietf.org/doc/html/rfc5952#section-4.2.3
+            ("2001:0:0:1:0:0:0:1", "2001:0:0:1::1"),
+            ("2001:db8:0:0:1:0:0:1", "2001:db8::1:0:0:1"),
+            // Ref: https://datatracker.ietf.org/doc/html/rfc5952#section-4.3
+            ("2001:DB8::ABCD", "2001:db8::abcd"),
+            ("2001:0db8::0001", "2001:db8::1"),
+            ("1:0:0:0:0:0:0:0", "1::"),
+            ("0:0:0:0:0:0:0:0", "::"),
+            ("1:0:1:0:1:0:1:0", "1:0:1:0:1:0:1:0"),
+            ("0:1:2:3:4:5:6:7", "0:1:2:3:4:5:6:7"),
+            ("0:0:1:2:3:4:5:6", "::1:2:3:4:5:6"),
+            ("1:2:3:4:5:6:0:0", "1:2:3:4:5:6::"),
+            ("::ffff:c000:201", "::ffff:192.0.2.1"),
+            ("::c000:201", "::c000:201"),
+        ]
+        .iter()
+        {
+            assert_eq!(ipv6a(address).to_string(), *expected, "{}", address);
+        }
+    }
+
+    fn arb_address() -> impl Strategy<Value = Address> {
+        // Mostly zeroes, for plenty of runs of them to shorten.
+        let group = prop_oneof![3 => Just(0u16), 1 => Just(0xffff), 2 => any::<u16>()];
+        prop::array::uniform8(group).prop_map(Address)
+    }
+
+    proptest! {
+        #[test]
+        fn display_matches_std(address in arb_address()) {
+            let expected = Ipv6Addr::from(address).to_string();
+            prop_assert_eq!(address.to_string(), expected);
+        }
+
+        #[test]
+        fn display_round_trips(address in arb_address()) {
+            prop_assert_eq!(ipv6a(&address.to_string()), address);
+        }
+    }
+
+    #[test]
+    fn eui64_addresses_embed_the_ether_address() {
+        let prefix = ipv6a("2001:db8:1:2::");
+        assert_eq!(
+            Address::from_eui64("00:1b:63:84:45:e6".parse().unwrap(), prefix),
+            ipv6a("2001:db8:1:2:21b:63ff:fe84:45e6")
+        );
+        assert_eq!(
+            Address::from_eui64("02:00:00:00:00:01".parse().unwrap(), ipv6a("fe80::1:2:3:4")),
+            ipv6a("fe80::ff:fe00:1")
+        );
+    }
+
+    #[test]
+    fn std_addresses_convert_both_ways() {
+        let std_address: Ipv6Addr = "2001:db8::1:2".parse().unwrap();
+        assert_eq!(Address::from(std_address), ipv6a("2001:db8::1:2"));
+        assert_eq!(Ipv6Addr::from(ipv6a("2001:db8::1:2")), std_address);
+        assert_eq!(
+            IpAddr::from(ipv6a("2001:db8::1:2")),
+            IpAddr::V6(std_address)
+        );
+    }
+
+    #[test]
+    fn addresses_serialize_as_strings() {
+        let addresses: Vec<Address> = serde_json::from_str(r#"["2001:db8::1", "::"]"#).unwrap();
+        assert_eq!(addresses, vec![ipv6a("2001:db8::1"), ipv6a("::")]);
+        assert_eq!(
+            serde_json::to_string(&addresses).unwrap(),
+            r#"["2001:db8::1","::"]"#
+        );
+
+        let scoped: ScopedAddress = serde_json::from_str(r#""fe80::1%eth0""#).unwrap();
+        assert_eq!(serde_json::to_string(&scoped).unwrap(), r#""fe80::1%eth0""#);
+    }
+
+    #[test]
+    fn prefixes_contain_addresses_sharing_their_bits() {
+        let prefix: Prefix = "fd00:1::/64".parse().unwrap();
+        assert!(prefix.contains(ipv6a("fd00:1::1234")));
+        assert!(!prefix.contains(ipv6a("fd00:2::1")));
+        assert!("::/0"
+            .parse::<Prefix>()
+            .unwrap()
+            .contains(ipv6a("2001:db8::1")));
+        assert_eq!(prefix.to_string(), "fd00:1::/64");
+
+        assert!("fd00::".parse::<Prefix>().is_err());
+        assert!("fd00::/129".parse::<Prefix>().is_err());
+    }
+
+    #[test]
+    fn scoped_addresses_round_trip() {
+        let scoped: ScopedAddress = "fe80::1%eth0".parse().unwrap();
+        assert_eq!(scoped.address, ipv6a("fe80::1"));
+        assert_eq!(scoped.zone.as_deref(), Some("eth0"));
+        assert_eq!(scoped.to_string(), "fe80::1%eth0");
+        assert_eq!(
+            "ff02::1".parse::<ScopedAddress>().unwrap(),
+            ipv6a("ff02::1").into()
+        );
+
+        assert!("fe80::1%".parse::<ScopedAddress>().is_err());
+        assert!("2001:db8::1%eth0".parse::<ScopedAddress>().is_err());
+    }
+
+    #[test]
+    fn scoped_addresses_are_only_on_their_zone() {
+        let scoped: ScopedAddress = "fe80::1%tunnel0".parse().unwrap();
+        assert_eq!(
+            scoped.on("eth0").unwrap_err().to_string(),
+            "fe80::1%tunnel0 is reached through interface tunnel0, but the node's only interface is eth0"
+        );
+        assert_eq!(scoped.on("tunnel0").unwrap(), ipv6a("fe80::1"));
+        assert_eq!(
+            ScopedAddress::from(ipv6a("fe80::1")).on("eth0").unwrap(),
+            ipv6a("fe80::1")
+        );
+    }
 }
@@ -0,0 +1,156 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use super::Address;
+use crate::status;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc8106#section-5.3
+
+const INFINITE_LIFETIME: u32 = 0xffffffff;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry<T> {
+    value: T,
+    expires_at: Option<Instant>,
+}
+
+/// Update `entries` with values from one option, returning when they expire if they were kept.
+fn update<T: PartialEq>(
+    entries: &mut Vec<Entry<T>>,
+    values: Vec<T>,
+    lifetime: u32,
+    now: Instant,
+) -> Option<Instant> {
+    let expires_at = match lifetime {
+        INFINITE_LIFETIME => None,
+        _ => Some(now + Duration::from_secs(lifetime as u64)),
+    };
+
+    for value in values {
+        entries.retain(|e| e.value != value);
+
+        if lifetime != 0 {
+            entries.push(Entry { value, expires_at });
+        }
+    }
+
+    expires_at.filter(|_| lifetime != 0)
+}
+
+/// Recursive DNS servers and search domains learned from router advertisements.
+#[derive(Debug, Default)]
+pub struct DnsConfig {
+    servers: Vec<Entry<Address>>,
+    search_list: Vec<Entry<String>>,
+}
+
+impl DnsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record servers from an RDNSS option, returning when they'll need to be checked for expiry.
+    pub fn update_servers(
+        &mut self,
+        servers: Vec<Address>,
+        lifetime: u32,
+        now: Instant,
+    ) -> Option<Instant> {
+        let result = update(&mut self.servers, servers, lifetime, now);
+        self.write_status();
+
+        result
+    }
+
+    /// Record domains from a DNSSL option, returning when they'll need to be checked for expiry.
+    pub fn update_search_list(
+        &mut self,
+        domains: Vec<String>,
+        lifetime: u32,
+        now: Instant,
+    ) -> Option<Instant> {
+        let result = update(&mut self.search_list, domains, lifetime, now);
+        self.write_status();
+
+        result
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let prev_len = self.servers.len() + self.search_list.len();
+        let live = |expires_at: Option<Instant>| expires_at.is_none_or(|t| t > now);
+        self.servers.retain(|e| live(e.expires_at));
+        self.search_list.retain(|e| live(e.expires_at));
+
+        if self.servers.len() + self.search_list.len() != prev_len {
+            self.write_status();
+        }
+    }
+
+    /// Servers in the order they were learned.
+    pub fn servers(&self) -> Vec<Address> {
+        self.servers.iter().map(|e| e.value).collect()
+    }
+
+    pub fn search_list(&self) -> Vec<String> {
+        self.search_list.iter().map(|e| e.value.clone()).collect()
+    }
+
+    fn write_status(&self) {
+        status::update(|s| {
+            s.interface.dns_servers = self.servers();
+            s.interface.dns_search_list = self.search_list();
+        });
+    }
+}
+
+/// Shared, read-only view of learned DNS configuration for upper layers.
+#[derive(Clone, Debug)]
+pub struct DnsHandle(pub(super) Arc<RwLock<DnsConfig>>);
+
+impl DnsHandle {
+    pub fn servers(&self) -> Vec<Address> {
+        self.0.read().unwrap().servers()
+    }
+
+    pub fn search_list(&self) -> Vec<String> {
+        self.0.read().unwrap().search_list()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv6a(s: &str) -> Address {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn servers_expire_with_their_lifetime() {
+        let now = Instant::now();
+        let mut config = DnsConfig::new();
+
+        config.update_servers(vec![ipv6a("2001:db8::53")], 30, now);
+        config.update_servers(vec![ipv6a("2001:db8::54")], INFINITE_LIFETIME, now);
+        assert_eq!(
+            config.servers(),
+            vec![ipv6a("2001:db8::53"), ipv6a("2001:db8::54")]
+        );
+
+        config.expire(now + Duration::from_secs(30));
+        assert_eq!(config.servers(), vec![ipv6a("2001:db8::54")]);
+    }
+
+    #[test]
+    fn zero_lifetime_removes_domains() {
+        let now = Instant::now();
+        let mut config = DnsConfig::new();
+
+        config.update_search_list(vec!["example.net".to_string()], 60, now);
+        assert_eq!(
+            config.update_search_list(vec!["example.net".to_string()], 0, now),
+            None
+        );
+        assert!(config.search_list().is_empty());
+    }
+}
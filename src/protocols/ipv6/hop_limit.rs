@@ -0,0 +1,34 @@
+//! The hop limit of everything sent besides NDP, shared with upper layers so a router advertising
+//! another changes it for all of them.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-6.3.4
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use crate::status;
+
+pub const DEFAULT_HOP_LIMIT: u8 = 64;
+
+#[derive(Clone, Debug)]
+pub struct HopLimitHandle(Arc<AtomicU8>);
+
+impl HopLimitHandle {
+    /// Handle to a hop limit of its own, for upper layers that run without a full IPv6 server.
+    pub fn new(hop_limit: u8) -> Self {
+        Self(Arc::new(AtomicU8::new(hop_limit)))
+    }
+
+    pub fn get(&self) -> u8 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set(&self, hop_limit: u8) {
+        self.0.store(hop_limit, Ordering::Relaxed);
+        self.report();
+    }
+
+    pub(super) fn report(&self) {
+        let hop_limit = self.get();
+        status::update(|s| s.interface.hop_limit = Some(hop_limit));
+    }
+}
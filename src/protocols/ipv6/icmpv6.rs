@@ -1,22 +1,37 @@
-use anyhow::{anyhow, bail, Result as AHResult};
+use anyhow::{bail, Result as AHResult};
 use byteorder::ByteOrder;
 use nom::{
     bytes::complete::take,
-    combinator::{consumed, eof, map_res},
+    combinator::{eof, map, map_res, rest, verify},
     multi::many0,
-    number::complete::be_u8,
+    number::complete::{be_u16, be_u32, be_u8},
     sequence::terminated,
 };
+use serde::Deserialize;
 use std::convert::TryFrom;
+use std::time::Duration;
 
-use crate::protocols::encdec::{BIResult, EncodeTo};
+use crate::protocols::dns;
+use crate::protocols::encdec::{flag, BIResult, EncodeTo, Tlv};
 use crate::protocols::ether;
 use crate::protocols::ipv4;
 use crate::protocols::ipv6;
-use crate::{encode, encode_to, proto_enum, proto_enum_with_unknown, try_parse};
+use crate::protocols::limits;
+use crate::{
+    encode, encode_to, flags, packet_layout, proto_enum, proto_enum_with_unknown, try_parse,
+};
+
+pub use super::packet::PseudoHeader;
 
 // Ref: https://datatracker.ietf.org/doc/html/rfc4443
 
+pub const UNREACHABLE_PORT: u8 = 4;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc4443#section-3.4
+pub const PROBLEM_ERRONEOUS_HEADER: u8 = 0;
+pub const PROBLEM_UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+pub const PROBLEM_UNRECOGNIZED_OPTION: u8 = 2;
+
 proto_enum_with_unknown!(Type, u8, {
     DestinationUnreachable = 1,
     TooBig = 2,
@@ -25,8 +40,10 @@ proto_enum_with_unknown!(Type, u8, {
     EchoRequest = 128,
     EchoReply = 129,
     RouterSolicitation = 133,
+    RouterAdvertisement = 134,
     NeighborSolicitation = 135,
     NeighborAdvertisement = 136,
+    MldQuery = 130,
     MldV2Report = 143,
 });
 
@@ -34,42 +51,105 @@ proto_enum_with_unknown!(Type, u8, {
 proto_enum_with_unknown!(NeighborSolicitationOptionType, u8, {
     SourceLinkLayerAddress = 1,
     TargetLinkLayerAddress = 2,
+    PrefixInformation = 3,
+    Mtu = 5,
     Nonce = 14,
+    RecursiveDnsServers = 25,
+    DnsSearchList = 31,
 });
 
+#[derive(Debug, PartialEq)]
+pub struct PrefixInformation {
+    pub prefix_length: u8,
+    pub on_link: bool,
+    pub autonomous: bool,
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+    pub prefix: ipv6::Address,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum NeighborSolicitationOption {
     SourceLinkLayerAddress(ether::Address),
     TargetLinkLayerAddress(ether::Address),
+    PrefixInformation(PrefixInformation),
+    Mtu(u32),
     Nonce(Vec<u8>),
+    /// Ref: https://datatracker.ietf.org/doc/html/rfc8106#section-5.1
+    RecursiveDnsServers {
+        lifetime: u32,
+        servers: Vec<ipv6::Address>,
+    },
+    /// Ref: https://datatracker.ietf.org/doc/html/rfc8106#section-5.2
+    DnsSearchList {
+        lifetime: u32,
+        domains: Vec<String>,
+    },
+    /// Options of unknown types, and DNS options too malformed to use.
+    Unknown(u8, Vec<u8>),
 }
 
-impl EncodeTo for NeighborSolicitationOption {
-    fn encoded_len(&self) -> usize {
-        match self {
-            NeighborSolicitationOption::Nonce(nonce) => 2 + nonce.len(),
-            _ => {
-                todo!("unsupported option: {:?}", self)
-            }
-        }
-    }
-    fn encode_to(&self, buf: &mut [u8]) {
+impl NeighborSolicitationOption {
+    /// The option's type and its value, before padding.
+    fn type_and_value(&self) -> (NeighborSolicitationOptionType, Vec<u8>) {
         match self {
+            NeighborSolicitationOption::SourceLinkLayerAddress(address) => (
+                NeighborSolicitationOptionType::SourceLinkLayerAddress,
+                encode!(address),
+            ),
+            NeighborSolicitationOption::TargetLinkLayerAddress(address) => (
+                NeighborSolicitationOptionType::TargetLinkLayerAddress,
+                encode!(address),
+            ),
+            NeighborSolicitationOption::PrefixInformation(info) => (
+                NeighborSolicitationOptionType::PrefixInformation,
+                encode!(
+                    info.prefix_length,
+                    flags!(u8, info.on_link => 7, info.autonomous => 6),
+                    info.valid_lifetime,
+                    info.preferred_lifetime,
+                    0u32, // Reserved
+                    info.prefix,
+                ),
+            ),
+            NeighborSolicitationOption::Mtu(mtu) => (
+                NeighborSolicitationOptionType::Mtu,
+                encode!(0u16 /* Reserved */, mtu),
+            ),
             NeighborSolicitationOption::Nonce(nonce) => {
-                encode_to!(
-                    buf,
-                    NeighborSolicitationOptionType::Nonce,
-                    ((nonce.len() + 2) as f64 / 8f64).ceil() as u8,
-                    nonce
-                );
+                (NeighborSolicitationOptionType::Nonce, nonce.clone())
             }
-            _ => {
-                todo!("unsupported option: {:?}", self)
+            NeighborSolicitationOption::RecursiveDnsServers { lifetime, servers } => (
+                NeighborSolicitationOptionType::RecursiveDnsServers,
+                encode!(0u16 /* Reserved */, lifetime, servers),
+            ),
+            NeighborSolicitationOption::DnsSearchList { lifetime, domains } => {
+                let names: Vec<_> = domains.iter().map(|d| dns::Name(d)).collect();
+                (
+                    NeighborSolicitationOptionType::DnsSearchList,
+                    // Anything after the names is zero padding
+                    encode!(0u16 /* Reserved */, lifetime, names),
+                )
             }
+            NeighborSolicitationOption::Unknown(option_type, value) => (
+                NeighborSolicitationOptionType::Unknown(*option_type),
+                value.clone(),
+            ),
         }
     }
 }
 
+impl EncodeTo for NeighborSolicitationOption {
+    fn encoded_len(&self) -> usize {
+        Tlv::NDP.encoded_len(self.type_and_value().1.len())
+    }
+
+    fn encode_to(&self, buf: &mut [u8]) {
+        let (option_type, value) = self.type_and_value();
+        Tlv::NDP.encode_to(buf, option_type, value);
+    }
+}
+
 proto_enum!(Mldv2AddressRecordType, u8, {
     CodeIsInclude = 1,
     CodeIsExclude = 2,
@@ -79,55 +159,267 @@ proto_enum!(Mldv2AddressRecordType, u8, {
     ClockOldSources = 6,
 });
 
+/// A multicast listener query, general when `address` is unspecified. MLDv1 queries parse as ones
+/// without the MLDv2 fields.
+// Ref: https://datatracker.ietf.org/doc/html/rfc3810#section-5.1
+#[derive(Debug, PartialEq)]
+pub struct MldQuery {
+    pub max_response_code: u16,
+    pub address: ipv6::Address,
+    pub suppress_router_processing: bool,
+    /// Querier's robustness variable, QRV.
+    pub robustness: u8,
+    /// Querier's query interval code, QQIC.
+    pub query_interval_code: u8,
+    pub sources: Vec<ipv6::Address>,
+}
+
+impl MldQuery {
+    pub fn is_general(&self) -> bool {
+        self.address == ipv6::Address::default()
+    }
+
+    /// Longest to wait before answering.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc3810#section-5.1.3
+    pub fn max_response_delay(&self) -> Duration {
+        let code = self.max_response_code as u64;
+        let millis = if code < 0x8000 {
+            code
+        } else {
+            let exponent = (code >> 12) & 0x7;
+            let mantissa = code & 0xfff;
+            (mantissa | 0x1000) << (exponent + 3)
+        };
+
+        Duration::from_millis(millis)
+    }
+}
+
+impl EncodeTo for MldQuery {
+    fn encoded_len(&self) -> usize {
+        2 + 2 + 16 + 1 + 1 + 2 + 16 * self.sources.len()
+    }
+
+    fn encode_to(&self, buf: &mut [u8]) {
+        encode_to!(
+            buf,
+            self.max_response_code,
+            0u16, // Reserved
+            self.address,
+            flags!(u8, self.suppress_router_processing => 3) | (self.robustness & 0x7),
+            self.query_interval_code,
+            self.sources.len() as u16,
+            self.sources,
+        );
+    }
+}
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc3810#section-5.2.4
 #[derive(Debug, PartialEq)]
 pub struct MldV2AddressRecord {
     pub record_type: Mldv2AddressRecordType,
     pub address: ipv6::Address,
+    pub sources: Vec<ipv6::Address>,
+    /// Must be a whole number of 32-bit words.
+    pub aux_data: Vec<u8>,
+}
+
+impl MldV2AddressRecord {
+    /// A record for `address` with no sources.
+    pub fn new(record_type: Mldv2AddressRecordType, address: ipv6::Address) -> Self {
+        Self {
+            record_type,
+            address,
+            sources: vec![],
+            aux_data: vec![],
+        }
+    }
 }
 
 impl EncodeTo for MldV2AddressRecord {
     fn encoded_len(&self) -> usize {
-        1 + 1 + 2 + 16
+        1 + 1 + 2 + 16 + 16 * self.sources.len() + self.aux_data.len()
     }
     fn encode_to(&self, buf: &mut [u8]) {
         encode_to!(
             buf,
             self.record_type,
-            0u8,
-            0u16, // Number of Sources
-            self.address
+            (self.aux_data.len() / 4) as u8,
+            self.sources.len() as u16,
+            self.address,
+            self.sources,
+            &self.aux_data[..],
         );
     }
 }
 
+// The layouts below start after the type, which selects them.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-4.3
+packet_layout! {
+    #[derive(Debug, PartialEq)]
+    pub struct NeighborSolicitation {
+        reserved 0u8 => be_u8, // Code
+        reserved 0u16 => be_u16, // Checksum
+        reserved 0u32 => be_u32,
+        pub dest: ipv6::Address => ipv6::address,
+        pub options: Vec<NeighborSolicitationOption> =>
+            terminated(many0(neighbor_solicitation_option), eof),
+    }
+}
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc4443#section-4.1
+packet_layout! {
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Echo {
+        reserved 0u8 => be_u8, // Code
+        reserved 0u16 => be_u16, // Checksum
+        pub identifier: u16 => be_u16,
+        pub sequence: u16 => be_u16,
+        pub data: Vec<u8> => map(rest, <[u8]>::to_vec),
+    }
+}
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-4.4
+packet_layout! {
+    #[derive(Debug, PartialEq)]
+    pub struct NeighborAdvertisement {
+        reserved 0u8 => be_u8, // Code
+        reserved 0u16 => be_u16, // Checksum
+        flags(u8) { pub router => 7, pub solicited => 6, pub override_flag => 5 } => be_u8,
+        reserved 0u8 => be_u8,
+        reserved 0u16 => be_u16,
+        pub src: ipv6::Address => ipv6::address,
+        pub options: Vec<NeighborSolicitationOption> =>
+            terminated(many0(neighbor_solicitation_option), eof),
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Packet {
-    RouterSolicitation,
-    NeighborSolicitation {
-        dest: ipv6::Address,
-        options: Vec<NeighborSolicitationOption>,
+    DestinationUnreachable {
+        code: u8,
+        invoking_packet: Vec<u8>,
+    },
+    TooBig {
+        mtu: u32,
+        invoking_packet: Vec<u8>,
     },
-    NeighborAdvertisement {
-        src: ipv6::Address,
+    ParameterProblem {
+        code: u8,
+        /// Offset into the invoking packet of the byte at fault.
+        pointer: u32,
+        invoking_packet: Vec<u8>,
+    },
+    EchoRequest(Echo),
+    EchoReply(Echo),
+    RouterSolicitation,
+    RouterAdvertisement {
+        cur_hop_limit: u8,
+        managed: bool,
+        other: bool,
+        router_lifetime: u16,
+        reachable_time: u32,
+        retrans_timer: u32,
         options: Vec<NeighborSolicitationOption>,
     },
+    NeighborSolicitation(NeighborSolicitation),
+    NeighborAdvertisement(NeighborAdvertisement),
+    MldQuery(MldQuery),
     MldV2Report(Vec<MldV2AddressRecord>),
 }
 
+/// Deliberate defects to introduce when encoding, for testing how other nodes validate what they
+/// receive.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-6.1
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Malformations {
+    /// Send a checksum that doesn't verify.
+    #[serde(default)]
+    pub bad_checksum: bool,
+    /// Code to send instead of the real one.
+    pub code: Option<u8>,
+    /// Length to claim for every option, in units of 8 octets; 0 is always illegal.
+    pub option_length: Option<u8>,
+}
+
 impl Packet {
-    /// Encode this packet.
-    ///
-    /// The length field in pseudo_header is ignored, and should be set to 0.
-    pub fn encode(&self, pseudo_header: PseudoHeader) -> Vec<u8> {
-        let mut buffer: Vec<u8> = match self {
-            Packet::NeighborSolicitation { dest, options } => encode!(
-                Type::NeighborSolicitation,
+    /// Offset of the first option in the encoded packet, for types that carry them.
+    fn options_offset(&self) -> Option<usize> {
+        match self {
+            Packet::RouterSolicitation => Some(8),
+            Packet::RouterAdvertisement { .. } => Some(16),
+            Packet::NeighborSolicitation(_) | Packet::NeighborAdvertisement(_) => Some(24),
+            _ => None,
+        }
+    }
+
+    fn encode_unchecksummed(&self) -> Vec<u8> {
+        match self {
+            Packet::DestinationUnreachable {
+                code,
+                invoking_packet,
+            } => encode!(
+                Type::DestinationUnreachable,
+                code,
+                0u16, // Checksum
+                0u32, // Unused
+                invoking_packet,
+            ),
+            Packet::TooBig {
+                mtu,
+                invoking_packet,
+            } => encode!(
+                Type::TooBig,
                 0u8,  // Code
                 0u16, // Checksum
-                0u32, // Reserved
-                dest,
+                mtu,
+                invoking_packet,
+            ),
+            Packet::ParameterProblem {
+                code,
+                pointer,
+                invoking_packet,
+            } => encode!(
+                Type::Problem,
+                code,
+                0u16, // Checksum
+                pointer,
+                invoking_packet,
+            ),
+            Packet::EchoRequest(message) => encode!(Type::EchoRequest, message),
+            Packet::EchoReply(message) => encode!(Type::EchoReply, message),
+            Packet::NeighborSolicitation(message) => {
+                encode!(Type::NeighborSolicitation, message)
+            }
+            Packet::NeighborAdvertisement(message) => {
+                encode!(Type::NeighborAdvertisement, message)
+            }
+            Packet::RouterAdvertisement {
+                cur_hop_limit,
+                managed,
+                other,
+                router_lifetime,
+                reachable_time,
+                retrans_timer,
+                options,
+            } => encode!(
+                Type::RouterAdvertisement,
+                0u8,  // Code
+                0u16, // Checksum
+                cur_hop_limit,
+                flags!(u8, *managed => 7, *other => 6),
+                router_lifetime,
+                reachable_time,
+                retrans_timer,
                 options,
             ),
+            Packet::MldQuery(query) => encode!(
+                Type::MldQuery,
+                0u8,  // Code
+                0u16, // Checksum
+                query,
+            ),
             Packet::MldV2Report(records) => encode!(
                 Type::MldV2Report,
                 0u8,  // Reserved
@@ -136,10 +428,20 @@ impl Packet {
                 records.len() as u16,
                 records,
             ),
-            _ => {
-                todo!("unimplemented icmpv6 option type: {:?}", self)
-            }
-        };
+            Packet::RouterSolicitation => encode!(
+                Type::RouterSolicitation,
+                0u8,  // Code
+                0u16, // Checksum
+                0u32, // Reserved
+            ),
+        }
+    }
+
+    /// Encode this packet.
+    ///
+    /// The length field in pseudo_header is ignored, and should be set to 0.
+    pub fn encode(&self, pseudo_header: PseudoHeader) -> Vec<u8> {
+        let mut buffer = self.encode_unchecksummed();
 
         let updated_pseudo_header = PseudoHeader {
             length: buffer.len() as u32,
@@ -151,137 +453,296 @@ impl Packet {
 
         buffer
     }
-}
 
-fn neighbor_solicitation_option<'a>(input: &'a [u8]) -> BIResult<'a, NeighborSolicitationOption> {
-    fn inner<'a>(input: &'a [u8]) -> BIResult<'a, NeighborSolicitationOption> {
-        let (input, option_type) = map_res(be_u8, NeighborSolicitationOptionType::try_from)(input)?;
-        let (input, length) = be_u8(input)?;
-
-        match option_type {
-            NeighborSolicitationOptionType::SourceLinkLayerAddress => {
-                let (input, address) = ether::address(input)?;
-
-                Ok((
-                    input,
-                    NeighborSolicitationOption::SourceLinkLayerAddress(address),
-                ))
-            }
-            NeighborSolicitationOptionType::TargetLinkLayerAddress => {
-                let (input, address) = ether::address(input)?;
+    /// Encode this packet with `malformations` applied, bypassing the usual guarantee that it's
+    /// valid.
+    pub fn encode_raw(
+        &self,
+        pseudo_header: PseudoHeader,
+        malformations: &Malformations,
+    ) -> Vec<u8> {
+        let mut buffer = self.encode_unchecksummed();
 
-                Ok((
-                    input,
-                    NeighborSolicitationOption::TargetLinkLayerAddress(address),
-                ))
-            }
-            NeighborSolicitationOptionType::Nonce => {
-                let (input, nonce) = take((length as usize * 8) - 2)(input)?;
+        if let Some(code) = malformations.code {
+            buffer[1] = code;
+        }
 
-                Ok((input, NeighborSolicitationOption::Nonce(nonce.to_vec())))
-            }
-            NeighborSolicitationOptionType::Unknown(t) => {
-                todo!("not yet implemented: {}", t)
+        if let (Some(option_length), Some(mut offset)) =
+            (malformations.option_length, self.options_offset())
+        {
+            // Walk the options by their real lengths, so each one's length field is found
+            while offset + 1 < buffer.len() {
+                let real_length = buffer[offset + 1] as usize * 8;
+                buffer[offset + 1] = option_length;
+                offset += real_length.max(8);
             }
         }
+
+        let checksum = packet_checksum(
+            &buffer,
+            &PseudoHeader {
+                length: buffer.len() as u32,
+                ..pseudo_header
+            },
+        );
+        // Flipping the top bit can't give the other form of zero, which would still verify.
+        let checksum = if malformations.bad_checksum {
+            checksum ^ 0x8000
+        } else {
+            checksum
+        };
+        byteorder::NetworkEndian::write_u16(&mut buffer[2..4], checksum);
+
+        buffer
     }
+}
+
+fn prefix_information<'a>(input: &'a [u8]) -> BIResult<'a, PrefixInformation> {
+    let (input, prefix_length) = be_u8(input)?;
+    let (input, flags) = be_u8(input)?;
+    let (input, valid_lifetime) = be_u32(input)?;
+    let (input, preferred_lifetime) = be_u32(input)?;
+    // ignore reserved
+    let (input, _) = be_u32(input)?;
+    let (input, prefix) = ipv6::address(input)?;
 
-    let (input, (option_bytes, option)) = consumed(inner)(input)?;
+    Ok((
+        input,
+        PrefixInformation {
+            prefix_length,
+            on_link: flag(flags, 7),
+            autonomous: flag(flags, 6),
+            valid_lifetime,
+            preferred_lifetime,
+            prefix,
+        },
+    ))
+}
+
+fn dns_search_list<'a>(input: &'a [u8]) -> BIResult<'a, Vec<String>> {
+    // The list ends with zero padding, which would otherwise read as empty names
+    terminated(
+        many0(verify(dns::name, |name: &str| !name.is_empty())),
+        verify(rest, |padding: &[u8]| padding.iter().all(|b| *b == 0)),
+    )(input)
+}
+
+fn recursive_dns_servers<'a>(input: &'a [u8]) -> BIResult<'a, NeighborSolicitationOption> {
+    // ignore reserved
+    let (input, _) = be_u16(input)?;
+    let (input, lifetime) = be_u32(input)?;
+    let (input, servers) = terminated(
+        verify(many0(ipv6::address), |servers: &[_]| !servers.is_empty()),
+        eof,
+    )(input)?;
+
+    Ok((
+        input,
+        NeighborSolicitationOption::RecursiveDnsServers { lifetime, servers },
+    ))
+}
 
-    let input = if option_bytes.len() % 8 == 0 {
-        input
-    } else {
-        let needed_padding = 8 - option_bytes.len() % 8;
-        &input[needed_padding..]
+fn dns_search_list_option<'a>(input: &'a [u8]) -> BIResult<'a, NeighborSolicitationOption> {
+    // ignore reserved
+    let (input, _) = be_u16(input)?;
+    let (input, lifetime) = be_u32(input)?;
+    let (input, domains) = verify(dns_search_list, |domains: &[_]| !domains.is_empty())(input)?;
+
+    Ok((
+        input,
+        NeighborSolicitationOption::DnsSearchList { lifetime, domains },
+    ))
+}
+
+fn neighbor_solicitation_option<'a>(input: &'a [u8]) -> BIResult<'a, NeighborSolicitationOption> {
+    let (input, (option_type, body)) = Tlv::NDP.parse(input)?;
+    let option_type = NeighborSolicitationOptionType::try_from(option_type as u8).unwrap();
+
+    let option = match option_type {
+        NeighborSolicitationOptionType::SourceLinkLayerAddress => {
+            NeighborSolicitationOption::SourceLinkLayerAddress(ether::address(body)?.1)
+        }
+        NeighborSolicitationOptionType::TargetLinkLayerAddress => {
+            NeighborSolicitationOption::TargetLinkLayerAddress(ether::address(body)?.1)
+        }
+        NeighborSolicitationOptionType::PrefixInformation => {
+            NeighborSolicitationOption::PrefixInformation(prefix_information(body)?.1)
+        }
+        NeighborSolicitationOptionType::Mtu => {
+            // ignore reserved
+            let (body, _) = be_u16(body)?;
+            NeighborSolicitationOption::Mtu(be_u32(body)?.1)
+        }
+        NeighborSolicitationOptionType::Nonce => NeighborSolicitationOption::Nonce(body.to_vec()),
+        // Invalid DNS options are ignored on their own, keeping the rest of the advertisement.
+        // Ref: https://datatracker.ietf.org/doc/html/rfc8106#section-5.3.1
+        NeighborSolicitationOptionType::RecursiveDnsServers => recursive_dns_servers(body)
+            .map(|(_, option)| option)
+            .unwrap_or_else(|_| {
+                NeighborSolicitationOption::Unknown(option_type.into(), body.to_vec())
+            }),
+        NeighborSolicitationOptionType::DnsSearchList => dns_search_list_option(body)
+            .map(|(_, option)| option)
+            .unwrap_or_else(|_| {
+                NeighborSolicitationOption::Unknown(option_type.into(), body.to_vec())
+            }),
+        NeighborSolicitationOptionType::Unknown(t) => {
+            NeighborSolicitationOption::Unknown(t, body.to_vec())
+        }
     };
 
     Ok((input, option))
 }
 
-fn neighbor_solicitation_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
-    // ignore code, checksum, and reserved
-    let input = &input[7..];
-    let (input, dest) = ipv6::address(input)?;
+fn destination_unreachable_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
+    let (input, code) = be_u8(input)?;
+    // ignore checksum and unused
+    let (input, _) = take(6usize)(input)?;
+    let (input, invoking_packet) = rest(input)?;
 
-    let (input, options) = terminated(many0(neighbor_solicitation_option), eof)(input)?;
+    Ok((
+        input,
+        Packet::DestinationUnreachable {
+            code,
+            invoking_packet: invoking_packet.to_vec(),
+        },
+    ))
+}
 
-    let (input, _) = eof(input)?;
+fn parameter_problem_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
+    let (input, code) = be_u8(input)?;
+    // ignore checksum
+    let (input, _) = take(2usize)(input)?;
+    let (input, pointer) = be_u32(input)?;
+    let (input, invoking_packet) = rest(input)?;
 
-    Ok((input, Packet::NeighborSolicitation { dest, options }))
+    Ok((
+        input,
+        Packet::ParameterProblem {
+            code,
+            pointer,
+            invoking_packet: invoking_packet.to_vec(),
+        },
+    ))
 }
 
-fn neighbor_advertisement_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
-    // ignore code, checksum, and reserved
-    let input = &input[7..];
+fn too_big_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
+    // ignore code and checksum
+    let (input, _) = take(3usize)(input)?;
+    let (input, mtu) = be_u32(input)?;
+    let (input, invoking_packet) = rest(input)?;
+
+    Ok((
+        input,
+        Packet::TooBig {
+            mtu,
+            invoking_packet: invoking_packet.to_vec(),
+        },
+    ))
+}
 
-    let (input, src) = ipv6::address(input)?;
+fn router_advertisement_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
+    // ignore code and checksum
+    let (input, _) = take(3usize)(input)?;
+    let (input, cur_hop_limit) = be_u8(input)?;
+    let (input, flags) = be_u8(input)?;
+    let (input, router_lifetime) = be_u16(input)?;
+    let (input, reachable_time) = be_u32(input)?;
+    let (input, retrans_timer) = be_u32(input)?;
 
     let (input, options) = terminated(many0(neighbor_solicitation_option), eof)(input)?;
 
-    let (input, _) = eof(input)?;
+    Ok((
+        input,
+        Packet::RouterAdvertisement {
+            cur_hop_limit,
+            managed: flag(flags, 7),
+            other: flag(flags, 6),
+            router_lifetime,
+            reachable_time,
+            retrans_timer,
+            options,
+        },
+    ))
+}
+
+fn neighbor_solicitation_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
+    map(NeighborSolicitation::parse, Packet::NeighborSolicitation)(input)
+}
 
-    Ok((input, Packet::NeighborAdvertisement { src, options }))
+fn neighbor_advertisement_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
+    map(NeighborAdvertisement::parse, Packet::NeighborAdvertisement)(input)
 }
 
 fn mld_v2_address_record<'a>(input: &'a [u8]) -> BIResult<'a, MldV2AddressRecord> {
     let (input, record_type) = map_res(be_u8, Mldv2AddressRecordType::try_from)(input)?;
-
-    // TODO: Aux Data Len, Number of Sources
-    let input = &input[3..];
-
+    // In 32-bit words
+    let (input, aux_data_len) = be_u8(input)?;
+    let (input, source_count) = be_u16(input)?;
     let (input, address) = ipv6::address(input)?;
+    let (input, sources) = limits::count(ipv6::address, source_count as usize, 16)(input)?;
+    let (input, aux_data) = take(aux_data_len as usize * 4)(input)?;
 
     Ok((
         input,
         MldV2AddressRecord {
             record_type,
             address,
+            sources,
+            aux_data: aux_data.to_vec(),
         },
     ))
 }
 
-fn mld_v2_report_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
-    // ignore code, checksum, and number of records
-    let input = &input[7..];
+fn mld_query_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
+    // ignore code and checksum
+    let (input, _) = take(3usize)(input)?;
+    let (input, max_response_code) = be_u16(input)?;
+    // ignore reserved
+    let (input, _) = be_u16(input)?;
+    let (input, address) = ipv6::address(input)?;
 
-    let (input, records) = terminated(many0(mld_v2_address_record), eof)(input)?;
+    let mut query = MldQuery {
+        max_response_code,
+        address,
+        suppress_router_processing: false,
+        robustness: 0,
+        query_interval_code: 0,
+        sources: vec![],
+    };
+    // MLDv1 queries end here.
+    if input.is_empty() {
+        return Ok((input, Packet::MldQuery(query)));
+    }
 
-    let (input, _) = eof(input)?;
+    let (input, flags) = be_u8(input)?;
+    let (input, query_interval_code) = be_u8(input)?;
+    let (input, source_count) = be_u16(input)?;
+    let (input, sources) = limits::count(ipv6::address, source_count as usize, 16)(input)?;
 
-    Ok((input, Packet::MldV2Report(records)))
-}
+    query.suppress_router_processing = flag(flags, 3);
+    query.robustness = flags & 0x7;
+    query.query_interval_code = query_interval_code;
+    query.sources = sources;
 
-pub struct PseudoHeader {
-    pub src: ipv6::Address,
-    pub dest: ipv6::Address,
-    pub length: u32,
+    Ok((input, Packet::MldQuery(query)))
 }
 
-fn packet_checksum(input: &[u8], pseudo_header: &PseudoHeader) -> u16 {
-    // RFC 8200 § 8.1
-    let checksummed_buffer = encode!(
-        pseudo_header.src,
-        pseudo_header.dest,
-        pseudo_header.length,
-        0u16,
-        0u8,
-        ipv4::ProtocolNumber::Ipv6Icmp,
-        input,
-    );
-
-    // RFC 4333 § 2.3
-    let mut checksum = 0u32;
+fn mld_v2_report_packet<'a>(input: &'a [u8]) -> BIResult<'a, Packet> {
+    // ignore code, checksum, and reserved
+    let (input, _) = take(5usize)(input)?;
+    let (input, record_count) = be_u16(input)?;
 
-    for i in (0..checksummed_buffer.len()).step_by(2) {
-        checksum += (checksummed_buffer[i] as u32) << 8 | (checksummed_buffer[i + 1] as u32);
-    }
+    let (input, records) = terminated(
+        limits::count(mld_v2_address_record, record_count as usize, 20),
+        eof,
+    )(input)?;
 
-    // Fold in carry repeatedly until nothing is left
-    while checksum > 0xffff {
-        checksum = (checksum & 0xffff) + (checksum >> 16);
-    }
+    Ok((input, Packet::MldV2Report(records)))
+}
 
-    !(checksum as u16)
+fn packet_checksum(input: &[u8], pseudo_header: &PseudoHeader) -> u16 {
+    pseudo_header.checksum(ipv4::ProtocolNumber::Ipv6Icmp, input)
 }
 
 pub fn packet(input: &[u8], pseudo_header: PseudoHeader) -> AHResult<Packet> {
@@ -292,17 +753,28 @@ pub fn packet(input: &[u8], pseudo_header: PseudoHeader) -> AHResult<Packet> {
     }
 
     try_parse!(
+        input,
         {
             let (input, packet_type) = map_res(be_u8, Type::try_from)(input)?;
 
             use Type::*;
             let (input, packet) = match packet_type {
+                DestinationUnreachable => destination_unreachable_packet(input)?,
+                TooBig => too_big_packet(input)?,
+                Problem => parameter_problem_packet(input)?,
+                EchoRequest => map(Echo::parse, Packet::EchoRequest)(input)?,
+                EchoReply => map(Echo::parse, Packet::EchoReply)(input)?,
                 RouterSolicitation => (input, Packet::RouterSolicitation),
+                RouterAdvertisement => router_advertisement_packet(input)?,
                 NeighborSolicitation => neighbor_solicitation_packet(input)?,
                 NeighborAdvertisement => neighbor_advertisement_packet(input)?,
+                MldQuery => mld_query_packet(input)?,
                 MldV2Report => mld_v2_report_packet(input)?,
                 _ => {
-                    todo!("not yet implemented: {:?}", packet_type)
+                    return Err(nom::Err::Failure(nom::error::Error::new(
+                        input,
+                        nom::error::ErrorKind::Switch,
+                    )))
                 }
             };
 
@@ -315,6 +787,7 @@ pub fn packet(input: &[u8], pseudo_header: PseudoHeader) -> AHResult<Packet> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     fn hexstring(s: &str) -> Vec<u8> {
         hex::decode(s).unwrap()
@@ -336,6 +809,152 @@ mod tests {
         );
     }
 
+    /// Parse a message of `packet_type` cut off partway through its checksum, with the other
+    /// bytes picked so what's there still verifies.
+    fn packet_too_short_for_its_checksum(packet_type: Type) -> AHResult<Packet> {
+        let pseudo_header = PseudoHeader {
+            src: "fe80::1".parse().unwrap(),
+            dest: "ff02::1".parse().unwrap(),
+            length: 3,
+        };
+        let message = (0..=0xffffu16)
+            .map(|filler| {
+                let [code, checksum] = filler.to_be_bytes();
+                vec![u8::from(packet_type), code, checksum]
+            })
+            .find(|message| packet_checksum(message, &pseudo_header) == 0)
+            .unwrap();
+
+        packet(&message, pseudo_header)
+    }
+
+    #[test]
+    fn truncated_router_advertisement_fails_to_decode() {
+        assert!(packet_too_short_for_its_checksum(Type::RouterAdvertisement).is_err());
+    }
+
+    #[test]
+    fn unsupported_types_fail_to_decode() {
+        assert!(packet_too_short_for_its_checksum(Type::Exceeded).is_err());
+        assert!(packet_too_short_for_its_checksum(Type::Unknown(200)).is_err());
+    }
+
+    #[test]
+    fn truncated_too_big_fails_to_decode() {
+        assert!(packet_too_short_for_its_checksum(Type::TooBig).is_err());
+    }
+
+    #[test]
+    fn too_big_packet_round_trips() {
+        let encoded = hexstring("020033c10000057860000000000811402001db800000000000000000000000022001db80000000000000000000000099");
+        let pseudo_header = PseudoHeader {
+            src: "2001:db8::1".parse().unwrap(),
+            dest: "2001:db8::2".parse().unwrap(),
+            length: 48,
+        };
+        let too_big = Packet::TooBig {
+            mtu: 1400,
+            invoking_packet: hexstring(
+                "60000000000811402001db800000000000000000000000022001db80000000000000000000000099",
+            ),
+        };
+
+        assert_eq!(packet(&encoded, pseudo_header).unwrap(), too_big);
+        assert_eq!(too_big.encode(pseudo_header), encoded);
+    }
+
+    #[test]
+    fn echo_request_round_trips() {
+        let pseudo_header = PseudoHeader {
+            src: "2001:db8::1".parse().unwrap(),
+            dest: "2001:db8::2".parse().unwrap(),
+            length: 0,
+        };
+        let request = Packet::EchoRequest(Echo {
+            identifier: 0x1234,
+            sequence: 7,
+            data: b"fakenet".to_vec(),
+        });
+
+        let encoded = request.encode(pseudo_header);
+        assert_eq!(hex::encode(&encoded[4..]), "1234000766616b656e6574");
+        assert_eq!(
+            packet(
+                &encoded,
+                PseudoHeader {
+                    length: encoded.len() as u32,
+                    ..pseudo_header
+                }
+            )
+            .unwrap(),
+            request
+        );
+    }
+
+    #[test]
+    fn router_advertisement_packet_decodes() {
+        assert_eq!(
+            packet(
+                &hexstring("86001ea24000070800000000000000000101020000000001030440c000278d0000093a8000000000fd00000000000000000000000000000005010000000005dc"),
+                PseudoHeader {
+                    src: "fe80::1".parse().unwrap(),
+                    dest: "ff02::1".parse().unwrap(),
+                    length: 64
+                }
+            )
+            .unwrap(),
+            Packet::RouterAdvertisement {
+                cur_hop_limit: 64,
+                managed: false,
+                other: false,
+                router_lifetime: 1800,
+                reachable_time: 0,
+                retrans_timer: 0,
+                options: vec![
+                    NeighborSolicitationOption::SourceLinkLayerAddress(ether::Address([
+                        0x02, 0, 0, 0, 0, 0x01
+                    ])),
+                    NeighborSolicitationOption::PrefixInformation(PrefixInformation {
+                        prefix_length: 64,
+                        on_link: true,
+                        autonomous: true,
+                        valid_lifetime: 2592000,
+                        preferred_lifetime: 604800,
+                        prefix: "fd00::".parse().unwrap(),
+                    }),
+                    NeighborSolicitationOption::Mtu(1500),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn router_advertisement_with_unknown_option_decodes() {
+        assert_eq!(
+            packet(
+                &hexstring("8600c6f94000070800000000000000006303000000000e10fd000000000000000000000000000001"),
+                PseudoHeader {
+                    src: "fe80::1".parse().unwrap(),
+                    dest: "ff02::1".parse().unwrap(),
+                    length: 40
+                }
+            )
+            .unwrap(),
+            Packet::RouterAdvertisement {
+                cur_hop_limit: 64,
+                managed: false,
+                other: false,
+                router_lifetime: 1800,
+                reachable_time: 0,
+                retrans_timer: 0,
+                options: vec![NeighborSolicitationOption::Unknown(
+                    99,
+                    hexstring("000000000e10fd000000000000000000000000000001")
+                )],
+            }
+        );
+    }
+
     #[test]
     fn neighbor_solicitation_packet_decodes() {
         assert_eq!(
@@ -348,12 +967,12 @@ mod tests {
                 }
             )
             .unwrap(),
-            Packet::NeighborSolicitation {
+            Packet::NeighborSolicitation(NeighborSolicitation {
                 dest: "f4:44::12".parse().unwrap(),
                 options: vec![NeighborSolicitationOption::SourceLinkLayerAddress(
                     ether::Address([0x56, 0x0d, 0x4f, 0x21, 0x64, 0xf3]),
                 ),],
-            },
+            }),
         );
     }
 
@@ -369,10 +988,10 @@ mod tests {
                 }
             )
             .unwrap(),
-            Packet::NeighborSolicitation {
+            Packet::NeighborSolicitation(NeighborSolicitation {
                 dest: "fe80::396d:f664:97e1:64f3".parse().unwrap(),
                 options: vec![NeighborSolicitationOption::Nonce(hexstring("d8d14717f0a0"))],
-            },
+            }),
         );
     }
 
@@ -388,12 +1007,15 @@ mod tests {
                 }
             )
             .unwrap(),
-            Packet::NeighborAdvertisement {
+            Packet::NeighborAdvertisement(NeighborAdvertisement {
                 src: "fd00:736f:746f:686e::1".parse().unwrap(),
+                router: true,
+                solicited: true,
+                override_flag: true,
                 options: vec![NeighborSolicitationOption::TargetLinkLayerAddress(
                     ether::Address([0x16, 0x91, 0x82, 0x2a, 0x80, 0x3b]),
                 ),],
-            }
+            })
         );
     }
 
@@ -411,16 +1033,132 @@ mod tests {
             .unwrap(),
             Packet::MldV2Report(
                 vec![
-                    MldV2AddressRecord {
-                        record_type: Mldv2AddressRecordType::ChangeToExcludeMode,
-                        address: "ff05::1:3".parse().unwrap(),
-                    },
-                    MldV2AddressRecord {
-                        record_type: Mldv2AddressRecordType::ChangeToExcludeMode,
-                        address: "ff02::1:2".parse().unwrap(),
-                    },
+                    MldV2AddressRecord::new(Mldv2AddressRecordType::ChangeToExcludeMode, "ff05::1:3".parse().unwrap()),
+                    MldV2AddressRecord::new(Mldv2AddressRecordType::ChangeToExcludeMode, "ff02::1:2".parse().unwrap()),
+                ],
+            ),
+        );
+    }
+
+    fn query_for(address: &str) -> MldQuery {
+        MldQuery {
+            max_response_code: 0,
+            address: address.parse().unwrap(),
+            suppress_router_processing: false,
+            robustness: 0,
+            query_interval_code: 0,
+            sources: vec![],
+        }
+    }
+
+    #[test]
+    fn multicast_listener_queries_round_trip() {
+        let general = MldQuery {
+            max_response_code: 10000,
+            address: "::".parse().unwrap(),
+            suppress_router_processing: false,
+            robustness: 2,
+            query_interval_code: 125,
+            sources: vec![],
+        };
+        assert!(general.is_general());
+        assert_eq!(general.max_response_delay(), Duration::from_secs(10));
+        round_trip(Packet::MldQuery(general));
+
+        round_trip(Packet::MldQuery(MldQuery {
+            max_response_code: 0x8000,
+            address: "ff3e::8000:1".parse().unwrap(),
+            suppress_router_processing: true,
+            robustness: 7,
+            query_interval_code: 0,
+            sources: vec!["2001:db8::1".parse().unwrap()],
+        }));
+    }
+
+    #[test]
+    fn long_max_response_codes_are_exponential() {
+        let query = |max_response_code| MldQuery {
+            max_response_code,
+            ..query_for("::")
+        };
+
+        assert_eq!(
+            query(0x7fff).max_response_delay(),
+            Duration::from_millis(0x7fff)
+        );
+        assert_eq!(
+            query(0x8000).max_response_delay(),
+            Duration::from_millis(0x8000)
+        );
+        assert_eq!(
+            query(0xffff).max_response_delay(),
+            Duration::from_millis(0x1fff << 10)
+        );
+    }
+
+    #[test]
+    fn mldv1_queries_decode() {
+        let pseudo_header = PseudoHeader {
+            src: "fe80::1".parse().unwrap(),
+            dest: "ff02::1".parse().unwrap(),
+            length: 24,
+        };
+        let mut encoded = Packet::MldQuery(MldQuery {
+            max_response_code: 1000,
+            ..query_for("ff02::1:ff00:2")
+        })
+        .encode(pseudo_header);
+        encoded.truncate(24);
+        encoded[2..4].fill(0);
+        let checksum = packet_checksum(&encoded, &pseudo_header);
+        byteorder::NetworkEndian::write_u16(&mut encoded[2..4], checksum);
+
+        assert_eq!(
+            packet(&encoded, pseudo_header).unwrap(),
+            Packet::MldQuery(MldQuery {
+                max_response_code: 1000,
+                ..query_for("ff02::1:ff00:2")
+            })
+        );
+    }
+
+    #[test]
+    fn multicast_listener_records_with_sources_and_aux_data_decode() {
+        let report = Packet::MldV2Report(vec![
+            MldV2AddressRecord {
+                record_type: Mldv2AddressRecordType::CodeIsInclude,
+                address: "ff3e::8000:1".parse().unwrap(),
+                sources: vec![
+                    "2001:db8::1".parse().unwrap(),
+                    "2001:db8::2".parse().unwrap(),
                 ],
+                aux_data: vec![1, 2, 3, 4],
+            },
+            MldV2AddressRecord::new(
+                Mldv2AddressRecordType::ChangeToExcludeMode,
+                "ff02::1:2".parse().unwrap(),
             ),
+        ]);
+        let pseudo_header = PseudoHeader {
+            src: "fe80::1".parse().unwrap(),
+            dest: "ff02::16".parse().unwrap(),
+            length: 0,
+        };
+        let encoded = report.encode(pseudo_header);
+
+        // The second record starts after both sources and the aux data.
+        assert_eq!(encoded.len(), 8 + (20 + 32 + 4) + 20);
+        assert_eq!(hex::encode(&encoded[8..12]), "01010002");
+        assert_eq!(
+            packet(
+                &encoded,
+                PseudoHeader {
+                    length: encoded.len() as u32,
+                    ..pseudo_header
+                }
+            )
+            .unwrap(),
+            report
         );
     }
 
@@ -441,10 +1179,10 @@ mod tests {
     #[test]
     fn neighbor_solicitation_packet_with_nonce_encodes() {
         assert_eq!(
-            Packet::NeighborSolicitation {
+            Packet::NeighborSolicitation(NeighborSolicitation {
                 dest: "fe80::396d:f664:97e1:64f3".parse().unwrap(),
                 options: vec![NeighborSolicitationOption::Nonce(hexstring("d8d14717f0a0")),],
-            }
+            })
             .encode(PseudoHeader {
                 dest: "fe80::396d:f664:97e1:64f3".parse().unwrap(),
                 src: "::".parse().unwrap(),
@@ -459,14 +1197,8 @@ mod tests {
         assert_eq!(
             Packet::MldV2Report(
                 vec![
-                    MldV2AddressRecord {
-                        record_type: Mldv2AddressRecordType::ChangeToExcludeMode,
-                        address: "ff05::1:3".parse().unwrap(),
-                    },
-                    MldV2AddressRecord {
-                        record_type: Mldv2AddressRecordType::ChangeToExcludeMode,
-                        address: "ff02::1:2".parse().unwrap(),
-                    },
+                    MldV2AddressRecord::new(Mldv2AddressRecordType::ChangeToExcludeMode, "ff05::1:3".parse().unwrap()),
+                    MldV2AddressRecord::new(Mldv2AddressRecordType::ChangeToExcludeMode, "ff02::1:2".parse().unwrap()),
                 ],
             ).encode(
                 PseudoHeader {
@@ -479,4 +1211,382 @@ mod tests {
             hexstring("8f002b5a0000000204000000ff05000000000000000000000001000304000000ff020000000000000000000000010002"),
         );
     }
+
+    fn round_trip(packet_in: Packet) {
+        let pseudo_header = PseudoHeader {
+            src: "fe80::1".parse().unwrap(),
+            dest: "ff02::1".parse().unwrap(),
+            length: 0,
+        };
+        let encoded = packet_in.encode(pseudo_header);
+
+        assert_eq!(
+            packet(
+                &encoded,
+                PseudoHeader {
+                    length: encoded.len() as u32,
+                    ..pseudo_header
+                }
+            )
+            .unwrap(),
+            packet_in
+        );
+    }
+
+    #[test]
+    fn neighbor_advertisement_packet_round_trips() {
+        round_trip(Packet::NeighborAdvertisement(NeighborAdvertisement {
+            src: "fd00::1".parse().unwrap(),
+            router: false,
+            solicited: false,
+            override_flag: true,
+            options: vec![],
+        }));
+    }
+
+    #[test]
+    fn router_advertisement_packet_round_trips() {
+        round_trip(Packet::RouterAdvertisement {
+            cur_hop_limit: 64,
+            managed: false,
+            other: true,
+            router_lifetime: 1800,
+            reachable_time: 0,
+            retrans_timer: 0,
+            options: vec![
+                NeighborSolicitationOption::PrefixInformation(PrefixInformation {
+                    prefix_length: 64,
+                    on_link: true,
+                    autonomous: true,
+                    valid_lifetime: 86400,
+                    preferred_lifetime: 14400,
+                    prefix: "2001:db8::".parse().unwrap(),
+                }),
+                NeighborSolicitationOption::Mtu(1400),
+            ],
+        });
+    }
+
+    #[test]
+    fn router_advertisement_dns_options_decode() {
+        assert_eq!(
+            neighbor_solicitation_option(&hexstring(
+                "1903000000000e1020010db8000000000000000000000053"
+            ))
+            .unwrap()
+            .1,
+            NeighborSolicitationOption::RecursiveDnsServers {
+                lifetime: 3600,
+                servers: vec!["2001:db8::53".parse().unwrap()],
+            }
+        );
+        assert_eq!(
+            neighbor_solicitation_option(&hexstring(
+                "1f04000000000e10036c6162076578616d706c65036e65740000000000000000"
+            ))
+            .unwrap()
+            .1,
+            NeighborSolicitationOption::DnsSearchList {
+                lifetime: 3600,
+                domains: vec!["lab.example.net".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_dns_options_are_skipped() {
+        let input = hexstring(concat!(
+            // RDNSS without any servers
+            "190100000000ffff",
+            // DNSSL without any domains
+            "1f01000000000e10",
+            // DNSSL with a 64-octet label
+            "1f02000000000e10406c616200000000",
+            "0501000000000578",
+        ));
+        let (_, options) = many0(neighbor_solicitation_option)(&input).unwrap();
+
+        assert_eq!(
+            options,
+            vec![
+                NeighborSolicitationOption::Unknown(25, hexstring("00000000ffff")),
+                NeighborSolicitationOption::Unknown(31, hexstring("000000000e10")),
+                NeighborSolicitationOption::Unknown(31, hexstring("000000000e10406c616200000000")),
+                NeighborSolicitationOption::Mtu(1400),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "dns label too long")]
+    fn over_long_search_domain_labels_are_not_truncated() {
+        encode!(NeighborSolicitationOption::DnsSearchList {
+            lifetime: 3600,
+            domains: vec![format!("{}.example.net", "a".repeat(64))],
+        });
+    }
+
+    #[test]
+    fn malformations_are_applied() {
+        let pseudo_header = PseudoHeader {
+            src: "fe80::1".parse().unwrap(),
+            dest: "ff02::1:ff00:2".parse().unwrap(),
+            length: 0,
+        };
+        let solicitation = Packet::NeighborSolicitation(NeighborSolicitation {
+            dest: "fe80::2".parse().unwrap(),
+            options: vec![NeighborSolicitationOption::Nonce(vec![1; 6])],
+        });
+
+        assert_eq!(
+            solicitation.encode_raw(pseudo_header, &Malformations::default()),
+            solicitation.encode(pseudo_header)
+        );
+
+        let encoded = solicitation.encode_raw(
+            pseudo_header,
+            &Malformations {
+                bad_checksum: false,
+                code: Some(1),
+                option_length: Some(0),
+            },
+        );
+        assert_eq!(encoded[1], 1);
+        assert_eq!(encoded[25], 0);
+
+        let with_length = PseudoHeader {
+            length: encoded.len() as u32,
+            ..pseudo_header
+        };
+        assert_eq!(packet_checksum(&encoded, &with_length), 0);
+
+        let encoded = solicitation.encode_raw(
+            pseudo_header,
+            &Malformations {
+                bad_checksum: true,
+                ..Default::default()
+            },
+        );
+        assert!(packet(&encoded, with_length).is_err());
+    }
+
+    #[test]
+    fn destination_unreachable_packet_round_trips() {
+        round_trip(Packet::DestinationUnreachable {
+            code: UNREACHABLE_PORT,
+            invoking_packet: vec![0x60, 0, 0, 0],
+        });
+    }
+
+    #[test]
+    fn parameter_problem_packet_round_trips() {
+        round_trip(Packet::ParameterProblem {
+            code: PROBLEM_UNRECOGNIZED_OPTION,
+            pointer: 42,
+            invoking_packet: vec![0x60, 0, 0, 0],
+        });
+    }
+
+    #[test]
+    fn link_layer_address_options_encode() {
+        let address = ether::Address([0x16, 0x91, 0x82, 0x2a, 0x80, 0x3b]);
+
+        assert_eq!(
+            hex::encode(encode!(NeighborSolicitationOption::SourceLinkLayerAddress(
+                address
+            ))),
+            "01011691822a803b"
+        );
+        round_trip(Packet::NeighborAdvertisement(NeighborAdvertisement {
+            src: "fd00:736f:746f:686e::1".parse().unwrap(),
+            router: true,
+            solicited: true,
+            override_flag: true,
+            options: vec![NeighborSolicitationOption::TargetLinkLayerAddress(address)],
+        }));
+        round_trip(Packet::NeighborSolicitation(NeighborSolicitation {
+            dest: "fe80::2".parse().unwrap(),
+            options: vec![
+                NeighborSolicitationOption::SourceLinkLayerAddress(address),
+                NeighborSolicitationOption::Unknown(200, vec![0; 14]),
+            ],
+        }));
+    }
+
+    #[test]
+    fn odd_length_nonces_are_padded_to_whole_units() {
+        let encoded = encode!(NeighborSolicitationOption::Nonce(vec![1, 2, 3, 4]));
+
+        assert_eq!(hex::encode(&encoded), "0e01010203040000");
+    }
+
+    fn arb_address() -> impl Strategy<Value = ipv6::Address> {
+        any::<[u16; 8]>().prop_map(ipv6::Address)
+    }
+
+    fn arb_option() -> impl Strategy<Value = NeighborSolicitationOption> {
+        prop_oneof![
+            any::<[u8; 6]>().prop_map(|a| NeighborSolicitationOption::SourceLinkLayerAddress(
+                ether::Address(a)
+            )),
+            any::<[u8; 6]>().prop_map(|a| NeighborSolicitationOption::TargetLinkLayerAddress(
+                ether::Address(a)
+            )),
+            (
+                any::<u8>(),
+                any::<bool>(),
+                any::<bool>(),
+                any::<u32>(),
+                any::<u32>(),
+                arb_address(),
+            )
+                .prop_map(
+                    |(prefix_length, on_link, autonomous, valid, preferred, prefix)| {
+                        NeighborSolicitationOption::PrefixInformation(PrefixInformation {
+                            prefix_length,
+                            on_link,
+                            autonomous,
+                            valid_lifetime: valid,
+                            preferred_lifetime: preferred,
+                            prefix,
+                        })
+                    }
+                ),
+            any::<u32>().prop_map(NeighborSolicitationOption::Mtu),
+            (any::<u32>(), prop::collection::vec(arb_address(), 1..4)).prop_map(
+                |(lifetime, servers)| NeighborSolicitationOption::RecursiveDnsServers {
+                    lifetime,
+                    servers
+                }
+            ),
+            (
+                any::<u32>(),
+                prop::collection::vec("[a-z0-9-]{1,12}(\\.[a-z0-9-]{1,12}){0,3}", 1..4)
+            )
+                .prop_map(|(lifetime, domains)| {
+                    NeighborSolicitationOption::DnsSearchList { lifetime, domains }
+                }),
+            // Parsed nonces keep their padding, so only lengths that fill whole 8-octet units round
+            // trip
+            (0..4usize)
+                .prop_flat_map(|units| prop::collection::vec(any::<u8>(), units * 8 + 6))
+                .prop_map(NeighborSolicitationOption::Nonce),
+        ]
+    }
+
+    fn arb_options() -> impl Strategy<Value = Vec<NeighborSolicitationOption>> {
+        prop::collection::vec(arb_option(), 0..4)
+    }
+
+    fn arb_packet() -> impl Strategy<Value = Packet> {
+        prop_oneof![
+            (any::<u8>(), prop::collection::vec(any::<u8>(), 0..128)).prop_map(
+                |(code, invoking_packet)| Packet::DestinationUnreachable {
+                    code,
+                    invoking_packet,
+                }
+            ),
+            (any::<u32>(), prop::collection::vec(any::<u8>(), 0..128)).prop_map(
+                |(mtu, invoking_packet)| Packet::TooBig {
+                    mtu,
+                    invoking_packet,
+                }
+            ),
+            (
+                any::<u8>(),
+                any::<u32>(),
+                prop::collection::vec(any::<u8>(), 0..128)
+            )
+                .prop_map(|(code, pointer, invoking_packet)| {
+                    Packet::ParameterProblem {
+                        code,
+                        pointer,
+                        invoking_packet,
+                    }
+                }),
+            (
+                any::<u8>(),
+                any::<(bool, bool)>(),
+                any::<u16>(),
+                any::<u32>(),
+                any::<u32>(),
+                arb_options(),
+            )
+                .prop_map(
+                    |(
+                        cur_hop_limit,
+                        (managed, other),
+                        router_lifetime,
+                        reachable_time,
+                        retrans_timer,
+                        options,
+                    )| {
+                        Packet::RouterAdvertisement {
+                            cur_hop_limit,
+                            managed,
+                            other,
+                            router_lifetime,
+                            reachable_time,
+                            retrans_timer,
+                            options,
+                        }
+                    }
+                ),
+            (arb_address(), arb_options()).prop_map(|(dest, options)| {
+                Packet::NeighborSolicitation(NeighborSolicitation { dest, options })
+            }),
+            (arb_address(), any::<(bool, bool, bool)>(), arb_options()).prop_map(
+                |(src, (router, solicited, override_flag), options)| {
+                    Packet::NeighborAdvertisement(NeighborAdvertisement {
+                        src,
+                        router,
+                        solicited,
+                        override_flag,
+                        options,
+                    })
+                }
+            ),
+            prop::collection::vec(
+                (
+                    (1u8..=6),
+                    arb_address(),
+                    prop::collection::vec(arb_address(), 0..4),
+                    (0..3usize)
+                        .prop_flat_map(|words| prop::collection::vec(any::<u8>(), words * 4)),
+                )
+                    .prop_map(|(record_type, address, sources, aux_data)| {
+                        MldV2AddressRecord {
+                            record_type: Mldv2AddressRecordType::try_from(record_type).unwrap(),
+                            address,
+                            sources,
+                            aux_data,
+                        }
+                    }),
+                0..8,
+            )
+            .prop_map(Packet::MldV2Report),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_option_encodes_to_its_length(option in arb_option()) {
+            let encoded = encode!(option);
+
+            prop_assert_eq!(encoded.len() % 8, 0);
+            prop_assert_eq!(neighbor_solicitation_option(&encoded), Ok((&b""[..], option)));
+        }
+
+        #[test]
+        fn arbitrary_packet_round_trips(
+            packet_in in arb_packet(),
+            src in arb_address(),
+            dest in arb_address(),
+        ) {
+            let encoded = packet_in.encode(PseudoHeader { src, dest, length: 0 });
+            let pseudo_header = PseudoHeader { src, dest, length: encoded.len() as u32 };
+
+            prop_assert_eq!(packet(&encoded, pseudo_header).unwrap(), packet_in);
+        }
+    }
 }
@@ -0,0 +1,258 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+
+use super::Address;
+use crate::status;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc4862#section-2
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum InterfaceAddressState {
+    New,
+    Tentative,
+    Valid,
+    Deprecated,
+    Invalid,
+}
+
+/// Preferred and valid lifetimes for an address; `None` means infinite.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Lifetimes {
+    pub preferred: Option<Duration>,
+    pub valid: Option<Duration>,
+}
+
+impl Lifetimes {
+    /// Convert lifetimes as sent on the wire, where all ones means infinity.
+    pub fn from_secs(preferred: u32, valid: u32) -> Self {
+        let to_duration = |secs| {
+            if secs == 0xffffffff {
+                None
+            } else {
+                Some(Duration::from_secs(secs as u64))
+            }
+        };
+
+        Self {
+            preferred: to_duration(preferred),
+            valid: to_duration(valid),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InterfaceAddress {
+    address: Address,
+    state: InterfaceAddressState,
+    preferred_until: Option<Instant>,
+    valid_until: Option<Instant>,
+}
+
+impl InterfaceAddress {
+    pub fn new(address: Address, lifetimes: Lifetimes) -> Self {
+        let mut result = Self {
+            address,
+            state: InterfaceAddressState::New,
+            preferred_until: None,
+            valid_until: None,
+        };
+
+        result.set_lifetimes(lifetimes);
+
+        result
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    pub fn state(&self) -> InterfaceAddressState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: InterfaceAddressState) {
+        self.state = state;
+
+        status::update(|s| {
+            s.interface.addresses.insert(
+                status::Key(self.address),
+                status::AddressStatus { state: self.state },
+            );
+        });
+    }
+
+    /// Restart the lifetimes of this address from now.
+    ///
+    /// The preferred lifetime is clamped to the valid lifetime.
+    pub fn set_lifetimes(&mut self, lifetimes: Lifetimes) {
+        let now = Instant::now();
+
+        self.valid_until = lifetimes.valid.map(|d| now + d);
+        self.preferred_until = match (lifetimes.preferred, self.valid_until) {
+            (Some(d), Some(valid_until)) => Some(std::cmp::min(now + d, valid_until)),
+            (Some(d), None) => Some(now + d),
+            (None, valid_until) => valid_until,
+        };
+    }
+
+    /// How much longer this address is valid for as of `now`, or `None` if it always will be.
+    pub fn remaining_valid(&self, now: Instant) -> Option<Duration> {
+        self.valid_until.map(|t| t.saturating_duration_since(now))
+    }
+
+    /// When this address next needs to change state because of its lifetimes, if ever.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        match self.state {
+            InterfaceAddressState::Valid => self.preferred_until,
+            InterfaceAddressState::Deprecated => self.valid_until,
+            _ => None,
+        }
+    }
+
+    /// Whether this address may be used as a source address.
+    pub fn is_usable(&self) -> bool {
+        matches!(
+            self.state,
+            InterfaceAddressState::Valid | InterfaceAddressState::Deprecated
+        )
+    }
+}
+
+fn common_prefix_len(a: Address, b: Address) -> u32 {
+    (u128::from(a) ^ u128::from(b)).leading_zeros()
+}
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc6724#section-5
+fn compare_sources(a: &InterfaceAddress, b: &InterfaceAddress, dest: Address) -> Ordering {
+    // Rule 1: prefer same address
+    if a.address() == dest || b.address() == dest {
+        return (a.address() == dest).cmp(&(b.address() == dest));
+    }
+
+    // Rule 2: prefer appropriate scope
+    let (a_scope, b_scope, dest_scope) = (a.address().scope(), b.address().scope(), dest.scope());
+    if a_scope != b_scope {
+        return if a_scope < b_scope {
+            if a_scope < dest_scope {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        } else if b_scope < dest_scope {
+            Ordering::Greater
+        } else {
+            Ordering::Less
+        };
+    }
+
+    // Rule 3: avoid deprecated addresses
+    let a_preferred = a.state() == InterfaceAddressState::Valid;
+    let b_preferred = b.state() == InterfaceAddressState::Valid;
+    if a_preferred != b_preferred {
+        return a_preferred.cmp(&b_preferred);
+    }
+
+    // Rule 8: use longest matching prefix
+    common_prefix_len(a.address(), dest).cmp(&common_prefix_len(b.address(), dest))
+}
+
+/// Pick the best source address for `dest`, never choosing tentative or invalid addresses.
+pub fn select_source<'a>(
+    addresses: impl IntoIterator<Item = &'a InterfaceAddress>,
+    dest: Address,
+) -> Option<Address> {
+    addresses
+        .into_iter()
+        .filter(|a| a.is_usable())
+        .max_by(|a, b| compare_sources(a, b, dest))
+        .map(|a| a.address())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr_in_state(s: &str, state: InterfaceAddressState) -> InterfaceAddress {
+        InterfaceAddress {
+            state,
+            ..InterfaceAddress::new(s.parse().unwrap(), Lifetimes::default())
+        }
+    }
+
+    #[test]
+    fn preferred_lifetime_is_clamped_to_valid() {
+        let addr = InterfaceAddress::new(
+            "fd00::1".parse().unwrap(),
+            Lifetimes {
+                preferred: Some(Duration::from_secs(60)),
+                valid: Some(Duration::from_secs(30)),
+            },
+        );
+
+        assert_eq!(addr.preferred_until, addr.valid_until);
+    }
+
+    #[test]
+    fn infinite_lifetimes_never_expire() {
+        let addr = addr_in_state("fd00::1", InterfaceAddressState::Valid);
+
+        assert_eq!(addr.next_expiry(), None);
+        assert_eq!(
+            Lifetimes::from_secs(0xffffffff, 0xffffffff),
+            Lifetimes::default()
+        );
+    }
+
+    #[test]
+    fn select_source_skips_tentative_addresses() {
+        let addresses = vec![
+            addr_in_state("fd00::1", InterfaceAddressState::Tentative),
+            addr_in_state("fe80::1", InterfaceAddressState::Valid),
+        ];
+
+        assert_eq!(
+            select_source(&addresses, "fd00::2".parse().unwrap()),
+            Some("fe80::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn select_source_avoids_deprecated_addresses() {
+        let addresses = vec![
+            addr_in_state("fd00::1", InterfaceAddressState::Deprecated),
+            addr_in_state("fd00::2", InterfaceAddressState::Valid),
+        ];
+
+        assert_eq!(
+            select_source(&addresses, "fd00::1:3".parse().unwrap()),
+            Some("fd00::2".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn select_source_falls_back_to_deprecated_addresses() {
+        let addresses = vec![addr_in_state("fd00::1", InterfaceAddressState::Deprecated)];
+
+        assert_eq!(
+            select_source(&addresses, "fd00::3".parse().unwrap()),
+            Some("fd00::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn select_source_prefers_matching_scope() {
+        let addresses = vec![
+            addr_in_state("fd00::1", InterfaceAddressState::Valid),
+            addr_in_state("fe80::1", InterfaceAddressState::Valid),
+        ];
+
+        assert_eq!(
+            select_source(&addresses, "ff02::16".parse().unwrap()),
+            Some("fe80::1".parse().unwrap())
+        );
+        assert_eq!(
+            select_source(&addresses, "2001:db8::1".parse().unwrap()),
+            Some("fd00::1".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Interface identifiers, the low 64 bits, of addresses the node makes up for itself.
+
+use blake2::{Blake2s256, Digest};
+use serde::Deserialize;
+use std::convert::TryInto;
+
+use super::Address;
+
+/// The universal/local bit, set in identifiers made from universally administered ether
+/// addresses, and the individual/group bit.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4291#appendix-A
+const UNIVERSAL: u64 = 0x0200_0000_0000_0000;
+const GROUP: u64 = 0x0100_0000_0000_0000;
+
+/// Tries at a stable identifier before giving up on avoiding reserved ones.
+// Ref: https://datatracker.ietf.org/doc/html/rfc7217#section-6
+const IDGEN_RETRIES: u8 = 3;
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum InterfaceIds {
+    /// New for each address each run.
+    #[default]
+    Random,
+    /// The same for each prefix and interface every run, but not guessable without the key.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc7217#section-5
+    Stable { secret_key: String },
+}
+
+/// Identifiers that mean something else.
+// Ref: https://datatracker.ietf.org/doc/html/rfc5453#section-3
+fn is_reserved(id: u64) -> bool {
+    id == 0
+        || (0x0200_5eff_fe00_0000..=0x0200_5eff_feff_ffff).contains(&id)
+        || id >= 0xfdff_ffff_ffff_ff80
+}
+
+/// Marks `id` as locally assigned and individual, as it doesn't come from a universal ether
+/// address.
+fn local(id: u64) -> u64 {
+    id & !(UNIVERSAL | GROUP)
+}
+
+impl InterfaceIds {
+    /// An address in the /64 `prefix` for the interface named `if_name`.
+    pub fn address(&self, prefix: Address, if_name: &str, rng: &mut impl rand::Rng) -> Address {
+        let id = match self {
+            InterfaceIds::Random => loop {
+                let id = local(rng.gen());
+                if !is_reserved(id) {
+                    break id;
+                }
+            },
+            InterfaceIds::Stable { secret_key } => {
+                let mut dad_counter = 0;
+                loop {
+                    let id = local(stable(prefix, if_name, dad_counter, secret_key));
+                    if !is_reserved(id) || dad_counter == IDGEN_RETRIES {
+                        break id;
+                    }
+                    dad_counter += 1;
+                }
+            }
+        };
+
+        Address::from(id as u128).combine_subnet(&prefix.prefix(64))
+    }
+}
+
+/// F(Prefix, Net_Iface, Network_ID, DAD_Counter, secret_key), leaving out the optional network
+/// ID.
+fn stable(prefix: Address, if_name: &str, dad_counter: u8, secret_key: &str) -> u64 {
+    let hash = Blake2s256::new()
+        .chain_update(u128::from(prefix.prefix(64)).to_be_bytes())
+        .chain_update(if_name)
+        .chain_update([dad_counter])
+        .chain_update(secret_key)
+        .finalize();
+
+    u64::from_be_bytes(hash[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn random_identifiers_are_local_and_individual() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let prefix: Address = "fe80::".parse().unwrap();
+
+        for _ in 0..64 {
+            let address = InterfaceIds::Random.address(prefix, "eth0", &mut rng);
+            assert_eq!(address.prefix(64), prefix);
+            assert_eq!(address.0[4] & 0x0300, 0, "{}", address);
+            assert!(!is_reserved(u128::from(address) as u64));
+        }
+    }
+
+    #[test]
+    fn stable_identifiers_depend_on_prefix_interface_and_key() {
+        let mut rng = rand::thread_rng();
+        let stable = |key: &str| InterfaceIds::Stable {
+            secret_key: key.to_string(),
+        };
+        let prefix: Address = "2001:db8:1::".parse().unwrap();
+
+        let address = stable("key").address(prefix, "eth0", &mut rng);
+        assert_eq!(address.prefix(64), prefix);
+        assert_eq!(address, stable("key").address(prefix, "eth0", &mut rng));
+        assert_ne!(address, stable("other").address(prefix, "eth0", &mut rng));
+        assert_ne!(address, stable("key").address(prefix, "eth1", &mut rng));
+        assert_ne!(
+            address.suffix(64),
+            stable("key")
+                .address("2001:db8:2::".parse().unwrap(), "eth0", &mut rng)
+                .suffix(64)
+        );
+    }
+
+    #[test]
+    fn reserved_identifiers_are_recognized() {
+        assert!(is_reserved(0));
+        assert!(is_reserved(0x0200_5eff_fe00_5213));
+        assert!(is_reserved(0xfdff_ffff_ffff_ff90));
+        assert!(!is_reserved(0x0000_0000_0000_0001));
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use super::ether;
+use super::icmpv6;
+use super::Address;
+
+/// Deliberately hostile behavior, for testing first-hop security and monitoring tools.
+#[derive(Clone, Debug, Default)]
+pub struct Misbehavior {
+    /// Claim every address that another node probes with duplicate address detection.
+    pub claim_dad: bool,
+    /// Periodically advertise ourselves as a router, regardless of what real routers say.
+    pub rogue_router: Option<RogueRouter>,
+    /// Prefixes and their lengths to answer neighbor solicitations for every address in, each at
+    /// its own made-up ether address.
+    pub sweep: Vec<(Address, u8)>,
+}
+
+impl Misbehavior {
+    /// Whether we pretend a host is at `target`, leaving out the subnet-router anycast address.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc4291#section-2.6.1
+    pub(super) fn sweeps(&self, target: Address) -> bool {
+        !target.is_multicast()
+            && self.sweep.iter().any(|&(prefix, length)| {
+                let network = prefix.prefix(length as usize);
+                target.prefix(length as usize) == network && (length == 128 || target != network)
+            })
+    }
+}
+
+/// The made-up ether address we answer for `address` at when sweeping, distinct per node.
+pub(super) fn swept_ether_address(
+    ether_address: ether::Address,
+    address: Address,
+) -> ether::Address {
+    let address_bits: u128 = address.into();
+    ether::Address::derived(&[&ether_address.0[..], &address_bits.to_be_bytes()[..]].concat())
+}
+
+#[derive(Clone, Debug)]
+pub struct RogueRouter {
+    pub interval: Duration,
+    pub router_lifetime: u16,
+    /// Prefixes and their lengths to advertise for autoconfiguration.
+    pub prefixes: Vec<(Address, u8)>,
+}
+
+impl RogueRouter {
+    pub(super) fn advertisement(&self) -> icmpv6::Packet {
+        icmpv6::Packet::RouterAdvertisement {
+            cur_hop_limit: 64,
+            managed: false,
+            other: false,
+            router_lifetime: self.router_lifetime,
+            reachable_time: 0,
+            retrans_timer: 0,
+            options: self
+                .prefixes
+                .iter()
+                .map(|(prefix, prefix_length)| {
+                    icmpv6::NeighborSolicitationOption::PrefixInformation(
+                        icmpv6::PrefixInformation {
+                            prefix_length: *prefix_length,
+                            on_link: true,
+                            autonomous: true,
+                            valid_lifetime: 86400,
+                            preferred_lifetime: 14400,
+                            prefix: *prefix,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Advertisement defending `target` against a duplicate address detection probe, claiming it for
+/// `ether_address`.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-7.2.4
+pub(super) fn dad_defense(target: Address, ether_address: ether::Address) -> icmpv6::Packet {
+    super::unsolicited_advertisement(target, ether_address)
+}
+
+/// Solicited advertisement for a swept `target`, at `ether_address`.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-7.2.4
+pub(super) fn sweep_advertisement(
+    target: Address,
+    ether_address: ether::Address,
+) -> icmpv6::Packet {
+    super::solicited_advertisement(target, ether_address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rogue_router_advertises_prefixes() {
+        let router = RogueRouter {
+            interval: Duration::from_secs(10),
+            router_lifetime: 1800,
+            prefixes: vec![("2001:db8:bad::".parse().unwrap(), 64)],
+        };
+
+        match router.advertisement() {
+            icmpv6::Packet::RouterAdvertisement {
+                router_lifetime,
+                options,
+                ..
+            } => {
+                assert_eq!(router_lifetime, 1800);
+                assert!(matches!(
+                    &options[..],
+                    [icmpv6::NeighborSolicitationOption::PrefixInformation(
+                        icmpv6::PrefixInformation {
+                            prefix_length: 64,
+                            ..
+                        }
+                    )]
+                ));
+            }
+            p => panic!("unexpected packet {:?}", p),
+        }
+    }
+
+    #[test]
+    fn sweeps_cover_hosts_in_their_prefixes() {
+        let misbehavior = Misbehavior {
+            sweep: vec![("2001:db8:5::".parse().unwrap(), 64)],
+            ..Misbehavior::default()
+        };
+
+        assert!(misbehavior.sweeps("2001:db8:5::7".parse().unwrap()));
+        assert!(!misbehavior.sweeps("2001:db8:5::".parse().unwrap()));
+        assert!(!misbehavior.sweeps("2001:db8:6::7".parse().unwrap()));
+        assert!(!Misbehavior::default().sweeps("2001:db8:5::7".parse().unwrap()));
+    }
+}
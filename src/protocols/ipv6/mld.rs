@@ -0,0 +1,151 @@
+//! Answering multicast listener queries for the groups the node has joined.
+// Ref: https://datatracker.ietf.org/doc/html/rfc3810#section-6
+
+use rand::Rng;
+use std::time::Duration;
+
+use super::icmpv6::{self, MldQuery, MldV2AddressRecord, Mldv2AddressRecordType};
+use super::Address;
+
+/// Where reports go.
+pub(super) const ALL_MLDV2_ROUTERS: Address = Address([0xff02, 0, 0, 0, 0, 0, 0, 0x16]);
+
+/// What a query asked about, kept until it's time to answer.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(super) enum Pending {
+    #[default]
+    General,
+    Group {
+        address: Address,
+        sources: Vec<Address>,
+    },
+}
+
+impl Pending {
+    /// What `query` from `src` asks about, or `None` if it must be ignored.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc3810#section-5.1.14
+    pub(super) fn from_query(src: Address, query: &MldQuery) -> Option<Self> {
+        if src.scope() != 0x2 || src.is_multicast() {
+            return None;
+        }
+
+        Some(if query.is_general() {
+            Pending::General
+        } else {
+            Pending::Group {
+                address: query.address,
+                sources: query.sources.clone(),
+            }
+        })
+    }
+
+    /// The report answering this for a node listening to `groups` in exclude mode with no
+    /// sources, as every group we join is, or `None` if we aren't listening.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc3810#section-6.3
+    pub(super) fn report(&self, groups: &[Address]) -> Option<icmpv6::Packet> {
+        let records: Vec<_> = match self {
+            Pending::General => groups
+                .iter()
+                .map(|group| MldV2AddressRecord::new(Mldv2AddressRecordType::CodeIsExclude, *group))
+                .collect(),
+            Pending::Group { address, .. } if !groups.contains(address) => return None,
+            Pending::Group { address, sources } if sources.is_empty() => {
+                vec![MldV2AddressRecord::new(
+                    Mldv2AddressRecordType::CodeIsExclude,
+                    *address,
+                )]
+            }
+            // Excluding nothing, we want every source asked about.
+            Pending::Group { address, sources } => vec![MldV2AddressRecord {
+                sources: sources.clone(),
+                ..MldV2AddressRecord::new(Mldv2AddressRecordType::CodeIsInclude, *address)
+            }],
+        };
+
+        (!records.is_empty()).then_some(icmpv6::Packet::MldV2Report(records))
+    }
+}
+
+/// How long to wait before answering `query`, picked at random up to its maximum response delay.
+pub(super) fn response_delay(query: &MldQuery, rng: &mut impl Rng) -> Duration {
+    rng.gen_range(Duration::ZERO..=query.max_response_delay())
+}
+
+/// Whether `group` is reported at all; the all-nodes address never is.
+// Ref: https://datatracker.ietf.org/doc/html/rfc3810#section-6
+pub(super) fn is_reported(group: Address) -> bool {
+    group.is_multicast() && group.scope() > 0x1 && group != "ff02::1".parse().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Address {
+        s.parse().unwrap()
+    }
+
+    fn query(address: &str, sources: &[&str]) -> MldQuery {
+        MldQuery {
+            max_response_code: 1000,
+            address: addr(address),
+            suppress_router_processing: false,
+            robustness: 2,
+            query_interval_code: 125,
+            sources: sources.iter().map(|s| addr(s)).collect(),
+        }
+    }
+
+    #[test]
+    fn queries_are_answered_for_joined_groups_only() {
+        let groups = [addr("ff02::1:ff00:1"), addr("ff02::1:ff00:2")];
+        let router = addr("fe80::1");
+
+        let general = Pending::from_query(router, &query("::", &[])).unwrap();
+        match general.report(&groups) {
+            Some(icmpv6::Packet::MldV2Report(records)) => assert_eq!(records.len(), 2),
+            report => panic!("unexpected report {:?}", report),
+        }
+
+        let specific = Pending::from_query(router, &query("ff02::1:ff00:2", &[])).unwrap();
+        assert_eq!(
+            specific.report(&groups),
+            Some(icmpv6::Packet::MldV2Report(vec![MldV2AddressRecord::new(
+                Mldv2AddressRecordType::CodeIsExclude,
+                groups[1]
+            )]))
+        );
+
+        let other = Pending::from_query(router, &query("ff02::1:ff00:3", &[])).unwrap();
+        assert_eq!(other.report(&groups), None);
+
+        assert_eq!(
+            Pending::from_query(addr("2001:db8::1"), &query("::", &[])),
+            None
+        );
+    }
+
+    #[test]
+    fn source_specific_queries_get_the_sources_back() {
+        let groups = [addr("ff3e::8000:1")];
+        let pending = Pending::from_query(
+            addr("fe80::1"),
+            &query("ff3e::8000:1", &["2001:db8::1", "2001:db8::2"]),
+        )
+        .unwrap();
+
+        match pending.report(&groups) {
+            Some(icmpv6::Packet::MldV2Report(records)) => {
+                assert_eq!(
+                    records[0].record_type,
+                    Mldv2AddressRecordType::CodeIsInclude
+                );
+                assert_eq!(records[0].sources.len(), 2);
+            }
+            report => panic!("unexpected report {:?}", report),
+        }
+
+        let delay = response_delay(&query("::", &[]), &mut rand::thread_rng());
+        assert!(delay <= Duration::from_secs(1));
+    }
+}
@@ -1,81 +1,104 @@
-use anyhow::Result as AHResult;
+use anyhow::{bail, Result as AHResult};
 use crossbeam::channel;
 use rand::Rng;
-use serde::Serialize;
 use std::cell::RefCell;
-use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 mod address;
-mod icmpv6;
+mod dns;
+mod hop_limit;
+pub mod icmpv6;
+mod interface_address;
+mod interface_id;
+mod misbehavior;
+mod mld;
 mod packet;
+mod path_mtu;
+mod resolution;
+mod router;
+mod validation;
 
+use super::answer_policy::{self, Answer};
 use super::ether;
+use super::icmp_limit;
 use super::ipv4;
-use super::utils::{KeyedDispatcher, RecvSenderMap};
+use super::neighbor;
+use super::utils::{Backpressure, KeyedDispatcher, RecvSenderMap};
 use crate::delay_queue::DelayQueue;
-use crate::select_queues;
-use crate::status;
+use crate::status::{self, EventKind, EventStatus};
+use crate::{debug, select_queues, warn};
+use crate::{stats, supervisor, watchdog};
 
-use self::address::address;
-pub use self::address::Address;
+pub(crate) use self::address::address;
+pub use self::address::{Address, Prefix, ScopedAddress};
+use self::dns::DnsConfig;
+pub use self::dns::DnsHandle;
+pub use self::hop_limit::{HopLimitHandle, DEFAULT_HOP_LIMIT};
+pub use self::interface_address::InterfaceAddressState;
+pub use self::interface_address::Lifetimes;
+use self::interface_address::{select_source, InterfaceAddress};
+pub use self::interface_id::InterfaceIds;
+pub use self::misbehavior::{Misbehavior, RogueRouter};
+use self::path_mtu::PathMtuCache;
+pub use self::path_mtu::PathMtuHandle;
+use self::resolution::Resolutions;
+use self::router::DefaultRouterList;
 
+pub use self::packet::packet;
 pub use self::packet::NextHeader;
 pub use self::packet::Packet;
+pub use self::packet::Problem;
+pub use self::packet::PseudoHeader;
+pub use self::packet::{ExtensionHeader, HopByHopOption, RouterAlertType};
+pub use self::packet::{UnrecognizedOption, UnrecognizedOptionAction};
 
 const _MULTICAST_ALL_NODES: Address = Address([0xff01, 0, 0, 0, 0, 0, 0, 0x1]);
 const RFC4861_MAX_RTR_SOLICITATION_DELAY: Duration = Duration::from_secs(1);
 const RFC4861_RETRANS_TIMER_MS: Duration = Duration::from_secs(1);
+// Ref: https://datatracker.ietf.org/doc/html/rfc4862#section-5.5.3
+const RFC4862_TWO_HOURS: Duration = Duration::from_secs(2 * 60 * 60);
 
-#[derive(Clone, Copy, Debug, Serialize)]
-enum InterfaceAddressState {
-    New,
-    Tentative,
-    Valid,
-}
-
-#[derive(Clone, Copy, Debug)]
-struct InterfaceAddress {
-    address: Address,
-    state: InterfaceAddressState,
-}
-
-impl InterfaceAddress {
-    fn new(address: Address) -> Self {
-        Self {
-            address,
-            state: InterfaceAddressState::New,
-        }
-    }
-
-    fn address(&self) -> Address {
-        self.address
-    }
-
-    fn state(&self) -> InterfaceAddressState {
-        self.state
-    }
-
-    fn set_state(&mut self, state: InterfaceAddressState) {
-        self.state = state;
-
-        status::update()
-            .child("interface")
-            .child("addresses")
-            .child(format!("{}", self.address))
-            .field("state", self.state)
-            .write();
-    }
+/// Where a packet goes on the link.
+enum NextHop {
+    Ether(ether::Address),
+    /// An on-link address whose link-layer address we don't know yet.
+    Unresolved(Address),
 }
 
 struct Actor {
     src_ether: ether::Address,
     incoming_receiver: channel::Receiver<ether::Frame>,
     outgoing_sender: channel::Sender<ether::Frame>,
+    upper_receiver: channel::Receiver<packet::Packet>,
     recv_map: Arc<RecvSenderMap<packet::Packet>>,
-    addresses: Vec<RefCell<InterfaceAddress>>,
+    addresses: Vec<InterfaceAddress>,
     addr_maint_queue: DelayQueue<Address>,
+    default_routers: DefaultRouterList,
+    router_maint_queue: DelayQueue<()>,
+    path_mtus: Arc<RwLock<PathMtuCache>>,
+    path_mtu_maint_queue: DelayQueue<()>,
+    dns: Arc<RwLock<DnsConfig>>,
+    dns_maint_queue: DelayQueue<()>,
+    hop_limit: HopLimitHandle,
+    neighbors: neighbor::Table,
+    /// Held in a cell, as packets are sent from everywhere, not just where we can mutate.
+    resolutions: RefCell<Resolutions>,
+    misbehavior: Misbehavior,
+    interface_ids: InterfaceIds,
+    /// Prefixes services are bound to, whose every address is answered for as if it were ours.
+    prefixes: Vec<Prefix>,
+    error_limiter: icmp_limit::Limiter,
+    answer_policies: answer_policy::Policies,
+    /// Solicitations held back by their policy, by target and requester.
+    answer_queue: DelayQueue<(Address, Address)>,
+    rogue_ra_queue: DelayQueue<()>,
+    mld_queue: DelayQueue<mld::Pending>,
+    /// When the answer to the last general query is due, if it hasn't been sent.
+    general_report_due: Option<Instant>,
+    unsolicited_advertisements: bool,
+    admin_states: channel::Receiver<ether::AdminState>,
 }
 
 impl Actor {
@@ -83,172 +106,1116 @@ impl Actor {
         src_ether: ether::Address,
         incoming_receiver: channel::Receiver<ether::Frame>,
         outgoing_sender: channel::Sender<ether::Frame>,
+        upper_receiver: channel::Receiver<packet::Packet>,
         recv_map: Arc<RecvSenderMap<packet::Packet>>,
+        path_mtus: Arc<RwLock<PathMtuCache>>,
+        neighbors: neighbor::Table,
     ) -> Self {
         Self {
             src_ether,
             incoming_receiver,
             outgoing_sender,
+            upper_receiver,
             recv_map,
             addresses: Vec::new(),
+            default_routers: DefaultRouterList::new(),
+            path_mtus,
+            dns: Arc::new(RwLock::new(DnsConfig::new())),
+            hop_limit: HopLimitHandle::new(DEFAULT_HOP_LIMIT),
+            neighbors,
+            resolutions: RefCell::new(Resolutions::default()),
+            misbehavior: Misbehavior::default(),
+            interface_ids: InterfaceIds::default(),
+            prefixes: Vec::new(),
+            error_limiter: icmp_limit::Limiter::new(icmp_limit::Config::default(), Instant::now()),
+            answer_policies: answer_policy::Policies::default(),
 
             addr_maint_queue: DelayQueue::new(),
+            router_maint_queue: DelayQueue::new(),
+            path_mtu_maint_queue: DelayQueue::new(),
+            dns_maint_queue: DelayQueue::new(),
+            answer_queue: DelayQueue::new(),
+            rogue_ra_queue: DelayQueue::new(),
+            mld_queue: DelayQueue::new(),
+            general_report_due: None,
+            unsolicited_advertisements: true,
+            admin_states: channel::never(),
+        }
+    }
+
+    fn is_on_link(&self, dest: Address) -> bool {
+        // Without a prefix list, treat link-local destinations and anything in the same /64 as
+        // one of our addresses as on-link.
+        dest.scope() <= 0x2
+            || self
+                .addresses
+                .iter()
+                .any(|a| a.address().prefix(64) == dest.prefix(64))
+    }
+
+    fn next_hop(&self, dest: Address) -> AHResult<NextHop> {
+        if dest.is_multicast() {
+            return Ok(NextHop::Ether(dest.multicast_ether_dest()));
+        }
+
+        let next_hop = if self.is_on_link(dest) {
+            dest
+        } else {
+            match self
+                .default_routers
+                .select(|router| self.neighbors.lookup(IpAddr::from(router)))
+            {
+                Some((_, Some(ether_address))) => return Ok(NextHop::Ether(ether_address)),
+                Some((router, None)) => router,
+                None => bail!("no route to {}", dest),
+            }
+        };
+
+        Ok(match self.neighbors.lookup(IpAddr::from(next_hop)) {
+            Some(ether_address) => NextHop::Ether(ether_address),
+            None => NextHop::Unresolved(next_hop),
+        })
+    }
+
+    /// Ask `target` for its link-layer address, from `src`.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-7.2.2
+    fn solicit(&self, target: Address, src: Address) -> AHResult<()> {
+        self.send_icmpv6(
+            src,
+            target.solicited_nodes_multicast(),
+            icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+                dest: target,
+                options: vec![icmpv6::NeighborSolicitationOption::SourceLinkLayerAddress(
+                    self.src_ether,
+                )],
+            }),
+        )
+    }
+
+    /// Solicit again the next hops that haven't answered yet.
+    fn solicit_due(&self) {
+        let due = self.resolutions.borrow_mut().due(Instant::now());
+
+        for (target, src) in due {
+            if let Err(e) = self.solicit(target, src) {
+                warn!("not soliciting {}: {}", target, e);
+            }
         }
     }
 
     fn send_ipv6(&self, packet: packet::Packet) -> AHResult<()> {
-        self.outgoing_sender.send(ether::Frame {
-            dest: packet.dest.multicast_ether_dest(),
-            src: self.src_ether,
-            ethertype: ether::Type::Ipv6,
-            payload: packet.encode(),
-        })?;
+        self.send_ipv6_as(self.src_ether, packet)
+    }
+
+    /// Whether packets carrying `next_header` are handled by anything, fragments always being
+    /// recognized even though they're never reassembled.
+    fn is_recognized(&self, next_header: packet::NextHeader) -> bool {
+        next_header == packet::NextHeader::Protocol(ipv4::ProtocolNumber::Ipv6Frag)
+            || self.recv_map.handles(&next_header)
+    }
+
+    /// Count and record the discard of the packet in `invoking_bytes` for `option`, answering
+    /// with a parameter problem if its type asks for one.
+    fn discard_unrecognized(&mut self, invoking_bytes: &[u8], option: &packet::UnrecognizedOption) {
+        stats::OPTION_DISCARDS.increment(&format!("{:#04x}", option.option_type));
+        status::update(|s| {
+            s.push_event(EventStatus {
+                input: Some(hex::encode(invoking_bytes)),
+                ..EventStatus::new(
+                    EventKind::Discard,
+                    "ipv6",
+                    format!("packet from {}: {}", option.src, option),
+                )
+            })
+        });
+
+        match option.problem() {
+            Some(problem) => self.report_problem(invoking_bytes, &problem),
+            None => debug!("discarding packet from {}: {}", option.src, option),
+        }
+    }
+
+    /// Discard the packet in `invoking_bytes`, answering with a parameter problem if allowed.
+    fn report_problem(&mut self, invoking_bytes: &[u8], problem: &packet::Problem) {
+        debug!("discarding packet from {}: {}", problem.src, problem);
+
+        if let Some(error) = parameter_problem(invoking_bytes, problem, self.hop_limit.get()) {
+            if let Err(e) = self.send_error(error) {
+                warn!("failed to send parameter problem: {}", e);
+            }
+        }
+    }
+
+    /// Send an ICMPv6 error, unless it's over the rate limits.
+    fn send_error(&mut self, packet: packet::Packet) -> AHResult<()> {
+        if let Err(limit) = self
+            .error_limiter
+            .check(IpAddr::from(packet.dest), Instant::now())
+        {
+            debug!(
+                "not sending error to {}: over the {} rate limit",
+                packet.dest, limit
+            );
+            return Ok(());
+        }
+
+        self.send_ipv6(packet)
+    }
+
+    /// Send `packet` from another ether address than ours.
+    fn send_ipv6_as(&self, src_ether: ether::Address, packet: packet::Packet) -> AHResult<()> {
+        let payload = packet.encode();
+
+        // We don't fragment, so anything over the path MTU can't be sent.
+        let path_mtu = self.path_mtus.read().unwrap().get(packet.dest);
+        if payload.len() > path_mtu {
+            bail!(
+                "{} byte packet exceeds path mtu {} to {}",
+                payload.len(),
+                path_mtu,
+                packet.dest
+            );
+        }
+
+        match self.next_hop(packet.dest)? {
+            NextHop::Ether(dest) => self.send_frame(dest, src_ether, payload),
+            NextHop::Unresolved(next_hop) => {
+                let held = resolution::Held { src_ether, payload };
+                let first =
+                    self.resolutions
+                        .borrow_mut()
+                        .hold(next_hop, packet.src, held, Instant::now());
+
+                if first {
+                    self.solicit(next_hop, packet.src)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn send_frame(
+        &self,
+        dest: ether::Address,
+        src_ether: ether::Address,
+        payload: Vec<u8>,
+    ) -> AHResult<()> {
+        let frame = ether::Frame::builder()
+            .dest(dest)
+            .src(src_ether)
+            .ethertype(ether::Type::Ipv6)
+            .payload(payload)
+            .build()?;
+        self.outgoing_sender.send(frame)?;
 
         Ok(())
     }
 
     fn send_icmpv6(&self, src: Address, dest: Address, packet: icmpv6::Packet) -> AHResult<()> {
-        let builder = packet::Packet::builder()
-            .protocol(ipv4::ProtocolNumber::Ipv6Icmp)
-            .hop_limit(0xff)
-            .src(src)
-            .dest(dest)
-            .payload(packet.encode(icmpv6::PseudoHeader {
-                src,
-                dest,
-                length: 0,
-            }));
-
-        let builder = match packet {
-            icmpv6::Packet::MldV2Report(_) => {
-                builder.extension_header(packet::ExtensionHeader::HopByHopOptions(vec![
-                    packet::HopByHopOption::RouterAlert(packet::RouterAlertType::Mld),
-                ]))
-            }
-            _ => builder,
-        };
+        self.send_ipv6(build_icmpv6(src, dest, packet))
+    }
+
+    fn add_address(&mut self, address: Address, lifetimes: Lifetimes, delay: Duration) {
+        self.addresses
+            .push(InterfaceAddress::new(address, lifetimes));
+
+        self.addr_maint_queue.push_after(delay, address);
+    }
+
+    fn select_source(&self, dest: Address) -> Option<Address> {
+        select_source(&self.addresses, dest)
+    }
 
-        self.send_ipv6(builder.build())
+    fn schedule_expiry(&mut self, addr_index: usize) {
+        let addr_info = self.addresses[addr_index];
+
+        if let Some(t) = addr_info.next_expiry() {
+            self.addr_maint_queue.push_at(t, addr_info.address());
+        }
     }
 
     fn maintain_addr(&mut self, addr: Address) -> AHResult<()> {
-        let mut addr_info = self
-            .addresses
-            .iter()
-            .find(|ai| ai.borrow().address() == addr)
-            .unwrap()
-            .borrow_mut();
+        let addr_index = match self.addresses.iter().position(|ai| ai.address() == addr) {
+            Some(i) => i,
+            // Already removed; this is a stale timer.
+            None => return Ok(()),
+        };
+        let state = self.addresses[addr_index].state();
 
-        match addr_info.state() {
+        match state {
             InterfaceAddressState::New => {
+                let mld_src = self
+                    .select_source(mld::ALL_MLDV2_ROUTERS)
+                    .unwrap_or_else(|| "::".parse().unwrap());
+
                 self.send_icmpv6(
-                    "::".parse().unwrap(),
-                    "ff02::16".parse().unwrap(),
+                    mld_src,
+                    mld::ALL_MLDV2_ROUTERS,
                     icmpv6::Packet::MldV2Report(vec![
-                        icmpv6::MldV2AddressRecord {
-                            record_type: icmpv6::Mldv2AddressRecordType::ChangeToExcludeMode,
-                            address: "ff02::1".parse().unwrap(),
-                        },
-                        icmpv6::MldV2AddressRecord {
-                            record_type: icmpv6::Mldv2AddressRecordType::ChangeToExcludeMode,
-                            address: addr.solicited_nodes_multicast(),
-                        },
+                        icmpv6::MldV2AddressRecord::new(
+                            icmpv6::Mldv2AddressRecordType::ChangeToExcludeMode,
+                            "ff02::1".parse().unwrap(),
+                        ),
+                        icmpv6::MldV2AddressRecord::new(
+                            icmpv6::Mldv2AddressRecordType::ChangeToExcludeMode,
+                            addr.solicited_nodes_multicast(),
+                        ),
                     ]),
                 )?;
 
                 self.send_icmpv6(
                     "::".parse().unwrap(),
                     addr.solicited_nodes_multicast(),
-                    icmpv6::Packet::NeighborSolicitation {
+                    icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
                         dest: addr,
                         options: vec![],
-                    },
+                    }),
                 )?;
 
-                addr_info.set_state(InterfaceAddressState::Tentative);
+                self.addresses[addr_index].set_state(InterfaceAddressState::Tentative);
 
                 self.addr_maint_queue
                     .push_after(RFC4861_RETRANS_TIMER_MS, addr);
             }
             InterfaceAddressState::Tentative => {
-                addr_info.set_state(InterfaceAddressState::Valid);
+                self.addresses[addr_index].set_state(InterfaceAddressState::Valid);
+                self.schedule_expiry(addr_index);
+                self.advertise(addr);
             }
-            _ => {}
+            InterfaceAddressState::Valid | InterfaceAddressState::Deprecated => {
+                let addr_info = &mut self.addresses[addr_index];
+
+                // The lifetimes may have been extended since this timer was set.
+                if !matches!(addr_info.next_expiry(), Some(t) if t <= Instant::now()) {
+                    self.schedule_expiry(addr_index);
+                    return Ok(());
+                }
+
+                if state == InterfaceAddressState::Valid {
+                    addr_info.set_state(InterfaceAddressState::Deprecated);
+                    self.schedule_expiry(addr_index);
+                } else {
+                    addr_info.set_state(InterfaceAddressState::Invalid);
+                    self.addresses.remove(addr_index);
+                }
+            }
+            InterfaceAddressState::Invalid => {}
         };
 
         Ok(())
     }
 
-    fn run(&mut self) {
-        let mut rng = rand::thread_rng();
+    fn process_too_big(&mut self, mtu: u32, invoking_packet: &[u8]) {
+        // The destination of the packet that was too big sits at the end of its header.
+        if let Ok((_, dest)) = address(invoking_packet.get(24..).unwrap_or_default()) {
+            let expires_at =
+                self.path_mtus
+                    .write()
+                    .unwrap()
+                    .update(dest, mtu as usize, Instant::now());
 
-        let link_local_address = Address::random(&mut rng)
-            .suffix(64)
-            .combine_subnet(&("fe80::".parse().unwrap()));
+            if let Some(expires_at) = expires_at {
+                self.path_mtu_maint_queue.push_at(expires_at, ());
+            }
+        }
+    }
 
-        self.addresses
-            .push(RefCell::new(InterfaceAddress::new(link_local_address)));
+    fn process_router_advertisement(
+        &mut self,
+        src: Address,
+        router_lifetime: u16,
+        options: &[icmpv6::NeighborSolicitationOption],
+    ) {
+        let ether_address = options.iter().find_map(|o| match o {
+            icmpv6::NeighborSolicitationOption::SourceLinkLayerAddress(a) => Some(*a),
+            _ => None,
+        });
 
-        self.addr_maint_queue.push_after(
-            rng.gen_range(Duration::ZERO..RFC4861_MAX_RTR_SOLICITATION_DELAY),
+        let now = Instant::now();
+        if let Some(expires_at) = self.default_routers.update(
+            src,
+            ether_address,
+            Duration::from_secs(router_lifetime as u64),
+            now,
+        ) {
+            self.router_maint_queue.push_at(expires_at, ());
+        }
+
+        for option in options {
+            let expires_at = match option {
+                icmpv6::NeighborSolicitationOption::RecursiveDnsServers { lifetime, servers } => {
+                    self.dns
+                        .write()
+                        .unwrap()
+                        .update_servers(servers.clone(), *lifetime, now)
+                }
+                icmpv6::NeighborSolicitationOption::DnsSearchList { lifetime, domains } => self
+                    .dns
+                    .write()
+                    .unwrap()
+                    .update_search_list(domains.clone(), *lifetime, now),
+                icmpv6::NeighborSolicitationOption::PrefixInformation(info) => {
+                    self.apply_prefix_lifetimes(info, now);
+                    None
+                }
+                _ => None,
+            };
+
+            if let Some(expires_at) = expires_at {
+                self.dns_maint_queue.push_at(expires_at, ());
+            }
+        }
+    }
+
+    /// Restart the lifetimes of our addresses within an autonomous prefix, deprecating them or
+    /// letting them be preferred again.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc4862#section-5.5.3
+    fn apply_prefix_lifetimes(&mut self, info: &icmpv6::PrefixInformation, now: Instant) {
+        if !info.autonomous
+            || info.prefix.scope() == 0x2
+            || info.preferred_lifetime > info.valid_lifetime
+        {
+            return;
+        }
+
+        let prefix = Prefix {
+            address: info.prefix,
+            len: info.prefix_length,
+        };
+        let received = Lifetimes::from_secs(info.preferred_lifetime, info.valid_lifetime);
+
+        for i in 0..self.addresses.len() {
+            let addr_info = &mut self.addresses[i];
+            if !prefix.contains(addr_info.address()) {
+                continue;
+            }
+
+            // Unauthenticated advertisements can't cut the valid lifetime below two hours, so a
+            // forged one can't take the address away.
+            let valid = match (received.valid, addr_info.remaining_valid(now)) {
+                (None, _) => None,
+                (Some(valid), remaining)
+                    if valid > RFC4862_TWO_HOURS || remaining.is_some_and(|r| valid > r) =>
+                {
+                    Some(valid)
+                }
+                (Some(_), Some(remaining)) if remaining <= RFC4862_TWO_HOURS => Some(remaining),
+                (Some(_), _) => Some(RFC4862_TWO_HOURS),
+            };
+            addr_info.set_lifetimes(Lifetimes {
+                preferred: received.preferred,
+                valid,
+            });
+
+            if addr_info.state() == InterfaceAddressState::Deprecated
+                && received.preferred != Some(Duration::ZERO)
+            {
+                addr_info.set_state(InterfaceAddressState::Valid);
+            }
+            self.schedule_expiry(i);
+        }
+    }
+
+    /// Update the neighbor cache from a link-layer address option, if present.
+    ///
+    /// We never solicit, so these are never proof of reachability.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-7.2.3
+    fn learn_neighbor(&self, address: Address, options: &[icmpv6::NeighborSolicitationOption]) {
+        let ether_address = options.iter().find_map(|o| match o {
+            icmpv6::NeighborSolicitationOption::SourceLinkLayerAddress(a)
+            | icmpv6::NeighborSolicitationOption::TargetLinkLayerAddress(a) => Some(*a),
+            _ => None,
+        });
+
+        // DAD probes come from the unspecified address.
+        if address == Address::default() {
+            return;
+        }
+
+        if let Some(ether_address) = ether_address {
+            self.neighbors
+                .learn(IpAddr::from(address), ether_address, false);
+
+            let held = self.resolutions.borrow_mut().resolved(address);
+            for held in held {
+                if let Err(e) = self.send_frame(ether_address, held.src_ether, held.payload) {
+                    warn!("dropping packet held for {}: {}", address, e);
+                }
+            }
+        }
+    }
+
+    fn send_rogue_advertisement(&mut self) -> AHResult<()> {
+        let rogue_router = match &self.misbehavior.rogue_router {
+            Some(r) => r.clone(),
+            None => return Ok(()),
+        };
+
+        self.rogue_ra_queue.push_after(rogue_router.interval, ());
+
+        // Router advertisements must come from a link-local address, so wait until ours is ready.
+        let all_nodes = "ff02::1".parse().unwrap();
+        match self.select_source(all_nodes) {
+            Some(src) if src.scope() == 0x2 => {
+                self.send_icmpv6(src, all_nodes, rogue_router.advertisement())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Groups we listen to and report: the solicited-node addresses of our own addresses.
+    fn listening_groups(&self) -> Vec<Address> {
+        let mut groups: Vec<_> = self
+            .addresses
+            .iter()
+            .map(|a| a.address().solicited_nodes_multicast())
+            .filter(|group| mld::is_reported(*group))
+            .collect();
+        groups.sort();
+        groups.dedup();
+
+        groups
+    }
+
+    fn process_mld_query(&mut self, src: Address, query: &icmpv6::MldQuery) {
+        let pending = match mld::Pending::from_query(src, query) {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        let due = Instant::now() + mld::response_delay(query, &mut rand::thread_rng());
+        if pending == mld::Pending::General {
+            // An answer already due sooner covers this query too.
+            if self.general_report_due.is_some_and(|at| at <= due) {
+                return;
+            }
+            self.general_report_due = Some(due);
+        }
+
+        self.mld_queue.push_at(due, pending);
+    }
+
+    fn send_mld_report(&mut self, pending: mld::Pending) -> AHResult<()> {
+        if pending == mld::Pending::General {
+            self.general_report_due = None;
+        }
+
+        match pending.report(&self.listening_groups()) {
+            Some(report) => {
+                let src = self
+                    .select_source(mld::ALL_MLDV2_ROUTERS)
+                    .unwrap_or_default();
+                self.send_icmpv6(src, mld::ALL_MLDV2_ROUTERS, report)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Tell all nodes `address` is at our ether address, overriding what they've cached.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-7.2.6
+    fn advertise(&self, address: Address) {
+        if !self.unsolicited_advertisements {
+            return;
+        }
+
+        let packet = unsolicited_advertisement(address, self.src_ether);
+        if let Err(e) = self.send_icmpv6(address, "ff02::1".parse().unwrap(), packet) {
+            warn!("not advertising {}: {}", address, e);
+        }
+    }
+
+    /// Announce every usable address again once the interface is back up, as its neighbors may
+    /// have moved on while it was away.
+    fn admin_state_changed(&self, state: ether::AdminState) {
+        if state != ether::AdminState::Up {
+            return;
+        }
+
+        for address in &self.addresses {
+            if matches!(
+                address.state(),
+                InterfaceAddressState::Valid | InterfaceAddressState::Deprecated
+            ) {
+                self.advertise(address.address());
+            }
+        }
+    }
+
+    fn claim_dad_target(&self, target: Address) -> AHResult<()> {
+        if self.addresses.iter().any(|a| a.address() == target) {
+            return Ok(());
+        }
+
+        let all_nodes = "ff02::1".parse().unwrap();
+        match self.select_source(all_nodes) {
+            Some(src) => self.send_icmpv6(
+                src,
+                all_nodes,
+                misbehavior::dad_defense(target, self.src_ether),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Answer a solicitation for `target` as the host we pretend is there, unless it's ours or
+    /// we've seen a real host use it.
+    fn answer_for_swept(&self, src: Address, target: Address) -> AHResult<()> {
+        if self.addresses.iter().any(|a| a.address() == target)
+            || self.neighbors.lookup(IpAddr::from(target)).is_some()
+        {
+            return Ok(());
+        }
+
+        let ether_address = misbehavior::swept_ether_address(self.src_ether, target);
+        self.send_ipv6_as(
+            ether_address,
+            build_icmpv6(
+                target,
+                src,
+                misbehavior::sweep_advertisement(target, ether_address),
+            ),
+        )
+    }
+
+    /// Whether solicitations for `target` get our own ether address: it's one of our addresses,
+    /// past duplicate address detection, or in a prefix services are bound to.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-7.2.3
+    fn answers_as_us(&self, target: Address) -> bool {
+        self.addresses.iter().any(|a| {
+            a.address() == target
+                && matches!(
+                    a.state(),
+                    InterfaceAddressState::Valid | InterfaceAddressState::Deprecated
+                )
+        }) || self.prefixes.iter().any(|p| p.contains(target))
+    }
+
+    /// Answer `requester`'s solicitation for `target`, as ourselves or a swept host.
+    fn answer_solicitation(&self, target: Address, requester: Address) {
+        let result = if self.answers_as_us(target) {
+            self.send_icmpv6(
+                target,
+                requester,
+                solicited_advertisement(target, self.src_ether),
+            )
+        } else {
+            self.answer_for_swept(requester, target)
+        };
+
+        if let Err(e) = result {
+            warn!("not answering for {}: {}", target, e);
+        }
+    }
+
+    fn process_icmpv6(&mut self, src: Address, packet: icmpv6::Packet) {
+        match &packet {
+            icmpv6::Packet::RouterAdvertisement { options, .. }
+            | icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+                options, ..
+            }) => self.learn_neighbor(src, options),
+            icmpv6::Packet::NeighborAdvertisement(icmpv6::NeighborAdvertisement {
+                src: target,
+                options,
+                ..
+            }) => self.learn_neighbor(*target, options),
+            _ => {}
+        }
+
+        match packet {
+            icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+                dest: target,
+                ..
+            }) if self.misbehavior.claim_dad && src == Address::default() => {
+                if let Err(e) = self.claim_dad_target(target) {
+                    warn!("not claiming {}: {}", target, e);
+                }
+            }
+            // Sweeping leaves duplicate address detection alone, so real hosts can still join.
+            icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+                dest: target,
+                ..
+            }) if src != Address::default()
+                && (self.answers_as_us(target) || self.misbehavior.sweeps(target)) =>
+            {
+                match self
+                    .answer_policies
+                    .answer(IpAddr::from(target), IpAddr::from(src))
+                {
+                    Answer::Now => self.answer_solicitation(target, src),
+                    Answer::After(delay) => self.answer_queue.push_after(delay, (target, src)),
+                    Answer::Ignore => {}
+                }
+            }
+            icmpv6::Packet::TooBig {
+                mtu,
+                invoking_packet,
+            } => self.process_too_big(mtu, &invoking_packet),
+            icmpv6::Packet::MldQuery(query) => self.process_mld_query(src, &query),
+            icmpv6::Packet::RouterAdvertisement {
+                cur_hop_limit,
+                router_lifetime,
+                options,
+                ..
+            } => {
+                // Zero leaves the hop limit to us.
+                if cur_hop_limit != 0 && cur_hop_limit != self.hop_limit.get() {
+                    self.hop_limit.set(cur_hop_limit);
+                }
+                self.process_router_advertisement(src, router_lifetime, &options)
+            }
+            _ => {}
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("ipv6 on {}", self.neighbors.interface())
+    }
+
+    /// Set up what's only done once, not again if the actor is restarted.
+    fn prepare(&mut self) {
+        self.hop_limit.report();
+        let mut rng = rand::thread_rng();
+
+        let link_local_address = self.interface_ids.address(
+            "fe80::".parse().unwrap(),
+            self.neighbors.interface(),
+            &mut rng,
+        );
+
+        self.add_address(
             link_local_address,
+            Lifetimes::default(),
+            rng.gen_range(Duration::ZERO..RFC4861_MAX_RTR_SOLICITATION_DELAY),
         );
 
+        if self.misbehavior.rogue_router.is_some() {
+            self.rogue_ra_queue.push_after(Duration::ZERO, ());
+        }
+    }
+
+    fn run(&mut self) {
+        let heartbeat = watchdog::register(&self.name());
+        let beats = channel::tick(watchdog::INTERVAL);
+        let resolution_checks = channel::tick(resolution::CHECK_INTERVAL);
+
         loop {
             select_queues! {
-                recv_queue(self.addr_maint_queue) -> addr => self.maintain_addr(addr.unwrap()).unwrap(),
-                recv(self.incoming_receiver) -> frame => {
-                    let packet = packet::packet(&frame.unwrap().payload).unwrap();
-
-                    if packet.next_header != packet::NextHeader::Protocol(ipv4::ProtocolNumber::Ipv6Icmp) {
-                        self.recv_map.dispatch(packet).unwrap();
-                        continue;
+                recv(beats) -> _ => heartbeat.beat(),
+                recv(resolution_checks) -> _ => self.solicit_due(),
+                recv_queue(self.addr_maint_queue) -> addr => {
+                    let addr = match addr {
+                        Ok(addr) => addr,
+                        Err(_) => return,
+                    };
+                    if let Err(e) = self.maintain_addr(addr) {
+                        warn!("maintaining {}: {}", addr, e);
+                    }
+                },
+                recv_queue(self.rogue_ra_queue) -> due => {
+                    if due.is_err() {
+                        return;
+                    }
+                    if let Err(e) = self.send_rogue_advertisement() {
+                        warn!("not sending rogue router advertisement: {}", e);
+                    }
+                },
+                recv_queue(self.answer_queue) -> answer => match answer {
+                    Ok((target, requester)) => self.answer_solicitation(target, requester),
+                    Err(_) => return,
+                },
+                recv(self.admin_states) -> state => match state {
+                    Ok(state) => self.admin_state_changed(state),
+                    Err(_) => self.admin_states = channel::never(),
+                },
+                recv_queue(self.mld_queue) -> pending => {
+                    let pending = match pending {
+                        Ok(pending) => pending,
+                        Err(_) => return,
+                    };
+                    if let Err(e) = self.send_mld_report(pending) {
+                        warn!("not answering multicast listener query: {}", e);
                     }
+                },
+                recv(self.upper_receiver) -> packet => {
+                    // The server, and so everyone who could send through us, is gone.
+                    let packet = match packet {
+                        Ok(packet) => packet,
+                        Err(_) => return,
+                    };
 
-                    let _icmpv6_packet = icmpv6::packet(
-                        &packet.payload,
-                        icmpv6::PseudoHeader {
-                            src: packet.src,
-                            dest: packet.dest,
-                            length: packet.payload.len() as u32,
-                        },
-                    )
-                        .unwrap();
+                    let result = if is_icmpv6_error(&packet) {
+                        self.send_error(packet)
+                    } else {
+                        self.send_ipv6(packet)
+                    };
+                    if let Err(e) = result {
+                        warn!("dropping outgoing packet: {}", e);
+                    }
+                },
+                recv_queue(self.router_maint_queue) -> _ => self.default_routers.expire(Instant::now()),
+                recv_queue(self.path_mtu_maint_queue) -> _ => self.path_mtus.write().unwrap().expire(Instant::now()),
+                recv_queue(self.dns_maint_queue) -> _ => self.dns.write().unwrap().expire(Instant::now()),
+                // The interface is gone, so nothing more will arrive.
+                recv(self.incoming_receiver) -> frame => match frame {
+                    Ok(frame) => self.receive(frame),
+                    Err(_) => return,
                 },
             }
         }
     }
+
+    /// Handle a frame from the interface, dropping anything malformed.
+    fn receive(&mut self, frame: ether::Frame) {
+        supervisor::handling(&frame.payload);
+        let packet = match packet::packet(&frame.payload) {
+            Ok(packet) => packet,
+            Err(e) => match e.downcast_ref::<packet::UnrecognizedOption>() {
+                Some(option) => {
+                    self.discard_unrecognized(&frame.payload, option);
+                    return;
+                }
+                None => {
+                    validation::record_drop(validation::Failure::Malformed);
+                    return;
+                }
+            },
+        };
+
+        if let Err(failure) = validation::check_header(&packet) {
+            validation::record_drop(failure);
+            return;
+        }
+
+        if packet.next_header != packet::NextHeader::Protocol(ipv4::ProtocolNumber::Ipv6Icmp) {
+            if !self.is_recognized(packet.next_header) {
+                self.report_problem(
+                    &frame.payload,
+                    &packet::Problem {
+                        code: icmpv6::PROBLEM_UNRECOGNIZED_NEXT_HEADER,
+                        pointer: packet::upper_layer_pointer(&frame.payload),
+                        src: packet.src,
+                        dest: packet.dest,
+                        report_multicast: false,
+                    },
+                );
+                return;
+            }
+
+            let src = packet.src;
+            if let Err(e) = self.recv_map.dispatch(packet) {
+                debug!("dropping packet from {}: {}", src, e);
+            }
+            return;
+        }
+
+        let icmpv6_packet = icmpv6::packet(
+            &packet.payload,
+            icmpv6::PseudoHeader {
+                src: packet.src,
+                dest: packet.dest,
+                length: packet.payload.len() as u32,
+            },
+        );
+        // Includes types we don't handle, which are discarded like malformed ones.
+        let icmpv6_packet = match icmpv6_packet {
+            Ok(icmpv6_packet) => icmpv6_packet,
+            Err(_) => {
+                validation::record_drop(validation::Failure::Malformed);
+                return;
+            }
+        };
+
+        if let Err(failure) = validation::check_message(packet.src, packet.dest, &icmpv6_packet) {
+            validation::record_drop(failure);
+            return;
+        }
+
+        self.process_icmpv6(packet.src, icmpv6_packet);
+    }
+}
+
+/// Advertisement of `target` at `ether_address` that nobody asked for, overriding cached entries.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-7.2.6
+fn unsolicited_advertisement(target: Address, ether_address: ether::Address) -> icmpv6::Packet {
+    icmpv6::Packet::NeighborAdvertisement(icmpv6::NeighborAdvertisement {
+        src: target,
+        router: false,
+        solicited: false,
+        override_flag: true,
+        options: vec![icmpv6::NeighborSolicitationOption::TargetLinkLayerAddress(
+            ether_address,
+        )],
+    })
+}
+
+/// Advertisement of `target` at `ether_address` answering a solicitation.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-7.2.4
+fn solicited_advertisement(target: Address, ether_address: ether::Address) -> icmpv6::Packet {
+    icmpv6::Packet::NeighborAdvertisement(icmpv6::NeighborAdvertisement {
+        src: target,
+        router: false,
+        solicited: true,
+        override_flag: true,
+        options: vec![icmpv6::NeighborSolicitationOption::TargetLinkLayerAddress(
+            ether_address,
+        )],
+    })
+}
+
+fn build_icmpv6(src: Address, dest: Address, packet: icmpv6::Packet) -> packet::Packet {
+    let builder = packet::Packet::builder()
+        .protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+        .hop_limit(0xff)
+        .src(src)
+        .dest(dest)
+        .payload(packet.encode(icmpv6::PseudoHeader {
+            src,
+            dest,
+            length: 0,
+        }));
+
+    let builder = match packet {
+        icmpv6::Packet::MldV2Report(_) => {
+            builder.extension_header(packet::ExtensionHeader::HopByHopOptions(vec![
+                packet::HopByHopOption::RouterAlert(packet::RouterAlertType::Mld),
+            ]))
+        }
+        _ => builder,
+    };
+
+    builder.build()
+}
+
+/// Whether `packet` carries an ICMPv6 error message, rather than an informational one.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4443#section-2.1
+fn is_icmpv6_error(packet: &packet::Packet) -> bool {
+    packet.next_header == packet::NextHeader::Protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+        && packet.payload.first().is_some_and(|t| t & 0x80 == 0)
+}
+
+/// ICMPv6 port unreachable error for `invoking_packet`, or `None` where one must not be sent.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4443#section-2.4
+pub fn port_unreachable(invoking_packet: &packet::Packet, hop_limit: u8) -> Option<packet::Packet> {
+    if invoking_packet.dest.is_multicast() || invoking_packet.src == Address::default() {
+        return None;
+    }
+
+    let src = invoking_packet.dest;
+    let dest = invoking_packet.src;
+    let mut invoking_bytes = invoking_packet.encode();
+    // Leave room for our IPv6 and ICMPv6 headers.
+    invoking_bytes.truncate(path_mtu::MINIMUM_MTU - 48);
+
+    Some(
+        packet::Packet::builder()
+            .protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+            .hop_limit(hop_limit)
+            .src(src)
+            .dest(dest)
+            .payload(
+                icmpv6::Packet::DestinationUnreachable {
+                    code: icmpv6::UNREACHABLE_PORT,
+                    invoking_packet: invoking_bytes,
+                }
+                .encode(icmpv6::PseudoHeader {
+                    src,
+                    dest,
+                    length: 0,
+                }),
+            )
+            .build(),
+    )
+}
+
+/// ICMPv6 parameter problem error for `problem` with `invoking_bytes`, or `None` where one must
+/// not be sent.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4443#section-2.4
+pub fn parameter_problem(
+    invoking_bytes: &[u8],
+    problem: &packet::Problem,
+    hop_limit: u8,
+) -> Option<packet::Packet> {
+    if (problem.dest.is_multicast() && !problem.report_multicast)
+        || problem.src.is_multicast()
+        || problem.src == Address::default()
+    {
+        return None;
+    }
+
+    let src = problem.dest;
+    let dest = problem.src;
+    // Leave room for our IPv6 and ICMPv6 headers.
+    let invoking_len = invoking_bytes.len().min(path_mtu::MINIMUM_MTU - 48);
+
+    Some(
+        packet::Packet::builder()
+            .protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+            .hop_limit(hop_limit)
+            .src(src)
+            .dest(dest)
+            .payload(
+                icmpv6::Packet::ParameterProblem {
+                    code: problem.code,
+                    pointer: problem.pointer,
+                    invoking_packet: invoking_bytes[..invoking_len].to_vec(),
+                }
+                .encode(icmpv6::PseudoHeader {
+                    src,
+                    dest,
+                    length: 0,
+                }),
+            )
+            .build(),
+    )
 }
 
 pub struct Server {
     actor: Option<Actor>,
+    upper_sender: channel::Sender<packet::Packet>,
     recv_map: Arc<RecvSenderMap<packet::Packet>>,
+    path_mtus: Arc<RwLock<PathMtuCache>>,
+    dns: Arc<RwLock<DnsConfig>>,
+    hop_limit: HopLimitHandle,
 }
 
 impl Server {
-    pub fn new(ether_server: &mut impl ether::Server) -> AHResult<Self> {
+    pub fn new(
+        ether_server: &mut impl ether::Server,
+        neighbors: neighbor::Table,
+    ) -> AHResult<Self> {
         let (incoming_sender, incoming_receiver) = channel::bounded(1024);
-        ether_server.register(ether::Type::Ipv6, incoming_sender);
+        ether_server.register_with_backpressure(
+            ether::Type::Ipv6,
+            incoming_sender,
+            Backpressure::DropOldest(incoming_receiver.clone()),
+        );
 
-        let recv_map = Arc::new(RecvSenderMap::new());
+        let (upper_sender, upper_receiver) = channel::bounded(1024);
+        let recv_map = Arc::new(RecvSenderMap::new("ipv6"));
+        let path_mtus = Arc::new(RwLock::new(PathMtuCache::new(ether::MTU)));
+
+        let actor = Actor::new(
+            ether_server.if_hwaddr()?,
+            incoming_receiver,
+            ether_server.writer(),
+            upper_receiver,
+            recv_map.clone(),
+            path_mtus.clone(),
+            neighbors,
+        );
+        let dns = actor.dns.clone();
+        let hop_limit = actor.hop_limit.clone();
 
         Ok(Self {
-            actor: Some(Actor::new(
-                ether_server.if_hwaddr()?,
-                incoming_receiver,
-                ether_server.writer(),
-                recv_map.clone(),
-            )),
+            actor: Some(actor),
+            upper_sender,
             recv_map,
+            path_mtus,
+            dns,
+            hop_limit,
         })
     }
 
+    /// Add a statically-configured address, which will go through duplicate address detection
+    /// once the server is started.
+    pub fn add_address(&mut self, address: Address, lifetimes: Lifetimes) {
+        self.actor
+            .as_mut()
+            .expect("addresses must be added before the server is started")
+            .add_address(
+                address,
+                lifetimes,
+                rand::thread_rng().gen_range(Duration::ZERO..RFC4861_MAX_RTR_SOLICITATION_DELAY),
+            );
+    }
+
+    pub fn set_misbehavior(&mut self, misbehavior: Misbehavior) {
+        self.actor
+            .as_mut()
+            .expect("misbehavior must be set before the server is started")
+            .misbehavior = misbehavior;
+    }
+
+    /// Answer for every address in `prefix`, so services bound to it get what's sent to any of
+    /// them.
+    pub fn add_prefix(&mut self, prefix: Prefix) {
+        self.actor
+            .as_mut()
+            .expect("prefixes must be added before the server is started")
+            .prefixes
+            .push(prefix);
+    }
+
+    /// Answer solicitations for the policy's address as it says, rather than right away.
+    pub fn set_answer_policy(&mut self, policy: answer_policy::Policy) {
+        self.actor
+            .as_mut()
+            .expect("answer policies must be set before the server is started")
+            .answer_policies
+            .set(policy);
+    }
+
+    /// Hop limit for everything sent besides NDP, until a router advertises another.
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.hop_limit.set(hop_limit);
+    }
+
+    /// Limits on the rate of ICMPv6 errors sent, whoever generates them.
+    pub fn set_error_rate_limit(&mut self, config: icmp_limit::Config) {
+        self.actor
+            .as_mut()
+            .expect("error rate limits must be set before the server is started")
+            .error_limiter = icmp_limit::Limiter::new(config, Instant::now());
+    }
+
+    /// How to pick the interface identifier of the link-local address.
+    pub fn set_interface_ids(&mut self, interface_ids: InterfaceIds) {
+        self.actor
+            .as_mut()
+            .expect("interface identifiers must be set before the server is started")
+            .interface_ids = interface_ids;
+    }
+
+    /// Whether to announce addresses with unsolicited neighbor advertisements once they're
+    /// configured and whenever the interface comes back up.
+    pub fn set_unsolicited_advertisements(&mut self, enabled: bool) {
+        self.actor
+            .as_mut()
+            .expect("unsolicited advertisements must be set before the server is started")
+            .unsolicited_advertisements = enabled;
+    }
+
+    /// Follow the interface's administrative state, as from `ether::AdminHandle::watch`.
+    pub fn watch_admin_state(&mut self, states: channel::Receiver<ether::AdminState>) {
+        self.actor
+            .as_mut()
+            .expect("admin state must be watched before the server is started")
+            .admin_states = states;
+    }
+
+    /// Channel for upper layers to send fully-formed packets on.
+    pub fn writer(&self) -> channel::Sender<packet::Packet> {
+        self.upper_sender.clone()
+    }
+
+    pub fn hop_limit(&self) -> HopLimitHandle {
+        self.hop_limit.clone()
+    }
+
+    pub fn path_mtus(&self) -> PathMtuHandle {
+        PathMtuHandle(self.path_mtus.clone())
+    }
+
+    /// DNS configuration learned from router advertisements.
+    pub fn dns(&self) -> DnsHandle {
+        DnsHandle(self.dns.clone())
+    }
+
     pub fn start(&mut self) {
         let mut actor = self.actor.take().unwrap();
+        actor.prepare();
 
-        thread::spawn(move || loop {
-            actor.run();
-        });
+        supervisor::spawn(actor.name(), move || actor.run());
     }
 }
 
@@ -259,3 +1226,219 @@ impl KeyedDispatcher for Server {
         &self.recv_map
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routers_set_the_hop_limit() {
+        let mut actor = Actor::new(
+            ether::Address([2, 0, 0, 0, 0, 1]),
+            channel::never(),
+            channel::unbounded().0,
+            channel::never(),
+            Arc::new(RecvSenderMap::new("ipv6")),
+            Arc::new(RwLock::new(PathMtuCache::new(ether::MTU))),
+            neighbor::Table::new("test"),
+        );
+        let advertisement = |cur_hop_limit| icmpv6::Packet::RouterAdvertisement {
+            cur_hop_limit,
+            managed: false,
+            other: false,
+            router_lifetime: 0,
+            reachable_time: 0,
+            retrans_timer: 0,
+            options: vec![],
+        };
+        let router: Address = "fe80::1".parse().unwrap();
+
+        assert_eq!(actor.hop_limit.get(), DEFAULT_HOP_LIMIT);
+        actor.process_icmpv6(router, advertisement(128));
+        assert_eq!(actor.hop_limit.get(), 128);
+        // Zero is unspecified, leaving the hop limit as it was.
+        actor.process_icmpv6(router, advertisement(0));
+        assert_eq!(actor.hop_limit.get(), 128);
+    }
+
+    #[test]
+    fn prefix_lifetimes_deprecate_addresses() {
+        let mut actor = Actor::new(
+            ether::Address([2, 0, 0, 0, 0, 1]),
+            channel::never(),
+            channel::unbounded().0,
+            channel::never(),
+            Arc::new(RecvSenderMap::new("ipv6")),
+            Arc::new(RwLock::new(PathMtuCache::new(ether::MTU))),
+            neighbor::Table::new("test"),
+        );
+        let address: Address = "2001:db8::1".parse().unwrap();
+        actor.add_address(address, Lifetimes::default(), Duration::ZERO);
+        actor.addresses[0].set_state(InterfaceAddressState::Valid);
+
+        let advertisement = |preferred_lifetime| icmpv6::Packet::RouterAdvertisement {
+            cur_hop_limit: 0,
+            managed: false,
+            other: false,
+            router_lifetime: 0,
+            reachable_time: 0,
+            retrans_timer: 0,
+            options: vec![icmpv6::NeighborSolicitationOption::PrefixInformation(
+                icmpv6::PrefixInformation {
+                    prefix_length: 64,
+                    on_link: true,
+                    autonomous: true,
+                    valid_lifetime: 600,
+                    preferred_lifetime,
+                    prefix: "2001:db8::".parse().unwrap(),
+                },
+            )],
+        };
+        let router: Address = "fe80::1".parse().unwrap();
+
+        actor.process_icmpv6(router, advertisement(0));
+        actor.maintain_addr(address).unwrap();
+        assert_eq!(
+            actor.addresses[0].state(),
+            InterfaceAddressState::Deprecated
+        );
+        // Too short a valid lifetime to take from an unauthenticated router.
+        assert!(
+            actor.addresses[0].remaining_valid(Instant::now()) > Some(Duration::from_secs(600))
+        );
+
+        actor.process_icmpv6(router, advertisement(300));
+        assert_eq!(actor.addresses[0].state(), InterfaceAddressState::Valid);
+    }
+
+    #[test]
+    fn malformed_packets_are_dropped() {
+        let mut actor = Actor::new(
+            ether::Address([2, 0, 0, 0, 0, 1]),
+            channel::never(),
+            channel::unbounded().0,
+            channel::never(),
+            Arc::new(RecvSenderMap::new("ipv6")),
+            Arc::new(RwLock::new(PathMtuCache::new(ether::MTU))),
+            neighbor::Table::new("test"),
+        );
+        let malformed = || {
+            status::snapshot()
+                .ndp_drops
+                .get(&validation::Failure::Malformed.to_string())
+                .copied()
+                .unwrap_or(0)
+        };
+
+        let mut receive = |payload| {
+            let before = malformed();
+            actor.receive(ether::Frame {
+                dest: ether::Address([2, 0, 0, 0, 0, 1]),
+                src: ether::Address([2, 0, 0, 0, 0, 2]),
+                ethertype: ether::Type::Ipv6,
+                payload,
+                received: None,
+            });
+            assert!(malformed() > before);
+        };
+
+        // Cut off partway through the header.
+        receive(vec![0x60, 0, 0, 0, 0, 8, 17]);
+        // Over our limits, with a jumbo payload option claiming a megabyte.
+        receive(
+            hex::decode(concat!(
+                "6000000000000040fe800000000000000000000000000001ff020000000000000000000000000001",
+                "1100c204001000000000000000000000",
+            ))
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn solicitations_are_answered_by_policy() {
+        let (outgoing_sender, outgoing) = channel::unbounded();
+        let mut actor = Actor::new(
+            ether::Address([2, 0, 0, 0, 0, 1]),
+            channel::never(),
+            outgoing_sender,
+            channel::never(),
+            Arc::new(RecvSenderMap::new("ipv6")),
+            Arc::new(RwLock::new(PathMtuCache::new(ether::MTU))),
+            neighbor::Table::new("test"),
+        );
+        let address: Address = "fe80::5".parse().unwrap();
+        let mut interface_address = InterfaceAddress::new(address, Lifetimes::default());
+        interface_address.set_state(InterfaceAddressState::Valid);
+        actor.addresses.push(interface_address);
+        actor.answer_policies.set(answer_policy::Policy {
+            address: IpAddr::from(address),
+            delay_ms: 0,
+            requesters: vec!["fe80::2".parse().unwrap()],
+            drop_every: 2,
+        });
+
+        let solicit = |actor: &mut Actor, requester: &str| {
+            actor.process_icmpv6(
+                requester.parse().unwrap(),
+                icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+                    dest: address,
+                    options: vec![icmpv6::NeighborSolicitationOption::SourceLinkLayerAddress(
+                        ether::Address([2, 0, 0, 0, 0, 2]),
+                    )],
+                }),
+            )
+        };
+        solicit(&mut actor, "fe80::2");
+        solicit(&mut actor, "fe80::2");
+        solicit(&mut actor, "fe80::3");
+
+        let answers: Vec<_> = outgoing.try_iter().collect();
+        assert_eq!(answers.len(), 1);
+        let answer = packet::packet(&answers[0].payload).unwrap();
+        assert_eq!(answer.src, address);
+        assert_eq!(answer.dest, "fe80::2".parse().unwrap());
+    }
+
+    #[test]
+    fn packets_wait_for_their_next_hop_to_be_resolved() {
+        let (outgoing_sender, outgoing) = channel::unbounded();
+        let mut actor = Actor::new(
+            ether::Address([2, 0, 0, 0, 0, 1]),
+            channel::never(),
+            outgoing_sender,
+            channel::never(),
+            Arc::new(RecvSenderMap::new("ipv6")),
+            Arc::new(RwLock::new(PathMtuCache::new(ether::MTU))),
+            neighbor::Table::new("test"),
+        );
+        let address: Address = "fe80::1".parse().unwrap();
+        let peer: Address = "fe80::2".parse().unwrap();
+        let peer_ether = ether::Address([2, 0, 0, 0, 0, 2]);
+
+        let packet = || {
+            build_icmpv6(
+                address,
+                peer,
+                unsolicited_advertisement(address, actor.src_ether),
+            )
+        };
+        actor.send_ipv6(packet()).unwrap();
+        actor.send_ipv6(packet()).unwrap();
+
+        // Only one solicitation, however many packets are waiting.
+        let sent: Vec<_> = outgoing.try_iter().collect();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].dest,
+            peer.solicited_nodes_multicast().multicast_ether_dest()
+        );
+        let solicitation = packet::packet(&sent[0].payload).unwrap();
+        assert_eq!(solicitation.src, address);
+        assert_eq!(solicitation.dest, peer.solicited_nodes_multicast());
+
+        actor.process_icmpv6(peer, solicited_advertisement(peer, peer_ether));
+        let sent: Vec<_> = outgoing.try_iter().collect();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|frame| frame.dest == peer_ether));
+    }
+}
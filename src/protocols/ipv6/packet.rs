@@ -1,24 +1,26 @@
-use anyhow::{anyhow, Result as AHResult};
+use anyhow::Result as AHResult;
 use nom::{
     bits, bytes,
-    combinator::{eof, map_res},
-    multi::many0,
-    number::complete::{be_u16, be_u8},
-    sequence::{terminated, tuple},
+    combinator::map_res,
+    number::complete::{be_u16, be_u32, be_u8},
+    sequence::tuple,
 };
 use std::convert::TryFrom;
 
 use crate::protocols::encdec::{round_up_to_next, EncodeTo};
 use crate::protocols::ipv4;
+use crate::protocols::limits::{Exceeded, Limit};
 use crate::protocols::utils::DispatchKeyed;
 use crate::{encode, encode_to, proto_enum_with_unknown, try_parse};
 
 use super::address::{address, Address};
+use super::icmpv6;
 
 const _MULTICAST_ALL_NODES: Address = Address([0xff01, 0, 0, 0, 0, 0, 0, 0x1]);
 
-#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
 pub enum NextHeader {
+    #[default]
     Unset,
     HopByHopOptions,
     Protocol(ipv4::ProtocolNumber),
@@ -30,7 +32,7 @@ impl std::convert::TryFrom<u8> for NextHeader {
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             0 => Ok(NextHeader::HopByHopOptions),
-            _ => ipv4::ProtocolNumber::try_from(value).map(|p| NextHeader::Protocol(p)),
+            _ => ipv4::ProtocolNumber::try_from(value).map(NextHeader::Protocol),
         }
     }
 }
@@ -59,16 +61,11 @@ impl EncodeTo for NextHeader {
     }
 }
 
-impl Default for NextHeader {
-    fn default() -> Self {
-        NextHeader::Unset
-    }
-}
-
 proto_enum_with_unknown!(HopByHopOptionType, u8, {
     Pad1 = 0,
     PadN = 1,
     RouterAlert = 5,
+    JumboPayload = 0xc2,
 });
 
 proto_enum_with_unknown!(RouterAlertType, u16, {
@@ -77,9 +74,12 @@ proto_enum_with_unknown!(RouterAlertType, u16, {
     ActiveNetworks = 2,
 });
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum HopByHopOption {
     RouterAlert(RouterAlertType),
+    /// Length of everything after the fixed header, for packets too long to give it there.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc2675#section-2
+    JumboPayload(u32),
 }
 
 impl EncodeTo for HopByHopOption {
@@ -87,6 +87,7 @@ impl EncodeTo for HopByHopOption {
         1 + 1
             + match self {
                 HopByHopOption::RouterAlert(_) => 2,
+                HopByHopOption::JumboPayload(_) => 4,
             }
     }
 
@@ -95,37 +96,150 @@ impl EncodeTo for HopByHopOption {
             HopByHopOption::RouterAlert(t) => {
                 encode_to!(buf, HopByHopOptionType::RouterAlert, 2u8, t)
             }
+            HopByHopOption::JumboPayload(length) => {
+                encode_to!(buf, HopByHopOptionType::JumboPayload, 4u8, length)
+            }
+        };
+    }
+}
+
+/// A received packet that must be discarded and answered with an ICMPv6 Parameter Problem.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4443#section-3.4
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Problem {
+    pub code: u8,
+    /// Offset from the start of the packet of the byte at fault.
+    pub pointer: u32,
+    pub src: Address,
+    pub dest: Address,
+    /// Whether it's answered even when sent to a multicast address.
+    pub report_multicast: bool,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "parameter problem (code {}) at byte {}",
+            self.code, self.pointer
+        )
+    }
+}
+
+/// What to do with a packet carrying an option we don't recognize, from the two high-order bits
+/// of its type.
+// Ref: https://datatracker.ietf.org/doc/html/rfc8200#section-4.2
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnrecognizedOptionAction {
+    Skip,
+    Discard,
+    /// Discard and send a Parameter Problem, even to a multicast destination.
+    Report,
+    /// Discard and send a Parameter Problem, unless sent to a multicast destination.
+    ReportUnlessMulticast,
+}
+
+impl UnrecognizedOptionAction {
+    pub fn of(option_type: u8) -> Self {
+        match option_type >> 6 {
+            0b00 => Self::Skip,
+            0b01 => Self::Discard,
+            0b10 => Self::Report,
+            _ => Self::ReportUnlessMulticast,
+        }
+    }
+}
+
+/// A received packet discarded for carrying an option we don't recognize.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UnrecognizedOption {
+    pub option_type: u8,
+    /// Offset from the start of the packet of the option.
+    pub pointer: u32,
+    pub src: Address,
+    pub dest: Address,
+}
+
+impl UnrecognizedOption {
+    /// The Parameter Problem to answer with, if the option's type asks for one.
+    pub fn problem(&self) -> Option<Problem> {
+        let report_multicast = match UnrecognizedOptionAction::of(self.option_type) {
+            UnrecognizedOptionAction::Skip | UnrecognizedOptionAction::Discard => return None,
+            UnrecognizedOptionAction::Report => true,
+            UnrecognizedOptionAction::ReportUnlessMulticast => false,
         };
+
+        Some(Problem {
+            code: icmpv6::PROBLEM_UNRECOGNIZED_OPTION,
+            pointer: self.pointer,
+            src: self.src,
+            dest: self.dest,
+            report_multicast,
+        })
+    }
+}
+
+impl std::fmt::Display for UnrecognizedOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "unrecognized hop-by-hop option {:#04x} at byte {}",
+            self.option_type, self.pointer
+        )
     }
 }
 
-fn hop_by_hop_option<'a>(input: &'a [u8]) -> nom::IResult<&'a [u8], Option<HopByHopOption>> {
+impl std::error::Error for UnrecognizedOption {}
+
+/// Why a packet with headers we could otherwise parse is discarded.
+enum Discard {
+    /// Without its source and destination set, which the extension header doesn't know.
+    Unrecognized(UnrecognizedOption),
+    Exceeded(Exceeded),
+}
+
+enum ParsedOption {
+    Option(HopByHopOption),
+    Ignored,
+    Unrecognized(u8),
+}
+
+fn hop_by_hop_option(input: &[u8]) -> nom::IResult<&[u8], ParsedOption> {
     let (input, option_type) = map_res(be_u8, HopByHopOptionType::try_from)(input)?;
 
-    if option_type == HopByHopOptionType::PadN {
-        let (input, pad_len) = be_u8(input)?;
-        let input = &input[(pad_len as usize)..];
-        return Ok((input, None));
-    } else if option_type == HopByHopOptionType::Pad1 {
-        return Ok((input, None));
+    if option_type == HopByHopOptionType::Pad1 {
+        return Ok((input, ParsedOption::Ignored));
     }
 
     let (input, option_len) = be_u8(input)?;
     let (input, option_bytes) = bytes::complete::take(option_len)(input)?;
 
     let option = match option_type {
+        HopByHopOptionType::PadN => return Ok((input, ParsedOption::Ignored)),
         HopByHopOptionType::RouterAlert => {
             let (_, router_alert_type) = map_res(be_u16, RouterAlertType::try_from)(option_bytes)?;
 
             HopByHopOption::RouterAlert(router_alert_type)
         }
-        _ => todo!("unhandled option type: {}", option_type),
+        HopByHopOptionType::JumboPayload => {
+            let (_, length) = be_u32(option_bytes)?;
+
+            HopByHopOption::JumboPayload(length)
+        }
+        HopByHopOptionType::Pad1 => unreachable!(),
+        HopByHopOptionType::Unknown(t) => {
+            let option = match UnrecognizedOptionAction::of(t) {
+                UnrecognizedOptionAction::Skip => ParsedOption::Ignored,
+                _ => ParsedOption::Unrecognized(t),
+            };
+            return Ok((input, option));
+        }
     };
 
-    Ok((input, Some(option)))
+    Ok((input, ParsedOption::Option(option)))
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ExtensionHeader {
     HopByHopOptions(Vec<HopByHopOption>),
 }
@@ -169,16 +283,29 @@ impl ExtensionHeader {
             ExtensionHeader::HopByHopOptions(_) => NextHeader::HopByHopOptions,
         }
     }
+
+    fn jumbo_payload_length(&self) -> Option<u32> {
+        match self {
+            ExtensionHeader::HopByHopOptions(options) => options.iter().find_map(|o| match o {
+                HopByHopOption::JumboPayload(length) => Some(*length),
+                _ => None,
+            }),
+        }
+    }
 }
 
-fn extension_header<'a>(
-    input: &'a [u8],
+/// Parse the extension header `cur_next_header` says comes next, which starts `offset` bytes into
+/// the packet, if it is one.
+#[allow(clippy::type_complexity)]
+fn extension_header(
+    input: &[u8],
     cur_next_header: NextHeader,
-) -> nom::IResult<&'a [u8], Option<(NextHeader, u16, ExtensionHeader)>> {
+    offset: usize,
+) -> nom::IResult<&[u8], Result<Option<(NextHeader, u16, ExtensionHeader)>, Discard>> {
     match cur_next_header {
         NextHeader::HopByHopOptions => {}
         _ => {
-            return Ok((input, None));
+            return Ok((input, Ok(None)));
         }
     };
 
@@ -189,16 +316,63 @@ fn extension_header<'a>(
 
     let header = match cur_next_header {
         NextHeader::HopByHopOptions => {
-            let (_, options) = terminated(many0(hop_by_hop_option), eof)(header_bytes)?;
-            ExtensionHeader::HopByHopOptions(options.into_iter().filter_map(|o| o).collect())
+            let mut options = Vec::new();
+            let mut remaining = header_bytes;
+            let mut num_options = 0;
+
+            while !remaining.is_empty() {
+                num_options += 1;
+                if let Err(exceeded) = Limit::OptionsPerHeader.check(num_options) {
+                    return Ok((input, Err(Discard::Exceeded(exceeded))));
+                }
+
+                let option_offset = offset + 2 + header_bytes.len() - remaining.len();
+                let (rest, option) = hop_by_hop_option(remaining)?;
+
+                match option {
+                    ParsedOption::Option(option) => options.push(option),
+                    ParsedOption::Ignored => {}
+                    ParsedOption::Unrecognized(option_type) => {
+                        return Ok((
+                            input,
+                            Err(Discard::Unrecognized(UnrecognizedOption {
+                                option_type,
+                                pointer: option_offset as u32,
+                                src: Address::default(),
+                                dest: Address::default(),
+                            })),
+                        ));
+                    }
+                }
+                remaining = rest;
+            }
+
+            ExtensionHeader::HopByHopOptions(options)
         }
         _ => unreachable!(),
     };
 
-    Ok((input, Some((next_header, header_len, header))))
+    Ok((input, Ok(Some((next_header, header_len, header)))))
 }
 
-#[derive(Debug, Default, PartialEq)]
+/// Offset of the next header field naming the upper-layer protocol of `input`, a packet `packet`
+/// parses: in the fixed header, or else the last extension header.
+pub fn upper_layer_pointer(input: &[u8]) -> u32 {
+    let mut pointer = 6;
+    let mut offset = 40;
+
+    while input.get(pointer) == Some(&0) {
+        pointer = offset;
+        offset += match input.get(offset + 1) {
+            Some(&len) => (1 + len as usize) * 8,
+            None => break,
+        };
+    }
+
+    pointer as u32
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Packet {
     pub traffic_class: u8,
     pub flow_label: u32,
@@ -215,9 +389,17 @@ impl Packet {
         PacketBuilder(Self::default())
     }
 
+    pub fn dscp(&self) -> u8 {
+        ipv4::dscp(self.traffic_class)
+    }
+
+    pub fn ecn(&self) -> ipv4::Ecn {
+        ipv4::Ecn::of(self.traffic_class)
+    }
+
     fn encode_extension_headers(&self, final_next_header: NextHeader) -> Vec<u8> {
         let mut result = Vec::new();
-        if self.extension_headers.len() == 0 {
+        if self.extension_headers.is_empty() {
             return result;
         }
 
@@ -242,19 +424,64 @@ impl Packet {
         result
     }
 
+    /// Length of everything after the fixed header.
+    fn encoded_payload_len(&self) -> usize {
+        self.extension_headers
+            .iter()
+            .map(|header| 1 + header.encoded_len())
+            .sum::<usize>()
+            + self.payload.len()
+    }
+
+    /// This packet with a Jumbo Payload option giving its length first in its hop-by-hop options,
+    /// if it's too long to go without one and doesn't already have it.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc2675#section-2
+    fn as_jumbogram(&self) -> Option<Packet> {
+        if self.encoded_payload_len() <= u16::MAX as usize {
+            return None;
+        }
+
+        let mut packet = self.clone();
+        match packet.extension_headers.first_mut() {
+            Some(ExtensionHeader::HopByHopOptions(options)) => {
+                options.retain(|o| !matches!(o, HopByHopOption::JumboPayload(_)));
+                options.insert(0, HopByHopOption::JumboPayload(0));
+            }
+            _ => packet.extension_headers.insert(
+                0,
+                ExtensionHeader::HopByHopOptions(vec![HopByHopOption::JumboPayload(0)]),
+            ),
+        }
+
+        let length = packet.encoded_payload_len() as u32;
+        if let Some(ExtensionHeader::HopByHopOptions(options)) =
+            packet.extension_headers.first_mut()
+        {
+            options[0] = HopByHopOption::JumboPayload(length);
+        }
+
+        (packet != *self).then_some(packet)
+    }
+
+    /// Encode this packet, as a jumbogram if it's longer than the payload length field allows.
     pub fn encode(&self) -> Vec<u8> {
+        if let Some(jumbogram) = self.as_jumbogram() {
+            return jumbogram.encode();
+        }
+
         let prelude = (6u32 << 28) | ((self.traffic_class as u32) << 20) | self.flow_label;
 
         let first_next_header = self
             .extension_headers
-            .get(0)
+            .first()
             .map_or(self.next_header, |h| h.next_header());
 
         let encoded_extension_headers = self.encode_extension_headers(self.next_header);
 
         encode!(
             prelude,
-            (encoded_extension_headers.len() + self.payload.len()) as u16,
+            // Jumbograms give theirs in a Jumbo Payload option instead.
+            u16::try_from(encoded_extension_headers.len() + self.payload.len()).unwrap_or(0),
             first_next_header,
             self.hop_limit,
             self.src,
@@ -265,8 +492,13 @@ impl Packet {
     }
 }
 
+/// Parse a packet, failing with an `UnrecognizedOption` if one of its options says to discard it,
+/// or `Exceeded` if it's beyond our limits.
 pub fn packet(input: &[u8]) -> AHResult<Packet> {
+    Limit::PacketSize.check(input.len())?;
+
     try_parse!(
+        input,
         {
             let (input, (_, traffic_class, flow_label)) =
                 bits::bits::<_, _, nom::error::Error<(&[u8], usize)>, _, _>(tuple((
@@ -274,18 +506,55 @@ pub fn packet(input: &[u8]) -> AHResult<Packet> {
                     bits::complete::take(8usize),
                     bits::complete::take(20usize),
                 )))(input)?;
-            let (input, mut payload_length) = be_u16(input)?;
+            let (input, payload_length) = be_u16(input)?;
+            let mut payload_length = payload_length as u32;
             let (input, mut next_header) = map_res(be_u8, NextHeader::try_from)(input)?;
             let (input, hop_limit) = be_u8(input)?;
             let (input, src) = address(input)?;
             let (mut input, dest) = address(input)?;
 
             let mut extension_headers = Vec::new();
-
-            while let (new_input, Some((new_next_header, num_header_bytes, header))) =
-                extension_header(input, next_header)?
-            {
-                payload_length -= num_header_bytes as u16;
+            let mut offset = 40;
+
+            loop {
+                let (new_input, (new_next_header, num_header_bytes, header)) =
+                    match extension_header(input, next_header, offset)? {
+                        (new_input, Ok(Some(parsed))) => (new_input, parsed),
+                        (_, Ok(None)) => break,
+                        (input, Err(Discard::Unrecognized(option))) => {
+                            return Ok((
+                                input,
+                                Err(anyhow::Error::new(UnrecognizedOption {
+                                    src,
+                                    dest,
+                                    ..option
+                                })),
+                            ))
+                        }
+                        (input, Err(Discard::Exceeded(exceeded))) => {
+                            return Ok((input, Err(anyhow::Error::new(exceeded))))
+                        }
+                    };
+
+                if let Err(exceeded) = Limit::ExtensionHeaders.check(extension_headers.len() + 1) {
+                    return Ok((input, Err(anyhow::Error::new(exceeded))));
+                }
+                if payload_length == 0 {
+                    payload_length = header.jumbo_payload_length().unwrap_or(0);
+                    if let Err(exceeded) = Limit::PacketSize.check(payload_length as usize) {
+                        return Ok((input, Err(anyhow::Error::new(exceeded))));
+                    }
+                }
+                payload_length = match payload_length.checked_sub(num_header_bytes as u32) {
+                    Some(length) => length,
+                    None => {
+                        return Err(nom::Err::Error(nom::error::make_error(
+                            input,
+                            nom::error::ErrorKind::LengthValue,
+                        )))
+                    }
+                };
+                offset += num_header_bytes as usize;
                 extension_headers.push(header);
                 input = new_input;
                 next_header = new_next_header;
@@ -295,7 +564,7 @@ pub fn packet(input: &[u8]) -> AHResult<Packet> {
 
             Ok((
                 input,
-                Packet {
+                Ok(Packet {
                     traffic_class,
                     flow_label,
                     next_header,
@@ -304,11 +573,50 @@ pub fn packet(input: &[u8]) -> AHResult<Packet> {
                     dest,
                     extension_headers,
                     payload: payload.to_vec(),
-                },
+                }),
             ))
         },
         "parsing ipv6 packet failed: {}"
-    )
+    )?
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PseudoHeader {
+    pub src: Address,
+    pub dest: Address,
+    pub length: u32,
+}
+
+impl PseudoHeader {
+    /// Compute the upper-layer checksum of `input` over this pseudo-header.
+    pub fn checksum(&self, protocol: ipv4::ProtocolNumber, input: &[u8]) -> u16 {
+        // RFC 8200 § 8.1
+        let header = encode!(self.src, self.dest, self.length, 0u16, 0u8, protocol);
+
+        // RFC 4443 § 2.3
+        let mut checksum = 0u64;
+
+        // The pseudo-header is always an even length, so only the input can need padding.
+        for bytes in [&header[..], input] {
+            let words = bytes.chunks_exact(2);
+
+            // Odd-length upper-layer packets are checksummed as if padded with a zero byte
+            if let [last] = words.remainder() {
+                checksum += (*last as u64) << 8;
+            }
+
+            for word in words {
+                checksum += u16::from_be_bytes([word[0], word[1]]) as u64;
+            }
+        }
+
+        // Fold in carry repeatedly until nothing is left
+        while checksum > 0xffff {
+            checksum = (checksum & 0xffff) + (checksum >> 16);
+        }
+
+        !(checksum as u16)
+    }
 }
 
 impl DispatchKeyed for Packet {
@@ -329,6 +637,17 @@ impl PacketBuilder {
         })
     }
 
+    /// Set the high six bits of the traffic class, keeping the ECN bits.
+    pub fn dscp(self, dscp: u8) -> Self {
+        let ecn = self.0.ecn();
+        self.traffic_class(ipv4::traffic_class(dscp, ecn))
+    }
+
+    pub fn ecn(self, ecn: ipv4::Ecn) -> Self {
+        let dscp = self.0.dscp();
+        self.traffic_class(ipv4::traffic_class(dscp, ecn))
+    }
+
     pub fn flow_label(self, flow_label: u32) -> Self {
         Self(Packet {
             flow_label,
@@ -375,6 +694,7 @@ impl PacketBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     fn hexstring(s: &str) -> Vec<u8> {
         hex::decode(s).unwrap()
@@ -420,6 +740,90 @@ mod tests {
         );
     }
 
+    #[test]
+    fn unrecognized_options_are_problems_pointing_at_them() {
+        let mut bytes = hexstring(
+            "600000000024000100000000000000000000000000000000ff0200000000000000000000000000163a000502000001008f008dca0000000104000000ff0200000000000000000001fff9e0c6"
+        );
+        assert_eq!(upper_layer_pointer(&bytes), 40);
+
+        bytes[42] = 0xc5;
+        let error = packet(&bytes).unwrap_err();
+        let option = error.downcast_ref::<UnrecognizedOption>().unwrap();
+        assert_eq!(
+            option.problem(),
+            Some(Problem {
+                code: icmpv6::PROBLEM_UNRECOGNIZED_OPTION,
+                pointer: 42,
+                src: ipv6a("::"),
+                dest: ipv6a("ff02::16"),
+                report_multicast: false,
+            })
+        );
+
+        bytes[42] = 0x85;
+        let error = packet(&bytes).unwrap_err();
+        let option = error.downcast_ref::<UnrecognizedOption>().unwrap();
+        assert!(option.problem().unwrap().report_multicast);
+    }
+
+    #[test]
+    fn unrecognized_options_are_skipped_or_discarded_by_type() {
+        let mut bytes = hexstring(
+            "600000000024000100000000000000000000000000000000ff0200000000000000000000000000163a000502000001008f008dca0000000104000000ff0200000000000000000001fff9e0c6"
+        );
+
+        bytes[42] = 0x3f;
+        assert_eq!(
+            packet(&bytes).unwrap().extension_headers,
+            [ExtensionHeader::HopByHopOptions(vec![])]
+        );
+
+        bytes[42] = 0x45;
+        let error = packet(&bytes).unwrap_err();
+        let option = error.downcast_ref::<UnrecognizedOption>().unwrap();
+        assert_eq!(option.option_type, 0x45);
+        assert_eq!(option.problem(), None);
+    }
+
+    #[test]
+    fn dscp_and_ecn_are_set_separately() {
+        let packet = Packet::builder().ecn(ipv4::Ecn::Ect0).dscp(46).build();
+        assert_eq!(packet.traffic_class, 0xba);
+        assert_eq!(packet.dscp(), 46);
+        assert_eq!(packet.ecn(), ipv4::Ecn::Ect0);
+    }
+
+    #[test]
+    fn jumbograms_round_trip() {
+        let packet_in = Packet::builder()
+            .protocol(ipv4::ProtocolNumber::Udp)
+            .hop_limit(0x40)
+            .src(ipv6a("2001:db8::1"))
+            .dest(ipv6a("2001:db8::2"))
+            .extension_header(ExtensionHeader::HopByHopOptions(vec![
+                HopByHopOption::RouterAlert(RouterAlertType::Mld),
+            ]))
+            .payload(vec![0x5a; 70000])
+            .build();
+
+        let encoded = packet_in.encode();
+        assert_eq!(encoded.len(), 40 + 16 + 70000);
+        assert_eq!(&encoded[4..6], [0, 0]);
+        assert_eq!(&encoded[42..48], [0xc2, 4, 0, 1, 0x11, 0x80]);
+
+        let packet_out = packet(&encoded).unwrap();
+        assert_eq!(packet_out.payload, packet_in.payload);
+        assert_eq!(
+            packet_out.extension_headers,
+            [ExtensionHeader::HopByHopOptions(vec![
+                HopByHopOption::JumboPayload(70016),
+                HopByHopOption::RouterAlert(RouterAlertType::Mld),
+            ])]
+        );
+        assert_eq!(packet_out.encode(), encoded);
+    }
+
     #[test]
     fn basic_packet_encodes() {
         assert_eq!(
@@ -454,4 +858,61 @@ mod tests {
             )
         );
     }
+
+    fn arb_extension_header() -> impl Strategy<Value = ExtensionHeader> {
+        prop::collection::vec(
+            any::<u16>()
+                .prop_map(|t| HopByHopOption::RouterAlert(RouterAlertType::try_from(t).unwrap())),
+            0..8,
+        )
+        .prop_map(ExtensionHeader::HopByHopOptions)
+    }
+
+    fn arb_packet() -> impl Strategy<Value = Packet> {
+        (
+            any::<u8>(),
+            0..(1u32 << 20),
+            // 0 would be read back as another hop-by-hop header
+            (1u8..).prop_map(|p| ipv4::ProtocolNumber::try_from(p).unwrap()),
+            any::<u8>(),
+            any::<[u16; 8]>(),
+            any::<[u16; 8]>(),
+            prop::collection::vec(arb_extension_header(), 0..3),
+            prop::collection::vec(any::<u8>(), 0..256),
+        )
+            .prop_map(
+                |(traffic_class, flow_label, protocol, hop_limit, src, dest, headers, payload)| {
+                    Packet {
+                        traffic_class,
+                        flow_label,
+                        next_header: NextHeader::Protocol(protocol),
+                        hop_limit,
+                        src: Address(src),
+                        dest: Address(dest),
+                        extension_headers: headers,
+                        payload,
+                    }
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_packet_round_trips(packet_in in arb_packet()) {
+            let encoded = packet_in.encode();
+            let headers_len: usize = packet_in
+                .extension_headers
+                .iter()
+                .map(|header| {
+                    prop_assert_eq!(encode!(header).len(), header.encoded_len());
+                    // Each header's length is a multiple of 8, counting its next header byte
+                    prop_assert_eq!((1 + header.encoded_len()) % 8, 0);
+                    Ok(1 + header.encoded_len())
+                })
+                .sum::<Result<_, TestCaseError>>()?;
+
+            prop_assert_eq!(encoded.len(), 40 + headers_len + packet_in.payload.len());
+            prop_assert_eq!(packet(&encoded).unwrap(), packet_in);
+        }
+    }
 }
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use super::Address;
+use crate::status;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc8201
+
+pub const MINIMUM_MTU: usize = 1280;
+pub const HEADER_LEN: usize = 40;
+const PMTU_AGING_TIME: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Entry {
+    mtu: usize,
+    expires_at: Instant,
+}
+
+/// Destination cache of path MTUs learned from Packet Too Big messages.
+#[derive(Debug)]
+pub struct PathMtuCache {
+    link_mtu: usize,
+    entries: HashMap<Address, Entry>,
+}
+
+impl PathMtuCache {
+    pub fn new(link_mtu: usize) -> Self {
+        Self {
+            link_mtu,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, dest: Address) -> usize {
+        self.entries.get(&dest).map_or(self.link_mtu, |e| e.mtu)
+    }
+
+    /// Largest upper-layer payload that can be sent to `dest` without fragmentation, e.g. for
+    /// sizing TCP segments.
+    pub fn max_payload(&self, dest: Address) -> usize {
+        self.get(dest) - HEADER_LEN
+    }
+
+    /// Record a reported MTU, returning when the entry should be aged out if it was updated.
+    ///
+    /// Reports are clamped to the IPv6 minimum MTU and can only ever lower the estimate.
+    pub fn update(&mut self, dest: Address, reported_mtu: usize, now: Instant) -> Option<Instant> {
+        let mtu = std::cmp::max(reported_mtu, MINIMUM_MTU);
+
+        if mtu >= self.get(dest) {
+            return None;
+        }
+
+        let expires_at = now + PMTU_AGING_TIME;
+        self.entries.insert(dest, Entry { mtu, expires_at });
+        self.write_status();
+
+        Some(expires_at)
+    }
+
+    /// Forget any estimates that have aged out, so larger MTUs get probed again.
+    pub fn expire(&mut self, now: Instant) {
+        let prev_len = self.entries.len();
+        self.entries.retain(|_, e| e.expires_at > now);
+
+        if self.entries.len() != prev_len {
+            self.write_status();
+        }
+    }
+
+    fn write_status(&self) {
+        status::update(|s| {
+            s.interface.path_mtus = self
+                .entries
+                .iter()
+                .map(|(dest, e)| (status::Key(*dest), e.mtu))
+                .collect();
+        });
+    }
+}
+
+/// Shared, read-only view of the path MTU cache for upper layers.
+#[derive(Clone, Debug)]
+pub struct PathMtuHandle(pub(super) Arc<RwLock<PathMtuCache>>);
+
+impl PathMtuHandle {
+    /// Handle to a fresh cache, for upper layers that run without a full IPv6 server.
+    pub fn new(link_mtu: usize) -> Self {
+        Self(Arc::new(RwLock::new(PathMtuCache::new(link_mtu))))
+    }
+
+    pub fn get(&self, dest: Address) -> usize {
+        self.0.read().unwrap().get(dest)
+    }
+
+    pub fn max_payload(&self, dest: Address) -> usize {
+        self.0.read().unwrap().max_payload(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv6a(s: &str) -> Address {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn unknown_destinations_use_link_mtu() {
+        let cache = PathMtuCache::new(1500);
+
+        assert_eq!(cache.get(ipv6a("2001:db8::1")), 1500);
+        assert_eq!(cache.max_payload(ipv6a("2001:db8::1")), 1460);
+    }
+
+    #[test]
+    fn update_only_lowers_estimate() {
+        let now = Instant::now();
+        let mut cache = PathMtuCache::new(1500);
+
+        assert!(cache.update(ipv6a("2001:db8::1"), 1400, now).is_some());
+        assert!(cache.update(ipv6a("2001:db8::1"), 1450, now).is_none());
+        assert_eq!(cache.get(ipv6a("2001:db8::1")), 1400);
+    }
+
+    #[test]
+    fn update_clamps_to_minimum_mtu() {
+        let now = Instant::now();
+        let mut cache = PathMtuCache::new(1500);
+
+        cache.update(ipv6a("2001:db8::1"), 500, now);
+        assert_eq!(cache.get(ipv6a("2001:db8::1")), MINIMUM_MTU);
+    }
+
+    #[test]
+    fn expire_restores_link_mtu() {
+        let now = Instant::now();
+        let mut cache = PathMtuCache::new(1500);
+
+        cache.update(ipv6a("2001:db8::1"), 1400, now);
+        cache.expire(now + PMTU_AGING_TIME);
+        assert_eq!(cache.get(ipv6a("2001:db8::1")), 1500);
+    }
+}
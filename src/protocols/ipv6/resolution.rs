@@ -0,0 +1,141 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::Address;
+use crate::protocols::ether;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-7.2.2
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-10
+const MAX_MULTICAST_SOLICIT: u32 = 3;
+const RETRANS_TIMER: Duration = Duration::from_secs(1);
+/// How often pending resolutions are checked for solicitations to retry.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// Packets held for each neighbor, past which the oldest are dropped.
+const MAX_HELD: usize = 16;
+
+/// A packet waiting on the link-layer address of its next hop.
+pub struct Held {
+    pub src_ether: ether::Address,
+    pub payload: Vec<u8>,
+}
+
+struct Pending {
+    /// Where the first held packet came from, to solicit from as well.
+    src: Address,
+    held: Vec<Held>,
+    solicitations: u32,
+    next_solicitation: Instant,
+}
+
+/// On-link next hops being resolved, with the packets waiting on each.
+#[derive(Default)]
+pub struct Resolutions {
+    pending: HashMap<Address, Pending>,
+}
+
+impl Resolutions {
+    /// Hold `held`, from `src`, until `next_hop` is resolved, returning whether to solicit it now.
+    pub fn hold(&mut self, next_hop: Address, src: Address, held: Held, now: Instant) -> bool {
+        match self.pending.entry(next_hop) {
+            Entry::Occupied(entry) => {
+                let pending = entry.into_mut();
+                if pending.held.len() == MAX_HELD {
+                    pending.held.remove(0);
+                }
+                pending.held.push(held);
+
+                false
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Pending {
+                    src,
+                    held: vec![held],
+                    solicitations: 1,
+                    next_solicitation: now + RETRANS_TIMER,
+                });
+
+                true
+            }
+        }
+    }
+
+    /// Next hops to solicit again now, with the source to solicit from; ones that never
+    /// answered are given up on, dropping their packets.
+    pub fn due(&mut self, now: Instant) -> Vec<(Address, Address)> {
+        let mut due = Vec::new();
+
+        self.pending.retain(|&next_hop, pending| {
+            if pending.next_solicitation > now {
+                return true;
+            }
+            if pending.solicitations == MAX_MULTICAST_SOLICIT {
+                return false;
+            }
+
+            pending.solicitations += 1;
+            pending.next_solicitation = now + RETRANS_TIMER;
+            due.push((next_hop, pending.src));
+            true
+        });
+
+        due
+    }
+
+    /// The packets held for `next_hop`, now that its link-layer address is known.
+    pub fn resolved(&mut self, next_hop: Address) -> Vec<Held> {
+        self.pending
+            .remove(&next_hop)
+            .map_or_else(Vec::new, |pending| pending.held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(payload: u8) -> Held {
+        Held {
+            src_ether: ether::Address([2, 0, 0, 0, 0, 1]),
+            payload: vec![payload],
+        }
+    }
+
+    #[test]
+    fn packets_wait_for_their_next_hop() {
+        let now = Instant::now();
+        let mut resolutions = Resolutions::default();
+        let next_hop: Address = "fe80::b".parse().unwrap();
+        let src: Address = "fe80::a".parse().unwrap();
+
+        assert!(resolutions.hold(next_hop, src, held(1), now));
+        assert!(!resolutions.hold(next_hop, src, held(2), now));
+
+        let payloads: Vec<_> = resolutions
+            .resolved(next_hop)
+            .into_iter()
+            .map(|h| h.payload)
+            .collect();
+        assert_eq!(payloads, vec![vec![1], vec![2]]);
+        assert!(resolutions.resolved(next_hop).is_empty());
+    }
+
+    #[test]
+    fn unanswered_solicitations_are_retried_then_given_up_on() {
+        let now = Instant::now();
+        let mut resolutions = Resolutions::default();
+        let next_hop: Address = "fe80::b".parse().unwrap();
+        let src: Address = "fe80::a".parse().unwrap();
+
+        resolutions.hold(next_hop, src, held(1), now);
+        assert!(resolutions.due(now).is_empty());
+        assert_eq!(resolutions.due(now + RETRANS_TIMER), vec![(next_hop, src)]);
+        assert_eq!(
+            resolutions.due(now + RETRANS_TIMER * 2),
+            vec![(next_hop, src)]
+        );
+        assert!(resolutions.due(now + RETRANS_TIMER * 3).is_empty());
+        assert!(resolutions.resolved(next_hop).is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::{Duration, Instant};
+
+use super::Address;
+use crate::protocols::ether;
+use crate::status;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-6.3.4
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DefaultRouter {
+    pub address: Address,
+    pub ether_address: Option<ether::Address>,
+    pub lifetime: Duration,
+    pub expires_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct DefaultRouterList {
+    routers: Vec<DefaultRouter>,
+}
+
+impl DefaultRouterList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the list from a router advertisement, returning when the router will next need to
+    /// be checked for expiry, if it is (still) in the list.
+    pub fn update(
+        &mut self,
+        address: Address,
+        ether_address: Option<ether::Address>,
+        lifetime: Duration,
+        now: Instant,
+    ) -> Option<Instant> {
+        let existing = self.routers.iter().position(|r| r.address == address);
+
+        let result = match (existing, lifetime) {
+            (Some(i), Duration::ZERO) => {
+                self.routers.remove(i);
+                None
+            }
+            (None, Duration::ZERO) => return None,
+            (Some(i), _) => {
+                let router = &mut self.routers[i];
+                router.ether_address = ether_address.or(router.ether_address);
+                router.lifetime = lifetime;
+                router.expires_at = now + lifetime;
+                Some(router.expires_at)
+            }
+            (None, _) => {
+                self.routers.push(DefaultRouter {
+                    address,
+                    ether_address,
+                    lifetime,
+                    expires_at: now + lifetime,
+                });
+                Some(now + lifetime)
+            }
+        };
+
+        self.write_status();
+
+        result
+    }
+
+    /// Remove any routers whose lifetimes have run out.
+    pub fn expire(&mut self, now: Instant) {
+        let prev_len = self.routers.len();
+        self.routers.retain(|r| r.expires_at > now);
+
+        if self.routers.len() != prev_len {
+            self.write_status();
+        }
+    }
+
+    /// Pick a router for off-link traffic, along with its link-layer address, from its
+    /// advertisements or else as found by `lookup`.
+    ///
+    /// Routers are used in the order they were first heard from, preferring ones whose
+    /// link-layer address is known, so traffic falls back to the next one when the current
+    /// router expires. The first router is picked to be resolved if none are known.
+    pub fn select(
+        &self,
+        lookup: impl Fn(Address) -> Option<ether::Address>,
+    ) -> Option<(Address, Option<ether::Address>)> {
+        self.routers
+            .iter()
+            .find_map(|r| {
+                r.ether_address
+                    .or_else(|| lookup(r.address))
+                    .map(|ether_address| (r.address, Some(ether_address)))
+            })
+            .or_else(|| self.routers.first().map(|r| (r.address, None)))
+    }
+
+    fn write_status(&self) {
+        status::update(|s| {
+            s.interface.default_routers = self
+                .routers
+                .iter()
+                .map(|r| status::RouterStatus {
+                    address: r.address,
+                    ether_address: r.ether_address,
+                    lifetime_secs: r.lifetime.as_secs(),
+                })
+                .collect();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETHER: ether::Address = ether::Address([2, 0, 0, 0, 0, 1]);
+
+    fn ipv6a(s: &str) -> Address {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn zero_lifetime_removes_router() {
+        let now = Instant::now();
+        let mut list = DefaultRouterList::new();
+
+        assert!(list
+            .update(ipv6a("fe80::1"), None, Duration::from_secs(30), now)
+            .is_some());
+        assert_eq!(
+            list.update(ipv6a("fe80::1"), None, Duration::ZERO, now),
+            None
+        );
+        assert_eq!(list.select(|_| None), None);
+    }
+
+    #[test]
+    fn update_refreshes_expiry() {
+        let now = Instant::now();
+        let mut list = DefaultRouterList::new();
+
+        list.update(ipv6a("fe80::1"), None, Duration::from_secs(30), now);
+        list.update(ipv6a("fe80::1"), None, Duration::from_secs(60), now);
+        list.expire(now + Duration::from_secs(45));
+
+        assert_eq!(list.select(|_| None).unwrap().0, ipv6a("fe80::1"));
+    }
+
+    #[test]
+    fn select_falls_back_when_router_expires() {
+        let now = Instant::now();
+        let mut list = DefaultRouterList::new();
+
+        list.update(ipv6a("fe80::1"), None, Duration::from_secs(30), now);
+        list.update(ipv6a("fe80::2"), None, Duration::from_secs(60), now);
+        assert_eq!(list.select(|_| None).unwrap().0, ipv6a("fe80::1"));
+
+        list.expire(now + Duration::from_secs(30));
+        assert_eq!(list.select(|_| None).unwrap().0, ipv6a("fe80::2"));
+
+        list.expire(now + Duration::from_secs(60));
+        assert_eq!(list.select(|_| None), None);
+    }
+
+    #[test]
+    fn routers_with_link_layer_addresses_are_preferred() {
+        let now = Instant::now();
+        let mut list = DefaultRouterList::new();
+
+        list.update(ipv6a("fe80::1"), None, Duration::from_secs(60), now);
+        assert_eq!(list.select(|_| None), Some((ipv6a("fe80::1"), None)));
+
+        list.update(ipv6a("fe80::2"), Some(ETHER), Duration::from_secs(60), now);
+        assert_eq!(list.select(|_| None), Some((ipv6a("fe80::2"), Some(ETHER))));
+
+        let neighbor = ether::Address([2, 0, 0, 0, 0, 2]);
+        assert_eq!(
+            list.select(|a| (a == ipv6a("fe80::1")).then_some(neighbor)),
+            Some((ipv6a("fe80::1"), Some(neighbor)))
+        );
+    }
+}
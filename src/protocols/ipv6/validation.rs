@@ -0,0 +1,226 @@
+//! Checks received neighbor discovery messages must pass before they're acted on, mostly so
+//! they can't come from off-link.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-6.1
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-7.1
+
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+
+use super::icmpv6;
+use super::packet::{NextHeader, Packet};
+use super::Address;
+use crate::protocols::ipv4;
+use crate::stats;
+
+const NDP_HOP_LIMIT: u8 = 255;
+
+/// A check that a received NDP message failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Failure {
+    HopLimit,
+    Code,
+    Fragmented,
+    Malformed,
+    SourceNotLinkLocal,
+    MulticastTarget,
+    SolicitedToMulticast,
+    UnspecifiedSourceToUnicast,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str(match self {
+            Failure::HopLimit => "hop_limit",
+            Failure::Code => "code",
+            Failure::Fragmented => "fragmented",
+            Failure::Malformed => "malformed",
+            Failure::SourceNotLinkLocal => "source_not_link_local",
+            Failure::MulticastTarget => "multicast_target",
+            Failure::SolicitedToMulticast => "solicited_to_multicast",
+            Failure::UnspecifiedSourceToUnicast => "unspecified_source_to_unicast",
+        })
+    }
+}
+
+/// Count a message discarded for `failure`.
+pub fn record_drop(failure: Failure) {
+    stats::NDP_DROPS.increment(&failure.to_string());
+}
+
+fn is_ndp_type(packet_type: u8) -> bool {
+    use icmpv6::Type::*;
+
+    matches!(
+        icmpv6::Type::try_from(packet_type),
+        Ok(RouterSolicitation | RouterAdvertisement | NeighborSolicitation | NeighborAdvertisement)
+    )
+}
+
+/// Whether ICMPv6 `payload` claims to be an NDP message.
+pub fn is_ndp(payload: &[u8]) -> bool {
+    payload.first().copied().is_some_and(is_ndp_type)
+}
+
+/// Check what can be checked of an NDP message before it's parsed.
+///
+/// NDP messages must never be fragmented, but only the first fragment shows what it carries, so
+/// that's the one that's discarded.
+pub fn check_header(packet: &Packet) -> Result<(), Failure> {
+    if packet.next_header == NextHeader::Protocol(ipv4::ProtocolNumber::Ipv6Frag) {
+        // Next header, reserved, fragment offset and flags, identification
+        return match packet.payload[..] {
+            [next_header, _, offset_high, offset_low, _, _, _, _, packet_type, ..]
+                if ipv4::ProtocolNumber::try_from(next_header).ok()
+                    == Some(ipv4::ProtocolNumber::Ipv6Icmp)
+                    && offset_high == 0
+                    && offset_low & 0xf8 == 0
+                    && is_ndp_type(packet_type) =>
+            {
+                Err(Failure::Fragmented)
+            }
+            _ => Ok(()),
+        };
+    }
+
+    if packet.next_header != NextHeader::Protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+        || !is_ndp(&packet.payload)
+    {
+        return Ok(());
+    }
+
+    if packet.hop_limit != NDP_HOP_LIMIT {
+        return Err(Failure::HopLimit);
+    }
+
+    // Codes aren't kept when parsing
+    if packet.payload.get(1) != Some(&0) {
+        return Err(Failure::Code);
+    }
+
+    Ok(())
+}
+
+/// Check a parsed NDP message against the addresses it was sent between.
+pub fn check_message(src: Address, dest: Address, message: &icmpv6::Packet) -> Result<(), Failure> {
+    match message {
+        icmpv6::Packet::RouterAdvertisement { .. } if src.scope() != 0x2 || src.is_multicast() => {
+            Err(Failure::SourceNotLinkLocal)
+        }
+        icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+            dest: target, ..
+        }) => {
+            if target.is_multicast() {
+                Err(Failure::MulticastTarget)
+            } else if src == Address::default() && dest != target.solicited_nodes_multicast() {
+                Err(Failure::UnspecifiedSourceToUnicast)
+            } else {
+                Ok(())
+            }
+        }
+        icmpv6::Packet::NeighborAdvertisement(icmpv6::NeighborAdvertisement {
+            src: target,
+            solicited,
+            ..
+        }) => {
+            if target.is_multicast() {
+                Err(Failure::MulticastTarget)
+            } else if *solicited && dest.is_multicast() {
+                Err(Failure::SolicitedToMulticast)
+            } else {
+                Ok(())
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv6a(s: &str) -> Address {
+        s.parse().unwrap()
+    }
+
+    fn solicitation(hop_limit: u8, code: u8) -> Packet {
+        let mut payload = icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+            dest: ipv6a("fe80::2"),
+            options: vec![],
+        })
+        .encode(icmpv6::PseudoHeader {
+            src: ipv6a("fe80::1"),
+            dest: ipv6a("ff02::1:ff00:2"),
+            length: 0,
+        });
+        payload[1] = code;
+
+        Packet::builder()
+            .protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+            .hop_limit(hop_limit)
+            .src(ipv6a("fe80::1"))
+            .dest(ipv6a("ff02::1:ff00:2"))
+            .payload(payload)
+            .build()
+    }
+
+    #[test]
+    fn header_checks() {
+        assert_eq!(check_header(&solicitation(255, 0)), Ok(()));
+        assert_eq!(check_header(&solicitation(64, 0)), Err(Failure::HopLimit));
+        assert_eq!(check_header(&solicitation(255, 1)), Err(Failure::Code));
+
+        let mut fragment = solicitation(255, 0);
+        fragment.next_header = NextHeader::Protocol(ipv4::ProtocolNumber::Ipv6Frag);
+        fragment.payload = [&[58, 0, 0, 1, 0, 0, 0, 1][..], &fragment.payload].concat();
+        assert_eq!(check_header(&fragment), Err(Failure::Fragmented));
+    }
+
+    #[test]
+    fn message_checks() {
+        let advertisement = |solicited| {
+            icmpv6::Packet::NeighborAdvertisement(icmpv6::NeighborAdvertisement {
+                router: false,
+                solicited,
+                override_flag: false,
+                src: ipv6a("fe80::2"),
+                options: vec![],
+            })
+        };
+
+        assert_eq!(
+            check_message(ipv6a("fe80::2"), ipv6a("ff02::1"), &advertisement(false)),
+            Ok(())
+        );
+        assert_eq!(
+            check_message(ipv6a("fe80::2"), ipv6a("ff02::1"), &advertisement(true)),
+            Err(Failure::SolicitedToMulticast)
+        );
+        assert_eq!(
+            check_message(
+                ipv6a("::"),
+                ipv6a("fe80::2"),
+                &icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+                    dest: ipv6a("fe80::2"),
+                    options: vec![],
+                })
+            ),
+            Err(Failure::UnspecifiedSourceToUnicast)
+        );
+        assert_eq!(
+            check_message(
+                ipv6a("2001:db8::1"),
+                ipv6a("ff02::1"),
+                &icmpv6::Packet::RouterAdvertisement {
+                    cur_hop_limit: 64,
+                    managed: false,
+                    other: false,
+                    router_lifetime: 1800,
+                    reachable_time: 0,
+                    retrans_timer: 0,
+                    options: vec![],
+                }
+            ),
+            Err(Failure::SourceNotLinkLocal)
+        );
+    }
+}
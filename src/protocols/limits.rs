@@ -0,0 +1,184 @@
+//! Bounds on what the parsers accept, so a crafted packet can't make us allocate or work without
+//! end. Exceeding one fails parsing with an `Exceeded`, which callers can downcast to.
+
+use nom::error::{make_error, ErrorKind};
+use std::fmt::{Display, Formatter};
+
+use super::encdec::BIResult;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Limit {
+    /// Bytes in a frame or packet, jumbograms included.
+    PacketSize,
+    /// Extension headers in an IPv6 packet.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc8504#section-5.3
+    ExtensionHeaders,
+    /// Options in one extension header, padding included.
+    OptionsPerHeader,
+    /// Bytes of a stream held while waiting for the rest of a request.
+    ReassemblyBuffer,
+}
+
+impl Limit {
+    pub const fn max(self) -> usize {
+        match self {
+            Limit::PacketSize => 1 << 18,
+            Limit::ExtensionHeaders => 8,
+            Limit::OptionsPerHeader => 16,
+            Limit::ReassemblyBuffer => 1 << 16,
+        }
+    }
+
+    /// Fails if `actual` is beyond the limit.
+    pub fn check(self, actual: usize) -> Result<(), Exceeded> {
+        if actual > self.max() {
+            return Err(Exceeded {
+                limit: self,
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Exceeded {
+    pub limit: Limit,
+    pub actual: usize,
+}
+
+impl Display for Exceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "{:?} limit exceeded: {} is over {}",
+            self.limit,
+            self.actual,
+            self.limit.max()
+        )
+    }
+}
+
+impl std::error::Error for Exceeded {}
+
+/// Like nom's `count`, but refusing counts `input` is too short to hold at `min_len` bytes each,
+/// as `count` allocates for all of them up front.
+pub fn count<'a, O>(
+    mut f: impl FnMut(&'a [u8]) -> BIResult<'a, O>,
+    count: usize,
+    min_len: usize,
+) -> impl FnMut(&'a [u8]) -> BIResult<'a, Vec<O>> {
+    move |input: &'a [u8]| {
+        if count.saturating_mul(min_len) > input.len() {
+            return Err(nom::Err::Failure(make_error(input, ErrorKind::TooLarge)));
+        }
+
+        nom::multi::count(&mut f, count)(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::ipv6::{self, icmpv6};
+    use crate::protocols::{dns, ether, sctp};
+    use proptest::prelude::*;
+
+    /// Throw `input` at every parser that might see it off the wire, which mustn't panic.
+    fn parse_everything(input: &[u8]) {
+        parse_icmpv6(input);
+        let _ = ether::frame(input);
+        let _ = ipv6::packet(input);
+        let _ = dns::response(input);
+        let _ = sctp::packet(input);
+    }
+
+    fn parse_icmpv6(input: &[u8]) {
+        let pseudo_header = icmpv6::PseudoHeader {
+            src: ipv6::Address::default(),
+            dest: ipv6::Address::default(),
+            length: input.len() as u32,
+        };
+        // Fixed up so parsing gets past the checksum.
+        let mut message = input.to_vec();
+        if message.len() >= 4 {
+            message[2..4].fill(0);
+            let checksum =
+                pseudo_header.checksum(crate::protocols::ipv4::ProtocolNumber::Ipv6Icmp, &message);
+            message[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+        let _ = icmpv6::packet(&message, pseudo_header);
+    }
+
+    /// Each line of the corpus is the parser an input is for, the input in hex, then the limit it
+    /// exceeds, if any.
+    fn corpus() -> Vec<(String, Vec<u8>, Option<String>)> {
+        include_str!("../../tests/corpus/parsers.txt")
+            .lines()
+            .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+            .map(|line| {
+                let mut fields = line.split_whitespace();
+                (
+                    fields.next().unwrap().to_string(),
+                    hex::decode(fields.next().unwrap()).unwrap(),
+                    fields.next().map(str::to_string),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn corpus_is_survived() {
+        for (_, input, _) in corpus() {
+            parse_everything(&input);
+        }
+    }
+
+    #[test]
+    fn corpus_fails_with_the_limit_exceeded() {
+        for (parser, input, expected) in corpus() {
+            let expected = match expected {
+                Some(expected) => expected,
+                None => continue,
+            };
+            let error = match parser.as_str() {
+                "ether" => ether::frame(&input).unwrap_err(),
+                "ipv6" => ipv6::packet(&input).unwrap_err(),
+                _ => panic!("no parser {}", parser),
+            };
+            let limit = error.downcast_ref::<Exceeded>().map(|e| e.limit);
+
+            assert_eq!(
+                format!("{:?}", limit),
+                format!("Some({})", expected),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn counts_the_input_cant_hold_are_refused() {
+        let mut two = count(nom::number::complete::be_u16, 2, 2);
+        assert!(two(&[0, 1, 0, 2][..]).is_ok());
+        assert!(count(nom::number::complete::be_u16, 0xffff, 2)(&[0, 1][..]).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_input_is_survived(input in prop::collection::vec(any::<u8>(), 0..256)) {
+            parse_everything(&input);
+        }
+
+        #[test]
+        fn arbitrary_icmpv6_of_every_type_is_survived(
+            packet_type in prop::sample::select(vec![1u8, 2, 3, 4, 128, 129, 130, 133, 134, 135, 136, 143]),
+            rest in prop::collection::vec(any::<u8>(), 0..128),
+        ) {
+            let mut input = vec![packet_type];
+            input.extend(rest);
+            parse_icmpv6(&input);
+        }
+    }
+}
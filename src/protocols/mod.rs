@@ -1,8 +1,29 @@
+pub mod answer_policy;
 pub mod arp;
+pub mod bridge;
+pub mod conntrack;
+pub mod dns;
 pub mod ether;
+pub mod filter;
+pub mod flow_export;
+pub mod icmp_limit;
+pub mod inventory;
 pub mod ipv4;
 pub mod ipv6;
+pub mod limits;
+pub mod names;
+pub mod neighbor;
+pub mod port_policy;
+pub mod pppoe;
+pub mod sctp;
+pub mod shaping;
+pub mod snooping;
+pub mod tcp;
+pub mod tunnel;
+pub mod twamp;
 pub mod udp;
+pub mod wireguard;
+pub mod wol;
 
 mod encdec;
-mod utils;
+pub(crate) mod utils;
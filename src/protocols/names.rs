@@ -0,0 +1,184 @@
+//! Names for protocols and well-known ports, so config and commands can say `udp` or `domain`
+//! instead of 17 or 53.
+
+use anyhow::{bail, Result as AHResult};
+use serde::{Deserialize, Deserializer};
+use std::str::FromStr;
+
+use super::ipv4::ProtocolNumber;
+
+/// Well-known ports by their service names, as in `/etc/services`, with a few common aliases.
+// Ref: https://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
+pub const SERVICES: &[(&str, u16)] = &[
+    ("echo", 7),
+    ("discard", 9),
+    ("daytime", 13),
+    ("chargen", 19),
+    ("ftp-data", 20),
+    ("ftp", 21),
+    ("ssh", 22),
+    ("telnet", 23),
+    ("smtp", 25),
+    ("time", 37),
+    ("domain", 53),
+    ("dns", 53),
+    ("bootps", 67),
+    ("bootpc", 68),
+    ("tftp", 69),
+    ("http", 80),
+    ("www", 80),
+    ("pop3", 110),
+    ("ident", 113),
+    ("auth", 113),
+    ("ntp", 123),
+    ("imap", 143),
+    ("snmp", 161),
+    ("snmp-trap", 162),
+    ("bgp", 179),
+    ("ldap", 389),
+    ("https", 443),
+    ("isakmp", 500),
+    ("syslog", 514),
+    ("dhcpv6-client", 546),
+    ("dhcpv6-server", 547),
+    ("submission", 587),
+    ("ldaps", 636),
+    ("twamp", 862),
+    ("imaps", 993),
+    ("pop3s", 995),
+    ("mqtt", 1883),
+    ("diameter", 3868),
+    ("ipsec-nat-t", 4500),
+    ("vxlan", 4789),
+    ("mdns", 5353),
+    ("wireguard", 51820),
+];
+
+/// Protocol names understood besides each protocol's own, as `tcpdump` and `ip6tables` spell them.
+const PROTOCOL_ALIASES: &[(&str, ProtocolNumber)] = &[
+    ("icmp6", ProtocolNumber::Ipv6Icmp),
+    ("icmpv6", ProtocolNumber::Ipv6Icmp),
+    ("frag", ProtocolNumber::Ipv6Frag),
+];
+
+/// A port given as a number or service name.
+pub fn port(s: &str) -> AHResult<u16> {
+    if let Ok(port) = s.parse() {
+        return Ok(port);
+    }
+
+    match SERVICES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(s))
+    {
+        Some((_, port)) => Ok(*port),
+        None => bail!(
+            "unknown port {:?}; expected a number or one of: {}",
+            s,
+            SERVICES
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// A protocol given as a number or name.
+pub fn protocol(s: &str) -> AHResult<ProtocolNumber> {
+    match PROTOCOL_ALIASES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(s))
+    {
+        Some((_, protocol)) => Ok(*protocol),
+        None => ProtocolNumber::from_str(s),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrName<T> {
+    Number(T),
+    Name(String),
+}
+
+/// For `deserialize_with` on ports that may be given by service name.
+pub fn deserialize_port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    match NumberOrName::deserialize(deserializer)? {
+        NumberOrName::Number(port) => Ok(port),
+        NumberOrName::Name(name) => port(&name).map_err(serde::de::Error::custom),
+    }
+}
+
+/// For `deserialize_with` on lists of ports that may be given by service name.
+pub fn deserialize_ports<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u16>, D::Error> {
+    Vec::<NumberOrName<u16>>::deserialize(deserializer)?
+        .into_iter()
+        .map(|port_or_name| match port_or_name {
+            NumberOrName::Number(port) => Ok(port),
+            NumberOrName::Name(name) => port(&name).map_err(serde::de::Error::custom),
+        })
+        .collect()
+}
+
+/// For `deserialize_with` on optional protocol numbers that may be given by name.
+pub fn deserialize_protocol<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u8>, D::Error> {
+    Ok(
+        match Option::<NumberOrName<u8>>::deserialize(deserializer)? {
+            Some(NumberOrName::Number(protocol)) => Some(protocol),
+            Some(NumberOrName::Name(name)) => {
+                let protocol = protocol(&name).map_err(serde::de::Error::custom)?;
+                Some(u8::from(protocol))
+            }
+            None => None,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_numbers_are_both_accepted() {
+        assert_eq!(port("domain").unwrap(), 53);
+        assert_eq!(port("HTTPS").unwrap(), 443);
+        assert_eq!(port("8080").unwrap(), 8080);
+        let error = port("gopher").unwrap_err().to_string();
+        assert!(error.contains("domain"), "{}", error);
+
+        assert_eq!(protocol("udp").unwrap(), ProtocolNumber::Udp);
+        assert_eq!(protocol("icmpv6").unwrap(), ProtocolNumber::Ipv6Icmp);
+        assert_eq!(protocol("IPv6-ICMP").unwrap(), ProtocolNumber::Ipv6Icmp);
+        assert_eq!(protocol("99").unwrap(), ProtocolNumber::Unknown(99));
+        assert!(protocol("quic").is_err());
+    }
+
+    #[test]
+    fn config_can_use_names() {
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(deserialize_with = "deserialize_port")]
+            port: u16,
+            #[serde(deserialize_with = "deserialize_ports")]
+            ports: Vec<u16>,
+            #[serde(default, deserialize_with = "deserialize_protocol")]
+            protocol: Option<u8>,
+        }
+
+        let config: Config =
+            toml::from_str("port = \"ntp\"\nports = [22, \"telnet\"]\nprotocol = \"sctp\"")
+                .unwrap();
+        assert_eq!(config.port, 123);
+        assert_eq!(config.ports, [22, 23]);
+        assert_eq!(config.protocol, Some(132));
+
+        let error = toml::from_str::<Config>("port = \"nope\"\nports = []")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("unknown port"), "{}", error);
+    }
+}
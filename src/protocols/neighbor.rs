@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::ether;
+use crate::status;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-10
+const REACHABLE_TIME: Duration = Duration::from_secs(30);
+/// Stale entries not heard from for this long are forgotten.
+// Ref: https://www.kernel.org/doc/Documentation/networking/ip-sysctl.txt (gc_stale_time)
+const STALE_LIFETIME: Duration = Duration::from_secs(60);
+/// Entries kept, as anyone on the link can add them; past this, the one heard from longest ago
+/// makes room.
+pub const MAX_ENTRIES: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum NeighborState {
+    Reachable,
+    Stale,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    ether_address: ether::Address,
+    reachable_until: Option<Instant>,
+    last_seen: SystemTime,
+    /// `last_seen`, for aging.
+    seen: Instant,
+}
+
+impl Entry {
+    fn state(&self, now: Instant) -> NeighborState {
+        match self.reachable_until {
+            Some(until) if until > now => NeighborState::Reachable,
+            _ => NeighborState::Stale,
+        }
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        self.state(now) == NeighborState::Stale
+            && now.saturating_duration_since(self.seen) >= STALE_LIFETIME
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Neighbor {
+    pub address: IpAddr,
+    pub ether_address: ether::Address,
+    pub state: NeighborState,
+    pub last_seen: SystemTime,
+    pub interface: String,
+}
+
+/// Mapping from protocol addresses to link-layer addresses, shared between ARP and NDP.
+#[derive(Clone)]
+pub struct Table {
+    interface: Arc<String>,
+    entries: Arc<RwLock<HashMap<IpAddr, Entry>>>,
+}
+
+impl Table {
+    pub fn new(interface: impl Into<String>) -> Self {
+        Self {
+            interface: Arc::new(interface.into()),
+            entries: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Name of the interface the neighbors are on.
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// Record that `address` is at `ether_address`.
+    ///
+    /// `confirmed` should only be set when the mapping was proven by a reply to us; otherwise the
+    /// entry is only known to be stale.
+    pub fn learn(&self, address: IpAddr, ether_address: ether::Address, confirmed: bool) {
+        let now = Instant::now();
+        let mut entries = self.entries.write().unwrap();
+
+        let reachable_until = if confirmed {
+            Some(now + REACHABLE_TIME)
+        } else {
+            entries
+                .get(&address)
+                .filter(|e| e.ether_address == ether_address)
+                .and_then(|e| e.reachable_until)
+        };
+
+        let mut forgotten = Vec::new();
+        if !entries.contains_key(&address) && entries.len() >= MAX_ENTRIES {
+            entries.retain(|&address, e| {
+                let expired = e.expired(now);
+                if expired {
+                    forgotten.push(address);
+                }
+                !expired
+            });
+            if entries.len() >= MAX_ENTRIES {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, e)| (e.state(now) == NeighborState::Reachable, e.seen))
+                    .map(|(&address, _)| address);
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                    forgotten.push(oldest);
+                }
+            }
+        }
+
+        let entry = Entry {
+            ether_address,
+            reachable_until,
+            last_seen: SystemTime::now(),
+            seen: now,
+        };
+        entries.insert(address, entry);
+
+        status::update(|s| {
+            for address in forgotten {
+                s.interface.neighbors.remove(&status::Key(address));
+            }
+            s.interface.neighbors.insert(
+                status::Key(address),
+                status::NeighborStatus {
+                    ether_address: Some(ether_address),
+                    state: entry.state(now),
+                    last_seen: entry
+                        .last_seen
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs()),
+                    interface: self.interface.to_string(),
+                },
+            );
+        });
+    }
+
+    pub fn lookup(&self, address: IpAddr) -> Option<ether::Address> {
+        self.entries
+            .read()
+            .unwrap()
+            .get(&address)
+            .filter(|e| !e.expired(Instant::now()))
+            .map(|e| e.ether_address)
+    }
+
+    /// List all neighbors, sorted by address.
+    pub fn neighbors(&self) -> Vec<Neighbor> {
+        let now = Instant::now();
+
+        let mut result: Vec<_> = self
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, e)| !e.expired(now))
+            .map(|(address, e)| Neighbor {
+                address: *address,
+                ether_address: e.ether_address,
+                state: e.state(now),
+                last_seen: e.last_seen,
+                interface: self.interface.to_string(),
+            })
+            .collect();
+        result.sort_by_key(|n| n.address);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unconfirmed_entries_are_stale() {
+        let table = Table::new("tap0");
+        table.learn(
+            "fe80::1".parse().unwrap(),
+            ether::Address([2, 0, 0, 0, 0, 1]),
+            false,
+        );
+
+        let neighbors = table.neighbors();
+        assert_eq!(neighbors.len(), 1);
+        assert_eq!(neighbors[0].state, NeighborState::Stale);
+        assert_eq!(neighbors[0].interface, "tap0");
+    }
+
+    #[test]
+    fn unconfirmed_update_keeps_reachability() {
+        let table = Table::new("tap0");
+        let address = "10.0.0.1".parse().unwrap();
+
+        table.learn(address, ether::Address([2, 0, 0, 0, 0, 1]), true);
+        table.learn(address, ether::Address([2, 0, 0, 0, 0, 1]), false);
+        assert_eq!(table.neighbors()[0].state, NeighborState::Reachable);
+
+        table.learn(address, ether::Address([2, 0, 0, 0, 0, 2]), false);
+        assert_eq!(table.neighbors()[0].state, NeighborState::Stale);
+        assert_eq!(
+            table.lookup(address),
+            Some(ether::Address([2, 0, 0, 0, 0, 2]))
+        );
+    }
+
+    #[test]
+    fn stale_entries_age_out_and_the_table_is_bounded() {
+        let table = Table::new("tap0");
+        let ether_address = ether::Address([2, 0, 0, 0, 0, 1]);
+        let address = |i: usize| IpAddr::from([10, 0, (i >> 8) as u8, i as u8]);
+
+        table.learn(address(0), ether_address, false);
+        table.learn(address(1), ether_address, true);
+        {
+            let mut entries = table.entries.write().unwrap();
+            let entry = entries.get_mut(&address(0)).unwrap();
+            entry.seen = Instant::now() - STALE_LIFETIME;
+        }
+        assert_eq!(table.lookup(address(0)), None);
+        assert_eq!(table.neighbors().len(), 1);
+
+        for i in 2..MAX_ENTRIES + 2 {
+            table.learn(address(i), ether_address, false);
+        }
+        let entries = table.entries.read().unwrap();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        // The expired entry went first, then the stale one heard from longest ago, leaving the
+        // reachable one.
+        assert!(!entries.contains_key(&address(0)));
+        assert!(!entries.contains_key(&address(2)));
+        assert!(entries.contains_key(&address(1)));
+    }
+}
@@ -0,0 +1,54 @@
+use serde::Deserialize;
+
+/// How a port without a listener answers, as seen by a port scanner.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnboundPort {
+    /// Refuse: TCP RST, or ICMPv6 port unreachable for UDP.
+    #[default]
+    Closed,
+    /// Silently drop.
+    Filtered,
+}
+
+/// Per-protocol policy for unbound ports, with per-port overrides.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PortPolicy {
+    #[serde(default)]
+    pub unbound: UnboundPort,
+    /// Ports to treat as closed regardless of `unbound`.
+    #[serde(default, deserialize_with = "super::names::deserialize_ports")]
+    pub closed: Vec<u16>,
+    /// Ports to treat as filtered regardless of `unbound`.
+    #[serde(default, deserialize_with = "super::names::deserialize_ports")]
+    pub filtered: Vec<u16>,
+}
+
+impl PortPolicy {
+    pub fn get(&self, port: u16) -> UnboundPort {
+        if self.filtered.contains(&port) {
+            UnboundPort::Filtered
+        } else if self.closed.contains(&port) {
+            UnboundPort::Closed
+        } else {
+            self.unbound
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_take_precedence() {
+        let policy = PortPolicy {
+            unbound: UnboundPort::Filtered,
+            closed: vec![113],
+            filtered: vec![],
+        };
+
+        assert_eq!(policy.get(113), UnboundPort::Closed);
+        assert_eq!(policy.get(22), UnboundPort::Filtered);
+    }
+}
@@ -0,0 +1,1008 @@
+//! PPPoE access concentrator: discovery, then just enough LCP and IPCP to hand each client an
+//! address. There's no authentication, and nothing is routed over the sessions.
+
+use anyhow::Result as AHResult;
+use crossbeam::channel;
+use nom::{
+    combinator::{eof, map, map_res},
+    multi::{length_data, many0},
+    number::complete::{be_u16, be_u8},
+    sequence::terminated,
+};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::thread;
+
+use super::encdec::{BIResult, Tlv};
+use super::utils::Backpressure;
+use super::{ether, ipv4};
+use crate::{encode, proto_enum_with_unknown, status, try_parse, warn};
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc2516
+
+const VERSION_TYPE: u8 = 0x11;
+/// Ethernet MTU, less the PPPoE and PPP headers.
+pub const MRU: u16 = 1492;
+
+proto_enum_with_unknown!(Code, u8, {
+    Session = 0x00,
+    Offer = 0x07,
+    Initiation = 0x09,
+    Request = 0x19,
+    SessionConfirmation = 0x65,
+    Terminate = 0xa7,
+});
+
+proto_enum_with_unknown!(TagType, u16, {
+    EndOfList = 0x0000,
+    ServiceName = 0x0101,
+    AcName = 0x0102,
+    HostUniq = 0x0103,
+    AcCookie = 0x0104,
+    RelaySessionId = 0x0110,
+    ServiceNameError = 0x0201,
+    AcSystemError = 0x0202,
+    GenericError = 0x0203,
+});
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tag {
+    pub tag_type: TagType,
+    pub value: Vec<u8>,
+}
+
+impl Tag {
+    pub fn new(tag_type: TagType, value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            tag_type,
+            value: value.into(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        Tlv::PPPOE.encode(self.tag_type, &self.value[..])
+    }
+}
+
+fn tag(input: &[u8]) -> BIResult<'_, Tag> {
+    let (input, (tag_type, value)) = Tlv::PPPOE.parse(input)?;
+
+    Ok((
+        input,
+        Tag::new(TagType::try_from(tag_type as u16).unwrap(), value),
+    ))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    pub code: Code,
+    pub session_id: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Build a discovery packet from its tags.
+    pub fn discovery(code: Code, session_id: u16, tags: &[Tag]) -> Self {
+        Self {
+            code,
+            session_id,
+            payload: tags.iter().flat_map(Tag::encode).collect(),
+        }
+    }
+
+    /// Build a session packet carrying a PPP frame.
+    pub fn session(session_id: u16, protocol: Protocol, payload: &[u8]) -> Self {
+        Self {
+            code: Code::Session,
+            session_id,
+            payload: encode!(protocol, payload),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode!(
+            VERSION_TYPE,
+            self.code,
+            self.session_id,
+            self.payload.len() as u16,
+            &self.payload[..],
+        )
+    }
+
+    pub fn tags(&self) -> AHResult<Vec<Tag>> {
+        try_parse!(
+            &self.payload[..],
+            {
+                let (input, tags) = terminated(many0(tag), eof)(&self.payload[..])?;
+
+                // Anything after the end-of-list tag is ignored.
+                Ok((
+                    input,
+                    tags.into_iter()
+                        .take_while(|t| t.tag_type != TagType::EndOfList)
+                        .collect(),
+                ))
+            },
+            "parsing pppoe tags failed: {}"
+        )
+    }
+
+    /// Split a session packet's payload into its PPP protocol and data.
+    pub fn ppp(&self) -> AHResult<(Protocol, &[u8])> {
+        try_parse!(
+            &self.payload[..],
+            {
+                let (input, protocol) = map_res(be_u16, Protocol::try_from)(&self.payload[..])?;
+
+                Ok((input, (protocol, input)))
+            },
+            "parsing ppp frame failed: {}"
+        )
+    }
+}
+
+pub fn packet(input: &[u8]) -> AHResult<Packet> {
+    try_parse!(
+        input,
+        {
+            let (input, _) = nom::combinator::verify(be_u8, |v| *v == VERSION_TYPE)(input)?;
+            let (input, code) = map_res(be_u8, Code::try_from)(input)?;
+            let (input, session_id) = be_u16(input)?;
+            // Ethernet padding may follow the payload.
+            let (input, payload) = length_data(be_u16)(input)?;
+
+            Ok((
+                input,
+                Packet {
+                    code,
+                    session_id,
+                    payload: payload.to_vec(),
+                },
+            ))
+        },
+        "parsing pppoe packet failed: {}"
+    )
+}
+
+// Ref: https://www.iana.org/assignments/ppp-numbers/ppp-numbers.xhtml
+proto_enum_with_unknown!(Protocol, u16, {
+    Ipv4 = 0x0021,
+    Ipcp = 0x8021,
+    Lcp = 0xc021,
+});
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc1661#section-5
+proto_enum_with_unknown!(ControlCode, u8, {
+    ConfigureRequest = 1,
+    ConfigureAck = 2,
+    ConfigureNak = 3,
+    ConfigureReject = 4,
+    TerminateRequest = 5,
+    TerminateAck = 6,
+    CodeReject = 7,
+    ProtocolReject = 8,
+    EchoRequest = 9,
+    EchoReply = 10,
+    DiscardRequest = 11,
+});
+
+/// An LCP or IPCP packet, which share a format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlPacket {
+    pub code: ControlCode,
+    pub identifier: u8,
+    pub data: Vec<u8>,
+}
+
+impl ControlPacket {
+    pub fn configure(code: ControlCode, identifier: u8, options: &[ConfigOption]) -> Self {
+        Self {
+            code,
+            identifier,
+            data: options.iter().flat_map(ConfigOption::encode).collect(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode!(
+            self.code,
+            self.identifier,
+            (self.data.len() + 4) as u16,
+            &self.data[..],
+        )
+    }
+
+    pub fn options(&self) -> AHResult<Vec<ConfigOption>> {
+        try_parse!(
+            &self.data[..],
+            { terminated(many0(config_option), eof)(&self.data[..]) },
+            "parsing ppp configuration options failed: {}"
+        )
+    }
+}
+
+pub fn control_packet(input: &[u8]) -> AHResult<ControlPacket> {
+    try_parse!(
+        input,
+        {
+            let (input, code) = map_res(be_u8, ControlCode::try_from)(input)?;
+            let (input, identifier) = be_u8(input)?;
+            let (input, data) =
+                length_data(map(nom::combinator::verify(be_u16, |l| *l >= 4), |l| l - 4))(input)?;
+
+            Ok((
+                input,
+                ControlPacket {
+                    code,
+                    identifier,
+                    data: data.to_vec(),
+                },
+            ))
+        },
+        "parsing ppp control packet failed: {}"
+    )
+}
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc1661#section-6
+pub const LCP_MRU: u8 = 1;
+pub const LCP_ACCM: u8 = 2;
+pub const LCP_MAGIC_NUMBER: u8 = 5;
+pub const LCP_PROTOCOL_COMPRESSION: u8 = 7;
+pub const LCP_ADDRESS_COMPRESSION: u8 = 8;
+// Ref: https://datatracker.ietf.org/doc/html/rfc1332#section-3
+pub const IPCP_ADDRESS: u8 = 3;
+// Ref: https://datatracker.ietf.org/doc/html/rfc1877
+pub const IPCP_PRIMARY_DNS: u8 = 129;
+pub const IPCP_SECONDARY_DNS: u8 = 131;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigOption {
+    pub option_type: u8,
+    pub value: Vec<u8>,
+}
+
+impl ConfigOption {
+    pub fn new(option_type: u8, value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            option_type,
+            value: value.into(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        Tlv::PPP.encode(self.option_type, &self.value[..])
+    }
+}
+
+fn config_option(input: &[u8]) -> BIResult<'_, ConfigOption> {
+    let (input, (option_type, value)) = Tlv::PPP.parse(input)?;
+
+    Ok((input, ConfigOption::new(option_type as u8, value)))
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub ac_name: String,
+    /// Clients asking for any other service are ignored; any service is fine when empty.
+    pub service_name: String,
+    /// Our end of every session.
+    pub local_address: ipv4::Address,
+    /// First address handed out to clients, counting up from there.
+    pub pool_start: ipv4::Address,
+    pub pool_size: u32,
+    /// Offered to clients that ask, in order.
+    pub dns_servers: Vec<ipv4::Address>,
+}
+
+/// One side's configuration of an LCP or IPCP link.
+#[derive(Debug)]
+struct Negotiation {
+    /// The options we're requesting, adjusted as the client naks and rejects them.
+    ours: Vec<ConfigOption>,
+    identifier: u8,
+    ours_acked: bool,
+    theirs_acked: bool,
+}
+
+impl Negotiation {
+    fn new(ours: Vec<ConfigOption>) -> Self {
+        Self {
+            ours,
+            identifier: 0,
+            ours_acked: false,
+            theirs_acked: false,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.ours_acked && self.theirs_acked
+    }
+
+    fn request(&mut self) -> ControlPacket {
+        self.identifier = self.identifier.wrapping_add(1);
+
+        ControlPacket::configure(ControlCode::ConfigureRequest, self.identifier, &self.ours)
+    }
+
+    /// Handle a reply to our request, returning a new request if it has to be retried.
+    fn handle_reply(&mut self, reply: &ControlPacket) -> AHResult<Option<ControlPacket>> {
+        if reply.identifier != self.identifier {
+            return Ok(None);
+        }
+
+        match reply.code {
+            ControlCode::ConfigureAck => {
+                self.ours_acked = true;
+                Ok(None)
+            }
+            ControlCode::ConfigureNak => {
+                for suggested in reply.options()? {
+                    for option in &mut self.ours {
+                        if option.option_type == suggested.option_type {
+                            option.value = suggested.value.clone();
+                        }
+                    }
+                }
+                Ok(Some(self.request()))
+            }
+            ControlCode::ConfigureReject => {
+                let rejected = reply.options()?;
+                self.ours
+                    .retain(|o| !rejected.iter().any(|r| r.option_type == o.option_type));
+                Ok(Some(self.request()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Reply to a Configure-Request, given what to do with each option: `Ok(())` to accept it,
+/// `Err(Some(option))` to nak it with a suggestion, or `Err(None)` to reject it.
+fn configure_reply(
+    request: &ControlPacket,
+    judge: impl Fn(&ConfigOption) -> Result<(), Option<ConfigOption>>,
+) -> AHResult<ControlPacket> {
+    let mut naks = Vec::new();
+    let mut rejects = Vec::new();
+
+    for option in request.options()? {
+        match judge(&option) {
+            Ok(()) => {}
+            Err(Some(suggestion)) => naks.push(suggestion),
+            Err(None) => rejects.push(option),
+        }
+    }
+
+    // Ref: https://datatracker.ietf.org/doc/html/rfc1661#section-5.3
+    Ok(if !rejects.is_empty() {
+        ControlPacket::configure(ControlCode::ConfigureReject, request.identifier, &rejects)
+    } else if !naks.is_empty() {
+        ControlPacket::configure(ControlCode::ConfigureNak, request.identifier, &naks)
+    } else {
+        ControlPacket {
+            code: ControlCode::ConfigureAck,
+            ..request.clone()
+        }
+    })
+}
+
+#[derive(Debug)]
+struct Session {
+    client: ether::Address,
+    address: ipv4::Address,
+    magic_number: u32,
+    lcp: Negotiation,
+    ipcp: Negotiation,
+}
+
+/// Protocol state for every client, turning each frame received into the frames to send back.
+pub struct Concentrator {
+    config: Config,
+    ether_address: ether::Address,
+    sessions: HashMap<u16, Session>,
+    next_session_id: u16,
+}
+
+impl Concentrator {
+    pub fn new(config: Config, ether_address: ether::Address) -> Self {
+        Self {
+            config,
+            ether_address,
+            sessions: HashMap::new(),
+            next_session_id: 1,
+        }
+    }
+
+    fn frame(&self, ethertype: ether::Type, dest: ether::Address, packet: Packet) -> ether::Frame {
+        ether::Frame {
+            dest,
+            src: self.ether_address,
+            ethertype,
+            payload: packet.encode(),
+            received: None,
+        }
+    }
+
+    fn control_frame(
+        &self,
+        client: ether::Address,
+        session_id: u16,
+        protocol: Protocol,
+        packet: ControlPacket,
+    ) -> ether::Frame {
+        self.frame(
+            ether::Type::PppoeSession,
+            client,
+            Packet::session(session_id, protocol, &packet.encode()),
+        )
+    }
+
+    /// Cookie for a client, so requests can be matched to our offers without keeping state.
+    fn cookie(&self, client: ether::Address) -> Vec<u8> {
+        let mut cookie = self.ether_address.0.to_vec();
+        cookie.extend_from_slice(&client.0);
+
+        cookie
+    }
+
+    fn wants_our_service(&self, tags: &[Tag]) -> bool {
+        tags.iter().any(|t| {
+            t.tag_type == TagType::ServiceName
+                && (t.value.is_empty() || t.value == self.config.service_name.as_bytes())
+        })
+    }
+
+    /// Tags a reply has to copy from the request.
+    fn echoed_tags(tags: &[Tag]) -> impl Iterator<Item = Tag> + '_ {
+        tags.iter()
+            .filter(|t| matches!(t.tag_type, TagType::HostUniq | TagType::RelaySessionId))
+            .cloned()
+    }
+
+    fn free_address(&self) -> Option<ipv4::Address> {
+        let start = u32::from_be_bytes(self.config.pool_start.0);
+
+        (0..self.config.pool_size)
+            .map(|i| ipv4::Address(start.wrapping_add(i).to_be_bytes()))
+            .find(|a| !self.sessions.values().any(|s| s.address == *a))
+    }
+
+    fn free_session_id(&mut self) -> u16 {
+        // 0 and 0xffff are reserved.
+        while self.next_session_id == 0
+            || self.next_session_id == 0xffff
+            || self.sessions.contains_key(&self.next_session_id)
+        {
+            self.next_session_id = self.next_session_id.wrapping_add(1);
+        }
+
+        let result = self.next_session_id;
+        self.next_session_id = self.next_session_id.wrapping_add(1);
+
+        result
+    }
+
+    pub fn handle(&mut self, frame: &ether::Frame) -> AHResult<Vec<ether::Frame>> {
+        let packet = packet(&frame.payload)?;
+
+        match (frame.ethertype, packet.code) {
+            (ether::Type::PppoeDiscovery, Code::Initiation) => {
+                self.handle_initiation(frame, &packet)
+            }
+            (ether::Type::PppoeDiscovery, Code::Request) => self.handle_request(frame, &packet),
+            (ether::Type::PppoeDiscovery, Code::Terminate) => {
+                if self
+                    .sessions
+                    .get(&packet.session_id)
+                    .is_some_and(|s| s.client == frame.src)
+                {
+                    self.close(packet.session_id);
+                }
+                Ok(vec![])
+            }
+            (ether::Type::PppoeSession, Code::Session) => {
+                match self.sessions.get(&packet.session_id) {
+                    Some(session) if session.client == frame.src => {
+                        self.handle_session(packet.session_id, &packet)
+                    }
+                    _ => Ok(vec![]),
+                }
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn handle_initiation(
+        &self,
+        frame: &ether::Frame,
+        packet: &Packet,
+    ) -> AHResult<Vec<ether::Frame>> {
+        let tags = packet.tags()?;
+        if !self.wants_our_service(&tags) {
+            return Ok(vec![]);
+        }
+
+        let mut offer = vec![
+            Tag::new(TagType::AcName, self.config.ac_name.as_bytes()),
+            Tag::new(TagType::ServiceName, self.config.service_name.as_bytes()),
+            Tag::new(TagType::AcCookie, self.cookie(frame.src)),
+        ];
+        offer.extend(Self::echoed_tags(&tags));
+
+        Ok(vec![self.frame(
+            ether::Type::PppoeDiscovery,
+            frame.src,
+            Packet::discovery(Code::Offer, 0, &offer),
+        )])
+    }
+
+    fn handle_request(
+        &mut self,
+        frame: &ether::Frame,
+        packet: &Packet,
+    ) -> AHResult<Vec<ether::Frame>> {
+        let tags = packet.tags()?;
+        let cookie = self.cookie(frame.src);
+        if !self.wants_our_service(&tags)
+            || tags
+                .iter()
+                .any(|t| t.tag_type == TagType::AcCookie && t.value != cookie)
+        {
+            return Ok(vec![]);
+        }
+
+        let mut confirmation = vec![Tag::new(
+            TagType::ServiceName,
+            self.config.service_name.as_bytes(),
+        )];
+        confirmation.extend(Self::echoed_tags(&tags));
+
+        let address = match self.free_address() {
+            Some(address) => address,
+            None => {
+                confirmation.push(Tag::new(TagType::AcSystemError, "address pool exhausted"));
+
+                return Ok(vec![self.frame(
+                    ether::Type::PppoeDiscovery,
+                    frame.src,
+                    Packet::discovery(Code::SessionConfirmation, 0, &confirmation),
+                )]);
+            }
+        };
+
+        let session_id = self.free_session_id();
+        let magic_number = rand::random();
+        let mut lcp = Negotiation::new(vec![
+            ConfigOption::new(LCP_MRU, MRU.to_be_bytes()),
+            ConfigOption::new(LCP_MAGIC_NUMBER, u32::to_be_bytes(magic_number)),
+        ]);
+        let request = lcp.request();
+
+        self.sessions.insert(
+            session_id,
+            Session {
+                client: frame.src,
+                address,
+                magic_number,
+                lcp,
+                ipcp: Negotiation::new(vec![ConfigOption::new(
+                    IPCP_ADDRESS,
+                    self.config.local_address.0,
+                )]),
+            },
+        );
+        self.report();
+
+        Ok(vec![
+            self.frame(
+                ether::Type::PppoeDiscovery,
+                frame.src,
+                Packet::discovery(Code::SessionConfirmation, session_id, &confirmation),
+            ),
+            self.control_frame(frame.src, session_id, Protocol::Lcp, request),
+        ])
+    }
+
+    fn handle_session(&mut self, session_id: u16, packet: &Packet) -> AHResult<Vec<ether::Frame>> {
+        let (protocol, data) = packet.ppp()?;
+        // Looked up first, since terminating removes the session.
+        let client = self.sessions[&session_id].client;
+
+        let replies = match protocol {
+            Protocol::Lcp => self.handle_lcp(session_id, &control_packet(data)?)?,
+            Protocol::Ipcp if self.sessions[&session_id].lcp.is_open() => {
+                self.handle_ipcp(session_id, &control_packet(data)?)?
+            }
+            _ => vec![],
+        };
+
+        Ok(replies
+            .into_iter()
+            .map(|(protocol, reply)| self.control_frame(client, session_id, protocol, reply))
+            .collect())
+    }
+
+    fn handle_lcp(
+        &mut self,
+        session_id: u16,
+        packet: &ControlPacket,
+    ) -> AHResult<Vec<(Protocol, ControlPacket)>> {
+        let session = self.sessions.get_mut(&session_id).unwrap();
+        let was_open = session.lcp.is_open();
+        let mut replies = Vec::new();
+
+        match packet.code {
+            ControlCode::ConfigureRequest => {
+                let reply = configure_reply(packet, |option| match option.option_type {
+                    LCP_MRU
+                    | LCP_ACCM
+                    | LCP_MAGIC_NUMBER
+                    | LCP_PROTOCOL_COMPRESSION
+                    | LCP_ADDRESS_COMPRESSION => Ok(()),
+                    // Including authentication, which we have no way to do.
+                    _ => Err(None),
+                })?;
+                session.lcp.theirs_acked = reply.code == ControlCode::ConfigureAck;
+                replies.push((Protocol::Lcp, reply));
+            }
+            ControlCode::ConfigureAck
+            | ControlCode::ConfigureNak
+            | ControlCode::ConfigureReject => {
+                if let Some(request) = session.lcp.handle_reply(packet)? {
+                    replies.push((Protocol::Lcp, request));
+                }
+            }
+            ControlCode::EchoRequest => {
+                let mut data = session.magic_number.to_be_bytes().to_vec();
+                data.extend(packet.data.iter().skip(4));
+                replies.push((
+                    Protocol::Lcp,
+                    ControlPacket {
+                        code: ControlCode::EchoReply,
+                        identifier: packet.identifier,
+                        data,
+                    },
+                ));
+            }
+            ControlCode::TerminateRequest => {
+                self.close(session_id);
+
+                return Ok(vec![(
+                    Protocol::Lcp,
+                    ControlPacket {
+                        code: ControlCode::TerminateAck,
+                        identifier: packet.identifier,
+                        data: vec![],
+                    },
+                )]);
+            }
+            _ => {}
+        }
+
+        if !was_open && session.lcp.is_open() {
+            replies.push((Protocol::Ipcp, session.ipcp.request()));
+        }
+
+        Ok(replies)
+    }
+
+    fn handle_ipcp(
+        &mut self,
+        session_id: u16,
+        packet: &ControlPacket,
+    ) -> AHResult<Vec<(Protocol, ControlPacket)>> {
+        let dns_servers = &self.config.dns_servers;
+        let session = self.sessions.get_mut(&session_id).unwrap();
+        let was_open = session.ipcp.is_open();
+        let mut replies = Vec::new();
+
+        match packet.code {
+            ControlCode::ConfigureRequest => {
+                let address = session.address;
+                let reply = configure_reply(packet, |option| {
+                    let wanted = match option.option_type {
+                        IPCP_ADDRESS => address,
+                        IPCP_PRIMARY_DNS => *dns_servers.first().ok_or(None)?,
+                        IPCP_SECONDARY_DNS => *dns_servers.get(1).ok_or(None)?,
+                        _ => return Err(None),
+                    };
+
+                    if option.value == wanted.0 {
+                        Ok(())
+                    } else {
+                        Err(Some(ConfigOption::new(option.option_type, wanted.0)))
+                    }
+                })?;
+                session.ipcp.theirs_acked = reply.code == ControlCode::ConfigureAck;
+                replies.push((Protocol::Ipcp, reply));
+            }
+            ControlCode::ConfigureAck
+            | ControlCode::ConfigureNak
+            | ControlCode::ConfigureReject => {
+                if let Some(request) = session.ipcp.handle_reply(packet)? {
+                    replies.push((Protocol::Ipcp, request));
+                }
+            }
+            _ => {}
+        }
+
+        if was_open != session.ipcp.is_open() {
+            self.report();
+        }
+
+        Ok(replies)
+    }
+
+    fn close(&mut self, session_id: u16) {
+        self.sessions.remove(&session_id);
+        self.report();
+    }
+
+    fn report(&self) {
+        let sessions = self
+            .sessions
+            .iter()
+            .map(|(&session_id, session)| {
+                (
+                    session_id,
+                    status::PppoeSessionStatus {
+                        client: session.client,
+                        address: session.address,
+                        open: session.ipcp.is_open(),
+                    },
+                )
+            })
+            .collect();
+
+        status::update(|s| s.pppoe_sessions = sessions);
+    }
+}
+
+pub struct Server {
+    receiver: channel::Receiver<ether::Frame>,
+    write_sender: channel::Sender<ether::Frame>,
+    concentrator: Concentrator,
+}
+
+impl Server {
+    pub fn new(interface: &mut impl ether::Server, config: Config) -> AHResult<Self> {
+        let (sender, receiver) = channel::bounded(1024);
+        for ethertype in [ether::Type::PppoeDiscovery, ether::Type::PppoeSession] {
+            interface.register_with_backpressure(
+                ethertype,
+                sender.clone(),
+                Backpressure::DropOldest(receiver.clone()),
+            );
+        }
+
+        Ok(Self {
+            receiver,
+            write_sender: interface.writer(),
+            concentrator: Concentrator::new(config, interface.if_hwaddr()?),
+        })
+    }
+
+    pub fn start(self) {
+        let Self {
+            receiver,
+            write_sender,
+            mut concentrator,
+        } = self;
+
+        thread::spawn(move || {
+            for frame in receiver {
+                match concentrator.handle(&frame) {
+                    Ok(replies) => {
+                        for reply in replies {
+                            write_sender.send(reply).unwrap();
+                        }
+                    }
+                    Err(e) => warn!("ignoring pppoe frame from {}: {}", frame.src, e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: ether::Address = ether::Address([2, 0, 0, 0, 0, 2]);
+
+    fn concentrator() -> Concentrator {
+        Concentrator::new(
+            Config {
+                ac_name: "fakenet".to_string(),
+                service_name: "internet".to_string(),
+                local_address: "10.0.0.1".parse().unwrap(),
+                pool_start: "10.0.0.100".parse().unwrap(),
+                pool_size: 1,
+                dns_servers: vec!["10.0.0.53".parse().unwrap()],
+            },
+            ether::Address([2, 0, 0, 0, 0, 1]),
+        )
+    }
+
+    fn discovery(code: Code, tags: &[Tag]) -> ether::Frame {
+        ether::Frame {
+            dest: ether::Address::BROADCAST,
+            src: CLIENT,
+            ethertype: ether::Type::PppoeDiscovery,
+            payload: Packet::discovery(code, 0, tags).encode(),
+            received: None,
+        }
+    }
+
+    fn control(session_id: u16, protocol: Protocol, packet: ControlPacket) -> ether::Frame {
+        ether::Frame {
+            dest: ether::Address([2, 0, 0, 0, 0, 1]),
+            src: CLIENT,
+            ethertype: ether::Type::PppoeSession,
+            payload: Packet::session(session_id, protocol, &packet.encode()).encode(),
+            received: None,
+        }
+    }
+
+    fn replies(frames: Vec<ether::Frame>) -> Vec<(Protocol, ControlPacket)> {
+        frames
+            .iter()
+            .map(|frame| {
+                let packet = packet(&frame.payload).unwrap();
+                let (protocol, data) = packet.ppp().unwrap();
+
+                (protocol, control_packet(data).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packet_round_trips() {
+        // PADI with an empty service name and a host-uniq, then ethernet padding.
+        let encoded = hex::decode("1109000000100101000001030008000000000000002a00000000").unwrap();
+        let parsed = packet(&encoded).unwrap();
+
+        assert_eq!(parsed.code, Code::Initiation);
+        assert_eq!(
+            parsed.tags().unwrap(),
+            vec![
+                Tag::new(TagType::ServiceName, vec![]),
+                Tag::new(TagType::HostUniq, vec![0, 0, 0, 0, 0, 0, 0, 0x2a]),
+            ]
+        );
+        assert_eq!(parsed.encode(), &encoded[..22]);
+    }
+
+    #[test]
+    fn client_gets_session_and_address() {
+        let mut concentrator = concentrator();
+        let host_uniq = Tag::new(TagType::HostUniq, vec![1, 2]);
+
+        assert!(concentrator
+            .handle(&discovery(
+                Code::Initiation,
+                &[Tag::new(TagType::ServiceName, "video")]
+            ))
+            .unwrap()
+            .is_empty());
+
+        let offer = concentrator
+            .handle(&discovery(
+                Code::Initiation,
+                &[Tag::new(TagType::ServiceName, ""), host_uniq.clone()],
+            ))
+            .unwrap();
+        let offer_tags = packet(&offer[0].payload).unwrap().tags().unwrap();
+        assert_eq!(offer[0].dest, CLIENT);
+        assert!(offer_tags.contains(&host_uniq));
+        let cookie = offer_tags
+            .into_iter()
+            .find(|t| t.tag_type == TagType::AcCookie)
+            .unwrap();
+
+        let mut confirmation = concentrator
+            .handle(&discovery(
+                Code::Request,
+                &[Tag::new(TagType::ServiceName, "internet"), cookie],
+            ))
+            .unwrap();
+        let lcp_request = replies(confirmation.split_off(1)).remove(0).1;
+        let session_id = packet(&confirmation[0].payload).unwrap().session_id;
+        assert_ne!(session_id, 0);
+
+        // Their LCP request is acked, then ours; IPCP starts once both are.
+        let ours = ControlPacket::configure(
+            ControlCode::ConfigureRequest,
+            7,
+            &[ConfigOption::new(LCP_MRU, MRU.to_be_bytes())],
+        );
+        assert_eq!(
+            replies(
+                concentrator
+                    .handle(&control(session_id, Protocol::Lcp, ours))
+                    .unwrap()
+            )[0]
+            .1
+            .code,
+            ControlCode::ConfigureAck
+        );
+        let ack = ControlPacket {
+            code: ControlCode::ConfigureAck,
+            ..lcp_request
+        };
+        let ipcp_request = replies(
+            concentrator
+                .handle(&control(session_id, Protocol::Lcp, ack))
+                .unwrap(),
+        )
+        .remove(0);
+        assert_eq!(ipcp_request.0, Protocol::Ipcp);
+
+        // Asking for 0.0.0.0 gets the pool address suggested instead, and no DNS is rejected.
+        let ipcp = |options: &[ConfigOption]| {
+            ControlPacket::configure(ControlCode::ConfigureRequest, 1, options)
+        };
+        let nak = replies(
+            concentrator
+                .handle(&control(
+                    session_id,
+                    Protocol::Ipcp,
+                    ipcp(&[
+                        ConfigOption::new(IPCP_ADDRESS, [0; 4]),
+                        ConfigOption::new(IPCP_PRIMARY_DNS, [0; 4]),
+                    ]),
+                ))
+                .unwrap(),
+        )
+        .remove(0)
+        .1;
+        assert_eq!(nak.code, ControlCode::ConfigureNak);
+        assert_eq!(
+            nak.options().unwrap(),
+            vec![
+                ConfigOption::new(IPCP_ADDRESS, [10, 0, 0, 100]),
+                ConfigOption::new(IPCP_PRIMARY_DNS, [10, 0, 0, 53]),
+            ]
+        );
+        let reject = replies(
+            concentrator
+                .handle(&control(
+                    session_id,
+                    Protocol::Ipcp,
+                    ipcp(&[ConfigOption::new(IPCP_SECONDARY_DNS, [0; 4])]),
+                ))
+                .unwrap(),
+        )
+        .remove(0)
+        .1;
+        assert_eq!(reject.code, ControlCode::ConfigureReject);
+
+        concentrator
+            .handle(&control(
+                session_id,
+                Protocol::Ipcp,
+                ipcp(&[ConfigOption::new(IPCP_ADDRESS, [10, 0, 0, 100])]),
+            ))
+            .unwrap();
+        concentrator
+            .handle(&control(
+                session_id,
+                Protocol::Ipcp,
+                ControlPacket {
+                    code: ControlCode::ConfigureAck,
+                    ..ipcp_request.1
+                },
+            ))
+            .unwrap();
+        assert!(concentrator.sessions[&session_id].ipcp.is_open());
+
+        // The pool only has one address.
+        let exhausted = concentrator
+            .handle(&discovery(
+                Code::Request,
+                &[Tag::new(TagType::ServiceName, "")],
+            ))
+            .unwrap();
+        assert_eq!(packet(&exhausted[0].payload).unwrap().session_id, 0);
+    }
+}
@@ -0,0 +1,646 @@
+//! Just enough SCTP to answer like a real endpoint: associations are set up through the cookie
+//! exchange on listening ports, and refused or ignored on others as their policy says. Data is
+//! acknowledged and dropped.
+// Ref: https://datatracker.ietf.org/doc/html/rfc9260
+
+use anyhow::{bail, Result as AHResult};
+use blake2::digest::{consts::U16, FixedOutput, KeyInit, Update};
+use blake2::Blake2sMac;
+use crossbeam::channel;
+use nom::{
+    bytes::complete::take,
+    multi::many0,
+    number::complete::{be_u16, be_u32, be_u8},
+};
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+
+use super::encdec::{round_up_to_next, BIResult, EncodeTo, Tlv};
+use super::port_policy::{PortPolicy, UnboundPort};
+use super::utils::{Backpressure, KeyedDispatcher};
+use super::{ipv4, ipv6};
+use crate::executor::{Actor, Executor};
+use crate::{encode, encode_to, proto_enum_with_unknown, supervisor, try_parse, warn};
+
+/// Receive window we advertise, never shrinking as data is dropped.
+const A_RWND: u32 = 65536;
+
+/// Streams we offer and accept in each direction.
+const STREAMS: u16 = 16;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc9260#section-3.3.3.1
+const PARAMETER_STATE_COOKIE: u16 = 7;
+
+/// Set in ABORT and SHUTDOWN COMPLETE when the verification tag is the one the packet answered
+/// carried, rather than the peer's own.
+// Ref: https://datatracker.ietf.org/doc/html/rfc9260#section-3.3.7
+const FLAG_T: u8 = 0x1;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc9260#section-3.2
+proto_enum_with_unknown!(ChunkType, u8, {
+    Data = 0,
+    Init = 1,
+    InitAck = 2,
+    Sack = 3,
+    Heartbeat = 4,
+    HeartbeatAck = 5,
+    Abort = 6,
+    Shutdown = 7,
+    ShutdownAck = 8,
+    OperationError = 9,
+    CookieEcho = 10,
+    CookieAck = 11,
+    ShutdownComplete = 14,
+});
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    pub chunk_type: ChunkType,
+    pub flags: u8,
+    pub value: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn new(chunk_type: ChunkType, flags: u8, value: Vec<u8>) -> Self {
+        Self {
+            chunk_type,
+            flags,
+            value,
+        }
+    }
+
+    fn parse(input: &[u8]) -> BIResult<'_, Self> {
+        let (input, chunk_type) = be_u8(input)?;
+        let (input, flags) = be_u8(input)?;
+        let (input, length) = be_u16(input)?;
+        let (input, value) = take((length as usize).saturating_sub(4))(input)?;
+        // Padding missing after the last chunk is tolerated.
+        let padding = round_up_to_next(length as usize, 4) - length as usize;
+        let (input, _) = take(padding.min(input.len()))(input)?;
+
+        Ok((
+            input,
+            Self::new(
+                ChunkType::try_from(chunk_type).unwrap(),
+                flags,
+                value.to_vec(),
+            ),
+        ))
+    }
+}
+
+impl EncodeTo for Chunk {
+    fn encoded_len(&self) -> usize {
+        round_up_to_next(4 + self.value.len(), 4)
+    }
+
+    fn encode_to(&self, buf: &mut [u8]) {
+        encode_to!(
+            buf,
+            self.chunk_type,
+            self.flags,
+            (4 + self.value.len()) as u16,
+            &self.value[..],
+        );
+    }
+}
+
+/// CRC32c, as SCTP checksums packets with.
+// Ref: https://datatracker.ietf.org/doc/html/rfc9260#appendix-A
+fn crc32c(input: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in input {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    pub src_port: u16,
+    pub dest_port: u16,
+    pub verification_tag: u32,
+    pub chunks: Vec<Chunk>,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = encode!(
+            self.src_port,
+            self.dest_port,
+            self.verification_tag,
+            0u32, // Checksum
+            self.chunks,
+        );
+
+        // The CRC goes in least significant byte first, unlike everything else.
+        let checksum = crc32c(&buffer);
+        buffer[8..12].copy_from_slice(&checksum.to_le_bytes());
+
+        buffer
+    }
+
+    /// A packet from `dest_port` back to the sender of `self`, with the one chunk.
+    fn answer(&self, verification_tag: u32, chunk: Chunk) -> Self {
+        Self {
+            src_port: self.dest_port,
+            dest_port: self.src_port,
+            verification_tag,
+            chunks: vec![chunk],
+        }
+    }
+}
+
+pub fn packet(input: &[u8]) -> AHResult<Packet> {
+    if input.len() < 12 {
+        bail!("sctp packet too short: {} bytes", input.len());
+    }
+
+    let mut zeroed = input.to_vec();
+    zeroed[8..12].fill(0);
+    let checksum = u32::from_le_bytes(input[8..12].try_into().unwrap());
+    if crc32c(&zeroed) != checksum {
+        bail!("sctp checksum invalid: {:x}", checksum);
+    }
+
+    try_parse!(
+        input,
+        {
+            let (input, src_port) = be_u16(input)?;
+            let (input, dest_port) = be_u16(input)?;
+            let (input, verification_tag) = be_u32(input)?;
+            let (input, _checksum) = be_u32(input)?;
+            let (input, chunks) = many0(Chunk::parse)(input)?;
+
+            Ok((
+                input,
+                Packet {
+                    src_port,
+                    dest_port,
+                    verification_tag,
+                    chunks,
+                },
+            ))
+        },
+        "parsing sctp packet failed: {}"
+    )
+}
+
+/// The value of an INIT or INIT ACK chunk.
+// Ref: https://datatracker.ietf.org/doc/html/rfc9260#section-3.3.2
+#[derive(Clone, Debug, PartialEq)]
+pub struct Init {
+    pub initiate_tag: u32,
+    pub a_rwnd: u32,
+    pub outbound_streams: u16,
+    pub inbound_streams: u16,
+    pub initial_tsn: u32,
+    /// Parameters, as type and value.
+    pub parameters: Vec<(u16, Vec<u8>)>,
+}
+
+impl Init {
+    pub fn parse(input: &[u8]) -> AHResult<Self> {
+        try_parse!(
+            input,
+            {
+                let (input, initiate_tag) = be_u32(input)?;
+                let (input, a_rwnd) = be_u32(input)?;
+                let (input, outbound_streams) = be_u16(input)?;
+                let (input, inbound_streams) = be_u16(input)?;
+                let (input, initial_tsn) = be_u32(input)?;
+                let (input, parameters) = many0(|input| Tlv::SCTP.parse(input))(input)?;
+
+                Ok((
+                    input,
+                    Init {
+                        initiate_tag,
+                        a_rwnd,
+                        outbound_streams,
+                        inbound_streams,
+                        initial_tsn,
+                        parameters: parameters
+                            .into_iter()
+                            .map(|(t, value)| (t as u16, value.to_vec()))
+                            .collect(),
+                    },
+                ))
+            },
+            "parsing sctp init failed: {}"
+        )
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let parameters: Vec<u8> = self
+            .parameters
+            .iter()
+            .flat_map(|(parameter_type, value)| Tlv::SCTP.encode(*parameter_type, &value[..]))
+            .collect();
+
+        encode!(
+            self.initiate_tag,
+            self.a_rwnd,
+            self.outbound_streams,
+            self.inbound_streams,
+            self.initial_tsn,
+            &parameters[..],
+        )
+    }
+
+    /// Value of the first parameter of `parameter_type`.
+    pub fn parameter(&self, parameter_type: u16) -> Option<&[u8]> {
+        self.parameters
+            .iter()
+            .find(|(t, _)| *t == parameter_type)
+            .map(|(_, value)| &value[..])
+    }
+}
+
+/// The ends of an association, as seen from our side.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct AssociationKey {
+    local: ipv6::Address,
+    local_port: u16,
+    remote: ipv6::Address,
+    remote_port: u16,
+    /// The tag the peer puts in everything it sends us.
+    local_tag: u32,
+}
+
+impl AssociationKey {
+    fn of(src: ipv6::Address, dest: ipv6::Address, packet: &Packet, local_tag: u32) -> Self {
+        Self {
+            local: dest,
+            local_port: packet.dest_port,
+            remote: src,
+            remote_port: packet.src_port,
+            local_tag,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        encode!(
+            self.local,
+            self.local_port,
+            self.remote,
+            self.remote_port,
+            self.local_tag,
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct Server {
+    ipv6_receiver: channel::Receiver<ipv6::Packet>,
+    ipv6_sender: channel::Sender<ipv6::Packet>,
+    port_policy: PortPolicy,
+    listening: HashSet<u16>,
+    hop_limit: ipv6::HopLimitHandle,
+    /// Key for the MACs that let state cookies come back to us unforged.
+    secret: [u8; 32],
+    /// The peer's tag for each association set up.
+    associations: HashMap<AssociationKey, u32>,
+}
+
+impl Server {
+    pub fn new(ipv6_server: &mut ipv6::Server) -> AHResult<Self> {
+        let (ipv6_sender, ipv6_receiver) = channel::bounded(1024);
+
+        ipv6_server.register_with_backpressure(
+            ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Sctp),
+            ipv6_sender,
+            Backpressure::DropOldest(ipv6_receiver.clone()),
+        );
+
+        Ok(Self {
+            ipv6_receiver,
+            ipv6_sender: ipv6_server.writer(),
+            port_policy: PortPolicy::default(),
+            listening: HashSet::new(),
+            hop_limit: ipv6_server.hop_limit(),
+            secret: rand::random(),
+            associations: HashMap::new(),
+        })
+    }
+
+    pub fn set_port_policy(&mut self, port_policy: PortPolicy) {
+        self.port_policy = port_policy;
+    }
+
+    /// Accept associations on `port`; must be called before the server is started.
+    pub fn listen(&mut self, port: u16) {
+        self.listening.insert(port);
+    }
+
+    /// State cookie for `key`, with the peer's tag, that only we could have made.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc9260#section-5.1.3
+    fn cookie(&self, key: &AssociationKey, peer_tag: u32) -> Vec<u8> {
+        let mut mac = <Blake2sMac<U16> as KeyInit>::new_from_slice(&self.secret).unwrap();
+        Update::update(&mut mac, &key.encode());
+        Update::update(&mut mac, &peer_tag.to_be_bytes());
+        let mac: [u8; 16] = mac.finalize_fixed().into();
+
+        encode!(key.local_tag, peer_tag, mac)
+    }
+
+    /// The tags in `cookie`, if we made it for the association `packet` belongs to.
+    fn check_cookie(
+        &self,
+        src: ipv6::Address,
+        dest: ipv6::Address,
+        packet: &Packet,
+        cookie: &[u8],
+    ) -> Option<(AssociationKey, u32)> {
+        if cookie.len() != 24 {
+            return None;
+        }
+        let local_tag = u32::from_be_bytes(cookie[..4].try_into().unwrap());
+        let peer_tag = u32::from_be_bytes(cookie[4..8].try_into().unwrap());
+        let key = AssociationKey::of(src, dest, packet, local_tag);
+
+        (packet.verification_tag == local_tag && self.cookie(&key, peer_tag) == cookie)
+            .then_some((key, peer_tag))
+    }
+
+    fn init_ack(&self, key: &AssociationKey, init: &Init) -> Chunk {
+        let init_ack = Init {
+            initiate_tag: key.local_tag,
+            a_rwnd: A_RWND,
+            outbound_streams: STREAMS.min(init.inbound_streams),
+            inbound_streams: STREAMS,
+            initial_tsn: rand::random(),
+            parameters: vec![(PARAMETER_STATE_COOKIE, self.cookie(key, init.initiate_tag))],
+        };
+
+        Chunk::new(ChunkType::InitAck, 0, init_ack.encode())
+    }
+
+    /// What to send back for `packet`, if anything.
+    fn answer(
+        &mut self,
+        src: ipv6::Address,
+        dest: ipv6::Address,
+        packet: &Packet,
+    ) -> AHResult<Option<Packet>> {
+        let first = match packet.chunks.first() {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        let listening = self.listening.contains(&packet.dest_port);
+
+        if first.chunk_type == ChunkType::Init {
+            let init = Init::parse(&first.value)?;
+            // Ref: https://datatracker.ietf.org/doc/html/rfc9260#section-5.1
+            if packet.verification_tag != 0 || init.initiate_tag == 0 {
+                return Ok(None);
+            }
+
+            return Ok(if listening {
+                // Zero is reserved for INITs.
+                let local_tag = rand::random::<u32>().max(1);
+                let key = AssociationKey::of(src, dest, packet, local_tag);
+                Some(packet.answer(init.initiate_tag, self.init_ack(&key, &init)))
+            } else if self.port_policy.get(packet.dest_port) == UnboundPort::Closed {
+                let abort = Chunk::new(ChunkType::Abort, 0, Vec::new());
+                Some(packet.answer(init.initiate_tag, abort))
+            } else {
+                None
+            });
+        }
+
+        if !listening && self.port_policy.get(packet.dest_port) == UnboundPort::Filtered {
+            return Ok(None);
+        }
+
+        if listening && first.chunk_type == ChunkType::CookieEcho {
+            // Forged or stale cookies are silently discarded.
+            // Ref: https://datatracker.ietf.org/doc/html/rfc9260#section-5.1.5
+            let (key, peer_tag) = match self.check_cookie(src, dest, packet, &first.value) {
+                Some(tags) => tags,
+                None => return Ok(None),
+            };
+            self.associations.insert(key, peer_tag);
+
+            let cookie_ack = Chunk::new(ChunkType::CookieAck, 0, Vec::new());
+            return Ok(Some(packet.answer(peer_tag, cookie_ack)));
+        }
+
+        let key = AssociationKey::of(src, dest, packet, packet.verification_tag);
+        match self.associations.get(&key).copied() {
+            Some(peer_tag) => Ok(self.answer_associated(key, peer_tag, packet)),
+            None => Ok(out_of_the_blue(packet)),
+        }
+    }
+
+    /// What to send back for `packet` on an association we set up.
+    fn answer_associated(
+        &mut self,
+        key: AssociationKey,
+        peer_tag: u32,
+        packet: &Packet,
+    ) -> Option<Packet> {
+        let mut highest_tsn = None;
+        for chunk in &packet.chunks {
+            let answer = match chunk.chunk_type {
+                ChunkType::Data => {
+                    let tsn = u32::from_be_bytes(chunk.value.get(..4)?.try_into().unwrap());
+                    highest_tsn = highest_tsn.max(Some(tsn));
+                    continue;
+                }
+                ChunkType::Heartbeat => Chunk::new(ChunkType::HeartbeatAck, 0, chunk.value.clone()),
+                ChunkType::Shutdown => Chunk::new(ChunkType::ShutdownAck, 0, Vec::new()),
+                ChunkType::Abort | ChunkType::ShutdownComplete => {
+                    self.associations.remove(&key);
+                    return None;
+                }
+                _ => continue,
+            };
+
+            return Some(packet.answer(peer_tag, answer));
+        }
+
+        // Everything is acknowledged as if it had arrived in order.
+        // Ref: https://datatracker.ietf.org/doc/html/rfc9260#section-3.3.4
+        let sack = encode!(highest_tsn?, A_RWND, 0u16, 0u16);
+        Some(packet.answer(peer_tag, Chunk::new(ChunkType::Sack, 0, sack)))
+    }
+
+    fn process_packet(&mut self, ipv6_packet: ipv6::Packet) -> AHResult<()> {
+        let sctp_packet = packet(&ipv6_packet.payload)?;
+        let answer = match self.answer(ipv6_packet.src, ipv6_packet.dest, &sctp_packet)? {
+            Some(answer) => answer,
+            None => return Ok(()),
+        };
+
+        self.ipv6_sender.send(
+            ipv6::Packet::builder()
+                .protocol(ipv4::ProtocolNumber::Sctp)
+                .hop_limit(self.hop_limit.get())
+                .src(ipv6_packet.dest)
+                .dest(ipv6_packet.src)
+                .payload(answer.encode())
+                .build(),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn start(&self, executor: &Executor) {
+        executor.start("sctp", "sctp", self.ipv6_receiver.clone(), self.clone());
+    }
+}
+
+/// What to answer `packet` with when it's for no association we know of.
+// Ref: https://datatracker.ietf.org/doc/html/rfc9260#section-8.4
+fn out_of_the_blue(packet: &Packet) -> Option<Packet> {
+    let reflected = |chunk_type| {
+        let chunk = Chunk::new(chunk_type, FLAG_T, Vec::new());
+        Some(packet.answer(packet.verification_tag, chunk))
+    };
+
+    let types: Vec<_> = packet.chunks.iter().map(|c| c.chunk_type).collect();
+    if types.iter().any(|t| {
+        matches!(
+            t,
+            ChunkType::Abort
+                | ChunkType::ShutdownComplete
+                | ChunkType::CookieAck
+                | ChunkType::OperationError
+        )
+    }) {
+        None
+    } else if types.contains(&ChunkType::ShutdownAck) {
+        reflected(ChunkType::ShutdownComplete)
+    } else {
+        reflected(ChunkType::Abort)
+    }
+}
+
+impl Actor for Server {
+    type Message = ipv6::Packet;
+
+    fn handle(&mut self, packet: ipv6::Packet) {
+        supervisor::handling(&packet.payload);
+        if let Err(e) = self.process_packet(packet) {
+            warn!("sctp: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> Server {
+        Server {
+            ipv6_receiver: channel::never(),
+            ipv6_sender: channel::unbounded().0,
+            port_policy: PortPolicy {
+                filtered: vec![9],
+                ..Default::default()
+            },
+            listening: std::iter::once(3868).collect(),
+            hop_limit: ipv6::HopLimitHandle::new(64),
+            secret: [7; 32],
+            associations: HashMap::new(),
+        }
+    }
+
+    fn init(dest_port: u16) -> Packet {
+        let init = Init {
+            initiate_tag: 0x1234_5678,
+            a_rwnd: 106496,
+            outbound_streams: 10,
+            inbound_streams: 65535,
+            initial_tsn: 1000,
+            parameters: vec![(0xc000, vec![])],
+        };
+
+        Packet {
+            src_port: 40000,
+            dest_port,
+            verification_tag: 0,
+            chunks: vec![Chunk::new(ChunkType::Init, 0, init.encode())],
+        }
+    }
+
+    #[test]
+    fn packets_round_trip_with_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+
+        let encoded = init(3868).encode();
+        assert_eq!(packet(&encoded).unwrap(), init(3868));
+
+        let mut corrupted = encoded;
+        corrupted[20] ^= 1;
+        assert!(packet(&corrupted).is_err());
+    }
+
+    #[test]
+    fn associations_are_set_up_through_cookies() {
+        let mut server = server();
+        let (src, dest) = (
+            "2001:db8::1".parse().unwrap(),
+            "2001:db8::2".parse().unwrap(),
+        );
+
+        let init_ack = server.answer(src, dest, &init(3868)).unwrap().unwrap();
+        assert_eq!(init_ack.verification_tag, 0x1234_5678);
+        assert_eq!(init_ack.chunks[0].chunk_type, ChunkType::InitAck);
+        let init_ack = Init::parse(&init_ack.chunks[0].value).unwrap();
+        assert_eq!(init_ack.outbound_streams, STREAMS);
+        let cookie = init_ack.parameter(PARAMETER_STATE_COOKIE).unwrap();
+
+        let mut cookie_echo = Packet {
+            src_port: 40000,
+            dest_port: 3868,
+            verification_tag: init_ack.initiate_tag,
+            chunks: vec![Chunk::new(ChunkType::CookieEcho, 0, cookie.to_vec())],
+        };
+        let cookie_ack = server.answer(src, dest, &cookie_echo).unwrap().unwrap();
+        assert_eq!(cookie_ack.verification_tag, 0x1234_5678);
+        assert_eq!(cookie_ack.chunks[0].chunk_type, ChunkType::CookieAck);
+
+        let mut data = 1000u32.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, b'h', b'i']);
+        let data = Packet {
+            chunks: vec![Chunk::new(ChunkType::Data, 0x3, data)],
+            ..cookie_echo.clone()
+        };
+        let sack = server.answer(src, dest, &data).unwrap().unwrap();
+        assert_eq!(sack.chunks[0].chunk_type, ChunkType::Sack);
+        assert_eq!(sack.chunks[0].value[..4], 1000u32.to_be_bytes());
+
+        // A cookie from somewhere else is ignored.
+        cookie_echo.src_port += 1;
+        assert_eq!(server.answer(src, dest, &cookie_echo).unwrap(), None);
+    }
+
+    #[test]
+    fn unbound_ports_follow_policy() {
+        let mut server = server();
+        let (src, dest) = (
+            "2001:db8::1".parse().unwrap(),
+            "2001:db8::2".parse().unwrap(),
+        );
+
+        let abort = server.answer(src, dest, &init(80)).unwrap().unwrap();
+        assert_eq!(abort.verification_tag, 0x1234_5678);
+        assert_eq!(abort.chunks, [Chunk::new(ChunkType::Abort, 0, vec![])]);
+        assert_eq!(server.answer(src, dest, &init(9)).unwrap(), None);
+
+        let heartbeat = Packet {
+            src_port: 40000,
+            dest_port: 80,
+            verification_tag: 0xabcd,
+            chunks: vec![Chunk::new(ChunkType::Heartbeat, 0, vec![0, 1, 0, 4])],
+        };
+        let abort = server.answer(src, dest, &heartbeat).unwrap().unwrap();
+        assert_eq!(abort.verification_tag, 0xabcd);
+        assert_eq!(abort.chunks, [Chunk::new(ChunkType::Abort, FLAG_T, vec![])]);
+    }
+}
@@ -0,0 +1,215 @@
+use anyhow::{bail, Result as AHResult};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// Token bucket that lets callers reserve tokens ahead of time, going into debt and waiting it off.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket refilled at `rate` tokens per second, holding at most `burst` tokens.
+    pub fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last_update: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_update);
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.last_update = std::cmp::max(now, self.last_update);
+    }
+
+    /// Tokens in the bucket as of `now`.
+    pub fn tokens(&mut self, now: Instant) -> f64 {
+        self.refill(now);
+        self.tokens
+    }
+
+    /// Take `amount` tokens if there are that many, without going into debt.
+    pub fn try_take(&mut self, amount: f64, now: Instant) -> bool {
+        if self.tokens(now) < amount {
+            return false;
+        }
+
+        self.tokens -= amount;
+        true
+    }
+
+    /// Take `amount` tokens, returning how long the caller should wait before using them.
+    pub fn reserve(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= amount;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct ShapingConfig {
+    pub bits_per_second: Option<u64>,
+    /// Defaults to one full-sized frame.
+    pub burst_bytes: Option<u64>,
+    pub packets_per_second: Option<f64>,
+    #[serde(default = "default_burst_packets")]
+    pub burst_packets: u32,
+}
+
+fn default_burst_packets() -> u32 {
+    1
+}
+
+impl ShapingConfig {
+    /// Check the rates, as a bucket that never refills would hold frames back forever.
+    pub fn validate(&self) -> AHResult<()> {
+        if self.bits_per_second == Some(0) {
+            bail!("bits_per_second must be more than 0");
+        }
+        if let Some(pps) = self.packets_per_second {
+            if !pps.is_finite() || pps <= 0.0 {
+                bail!(
+                    "packets_per_second must be a number more than 0, not {}",
+                    pps
+                );
+            }
+        }
+        if self.burst_packets == 0 {
+            bail!("burst_packets must be at least 1");
+        }
+
+        Ok(())
+    }
+}
+
+/// Egress shaper, limiting both bandwidth and packet rate.
+#[derive(Clone, Debug, Default)]
+pub struct Shaper {
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+}
+
+impl Shaper {
+    pub fn new(config: ShapingConfig, max_frame_len: usize) -> Self {
+        let now = Instant::now();
+
+        Self {
+            bytes: config.bits_per_second.map(|bps| {
+                let burst = config.burst_bytes.unwrap_or(max_frame_len as u64);
+
+                TokenBucket::new(bps as f64 / 8.0, burst as f64, now)
+            }),
+            packets: config
+                .packets_per_second
+                .map(|pps| TokenBucket::new(pps, config.burst_packets as f64, now)),
+        }
+    }
+
+    /// Account for sending a frame of `len` bytes, returning how long to hold it back.
+    pub fn reserve(&mut self, len: usize, now: Instant) -> Duration {
+        let bytes_delay = self
+            .bytes
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.reserve(len as f64, now));
+        let packets_delay = self
+            .packets
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.reserve(1.0, now));
+
+        std::cmp::max(bytes_delay, packets_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_delays() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100.0, 200.0, now);
+
+        assert_eq!(bucket.reserve(200.0, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(50.0, now), Duration::from_millis(500));
+        assert_eq!(bucket.reserve(50.0, now), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100.0, 200.0, now);
+
+        bucket.reserve(200.0, now);
+        assert_eq!(
+            bucket.reserve(200.0, now + Duration::from_secs(10)),
+            Duration::ZERO
+        );
+        assert_eq!(
+            bucket.reserve(1.0, now + Duration::from_secs(10)),
+            Duration::from_millis(10)
+        );
+    }
+
+    #[test]
+    fn shaper_uses_slowest_limit() {
+        let now = Instant::now();
+        let mut shaper = Shaper::new(
+            ShapingConfig {
+                bits_per_second: Some(8000),
+                burst_bytes: Some(100),
+                packets_per_second: Some(1.0),
+                burst_packets: 1,
+            },
+            1500,
+        );
+
+        assert_eq!(shaper.reserve(100, now), Duration::ZERO);
+        // Bandwidth allows this after 10ms, but the packet rate takes a full second.
+        assert_eq!(shaper.reserve(10, now), Duration::from_secs(1));
+        assert_eq!(Shaper::default().reserve(1500, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn zero_rates_are_rejected() {
+        let config = ShapingConfig {
+            bits_per_second: Some(8000),
+            burst_bytes: None,
+            packets_per_second: Some(1.0),
+            burst_packets: 1,
+        };
+        config.validate().unwrap();
+
+        for config in [
+            ShapingConfig {
+                bits_per_second: Some(0),
+                ..config
+            },
+            ShapingConfig {
+                packets_per_second: Some(0.0),
+                ..config
+            },
+            ShapingConfig {
+                packets_per_second: Some(f64::NAN),
+                ..config
+            },
+            ShapingConfig {
+                burst_packets: 0,
+                ..config
+            },
+        ] {
+            assert!(config.validate().is_err());
+        }
+    }
+}
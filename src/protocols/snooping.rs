@@ -0,0 +1,317 @@
+//! Which bridge ports want which multicast groups, learned from the MLD and IGMP messages passing
+//! through, so group traffic only goes where there are listeners.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4541
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use super::ether;
+use super::ipv4;
+use super::ipv6::{self, icmpv6};
+
+/// How long a report keeps a port in a group, and a query marks it as leading to a router.
+// Ref: https://datatracker.ietf.org/doc/html/rfc3810#section-9.4
+pub const LISTENER_INTERVAL: Duration = Duration::from_secs(260);
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc2710#section-3.6
+const MLD_QUERY: u8 = 130;
+const MLD_V1_REPORT: u8 = 131;
+const MLD_DONE: u8 = 132;
+const MLD_V2_REPORT: u8 = 143;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc3376#section-4
+const IGMP_QUERY: u8 = 0x11;
+const IGMP_V1_REPORT: u8 = 0x12;
+const IGMP_V2_REPORT: u8 = 0x16;
+const IGMP_LEAVE: u8 = 0x17;
+const IGMP_V3_REPORT: u8 = 0x22;
+
+/// What a group management message says about the port it arrived on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Membership {
+    /// A multicast router is somewhere beyond the port.
+    Query,
+    Join(ether::Address),
+    Leave(ether::Address),
+}
+
+/// What an MLDv2 or IGMPv3 record, which share their types, means for `group`.
+// Ref: https://datatracker.ietf.org/doc/html/rfc3810#section-5.2.12
+fn record(record_type: u8, group: ether::Address, sources: usize) -> Option<Membership> {
+    match record_type {
+        // Including no sources is not listening at all.
+        1 | 3 if sources == 0 => Some(Membership::Leave(group)),
+        1..=5 => Some(Membership::Join(group)),
+        _ => None,
+    }
+}
+
+fn snoop_mld(packet: &ipv6::Packet) -> Vec<Membership> {
+    let payload = &packet.payload;
+    let group = || {
+        let address = <[u8; 16]>::try_from(payload.get(8..24)?).unwrap();
+        Some(ipv6::Address::from(u128::from_be_bytes(address)).multicast_ether_dest())
+    };
+
+    match payload.first() {
+        Some(&MLD_QUERY) => vec![Membership::Query],
+        Some(&MLD_V1_REPORT) => group().map(Membership::Join).into_iter().collect(),
+        Some(&MLD_DONE) => group().map(Membership::Leave).into_iter().collect(),
+        Some(&MLD_V2_REPORT) => {
+            let pseudo_header = icmpv6::PseudoHeader {
+                src: packet.src,
+                dest: packet.dest,
+                length: payload.len() as u32,
+            };
+
+            match icmpv6::packet(payload, pseudo_header) {
+                Ok(icmpv6::Packet::MldV2Report(records)) => records
+                    .iter()
+                    .filter(|r| r.address.is_multicast())
+                    .filter_map(|r| {
+                        record(
+                            r.record_type as u8,
+                            r.address.multicast_ether_dest(),
+                            r.sources.len(),
+                        )
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn snoop_igmp(message: &[u8]) -> Vec<Membership> {
+    let group = |i: usize| {
+        let address = ipv4::Address(<[u8; 4]>::try_from(message.get(i..i + 4)?).unwrap());
+        address
+            .is_multicast()
+            .then(|| address.multicast_ether_dest())
+    };
+
+    match message.first() {
+        Some(&IGMP_QUERY) => vec![Membership::Query],
+        Some(&IGMP_V1_REPORT | &IGMP_V2_REPORT) => {
+            group(4).map(Membership::Join).into_iter().collect()
+        }
+        Some(&IGMP_LEAVE) => group(4).map(Membership::Leave).into_iter().collect(),
+        Some(&IGMP_V3_REPORT) => {
+            let count = match message.get(6..8) {
+                Some(count) => u16::from_be_bytes([count[0], count[1]]),
+                None => return Vec::new(),
+            };
+
+            // Ref: https://datatracker.ietf.org/doc/html/rfc3376#section-4.2.4
+            let mut memberships = Vec::new();
+            let mut offset = 8;
+            for _ in 0..count {
+                let header = match message.get(offset..offset + 4) {
+                    Some(header) => header,
+                    None => break,
+                };
+                let sources = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+                if let Some(group) = group(offset + 4) {
+                    memberships.extend(record(header[0], group, sources));
+                }
+                offset += 8 + 4 * sources + 4 * header[1] as usize;
+            }
+
+            memberships
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// What `frame` says about group membership, if it's an MLD or IGMP message.
+pub fn snoop(frame: &ether::Frame) -> Vec<Membership> {
+    match frame.ethertype {
+        ether::Type::Ipv6 => match ipv6::packet(&frame.payload) {
+            Ok(packet)
+                if packet.next_header
+                    == ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Ipv6Icmp) =>
+            {
+                snoop_mld(&packet)
+            }
+            _ => Vec::new(),
+        },
+        ether::Type::Ipv4 => {
+            let header = &frame.payload;
+            let header_len = match header.first() {
+                Some(first) if header.len() >= 20 => (first & 0xf) as usize * 4,
+                _ => return Vec::new(),
+            };
+
+            if ipv4::ProtocolNumber::try_from(header[9]).ok() != Some(ipv4::ProtocolNumber::Igmp) {
+                return Vec::new();
+            }
+            snoop_igmp(header.get(header_len..).unwrap_or_default())
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Whether frames to `group` are kept to its listeners. Everything else multicast is flooded,
+/// including the all-nodes group and IPv4's local network control block, which hosts never report.
+// Ref: https://datatracker.ietf.org/doc/html/rfc4541#section-2.1.2
+pub fn is_snooped(group: ether::Address) -> bool {
+    match group.0 {
+        [0x33, 0x33, 0, 0, 0, 1] => false,
+        [0x33, 0x33, ..] => true,
+        [0x01, 0x00, 0x5e, high, 0, _] => high & 0x7f != 0,
+        [0x01, 0x00, 0x5e, ..] => true,
+        _ => false,
+    }
+}
+
+/// Ports listening to each group, and those leading to multicast routers, until their reports or
+/// queries go stale.
+#[derive(Debug, Default)]
+pub struct Groups {
+    listeners: HashMap<ether::Address, HashMap<usize, Instant>>,
+    routers: HashMap<usize, Instant>,
+}
+
+impl Groups {
+    /// Leaves take effect immediately, as there's no querier of our own to confirm them with.
+    pub fn learn(&mut self, port: usize, membership: Membership, now: Instant) {
+        match membership {
+            Membership::Query => {
+                self.routers.insert(port, now + LISTENER_INTERVAL);
+            }
+            Membership::Join(group) => {
+                self.listeners
+                    .entry(group)
+                    .or_default()
+                    .insert(port, now + LISTENER_INTERVAL);
+            }
+            Membership::Leave(group) => {
+                if let Some(ports) = self.listeners.get_mut(&group) {
+                    ports.remove(&port);
+                }
+            }
+        }
+    }
+
+    /// Ports frames to `group` should go out of, or `None` to flood them.
+    pub fn ports(&self, group: ether::Address, now: Instant) -> Option<Vec<usize>> {
+        if !is_snooped(group) {
+            return None;
+        }
+
+        let listeners = self.listeners.get(&group).into_iter().flatten();
+        let mut ports: Vec<_> = listeners
+            .chain(&self.routers)
+            .filter(|(_, expires)| now < **expires)
+            .map(|(port, _)| *port)
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+
+        Some(ports)
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        self.routers.retain(|_, expires| now < *expires);
+        for ports in self.listeners.values_mut() {
+            ports.retain(|_, expires| now < *expires);
+        }
+        self.listeners.retain(|_, ports| !ports.is_empty());
+    }
+
+    pub fn forget_port(&mut self, port: usize) {
+        self.routers.remove(&port);
+        for ports in self.listeners.values_mut() {
+            ports.remove(&port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(s: &str) -> ether::Address {
+        s.parse::<ipv6::Address>().unwrap().multicast_ether_dest()
+    }
+
+    #[test]
+    fn reports_and_queries_are_snooped() {
+        let src: ipv6::Address = "fe80::1".parse().unwrap();
+        let dest: ipv6::Address = "ff02::16".parse().unwrap();
+        let report = icmpv6::Packet::MldV2Report(vec![
+            icmpv6::MldV2AddressRecord::new(
+                icmpv6::Mldv2AddressRecordType::ChangeToExcludeMode,
+                "ff3e::8000:1".parse().unwrap(),
+            ),
+            icmpv6::MldV2AddressRecord::new(
+                icmpv6::Mldv2AddressRecordType::ChangeToIncludeMode,
+                "ff3e::8000:2".parse().unwrap(),
+            ),
+        ]);
+        let packet = ipv6::Packet::builder()
+            .protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+            .src(src)
+            .dest(dest)
+            .payload(report.encode(icmpv6::PseudoHeader {
+                src,
+                dest,
+                length: 0,
+            }))
+            .build();
+        let frame = ether::Frame {
+            dest: dest.multicast_ether_dest(),
+            src: ether::Address([2, 0, 0, 0, 0, 1]),
+            ethertype: ether::Type::Ipv6,
+            payload: packet.encode(),
+            received: None,
+        };
+        assert_eq!(
+            snoop(&frame),
+            [
+                Membership::Join(group("ff3e::8000:1")),
+                Membership::Leave(group("ff3e::8000:2"))
+            ]
+        );
+
+        // An IGMPv2 report for 239.1.2.3, after an IPv4 header with a router alert option.
+        let mut igmp = vec![0x46, 0, 0, 32, 0, 0, 0, 0, 1, 2, 0, 0];
+        igmp.extend_from_slice(&[10, 0, 0, 1, 239, 1, 2, 3, 0x94, 4, 0, 0]);
+        igmp.extend_from_slice(&[IGMP_V2_REPORT, 0, 0, 0, 239, 1, 2, 3]);
+        let frame = ether::Frame {
+            ethertype: ether::Type::Ipv4,
+            payload: igmp,
+            ..frame
+        };
+        assert_eq!(
+            snoop(&frame),
+            [Membership::Join(ether::Address([1, 0, 0x5e, 1, 2, 3]))]
+        );
+    }
+
+    #[test]
+    fn groups_go_to_listeners_and_routers() {
+        let now = Instant::now();
+        let mut groups = Groups::default();
+        let data = group("ff3e::8000:1");
+
+        assert_eq!(groups.ports(data, now), Some(vec![]));
+        assert_eq!(groups.ports(group("ff02::1"), now), None);
+        assert_eq!(groups.ports(ether::Address::BROADCAST, now), None);
+
+        groups.learn(3, Membership::Join(data), now);
+        groups.learn(1, Membership::Query, now);
+        assert_eq!(groups.ports(data, now), Some(vec![1, 3]));
+
+        groups.learn(3, Membership::Leave(data), now);
+        assert_eq!(groups.ports(data, now), Some(vec![1]));
+
+        groups.learn(2, Membership::Join(data), now);
+        assert_eq!(groups.ports(data, now + LISTENER_INTERVAL), Some(vec![]));
+        groups.expire(now + LISTENER_INTERVAL);
+        assert!(groups.listeners.is_empty() && groups.routers.is_empty());
+    }
+}
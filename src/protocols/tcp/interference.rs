@@ -0,0 +1,450 @@
+//! Interrupting TCP connections, both our own (through `FlowHook`s) and others' that we only see
+//! go by, such as those forwarded over a bridge, by forging resets or FINs from each end to the
+//! other.
+
+use anyhow::{anyhow, bail, Result as AHResult};
+use crossbeam::channel;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{segment, ConnectionKey, Endpoint, FlowHook, Interruption, Segment, HOP_LIMIT};
+use crate::protocols::filter::Filter;
+use crate::protocols::{ether, ipv4, ipv6};
+
+/// How long an observed flow can go quiet before it's forgotten.
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// When to interrupt connections without being asked.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub interruption: Interruption,
+    /// Only connections with either end on one of these ports; any when empty.
+    pub ports: Vec<u16>,
+    /// Payload bytes a connection carries, both ways, before it's interrupted.
+    pub after_bytes: u64,
+}
+
+impl Policy {
+    fn applies(&self, key: &ConnectionKey, bytes: u64) -> bool {
+        (self.ports.is_empty()
+            || self.ports.contains(&key.local.port)
+            || self.ports.contains(&key.remote.port))
+            && bytes >= self.after_bytes
+    }
+
+    /// A hook applying this policy to a `Server`'s own connections.
+    pub fn hook(self) -> FlowHook {
+        let bytes = Mutex::new(HashMap::<ConnectionKey, u64>::new());
+
+        Box::new(move |key, _, segment| {
+            let mut bytes = bytes.lock().unwrap();
+
+            if segment.has(Segment::RST) || segment.has(Segment::FIN) {
+                bytes.remove(&key);
+                return None;
+            }
+
+            let total = bytes.entry(key).or_default();
+            *total += segment.payload.len() as u64;
+
+            if self.applies(&key, *total) {
+                Some(self.interruption)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// One end of an observed flow.
+#[derive(Clone, Copy, Debug)]
+struct Side {
+    ether_address: ether::Address,
+    /// Sequence number just past the last segment this end sent.
+    next_seq: u32,
+}
+
+#[derive(Clone, Debug)]
+struct ObservedFlow {
+    /// For `key.local`, which sent the first segment we saw, then `key.remote`; each is known once
+    /// it has sent something.
+    sides: [Option<Side>; 2],
+    bytes: u64,
+    last_seen: Instant,
+    /// Already sent FINs, so the policy leaves it alone.
+    interrupted: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FlowInfo {
+    pub key: ConnectionKey,
+    pub bytes: u64,
+}
+
+/// Sequence state of flows seen on the wire, enough to forge segments either end will accept.
+struct Tracker {
+    flows: HashMap<ConnectionKey, ObservedFlow>,
+    /// Our own address; flows it's part of are left to the TCP server's hooks.
+    own_address: ether::Address,
+    policy: Option<Policy>,
+}
+
+impl Tracker {
+    /// Key a flow is stored under, and which of its sides sent the segment.
+    fn find(&self, key: ConnectionKey) -> Option<(ConnectionKey, usize)> {
+        if self.flows.contains_key(&key) {
+            Some((key, 0))
+        } else if self.flows.contains_key(&key.reversed()) {
+            Some((key.reversed(), 1))
+        } else {
+            None
+        }
+    }
+
+    /// Track a frame, returning any frames to send because of the policy.
+    fn observe(&mut self, frame: &ether::Frame, now: Instant) -> Vec<ether::Frame> {
+        if frame.src == self.own_address || frame.dest == self.own_address {
+            return Vec::new();
+        }
+
+        let (key, segment) = match tcp_segment(frame) {
+            Some(parsed) => parsed,
+            None => return Vec::new(),
+        };
+
+        let (key, side) = self.find(key).unwrap_or_else(|| {
+            self.flows.insert(
+                key,
+                ObservedFlow {
+                    sides: [None, None],
+                    bytes: 0,
+                    last_seen: now,
+                    interrupted: false,
+                },
+            );
+
+            (key, 0)
+        });
+
+        if segment.has(Segment::RST) {
+            self.flows.remove(&key);
+            return Vec::new();
+        }
+
+        let flow = self.flows.get_mut(&key).unwrap();
+        flow.bytes += segment.payload.len() as u64;
+        flow.last_seen = now;
+        flow.sides[side] = Some(Side {
+            ether_address: frame.src,
+            next_seq: segment.seq.wrapping_add(segment.seq_len()),
+        });
+        let (bytes, interrupted) = (flow.bytes, flow.interrupted);
+
+        match &self.policy {
+            // Our own forgeries come back through here, so each flow is only interrupted once.
+            Some(policy) if !interrupted && policy.applies(&key, bytes) => {
+                let interruption = policy.interruption;
+                // Fails until both ends have been seen, which is retried on the next segment.
+                self.interrupt(key, interruption).unwrap_or_default()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Frames that interrupt a flow, sent to both ends as if from the other.
+    fn interrupt(
+        &mut self,
+        key: ConnectionKey,
+        interruption: Interruption,
+    ) -> AHResult<Vec<ether::Frame>> {
+        let (key, _) = self
+            .find(key)
+            .ok_or_else(|| anyhow!("no flow between {} and {}", key.local, key.remote))?;
+        let sides = match self.flows[&key].sides {
+            [Some(local), Some(remote)] => [local, remote],
+            _ => bail!("only one end of {} to {} seen yet", key.local, key.remote),
+        };
+        let endpoints = [key.local, key.remote];
+
+        let frames = (0..2)
+            .map(|to| {
+                let from = 1 - to;
+                let sender = sides[from];
+                let receiver = sides[to];
+
+                let segment = Segment {
+                    src_port: endpoints[from].port,
+                    dest_port: endpoints[to].port,
+                    // Exactly what the receiver expects next, if it got everything we saw, so
+                    // even stacks that only take resets at the edge of their window accept it.
+                    seq: sender.next_seq,
+                    ack: receiver.next_seq,
+                    flags: Segment::ACK
+                        | match interruption {
+                            Interruption::Reset => Segment::RST,
+                            Interruption::Fin => Segment::FIN,
+                        },
+                    window: super::WINDOW,
+                    ..Default::default()
+                };
+
+                forged_frame(
+                    sender.ether_address,
+                    receiver.ether_address,
+                    endpoints[from].address,
+                    endpoints[to].address,
+                    &segment,
+                )
+            })
+            .collect();
+
+        if interruption == Interruption::Reset {
+            self.flows.remove(&key);
+        } else {
+            self.flows.get_mut(&key).unwrap().interrupted = true;
+        }
+
+        Ok(frames)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.flows
+            .retain(|_, flow| now.saturating_duration_since(flow.last_seen) < FLOW_IDLE_TIMEOUT);
+    }
+}
+
+fn tcp_segment(frame: &ether::Frame) -> Option<(ConnectionKey, Segment)> {
+    if frame.ethertype != ether::Type::Ipv6 {
+        return None;
+    }
+
+    let packet = ipv6::packet(&frame.payload).ok()?;
+    if packet.next_header != ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Tcp) {
+        return None;
+    }
+
+    let segment = segment(
+        &packet.payload,
+        ipv6::PseudoHeader {
+            src: packet.src,
+            dest: packet.dest,
+            length: 0,
+        },
+    )
+    .ok()?;
+
+    let key = ConnectionKey {
+        local: Endpoint {
+            address: packet.src,
+            port: segment.src_port,
+        },
+        remote: Endpoint {
+            address: packet.dest,
+            port: segment.dest_port,
+        },
+    };
+
+    Some((key, segment))
+}
+
+fn forged_frame(
+    ether_src: ether::Address,
+    ether_dest: ether::Address,
+    src: ipv6::Address,
+    dest: ipv6::Address,
+    segment: &Segment,
+) -> ether::Frame {
+    let payload = segment.encode(ipv6::PseudoHeader {
+        src,
+        dest,
+        length: 0,
+    });
+
+    ether::Frame {
+        dest: ether_dest,
+        src: ether_src,
+        ethertype: ether::Type::Ipv6,
+        payload: ipv6::Packet::builder()
+            .protocol(ipv4::ProtocolNumber::Tcp)
+            .hop_limit(HOP_LIMIT)
+            .src(src)
+            .dest(dest)
+            .payload(payload)
+            .build()
+            .encode(),
+        received: None,
+    }
+}
+
+/// Interrupts TCP flows between other nodes, seen through an interface's observers.
+#[derive(Clone)]
+pub struct Interferer {
+    tracker: Arc<Mutex<Tracker>>,
+    writer: channel::Sender<ether::Frame>,
+}
+
+impl Interferer {
+    pub fn new(interface: &impl ether::Server, policy: Option<Policy>) -> AHResult<Self> {
+        Ok(Self {
+            tracker: Arc::new(Mutex::new(Tracker {
+                flows: HashMap::new(),
+                own_address: interface.if_hwaddr()?,
+                policy,
+            })),
+            writer: interface.writer(),
+        })
+    }
+
+    pub fn start(&self, observer: &ether::ObserveHandle) {
+        let receiver = observer.observe(1, Filter::Protocol(ipv4::ProtocolNumber::Tcp));
+        let interferer = self.clone();
+
+        thread::spawn(move || {
+            let mut last_expiry = Instant::now();
+
+            for frame in receiver {
+                let now = Instant::now();
+                let mut tracker = interferer.tracker.lock().unwrap();
+
+                for forged in tracker.observe(&frame, now) {
+                    let _ = interferer.writer.send(forged);
+                }
+
+                if now.saturating_duration_since(last_expiry) >= FLOW_IDLE_TIMEOUT {
+                    tracker.expire(now);
+                    last_expiry = now;
+                }
+            }
+        });
+    }
+
+    pub fn flows(&self) -> Vec<FlowInfo> {
+        self.tracker
+            .lock()
+            .unwrap()
+            .flows
+            .iter()
+            .map(|(key, flow)| FlowInfo {
+                key: *key,
+                bytes: flow.bytes,
+            })
+            .collect()
+    }
+
+    /// Interrupt an observed flow between the given ends, in either order.
+    pub fn interrupt(&self, key: ConnectionKey, interruption: Interruption) -> AHResult<()> {
+        for frame in self.tracker.lock().unwrap().interrupt(key, interruption)? {
+            self.writer.send(frame)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ethera(last: u8) -> ether::Address {
+        ether::Address([2, 0, 0, 0, 0, last])
+    }
+
+    fn key() -> ConnectionKey {
+        ConnectionKey {
+            local: "[2001:db8::1]:49152".parse().unwrap(),
+            remote: "[2001:db8::2]:80".parse().unwrap(),
+        }
+    }
+
+    fn frame(from_client: bool, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> ether::Frame {
+        let key = if from_client { key() } else { key().reversed() };
+        let (ether_src, ether_dest) = if from_client {
+            (ethera(1), ethera(2))
+        } else {
+            (ethera(2), ethera(1))
+        };
+
+        forged_frame(
+            ether_src,
+            ether_dest,
+            key.local.address,
+            key.remote.address,
+            &Segment {
+                src_port: key.local.port,
+                dest_port: key.remote.port,
+                seq,
+                ack,
+                flags,
+                payload: payload.to_vec(),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn tracker(policy: Option<Policy>) -> Tracker {
+        Tracker {
+            flows: HashMap::new(),
+            own_address: ethera(9),
+            policy,
+        }
+    }
+
+    #[test]
+    fn resets_land_in_both_windows() {
+        let now = Instant::now();
+        let mut tracker = tracker(None);
+
+        tracker.observe(&frame(true, 100, 0, Segment::SYN, b""), now);
+        tracker.observe(
+            &frame(false, 500, 101, Segment::SYN | Segment::ACK, b""),
+            now,
+        );
+        tracker.observe(&frame(true, 101, 501, Segment::ACK, b"GET /"), now);
+        assert_eq!(
+            tracker.flows.keys().copied().collect::<Vec<_>>(),
+            vec![key()]
+        );
+
+        let forged = tracker
+            .interrupt(key().reversed(), Interruption::Reset)
+            .unwrap();
+        let (to_client_key, to_client) = tcp_segment(&forged[0]).unwrap();
+        assert_eq!(to_client_key, key().reversed());
+        assert_eq!(forged[0].dest, ethera(1));
+        assert_eq!(to_client.seq, 501);
+        assert!(to_client.has(Segment::RST));
+
+        let (_, to_server) = tcp_segment(&forged[1]).unwrap();
+        assert_eq!(forged[1].src, ethera(1));
+        assert_eq!(to_server.seq, 106);
+        assert!(tracker.flows.is_empty());
+    }
+
+    #[test]
+    fn policy_waits_for_bytes_and_skips_our_flows() {
+        let now = Instant::now();
+        let mut tracker = tracker(Some(Policy {
+            interruption: Interruption::Fin,
+            ports: vec![80],
+            after_bytes: 10,
+        }));
+
+        tracker.observe(&frame(true, 101, 501, Segment::ACK, b"GET /"), now);
+        assert!(tracker
+            .observe(&frame(false, 501, 106, Segment::ACK, b"200"), now)
+            .is_empty());
+
+        let forged = tracker.observe(&frame(true, 106, 504, Segment::ACK, b"more"), now);
+        assert_eq!(forged.len(), 2);
+        assert!(tcp_segment(&forged[0]).unwrap().1.has(Segment::FIN));
+        assert!(tracker.observe(&forged[0], now).is_empty());
+
+        let mut ours = frame(true, 101, 501, Segment::ACK, b"");
+        ours.src = ethera(9);
+        tracker.flows.clear();
+        tracker.observe(&ours, now);
+        assert!(tracker.flows.is_empty());
+    }
+}
@@ -0,0 +1,1601 @@
+use anyhow::{anyhow, bail, Result as AHResult};
+use crossbeam::channel;
+use crossbeam::select;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use super::port_policy::{PortPolicy, UnboundPort};
+use super::utils::{Backpressure, KeyedDispatcher, WakerSlot, WakingSender};
+use super::{ipv4, ipv6};
+use crate::status::{self, EventKind, EventStatus};
+use crate::{clock, stats, supervisor, warn, watchdog};
+
+pub mod interference;
+mod segment;
+
+pub use self::segment::segment;
+pub use self::segment::{Segment, TcpOption};
+use self::segment::{OPTION_NOP, OPTION_SACK_PERMITTED, OPTION_TIMESTAMPS, OPTION_WINDOW_SCALE};
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc793
+
+const WINDOW: u16 = 0xffff;
+const HOP_LIMIT: u8 = 64;
+const HEADER_LEN: usize = 20;
+/// What we may send in each segment to a peer that didn't say in its SYN.
+// Ref: https://datatracker.ietf.org/doc/html/rfc9293#section-3.7.1
+const DEFAULT_SEND_MSS: usize = 1220;
+/// Least we'll send in each segment, whatever the peer says, as every host has to take this much.
+// Ref: https://datatracker.ietf.org/doc/html/rfc9293#section-3.7.1
+const MIN_SEND_MSS: usize = 536;
+/// Wait for the first SYN-ACK to be acknowledged, doubled after each one sent again.
+// Ref: https://datatracker.ietf.org/doc/html/rfc6298#section-2
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// How often handshakes are checked for SYN-ACKs to send again.
+const SYN_ACK_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How often connections are checked for keep-alives to send and for being idle too long.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What happens to a connection a listener's backlog has no room for.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Ignore the segment that would have queued it, leaving the peer to try again, as Linux does
+    /// by default.
+    #[default]
+    Drop,
+    Reset,
+}
+
+/// Limits on each listener's queues of connections, to pass for an overloaded server.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Backlog {
+    /// Connections still in the handshake.
+    #[serde(default = "default_syn_backlog")]
+    pub syn: usize,
+    /// Established connections waiting for the service to accept them; 0 overflows with every
+    /// connection, like a server that's stopped accepting.
+    #[serde(default = "default_accept_backlog")]
+    pub accept: usize,
+    /// SYN-ACKs sent again before a handshake is given up on.
+    #[serde(default = "default_syn_ack_retries")]
+    pub syn_ack_retries: u32,
+    #[serde(default)]
+    pub overflow: Overflow,
+}
+
+fn default_syn_backlog() -> usize {
+    256
+}
+
+fn default_accept_backlog() -> usize {
+    128
+}
+
+// Ref: https://www.kernel.org/doc/Documentation/networking/ip-sysctl.txt (tcp_synack_retries)
+fn default_syn_ack_retries() -> u32 {
+    5
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        Self {
+            syn: default_syn_backlog(),
+            accept: default_accept_backlog(),
+            syn_ack_retries: default_syn_ack_retries(),
+            overflow: Overflow::default(),
+        }
+    }
+}
+
+/// Probing peers that have gone quiet, with the defaults Linux uses.
+// Ref: https://datatracker.ietf.org/doc/html/rfc9293#section-3.8.4
+// Ref: https://www.kernel.org/doc/Documentation/networking/ip-sysctl.txt (tcp_keepalive_time)
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Keepalive {
+    /// Seconds without hearing from the peer before the first probe.
+    #[serde(default = "default_keepalive_idle_secs")]
+    pub idle_secs: u64,
+    /// Seconds between probes.
+    #[serde(default = "default_keepalive_interval_secs")]
+    pub interval_secs: u64,
+    /// Probes left unanswered before the connection is reset.
+    #[serde(default = "default_keepalive_probes")]
+    pub probes: u32,
+}
+
+fn default_keepalive_idle_secs() -> u64 {
+    7200
+}
+
+fn default_keepalive_interval_secs() -> u64 {
+    75
+}
+
+fn default_keepalive_probes() -> u32 {
+    9
+}
+
+/// What happens to established connections that go quiet; they're left be by default.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Idle {
+    /// Reset connections no data has been sent or received on for this many seconds, like a
+    /// server or middlebox reaping them.
+    pub timeout_secs: Option<u64>,
+    pub keepalive: Option<Keepalive>,
+}
+
+/// An option sent on SYN-ACKs; those besides `Nop` and `Mss` are only sent when the SYN had them
+/// too, as they only take effect when both sides send them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SynOption {
+    Nop,
+    Mss,
+    WindowScale(u8),
+    SackPermitted,
+    /// Sent on every later segment too, once agreed on.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc7323#section-3
+    Timestamps,
+}
+
+/// How our segments look, to tools that guess the OS from them; the hop limit is the IPv6 stack's.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Fingerprint {
+    pub window: u16,
+    /// In the order they're sent.
+    pub syn_options: Vec<SynOption>,
+}
+
+impl Default for Fingerprint {
+    fn default() -> Self {
+        Self {
+            window: WINDOW,
+            syn_options: Vec::new(),
+        }
+    }
+}
+
+/// Our clock for the timestamps option, in milliseconds.
+fn timestamp() -> u32 {
+    clock::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u32
+}
+
+/// The sender's clock from a segment's timestamps option.
+fn timestamp_value(segment: &Segment) -> Option<u32> {
+    segment
+        .parsed_options()
+        .into_iter()
+        .find_map(|option| match option {
+            TcpOption::Timestamps { value, .. } => Some(value),
+            _ => None,
+        })
+}
+
+/// The most the peer will take in each segment, from the SYN that opened the connection.
+fn peer_mss(syn: &Segment) -> usize {
+    syn.parsed_options()
+        .into_iter()
+        .find_map(|option| match option {
+            TcpOption::Mss(mss) => Some((mss as usize).max(MIN_SEND_MSS)),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_SEND_MSS)
+}
+
+fn encode_timestamps(options: &mut Vec<u8>, echo: u32) {
+    TcpOption::Timestamps {
+        value: timestamp(),
+        echo,
+    }
+    .encode(options);
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Endpoint {
+    pub address: ipv6::Address,
+    pub port: u16,
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}]:{}", self.address, self.port)
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    /// Parses `[address]:port`.
+    fn from_str(s: &str) -> AHResult<Self> {
+        let (address, port) = s
+            .strip_prefix('[')
+            .and_then(|s| s.split_once("]:"))
+            .ok_or_else(|| anyhow!("expected [address]:port, got {}", s))?;
+
+        Ok(Self {
+            address: address.parse()?,
+            port: port.parse()?,
+        })
+    }
+}
+
+impl From<SocketAddrV6> for Endpoint {
+    fn from(address: SocketAddrV6) -> Self {
+        Self {
+            address: (*address.ip()).into(),
+            port: address.port(),
+        }
+    }
+}
+
+impl From<Endpoint> for SocketAddrV6 {
+    fn from(endpoint: Endpoint) -> Self {
+        SocketAddrV6::new(endpoint.address.into(), endpoint.port, 0, 0)
+    }
+}
+
+impl From<Endpoint> for SocketAddr {
+    fn from(endpoint: Endpoint) -> Self {
+        SocketAddr::V6(endpoint.into())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ConnectionKey {
+    pub local: Endpoint,
+    pub remote: Endpoint,
+}
+
+impl ConnectionKey {
+    pub fn reversed(&self) -> Self {
+        Self {
+            local: self.remote,
+            remote: self.local,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    LastAck,
+}
+
+/// Which way a segment is going, from our side of the connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A way of ending a connection early, to test how applications cope with it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Interruption {
+    Reset,
+    /// Close our side, as if the application had.
+    Fin,
+}
+
+/// Sees each segment of every connection, and may interrupt the connection it belongs to.
+pub type FlowHook =
+    Box<dyn Fn(ConnectionKey, Direction, &Segment) -> Option<Interruption> + Send + Sync>;
+
+enum Command {
+    Send(ConnectionKey, Vec<u8>),
+    Close(ConnectionKey),
+    Interrupt(ConnectionKey, Interruption),
+    List(channel::Sender<Vec<(ConnectionKey, State)>>),
+}
+
+/// A SYN-ACK waiting to be acknowledged, and when to send it again.
+struct SynAck {
+    segment: Segment,
+    due: Instant,
+    timeout: Duration,
+    retries_left: u32,
+}
+
+/// Transmission control block for one connection.
+struct Tcb {
+    state: State,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    /// Where received data goes; dropped when the peer closes its side.
+    incoming_sender: Option<WakingSender<Vec<u8>>>,
+    /// The peer's latest timestamp, to echo, if timestamps were agreed on.
+    ts_recent: Option<u32>,
+    /// Until the handshake is complete.
+    syn_ack: Option<SynAck>,
+    /// The peer's MSS; segments are also kept to what the path MTU allows when sent.
+    send_mss: usize,
+    /// When any segment last arrived, for keep-alives.
+    last_heard: Instant,
+    /// When data or a FIN was last sent or received, for the idle timeout.
+    last_active: Instant,
+    /// Keep-alives sent since we last heard from the peer.
+    probes_sent: u32,
+}
+
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// Listeners by port, each with the prefix it's limited to, if any.
+type Listeners = Arc<RwLock<HashMap<u16, (Option<ipv6::Prefix>, Arc<WakingSender<Connection>>)>>>;
+
+struct Actor {
+    ipv6_receiver: channel::Receiver<ipv6::Packet>,
+    ipv6_sender: channel::Sender<ipv6::Packet>,
+    command_receiver: channel::Receiver<Command>,
+    command_sender: channel::Sender<Command>,
+    path_mtus: ipv6::PathMtuHandle,
+    hop_limit: ipv6::HopLimitHandle,
+    listeners: Listeners,
+    port_policy: PortPolicy,
+    fingerprint: Fingerprint,
+    backlog: Backlog,
+    idle: Idle,
+    /// DSCP to mark segments from each local port with, where it isn't 0.
+    dscp: HashMap<u16, u8>,
+    hooks: Vec<FlowHook>,
+    connections: HashMap<ConnectionKey, Tcb>,
+}
+
+impl Actor {
+    /// Run the hooks over a segment, queueing any interruption they ask for.
+    fn run_hooks(&self, key: ConnectionKey, direction: Direction, segment: &Segment) {
+        if let Some(interruption) = self
+            .hooks
+            .iter()
+            .find_map(|hook| hook(key, direction, segment))
+        {
+            let _ = self
+                .command_sender
+                .send(Command::Interrupt(key, interruption));
+        }
+    }
+
+    fn send_segment(&self, key: ConnectionKey, mut segment: Segment) -> AHResult<()> {
+        if let Some(tcb) = self.connections.get(&key) {
+            if let Some(ts_recent) = tcb.ts_recent {
+                if segment.option(OPTION_TIMESTAMPS).is_none() {
+                    segment.options.extend_from_slice(&[OPTION_NOP, OPTION_NOP]);
+                    encode_timestamps(&mut segment.options, ts_recent);
+                }
+            }
+
+            self.run_hooks(key, Direction::Outbound, &segment);
+        }
+
+        let payload = segment.encode(ipv6::PseudoHeader {
+            src: key.local.address,
+            dest: key.remote.address,
+            length: 0,
+        });
+
+        self.ipv6_sender.send(
+            ipv6::Packet::builder()
+                .protocol(ipv4::ProtocolNumber::Tcp)
+                .dscp(self.dscp.get(&key.local.port).copied().unwrap_or(0))
+                .hop_limit(self.hop_limit.get())
+                .src(key.local.address)
+                .dest(key.remote.address)
+                .payload(payload)
+                .build(),
+        )?;
+
+        Ok(())
+    }
+
+    fn send_control(&self, key: ConnectionKey, flags: u8) -> AHResult<()> {
+        let tcb = &self.connections[&key];
+
+        self.send_segment(
+            key,
+            Segment {
+                src_port: key.local.port,
+                dest_port: key.remote.port,
+                seq: tcb.snd_nxt,
+                ack: tcb.rcv_nxt,
+                flags: flags | Segment::ACK,
+                window: self.fingerprint.window,
+                ..Default::default()
+            },
+        )
+    }
+
+    // Ref: https://datatracker.ietf.org/doc/html/rfc793#page-65 ("If the state is CLOSED")
+    fn send_reset(&self, key: ConnectionKey, incoming: &Segment) -> AHResult<()> {
+        let reply = if incoming.has(Segment::ACK) {
+            Segment {
+                seq: incoming.ack,
+                flags: Segment::RST,
+                ..Default::default()
+            }
+        } else {
+            Segment {
+                ack: incoming.seq.wrapping_add(incoming.seq_len()),
+                flags: Segment::RST | Segment::ACK,
+                ..Default::default()
+            }
+        };
+
+        self.send_segment(
+            key,
+            Segment {
+                src_port: key.local.port,
+                dest_port: key.remote.port,
+                ..reply
+            },
+        )
+    }
+
+    /// Options for the SYN-ACK answering `syn`, and the timestamp to echo if they include them.
+    fn syn_options(&self, key: ConnectionKey, syn: &Segment) -> (Vec<u8>, Option<u32>) {
+        let mut options = Vec::new();
+        let mut ts_recent = None;
+
+        for option in &self.fingerprint.syn_options {
+            match *option {
+                SynOption::Nop => options.push(OPTION_NOP),
+                SynOption::Mss => {
+                    let mss = self.max_segment_size(key).min(u16::MAX as usize) as u16;
+                    TcpOption::Mss(mss).encode(&mut options);
+                }
+                SynOption::WindowScale(shift) => {
+                    if syn.option(OPTION_WINDOW_SCALE).is_some() {
+                        // Ref: https://datatracker.ietf.org/doc/html/rfc7323#section-2.3
+                        TcpOption::WindowScale(shift.min(14)).encode(&mut options);
+                    }
+                }
+                SynOption::SackPermitted => {
+                    if syn.option(OPTION_SACK_PERMITTED).is_some() {
+                        TcpOption::SackPermitted.encode(&mut options);
+                    }
+                }
+                SynOption::Timestamps => {
+                    if let Some(value) = timestamp_value(syn) {
+                        encode_timestamps(&mut options, value);
+                        ts_recent = Some(value);
+                    }
+                }
+            }
+        }
+
+        (options, ts_recent)
+    }
+
+    /// Handle what the backlog has no room for, counted by the queue that overflowed.
+    fn overflow(&self, queue: &str, key: ConnectionKey, segment: &Segment) -> AHResult<()> {
+        stats::TCP_LISTEN_OVERFLOWS.increment(queue);
+
+        match self.backlog.overflow {
+            Overflow::Drop => Ok(()),
+            Overflow::Reset => self.send_reset(key, segment),
+        }
+    }
+
+    fn accept(&mut self, key: ConnectionKey, segment: &Segment) -> AHResult<()> {
+        let handshakes = self
+            .connections
+            .iter()
+            .filter(|(k, tcb)| k.local.port == key.local.port && tcb.state == State::SynReceived)
+            .count();
+        if handshakes >= self.backlog.syn {
+            return self.overflow("syn", key, segment);
+        }
+
+        let iss: u32 = rand::thread_rng().gen();
+        let (options, ts_recent) = self.syn_options(key, segment);
+        let now = Instant::now();
+
+        self.connections.insert(
+            key,
+            Tcb {
+                state: State::SynReceived,
+                snd_una: iss,
+                snd_nxt: iss,
+                rcv_nxt: segment.seq.wrapping_add(1),
+                incoming_sender: None,
+                ts_recent,
+                syn_ack: None,
+                send_mss: peer_mss(segment),
+                last_heard: now,
+                last_active: now,
+                probes_sent: 0,
+            },
+        );
+
+        let syn_ack = Segment {
+            src_port: key.local.port,
+            dest_port: key.remote.port,
+            seq: iss,
+            ack: segment.seq.wrapping_add(1),
+            flags: Segment::SYN | Segment::ACK,
+            window: self.fingerprint.window,
+            options,
+            ..Default::default()
+        };
+        self.send_segment(key, syn_ack.clone())?;
+
+        let tcb = self.connections.get_mut(&key).unwrap();
+        tcb.snd_nxt = iss.wrapping_add(1);
+        tcb.syn_ack = Some(SynAck {
+            segment: syn_ack,
+            due: now + INITIAL_RTO,
+            timeout: INITIAL_RTO,
+            retries_left: self.backlog.syn_ack_retries,
+        });
+
+        Ok(())
+    }
+
+    /// Send SYN-ACKs that have gone unacknowledged again, giving up on handshakes that are out of
+    /// retries.
+    fn retransmit_syn_acks(&mut self, now: Instant) -> AHResult<()> {
+        let mut due = Vec::new();
+        let mut given_up = Vec::new();
+        for (key, tcb) in &mut self.connections {
+            let syn_ack = match &mut tcb.syn_ack {
+                Some(syn_ack) if syn_ack.due <= now => syn_ack,
+                _ => continue,
+            };
+
+            if syn_ack.retries_left == 0 {
+                given_up.push(*key);
+                continue;
+            }
+            syn_ack.retries_left -= 1;
+            syn_ack.timeout *= 2;
+            syn_ack.due = now + syn_ack.timeout;
+            due.push((*key, syn_ack.segment.clone()));
+        }
+
+        for key in given_up {
+            self.connections.remove(&key);
+        }
+        for (key, segment) in due {
+            self.send_segment(key, segment)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send keep-alives to peers that have gone quiet, and reset connections that are out of
+    /// probes or have been idle too long.
+    fn check_idle(&mut self, now: Instant) -> AHResult<()> {
+        let mut probes = Vec::new();
+        let mut reaped = Vec::new();
+        for (key, tcb) in &mut self.connections {
+            if !matches!(tcb.state, State::Established | State::CloseWait) {
+                continue;
+            }
+
+            if let Some(timeout_secs) = self.idle.timeout_secs {
+                if now.duration_since(tcb.last_active) >= Duration::from_secs(timeout_secs) {
+                    reaped.push((*key, "idle"));
+                    continue;
+                }
+            }
+
+            let keepalive = match self.idle.keepalive {
+                Some(keepalive) => keepalive,
+                None => continue,
+            };
+            let due = Duration::from_secs(
+                keepalive.idle_secs + keepalive.interval_secs * tcb.probes_sent as u64,
+            );
+            if now.duration_since(tcb.last_heard) < due {
+                continue;
+            }
+            if tcb.probes_sent >= keepalive.probes {
+                reaped.push((*key, "keepalive"));
+                continue;
+            }
+
+            tcb.probes_sent += 1;
+            // One byte back from what we've sent, which the peer has to acknowledge again.
+            probes.push((
+                *key,
+                Segment {
+                    src_port: key.local.port,
+                    dest_port: key.remote.port,
+                    seq: tcb.snd_nxt.wrapping_sub(1),
+                    ack: tcb.rcv_nxt,
+                    flags: Segment::ACK,
+                    window: self.fingerprint.window,
+                    ..Default::default()
+                },
+            ));
+        }
+
+        for (key, segment) in probes {
+            self.send_segment(key, segment)?;
+        }
+        for (key, reason) in reaped {
+            self.send_control(key, Segment::RST)?;
+            self.connections.remove(&key);
+
+            stats::TCP_REAPED.increment(reason);
+            status::update(|s| {
+                s.push_event(EventStatus::new(
+                    EventKind::Reaped,
+                    "tcp",
+                    format!(
+                        "reset connection from {} to {}: {}",
+                        key.remote, key.local, reason
+                    ),
+                ))
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The listener for connections to `local`, if its port is listened on and its prefix, if
+    /// any, contains the address.
+    fn listener(&self, local: Endpoint) -> Option<Arc<WakingSender<Connection>>> {
+        match self.listeners.read().unwrap().get(&local.port) {
+            Some((prefix, sender)) if prefix.is_none_or(|p| p.contains(local.address)) => {
+                Some(sender.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Hand a newly established connection off to its listener, resetting it if nobody is
+    /// listening anymore. Returns whether it was, as it's left in the handshake when the
+    /// listener's accept queue is full.
+    fn establish(&mut self, key: ConnectionKey, segment: &Segment) -> AHResult<bool> {
+        let listener = self.listener(key.local);
+        if listener
+            .as_ref()
+            .is_some_and(|l| l.len() >= self.backlog.accept)
+        {
+            if self.backlog.overflow == Overflow::Reset {
+                self.connections.remove(&key);
+            }
+            self.overflow("accept", key, segment)?;
+            return Ok(false);
+        }
+
+        let (incoming_sender, incoming_receiver) = channel::unbounded();
+        let incoming_waker = WakerSlot::default();
+
+        let connection = Connection {
+            key,
+            receiver: incoming_receiver,
+            waker: incoming_waker.clone(),
+            commands: self.command_sender.clone(),
+        };
+
+        let delivered = listener.is_some_and(|l| l.send(connection).is_ok());
+
+        if !delivered {
+            self.connections.remove(&key);
+            self.send_reset(key, segment)?;
+            return Ok(false);
+        }
+
+        let tcb = self.connections.get_mut(&key).unwrap();
+        tcb.state = State::Established;
+        tcb.incoming_sender = Some(WakingSender::new(incoming_sender, incoming_waker));
+        tcb.syn_ack = None;
+
+        Ok(true)
+    }
+
+    fn process_segment(&mut self, key: ConnectionKey, segment: Segment) -> AHResult<()> {
+        if self.connections.contains_key(&key) {
+            self.run_hooks(key, Direction::Inbound, &segment);
+        }
+
+        let tcb = match self.connections.get_mut(&key) {
+            Some(tcb) => tcb,
+            None => {
+                let listening = self.listener(key.local).is_some();
+
+                return if segment.has(Segment::RST)
+                    || (!listening && self.port_policy.get(key.local.port) == UnboundPort::Filtered)
+                {
+                    Ok(())
+                } else if listening && segment.has(Segment::SYN) && !segment.has(Segment::ACK) {
+                    self.accept(key, &segment)
+                } else {
+                    self.send_reset(key, &segment)
+                };
+            }
+        };
+
+        let now = Instant::now();
+        tcb.last_heard = now;
+        tcb.probes_sent = 0;
+        if !segment.payload.is_empty() || segment.has(Segment::FIN) {
+            tcb.last_active = now;
+        }
+
+        if tcb.ts_recent.is_some() {
+            if let Some(value) = timestamp_value(&segment) {
+                tcb.ts_recent = Some(value);
+            }
+        }
+
+        if segment.has(Segment::RST) {
+            if segment.seq == tcb.rcv_nxt {
+                self.connections.remove(&key);
+            }
+
+            return Ok(());
+        }
+
+        if segment.has(Segment::ACK) {
+            if seq_lt(tcb.snd_una, segment.ack) && seq_le(segment.ack, tcb.snd_nxt) {
+                tcb.snd_una = segment.ack;
+            }
+            let all_acked = tcb.snd_una == tcb.snd_nxt;
+
+            match tcb.state {
+                State::SynReceived if all_acked => {
+                    // Left in the handshake, anything else it carries waits for the peer to try
+                    // again.
+                    let established = self.establish(key, &segment)?;
+                    if !established {
+                        return Ok(());
+                    }
+                }
+                State::FinWait1 if all_acked => tcb.state = State::FinWait2,
+                State::LastAck if all_acked => {
+                    self.connections.remove(&key);
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
+        if segment.payload.is_empty() && !segment.has(Segment::FIN) {
+            return Ok(());
+        }
+
+        let tcb = match self.connections.get_mut(&key) {
+            Some(tcb) => tcb,
+            None => return Ok(()),
+        };
+
+        // We don't queue out-of-order segments, so just ask for what we're missing.
+        if segment.seq != tcb.rcv_nxt {
+            return self.send_control(key, 0);
+        }
+
+        if !segment.payload.is_empty() {
+            tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(segment.payload.len() as u32);
+
+            if let Some(sender) = &tcb.incoming_sender {
+                let _ = sender.send(segment.payload.clone());
+            }
+        }
+
+        let mut closed = false;
+        if segment.has(Segment::FIN) {
+            tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(1);
+            tcb.incoming_sender = None;
+
+            match tcb.state {
+                State::SynReceived | State::Established => tcb.state = State::CloseWait,
+                // Skip TIME-WAIT, since we never reuse ports quickly enough for it to matter.
+                State::FinWait1 | State::FinWait2 => closed = true,
+                _ => {}
+            }
+        }
+
+        self.send_control(key, 0)?;
+
+        if closed {
+            self.connections.remove(&key);
+        }
+
+        Ok(())
+    }
+
+    /// The most we can take in each segment, which is what the path MTU allows.
+    fn max_segment_size(&self, key: ConnectionKey) -> usize {
+        self.path_mtus.max_payload(key.remote.address) - HEADER_LEN
+    }
+
+    // TODO: retransmission; we currently rely on the link not losing anything.
+    fn process_command(&mut self, command: Command) -> AHResult<()> {
+        match command {
+            Command::Send(key, data) => {
+                let mss = match self.connections.get(&key) {
+                    Some(tcb) => tcb.send_mss.min(self.max_segment_size(key)),
+                    None => return Ok(()),
+                };
+
+                for chunk in data.chunks(mss) {
+                    let tcb = match self.connections.get_mut(&key) {
+                        Some(tcb) if matches!(tcb.state, State::Established | State::CloseWait) => {
+                            tcb
+                        }
+                        _ => return Ok(()),
+                    };
+
+                    let segment = Segment {
+                        src_port: key.local.port,
+                        dest_port: key.remote.port,
+                        seq: tcb.snd_nxt,
+                        ack: tcb.rcv_nxt,
+                        flags: Segment::ACK | Segment::PSH,
+                        window: self.fingerprint.window,
+                        payload: chunk.to_vec(),
+                        ..Default::default()
+                    };
+                    tcb.snd_nxt = tcb.snd_nxt.wrapping_add(chunk.len() as u32);
+                    tcb.last_active = Instant::now();
+
+                    self.send_segment(key, segment)?;
+                }
+            }
+            Command::Close(key) | Command::Interrupt(key, Interruption::Fin) => self.close(key)?,
+            Command::Interrupt(key, Interruption::Reset) => {
+                if self.connections.contains_key(&key) {
+                    self.send_control(key, Segment::RST)?;
+                    // Dropping the TCB disconnects the application's receiver.
+                    self.connections.remove(&key);
+                }
+            }
+            Command::List(sender) => {
+                let _ = sender.send(
+                    self.connections
+                        .iter()
+                        .map(|(key, tcb)| (*key, tcb.state))
+                        .collect(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self, key: ConnectionKey) -> AHResult<()> {
+        let next_state = match self.connections.get(&key).map(|tcb| tcb.state) {
+            Some(State::Established) => State::FinWait1,
+            Some(State::CloseWait) => State::LastAck,
+            _ => return Ok(()),
+        };
+
+        self.send_control(key, Segment::FIN)?;
+
+        let tcb = self.connections.get_mut(&key).unwrap();
+        tcb.snd_nxt = tcb.snd_nxt.wrapping_add(1);
+        tcb.state = next_state;
+
+        Ok(())
+    }
+
+    fn process_packet(&mut self, packet: ipv6::Packet) -> AHResult<()> {
+        let segment = segment(
+            &packet.payload,
+            ipv6::PseudoHeader {
+                src: packet.src,
+                dest: packet.dest,
+                length: 0,
+            },
+        )?;
+
+        let key = ConnectionKey {
+            local: Endpoint {
+                address: packet.dest,
+                port: segment.dest_port,
+            },
+            remote: Endpoint {
+                address: packet.src,
+                port: segment.src_port,
+            },
+        };
+
+        self.process_segment(key, segment)
+    }
+
+    fn run(&mut self) {
+        let heartbeat = watchdog::register("tcp");
+        let beats = channel::tick(watchdog::INTERVAL);
+        let syn_ack_checks = channel::tick(SYN_ACK_CHECK_INTERVAL);
+        let idle_checks = channel::tick(IDLE_CHECK_INTERVAL);
+
+        loop {
+            let result = select! {
+                recv(beats) -> _ => {
+                    heartbeat.beat();
+                    Ok(())
+                },
+                // IPv6 is gone, so nothing more will arrive.
+                recv(self.ipv6_receiver) -> packet => match packet {
+                    Ok(packet) => {
+                        supervisor::handling(&packet.payload);
+                        self.process_packet(packet)
+                    }
+                    Err(_) => return,
+                },
+                recv(self.command_receiver) -> command => match command {
+                    Ok(command) => self.process_command(command),
+                    Err(_) => return,
+                },
+                recv(syn_ack_checks) -> _ => self.retransmit_syn_acks(Instant::now()),
+                recv(idle_checks) -> _ => self.check_idle(Instant::now()),
+            };
+
+            if let Err(e) = result {
+                warn!("tcp: {}", e);
+            }
+        }
+    }
+}
+
+/// An established connection.
+///
+/// Received data arrives on `receiver()`, which disconnects once the peer has closed its side.
+pub struct Connection {
+    key: ConnectionKey,
+    receiver: channel::Receiver<Vec<u8>>,
+    waker: WakerSlot,
+    commands: channel::Sender<Command>,
+}
+
+impl Connection {
+    pub fn local(&self) -> Endpoint {
+        self.key.local
+    }
+
+    pub fn remote(&self) -> Endpoint {
+        self.key.remote
+    }
+
+    pub fn receiver(&self) -> &channel::Receiver<Vec<u8>> {
+        &self.receiver
+    }
+
+    /// Where to leave the task waiting on `receiver()`, to wake when data arrives or the peer
+    /// closes its side.
+    pub fn waker(&self) -> &WakerSlot {
+        &self.waker
+    }
+
+    pub fn send(&self, data: impl Into<Vec<u8>>) -> AHResult<()> {
+        self.commands
+            .send(Command::Send(self.key, data.into()))
+            .map_err(|_| anyhow!("tcp server has stopped"))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Close(self.key));
+    }
+}
+
+pub struct Listener {
+    port: u16,
+    receiver: channel::Receiver<Connection>,
+    waker: WakerSlot,
+    listeners: Listeners,
+}
+
+impl Listener {
+    pub fn accept(&self) -> AHResult<Connection> {
+        Ok(self.receiver.recv()?)
+    }
+
+    pub fn receiver(&self) -> &channel::Receiver<Connection> {
+        &self.receiver
+    }
+
+    /// Where to leave the task waiting on `receiver()`, to wake when a connection arrives.
+    pub fn waker(&self) -> &WakerSlot {
+        &self.waker
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.listeners.write().unwrap().remove(&self.port);
+    }
+}
+
+/// Handle for inspecting and interrupting a `Server`'s connections from elsewhere.
+#[derive(Clone)]
+pub struct FlowHandle {
+    commands: channel::Sender<Command>,
+}
+
+impl FlowHandle {
+    pub fn connections(&self) -> AHResult<Vec<(ConnectionKey, State)>> {
+        let (sender, receiver) = channel::bounded(1);
+        self.commands
+            .send(Command::List(sender))
+            .map_err(|_| anyhow!("tcp server has stopped"))?;
+
+        Ok(receiver.recv()?)
+    }
+
+    /// Interrupt a connection, if it still exists.
+    pub fn interrupt(&self, key: ConnectionKey, interruption: Interruption) -> AHResult<()> {
+        self.commands
+            .send(Command::Interrupt(key, interruption))
+            .map_err(|_| anyhow!("tcp server has stopped"))
+    }
+}
+
+pub struct Server {
+    actor: Option<Actor>,
+    listeners: Listeners,
+    commands: channel::Sender<Command>,
+}
+
+impl Server {
+    pub fn new(ipv6_server: &mut ipv6::Server) -> AHResult<Self> {
+        let (ipv6_sender, ipv6_receiver) = channel::bounded(1024);
+        ipv6_server.register_with_backpressure(
+            ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Tcp),
+            ipv6_sender,
+            Backpressure::DropOldest(ipv6_receiver.clone()),
+        );
+
+        let (command_sender, command_receiver) = channel::unbounded();
+        let listeners = Arc::new(RwLock::new(HashMap::new()));
+
+        Ok(Self {
+            actor: Some(Actor {
+                ipv6_receiver,
+                ipv6_sender: ipv6_server.writer(),
+                command_receiver,
+                command_sender: command_sender.clone(),
+                path_mtus: ipv6_server.path_mtus(),
+                hop_limit: ipv6_server.hop_limit(),
+                listeners: listeners.clone(),
+                port_policy: PortPolicy::default(),
+                fingerprint: Fingerprint::default(),
+                backlog: Backlog::default(),
+                idle: Idle::default(),
+                dscp: HashMap::new(),
+                hooks: Vec::new(),
+                connections: HashMap::new(),
+            }),
+            listeners,
+            commands: command_sender,
+        })
+    }
+
+    pub fn set_port_policy(&mut self, port_policy: PortPolicy) {
+        self.actor
+            .as_mut()
+            .expect("port policy must be set before the server is started")
+            .port_policy = port_policy;
+    }
+
+    pub fn set_fingerprint(&mut self, fingerprint: Fingerprint) {
+        self.actor
+            .as_mut()
+            .expect("fingerprint must be set before the server is started")
+            .fingerprint = fingerprint;
+    }
+
+    pub fn set_backlog(&mut self, backlog: Backlog) {
+        self.actor
+            .as_mut()
+            .expect("backlog must be set before the server is started")
+            .backlog = backlog;
+    }
+
+    pub fn set_idle(&mut self, idle: Idle) {
+        self.actor
+            .as_mut()
+            .expect("idle handling must be set before the server is started")
+            .idle = idle;
+    }
+
+    /// Mark segments sent from local `port` with `dscp`.
+    pub fn set_port_dscp(&mut self, port: u16, dscp: u8) {
+        self.actor
+            .as_mut()
+            .expect("dscp must be set before the server is started")
+            .dscp
+            .insert(port, dscp);
+    }
+
+    pub fn add_flow_hook(&mut self, hook: FlowHook) {
+        self.actor
+            .as_mut()
+            .expect("flow hooks must be added before the server is started")
+            .hooks
+            .push(hook);
+    }
+
+    pub fn flows(&self) -> FlowHandle {
+        FlowHandle {
+            commands: self.commands.clone(),
+        }
+    }
+
+    /// Accept connections to `port` on any of our addresses.
+    pub fn listen(&self, port: u16) -> AHResult<Listener> {
+        self.listen_on(port, None)
+    }
+
+    /// Accept connections to `port` on any address in `prefix`, whether or not it's one of ours,
+    /// like a server behind an anycast or virtual address.
+    pub fn listen_prefix(&self, port: u16, prefix: ipv6::Prefix) -> AHResult<Listener> {
+        self.listen_on(port, Some(prefix))
+    }
+
+    fn listen_on(&self, port: u16, prefix: Option<ipv6::Prefix>) -> AHResult<Listener> {
+        let (sender, receiver) = channel::unbounded();
+        let waker = WakerSlot::default();
+
+        let mut listeners = self.listeners.write().unwrap();
+        if listeners.contains_key(&port) {
+            bail!("tcp port {} is already listened on", port);
+        }
+        let sender = WakingSender::new(sender, waker.clone());
+        listeners.insert(port, (prefix, Arc::new(sender)));
+
+        Ok(Listener {
+            port,
+            receiver,
+            waker,
+            listeners: self.listeners.clone(),
+        })
+    }
+
+    pub fn start(&mut self) {
+        let mut actor = self.actor.take().unwrap();
+
+        supervisor::spawn("tcp", move || actor.run());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Harness {
+        actor: Actor,
+        sent: channel::Receiver<ipv6::Packet>,
+        key: ConnectionKey,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let (ipv6_sender, sent) = channel::unbounded();
+            let (command_sender, command_receiver) = channel::unbounded();
+
+            Self {
+                actor: Actor {
+                    ipv6_receiver: channel::never(),
+                    ipv6_sender,
+                    command_receiver,
+                    command_sender,
+                    path_mtus: ipv6::PathMtuHandle::new(1500),
+                    hop_limit: ipv6::HopLimitHandle::new(HOP_LIMIT),
+                    listeners: Arc::new(RwLock::new(HashMap::new())),
+                    port_policy: PortPolicy::default(),
+                    fingerprint: Fingerprint::default(),
+                    backlog: Backlog::default(),
+                    idle: Idle::default(),
+                    dscp: HashMap::new(),
+                    hooks: Vec::new(),
+                    connections: HashMap::new(),
+                },
+                sent,
+                key: ConnectionKey {
+                    local: Endpoint {
+                        address: "fe80::1".parse().unwrap(),
+                        port: 23,
+                    },
+                    remote: Endpoint {
+                        address: "fe80::2".parse().unwrap(),
+                        port: 49152,
+                    },
+                },
+            }
+        }
+
+        /// Listen on `port`, returning where its connections arrive.
+        fn listen(&mut self, port: u16) -> channel::Receiver<Connection> {
+            let (sender, receiver) = channel::unbounded();
+            let sender = WakingSender::new(sender, WakerSlot::default());
+            self.actor
+                .listeners
+                .write()
+                .unwrap()
+                .insert(port, (None, Arc::new(sender)));
+            receiver
+        }
+
+        fn receive(&mut self, seq: u32, ack: u32, flags: u8, payload: &[u8]) {
+            let segment = Segment {
+                src_port: self.key.remote.port,
+                dest_port: self.key.local.port,
+                seq,
+                ack,
+                flags,
+                window: WINDOW,
+                payload: payload.to_vec(),
+                ..Default::default()
+            };
+
+            self.actor.process_segment(self.key, segment).unwrap();
+        }
+
+        fn sent_segment(&self) -> Segment {
+            let packet = self.sent.try_recv().unwrap();
+
+            segment(
+                &packet.payload,
+                ipv6::PseudoHeader {
+                    src: packet.src,
+                    dest: packet.dest,
+                    length: 0,
+                },
+            )
+            .unwrap()
+        }
+    }
+
+    #[test]
+    fn endpoints_convert_to_and_from_socket_addresses() {
+        let endpoint: Endpoint = "[2001:db8::1]:8080".parse().unwrap();
+        let socket_address: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+        assert_eq!(SocketAddr::from(endpoint), socket_address);
+        match socket_address {
+            SocketAddr::V6(v6) => assert_eq!(Endpoint::from(v6), endpoint),
+            SocketAddr::V4(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn closed_port_resets() {
+        let mut harness = Harness::new();
+        harness.receive(1000, 0, Segment::SYN, b"");
+
+        let reset = harness.sent_segment();
+        assert_eq!(reset.flags, Segment::RST | Segment::ACK);
+        assert_eq!(reset.ack, 1001);
+    }
+
+    #[test]
+    fn filtered_port_drops() {
+        let mut harness = Harness::new();
+        harness.actor.port_policy.filtered = vec![23];
+        harness.receive(1000, 0, Segment::SYN, b"");
+
+        assert!(harness.sent.try_recv().is_err());
+    }
+
+    #[test]
+    fn handshake_data_and_close() {
+        let mut harness = Harness::new();
+        let listener = harness.listen(23);
+
+        harness.receive(1000, 0, Segment::SYN, b"");
+        let syn_ack = harness.sent_segment();
+        assert_eq!(syn_ack.flags, Segment::SYN | Segment::ACK);
+        assert_eq!(syn_ack.ack, 1001);
+
+        let iss = syn_ack.seq;
+        harness.receive(1001, iss.wrapping_add(1), Segment::ACK, b"");
+        let connection = listener.try_recv().unwrap();
+
+        harness.receive(1001, iss.wrapping_add(1), Segment::ACK, b"root\r\n");
+        assert_eq!(connection.receiver().try_recv().unwrap(), b"root\r\n");
+        assert_eq!(harness.sent_segment().ack, 1007);
+
+        connection.send("Password: ").unwrap();
+        drop(connection);
+        while let Ok(command) = harness.actor.command_receiver.try_recv() {
+            harness.actor.process_command(command).unwrap();
+        }
+
+        assert_eq!(harness.sent_segment().payload, b"Password: ");
+        let fin = harness.sent_segment();
+        assert!(fin.has(Segment::FIN));
+
+        harness.receive(
+            1007,
+            fin.seq.wrapping_add(1),
+            Segment::ACK | Segment::FIN,
+            b"",
+        );
+        assert!(harness.actor.connections.is_empty());
+    }
+
+    #[test]
+    fn syn_acks_follow_the_fingerprint() {
+        let mut harness = Harness::new();
+        let _receiver = harness.listen(23);
+        harness.actor.hop_limit = ipv6::HopLimitHandle::new(128);
+        harness.actor.fingerprint = Fingerprint {
+            window: 8192,
+            syn_options: vec![
+                SynOption::Mss,
+                SynOption::Nop,
+                SynOption::WindowScale(8),
+                SynOption::SackPermitted,
+                SynOption::Timestamps,
+            ],
+        };
+
+        // Offering timestamps but not window scaling or selective acknowledgements.
+        let syn = Segment {
+            src_port: harness.key.remote.port,
+            dest_port: harness.key.local.port,
+            seq: 1000,
+            flags: Segment::SYN,
+            options: vec![8, 10, 0, 0, 0, 7, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        harness.actor.process_segment(harness.key, syn).unwrap();
+
+        let packet = harness.sent.try_recv().unwrap();
+        assert_eq!(packet.hop_limit, 128);
+        let syn_ack = segment(
+            &packet.payload,
+            ipv6::PseudoHeader {
+                src: packet.src,
+                dest: packet.dest,
+                length: 0,
+            },
+        )
+        .unwrap();
+        assert_eq!(syn_ack.window, 8192);
+        assert_eq!(&syn_ack.options[..6], &[2, 4, 0x05, 0xa0, 1, 8]);
+        assert_eq!(&syn_ack.options[11..15], &[0, 0, 0, 7]);
+
+        harness.receive(1001, syn_ack.seq.wrapping_add(1), Segment::ACK, b"hi");
+        assert_eq!(
+            harness.sent_segment().option(OPTION_TIMESTAMPS).unwrap()[4..],
+            [0, 0, 0, 7]
+        );
+    }
+
+    #[test]
+    fn data_is_kept_to_the_peers_mss_and_path_mtu() {
+        let mut harness = Harness::new();
+        let receiver = harness.listen(23);
+
+        let mut options = Vec::new();
+        TcpOption::Mss(1000).encode(&mut options);
+        let syn = Segment {
+            src_port: harness.key.remote.port,
+            dest_port: harness.key.local.port,
+            seq: 1000,
+            flags: Segment::SYN,
+            options,
+            ..Default::default()
+        };
+        harness.actor.process_segment(harness.key, syn).unwrap();
+        let iss = harness.sent_segment().seq;
+        harness.receive(1001, iss.wrapping_add(1), Segment::ACK, b"");
+        let connection = receiver.try_recv().unwrap();
+
+        connection.send(vec![0; 2500]).unwrap();
+        let command = harness.actor.command_receiver.try_recv().unwrap();
+        harness.actor.process_command(command).unwrap();
+        let sizes: Vec<_> = (0..3)
+            .map(|_| harness.sent_segment().payload.len())
+            .collect();
+        assert_eq!(sizes, [1000, 1000, 500]);
+
+        // A smaller path MTU wins over the peer's MSS.
+        harness.actor.path_mtus = ipv6::PathMtuHandle::new(1000);
+        connection.send(vec![0; 1000]).unwrap();
+        let command = harness.actor.command_receiver.try_recv().unwrap();
+        harness.actor.process_command(command).unwrap();
+        assert_eq!(harness.sent_segment().payload.len(), 1000 - 40 - HEADER_LEN);
+    }
+
+    #[test]
+    fn tiny_peer_mss_is_raised_to_the_minimum() {
+        let syn_with_mss = |mss| {
+            let mut options = Vec::new();
+            TcpOption::Mss(mss).encode(&mut options);
+            Segment {
+                flags: Segment::SYN,
+                options,
+                ..Default::default()
+            }
+        };
+
+        assert_eq!(peer_mss(&syn_with_mss(0)), MIN_SEND_MSS);
+        assert_eq!(peer_mss(&syn_with_mss(1000)), 1000);
+        assert_eq!(peer_mss(&Segment::default()), DEFAULT_SEND_MSS);
+    }
+
+    #[test]
+    fn quiet_connections_are_probed_and_reaped() {
+        let mut harness = Harness::new();
+        let receiver = harness.listen(23);
+        harness.actor.idle = Idle {
+            timeout_secs: Some(3600),
+            keepalive: Some(Keepalive {
+                idle_secs: 60,
+                interval_secs: 10,
+                probes: 2,
+            }),
+        };
+
+        harness.receive(1000, 0, Segment::SYN, b"");
+        let iss = harness.sent_segment().seq;
+        harness.receive(1001, iss.wrapping_add(1), Segment::ACK, b"");
+        let _connection = receiver.try_recv().unwrap();
+        let start = Instant::now();
+
+        harness.actor.check_idle(start).unwrap();
+        assert!(harness.sent.try_recv().is_err());
+
+        harness
+            .actor
+            .check_idle(start + Duration::from_secs(60))
+            .unwrap();
+        let probe = harness.sent_segment();
+        assert_eq!(probe.seq, iss);
+        assert!(probe.payload.is_empty());
+
+        // An answer starts the wait for the next probe over, without counting as activity.
+        harness.receive(1001, iss.wrapping_add(1), Segment::ACK, b"");
+        harness
+            .actor
+            .check_idle(start + Duration::from_secs(30))
+            .unwrap();
+        assert!(harness.sent.try_recv().is_err());
+
+        let later = Instant::now() + Duration::from_secs(60);
+        harness.actor.check_idle(later).unwrap();
+        harness
+            .actor
+            .check_idle(later + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(harness.sent_segment().seq, iss);
+        assert_eq!(harness.sent_segment().seq, iss);
+        assert!(harness.actor.connections.contains_key(&harness.key));
+
+        harness
+            .actor
+            .check_idle(later + Duration::from_secs(20))
+            .unwrap();
+        assert!(harness.sent_segment().has(Segment::RST));
+        assert!(harness.actor.connections.is_empty());
+
+        // The idle timeout applies even to peers that answer every probe.
+        harness.actor.idle.keepalive = None;
+        harness.receive(2000, 0, Segment::SYN, b"");
+        let iss = harness.sent_segment().seq;
+        harness.receive(2001, iss.wrapping_add(1), Segment::ACK, b"");
+        harness
+            .actor
+            .check_idle(Instant::now() + Duration::from_secs(3600))
+            .unwrap();
+        assert!(harness.sent_segment().has(Segment::RST));
+        assert!(harness.actor.connections.is_empty());
+    }
+
+    #[test]
+    fn hooks_interrupt_connections() {
+        let mut harness = Harness::new();
+        let receiver = harness.listen(23);
+        harness.actor.hooks.push(Box::new(|_, direction, segment| {
+            (direction == Direction::Inbound && !segment.payload.is_empty())
+                .then_some(Interruption::Reset)
+        }));
+
+        harness.receive(1000, 0, Segment::SYN, b"");
+        let iss = harness.sent_segment().seq;
+        harness.receive(1001, iss.wrapping_add(1), Segment::ACK, b"");
+        let connection = receiver.try_recv().unwrap();
+
+        harness.receive(1001, iss.wrapping_add(1), Segment::ACK, b"quit\r\n");
+        harness.sent_segment();
+        while let Ok(command) = harness.actor.command_receiver.try_recv() {
+            harness.actor.process_command(command).unwrap();
+        }
+
+        let reset = harness.sent_segment();
+        assert!(reset.has(Segment::RST));
+        assert_eq!(reset.seq, iss.wrapping_add(1));
+        assert!(harness.actor.connections.is_empty());
+        assert_eq!(connection.receiver().try_recv().unwrap(), b"quit\r\n");
+        assert!(connection.receiver().recv().is_err());
+    }
+
+    #[test]
+    fn full_backlogs_overflow() {
+        let mut harness = Harness::new();
+        let receiver = harness.listen(23);
+        harness.actor.backlog = Backlog {
+            syn: 1,
+            accept: 0,
+            overflow: Overflow::Reset,
+            ..Default::default()
+        };
+
+        harness.receive(1000, 0, Segment::SYN, b"");
+        let iss = harness.sent_segment().seq;
+
+        let other = ConnectionKey {
+            remote: Endpoint {
+                port: 49153,
+                ..harness.key.remote
+            },
+            ..harness.key
+        };
+        let syn = Segment {
+            src_port: other.remote.port,
+            dest_port: other.local.port,
+            seq: 5000,
+            flags: Segment::SYN,
+            ..Default::default()
+        };
+        harness.actor.process_segment(other, syn).unwrap();
+        assert!(harness.sent_segment().has(Segment::RST));
+
+        // Dropped rather than reset, the handshake is left for the peer to try again.
+        harness.actor.backlog.overflow = Overflow::Drop;
+        harness.receive(1001, iss.wrapping_add(1), Segment::ACK, b"GET /");
+        assert!(harness.sent.try_recv().is_err());
+        assert_eq!(
+            harness.actor.connections[&harness.key].state,
+            State::SynReceived
+        );
+
+        harness.actor.backlog.accept = 1;
+        harness.receive(1001, iss.wrapping_add(1), Segment::ACK, b"GET /");
+        assert_eq!(harness.sent_segment().ack, 1006);
+        assert!(receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn syn_acks_are_sent_again_until_given_up() {
+        let mut harness = Harness::new();
+        let _receiver = harness.listen(23);
+        harness.actor.backlog.syn_ack_retries = 1;
+
+        let start = Instant::now();
+        harness.receive(1000, 0, Segment::SYN, b"");
+        let syn_ack = harness.sent_segment();
+
+        harness.actor.retransmit_syn_acks(start).unwrap();
+        assert!(harness.sent.try_recv().is_err());
+
+        let first_retry = start + INITIAL_RTO + Duration::from_millis(10);
+        harness.actor.retransmit_syn_acks(first_retry).unwrap();
+        assert_eq!(harness.sent_segment(), syn_ack);
+
+        harness
+            .actor
+            .retransmit_syn_acks(first_retry + INITIAL_RTO * 2)
+            .unwrap();
+        assert!(harness.sent.try_recv().is_err());
+        assert!(harness.actor.connections.is_empty());
+    }
+}
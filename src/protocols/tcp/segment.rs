@@ -0,0 +1,345 @@
+use anyhow::{bail, Result as AHResult};
+use byteorder::ByteOrder;
+use nom::{
+    bytes::complete::take,
+    combinator::{rest, verify},
+    number::complete::{be_u16, be_u32, be_u8},
+};
+
+use crate::protocols::{ipv4, ipv6};
+use crate::{encode, try_parse};
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc793#section-3.1
+
+// Ref: https://www.iana.org/assignments/tcp-parameters/tcp-parameters.xhtml
+pub const OPTION_END: u8 = 0;
+pub const OPTION_NOP: u8 = 1;
+pub const OPTION_MSS: u8 = 2;
+pub const OPTION_WINDOW_SCALE: u8 = 3;
+pub const OPTION_SACK_PERMITTED: u8 = 4;
+pub const OPTION_SACK: u8 = 5;
+pub const OPTION_TIMESTAMPS: u8 = 8;
+
+/// An option, parsed; ones we don't know, or with the wrong length for their kind, are `Other`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TcpOption {
+    Mss(u16),
+    // Ref: https://datatracker.ietf.org/doc/html/rfc7323#section-2
+    WindowScale(u8),
+    // Ref: https://datatracker.ietf.org/doc/html/rfc2018#section-2
+    SackPermitted,
+    /// Blocks of sequence space received, each from its left to its right edge.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc2018#section-3
+    Sack(Vec<(u32, u32)>),
+    // Ref: https://datatracker.ietf.org/doc/html/rfc7323#section-3
+    Timestamps {
+        value: u32,
+        echo: u32,
+    },
+    Other {
+        kind: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl TcpOption {
+    fn parse(kind: u8, data: &[u8]) -> Self {
+        let be_u32 = |bytes: &[u8]| byteorder::NetworkEndian::read_u32(bytes);
+
+        match (kind, data.len()) {
+            (OPTION_MSS, 2) => Self::Mss(byteorder::NetworkEndian::read_u16(data)),
+            (OPTION_WINDOW_SCALE, 1) => Self::WindowScale(data[0]),
+            (OPTION_SACK_PERMITTED, 0) => Self::SackPermitted,
+            (OPTION_SACK, len) if len > 0 && len % 8 == 0 => Self::Sack(
+                data.chunks(8)
+                    .map(|block| (be_u32(&block[..4]), be_u32(&block[4..])))
+                    .collect(),
+            ),
+            (OPTION_TIMESTAMPS, 8) => Self::Timestamps {
+                value: be_u32(&data[..4]),
+                echo: be_u32(&data[4..]),
+            },
+            _ => Self::Other {
+                kind,
+                data: data.to_vec(),
+            },
+        }
+    }
+
+    /// Append this option, kind and length first, to `options`.
+    pub fn encode(&self, options: &mut Vec<u8>) {
+        let (kind, data) = match self {
+            Self::Mss(mss) => (OPTION_MSS, mss.to_be_bytes().to_vec()),
+            Self::WindowScale(shift) => (OPTION_WINDOW_SCALE, vec![*shift]),
+            Self::SackPermitted => (OPTION_SACK_PERMITTED, vec![]),
+            Self::Sack(blocks) => (
+                OPTION_SACK,
+                blocks
+                    .iter()
+                    .flat_map(|&(left, right)| encode!(left, right))
+                    .collect(),
+            ),
+            Self::Timestamps { value, echo } => (OPTION_TIMESTAMPS, encode!(*value, *echo)),
+            Self::Other { kind, data } => (*kind, data.clone()),
+        };
+
+        options.extend_from_slice(&[kind, data.len() as u8 + 2]);
+        options.extend_from_slice(&data);
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Segment {
+    pub src_port: u16,
+    pub dest_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub urgent: u16,
+    /// Raw options, padded to a multiple of 4 bytes when encoded.
+    pub options: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl Segment {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+    pub const URG: u8 = 0x20;
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Each option's kind and data, up to the end of the list or the first malformed one.
+    fn raw_options(&self) -> impl Iterator<Item = (u8, &[u8])> {
+        let mut options = &self.options[..];
+
+        std::iter::from_fn(move || loop {
+            match *options {
+                [] | [OPTION_END, ..] => return None,
+                [OPTION_NOP, ref rest @ ..] => options = rest,
+                [kind, len, ..] => {
+                    let len = len as usize;
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    let data = &options[2..len];
+                    options = &options[len..];
+                    return Some((kind, data));
+                }
+                [_] => return None,
+            }
+        })
+    }
+
+    /// The data of the first option of `kind`, if it's present and well-formed.
+    pub fn option(&self, kind: u8) -> Option<&[u8]> {
+        self.raw_options()
+            .find(|&(found, _)| found == kind)
+            .map(|(_, data)| data)
+    }
+
+    /// The options, parsed, up to the first malformed one.
+    pub fn parsed_options(&self) -> Vec<TcpOption> {
+        self.raw_options()
+            .map(|(kind, data)| TcpOption::parse(kind, data))
+            .collect()
+    }
+
+    /// Amount of sequence space this segment occupies.
+    pub fn seq_len(&self) -> u32 {
+        self.payload.len() as u32 + self.has(Self::SYN) as u32 + self.has(Self::FIN) as u32
+    }
+
+    /// Encode this segment.
+    ///
+    /// The length field in pseudo_header is ignored, and should be set to 0.
+    pub fn encode(&self, pseudo_header: ipv6::PseudoHeader) -> Vec<u8> {
+        let mut options = self.options.clone();
+        options.resize(options.len().div_ceil(4) * 4, 0u8);
+        let data_offset = (20 + options.len()) / 4;
+
+        let mut buffer = encode!(
+            self.src_port,
+            self.dest_port,
+            self.seq,
+            self.ack,
+            (data_offset as u8) << 4,
+            self.flags,
+            self.window,
+            0u16, // Checksum
+            self.urgent,
+            options,
+            self.payload,
+        );
+
+        let checksum = ipv6::PseudoHeader {
+            length: buffer.len() as u32,
+            ..pseudo_header
+        }
+        .checksum(ipv4::ProtocolNumber::Tcp, &buffer);
+        byteorder::NetworkEndian::write_u16(&mut buffer[16..18], checksum);
+
+        buffer
+    }
+}
+
+pub fn segment(input: &[u8], pseudo_header: ipv6::PseudoHeader) -> AHResult<Segment> {
+    let checksum = ipv6::PseudoHeader {
+        length: input.len() as u32,
+        ..pseudo_header
+    }
+    .checksum(ipv4::ProtocolNumber::Tcp, input);
+
+    if checksum != 0x0000 {
+        bail!("tcp checksum invalid: {:x}", checksum);
+    }
+
+    try_parse!(
+        input,
+        {
+            let (input, src_port) = be_u16(input)?;
+            let (input, dest_port) = be_u16(input)?;
+            let (input, seq) = be_u32(input)?;
+            let (input, ack) = be_u32(input)?;
+            let (input, data_offset) = verify(be_u8, |o| *o >> 4 >= 5)(input)?;
+            let (input, flags) = be_u8(input)?;
+            let (input, window) = be_u16(input)?;
+            // ignore checksum
+            let (input, _) = be_u16(input)?;
+            let (input, urgent) = be_u16(input)?;
+            let (input, options) = take(((data_offset >> 4) as usize - 5) * 4)(input)?;
+            let (input, payload) = rest(input)?;
+
+            Ok((
+                input,
+                Segment {
+                    src_port,
+                    dest_port,
+                    seq,
+                    ack,
+                    flags: flags & 0x3f,
+                    window,
+                    urgent,
+                    options: options.to_vec(),
+                    payload: payload.to_vec(),
+                },
+            ))
+        },
+        "parsing tcp segment failed: {}"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_header() -> ipv6::PseudoHeader {
+        ipv6::PseudoHeader {
+            src: "fe80::1".parse().unwrap(),
+            dest: "fe80::2".parse().unwrap(),
+            length: 0,
+        }
+    }
+
+    #[test]
+    fn segment_round_trips() {
+        let syn = Segment {
+            src_port: 49152,
+            dest_port: 23,
+            seq: 0x01020304,
+            flags: Segment::SYN,
+            window: 65535,
+            options: vec![2, 4, 5, 0xa0],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            segment(&syn.encode(pseudo_header()), pseudo_header()).unwrap(),
+            syn
+        );
+    }
+
+    #[test]
+    fn segment_with_bad_checksum_fails_to_decode() {
+        let mut encoded = Segment {
+            src_port: 49152,
+            dest_port: 23,
+            payload: b"hi".to_vec(),
+            ..Default::default()
+        }
+        .encode(pseudo_header());
+        encoded[21] ^= 0xff;
+
+        assert!(segment(&encoded, pseudo_header()).is_err());
+    }
+
+    #[test]
+    fn options_are_found_past_nops() {
+        let segment = Segment {
+            options: vec![2, 4, 5, 0xb4, 1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+
+        assert_eq!(segment.option(OPTION_MSS), Some(&[5, 0xb4][..]));
+        assert_eq!(
+            segment.option(OPTION_TIMESTAMPS),
+            Some(&[0, 0, 0, 1, 0, 0, 0, 0][..])
+        );
+        assert_eq!(segment.option(OPTION_WINDOW_SCALE), None);
+    }
+
+    #[test]
+    fn options_parse_and_encode() {
+        let options = vec![
+            TcpOption::Mss(1440),
+            TcpOption::WindowScale(7),
+            TcpOption::SackPermitted,
+            TcpOption::Sack(vec![(1000, 2000), (3000, 4000)]),
+            TcpOption::Timestamps { value: 7, echo: 0 },
+            TcpOption::Other {
+                kind: 30,
+                data: vec![0, 1],
+            },
+        ];
+        let mut encoded = Vec::new();
+        for option in &options {
+            option.encode(&mut encoded);
+        }
+        let segment = Segment {
+            options: encoded,
+            ..Default::default()
+        };
+
+        assert_eq!(segment.parsed_options(), options);
+        assert_eq!(segment.option(OPTION_MSS), Some(&[0x05, 0xa0][..]));
+
+        // A window scale with the wrong length isn't one.
+        let odd = Segment {
+            options: vec![3, 4, 7, 0],
+            ..Default::default()
+        };
+        assert_eq!(
+            odd.parsed_options(),
+            [TcpOption::Other {
+                kind: 3,
+                data: vec![7, 0]
+            }]
+        );
+    }
+
+    #[test]
+    fn seq_len_counts_syn_and_fin() {
+        let segment = Segment {
+            flags: Segment::SYN | Segment::FIN,
+            payload: b"abc".to_vec(),
+            ..Default::default()
+        };
+
+        assert_eq!(segment.seq_len(), 5);
+    }
+}
@@ -0,0 +1,575 @@
+//! Tunnels to a single remote endpoint, each showing up locally as another `ether::Server` to run
+//! protocols on: GRE and VXLAN over the IPv6 stack carrying Ethernet frames, and 6in4 and IPIP
+//! over IPv4 carrying bare packets, which are framed on the way in and unframed on the way out.
+
+use anyhow::{anyhow, bail, Result as AHResult};
+use crossbeam::channel;
+use nom::{
+    bytes::complete::take,
+    combinator::{cond, map, rest, verify},
+    number::complete::{be_u16, be_u32, be_u8},
+};
+use std::sync::Arc;
+use std::thread;
+
+use super::encdec::flag;
+use super::utils::{Backpressure, KeyedDispatcher, RecvSenderMap};
+use super::{arp, ether, ipv4, ipv6, udp};
+use crate::{encode, flags, try_parse, warn};
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc2784
+// Ref: https://datatracker.ietf.org/doc/html/rfc2890
+
+/// GRE protocol type for Ethernet frames.
+// Ref: https://datatracker.ietf.org/doc/html/rfc1701#section-3.3
+pub const TRANSPARENT_ETHERNET_BRIDGING: u16 = 0x6558;
+
+/// A GRE packet, carrying either an Ethernet frame or an IP packet depending on `protocol`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrePacket {
+    /// An ethertype, or `TRANSPARENT_ETHERNET_BRIDGING`.
+    pub protocol: u16,
+    pub key: Option<u32>,
+    pub sequence: Option<u32>,
+    pub payload: Vec<u8>,
+}
+
+impl GrePacket {
+    pub fn encode(&self) -> Vec<u8> {
+        let flags = flags!(u16, self.key.is_some() => 13, self.sequence.is_some() => 12);
+        let mut result = encode!(flags, self.protocol);
+
+        for field in self.key.iter().chain(&self.sequence) {
+            result.extend_from_slice(&field.to_be_bytes());
+        }
+        result.extend_from_slice(&self.payload);
+
+        result
+    }
+}
+
+pub fn gre_packet(input: &[u8]) -> AHResult<GrePacket> {
+    try_parse!(
+        input,
+        {
+            // Version 0 only; PPTP's enhanced GRE is version 1.
+            let (input, flags) = verify(be_u16, |f| f & 0x7 == 0)(input)?;
+            let (input, protocol) = be_u16(input)?;
+            // Checksum and reserved; the IPv6 layer has already checked the packet arrived intact.
+            let (input, _) = cond(flag(flags, 15), take(4usize))(input)?;
+            let (input, key) = cond(flag(flags, 13), be_u32)(input)?;
+            let (input, sequence) = cond(flag(flags, 12), be_u32)(input)?;
+            let (input, payload) = rest(input)?;
+
+            Ok((
+                input,
+                GrePacket {
+                    protocol,
+                    key,
+                    sequence,
+                    payload: payload.to_vec(),
+                },
+            ))
+        },
+        "parsing gre packet failed: {}"
+    )
+}
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc7348#section-5
+
+pub const VXLAN_PORT: u16 = 4789;
+const VXLAN_VALID_VNI: u8 = 0x08;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VxlanPacket {
+    /// 24-bit network identifier.
+    pub vni: u32,
+    pub frame: Vec<u8>,
+}
+
+impl VxlanPacket {
+    pub fn encode(&self) -> Vec<u8> {
+        encode!(VXLAN_VALID_VNI, [0u8; 3], self.vni << 8, &self.frame[..],)
+    }
+}
+
+pub fn vxlan_packet(input: &[u8]) -> AHResult<VxlanPacket> {
+    try_parse!(
+        input,
+        {
+            let (input, _) = verify(be_u8, |f| f & VXLAN_VALID_VNI != 0)(input)?;
+            let (input, _) = take(3usize)(input)?;
+            let (input, vni) = map(be_u32, |v| v >> 8)(input)?;
+            let (input, frame) = rest(input)?;
+
+            Ok((
+                input,
+                VxlanPacket {
+                    vni,
+                    frame: frame.to_vec(),
+                },
+            ))
+        },
+        "parsing vxlan packet failed: {}"
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encapsulation {
+    Gre {
+        key: Option<u32>,
+    },
+    Vxlan {
+        vni: u32,
+        port: u16,
+    },
+    /// IPv6 packets directly over IPv4, as tunnel brokers hand out.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc4213#section-3.5
+    SixInFour,
+    /// IPv4 packets directly over IPv4.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc2003
+    Ipip,
+}
+
+impl Encapsulation {
+    /// For encapsulations over IPv4, the protocol number of the packets carried and the ethertype
+    /// they're framed with locally.
+    fn carried(&self) -> Option<(ipv4::ProtocolNumber, ether::Type)> {
+        match self {
+            Encapsulation::SixInFour => Some((ipv4::ProtocolNumber::Ipv6, ether::Type::Ipv6)),
+            Encapsulation::Ipip => Some((ipv4::ProtocolNumber::Ipv4, ether::Type::Ipv4)),
+            Encapsulation::Gre { .. } | Encapsulation::Vxlan { .. } => None,
+        }
+    }
+
+    pub fn is_over_ipv4(&self) -> bool {
+        self.carried().is_some()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Tunnel {
+    /// Outer addresses, IPv4-mapped for encapsulations over IPv4.
+    pub local: ipv6::Address,
+    pub remote: ipv6::Address,
+    pub encapsulation: Encapsulation,
+}
+
+impl Tunnel {
+    /// The payload to carry `frame` in, if the tunnel can carry it at all.
+    fn encapsulate(&self, frame: &ether::Frame) -> Option<Vec<u8>> {
+        Some(match self.encapsulation {
+            Encapsulation::Gre { key } => GrePacket {
+                protocol: TRANSPARENT_ETHERNET_BRIDGING,
+                key,
+                sequence: None,
+                payload: frame.encode(),
+            }
+            .encode(),
+            Encapsulation::Vxlan { vni, .. } => VxlanPacket {
+                vni,
+                frame: frame.encode(),
+            }
+            .encode(),
+            Encapsulation::SixInFour | Encapsulation::Ipip => {
+                let (_, ethertype) = self.encapsulation.carried()?;
+                if frame.ethertype != ethertype {
+                    return None;
+                }
+
+                frame.payload.clone()
+            }
+        })
+    }
+
+    /// The frame carried by a packet's payload, if it belongs to this tunnel; bare packets are
+    /// framed as sent to `hw_address`.
+    fn decapsulate(
+        &self,
+        payload: &[u8],
+        hw_address: ether::Address,
+    ) -> AHResult<Option<ether::Frame>> {
+        let frame = match self.encapsulation {
+            Encapsulation::Gre { key } => {
+                let packet = gre_packet(payload)?;
+                if packet.key != key || packet.protocol != TRANSPARENT_ETHERNET_BRIDGING {
+                    return Ok(None);
+                }
+
+                packet.payload
+            }
+            Encapsulation::Vxlan { vni, .. } => {
+                let packet = vxlan_packet(payload)?;
+                if packet.vni != vni {
+                    return Ok(None);
+                }
+
+                packet.frame
+            }
+            Encapsulation::SixInFour | Encapsulation::Ipip => {
+                let (_, ethertype) = self.encapsulation.carried().unwrap();
+
+                return Ok(Some(ether::Frame {
+                    dest: hw_address,
+                    src: ether::Address([0; 6]),
+                    ethertype,
+                    payload: payload.to_vec(),
+                    received: None,
+                }));
+            }
+        };
+
+        Ok(Some(ether::frame(&frame)?))
+    }
+}
+
+/// The stacks of the interface tunnels run over.
+pub struct Underlay<'a, E: ether::Server> {
+    pub eth: &'a mut E,
+    /// Answering for the local address of tunnels over IPv4, which can't be set up without it.
+    pub arp_server: Option<&'a arp::Server>,
+    pub ipv6_server: &'a mut ipv6::Server,
+    pub udp_server: &'a udp::Server,
+    /// Of IPv4 packets sent, there being no IPv4 stack to keep it.
+    pub ttl: u8,
+}
+
+/// How encapsulated packets get to and from the underlay.
+enum Outer {
+    Gre {
+        sender: channel::Sender<ipv6::Packet>,
+        receiver: channel::Receiver<ipv6::Packet>,
+        hop_limit: ipv6::HopLimitHandle,
+    },
+    Vxlan(udp::Socket),
+    /// IPv4 packets are exchanged in frames directly, as there's no IPv4 stack; the remote
+    /// must be on-link.
+    Ipv4 {
+        sender: channel::Sender<ether::Frame>,
+        receiver: channel::Receiver<ether::Frame>,
+        ether_address: ether::Address,
+        arp_server: arp::Server,
+        ttl: u8,
+    },
+}
+
+/// Virtual interface exchanging frames through a tunnel.
+pub struct TunnelInterface {
+    hw_address: ether::Address,
+    tunnel: Tunnel,
+    recv_map: Arc<RecvSenderMap<ether::Frame>>,
+    outer: Arc<Outer>,
+}
+
+impl TunnelInterface {
+    /// Set up a tunnel; VXLAN tunnels bind their UDP port here, so each needs a different one.
+    pub fn new(
+        hw_address: ether::Address,
+        tunnel: Tunnel,
+        underlay: &mut Underlay<impl ether::Server>,
+    ) -> AHResult<Self> {
+        let outer = match tunnel.encapsulation {
+            Encapsulation::Gre { .. } => {
+                let (sender, receiver) = channel::bounded(1024);
+                underlay.ipv6_server.register_with_backpressure(
+                    ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Gre),
+                    sender,
+                    Backpressure::DropOldest(receiver.clone()),
+                );
+
+                Outer::Gre {
+                    sender: underlay.ipv6_server.writer(),
+                    receiver,
+                    hop_limit: underlay.ipv6_server.hop_limit(),
+                }
+            }
+            Encapsulation::Vxlan { vni, port } => {
+                if vni >= 1 << 24 {
+                    bail!("vxlan vni {} doesn't fit in 24 bits", vni);
+                }
+
+                Outer::Vxlan(underlay.udp_server.bind(port)?)
+            }
+            Encapsulation::SixInFour | Encapsulation::Ipip => {
+                if tunnel.local.to_ipv4_mapped().is_none()
+                    || tunnel.remote.to_ipv4_mapped().is_none()
+                {
+                    bail!("tunnels over ipv4 need ipv4 endpoints");
+                }
+                let arp_server = underlay
+                    .arp_server
+                    .ok_or_else(|| anyhow!("tunnels over ipv4 need an ipv4 address to answer for"))?
+                    .clone();
+
+                let (sender, receiver) = channel::bounded(1024);
+                underlay.eth.register_with_backpressure(
+                    ether::Type::Ipv4,
+                    sender,
+                    Backpressure::DropOldest(receiver.clone()),
+                );
+
+                Outer::Ipv4 {
+                    sender: underlay.eth.writer(),
+                    receiver,
+                    ether_address: underlay.eth.if_hwaddr()?,
+                    arp_server,
+                    ttl: underlay.ttl,
+                }
+            }
+        };
+
+        Ok(Self {
+            hw_address,
+            tunnel,
+            recv_map: Arc::new(RecvSenderMap::new("tunnel")),
+            outer: Arc::new(outer),
+        })
+    }
+
+    pub fn start(&self) {
+        let hw_address = self.hw_address;
+        let tunnel = self.tunnel;
+        let recv_map = Arc::clone(&self.recv_map);
+        let outer = Arc::clone(&self.outer);
+
+        thread::spawn(move || loop {
+            let (src, dest, payload) = match &*outer {
+                Outer::Gre { receiver, .. } => {
+                    let packet = receiver.recv().unwrap();
+                    (packet.src, packet.dest, packet.payload)
+                }
+                Outer::Vxlan(socket) => {
+                    let datagram = socket.receiver().recv().unwrap();
+                    (datagram.src, datagram.dest, datagram.packet.payload)
+                }
+                Outer::Ipv4 { receiver, .. } => {
+                    // Packets that don't parse can't be for the tunnel either.
+                    let packet = match ipv4::packet(&receiver.recv().unwrap().payload) {
+                        Ok(packet) => packet,
+                        Err(_) => continue,
+                    };
+                    if tunnel.encapsulation.carried().map(|(protocol, _)| protocol)
+                        != Some(packet.protocol)
+                    {
+                        continue;
+                    }
+
+                    (
+                        packet.src.to_ipv6_mapped(),
+                        packet.dest.to_ipv6_mapped(),
+                        packet.payload,
+                    )
+                }
+            };
+
+            if src != tunnel.remote || dest != tunnel.local {
+                continue;
+            }
+
+            match tunnel.decapsulate(&payload, hw_address) {
+                Ok(Some(frame)) => {
+                    if let Err(e) = recv_map.dispatch(frame) {
+                        warn!("tunnel to {}: {}", tunnel.remote, e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("tunnel to {}: {}", tunnel.remote, e),
+            }
+        });
+    }
+}
+
+impl KeyedDispatcher for TunnelInterface {
+    type Item = ether::Frame;
+
+    fn recv_map(&self) -> &RecvSenderMap<ether::Frame> {
+        &self.recv_map
+    }
+}
+
+impl ether::Server for TunnelInterface {
+    fn if_hwaddr(&self) -> AHResult<ether::Address> {
+        Ok(self.hw_address)
+    }
+
+    fn writer(&self) -> channel::Sender<ether::Frame> {
+        let tunnel = self.tunnel;
+        let outer = Arc::clone(&self.outer);
+        let (sender, receiver) = channel::bounded::<ether::Frame>(1024);
+
+        thread::spawn(move || {
+            for frame in receiver {
+                let payload = match tunnel.encapsulate(&frame) {
+                    Some(payload) => payload,
+                    None => continue,
+                };
+
+                let result = match &*outer {
+                    Outer::Gre {
+                        sender, hop_limit, ..
+                    } => sender
+                        .send(
+                            ipv6::Packet::builder()
+                                .protocol(ipv4::ProtocolNumber::Gre)
+                                .hop_limit(hop_limit.get())
+                                .src(tunnel.local)
+                                .dest(tunnel.remote)
+                                .payload(payload)
+                                .build(),
+                        )
+                        .map_err(|e| anyhow!("{}", e)),
+                    Outer::Vxlan(socket) => {
+                        let port = match tunnel.encapsulation {
+                            Encapsulation::Vxlan { port, .. } => port,
+                            _ => unreachable!(),
+                        };
+
+                        socket.send_to(tunnel.local, tunnel.remote, port, payload)
+                    }
+                    Outer::Ipv4 {
+                        sender,
+                        ether_address,
+                        arp_server,
+                        ttl,
+                        ..
+                    } => {
+                        let (protocol, _) = tunnel.encapsulation.carried().unwrap();
+                        let remote = tunnel.remote.to_ipv4_mapped().unwrap();
+
+                        // Waits for the remote to be resolved, which fails once it's given up on.
+                        match arp_server.resolve(remote).recv() {
+                            Ok(dest) => sender
+                                .send(ether::Frame {
+                                    dest,
+                                    src: *ether_address,
+                                    ethertype: ether::Type::Ipv4,
+                                    payload: ipv4::Packet {
+                                        tos: 0,
+                                        ttl: *ttl,
+                                        protocol,
+                                        src: tunnel.local.to_ipv4_mapped().unwrap(),
+                                        dest: remote,
+                                        payload,
+                                    }
+                                    .encode(),
+                                    received: None,
+                                })
+                                .map_err(|e| anyhow!("{}", e)),
+                            Err(_) => Err(anyhow!("no link-layer address for {}", remote)),
+                        }
+                    }
+                };
+
+                if let Err(e) = result {
+                    warn!("tunnel to {}: {}", tunnel.remote, e);
+                }
+            }
+        });
+
+        sender
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gre_round_trips() {
+        let packet = GrePacket {
+            protocol: TRANSPARENT_ETHERNET_BRIDGING,
+            key: Some(42),
+            sequence: None,
+            payload: vec![1, 2, 3],
+        };
+        let encoded = packet.encode();
+
+        assert_eq!(encoded, hex::decode("200065580000002a010203").unwrap());
+        assert_eq!(gre_packet(&encoded).unwrap(), packet);
+        // Checksums are skipped over.
+        assert_eq!(
+            gre_packet(&hex::decode("800086dd12340000ff").unwrap())
+                .unwrap()
+                .payload,
+            vec![0xff]
+        );
+    }
+
+    #[test]
+    fn vxlan_round_trips() {
+        let packet = VxlanPacket {
+            vni: 0x123456,
+            frame: vec![1, 2, 3],
+        };
+        let encoded = packet.encode();
+
+        assert_eq!(encoded, hex::decode("0800000012345600010203").unwrap());
+        assert_eq!(vxlan_packet(&encoded).unwrap(), packet);
+        assert!(vxlan_packet(&hex::decode("0000000012345600").unwrap()).is_err());
+    }
+
+    #[test]
+    fn tunnels_only_take_their_own_traffic() {
+        let tunnel = Tunnel {
+            local: "2001:db8::1".parse().unwrap(),
+            remote: "2001:db8::2".parse().unwrap(),
+            encapsulation: Encapsulation::Vxlan {
+                vni: 7,
+                port: VXLAN_PORT,
+            },
+        };
+        let frame = ether::Frame {
+            dest: ether::Address::BROADCAST,
+            src: ether::Address([2, 0, 0, 0, 0, 1]),
+            ethertype: ether::Type::WakeOnLan,
+            payload: vec![0; 46],
+            received: None,
+        };
+
+        let encapsulated = tunnel.encapsulate(&frame).unwrap();
+        assert_eq!(
+            tunnel.decapsulate(&encapsulated, frame.dest).unwrap(),
+            Some(frame.clone())
+        );
+
+        let other = Tunnel {
+            encapsulation: Encapsulation::Vxlan {
+                vni: 8,
+                port: VXLAN_PORT,
+            },
+            ..tunnel
+        };
+        assert_eq!(other.decapsulate(&encapsulated, frame.dest).unwrap(), None);
+    }
+
+    #[test]
+    fn six_in_four_carries_bare_ipv6_packets() {
+        let tunnel = Tunnel {
+            local: ipv4::Address([192, 0, 2, 1]).to_ipv6_mapped(),
+            remote: ipv4::Address([192, 0, 2, 2]).to_ipv6_mapped(),
+            encapsulation: Encapsulation::SixInFour,
+        };
+        let hw_address = ether::Address([2, 0, 0, 0, 0, 1]);
+        let packet = ipv6::Packet::builder()
+            .protocol(ipv4::ProtocolNumber::Udp)
+            .hop_limit(64)
+            .src("2001:db8::2".parse().unwrap())
+            .dest("2001:db8::1".parse().unwrap())
+            .build()
+            .encode();
+
+        let frame = tunnel.decapsulate(&packet, hw_address).unwrap().unwrap();
+        assert_eq!(frame.dest, hw_address);
+        assert_eq!(frame.ethertype, ether::Type::Ipv6);
+        assert_eq!(tunnel.encapsulate(&frame), Some(packet));
+
+        // Only IPv6 fits in the tunnel.
+        assert_eq!(
+            tunnel.encapsulate(&ether::Frame {
+                ethertype: ether::Type::Arp,
+                ..frame
+            }),
+            None
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! TWAMP test packets, in unauthenticated mode.
+//!
+//! Ref: https://datatracker.ietf.org/doc/html/rfc5357
+
+use anyhow::Result as AHResult;
+use nom::{
+    combinator::{map, rest},
+    number::complete::{be_u16, be_u32, be_u64, be_u8},
+};
+
+use crate::{encode, packet_layout, try_parse};
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc5357#section-4.1.2
+packet_layout! {
+    #[derive(Debug, PartialEq)]
+    pub struct TestPacket {
+        pub seq: u32 => be_u32,
+        pub timestamp: u64 => be_u64,
+        pub error_estimate: u16 => be_u16,
+        pub padding: Vec<u8> => map(rest, <[u8]>::to_vec),
+    }
+}
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc5357#section-4.2.1
+packet_layout! {
+    #[derive(Debug, PartialEq)]
+    pub struct ReflectedPacket {
+        pub seq: u32 => be_u32,
+        pub timestamp: u64 => be_u64,
+        pub error_estimate: u16 => be_u16,
+        reserved 0u16 => be_u16,
+        pub receive_timestamp: u64 => be_u64,
+        pub sender_seq: u32 => be_u32,
+        pub sender_timestamp: u64 => be_u64,
+        pub sender_error_estimate: u16 => be_u16,
+        reserved 0u16 => be_u16,
+        pub sender_ttl: u8 => be_u8,
+        pub padding: Vec<u8> => map(rest, <[u8]>::to_vec),
+    }
+}
+
+/// Length of a reflected packet without padding.
+pub const REFLECTED_LEN: usize = 41;
+
+impl TestPacket {
+    pub fn encode(&self) -> Vec<u8> {
+        encode!(self)
+    }
+}
+
+impl ReflectedPacket {
+    pub fn encode(&self) -> Vec<u8> {
+        encode!(self)
+    }
+}
+
+pub fn test_packet(input: &[u8]) -> AHResult<TestPacket> {
+    try_parse!(
+        input,
+        { TestPacket::parse(input) },
+        "parsing twamp test packet failed: {}"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_decodes_with_padding() {
+        assert_eq!(
+            test_packet(&hex::decode("000000070000000100000002800100").unwrap()).unwrap(),
+            TestPacket {
+                seq: 7,
+                timestamp: 0x100000002,
+                error_estimate: 0x8001,
+                padding: vec![0],
+            }
+        );
+        assert!(test_packet(&[0; 13]).is_err());
+    }
+}
@@ -1,31 +1,573 @@
-use anyhow::Result as AHResult;
+use anyhow::{anyhow, bail, Result as AHResult};
+use byteorder::ByteOrder;
 use crossbeam::channel;
-use std::thread;
+use nom::{bytes::complete::take, number::complete::be_u16};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
-use super::utils::KeyedDispatcher;
+use super::conntrack::{FlowKey, FlowTable};
+use super::port_policy::{PortPolicy, UnboundPort};
+use super::tcp::{Direction, Endpoint};
+use super::utils::{Backpressure, KeyedDispatcher, WakerSlot, WakingSender};
 use super::{ipv4, ipv6};
+use crate::executor::{Actor, Executor};
+use crate::{encode, supervisor, try_parse, warn};
 
+// Ref: https://datatracker.ietf.org/doc/html/rfc768
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    pub src_port: u16,
+    pub dest_port: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Encode this packet.
+    ///
+    /// The length field in pseudo_header is ignored, and should be set to 0.
+    pub fn encode(&self, pseudo_header: ipv6::PseudoHeader) -> Vec<u8> {
+        let length = (8 + self.payload.len()) as u16;
+
+        let mut buffer = encode!(
+            self.src_port,
+            self.dest_port,
+            length,
+            0u16, // Checksum
+            self.payload,
+        );
+
+        let checksum = ipv6::PseudoHeader {
+            length: length as u32,
+            ..pseudo_header
+        }
+        .checksum(ipv4::ProtocolNumber::Udp, &buffer);
+
+        // RFC 8200 § 8.1: a computed checksum of zero is transmitted as all ones
+        let checksum = if checksum == 0 { 0xffff } else { checksum };
+        byteorder::NetworkEndian::write_u16(&mut buffer[6..8], checksum);
+
+        buffer
+    }
+}
+
+/// Parse a UDP packet.
+///
+/// The length field in pseudo_header is ignored, and should be set to 0.
+pub fn packet(input: &[u8], pseudo_header: ipv6::PseudoHeader) -> AHResult<Packet> {
+    let checksum = ipv6::PseudoHeader {
+        length: input.len() as u32,
+        ..pseudo_header
+    }
+    .checksum(ipv4::ProtocolNumber::Udp, input);
+
+    if checksum != 0x0000 {
+        bail!("udp checksum invalid: {:x}", checksum);
+    }
+
+    try_parse!(
+        input,
+        {
+            let (input, src_port) = be_u16(input)?;
+            let (input, dest_port) = be_u16(input)?;
+            let (input, length) = be_u16(input)?;
+            let (input, _checksum) = be_u16(input)?;
+            let (input, payload) = take(length.saturating_sub(8))(input)?;
+
+            Ok((
+                input,
+                Packet {
+                    src_port,
+                    dest_port,
+                    payload: payload.to_vec(),
+                },
+            ))
+        },
+        "parsing udp packet failed: {}"
+    )
+}
+
+/// Handle for sending datagrams through the stack.
+#[derive(Clone)]
+pub struct Sender {
+    pub(super) ipv6_sender: channel::Sender<ipv6::Packet>,
+    pub(super) flow_table: Option<FlowTable>,
+    pub(super) hop_limit: ipv6::HopLimitHandle,
+    pub(super) dscp: u8,
+}
+
+fn flow_key(
+    local: ipv6::Address,
+    local_port: u16,
+    remote: ipv6::Address,
+    remote_port: u16,
+) -> FlowKey {
+    FlowKey {
+        protocol: ipv4::ProtocolNumber::Udp,
+        local: Endpoint {
+            address: local,
+            port: local_port,
+        },
+        remote: Endpoint {
+            address: remote,
+            port: remote_port,
+        },
+    }
+}
+
+impl Sender {
+    pub fn send(&self, src: ipv6::Address, dest: ipv6::Address, packet: &Packet) -> AHResult<()> {
+        if let Some(flow_table) = &self.flow_table {
+            flow_table.record(
+                flow_key(src, packet.src_port, dest, packet.dest_port),
+                Direction::Outbound,
+                packet.payload.len(),
+                Instant::now(),
+            );
+        }
+
+        let payload = packet.encode(ipv6::PseudoHeader {
+            src,
+            dest,
+            length: 0,
+        });
+
+        self.ipv6_sender.send(
+            ipv6::Packet::builder()
+                .protocol(ipv4::ProtocolNumber::Udp)
+                .dscp(self.dscp)
+                .hop_limit(self.hop_limit.get())
+                .src(src)
+                .dest(dest)
+                .payload(payload)
+                .build(),
+        )?;
+
+        Ok(())
+    }
+}
+
+/// A datagram received on a bound port.
+#[derive(Debug, PartialEq)]
+pub struct Datagram {
+    pub src: ipv6::Address,
+    pub dest: ipv6::Address,
+    /// What was left of the hop limit when it arrived.
+    pub hop_limit: u8,
+    pub packet: Packet,
+}
+
+/// Bindings by port, each with the prefix it's limited to, if any.
+type Bindings = Arc<RwLock<HashMap<u16, (Option<ipv6::Prefix>, WakingSender<Datagram>)>>>;
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc6335#section-6
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// A bound port, which is released when dropped.
+pub struct Socket {
+    port: u16,
+    receiver: channel::Receiver<Datagram>,
+    waker: WakerSlot,
+    sender: Sender,
+    bindings: Bindings,
+}
+
+impl Socket {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn receiver(&self) -> &channel::Receiver<Datagram> {
+        &self.receiver
+    }
+
+    /// Where to leave the task waiting on `receiver()`, to wake when a datagram arrives.
+    pub fn waker(&self) -> &WakerSlot {
+        &self.waker
+    }
+
+    pub fn send_to(
+        &self,
+        src: ipv6::Address,
+        dest: ipv6::Address,
+        dest_port: u16,
+        payload: Vec<u8>,
+    ) -> AHResult<()> {
+        self.sender.send(
+            src,
+            dest,
+            &Packet {
+                src_port: self.port,
+                dest_port,
+                payload,
+            },
+        )
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.bindings.write().unwrap().remove(&self.port);
+    }
+}
+
+#[derive(Clone)]
 pub struct Server {
     ipv6_receiver: channel::Receiver<ipv6::Packet>,
+    ipv6_sender: channel::Sender<ipv6::Packet>,
+    port_policy: PortPolicy,
+    bindings: Bindings,
+    flow_table: Option<FlowTable>,
+    hop_limit: ipv6::HopLimitHandle,
+    /// DSCP to mark datagrams from each port with, where it isn't 0.
+    dscp: HashMap<u16, u8>,
 }
 
 impl Server {
     pub fn new(ipv6_server: &mut ipv6::Server) -> AHResult<Self> {
         let (ipv6_sender, ipv6_receiver) = channel::bounded(1024);
 
-        ipv6_server.register(
+        ipv6_server.register_with_backpressure(
             ipv6::NextHeader::Protocol(ipv4::ProtocolNumber::Udp),
             ipv6_sender,
+            Backpressure::DropOldest(ipv6_receiver.clone()),
         );
 
-        Ok(Self { ipv6_receiver })
+        Ok(Self {
+            ipv6_receiver,
+            ipv6_sender: ipv6_server.writer(),
+            port_policy: PortPolicy::default(),
+            bindings: Arc::new(RwLock::new(HashMap::new())),
+            flow_table: None,
+            hop_limit: ipv6_server.hop_limit(),
+            dscp: HashMap::new(),
+        })
     }
 
-    pub fn start(&self) {
-        let ipv6_receiver = self.ipv6_receiver.clone();
+    pub fn set_port_policy(&mut self, port_policy: PortPolicy) {
+        self.port_policy = port_policy;
+    }
 
-        thread::spawn(move || loop {
-            let _packet = ipv6_receiver.recv().unwrap();
-        });
+    /// Record flows in `flow_table`; must be set before any ports are bound.
+    pub fn set_flow_table(&mut self, flow_table: FlowTable) {
+        self.flow_table = Some(flow_table);
+    }
+
+    /// Mark datagrams sent from `port` with `dscp`; must be set before any ports are bound.
+    pub fn set_port_dscp(&mut self, port: u16, dscp: u8) {
+        self.dscp.insert(port, dscp);
+    }
+
+    pub fn sender(&self) -> Sender {
+        Sender {
+            ipv6_sender: self.ipv6_sender.clone(),
+            flow_table: self.flow_table.clone(),
+            hop_limit: self.hop_limit.clone(),
+            dscp: 0,
+        }
+    }
+
+    /// Receive datagrams sent to `port`.
+    pub fn bind(&self, port: u16) -> AHResult<Socket> {
+        self.bind_on(port, None)
+    }
+
+    /// Receive datagrams sent to `port` on any address in `prefix`, whether or not it's one of
+    /// ours, like a server behind an anycast or virtual address.
+    pub fn bind_prefix(&self, port: u16, prefix: ipv6::Prefix) -> AHResult<Socket> {
+        self.bind_on(port, Some(prefix))
+    }
+
+    fn bind_on(&self, port: u16, prefix: Option<ipv6::Prefix>) -> AHResult<Socket> {
+        let (sender, receiver) = channel::bounded(1024);
+        let waker = WakerSlot::default();
+
+        let mut bindings = self.bindings.write().unwrap();
+        if bindings.contains_key(&port) {
+            bail!("udp port {} is already bound", port);
+        }
+        bindings.insert(port, (prefix, WakingSender::new(sender, waker.clone())));
+
+        Ok(Socket {
+            port,
+            receiver,
+            waker,
+            sender: Sender {
+                dscp: self.dscp.get(&port).copied().unwrap_or(0),
+                ..self.sender()
+            },
+            bindings: self.bindings.clone(),
+        })
+    }
+
+    /// Bind a random free port from the ephemeral range, e.g. for the client side of a
+    /// request/response exchange.
+    pub fn bind_ephemeral(&self) -> AHResult<Socket> {
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..100 {
+            if let Ok(socket) = self.bind(rng.gen_range(EPHEMERAL_PORTS)) {
+                return Ok(socket);
+            }
+        }
+
+        Err(anyhow!("no free ephemeral udp ports"))
+    }
+
+    fn process_packet(&self, ipv6_packet: ipv6::Packet) -> AHResult<()> {
+        let udp_packet = packet(
+            &ipv6_packet.payload,
+            ipv6::PseudoHeader {
+                src: ipv6_packet.src,
+                dest: ipv6_packet.dest,
+                length: 0,
+            },
+        )?;
+
+        if let Some((_, sender)) = self
+            .bindings
+            .read()
+            .unwrap()
+            .get(&udp_packet.dest_port)
+            .filter(|(prefix, _)| prefix.is_none_or(|p| p.contains(ipv6_packet.dest)))
+        {
+            if let Some(flow_table) = &self.flow_table {
+                flow_table.record(
+                    flow_key(
+                        ipv6_packet.dest,
+                        udp_packet.dest_port,
+                        ipv6_packet.src,
+                        udp_packet.src_port,
+                    ),
+                    Direction::Inbound,
+                    udp_packet.payload.len(),
+                    Instant::now(),
+                );
+            }
+
+            // A full socket drops datagrams, like a full receive buffer would.
+            let _ = sender.try_send(Datagram {
+                src: ipv6_packet.src,
+                dest: ipv6_packet.dest,
+                hop_limit: ipv6_packet.hop_limit,
+                packet: udp_packet,
+            });
+
+            return Ok(());
+        }
+
+        if self.port_policy.get(udp_packet.dest_port) == UnboundPort::Closed {
+            if let Some(error) = ipv6::port_unreachable(&ipv6_packet, self.hop_limit.get()) {
+                self.ipv6_sender.send(error)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn start(&self, executor: &Executor) {
+        executor.start("udp", "udp", self.ipv6_receiver.clone(), self.clone());
+    }
+}
+
+impl Actor for Server {
+    type Message = ipv6::Packet;
+
+    fn handle(&mut self, packet: ipv6::Packet) {
+        supervisor::handling(&packet.payload);
+        if let Err(e) = self.process_packet(packet) {
+            warn!("udp: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hexstring(s: &str) -> Vec<u8> {
+        hex::decode(s).unwrap()
+    }
+
+    fn pseudo_header() -> ipv6::PseudoHeader {
+        ipv6::PseudoHeader {
+            src: "fe80::1".parse().unwrap(),
+            dest: "fe80::2".parse().unwrap(),
+            length: 0,
+        }
+    }
+
+    #[test]
+    fn packet_encodes() {
+        assert_eq!(
+            Packet {
+                src_port: 1234,
+                dest_port: 53,
+                payload: b"abc".to_vec(),
+            }
+            .encode(pseudo_header()),
+            hexstring("04d20035000b396a616263"),
+        );
+    }
+
+    #[test]
+    fn packet_round_trips() {
+        let original = Packet {
+            src_port: 5353,
+            dest_port: 5353,
+            payload: b"odd length payload".to_vec(),
+        };
+
+        assert_eq!(
+            packet(&original.encode(pseudo_header()), pseudo_header()).unwrap(),
+            original
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "checksum")]
+    fn packet_with_invalid_checksum_fails_to_decode() {
+        packet(&hexstring("04d20035000b1111616263"), pseudo_header()).unwrap();
+    }
+
+    #[test]
+    fn unbound_ports_follow_policy() {
+        let (ipv6_sender, sent) = channel::unbounded();
+        let server = Server {
+            ipv6_receiver: channel::never(),
+            ipv6_sender,
+            port_policy: PortPolicy {
+                filtered: vec![161],
+                ..Default::default()
+            },
+            bindings: Arc::new(RwLock::new(HashMap::new())),
+            flow_table: None,
+            hop_limit: ipv6::HopLimitHandle::new(64),
+            dscp: HashMap::new(),
+        };
+
+        let probe = |dest_port| {
+            ipv6::Packet::builder()
+                .protocol(ipv4::ProtocolNumber::Udp)
+                .hop_limit(64)
+                .src(pseudo_header().src)
+                .dest(pseudo_header().dest)
+                .payload(
+                    Packet {
+                        src_port: 40000,
+                        dest_port,
+                        payload: vec![],
+                    }
+                    .encode(pseudo_header()),
+                )
+                .build()
+        };
+
+        server.process_packet(probe(161)).unwrap();
+        assert!(sent.try_recv().is_err());
+
+        server.process_packet(probe(53)).unwrap();
+        let error = sent.try_recv().unwrap();
+        assert_eq!(error.dest, pseudo_header().src);
+        assert_eq!(error.payload[..2], [1, 4]);
+    }
+
+    #[test]
+    fn bound_ports_receive_datagrams() {
+        let (ipv6_sender, sent) = channel::unbounded();
+        let server = Server {
+            ipv6_receiver: channel::never(),
+            ipv6_sender,
+            port_policy: PortPolicy::default(),
+            bindings: Arc::new(RwLock::new(HashMap::new())),
+            flow_table: None,
+            hop_limit: ipv6::HopLimitHandle::new(64),
+            dscp: HashMap::new(),
+        };
+        let socket = server.bind(5353).unwrap();
+        assert!(server.bind(5353).is_err());
+
+        let datagram = Packet {
+            src_port: 40000,
+            dest_port: 5353,
+            payload: b"query".to_vec(),
+        };
+        server
+            .process_packet(
+                ipv6::Packet::builder()
+                    .protocol(ipv4::ProtocolNumber::Udp)
+                    .hop_limit(64)
+                    .src(pseudo_header().src)
+                    .dest(pseudo_header().dest)
+                    .payload(datagram.encode(pseudo_header()))
+                    .build(),
+            )
+            .unwrap();
+
+        assert_eq!(socket.receiver().try_recv().unwrap().packet, datagram);
+        assert!(sent.try_recv().is_err());
+
+        drop(socket);
+        assert!(server.bind(5353).is_ok());
+    }
+
+    #[test]
+    fn prefix_bindings_receive_datagrams_to_any_address_in_them() {
+        let (ipv6_sender, sent) = channel::unbounded();
+        let server = Server {
+            ipv6_receiver: channel::never(),
+            ipv6_sender,
+            port_policy: PortPolicy::default(),
+            bindings: Arc::new(RwLock::new(HashMap::new())),
+            flow_table: None,
+            hop_limit: ipv6::HopLimitHandle::new(64),
+            dscp: HashMap::new(),
+        };
+        let socket = server
+            .bind_prefix(53, "fd00:53::/64".parse().unwrap())
+            .unwrap();
+
+        let send_to = |dest: &str| {
+            let pseudo_header = ipv6::PseudoHeader {
+                dest: dest.parse().unwrap(),
+                ..pseudo_header()
+            };
+            server
+                .process_packet(
+                    ipv6::Packet::builder()
+                        .protocol(ipv4::ProtocolNumber::Udp)
+                        .hop_limit(64)
+                        .src(pseudo_header.src)
+                        .dest(pseudo_header.dest)
+                        .payload(
+                            Packet {
+                                src_port: 40000,
+                                dest_port: 53,
+                                payload: b"query".to_vec(),
+                            }
+                            .encode(pseudo_header),
+                        )
+                        .build(),
+                )
+                .unwrap();
+        };
+
+        send_to("fd00:53::1");
+        send_to("fd00:53::abcd");
+        assert_eq!(
+            socket.receiver().try_recv().unwrap().dest.to_string(),
+            "fd00:53::1"
+        );
+        assert_eq!(
+            socket.receiver().try_recv().unwrap().dest.to_string(),
+            "fd00:53::abcd"
+        );
+
+        // Outside the prefix, the port is as good as unbound.
+        send_to("fd00:54::1");
+        assert!(socket.receiver().try_recv().is_err());
+        assert_eq!(sent.try_recv().unwrap().payload[..2], [1, 4]);
     }
 }
@@ -1,9 +1,13 @@
-use anyhow::{anyhow, Result as AHResult};
+use anyhow::{anyhow, bail, Result as AHResult};
 use crossbeam::channel;
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::Waker;
 
-pub trait DispatchKeyed: Send + Sync + std::fmt::Debug
+use crate::{resources, stats, warn};
+
+pub trait DispatchKeyed: Clone + Send + Sync + std::fmt::Debug
 where
     Self::Key: std::fmt::Display + Eq + std::hash::Hash + Sync + Send,
 {
@@ -12,30 +16,202 @@ where
     fn dispatch_key(&self) -> Self::Key;
 }
 
-pub struct RecvSenderMap<T: DispatchKeyed>(
-    RwLock<HashMap<<T as DispatchKeyed>::Key, channel::Sender<T>>>,
-);
+/// What to do with items that no receiver is registered for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnregisteredPolicy {
+    /// Silently throw them away, without counting them.
+    Drop,
+    Log,
+    /// Silently count them; see `RecvSenderMap::unregistered_count`.
+    Count,
+    Error,
+}
+
+/// What to do when a receiver's channel is full.
+#[derive(Clone)]
+pub enum Backpressure<T> {
+    /// Wait for the receiver to catch up, stalling the dispatcher.
+    Block,
+    /// Throw away the item being dispatched.
+    DropNewest,
+    /// Make room by throwing away the item at the front of the channel, using the given receiving
+    /// end.
+    DropOldest(channel::Receiver<T>),
+}
+
+#[derive(Clone)]
+struct Subscription<T> {
+    sender: channel::Sender<T>,
+    backpressure: Backpressure<T>,
+}
+
+impl<T> Subscription<T> {
+    /// Send according to the backpressure policy, returning whether an item had to be dropped.
+    fn send(&self, item: T) -> Result<bool, channel::SendError<T>> {
+        match &self.backpressure {
+            Backpressure::Block => self.sender.send(item).map(|_| false),
+            Backpressure::DropNewest => match self.sender.try_send(item) {
+                Ok(()) => Ok(false),
+                Err(channel::TrySendError::Full(_)) => Ok(true),
+                Err(channel::TrySendError::Disconnected(item)) => Err(channel::SendError(item)),
+            },
+            Backpressure::DropOldest(receiver) => {
+                let mut item = item;
+                let mut dropped = false;
 
-impl<T: DispatchKeyed + Send + Sync + std::fmt::Debug> RecvSenderMap<T> {
-    pub fn new() -> Self {
-        Self(RwLock::new(HashMap::new()))
+                loop {
+                    match self.sender.try_send(item) {
+                        Ok(()) => return Ok(dropped),
+                        Err(channel::TrySendError::Full(returned)) => {
+                            item = returned;
+                            dropped |= receiver.try_recv().is_ok();
+                        }
+                        Err(channel::TrySendError::Disconnected(item)) => {
+                            return Err(channel::SendError(item))
+                        }
+                    }
+                }
+            }
+        }
     }
+}
+
+pub struct RecvSenderMap<T: DispatchKeyed> {
+    name: &'static str,
+    senders: RwLock<HashMap<<T as DispatchKeyed>::Key, Vec<Subscription<T>>>>,
+    default_sender: RwLock<Option<channel::Sender<T>>>,
+    unregistered_policy: RwLock<UnregisteredPolicy>,
+    unregistered_count: AtomicU64,
+}
 
+impl<T: DispatchKeyed> RecvSenderMap<T> {
+    /// Create a map; `name` identifies it in status.
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            senders: RwLock::new(HashMap::new()),
+            default_sender: RwLock::new(None),
+            unregistered_policy: RwLock::new(UnregisteredPolicy::Log),
+            unregistered_count: AtomicU64::new(0),
+        }
+    }
+
+    /// Send an item to every receiver registered for its key.
+    ///
+    /// Receivers that have gone away are unregistered.
     pub fn dispatch(&self, item: T) -> AHResult<()> {
         let key = item.dispatch_key();
-        if let Some(ref sender) = &self.0.write().unwrap().get(&key) {
-            sender
+        // Sent to without the map locked, so a receiver that's blocking dispatch only holds up
+        // items for its own key.
+        let subscriptions = self.senders.read().unwrap().get(&key).cloned();
+
+        if let Some(subscriptions) = subscriptions {
+            let mut disconnected = Vec::new();
+            for subscription in subscriptions {
+                match subscription.send(item.clone()) {
+                    Ok(true) => self.record_drop(&key),
+                    Ok(false) => {}
+                    Err(_) => disconnected.push(subscription.sender),
+                }
+            }
+
+            if disconnected.is_empty() {
+                return Ok(());
+            }
+
+            let mut senders = self.senders.write().unwrap();
+            if let Some(key_senders) = senders.get_mut(&key) {
+                key_senders.retain(|subscription| {
+                    let gone = disconnected
+                        .iter()
+                        .any(|sender| sender.same_channel(&subscription.sender));
+                    if gone {
+                        resources::CHANNELS.remove(1);
+                    }
+                    !gone
+                });
+
+                if !key_senders.is_empty() {
+                    return Ok(());
+                }
+                senders.remove(&key);
+            }
+        }
+
+        if let Some(sender) = &*self.default_sender.read().unwrap() {
+            return sender
                 .send(item)
-                .map_err(|_| anyhow!("failed to send to {}", key))?;
-        } else {
-            println!("WARN: no receiver for {} ({:?})", key, item,);
-        };
+                .map_err(|_| anyhow!("failed to send {} to default receiver", key));
+        }
+
+        let policy = *self.unregistered_policy.read().unwrap();
+        if policy != UnregisteredPolicy::Drop {
+            self.unregistered_count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match policy {
+            UnregisteredPolicy::Drop | UnregisteredPolicy::Count => {}
+            UnregisteredPolicy::Log => warn!("no receiver for {} ({:?})", key, item),
+            UnregisteredPolicy::Error => bail!("no receiver for {}", key),
+        }
 
         Ok(())
     }
 
+    /// Add a receiver for `key`, alongside any that are already registered.
+    ///
+    /// Dispatch blocks while the receiver's channel is full.
     pub fn register(&self, key: <T as DispatchKeyed>::Key, sender: channel::Sender<T>) {
-        self.0.write().unwrap().insert(key, sender.clone());
+        self.register_with_backpressure(key, sender, Backpressure::Block);
+    }
+
+    pub fn register_with_backpressure(
+        &self,
+        key: <T as DispatchKeyed>::Key,
+        sender: channel::Sender<T>,
+        backpressure: Backpressure<T>,
+    ) {
+        self.senders
+            .write()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .push(Subscription {
+                sender,
+                backpressure,
+            });
+        resources::CHANNELS.add(1);
+    }
+
+    fn record_drop(&self, key: &<T as DispatchKeyed>::Key) {
+        stats::DISPATCH_DROPS.increment(&format!("{}/{}", self.name, key));
+    }
+
+    /// Whether items for `key` go anywhere: to a registered receiver or the default one.
+    pub fn handles(&self, key: &<T as DispatchKeyed>::Key) -> bool {
+        self.senders.read().unwrap().contains_key(key)
+            || self.default_sender.read().unwrap().is_some()
+    }
+
+    /// Set a receiver for items that no other receiver is registered for.
+    pub fn set_default(&self, sender: channel::Sender<T>) {
+        *self.default_sender.write().unwrap() = Some(sender);
+    }
+
+    pub fn set_unregistered_policy(&self, policy: UnregisteredPolicy) {
+        *self.unregistered_policy.write().unwrap() = policy;
+    }
+
+    /// Number of items dispatched with no receiver, unless the policy was to drop them.
+    pub fn unregistered_count(&self) -> u64 {
+        self.unregistered_count.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: DispatchKeyed> Drop for RecvSenderMap<T> {
+    fn drop(&mut self) {
+        let senders = self.senders.get_mut().unwrap_or_else(|e| e.into_inner());
+        resources::CHANNELS.remove(senders.values().map(Vec::len).sum());
     }
 }
 
@@ -54,4 +230,214 @@ where
     ) {
         self.recv_map().register(key, sender);
     }
+
+    fn register_with_backpressure(
+        &mut self,
+        key: <Self::Item as DispatchKeyed>::Key,
+        sender: channel::Sender<Self::Item>,
+        backpressure: Backpressure<Self::Item>,
+    ) {
+        self.recv_map()
+            .register_with_backpressure(key, sender, backpressure);
+    }
+}
+
+/// The task waiting on a channel, if any, for whatever delivers to it to wake.
+#[derive(Clone, Default)]
+pub struct WakerSlot(Arc<Mutex<Option<Waker>>>);
+
+impl WakerSlot {
+    /// Wake `waker` next, instead of whichever was set before.
+    pub fn set(&self, waker: &Waker) {
+        let mut slot = self.0.lock().unwrap();
+        if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+            *slot = Some(waker.clone());
+        }
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().take();
+    }
+
+    pub fn wake(&self) {
+        if let Some(waker) = self.0.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A sender that wakes its receiver's task after each delivery, and once it's dropped, so the
+/// task sees the disconnection.
+pub struct WakingSender<T> {
+    sender: channel::Sender<T>,
+    waker: WakerSlot,
+}
+
+impl<T> WakingSender<T> {
+    pub fn new(sender: channel::Sender<T>, waker: WakerSlot) -> Self {
+        Self { sender, waker }
+    }
+
+    pub fn send(&self, item: T) -> Result<(), channel::SendError<T>> {
+        self.sender.send(item)?;
+        self.waker.wake();
+        Ok(())
+    }
+
+    pub fn try_send(&self, item: T) -> Result<(), channel::TrySendError<T>> {
+        self.sender.try_send(item)?;
+        self.waker.wake();
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.sender.len()
+    }
+}
+
+impl<T> Drop for WakingSender<T> {
+    fn drop(&mut self) {
+        self.waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Item(u8);
+
+    impl DispatchKeyed for Item {
+        type Key = u8;
+
+        fn dispatch_key(&self) -> u8 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn dispatch_fans_out_to_all_receivers() {
+        let map = RecvSenderMap::new("test");
+        let (sender_a, receiver_a) = channel::unbounded();
+        let (sender_b, receiver_b) = channel::unbounded();
+        map.register(1, sender_a);
+        map.register(1, sender_b);
+
+        map.dispatch(Item(1)).unwrap();
+
+        assert_eq!(receiver_a.try_recv(), Ok(Item(1)));
+        assert_eq!(receiver_b.try_recv(), Ok(Item(1)));
+    }
+
+    #[test]
+    fn dispatch_drops_disconnected_receivers() {
+        let map = RecvSenderMap::new("test");
+        let (sender_a, receiver_a) = channel::unbounded();
+        let (sender_b, receiver_b) = channel::unbounded();
+        map.register(1, sender_a);
+        map.register(1, sender_b);
+        drop(receiver_a);
+
+        map.dispatch(Item(1)).unwrap();
+        drop(receiver_b);
+
+        map.set_unregistered_policy(UnregisteredPolicy::Count);
+        map.dispatch(Item(1)).unwrap();
+        assert_eq!(map.unregistered_count(), 1);
+    }
+
+    #[test]
+    fn unregistered_items_go_to_default() {
+        let map = RecvSenderMap::new("test");
+        let (sender, receiver) = channel::unbounded();
+        map.set_default(sender);
+
+        map.dispatch(Item(2)).unwrap();
+
+        assert_eq!(receiver.try_recv(), Ok(Item(2)));
+        assert_eq!(map.unregistered_count(), 0);
+    }
+
+    #[test]
+    fn unregistered_policy_drop_does_not_count() {
+        let map = RecvSenderMap::<Item>::new("test");
+        map.set_unregistered_policy(UnregisteredPolicy::Drop);
+        map.dispatch(Item(2)).unwrap();
+        assert_eq!(map.unregistered_count(), 0);
+
+        map.set_unregistered_policy(UnregisteredPolicy::Count);
+        map.dispatch(Item(2)).unwrap();
+        assert_eq!(map.unregistered_count(), 1);
+    }
+
+    #[test]
+    fn unregistered_policy_error_fails_dispatch() {
+        let map = RecvSenderMap::<Item>::new("test");
+        map.set_unregistered_policy(UnregisteredPolicy::Error);
+
+        assert!(map.dispatch(Item(2)).is_err());
+    }
+
+    #[test]
+    fn drop_newest_keeps_queued_items() {
+        let map = RecvSenderMap::new("drop_newest");
+        let (sender, receiver) = channel::bounded(1);
+        map.register_with_backpressure(1, sender, Backpressure::DropNewest);
+
+        map.dispatch(Item(1)).unwrap();
+        map.dispatch(Item(1)).unwrap();
+
+        assert_eq!(receiver.len(), 1);
+        assert_eq!(status::snapshot().dispatch_drops["drop_newest/1"], 1);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Numbered(u8, u32);
+
+        impl DispatchKeyed for Numbered {
+            type Key = u8;
+
+            fn dispatch_key(&self) -> u8 {
+                self.0
+            }
+        }
+
+        let map = RecvSenderMap::new("test");
+        let (sender, receiver) = channel::bounded(2);
+        map.register_with_backpressure(2, sender, Backpressure::DropOldest(receiver.clone()));
+
+        for i in 0..5 {
+            map.dispatch(Numbered(2, i)).unwrap();
+        }
+
+        assert_eq!(receiver.try_recv(), Ok(Numbered(2, 3)));
+        assert_eq!(receiver.try_recv(), Ok(Numbered(2, 4)));
+    }
+
+    #[test]
+    fn blocked_receivers_only_hold_up_their_own_key() {
+        let map = std::sync::Arc::new(RecvSenderMap::new("test"));
+        let (blocked_sender, _blocked) = channel::bounded(0);
+        map.register(1, blocked_sender);
+        let (sender, receiver) = channel::bounded(1);
+        map.register_with_backpressure(2, sender, Backpressure::DropOldest(receiver.clone()));
+
+        {
+            let map = map.clone();
+            std::thread::spawn(move || map.dispatch(Item(1)));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let (done_sender, done) = channel::bounded(1);
+        std::thread::spawn(move || done_sender.send(map.dispatch(Item(2)).is_ok()));
+        assert_eq!(
+            done.recv_timeout(std::time::Duration::from_secs(5)),
+            Ok(true)
+        );
+        assert_eq!(receiver.try_recv(), Ok(Item(2)));
+    }
 }
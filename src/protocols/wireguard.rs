@@ -0,0 +1,735 @@
+//! WireGuard handshake responder, for testing how initiators handle peers that answer, don't
+//! answer, are under load or answer wrongly. Handshakes can complete, but no data is passed.
+
+use anyhow::{anyhow, bail, Result as AHResult};
+use blake2::digest::{consts::U16, FixedOutput, KeyInit, Update};
+use blake2::{Blake2s256, Blake2sMac, Digest};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use nom::{
+    bytes::complete::take,
+    combinator::{eof, map, map_res},
+    number::complete::{be_u8, le_u32},
+    sequence::terminated,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::thread;
+use std::time::{Duration, Instant};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::encdec::BIResult;
+use super::udp;
+use crate::{encode, proto_enum_with_unknown, status, try_parse, warn};
+
+// Ref: https://www.wireguard.com/papers/wireguard.pdf, section 5.4
+
+pub const PORT: u16 = 51820;
+
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+const LABEL_MAC1: &[u8] = b"mac1----";
+const LABEL_COOKIE: &[u8] = b"cookie--";
+const COOKIE_SECRET_LIFETIME: Duration = Duration::from_secs(120);
+
+pub type Key = [u8; 32];
+
+proto_enum_with_unknown!(MessageType, u8, {
+    HandshakeInitiation = 1,
+    HandshakeResponse = 2,
+    CookieReply = 3,
+    TransportData = 4,
+});
+
+fn hash(parts: &[&[u8]]) -> Key {
+    let mut hasher = Blake2s256::new();
+    for part in parts {
+        Digest::update(&mut hasher, part);
+    }
+
+    hasher.finalize().into()
+}
+
+fn mac(key: &[u8], input: &[u8]) -> [u8; 16] {
+    let mut mac = <Blake2sMac<U16> as KeyInit>::new_from_slice(key).unwrap();
+    Update::update(&mut mac, input);
+
+    mac.finalize_fixed().into()
+}
+
+fn hmac(key: &[u8], input: &[u8]) -> Key {
+    const BLOCK_LEN: usize = 64;
+
+    let mut inner_key = [0x36; BLOCK_LEN];
+    let mut outer_key = [0x5c; BLOCK_LEN];
+    for (i, byte) in key.iter().enumerate() {
+        inner_key[i] ^= byte;
+        outer_key[i] ^= byte;
+    }
+
+    hash(&[&outer_key, &hash(&[&inner_key, input])])
+}
+
+fn kdf<const N: usize>(key: &Key, input: &[u8]) -> [Key; N] {
+    let prk = hmac(key, input);
+    let mut result = [[0; 32]; N];
+    let mut previous = Vec::new();
+
+    for (i, output) in result.iter_mut().enumerate() {
+        previous.push(i as u8 + 1);
+        *output = hmac(&prk, &previous);
+        previous = output.to_vec();
+    }
+
+    result
+}
+
+fn aead_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+
+    nonce
+}
+
+fn seal(key: &Key, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(
+            &aead_nonce(0).into(),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .unwrap()
+}
+
+fn open(key: &Key, ciphertext: &[u8], aad: &[u8]) -> AHResult<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(
+            &aead_nonce(0).into(),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| anyhow!("decryption failed"))
+}
+
+fn dh(private: &StaticSecret, public: &[u8; 32]) -> Key {
+    private.diffie_hellman(&PublicKey::from(*public)).to_bytes()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    HandshakeInitiation {
+        sender_index: u32,
+        ephemeral: Key,
+        encrypted_static: [u8; 48],
+        encrypted_timestamp: [u8; 28],
+        mac1: [u8; 16],
+        mac2: [u8; 16],
+    },
+    HandshakeResponse {
+        sender_index: u32,
+        receiver_index: u32,
+        ephemeral: Key,
+        encrypted_nothing: [u8; 16],
+        mac1: [u8; 16],
+        mac2: [u8; 16],
+    },
+    CookieReply {
+        receiver_index: u32,
+        nonce: [u8; 24],
+        encrypted_cookie: [u8; 32],
+    },
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Message::HandshakeInitiation {
+                sender_index,
+                ephemeral,
+                encrypted_static,
+                encrypted_timestamp,
+                mac1,
+                mac2,
+            } => encode!(
+                MessageType::HandshakeInitiation,
+                [0u8; 3],
+                sender_index.to_le_bytes(),
+                ephemeral,
+                encrypted_static,
+                encrypted_timestamp,
+                mac1,
+                mac2,
+            ),
+            Message::HandshakeResponse {
+                sender_index,
+                receiver_index,
+                ephemeral,
+                encrypted_nothing,
+                mac1,
+                mac2,
+            } => encode!(
+                MessageType::HandshakeResponse,
+                [0u8; 3],
+                sender_index.to_le_bytes(),
+                receiver_index.to_le_bytes(),
+                ephemeral,
+                encrypted_nothing,
+                mac1,
+                mac2,
+            ),
+            Message::CookieReply {
+                receiver_index,
+                nonce,
+                encrypted_cookie,
+            } => encode!(
+                MessageType::CookieReply,
+                [0u8; 3],
+                receiver_index.to_le_bytes(),
+                nonce,
+                encrypted_cookie,
+            ),
+        }
+    }
+}
+
+fn array<const N: usize>(input: &[u8]) -> BIResult<'_, [u8; N]> {
+    map(take(N), |b: &[u8]| b.try_into().unwrap())(input)
+}
+
+pub fn message(input: &[u8]) -> AHResult<Message> {
+    try_parse!(
+        input,
+        {
+            let (input, message_type) = map_res(be_u8, MessageType::try_from)(input)?;
+            let (input, _) = take(3usize)(input)?;
+
+            match message_type {
+                MessageType::HandshakeInitiation => {
+                    let (input, sender_index) = le_u32(input)?;
+                    let (input, ephemeral) = array(input)?;
+                    let (input, encrypted_static) = array(input)?;
+                    let (input, encrypted_timestamp) = array(input)?;
+                    let (input, mac1) = array(input)?;
+                    let (input, mac2) = terminated(array, eof)(input)?;
+
+                    Ok((
+                        input,
+                        Message::HandshakeInitiation {
+                            sender_index,
+                            ephemeral,
+                            encrypted_static,
+                            encrypted_timestamp,
+                            mac1,
+                            mac2,
+                        },
+                    ))
+                }
+                MessageType::HandshakeResponse => {
+                    let (input, sender_index) = le_u32(input)?;
+                    let (input, receiver_index) = le_u32(input)?;
+                    let (input, ephemeral) = array(input)?;
+                    let (input, encrypted_nothing) = array(input)?;
+                    let (input, mac1) = array(input)?;
+                    let (input, mac2) = terminated(array, eof)(input)?;
+
+                    Ok((
+                        input,
+                        Message::HandshakeResponse {
+                            sender_index,
+                            receiver_index,
+                            ephemeral,
+                            encrypted_nothing,
+                            mac1,
+                            mac2,
+                        },
+                    ))
+                }
+                MessageType::CookieReply => {
+                    let (input, receiver_index) = le_u32(input)?;
+                    let (input, nonce) = array(input)?;
+                    let (input, encrypted_cookie) = terminated(array, eof)(input)?;
+
+                    Ok((
+                        input,
+                        Message::CookieReply {
+                            receiver_index,
+                            nonce,
+                            encrypted_cookie,
+                        },
+                    ))
+                }
+                _ => Err(nom::Err::Failure(nom::error::Error::new(
+                    input,
+                    nom::error::ErrorKind::Switch,
+                ))),
+            }
+        },
+        "parsing wireguard message failed: {}"
+    )
+}
+
+/// Fill in a message's mac1 and mac2, which cover everything before them.
+fn add_macs(encoded: &mut [u8], receiver_public: &Key, cookie: Option<&[u8; 16]>) {
+    let len = encoded.len();
+
+    let mac1 = mac(&hash(&[LABEL_MAC1, receiver_public]), &encoded[..len - 32]);
+    encoded[len - 32..len - 16].copy_from_slice(&mac1);
+
+    let mac2 = cookie.map_or([0; 16], |cookie| mac(cookie, &encoded[..len - 16]));
+    encoded[len - 16..].copy_from_slice(&mac2);
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Behavior {
+    #[default]
+    Respond,
+    /// Never answer, like a peer that's down.
+    Ignore,
+    /// Answer initiations with cookie replies until they come back with a valid mac2.
+    UnderLoad,
+    /// Answer with responses that fail to authenticate.
+    CorruptResponse,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub private_key: Key,
+    /// All zeroes when not in use.
+    pub preshared_key: Key,
+    /// Initiators' public keys; any are accepted when empty.
+    pub peers: Vec<Key>,
+    pub behavior: Behavior,
+}
+
+/// Handshake state for the responder side, turning messages received into replies.
+pub struct Responder {
+    config: Config,
+    private_key: StaticSecret,
+    public_key: Key,
+    /// Latest timestamp from each peer, so replayed initiations are ignored.
+    timestamps: HashMap<Key, [u8; 12]>,
+    cookie_secret: Key,
+    cookie_secret_born: Instant,
+    next_index: u32,
+}
+
+impl Responder {
+    pub fn new(config: Config) -> Self {
+        let private_key = StaticSecret::from(config.private_key);
+
+        Self {
+            public_key: PublicKey::from(&private_key).to_bytes(),
+            private_key,
+            config,
+            timestamps: HashMap::new(),
+            cookie_secret: rand::random(),
+            cookie_secret_born: Instant::now(),
+            next_index: rand::random(),
+        }
+    }
+
+    pub fn public_key(&self) -> Key {
+        self.public_key
+    }
+
+    /// Handle a message from `src`, the initiator's address and port as bytes, returning our reply.
+    pub fn handle(&mut self, input: &[u8], src: &[u8], now: Instant) -> AHResult<Option<Vec<u8>>> {
+        let (sender_index, ephemeral, encrypted_static, encrypted_timestamp, mac1, mac2) =
+            match message(input)? {
+                Message::HandshakeInitiation {
+                    sender_index,
+                    ephemeral,
+                    encrypted_static,
+                    encrypted_timestamp,
+                    mac1,
+                    mac2,
+                } => (
+                    sender_index,
+                    ephemeral,
+                    encrypted_static,
+                    encrypted_timestamp,
+                    mac1,
+                    mac2,
+                ),
+                _ => return Ok(None),
+            };
+
+        let len = input.len();
+        if mac1 != mac(&hash(&[LABEL_MAC1, &self.public_key]), &input[..len - 32]) {
+            bail!("initiation has a bad mac1");
+        }
+
+        match self.config.behavior {
+            Behavior::Ignore => return Ok(None),
+            Behavior::UnderLoad => {
+                let cookie = self.cookie(src, now);
+                if mac2 != mac(&cookie, &input[..len - 16]) {
+                    record(|s| s.cookie_replies += 1);
+
+                    return Ok(Some(self.cookie_reply(sender_index, &mac1, &cookie)));
+                }
+            }
+            _ => {}
+        }
+
+        match self.respond(
+            sender_index,
+            ephemeral,
+            encrypted_static,
+            encrypted_timestamp,
+        ) {
+            Ok(response) => {
+                record(|s| s.handshakes_completed += 1);
+                Ok(response)
+            }
+            Err(e) => {
+                record(|s| s.handshakes_failed += 1);
+                Err(e)
+            }
+        }
+    }
+
+    fn cookie(&mut self, src: &[u8], now: Instant) -> [u8; 16] {
+        if now.saturating_duration_since(self.cookie_secret_born) >= COOKIE_SECRET_LIFETIME {
+            self.cookie_secret = rand::random();
+            self.cookie_secret_born = now;
+        }
+
+        mac(&self.cookie_secret, src)
+    }
+
+    fn cookie_reply(&self, receiver_index: u32, mac1: &[u8; 16], cookie: &[u8; 16]) -> Vec<u8> {
+        let nonce: [u8; 24] = rand::random();
+        let encrypted_cookie =
+            XChaCha20Poly1305::new(&hash(&[LABEL_COOKIE, &self.public_key]).into())
+                .encrypt(
+                    &nonce.into(),
+                    Payload {
+                        msg: cookie,
+                        aad: mac1,
+                    },
+                )
+                .unwrap();
+
+        Message::CookieReply {
+            receiver_index,
+            nonce,
+            encrypted_cookie: encrypted_cookie.try_into().unwrap(),
+        }
+        .encode()
+    }
+
+    fn respond(
+        &mut self,
+        receiver_index: u32,
+        initiator_ephemeral: Key,
+        encrypted_static: [u8; 48],
+        encrypted_timestamp: [u8; 28],
+    ) -> AHResult<Option<Vec<u8>>> {
+        let mut chaining_key = hash(&[CONSTRUCTION]);
+        let mut h = hash(&[&hash(&[&chaining_key, IDENTIFIER]), &self.public_key]);
+
+        [chaining_key] = kdf(&chaining_key, &initiator_ephemeral);
+        h = hash(&[&h, &initiator_ephemeral]);
+        let [next_chaining_key, key] =
+            kdf(&chaining_key, &dh(&self.private_key, &initiator_ephemeral));
+        chaining_key = next_chaining_key;
+        let initiator_static: Key = open(&key, &encrypted_static, &h)?.try_into().unwrap();
+        h = hash(&[&h, &encrypted_static]);
+
+        if !self.config.peers.is_empty() && !self.config.peers.contains(&initiator_static) {
+            bail!(
+                "initiation from unknown peer {}",
+                base64::encode(initiator_static)
+            );
+        }
+
+        let [next_chaining_key, key] =
+            kdf(&chaining_key, &dh(&self.private_key, &initiator_static));
+        chaining_key = next_chaining_key;
+        let timestamp: [u8; 12] = open(&key, &encrypted_timestamp, &h)?.try_into().unwrap();
+        h = hash(&[&h, &encrypted_timestamp]);
+
+        // TAI64N timestamps compare correctly as big-endian bytes.
+        if let Some(last) = self.timestamps.get(&initiator_static) {
+            if timestamp <= *last {
+                bail!("replayed initiation");
+            }
+        }
+        self.timestamps.insert(initiator_static, timestamp);
+
+        let ephemeral_private = StaticSecret::from(rand::random::<Key>());
+        let ephemeral = PublicKey::from(&ephemeral_private).to_bytes();
+        [chaining_key] = kdf(&chaining_key, &ephemeral);
+        h = hash(&[&h, &ephemeral]);
+        [chaining_key] = kdf(&chaining_key, &dh(&ephemeral_private, &initiator_ephemeral));
+        [chaining_key] = kdf(&chaining_key, &dh(&ephemeral_private, &initiator_static));
+        let [_, tau, key] = kdf(&chaining_key, &self.config.preshared_key);
+        h = hash(&[&h, &tau]);
+        let mut encrypted_nothing: [u8; 16] = seal(&key, &[], &h).try_into().unwrap();
+
+        if self.config.behavior == Behavior::CorruptResponse {
+            encrypted_nothing[0] ^= 0x01;
+        }
+
+        self.next_index = self.next_index.wrapping_add(1);
+        let mut response = Message::HandshakeResponse {
+            sender_index: self.next_index,
+            receiver_index,
+            ephemeral,
+            encrypted_nothing,
+            mac1: [0; 16],
+            mac2: [0; 16],
+        }
+        .encode();
+        add_macs(&mut response, &initiator_static, None);
+
+        Ok(Some(response))
+    }
+}
+
+fn record(f: impl FnOnce(&mut status::WireGuardStatus)) {
+    status::update(|s| f(s.wireguard.get_or_insert_with(Default::default)));
+}
+
+pub struct Server {
+    socket: udp::Socket,
+    responder: Responder,
+}
+
+impl Server {
+    pub fn new(config: Config, udp_server: &udp::Server, port: u16) -> AHResult<Self> {
+        Ok(Self {
+            socket: udp_server.bind(port)?,
+            responder: Responder::new(config),
+        })
+    }
+
+    pub fn start(self) {
+        let Self {
+            socket,
+            mut responder,
+        } = self;
+
+        thread::spawn(move || loop {
+            let datagram = socket.receiver().recv().unwrap();
+            let src = encode!(datagram.src, datagram.packet.src_port);
+
+            match responder.handle(&datagram.packet.payload, &src, Instant::now()) {
+                Ok(Some(reply)) => {
+                    if let Err(e) =
+                        socket.send_to(datagram.dest, datagram.src, datagram.packet.src_port, reply)
+                    {
+                        warn!("wireguard: {}", e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("wireguard: {}", e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The initiator's side of the handshake, enough to check the responder's answers.
+    struct Initiator {
+        private_key: StaticSecret,
+        public_key: Key,
+        responder_public: Key,
+        ephemeral_private: StaticSecret,
+        chaining_key: Key,
+        h: Key,
+        mac1: [u8; 16],
+    }
+
+    impl Initiator {
+        fn new(responder_public: Key) -> Self {
+            let private_key = StaticSecret::from(rand::random::<Key>());
+
+            Self {
+                public_key: PublicKey::from(&private_key).to_bytes(),
+                private_key,
+                responder_public,
+                ephemeral_private: StaticSecret::from(rand::random::<Key>()),
+                chaining_key: [0; 32],
+                h: [0; 32],
+                mac1: [0; 16],
+            }
+        }
+
+        fn initiation(&mut self, timestamp: [u8; 12], cookie: Option<&[u8; 16]>) -> Vec<u8> {
+            let mut chaining_key = hash(&[CONSTRUCTION]);
+            let mut h = hash(&[&hash(&[&chaining_key, IDENTIFIER]), &self.responder_public]);
+
+            let ephemeral = PublicKey::from(&self.ephemeral_private).to_bytes();
+            [chaining_key] = kdf(&chaining_key, &ephemeral);
+            h = hash(&[&h, &ephemeral]);
+            let [next, key] = kdf(
+                &chaining_key,
+                &dh(&self.ephemeral_private, &self.responder_public),
+            );
+            chaining_key = next;
+            let encrypted_static = seal(&key, &self.public_key, &h);
+            h = hash(&[&h, &encrypted_static]);
+            let [next, key] = kdf(
+                &chaining_key,
+                &dh(&self.private_key, &self.responder_public),
+            );
+            chaining_key = next;
+            let encrypted_timestamp = seal(&key, &timestamp, &h);
+            h = hash(&[&h, &encrypted_timestamp]);
+
+            self.chaining_key = chaining_key;
+            self.h = h;
+
+            let mut initiation = Message::HandshakeInitiation {
+                sender_index: 7,
+                ephemeral,
+                encrypted_static: encrypted_static.try_into().unwrap(),
+                encrypted_timestamp: encrypted_timestamp.try_into().unwrap(),
+                mac1: [0; 16],
+                mac2: [0; 16],
+            }
+            .encode();
+            add_macs(&mut initiation, &self.responder_public, cookie);
+            self.mac1 = initiation[116..132].try_into().unwrap();
+
+            initiation
+        }
+
+        fn check_response(&self, response: &[u8]) -> AHResult<()> {
+            let (ephemeral, encrypted_nothing) = match message(response)? {
+                Message::HandshakeResponse {
+                    receiver_index: 7,
+                    ephemeral,
+                    encrypted_nothing,
+                    ..
+                } => (ephemeral, encrypted_nothing),
+                other => bail!("unexpected {:?}", other),
+            };
+
+            let [mut chaining_key] = kdf(&self.chaining_key, &ephemeral);
+            let h = hash(&[&self.h, &ephemeral]);
+            [chaining_key] = kdf(&chaining_key, &dh(&self.ephemeral_private, &ephemeral));
+            [chaining_key] = kdf(&chaining_key, &dh(&self.private_key, &ephemeral));
+            let [_, tau, key] = kdf(&chaining_key, &[0; 32]);
+            let h = hash(&[&h, &tau]);
+
+            open(&key, &encrypted_nothing, &h).map(|_| ())
+        }
+
+        fn open_cookie(&self, reply: &[u8]) -> [u8; 16] {
+            match message(reply).unwrap() {
+                Message::CookieReply {
+                    nonce,
+                    encrypted_cookie,
+                    ..
+                } => XChaCha20Poly1305::new(&hash(&[LABEL_COOKIE, &self.responder_public]).into())
+                    .decrypt(
+                        &nonce.into(),
+                        Payload {
+                            msg: &encrypted_cookie,
+                            aad: &self.mac1,
+                        },
+                    )
+                    .unwrap()
+                    .try_into()
+                    .unwrap(),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    fn responder(behavior: Behavior) -> Responder {
+        Responder::new(Config {
+            private_key: rand::random(),
+            preshared_key: [0; 32],
+            peers: vec![],
+            behavior,
+        })
+    }
+
+    fn timestamp(seconds: u8) -> [u8; 12] {
+        let mut result = [0; 12];
+        result[7] = seconds;
+
+        result
+    }
+
+    #[test]
+    fn kdf_matches_hmac_chain() {
+        let [a, b] = kdf(&[1; 32], b"input");
+        let prk = hmac(&[1; 32], b"input");
+
+        assert_eq!(a, hmac(&prk, &[1]));
+        assert_eq!(b, hmac(&prk, &[&a[..], &[2]].concat()));
+    }
+
+    #[test]
+    fn handshake_completes_once_per_timestamp() {
+        let now = Instant::now();
+        let mut responder = responder(Behavior::Respond);
+        let mut initiator = Initiator::new(responder.public_key());
+
+        let initiation = initiator.initiation(timestamp(1), None);
+        let response = responder.handle(&initiation, b"src", now).unwrap().unwrap();
+        initiator.check_response(&response).unwrap();
+
+        assert!(responder.handle(&initiation, b"src", now).is_err());
+
+        let mut bad_mac = initiator.initiation(timestamp(2), None);
+        bad_mac[120] ^= 1;
+        assert!(responder.handle(&bad_mac, b"src", now).is_err());
+    }
+
+    #[test]
+    fn misbehaviors_fail_handshakes() {
+        let now = Instant::now();
+
+        let mut responder = responder(Behavior::CorruptResponse);
+        let mut initiator = Initiator::new(responder.public_key());
+        let initiation = initiator.initiation(timestamp(1), None);
+        let response = responder.handle(&initiation, b"src", now).unwrap().unwrap();
+        assert!(initiator.check_response(&response).is_err());
+
+        let mut responder = self::responder(Behavior::Ignore);
+        let mut initiator = Initiator::new(responder.public_key());
+        let initiation = initiator.initiation(timestamp(1), None);
+        assert_eq!(responder.handle(&initiation, b"src", now).unwrap(), None);
+    }
+
+    #[test]
+    fn under_load_requires_cookie() {
+        let now = Instant::now();
+        let mut responder = responder(Behavior::UnderLoad);
+        let mut initiator = Initiator::new(responder.public_key());
+
+        let initiation = initiator.initiation(timestamp(1), None);
+        let reply = responder.handle(&initiation, b"src", now).unwrap().unwrap();
+        let cookie = initiator.open_cookie(&reply);
+
+        let initiation = initiator.initiation(timestamp(2), Some(&cookie));
+        let response = responder.handle(&initiation, b"src", now).unwrap().unwrap();
+        initiator.check_response(&response).unwrap();
+
+        // Cookies are tied to the initiator's address.
+        let initiation = initiator.initiation(timestamp(3), Some(&cookie));
+        let reply = responder
+            .handle(&initiation, b"other", now)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            message(&reply).unwrap(),
+            Message::CookieReply { .. }
+        ));
+    }
+}
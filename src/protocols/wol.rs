@@ -0,0 +1,213 @@
+use anyhow::Result as AHResult;
+use crossbeam::channel;
+use nom::{
+    bytes::complete::{tag, take},
+    combinator::{eof, opt, verify},
+    multi::count,
+    sequence::terminated,
+};
+
+use super::ether;
+use super::utils::Backpressure;
+use crate::executor::{Actor, Executor};
+use crate::{status, try_parse};
+
+// Ref: https://en.wikipedia.org/wiki/Wake-on-LAN#Magic_packet
+
+const SYNC_STREAM: [u8; 6] = [0xff; 6];
+const TARGET_REPETITIONS: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub struct Packet {
+    pub target: ether::Address,
+    pub password: Option<Vec<u8>>,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = SYNC_STREAM.to_vec();
+
+        for _ in 0..TARGET_REPETITIONS {
+            result.extend_from_slice(&self.target.0);
+        }
+
+        if let Some(ref password) = self.password {
+            result.extend_from_slice(password);
+        }
+
+        result
+    }
+
+    /// Build a broadcast magic frame, as sent with the dedicated Wake-on-LAN ethertype.
+    pub fn frame(&self, src: ether::Address) -> ether::Frame {
+        ether::Frame {
+            dest: ether::Address::BROADCAST,
+            src,
+            ethertype: ether::Type::WakeOnLan,
+            payload: self.encode(),
+            received: None,
+        }
+    }
+}
+
+pub fn packet(input: &[u8]) -> AHResult<Packet> {
+    try_parse!(
+        input,
+        {
+            let (input, _) = tag(&SYNC_STREAM[..])(input)?;
+            let (input, target) = ether::address(input)?;
+            let (input, _) = count(
+                verify(ether::address, |a| *a == target),
+                TARGET_REPETITIONS - 1,
+            )(input)?;
+            // SecureOn passwords are 4 or 6 bytes; anything after that is frame padding.
+            let (input, password) = opt(terminated(
+                take(6usize),
+                verify(eof, |_: &[u8]| input.len() == 6),
+            ))(input)?;
+            let (input, password) = match password {
+                Some(p) => (input, Some(p)),
+                None => opt(terminated(
+                    take(4usize),
+                    verify(eof, |_: &[u8]| input.len() == 4),
+                ))(input)?,
+            };
+
+            Ok((
+                input,
+                Packet {
+                    target,
+                    password: password.map(|p| p.to_vec()),
+                },
+            ))
+        },
+        "parsing wake-on-lan packet failed: {}"
+    )
+}
+
+pub struct Server {
+    receiver: channel::Receiver<ether::Frame>,
+    ether_address: ether::Address,
+}
+
+impl Server {
+    pub fn new(interface: &mut impl ether::Server) -> AHResult<Self> {
+        let (sender, receiver) = channel::bounded(1024);
+        interface.register_with_backpressure(
+            ether::Type::WakeOnLan,
+            sender,
+            Backpressure::DropOldest(receiver.clone()),
+        );
+
+        Ok(Self {
+            receiver,
+            ether_address: interface.if_hwaddr()?,
+        })
+    }
+
+    /// Start listening, returning a channel that receives a message for every magic packet
+    /// addressed to us.
+    pub fn start(&self, executor: &Executor) -> channel::Receiver<()> {
+        let (wake_sender, wake_receiver) = channel::unbounded();
+        let listener = Listener {
+            ether_address: self.ether_address,
+            wake_sender,
+            num_wakes: 0,
+        };
+        executor.start(
+            "wake-on-lan",
+            "wake-on-lan",
+            self.receiver.clone(),
+            listener,
+        );
+
+        wake_receiver
+    }
+}
+
+struct Listener {
+    ether_address: ether::Address,
+    wake_sender: channel::Sender<()>,
+    num_wakes: u64,
+}
+
+impl Actor for Listener {
+    type Message = ether::Frame;
+
+    fn handle(&mut self, frame: ether::Frame) {
+        match packet(&frame.payload) {
+            Ok(packet) if packet.target == self.ether_address => {
+                self.num_wakes += 1;
+
+                let num_wakes = self.num_wakes;
+                status::update(|s| {
+                    s.wake_on_lan = Some(status::WakeOnLanStatus {
+                        magic_packets: num_wakes,
+                        last_source: frame.src,
+                    })
+                });
+
+                // Nobody may be waiting for a wakeup anymore.
+                let _ = self.wake_sender.send(());
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn magic(target: &str, password: &str) -> Vec<u8> {
+        hex::decode(format!(
+            "ffffffffffff{}{}",
+            target.replace(':', "").repeat(16),
+            password
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn packet_decodes() {
+        assert_eq!(
+            packet(&magic("02:00:00:00:00:01", "")).unwrap(),
+            Packet {
+                target: "02:00:00:00:00:01".parse().unwrap(),
+                password: None,
+            }
+        );
+    }
+
+    #[test]
+    fn packet_with_password_decodes() {
+        assert_eq!(
+            packet(&magic("02:00:00:00:00:01", "01020304")).unwrap(),
+            Packet {
+                target: "02:00:00:00:00:01".parse().unwrap(),
+                password: Some(vec![1, 2, 3, 4]),
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "wake-on-lan")]
+    fn packet_with_mismatched_repetition_fails_to_decode() {
+        let mut input = magic("02:00:00:00:00:01", "");
+        input[50] = 0x42;
+
+        packet(&input).unwrap();
+    }
+
+    #[test]
+    fn packet_encodes() {
+        assert_eq!(
+            Packet {
+                target: "02:00:00:00:00:01".parse().unwrap(),
+                password: Some(vec![1, 2, 3, 4, 5, 6]),
+            }
+            .encode(),
+            magic("02:00:00:00:00:01", "010203040506")
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Accounting of what the node has made, channels, timers, threads and memory, against limits, so
+//! a flood that piles them up shows in status before it takes the process down.
+
+use anyhow::{anyhow, Context, Result as AHResult};
+use serde::Deserialize;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::status::{self, ResourceStatus};
+use crate::warn;
+
+/// How often usage is checked.
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Exit status when exiting after a limit is exceeded, so a supervisor can tell why.
+pub const EXIT_STATUS: i32 = 71;
+
+/// A count of live things, kept up by whatever makes and drops them.
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, n: usize) {
+        self.0.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn remove(&self, n: usize) {
+        self.0.fetch_sub(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Receivers registered with dispatchers.
+pub static CHANNELS: Gauge = Gauge::new();
+/// Entries waiting in delay queues.
+pub static TIMERS: Gauge = Gauge::new();
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    pub max_memory_mb: Option<u64>,
+    pub max_threads: Option<u64>,
+    pub max_channels: Option<u64>,
+    pub max_timers: Option<u64>,
+    /// Exit when a limit is exceeded, instead of only reporting it.
+    #[serde(default)]
+    pub exit_on_exceeded: bool,
+}
+
+impl Config {
+    /// Names of the limits `usage` is over.
+    fn exceeded(&self, usage: &ResourceStatus) -> Vec<String> {
+        [
+            ("memory", self.max_memory_mb, usage.memory_bytes / (1 << 20)),
+            ("threads", self.max_threads, usage.threads),
+            ("channels", self.max_channels, usage.channels),
+            ("timers", self.max_timers, usage.timers),
+        ]
+        .iter()
+        .filter(|(_, limit, used)| limit.is_some_and(|limit| *used > limit))
+        .map(|(name, _, _)| name.to_string())
+        .collect()
+    }
+}
+
+/// Resident memory in bytes and thread count, from the contents of `/proc/PID/status`.
+fn parse_proc_status(text: &str) -> AHResult<(u64, u64)> {
+    let field = |name: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.split_whitespace().next())
+            .ok_or_else(|| anyhow!("no {} field", name))?
+            .parse::<u64>()
+            .with_context(|| format!("parsing {} field", name))
+    };
+
+    Ok((field("VmRSS:")? * 1024, field("Threads:")?))
+}
+
+/// Resident memory in bytes and thread count of process `pid`.
+pub fn process_usage(pid: u32) -> AHResult<(u64, u64)> {
+    let path = format!("/proc/{}/status", pid);
+    parse_proc_status(&fs::read_to_string(&path).with_context(|| format!("reading {}", path))?)
+}
+
+/// What the node is using now.
+pub fn usage() -> AHResult<ResourceStatus> {
+    let (memory_bytes, threads) = process_usage(std::process::id())?;
+
+    Ok(ResourceStatus {
+        memory_bytes,
+        threads,
+        channels: CHANNELS.get(),
+        timers: TIMERS.get(),
+        exceeded: Vec::new(),
+    })
+}
+
+/// Check usage in the background, recording it in status and updating status when the limits
+/// exceeded change.
+pub fn start(config: Config) {
+    thread::spawn(move || {
+        let mut last: Vec<String> = Vec::new();
+
+        loop {
+            let mut usage = match usage() {
+                Ok(usage) => usage,
+                Err(e) => {
+                    warn!("resource accounting stopped: {:#}", e);
+                    return;
+                }
+            };
+            usage.exceeded = config.exceeded(&usage);
+
+            if usage.exceeded == last {
+                status::record(|s| s.resources = Some(usage));
+            } else {
+                for name in usage.exceeded.iter().filter(|name| !last.contains(name)) {
+                    warn!("over the {} limit", name);
+                }
+                last = usage.exceeded.clone();
+                status::update(|s| s.resources = Some(usage));
+
+                if !last.is_empty() && config.exit_on_exceeded {
+                    warn!("exiting because a resource limit was exceeded");
+                    status::drain();
+                    std::process::exit(EXIT_STATUS);
+                }
+            }
+
+            thread::sleep(INTERVAL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proc_status_is_parsed() {
+        let text = "Name:\tfakenet\nVmRSS:\t    2048 kB\nThreads:\t7\n";
+        assert_eq!(parse_proc_status(text).unwrap(), (2048 * 1024, 7));
+        assert!(parse_proc_status("Name:\tfakenet\n").is_err());
+
+        let (memory, threads) = process_usage(std::process::id()).unwrap();
+        assert!(memory > 0 && threads > 0);
+    }
+
+    #[test]
+    fn limits_are_checked() {
+        let config = Config {
+            max_memory_mb: Some(64),
+            max_timers: Some(10),
+            ..Config::default()
+        };
+        let mut usage = ResourceStatus {
+            memory_bytes: 64 << 20,
+            threads: 500,
+            channels: 0,
+            timers: 10,
+            exceeded: Vec::new(),
+        };
+        assert!(config.exceeded(&usage).is_empty());
+
+        usage.memory_bytes += 1 << 20;
+        usage.timers += 1;
+        assert_eq!(config.exceeded(&usage), ["memory", "timers"]);
+    }
+}
@@ -0,0 +1,173 @@
+//! Telnet-style service that greets each connection with a banner and optionally answers simple
+//! prompts, so scanners see a plausible fingerprint.
+
+use anyhow::Result as AHResult;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::protocols::limits::Limit;
+use crate::protocols::{ipv6, names, tcp};
+use crate::{identity, warn};
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc854
+const IAC: u8 = 255;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    #[serde(deserialize_with = "names::deserialize_port")]
+    pub port: u16,
+    /// `{hostname}` and `{fqdn}` are replaced with the node's name, here and in replies; the
+    /// login prompt of the node's personality when unset.
+    pub banner: Option<String>,
+    #[serde(default)]
+    pub responses: Vec<Response>,
+    /// Close the connection as soon as the banner is sent.
+    #[serde(default)]
+    pub close_after_banner: bool,
+    /// Seconds to wait for input before closing; wait forever when unset.
+    pub idle_timeout: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Response {
+    /// Line to answer; a trailing `*` matches any line starting with the rest.
+    pub input: String,
+    pub reply: String,
+}
+
+impl Response {
+    fn matches(&self, line: &str) -> bool {
+        match self.input.strip_suffix('*') {
+            Some(prefix) => line.starts_with(prefix),
+            None => line == self.input,
+        }
+    }
+}
+
+/// Remove telnet commands and option negotiation from `data`.
+fn strip_telnet(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut iter = data.iter().copied();
+
+    while let Some(byte) = iter.next() {
+        if byte != IAC {
+            result.push(byte);
+            continue;
+        }
+
+        match iter.next() {
+            Some(IAC) => result.push(IAC),
+            Some(SB) => {
+                let mut last = 0;
+                for byte in iter.by_ref() {
+                    if last == IAC && byte == SE {
+                        break;
+                    }
+                    last = byte;
+                }
+            }
+            // WILL, WONT, DO and DONT are followed by an option code.
+            Some(251..=254) => {
+                iter.next();
+            }
+            _ => {}
+        }
+    }
+
+    result
+}
+
+fn serve(config: &Config, connection: tcp::Connection) {
+    let identity = identity::get();
+    let banner = identity.expand(
+        config
+            .banner
+            .as_deref()
+            .unwrap_or_else(|| identity.personality.login_banner()),
+    );
+    if connection.send(banner.as_bytes()).is_err() || config.close_after_banner {
+        return;
+    }
+
+    let mut buffer = Vec::new();
+
+    loop {
+        let data = match config.idle_timeout {
+            Some(timeout) => connection
+                .receiver()
+                .recv_timeout(Duration::from_secs_f64(timeout))
+                .ok(),
+            None => connection.receiver().recv().ok(),
+        };
+
+        let data = match data {
+            Some(data) => data,
+            None => return,
+        };
+
+        buffer.extend(strip_telnet(&data));
+
+        while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+
+            if let Some(response) = config.responses.iter().find(|r| r.matches(line)) {
+                if connection
+                    .send(identity.expand(&response.reply).as_bytes())
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+
+        if let Err(e) = Limit::ReassemblyBuffer.check(buffer.len()) {
+            warn!("banner dropping connection: {}", e);
+            return;
+        }
+    }
+}
+
+pub fn start(config: Config, bind: Option<ipv6::Prefix>, tcp_server: &tcp::Server) -> AHResult<()> {
+    let listener = super::listen(tcp_server, config.port, bind)?;
+    let config = Arc::new(config);
+
+    thread::spawn(move || {
+        while let Ok(connection) = listener.accept() {
+            let config = Arc::clone(&config);
+
+            thread::spawn(move || serve(&config, connection));
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_telnet_removes_negotiation() {
+        assert_eq!(
+            strip_telnet(&[IAC, 253, 1, b'o', IAC, SB, 24, 0, IAC, SE, b'k', IAC, IAC]),
+            vec![b'o', b'k', IAC]
+        );
+    }
+
+    #[test]
+    fn response_matches_prefixes() {
+        let response = Response {
+            input: "GET *".to_string(),
+            reply: "HTTP/1.0 400 Bad Request\r\n".to_string(),
+        };
+
+        assert!(response.matches("GET / HTTP/1.0"));
+        assert!(!response.matches("HEAD / HTTP/1.0"));
+    }
+}
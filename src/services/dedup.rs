@@ -0,0 +1,138 @@
+//! Suppressing retransmitted requests to UDP services, for scenarios where a client retrying should
+//! get one answer rather than one for every copy it sends.
+
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::protocols::{ipv6, udp};
+
+/// What makes two requests from the same client the same.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Key {
+    /// The whole payload.
+    #[default]
+    Payload,
+    /// The first this many bytes of the payload, like a DNS message's ID, so retries that change
+    /// the rest still count.
+    Prefix(usize),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Config {
+    /// Seconds a request is remembered for, after which another copy is answered again.
+    pub window: f64,
+    #[serde(default)]
+    pub key: Key,
+}
+
+type Seen = (ipv6::Address, u16, u64);
+
+pub struct Dedup {
+    window: Duration,
+    key: Key,
+    /// When each request was first seen, by client address, port and digest of its key.
+    seen: HashMap<Seen, Instant>,
+    /// The same, oldest first, for expiring them.
+    order: VecDeque<(Instant, Seen)>,
+}
+
+impl Dedup {
+    pub fn new(config: Config) -> Self {
+        Self {
+            window: Duration::from_secs_f64(config.window),
+            key: config.key,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn digest(&self, payload: &[u8]) -> u64 {
+        let key = match self.key {
+            Key::Payload => payload,
+            Key::Prefix(len) => &payload[..len.min(payload.len())],
+        };
+
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Whether `datagram` repeats a request from the same client within the window; it's
+    /// remembered if not.
+    pub fn is_duplicate(&mut self, datagram: &udp::Datagram, now: Instant) -> bool {
+        while let Some((first_seen, seen)) = self.order.front() {
+            if now.saturating_duration_since(*first_seen) < self.window {
+                break;
+            }
+
+            self.seen.remove(seen);
+            self.order.pop_front();
+        }
+
+        let seen = (
+            datagram.src,
+            datagram.packet.src_port,
+            self.digest(&datagram.packet.payload),
+        );
+        if self.seen.contains_key(&seen) {
+            return true;
+        }
+
+        self.seen.insert(seen, now);
+        self.order.push_back((now, seen));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(src_port: u16, payload: &[u8]) -> udp::Datagram {
+        udp::Datagram {
+            src: "2001:db8::1".parse().unwrap(),
+            dest: "2001:db8::2".parse().unwrap(),
+            hop_limit: 64,
+            packet: udp::Packet {
+                src_port,
+                dest_port: 53,
+                payload: payload.to_vec(),
+            },
+        }
+    }
+
+    #[test]
+    fn copies_are_suppressed_within_the_window() {
+        let now = Instant::now();
+        let mut dedup = Dedup::new(Config {
+            window: 2.0,
+            key: Key::Payload,
+        });
+
+        assert!(!dedup.is_duplicate(&datagram(1000, b"query"), now));
+        assert!(dedup.is_duplicate(&datagram(1000, b"query"), now + Duration::from_secs(1)));
+        assert!(!dedup.is_duplicate(&datagram(1001, b"query"), now + Duration::from_secs(1)));
+        assert!(!dedup.is_duplicate(&datagram(1000, b"other"), now + Duration::from_secs(1)));
+
+        // Remembered from when it was first seen, not from the last copy.
+        assert!(!dedup.is_duplicate(&datagram(1000, b"query"), now + Duration::from_secs(2)));
+        assert!(dedup.is_duplicate(&datagram(1000, b"query"), now + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn prefix_keys_ignore_the_rest() {
+        let now = Instant::now();
+        let mut dedup = Dedup::new(Config {
+            window: 1.0,
+            key: Key::Prefix(2),
+        });
+
+        assert!(!dedup.is_duplicate(&datagram(1000, b"\x12\x34query"), now));
+        assert!(dedup.is_duplicate(&datagram(1000, b"\x12\x34retry"), now));
+        assert!(!dedup.is_duplicate(&datagram(1000, b"\x12\x35query"), now));
+    }
+}
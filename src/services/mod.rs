@@ -0,0 +1,87 @@
+//! Application-level services that run on top of the protocol servers.
+
+use anyhow::Result as AHResult;
+use serde::Deserialize;
+
+use crate::protocols::{ipv6, tcp, udp};
+
+pub mod banner;
+pub mod dedup;
+pub mod replay;
+pub mod twamp;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Config {
+    Banner(banner::Config),
+    Replay(replay::Config),
+    Twamp(twamp::Config),
+}
+
+impl Config {
+    /// The protocol and port the service listens on.
+    pub fn endpoint(&self) -> (replay::Protocol, u16) {
+        match self {
+            Config::Banner(banner) => (replay::Protocol::Tcp, banner.port),
+            Config::Replay(replay) => (replay.protocol, replay.port),
+            Config::Twamp(twamp) => (replay::Protocol::Udp, twamp.port),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Service {
+    #[serde(flatten)]
+    pub config: Config,
+    /// Differentiated services codepoint to mark everything the service sends with.
+    pub dscp: Option<u8>,
+    /// Answer only the first copy of a request a UDP client sends; every copy is answered when
+    /// unset.
+    pub dedup: Option<dedup::Config>,
+    /// Serve only on addresses in this prefix, answering for all of them whether or not they're
+    /// the node's; any of the node's addresses when unset.
+    pub bind: Option<ipv6::Prefix>,
+}
+
+impl Service {
+    /// The protocol and port to mark what the service sends from, with the DSCP to mark it with.
+    pub fn marking(&self) -> Option<(replay::Protocol, u16, u8)> {
+        let (protocol, port) = self.config.endpoint();
+        self.dscp.map(|dscp| (protocol, port, dscp))
+    }
+}
+
+/// Start listening for and serving connections in the background.
+pub fn start(service: Service, tcp_server: &tcp::Server, udp_server: &udp::Server) -> AHResult<()> {
+    let dedup = service.dedup.map(dedup::Dedup::new);
+
+    match service.config {
+        Config::Banner(config) => banner::start(config, service.bind, tcp_server)?,
+        Config::Replay(config) => {
+            replay::start(config, service.bind, dedup, tcp_server, udp_server)?
+        }
+        Config::Twamp(config) => twamp::start(config, service.bind, dedup, udp_server)?,
+    }
+
+    Ok(())
+}
+
+/// Listen on `port`, within `bind` if set.
+fn listen(
+    tcp_server: &tcp::Server,
+    port: u16,
+    bind: Option<ipv6::Prefix>,
+) -> AHResult<tcp::Listener> {
+    match bind {
+        Some(prefix) => tcp_server.listen_prefix(port, prefix),
+        None => tcp_server.listen(port),
+    }
+}
+
+/// Bind `port`, within `bind` if set.
+fn bind(udp_server: &udp::Server, port: u16, bind: Option<ipv6::Prefix>) -> AHResult<udp::Socket> {
+    match bind {
+        Some(prefix) => udp_server.bind_prefix(port, prefix),
+        None => udp_server.bind(port),
+    }
+}
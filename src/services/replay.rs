@@ -0,0 +1,512 @@
+//! Recording request/response exchanges with a real server from traffic we see, and replaying
+//! them as canned responses, so a fake server can impersonate one that was captured before.
+//!
+//! Recordings are files of JSON lines, one exchange each:
+//!
+//! ```json
+//! {"protocol": "udp", "port": 53, "request": "1234...", "response": "1234..."}
+//! ```
+//!
+//! TCP exchanges with an empty request are sent as soon as a connection is accepted, like a
+//! banner.
+
+use anyhow::{Context, Result as AHResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use super::dedup::Dedup;
+use crate::protocols::filter::{Direction, Filter};
+use crate::protocols::limits::Limit;
+use crate::protocols::{dns, ether, ipv6, names, tcp, udp};
+use crate::{expect, warn};
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Exchange {
+    pub protocol: Protocol,
+    /// The server's port.
+    pub port: u16,
+    /// Hex.
+    pub request: String,
+    /// Hex.
+    pub response: String,
+}
+
+pub fn load(path: &str) -> AHResult<Vec<Exchange>> {
+    BufReader::new(File::open(path).with_context(|| format!("opening {}", path))?)
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// How requests are matched against recorded ones.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Matching {
+    #[default]
+    Bytes,
+    /// By question, answering with the query's own ID.
+    Dns,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub protocol: Protocol,
+    #[serde(deserialize_with = "names::deserialize_port")]
+    pub port: u16,
+    /// Recording to replay; only exchanges for this protocol and port are used.
+    pub path: String,
+    #[serde(default)]
+    pub matching: Matching,
+}
+
+struct Replayer {
+    matching: Matching,
+    /// Decoded requests and responses, in recorded order.
+    exchanges: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Replayer {
+    fn new(config: &Config) -> AHResult<Self> {
+        Ok(Self {
+            matching: config.matching,
+            exchanges: load(&config.path)?
+                .into_iter()
+                .filter(|e| e.protocol == config.protocol && e.port == config.port)
+                .map(|e| Ok((hex::decode(&e.request)?, hex::decode(&e.response)?)))
+                .collect::<AHResult<_>>()?,
+        })
+    }
+
+    /// The response to a whole request, if one was recorded.
+    fn respond(&self, request: &[u8]) -> Option<Vec<u8>> {
+        match self.matching {
+            Matching::Bytes => self
+                .exchanges
+                .iter()
+                .find(|(recorded, _)| recorded == request)
+                .map(|(_, response)| response.clone()),
+            Matching::Dns => {
+                let query = dns::query(request).ok()?;
+                let (_, response) = self.exchanges.iter().find(|(recorded, _)| {
+                    dns::query(recorded).is_ok_and(|recorded| {
+                        recorded.name.eq_ignore_ascii_case(&query.name)
+                            && recorded.record_type == query.record_type
+                    })
+                })?;
+
+                let mut response = response.clone();
+                if response.len() >= 2 {
+                    response[..2].copy_from_slice(&query.id.to_be_bytes());
+                }
+
+                Some(response)
+            }
+        }
+    }
+
+    /// The first recorded request `buffer` starts with, and its response.
+    fn respond_to_stream(&self, buffer: &[u8]) -> Option<(usize, Vec<u8>)> {
+        self.exchanges
+            .iter()
+            .find(|(request, _)| !request.is_empty() && buffer.starts_with(request))
+            .map(|(request, response)| (request.len(), response.clone()))
+    }
+
+    fn greeting(&self) -> Option<&[u8]> {
+        self.exchanges
+            .iter()
+            .find(|(request, _)| request.is_empty())
+            .map(|(_, response)| &response[..])
+    }
+}
+
+fn serve_tcp(replayer: &Replayer, connection: tcp::Connection) {
+    if let Some(greeting) = replayer.greeting() {
+        if connection.send(greeting).is_err() {
+            return;
+        }
+    }
+
+    let mut buffer = Vec::new();
+    for data in connection.receiver() {
+        buffer.extend(data);
+
+        while let Some((len, response)) = replayer.respond_to_stream(&buffer) {
+            buffer.drain(..len);
+            if connection.send(response).is_err() {
+                return;
+            }
+        }
+
+        // Hung up on, rather than holding on to whatever a client sends that's never answered.
+        if let Err(e) = Limit::ReassemblyBuffer.check(buffer.len()) {
+            warn!("replay dropping connection: {}", e);
+            return;
+        }
+    }
+}
+
+/// `dedup` only applies over UDP, where retransmissions are the client's own.
+pub fn start(
+    config: Config,
+    bind: Option<ipv6::Prefix>,
+    mut dedup: Option<Dedup>,
+    tcp_server: &tcp::Server,
+    udp_server: &udp::Server,
+) -> AHResult<()> {
+    let replayer = Arc::new(Replayer::new(&config)?);
+
+    match config.protocol {
+        Protocol::Tcp => {
+            let listener = super::listen(tcp_server, config.port, bind)?;
+
+            thread::spawn(move || {
+                while let Ok(connection) = listener.accept() {
+                    let replayer = Arc::clone(&replayer);
+
+                    thread::spawn(move || serve_tcp(&replayer, connection));
+                }
+            });
+        }
+        Protocol::Udp => {
+            let socket = super::bind(udp_server, config.port, bind)?;
+
+            thread::spawn(move || {
+                for datagram in socket.receiver() {
+                    if let Some(dedup) = &mut dedup {
+                        if dedup.is_duplicate(&datagram, Instant::now()) {
+                            continue;
+                        }
+                    }
+
+                    let response = match replayer.respond(&datagram.packet.payload) {
+                        Some(response) => response,
+                        None => continue,
+                    };
+
+                    if let Err(e) = socket.send_to(
+                        datagram.dest,
+                        datagram.src,
+                        datagram.packet.src_port,
+                        response,
+                    ) {
+                        warn!("replay on udp port {}: {}", config.port, e);
+                    }
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// A conversation being recorded: the request so far, then the response so far.
+#[derive(Default)]
+struct Conversation {
+    request: Vec<u8>,
+    response: Vec<u8>,
+    /// Next sequence number expected from the client and server, to skip retransmissions.
+    next_seq: [Option<u32>; 2],
+}
+
+/// Pairs up requests to a server with its responses.
+struct Recording {
+    protocol: Protocol,
+    port: u16,
+    /// By client address and port.
+    conversations: HashMap<String, Conversation>,
+}
+
+impl Recording {
+    fn exchange(&self, request: Vec<u8>, response: Vec<u8>) -> Exchange {
+        Exchange {
+            protocol: self.protocol,
+            port: self.port,
+            request: hex::encode(request),
+            response: hex::encode(response),
+        }
+    }
+
+    /// Take in a packet, returning any exchange it completes.
+    fn observe(&mut self, packet: &expect::Packet) -> Vec<Exchange> {
+        let ipv6 = match &packet.ipv6 {
+            Some(ipv6) => ipv6,
+            None => return Vec::new(),
+        };
+
+        match (self.protocol, &packet.udp, &packet.tcp) {
+            (Protocol::Udp, Some(udp), _) => {
+                let key = |address, port| format!("[{}]:{}", address, port);
+
+                if udp.dest_port == self.port {
+                    self.conversations.insert(
+                        key(ipv6.src, udp.src_port),
+                        Conversation {
+                            request: udp.payload.clone(),
+                            ..Default::default()
+                        },
+                    );
+                } else if udp.src_port == self.port {
+                    if let Some(conversation) =
+                        self.conversations.remove(&key(ipv6.dest, udp.dest_port))
+                    {
+                        return vec![self.exchange(conversation.request, udp.payload.clone())];
+                    }
+                }
+
+                Vec::new()
+            }
+            (Protocol::Tcp, _, Some(segment)) => {
+                let (client, from_client) = if segment.dest_port == self.port {
+                    (format!("[{}]:{}", ipv6.src, segment.src_port), true)
+                } else if segment.src_port == self.port {
+                    (format!("[{}]:{}", ipv6.dest, segment.dest_port), false)
+                } else {
+                    return Vec::new();
+                };
+                let conversation = self.conversations.entry(client.clone()).or_default();
+                let side = if from_client { 0 } else { 1 };
+                let mut result = Vec::new();
+
+                let fresh = match conversation.next_seq[side] {
+                    Some(next) => segment.seq == next,
+                    None => true,
+                };
+                if fresh && !segment.payload.is_empty() {
+                    // A new request ends the previous exchange.
+                    if from_client && !conversation.response.is_empty() {
+                        let request = std::mem::take(&mut conversation.request);
+                        let response = std::mem::take(&mut conversation.response);
+                        result.push((request, response));
+                    }
+
+                    if from_client {
+                        conversation.request.extend(&segment.payload);
+                    } else {
+                        conversation.response.extend(&segment.payload);
+                    }
+                }
+                if fresh || segment.has(tcp::Segment::SYN) {
+                    conversation.next_seq[side] = Some(segment.seq.wrapping_add(segment.seq_len()));
+                }
+
+                if segment.has(tcp::Segment::FIN) || segment.has(tcp::Segment::RST) {
+                    let conversation = self.conversations.remove(&client).unwrap();
+                    if !conversation.response.is_empty() {
+                        result.push((conversation.request, conversation.response));
+                    }
+                }
+
+                result
+                    .into_iter()
+                    .map(|(request, response)| self.exchange(request, response))
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Record exchanges with the server on `port`, from traffic seen on an interface, appending them
+/// to the file at `path`.
+pub fn record(
+    observer: &ether::ObserveHandle,
+    protocol: Protocol,
+    port: u16,
+    path: &str,
+) -> AHResult<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening {}", path))?;
+    let receiver = observer.observe(1, Filter::Port(Direction::Either, port));
+    let mut recording = Recording {
+        protocol,
+        port,
+        conversations: HashMap::new(),
+    };
+
+    thread::spawn(move || {
+        for frame in receiver {
+            for exchange in recording.observe(&expect::Packet::decode(frame)) {
+                let result = serde_json::to_writer(&mut file, &exchange)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| Ok(writeln!(file)?));
+
+                if let Err(e) = result {
+                    warn!("recording exchange failed: {}", e);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::ipv6;
+
+    fn packet(
+        from_client: bool,
+        tcp: Option<tcp::Segment>,
+        udp: Option<udp::Packet>,
+    ) -> expect::Packet {
+        let client: ipv6::Address = "2001:db8::1".parse().unwrap();
+        let server: ipv6::Address = "2001:db8::2".parse().unwrap();
+        let (src, dest) = if from_client {
+            (client, server)
+        } else {
+            (server, client)
+        };
+
+        expect::Packet {
+            frame: ether::Frame {
+                dest: ether::Address::BROADCAST,
+                src: ether::Address::BROADCAST,
+                ethertype: ether::Type::Ipv6,
+                payload: Vec::new(),
+                received: None,
+            },
+            ipv6: Some(ipv6::Packet::builder().src(src).dest(dest).build()),
+            udp,
+            tcp,
+        }
+    }
+
+    fn segment(from_client: bool, seq: u32, flags: u8, payload: &[u8]) -> expect::Packet {
+        let (src_port, dest_port) = if from_client {
+            (40000, 25)
+        } else {
+            (25, 40000)
+        };
+
+        packet(
+            from_client,
+            Some(tcp::Segment {
+                src_port,
+                dest_port,
+                seq,
+                flags: flags | tcp::Segment::ACK,
+                payload: payload.to_vec(),
+                ..Default::default()
+            }),
+            None,
+        )
+    }
+
+    #[test]
+    fn tcp_conversations_are_split_into_exchanges() {
+        let mut recording = Recording {
+            protocol: Protocol::Tcp,
+            port: 25,
+            conversations: HashMap::new(),
+        };
+        let mut exchanges = Vec::new();
+        let mut observe = |packet| exchanges.extend(recording.observe(&packet));
+
+        observe(segment(false, 100, 0, b"220 hi\r\n"));
+        observe(segment(true, 500, 0, b"HE"));
+        observe(segment(true, 502, 0, b"LO\r\n"));
+        // Retransmitted.
+        observe(segment(true, 502, 0, b"LO\r\n"));
+        observe(segment(false, 108, 0, b"250 ok\r\n"));
+        observe(segment(true, 506, tcp::Segment::FIN, b""));
+
+        let pair = |request: &[u8], response: &[u8]| Exchange {
+            protocol: Protocol::Tcp,
+            port: 25,
+            request: hex::encode(request),
+            response: hex::encode(response),
+        };
+        assert_eq!(
+            exchanges,
+            vec![pair(b"", b"220 hi\r\n"), pair(b"HELO\r\n", b"250 ok\r\n")]
+        );
+
+        let replayer = Replayer {
+            matching: Matching::Bytes,
+            exchanges: exchanges
+                .iter()
+                .map(|e| {
+                    (
+                        hex::decode(&e.request).unwrap(),
+                        hex::decode(&e.response).unwrap(),
+                    )
+                })
+                .collect(),
+        };
+        assert_eq!(replayer.greeting(), Some(&b"220 hi\r\n"[..]));
+        assert_eq!(
+            replayer.respond_to_stream(b"HELO\r\nQUIT"),
+            Some((6, b"250 ok\r\n".to_vec()))
+        );
+    }
+
+    #[test]
+    fn dns_replays_match_questions() {
+        let query = |id, name: &str| {
+            dns::Query {
+                id,
+                name: name.to_string(),
+                record_type: dns::RecordType::Aaaa,
+            }
+            .encode()
+        };
+        let mut recording = Recording {
+            protocol: Protocol::Udp,
+            port: 53,
+            conversations: HashMap::new(),
+        };
+        let udp = |from_client, payload: Vec<u8>| {
+            let (src_port, dest_port) = if from_client {
+                (40000, 53)
+            } else {
+                (53, 40000)
+            };
+            packet(
+                from_client,
+                None,
+                Some(udp::Packet {
+                    src_port,
+                    dest_port,
+                    payload,
+                }),
+            )
+        };
+
+        assert!(recording
+            .observe(&udp(true, query(1, "example.com")))
+            .is_empty());
+        let mut response = query(1, "example.com");
+        response[2] |= 0x80;
+        let exchanges = recording.observe(&udp(false, response));
+        assert_eq!(exchanges.len(), 1);
+
+        let replayer = Replayer {
+            matching: Matching::Dns,
+            exchanges: vec![(
+                hex::decode(&exchanges[0].request).unwrap(),
+                hex::decode(&exchanges[0].response).unwrap(),
+            )],
+        };
+        let replayed = replayer.respond(&query(7, "EXAMPLE.com")).unwrap();
+        assert_eq!(replayed[..2], [0, 7]);
+        assert_eq!(replayer.respond(&query(7, "example.org")), None);
+    }
+}
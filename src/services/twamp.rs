@@ -0,0 +1,183 @@
+//! TWAMP Light reflector: answers test packets with when they were received and when the answer
+//! was sent, so measurement tools pointed at the node get delay numbers back.
+//!
+//! Ref: https://datatracker.ietf.org/doc/html/rfc5357#section-4.2 and appendix I; only
+//! unauthenticated mode, with the reflector stateless.
+
+use anyhow::Result as AHResult;
+use crossbeam::channel;
+use serde::Deserialize;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::dedup::Dedup;
+use crate::protocols::twamp::{self, ReflectedPacket, TestPacket, REFLECTED_LEN};
+use crate::protocols::{ipv6, names, udp};
+use crate::{clock, warn};
+
+// Ref: https://datatracker.ietf.org/doc/html/rfc5357#section-8
+const DEFAULT_PORT: u16 = 862;
+
+/// Seconds between the NTP epoch of 1900 and the Unix one.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Not synchronized to UTC, and good to within about a millisecond (1 * 2^(22 - 32) seconds).
+// Ref: https://datatracker.ietf.org/doc/html/rfc4656#section-4.1.2
+const ERROR_ESTIMATE: u16 = 22 << 8 | 1;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_port", deserialize_with = "names::deserialize_port")]
+    pub port: u16,
+    /// Seconds to hold each answer, on top of the time spent handling it.
+    #[serde(default)]
+    pub delay: f64,
+    /// Seconds to add to the node's clock in timestamps, to skew one-way delays.
+    #[serde(default)]
+    pub clock_offset: f64,
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+/// `time` as a 64-bit NTP timestamp.
+fn ntp_timestamp(time: SystemTime, offset: f64) -> u64 {
+    let since_unix = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let since_ntp = since_unix.as_secs_f64() + NTP_UNIX_OFFSET as f64 + offset;
+
+    let seconds = since_ntp.trunc() as u64;
+    let fraction = (since_ntp.fract() * (1u64 << 32) as f64) as u64;
+
+    seconds << 32 | fraction
+}
+
+/// The answer to `test`, stamped at `receive_timestamp` and waiting for its send timestamp; as
+/// long as the test packet when there's room, as symmetric measurement expects.
+fn reflect(test: TestPacket, len: usize, hop_limit: u8, receive_timestamp: u64) -> ReflectedPacket {
+    ReflectedPacket {
+        // Stateless reflectors echo the sender's sequence number.
+        seq: test.seq,
+        timestamp: 0,
+        error_estimate: ERROR_ESTIMATE,
+        receive_timestamp,
+        sender_seq: test.seq,
+        sender_timestamp: test.timestamp,
+        sender_error_estimate: test.error_estimate,
+        sender_ttl: hop_limit,
+        padding: vec![0; len.saturating_sub(REFLECTED_LEN)],
+    }
+}
+
+struct Pending {
+    due: Instant,
+    datagram: udp::Datagram,
+    reflected: ReflectedPacket,
+}
+
+pub fn start(
+    config: Config,
+    bind: Option<ipv6::Prefix>,
+    mut dedup: Option<Dedup>,
+    udp_server: &udp::Server,
+) -> AHResult<()> {
+    let socket = super::bind(udp_server, config.port, bind)?;
+    let sender = udp_server.sender();
+    let (pending_sender, pending_receiver) = channel::unbounded::<Pending>();
+    let delay = Duration::from_secs_f64(config.delay);
+    let (port, clock_offset) = (config.port, config.clock_offset);
+
+    thread::spawn(move || {
+        for datagram in socket.receiver() {
+            let received = clock::now();
+            if let Some(dedup) = &mut dedup {
+                if dedup.is_duplicate(&datagram, Instant::now()) {
+                    continue;
+                }
+            }
+
+            let test = match twamp::test_packet(&datagram.packet.payload) {
+                Ok(test) => test,
+                Err(_) => continue,
+            };
+
+            let reflected = reflect(
+                test,
+                datagram.packet.payload.len(),
+                datagram.hop_limit,
+                ntp_timestamp(received, clock_offset),
+            );
+            let pending = Pending {
+                due: Instant::now() + delay,
+                datagram,
+                reflected,
+            };
+            if pending_sender.send(pending).is_err() {
+                return;
+            }
+        }
+    });
+
+    // Answers wait here, in order, so the delay doesn't hold up timestamping later arrivals.
+    thread::spawn(move || {
+        for mut pending in pending_receiver {
+            let now = Instant::now();
+            if pending.due > now {
+                thread::sleep(pending.due - now);
+            }
+
+            pending.reflected.timestamp = ntp_timestamp(clock::now(), clock_offset);
+            let datagram = &pending.datagram;
+            let answer = udp::Packet {
+                src_port: datagram.packet.dest_port,
+                dest_port: datagram.packet.src_port,
+                payload: pending.reflected.encode(),
+            };
+            if let Err(e) = sender.send(datagram.dest, datagram.src, &answer) {
+                warn!("twamp on udp port {}: {}", port, e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_count_from_1900() {
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+
+        assert_eq!(
+            ntp_timestamp(time, 0.0),
+            (NTP_UNIX_OFFSET + 1) << 32 | 1 << 31
+        );
+        assert_eq!(ntp_timestamp(time, 0.5) >> 32, NTP_UNIX_OFFSET + 2);
+    }
+
+    #[test]
+    fn reflections_carry_the_test_packet() {
+        let test = TestPacket {
+            seq: 7,
+            timestamp: 0x1122334455667788,
+            error_estimate: 0x8001,
+            padding: vec![0; 50],
+        };
+        let len = test.encode().len();
+        let test = twamp::test_packet(&test.encode()).unwrap();
+
+        let mut reflected = reflect(test, len, 63, 0xaabb);
+        reflected.timestamp = 0xccdd;
+        let encoded = reflected.encode();
+
+        assert_eq!(encoded.len(), len);
+        assert_eq!(
+            hex::encode(&encoded[..REFLECTED_LEN]),
+            "00000007000000000000ccdd1601000000000000\
+             0000aabb000000071122334455667788800100003f"
+        );
+        assert_eq!(ReflectedPacket::parse(&encoded).unwrap().1, reflected);
+    }
+}
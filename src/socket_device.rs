@@ -0,0 +1,146 @@
+//! User-mode backend: frames exchanged with a single peer over a Unix stream socket, so fakenet
+//! can be the other end of a VM or container's network without any host interfaces.
+//!
+//! Frames are framed as QEMU's `-netdev stream` does, each preceded by its length as a 32-bit
+//! big-endian integer.
+
+use anyhow::{bail, Result as AHResult};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::SystemTime;
+
+use crate::device::{Device, FRAME_SIZE};
+
+const LENGTH_LEN: usize = 4;
+
+pub struct SocketDevice {
+    name: String,
+    stream: UnixStream,
+    /// Bytes read but not yet handed out as frames.
+    pending: Vec<u8>,
+}
+
+impl SocketDevice {
+    pub fn from_stream(name: impl Into<String>, stream: UnixStream) -> Self {
+        Self {
+            name: name.into(),
+            stream,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Connect to a peer already listening at `path`.
+    pub fn connect(path: &str) -> AHResult<Self> {
+        Ok(Self::from_stream(path, UnixStream::connect(path)?))
+    }
+
+    /// Listen at `path`, replacing any stale socket left there, and wait for a peer to connect.
+    pub fn listen(path: &str) -> AHResult<Self> {
+        let _ = std::fs::remove_file(path);
+        let (stream, _) = UnixListener::bind(path)?.accept()?;
+
+        Ok(Self::from_stream(path, stream))
+    }
+}
+
+impl Device for SocketDevice {
+    // There's no host side to bring up or down.
+    fn up(&mut self) -> AHResult<()> {
+        Ok(())
+    }
+
+    fn down(&mut self) -> AHResult<()> {
+        Ok(())
+    }
+
+    fn if_name(&self) -> AHResult<String> {
+        Ok(self.name.clone())
+    }
+
+    fn if_hwaddr(&self) -> AHResult<[u8; 6]> {
+        bail!("{} has no host side with a hardware address", self.name)
+    }
+
+    fn set_if_hwaddr(&mut self, _address: [u8; 6]) -> AHResult<()> {
+        bail!("{} has no host side with a hardware address", self.name)
+    }
+
+    fn read_frames(&mut self, f: &mut dyn FnMut(&[u8], SystemTime)) -> AHResult<()> {
+        let mut buffer = [0; FRAME_SIZE + LENGTH_LEN];
+        let num_read = self.stream.read(&mut buffer)?;
+        let received = SystemTime::now();
+        if num_read == 0 {
+            bail!("peer closed {}", self.name);
+        }
+        self.pending.extend_from_slice(&buffer[..num_read]);
+
+        let mut offset = 0;
+        while let Some(length) = self.pending.get(offset..offset + LENGTH_LEN) {
+            let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+            let start = offset + LENGTH_LEN;
+
+            if length > FRAME_SIZE {
+                bail!("peer sent a {} byte frame", length);
+            }
+            if self.pending.len() < start + length {
+                break;
+            }
+
+            f(&self.pending[start..start + length], received);
+            offset = start + length;
+        }
+        self.pending.drain(..offset);
+
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> AHResult<()> {
+        let mut message = Vec::with_capacity(LENGTH_LEN + buf.len());
+        message.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+        message.extend_from_slice(buf);
+
+        Ok(self.stream.write_all(&message)?)
+    }
+
+    fn rawfd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+
+    fn set_down_on_drop(&mut self, _down_on_drop: bool) {}
+
+    fn down_on_drop(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_survive_partial_reads() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut device = SocketDevice::from_stream("test", a);
+
+        // One whole frame, then the start of another.
+        b.write_all(b"\x00\x00\x00\x03abc\x00\x00\x00\x04de")
+            .unwrap();
+        let mut frames = Vec::new();
+        device
+            .read_frames(&mut |frame, _| frames.push(frame.to_vec()))
+            .unwrap();
+        assert_eq!(frames, vec![b"abc".to_vec()]);
+
+        b.write_all(b"fg").unwrap();
+        device
+            .read_frames(&mut |frame, _| frames.push(frame.to_vec()))
+            .unwrap();
+        assert_eq!(frames, vec![b"abc".to_vec(), b"defg".to_vec()]);
+
+        device.write(b"xyz").unwrap();
+        let mut written = [0; 7];
+        b.read_exact(&mut written).unwrap();
+        assert_eq!(&written, b"\x00\x00\x00\x03xyz");
+    }
+}
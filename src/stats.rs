@@ -0,0 +1,159 @@
+//! Counters bumped for every frame, kept in atomics rather than the status tree so the hot path
+//! doesn't contend on the status lock; they're folded into status periodically and whenever it's
+//! read or written out.
+
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::status::{self, Status};
+
+/// How often counters are folded into status between updates.
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters for keys that aren't known up front, like dispatchers or drop reasons.
+#[derive(Default)]
+pub struct CounterMap(RwLock<HashMap<String, Arc<AtomicU64>>>);
+
+impl CounterMap {
+    pub fn increment(&self, key: &str) {
+        if let Some(counter) = self.0.read().unwrap().get(key) {
+            counter.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let mut counters = self.0.write().unwrap();
+        counters
+            .entry(key.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn fold_into(&self, totals: &mut BTreeMap<String, u64>) {
+        for (key, counter) in self.0.read().unwrap().iter() {
+            totals.insert(key.clone(), counter.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// A counter for each of the tap's queues.
+#[derive(Default)]
+pub struct QueueCounters(RwLock<Vec<AtomicU64>>);
+
+impl QueueCounters {
+    /// Count for `queues` queues, starting from zero.
+    pub fn set_queues(&self, queues: usize) {
+        *self.0.write().unwrap() = (0..queues).map(|_| AtomicU64::new(0)).collect();
+    }
+
+    /// Does nothing for a single queue, which isn't counted separately.
+    pub fn increment(&self, queue: usize) {
+        if let Some(counter) = self.0.read().unwrap().get(queue) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self) -> Vec<u64> {
+        let counters = self.0.read().unwrap();
+        counters.iter().map(|c| c.load(Ordering::Relaxed)).collect()
+    }
+}
+
+pub static FRAMES_RECEIVED: Counter = Counter::new();
+pub static FRAMES_SENT: Counter = Counter::new();
+pub static FRAMES_DROPPED: Counter = Counter::new();
+pub static OWN_FRAMES_RECEIVED: Counter = Counter::new();
+
+lazy_static! {
+    pub static ref QUEUE_FRAMES_RECEIVED: QueueCounters = QueueCounters::default();
+    /// Keyed by dispatcher name and key, as `name/key`.
+    pub static ref DISPATCH_DROPS: CounterMap = CounterMap::default();
+    /// Keyed by the reason NDP messages were discarded.
+    pub static ref NDP_DROPS: CounterMap = CounterMap::default();
+    /// Keyed by the type of unrecognized hop-by-hop option, in hex.
+    pub static ref OPTION_DISCARDS: CounterMap = CounterMap::default();
+    /// Keyed by the rate limit ICMP errors were over, `peer` or `global`.
+    pub static ref ICMP_ERROR_DROPS: CounterMap = CounterMap::default();
+    /// Keyed by the TCP listen queue that was full, `syn` or `accept`.
+    pub static ref TCP_LISTEN_OVERFLOWS: CounterMap = CounterMap::default();
+    /// Keyed by why the connection was reset, `idle` or `keepalive`.
+    pub static ref TCP_REAPED: CounterMap = CounterMap::default();
+}
+
+/// Copy the counters into `status`.
+pub fn aggregate(status: &mut Status) {
+    let counters = &mut status.interface.counters;
+    counters.frames_received = FRAMES_RECEIVED.get();
+    counters.frames_sent = FRAMES_SENT.get();
+    counters.frames_dropped = FRAMES_DROPPED.get();
+    counters.own_frames_received = OWN_FRAMES_RECEIVED.get();
+    status.interface.queue_frames_received = QUEUE_FRAMES_RECEIVED.get();
+
+    DISPATCH_DROPS.fold_into(&mut status.dispatch_drops);
+    NDP_DROPS.fold_into(&mut status.ndp_drops);
+    OPTION_DISCARDS.fold_into(&mut status.option_discards);
+    ICMP_ERROR_DROPS.fold_into(&mut status.icmp_error_drops);
+    TCP_LISTEN_OVERFLOWS.fold_into(&mut status.tcp_listen_overflows);
+    TCP_REAPED.fold_into(&mut status.tcp_reaped);
+}
+
+/// Fold the counters into status every `INTERVAL`, so they're never far behind for anything
+/// looking at the status tree directly.
+pub fn start() {
+    thread::spawn(|| loop {
+        thread::sleep(INTERVAL);
+        status::record(aggregate);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyed_counters_are_folded_in() {
+        let drops = CounterMap::default();
+        drops.increment("ipv6/udp");
+        drops.increment("ipv6/udp");
+        drops.increment("ether/arp");
+
+        let mut totals = BTreeMap::new();
+        totals.insert("ipv6/udp".to_string(), 1);
+        drops.fold_into(&mut totals);
+
+        assert_eq!(totals["ipv6/udp"], 2);
+        assert_eq!(totals["ether/arp"], 1);
+    }
+
+    #[test]
+    fn only_known_queues_are_counted() {
+        let queues = QueueCounters::default();
+        queues.increment(0);
+        assert!(queues.get().is_empty());
+
+        queues.set_queues(2);
+        queues.increment(1);
+        queues.increment(1);
+        queues.increment(5);
+        assert_eq!(queues.get(), [0, 2]);
+    }
+}
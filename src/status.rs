@@ -1,58 +1,754 @@
+use anyhow::{bail, Context, Result as AHResult};
+use crossbeam::channel;
 use lazy_static::lazy_static;
-use serde::Serialize;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
 use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::protocols::conntrack::{FlowCounters, FlowState};
+use crate::protocols::ether::{self, AdminState};
+use crate::protocols::ipv6::InterfaceAddressState;
+use crate::protocols::neighbor::NeighborState;
+use crate::protocols::{ipv4, ipv6};
+use crate::stats;
+use crate::warn;
+use crate::watchdog::Health;
 
 lazy_static! {
-    static ref STATUS_UPDATE_LOCK: Mutex<()> = Mutex::new(());
-    static ref STATUS: Mutex<serde_json::Map<String, serde_json::Value>> =
-        Mutex::new(serde_json::Map::new());
+    static ref STATUS: Mutex<Status> = Mutex::new(Status::default());
+    /// Clients of the status socket, when updates go there instead of stdout.
+    static ref SUBSCRIBERS: Mutex<Option<Vec<UnixStream>>> = Mutex::new(None);
+    /// Taken before `STATUS` when both are needed, so lines go out in order.
+    static ref OUTPUT: Mutex<Output> = Mutex::new(Output::default());
+    /// Sent to under `OUTPUT`, so the writer sees everything in order.
+    static ref WRITES: channel::Sender<Outgoing> = start_writer();
+}
+
+/// Map key that is serialized as its `Display` form, so typed values can key JSON objects.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Key<T>(pub T);
+
+impl<T: Display> Serialize for Key<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
 }
 
-#[must_use]
-pub struct UpdateBuilder<'a> {
-    _status_lock: std::sync::MutexGuard<'a, ()>,
-    path: Vec<String>,
+impl<'de, T: FromStr> Deserialize<'de> for Key<T>
+where
+    T::Err: Display,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map(Key)
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Status {
+    /// Fully qualified, if the node has a domain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub interface: InterfaceStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wake_on_lan: Option<WakeOnLanStatus>,
+    /// Items dropped because a receiver fell behind, by dispatcher and key.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dispatch_drops: BTreeMap<String, u64>,
+    /// Received NDP messages discarded by validation, by the check they failed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ndp_drops: BTreeMap<String, u64>,
+    /// Received packets discarded for an unrecognized hop-by-hop option, by option type.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub option_discards: BTreeMap<String, u64>,
+    /// ICMP errors not sent for being over a rate limit, by the limit.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub icmp_error_drops: BTreeMap<String, u64>,
+    /// Connections a TCP listener's backlog had no room for, by the queue that was full.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tcp_listen_overflows: BTreeMap<String, u64>,
+    /// TCP connections reset for going quiet, by why: `idle` or `keepalive`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tcp_reaped: BTreeMap<String, u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pppoe_sessions: BTreeMap<u16, PppoeSessionStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wireguard: Option<WireGuardStatus>,
+    /// Other hosts seen on the segment by passive inventory, by ether address.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub learned_hosts: BTreeMap<Key<ether::Address>, LearnedHostStatus>,
+    /// The node's own TCP connections and UDP flows.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flows: Vec<FlowStatus>,
+    /// Health of threads that are watched, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub threads: BTreeMap<String, Health>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceStatus>,
+    /// Recent notable events, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<EventStatus>,
 }
 
-impl<'a> UpdateBuilder<'a> {
-    pub fn child(mut self, name: impl Into<String>) -> Self {
-        self.path.push(name.into());
+/// Events kept in status; older ones are dropped to make room.
+pub const MAX_EVENTS: usize = 100;
 
-        self
+impl Status {
+    pub fn push_event(&mut self, event: EventStatus) {
+        if self.events.len() >= MAX_EVENTS {
+            self.events.remove(0);
+        }
+        self.events.push(event);
     }
+}
 
-    pub fn field(self, name: impl AsRef<str>, value: impl Serialize) -> Self {
-        let mut current = &mut *STATUS.lock().unwrap();
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct InterfaceStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ether_address: Option<ether::Address>,
+    /// Network namespace the other end of the interface is in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub netns: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_state: Option<AdminState>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub addresses: BTreeMap<Key<ipv6::Address>, AddressStatus>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub neighbors: BTreeMap<Key<IpAddr>, NeighborStatus>,
+    /// IPv4 addresses answered for over ARP.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub arp_addresses: BTreeSet<ipv4::Address>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub default_routers: Vec<RouterStatus>,
+    /// Recursive DNS servers learned from router advertisements.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<ipv6::Address>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_search_list: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub path_mtus: BTreeMap<Key<ipv6::Address>, usize>,
+    /// Of everything sent besides NDP, as configured or last advertised by a router.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hop_limit: Option<u8>,
+    /// Of IPv4 packets sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u8>,
+    #[serde(default)]
+    pub counters: CounterSet,
+    /// Frames received on each of the tap's queues, when it has more than one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub queue_frames_received: Vec<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AddressStatus {
+    pub state: InterfaceAddressState,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct NeighborStatus {
+    pub ether_address: Option<ether::Address>,
+    pub state: NeighborState,
+    /// Seconds since the Unix epoch.
+    pub last_seen: u64,
+    pub interface: String,
+}
 
-        for elem in &self.path {
-            current = current
-                .entry(elem)
-                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()))
-                .as_object_mut()
-                .unwrap();
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LearnedHostStatus {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub ipv4_addresses: BTreeSet<ipv4::Address>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub ipv6_addresses: BTreeSet<ipv6::Address>,
+    /// Seconds since the Unix epoch.
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RouterStatus {
+    pub address: ipv6::Address,
+    pub ether_address: Option<ether::Address>,
+    pub lifetime_secs: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CounterSet {
+    pub frames_received: u64,
+    pub frames_sent: u64,
+    pub frames_dropped: u64,
+    /// Received from one of the interface's own addresses, like our frames looped back.
+    #[serde(default)]
+    pub own_frames_received: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct WakeOnLanStatus {
+    pub magic_packets: u64,
+    pub last_source: ether::Address,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PppoeSessionStatus {
+    pub client: ether::Address,
+    pub address: ipv4::Address,
+    /// Whether IPCP has finished, so the client has its address.
+    pub open: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct WireGuardStatus {
+    pub handshakes_completed: u64,
+    pub handshakes_failed: u64,
+    pub cookie_replies: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ResourceStatus {
+    pub memory_bytes: u64,
+    pub threads: u64,
+    pub channels: u64,
+    pub timers: u64,
+    /// Limits currently exceeded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exceeded: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Panic,
+    /// A received packet was discarded because of something in it we don't support.
+    Discard,
+    /// A TCP connection was reset for being idle or not answering keep-alives.
+    Reaped,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EventStatus {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub kind: EventKind,
+    pub thread: String,
+    pub message: String,
+    /// What the thread was handling, in hex, if it said.
+    pub input: Option<String>,
+    /// Whether the thread is being restarted.
+    pub restarting: bool,
+}
+
+impl EventStatus {
+    /// An event happening now.
+    pub fn new(kind: EventKind, thread: &str, message: String) -> Self {
+        Self {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            kind,
+            thread: thread.to_string(),
+            message,
+            input: None,
+            restarting: false,
         }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FlowStatus {
+    pub protocol: String,
+    pub local: String,
+    pub remote: String,
+    pub state: FlowState,
+    #[serde(flatten)]
+    pub counters: FlowCounters,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OutputPolicy {
+    /// Most status lines to write a second, with changes in between coalesced into the next; 0 for
+    /// no limit.
+    #[serde(default = "default_max_rate")]
+    pub max_rate: f64,
+    /// Write only what changed since the last line, as a JSON merge patch, after a full first line.
+    // Ref: https://datatracker.ietf.org/doc/html/rfc7396
+    #[serde(default)]
+    pub delta: bool,
+}
 
-        current.insert(
-            name.as_ref().to_string(),
-            serde_json::to_value(value).unwrap(),
+fn default_max_rate() -> f64 {
+    20.0
+}
+
+/// Slowest rate kept to, a line an hour, so the interval between lines can be represented.
+const MIN_RATE: f64 = 1.0 / 3600.0;
+
+impl Default for OutputPolicy {
+    fn default() -> Self {
+        Self {
+            max_rate: default_max_rate(),
+            delta: false,
+        }
+    }
+}
+
+impl OutputPolicy {
+    fn interval(&self) -> Duration {
+        if self.max_rate > 0.0 {
+            Duration::from_secs_f64(1.0 / self.max_rate.max(MIN_RATE))
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[derive(Default)]
+struct Output {
+    policy: OutputPolicy,
+    last_written: Option<Instant>,
+    flusher_started: bool,
+}
+
+/// Set when the status changes in a way that should be written out.
+static DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Write status out according to `policy` from now on.
+pub fn set_output(policy: OutputPolicy) {
+    OUTPUT.lock().unwrap().policy = policy;
+}
+
+/// What changed from `old` to `new` as a merge patch, or `None` if nothing did.
+fn merge_patch(old: &serde_json::Value, new: &serde_json::Value) -> Option<serde_json::Value> {
+    use serde_json::{Map, Value};
+
+    let (old, new) = match (old, new) {
+        (Value::Object(old), Value::Object(new)) => (old, new),
+        _ if old == new => return None,
+        _ => return Some(new.clone()),
+    };
+
+    let mut patch = Map::new();
+    for (key, value) in new {
+        match old.get(key) {
+            Some(old_value) => {
+                if let Some(change) = merge_patch(old_value, value) {
+                    patch.insert(key.clone(), change);
+                }
+            }
+            None => {
+                patch.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    for key in old.keys().filter(|key| !new.contains_key(*key)) {
+        patch.insert(key.clone(), Value::Null);
+    }
+
+    (!patch.is_empty()).then_some(Value::Object(patch))
+}
+
+/// Version of the status line format, bumped when a change would break existing consumers.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineKind {
+    /// The whole status.
+    Snapshot,
+    /// Changes to the last status, as a JSON merge patch.
+    Delta,
+}
+
+/// One line of the status stream.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Line {
+    pub schema_version: u32,
+    #[serde(rename = "type")]
+    pub kind: LineKind,
+    /// The node the line came from, when several are run as a topology.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    pub status: serde_json::Value,
+}
+
+impl Line {
+    fn new(kind: LineKind, status: serde_json::Value) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            kind,
+            node: None,
+            status,
+        }
+    }
+
+    /// As written to the stream, newline included.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut line = serde_json::to_vec(self).unwrap();
+        line.push(b'\n');
+
+        line
+    }
+}
+
+/// Parse a line of the status stream, checking it's a version we understand.
+pub fn parse_line(line: &str) -> AHResult<Line> {
+    let line: Line = serde_json::from_str(line).context("parsing status line")?;
+    if line.schema_version != SCHEMA_VERSION {
+        bail!(
+            "status schema version {} isn't supported, only {}",
+            line.schema_version,
+            SCHEMA_VERSION
         );
+    }
+
+    Ok(line)
+}
+
+/// Apply a merge patch made by `merge_patch` to `target`.
+fn apply_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    use serde_json::{Map, Value};
+
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            apply_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Follows a status stream, snapshots and deltas alike, keeping the whole current status.
+#[derive(Default)]
+pub struct Reader {
+    current: Option<serde_json::Value>,
+}
+
+impl Reader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take in the next line of the stream, returning the status as of it.
+    pub fn read_line(&mut self, line: &str) -> AHResult<Status> {
+        let line = parse_line(line)?;
+        match (line.kind, &mut self.current) {
+            (LineKind::Snapshot, _) => self.current = Some(line.status),
+            (LineKind::Delta, Some(current)) => apply_patch(current, &line.status),
+            (LineKind::Delta, None) => bail!("status delta before any snapshot"),
+        }
 
-        self
+        serde_json::from_value(self.current.clone().unwrap()).context("reading status")
     }
+}
+
+fn write_line(line: &Line) {
+    let line = line.encode();
+
+    match &mut *SUBSCRIBERS.lock().unwrap() {
+        // Clients that hung up are forgotten.
+        Some(subscribers) => subscribers.retain_mut(|s| s.write_all(&line).is_ok()),
+        None => std::io::stdout().lock().write_all(&line).unwrap(),
+    }
+}
 
-    pub fn write(self) {
-        let stdout_handle = std::io::stdout();
-        let mut stdout = stdout_handle.lock();
+/// What the writer is asked to do, in order.
+enum Outgoing {
+    /// The status as of a flush, written as a delta against the last one if `delta` is set.
+    Status {
+        value: serde_json::Value,
+        delta: bool,
+    },
+    /// A new client, first sent the last status written.
+    Subscribe(UnixStream),
+    /// Signalled once everything before it has been written.
+    Drained(channel::Sender<()>),
+}
+
+/// Write status lines on their own thread, so a slow reader never holds up the stack.
+fn start_writer() -> channel::Sender<Outgoing> {
+    let (sender, writes) = channel::unbounded();
+
+    thread::spawn(move || {
+        // The status as of the last line written, for deltas and new subscribers.
+        let mut last: Option<serde_json::Value> = None;
+
+        for write in writes {
+            match write {
+                Outgoing::Status { value, delta } => {
+                    let line = match (&last, delta) {
+                        (Some(last), true) => {
+                            merge_patch(last, &value).map(|patch| Line::new(LineKind::Delta, patch))
+                        }
+                        _ => Some(Line::new(LineKind::Snapshot, value.clone())),
+                    };
+                    last = Some(value);
+                    if let Some(line) = line {
+                        write_line(&line);
+                    }
+                }
+                // Clients start from the last status written, which any deltas that follow are
+                // against.
+                Outgoing::Subscribe(mut stream) => {
+                    let status = match &last {
+                        Some(last) => last.clone(),
+                        None => serde_json::to_value(&*STATUS.lock().unwrap()).unwrap(),
+                    };
+                    let line = Line::new(LineKind::Snapshot, status);
+                    if stream.write_all(&line.encode()).is_ok() {
+                        SUBSCRIBERS
+                            .lock()
+                            .unwrap()
+                            .get_or_insert_with(Vec::new)
+                            .push(stream);
+                    }
+                }
+                Outgoing::Drained(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+
+    sender
+}
+
+/// Longest to wait for status to be written out before giving up.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait for the status flushed so far to be written out, e.g. before exiting.
+pub fn drain() {
+    let (done, drained) = channel::bounded(1);
+    if WRITES.send(Outgoing::Drained(done)).is_ok() {
+        let _ = drained.recv_timeout(DRAIN_TIMEOUT);
+    }
+}
+
+/// Send the status to be written if it changed and the rate limit allows, leaving it for the
+/// background flush otherwise.
+fn flush() {
+    let mut output = OUTPUT.lock().unwrap();
+    if !DIRTY.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let interval = output.policy.interval();
+    if output
+        .last_written
+        .is_some_and(|at| at.elapsed() < interval)
+    {
+        if !output.flusher_started {
+            output.flusher_started = true;
+            thread::spawn(run_flush);
+        }
+        return;
+    }
+
+    DIRTY.store(false, Ordering::Relaxed);
+    let value = {
+        let mut status = STATUS.lock().unwrap();
+        stats::aggregate(&mut status);
+        serde_json::to_value(&*status).unwrap()
+    };
+
+    output.last_written = Some(Instant::now());
+    let _ = WRITES.send(Outgoing::Status {
+        value,
+        delta: output.policy.delta,
+    });
+}
 
-        serde_json::to_writer(&mut stdout, &*STATUS).unwrap();
-        write!(stdout, "\n").unwrap();
+/// Write out changes held back by the rate limit, as it allows.
+fn run_flush() {
+    loop {
+        let interval = OUTPUT.lock().unwrap().policy.interval();
+        thread::sleep(interval.max(Duration::from_millis(1)));
+        flush();
     }
 }
 
-pub fn update<'a>() -> UpdateBuilder<'a> {
-    UpdateBuilder {
-        _status_lock: STATUS_UPDATE_LOCK.lock().unwrap(),
-        path: Vec::new(),
+/// Change the status and write it out, as soon as the rate limit allows.
+pub fn update(f: impl FnOnce(&mut Status)) {
+    f(&mut STATUS.lock().unwrap());
+    DIRTY.store(true, Ordering::Relaxed);
+    flush();
+}
+
+const SUBSCRIBER_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Send updates to clients of a Unix socket at `path` instead of stdout, each starting with the
+/// current status.
+pub fn serve(path: &str) -> AHResult<()> {
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path).with_context(|| format!("binding {}", path))?;
+    *SUBSCRIBERS.lock().unwrap() = Some(Vec::new());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("accepting status connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            // A client that stops reading is dropped rather than holding up every update.
+            if let Err(e) = stream.set_write_timeout(Some(SUBSCRIBER_WRITE_TIMEOUT)) {
+                warn!("configuring status connection failed: {}", e);
+                continue;
+            }
+
+            // In line with flushes, so the client can't miss an update in between.
+            let _output = OUTPUT.lock().unwrap();
+            let _ = WRITES.send(Outgoing::Subscribe(stream));
+        }
+    });
+
+    Ok(())
+}
+
+/// Change the status without writing it out, for values like counters that change too often to
+/// report every time. They are included in the next update.
+pub fn record(f: impl FnOnce(&mut Status)) {
+    f(&mut STATUS.lock().unwrap());
+}
+
+pub fn snapshot() -> Status {
+    let mut status = STATUS.lock().unwrap();
+    stats::aggregate(&mut status);
+
+    status.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_serializes_keys_as_strings() {
+        let mut status = Status::default();
+        status.interface.addresses.insert(
+            Key("fe80::1".parse().unwrap()),
+            AddressStatus {
+                state: InterfaceAddressState::Tentative,
+            },
+        );
+
+        let value = serde_json::to_value(&status).unwrap();
+        assert_eq!(
+            value["interface"]["addresses"]["fe80::1"]["state"],
+            "Tentative"
+        );
+        assert_eq!(value["interface"]["counters"]["frames_received"], 0);
+        assert!(value.get("wake_on_lan").is_none());
+
+        assert_eq!(serde_json::from_value::<Status>(value).unwrap(), status);
+    }
+
+    #[test]
+    fn key_rejects_invalid_strings() {
+        assert!(serde_json::from_str::<Key<ipv6::Address>>("\"nope\"").is_err());
+    }
+
+    #[test]
+    fn deltas_carry_only_changes() {
+        let old = serde_json::json!({
+            "hostname": "nas",
+            "interface": {"counters": {"frames_received": 1, "frames_sent": 2}},
+            "wireguard": {"peers": 1},
+        });
+        let new = serde_json::json!({
+            "hostname": "nas",
+            "interface": {"counters": {"frames_received": 3, "frames_sent": 2}},
+            "dispatch_drops": {"ipv6/udp": 1},
+        });
+
+        assert_eq!(
+            merge_patch(&old, &new).unwrap(),
+            serde_json::json!({
+                "interface": {"counters": {"frames_received": 3}},
+                "dispatch_drops": {"ipv6/udp": 1},
+                "wireguard": null,
+            })
+        );
+        assert_eq!(merge_patch(&new, &new), None);
+
+        let mut patched = old.clone();
+        apply_patch(&mut patched, &merge_patch(&old, &new).unwrap());
+        assert_eq!(patched, new);
+    }
+
+    #[test]
+    fn output_is_rate_limited() {
+        assert_eq!(
+            OutputPolicy::default().interval(),
+            Duration::from_millis(50)
+        );
+
+        let unlimited = OutputPolicy {
+            max_rate: 0.0,
+            delta: false,
+        };
+        assert_eq!(unlimited.interval(), Duration::ZERO);
+
+        let glacial = OutputPolicy {
+            max_rate: 1e-300,
+            delta: false,
+        };
+        assert_eq!(glacial.interval(), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn readers_follow_snapshots_and_deltas() {
+        let mut reader = Reader::new();
+        let delta = Line::new(
+            LineKind::Delta,
+            serde_json::json!({"interface": {"counters": {"frames_sent": 4}}}),
+        );
+        let delta = String::from_utf8(delta.encode()).unwrap();
+        assert!(reader.read_line(&delta).is_err());
+
+        let mut status = Status {
+            hostname: Some("nas".to_string()),
+            ..Status::default()
+        };
+        status.interface.counters.frames_received = 2;
+        let snapshot = Line::new(LineKind::Snapshot, serde_json::to_value(&status).unwrap());
+        let snapshot = String::from_utf8(snapshot.encode()).unwrap();
+        assert!(snapshot.starts_with("{\"schema_version\":1,\"type\":\"snapshot\""));
+        assert_eq!(reader.read_line(&snapshot).unwrap(), status);
+
+        let status = reader.read_line(&delta).unwrap();
+        assert_eq!(status.hostname.as_deref(), Some("nas"));
+        assert_eq!(status.interface.counters.frames_received, 2);
+        assert_eq!(status.interface.counters.frames_sent, 4);
+
+        let newer = snapshot.replace("\"schema_version\":1", "\"schema_version\":2");
+        assert!(reader.read_line(&newer).is_err());
     }
 }
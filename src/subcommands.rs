@@ -0,0 +1,271 @@
+//! The subcommands besides `run`, each doing one thing with a node's interface or config and
+//! exiting.
+
+use anyhow::{anyhow, bail, Result as AHResult};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{check_network, read_file, Network};
+use crate::node::shutdown_signals;
+use crate::overrides::Override;
+use crate::{
+    bench, cli, control, expect, info, inject, pcap, protocols, status, topology, trafficgen, warn,
+};
+
+/// Open the node's interface, inject the frames described in the spec file, and exit.
+pub fn send(network: Network, spec_path: &str) -> AHResult<()> {
+    let hw_address = network.node.ether_address()?;
+    let specs = inject::parse_specs(&read_file(spec_path)?)?;
+
+    let eth = protocols::ether::TapInterface::open(hw_address)?;
+    let if_name = eth.if_name()?;
+    status::update(|s| s.interface.name = Some(if_name));
+    eth.up()?;
+
+    for spec in specs {
+        eth.send(&spec.build(hw_address)?)?;
+    }
+
+    Ok(())
+}
+
+/// Send the streams described in the streams file until they're done or we're told to stop,
+/// then print how much was sent.
+pub fn generate(network: Network, streams_path: &str) -> AHResult<()> {
+    shutdown_signals().thread_block()?;
+
+    let hw_address = network.node.ether_address()?;
+    let config: trafficgen::Config = toml::from_str(&read_file(streams_path)?)?;
+    let generator = trafficgen::Generator::new(&config)?;
+
+    let eth = protocols::ether::TapInterface::open(hw_address)?;
+    let if_name = eth.if_name()?;
+    status::update(|s| s.interface.name = Some(if_name));
+    eth.up()?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            if shutdown_signals().wait().is_ok() {
+                stop.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+
+    let report = generator.run(hw_address, &stop, |frame| eth.send(frame));
+    println!("{}", serde_json::to_string(&report)?);
+
+    Ok(())
+}
+
+/// Broadcast a Wake-on-LAN magic packet for `target` from the node's interface.
+pub fn wake(network: Network, target: &str) -> AHResult<()> {
+    let hw_address = network.node.ether_address()?;
+    let packet = protocols::wol::Packet {
+        target: target.parse()?,
+        password: None,
+    };
+
+    let eth = protocols::ether::TapInterface::open(hw_address)?;
+    eth.up()?;
+    eth.send(&packet.frame(hw_address))
+}
+
+/// How long `ping` waits for each reply, and between requests.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The echo reply `frame` carries, if it's one from `from`.
+fn echo_reply(
+    frame: protocols::ether::Frame,
+    from: protocols::ipv6::Address,
+) -> Option<protocols::ipv6::icmpv6::Echo> {
+    let packet = expect::Packet::decode(frame).ipv6?;
+    if packet.src != from {
+        return None;
+    }
+
+    let pseudo_header = protocols::ipv6::PseudoHeader {
+        src: packet.src,
+        dest: packet.dest,
+        length: packet.payload.len() as u32,
+    };
+    match protocols::ipv6::icmpv6::packet(&packet.payload, pseudo_header) {
+        Ok(protocols::ipv6::icmpv6::Packet::EchoReply(echo)) => Some(echo),
+        _ => None,
+    }
+}
+
+/// Ping `dest` from the node's first IPv6 address, printing each reply.
+pub fn ping(network: Network, options: &cli::Options, dest: &str, count: usize) -> AHResult<()> {
+    let hw_address = network.node.ether_address()?;
+    let dest: protocols::ipv6::ScopedAddress = dest.parse()?;
+    let src = network
+        .node
+        .ipv6_addresses
+        .first()
+        .ok_or_else(|| anyhow!("ping needs an ipv6 address for the node"))?;
+
+    let mut eth = protocols::ether::TapInterface::open(hw_address)?;
+    let if_name = eth.if_name()?;
+    let dest = dest.on(&if_name)?;
+    let neighbors = protocols::neighbor::Table::new(&if_name);
+    status::update(|s| s.interface.name = Some(if_name));
+
+    let mut ipv6_server = protocols::ipv6::Server::new(&mut eth, neighbors)?;
+    let src_address = src.address(hw_address)?;
+    ipv6_server.add_address(src_address, src.lifetimes());
+    ipv6_server.start();
+
+    let replies = eth.observe_handle().observe(1, "icmp6".parse()?);
+    if let Some(path) = &options.capture {
+        pcap::capture(path, &eth.observe_handle())?;
+    }
+    eth.start()?;
+
+    let writer = ipv6_server.writer();
+    let identifier = rand::random();
+    let mut received = 0;
+    for sequence in 0..count as u16 {
+        let sent = std::time::Instant::now();
+        let request = protocols::ipv6::icmpv6::Packet::EchoRequest(protocols::ipv6::icmpv6::Echo {
+            identifier,
+            sequence,
+            data: b"fakenet".to_vec(),
+        });
+        writer.send(
+            protocols::ipv6::Packet::builder()
+                .protocol(protocols::ipv4::ProtocolNumber::Ipv6Icmp)
+                .hop_limit(64)
+                .src(src_address)
+                .dest(dest)
+                .payload(request.encode(protocols::ipv6::PseudoHeader {
+                    src: src_address,
+                    dest,
+                    length: 0,
+                }))
+                .build(),
+        )?;
+
+        while let Ok(frame) = replies.recv_deadline(sent + PING_INTERVAL) {
+            match echo_reply(frame, dest) {
+                Some(echo) if echo.identifier == identifier && echo.sequence == sequence => {
+                    received += 1;
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "from": dest.to_string(),
+                            "sequence": sequence,
+                            "time_ms": sent.elapsed().as_secs_f64() * 1000.0,
+                        })
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    println!(
+        "{}",
+        serde_json::json!({ "transmitted": count, "received": received })
+    );
+    if received == 0 {
+        bail!("no replies from {}", dest);
+    }
+
+    Ok(())
+}
+
+/// Print each frame on the node's interface that matches `filter`, until interrupted.
+pub fn dump(network: Network, options: &cli::Options, filter: &str) -> AHResult<()> {
+    let hw_address = network.node.ether_address()?;
+    let eth = protocols::ether::TapInterface::open(hw_address)?;
+    let if_name = eth.if_name()?;
+    status::update(|s| s.interface.name = Some(if_name));
+
+    let frames = eth.observe_handle().observe(1, filter.parse()?);
+    if let Some(path) = &options.capture {
+        pcap::capture(path, &eth.observe_handle())?;
+    }
+    eth.start()?;
+
+    for frame in frames {
+        let packet = expect::Packet::decode(frame);
+        println!(
+            "{}",
+            serde_json::json!({
+                "summary": packet.summary(),
+                "dscp": packet.ipv6.as_ref().map(|ipv6| ipv6.dscp()),
+                "ecn": packet.ipv6.as_ref().map(|ipv6| ipv6.ecn().to_string()),
+                "frame": hex::encode(packet.frame.encode()),
+            })
+        );
+    }
+
+    Ok(())
+}
+
+/// Report every problem with a config file, without opening anything.
+pub fn validate_config(path: &str, overrides: &[Override]) -> AHResult<()> {
+    let (_, problems) = check_network(&read_file(path)?, overrides);
+    println!(
+        "{}",
+        serde_json::json!({ "valid": problems.is_empty(), "errors": problems })
+    );
+
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Run several nodes joined by switches until told to stop, printing each node's status tagged
+/// with its name.
+pub fn run_topology(path: &str, options: &cli::Options) -> AHResult<()> {
+    shutdown_signals().thread_block()?;
+
+    let config: topology::Config = toml::from_str(&read_file(path)?)?;
+    let node_args = vec!["--log-level".to_string(), options.log_level.to_string()];
+    let topology =
+        topology::Topology::start(&config, &env::current_exe()?, &node_args, |node, line| {
+            match status::parse_line(line) {
+                Ok(mut line) => {
+                    line.node = Some(node.to_string());
+                    println!("{}", serde_json::to_string(&line).unwrap());
+                }
+                Err(e) => warn!("node {} wrote a bad status line: {:#}", node, e),
+            }
+        })?;
+    info!("started {} nodes", config.nodes.len());
+
+    if let Some(path) = &config.control_socket {
+        let mut control_server = control::Server::new();
+        topology.add_control_commands(&mut control_server);
+        control_server.start(path)?;
+    }
+
+    shutdown_signals().wait()?;
+
+    Ok(())
+}
+
+/// Measure the stack's own throughput and latency, without touching any real interfaces.
+pub fn run_bench(packets: usize, payload_len: usize) -> AHResult<()> {
+    let report = bench::run(packets, payload_len)?;
+    println!("{}", serde_json::to_string(&report)?);
+
+    Ok(())
+}
+
+/// Time encoding and parsing, checking each against its golden encoding first.
+pub fn run_micro_bench(iterations: u32) -> AHResult<()> {
+    for measurement in bench::micro::run(iterations)? {
+        println!("{}", serde_json::to_string(&measurement)?);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,226 @@
+//! Restarting protocol actors that panic, rather than leaving the node running without them, after
+//! recording what they were handling at the time.
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::status::{self, EventKind, EventStatus};
+use crate::warn;
+
+thread_local! {
+    /// What the current thread is handling, kept to report if it panics.
+    static INPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Note that the calling thread is about to handle `input`, e.g. a received packet.
+pub fn handling(input: &[u8]) {
+    INPUT.with(|i| {
+        let mut i = i.borrow_mut();
+        i.clear();
+        i.extend_from_slice(input);
+    });
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Policy {
+    /// Restarts in a row before an actor is left stopped.
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    /// Seconds an actor has to run without panicking for its earlier restarts to be forgotten.
+    #[serde(default = "default_healthy_after")]
+    pub healthy_after: f64,
+    /// Seconds to wait before the first restart, doubling each time after.
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff: f64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: f64,
+}
+
+fn default_max_restarts() -> u32 {
+    5
+}
+
+fn default_healthy_after() -> f64 {
+    60.0
+}
+
+fn default_initial_backoff() -> f64 {
+    0.1
+}
+
+fn default_max_backoff() -> f64 {
+    10.0
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            max_restarts: default_max_restarts(),
+            healthy_after: default_healthy_after(),
+            initial_backoff: default_initial_backoff(),
+            max_backoff: default_max_backoff(),
+        }
+    }
+}
+
+impl Policy {
+    /// Whether running for `up_for` without a panic wipes the slate clean.
+    pub fn is_healthy(&self, up_for: Duration) -> bool {
+        up_for.as_secs_f64() >= self.healthy_after
+    }
+
+    fn backoff(&self, restarts: u32) -> Duration {
+        let backoff = self.initial_backoff * 2f64.powi(restarts.min(64) as i32);
+
+        Duration::from_secs_f64(backoff.min(self.max_backoff))
+    }
+}
+
+lazy_static! {
+    static ref POLICY: RwLock<Policy> = RwLock::new(Policy::default());
+}
+
+/// Policy for actors that panic from now on.
+pub fn set_policy(policy: Policy) {
+    *POLICY.write().unwrap() = policy;
+}
+
+pub fn policy() -> Policy {
+    POLICY.read().unwrap().clone()
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Record in status that `name` panicked with `payload`, with what it was handling.
+pub fn report_panic(name: &str, payload: &(dyn Any + Send), restarting: bool) {
+    let input = INPUT.with(|i| i.borrow().clone());
+    status::update(|s| {
+        s.push_event(EventStatus {
+            input: (!input.is_empty()).then(|| hex::encode(&input)),
+            restarting,
+            ..EventStatus::new(EventKind::Panic, name, panic_message(payload))
+        })
+    });
+}
+
+/// Run `actor` until it returns, restarting it whenever it panics until the policy gives up.
+pub fn supervise(name: &str, actor: impl FnMut()) {
+    supervise_with(name, actor, policy)
+}
+
+fn supervise_with(name: &str, mut actor: impl FnMut(), policy: impl Fn() -> Policy) {
+    let mut restarts = 0;
+
+    loop {
+        handling(&[]);
+        let started = Instant::now();
+        let payload = match panic::catch_unwind(AssertUnwindSafe(&mut actor)) {
+            Ok(()) => return,
+            Err(payload) => payload,
+        };
+
+        let policy = policy();
+        if policy.is_healthy(started.elapsed()) {
+            restarts = 0;
+        }
+        let restarting = restarts < policy.max_restarts;
+        report_panic(name, &*payload, restarting);
+
+        if !restarting {
+            warn!(
+                "{} panicked {} times; leaving it stopped",
+                name,
+                restarts + 1
+            );
+            return;
+        }
+        thread::sleep(policy.backoff(restarts));
+        restarts += 1;
+        warn!("restarting {} after a panic", name);
+    }
+}
+
+/// Start `actor` on a new thread, supervised.
+pub fn spawn(name: impl Into<String>, actor: impl FnMut() + Send + 'static) {
+    let name = name.into();
+    thread::spawn(move || supervise(&name, actor));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let policy = Policy::default();
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3), Duration::from_millis(800));
+        assert_eq!(policy.backoff(1000), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn restarts_are_forgotten_after_a_healthy_run() {
+        let policy = || Policy {
+            max_restarts: 1,
+            healthy_after: 0.05,
+            initial_backoff: 0.0,
+            max_backoff: 0.0,
+        };
+        let runs_until_stopped = |up_for: Duration| {
+            let mut runs = 0;
+            supervise_with(
+                "supervisor-restart-test",
+                || {
+                    runs += 1;
+                    if runs < 3 {
+                        thread::sleep(up_for);
+                        panic!("flaky");
+                    }
+                },
+                policy,
+            );
+
+            runs
+        };
+
+        assert_eq!(runs_until_stopped(Duration::ZERO), 2);
+        assert_eq!(runs_until_stopped(Duration::from_millis(60)), 3);
+    }
+
+    #[test]
+    fn panics_are_recorded_with_their_input() {
+        let mut runs = 0;
+        supervise("supervisor-test", || {
+            runs += 1;
+            if runs == 1 {
+                handling(&[0xde, 0xad]);
+                panic!("bad packet");
+            }
+        });
+        assert_eq!(runs, 2);
+
+        let events = status::snapshot().events;
+        let event = events
+            .iter()
+            .find(|e| e.thread == "supervisor-test")
+            .unwrap();
+        assert_eq!(event.message, "bad packet");
+        assert_eq!(event.input.as_deref(), Some("dead"));
+        assert!(event.restarting);
+    }
+}
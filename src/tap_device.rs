@@ -15,7 +15,7 @@
 //! Bindings to internal Linux stuff.
 #![allow(dead_code)]
 
-mod tun_sys {
+pub(crate) mod tun_sys {
     use libc::sockaddr;
     use libc::{c_char, c_int, c_short, c_uchar, c_uint, c_ulong, c_ushort, c_void};
     use nix::{ioctl_read_bad, ioctl_write_ptr, ioctl_write_ptr_bad};
@@ -31,7 +31,7 @@ mod tun_sys {
     const IFF_TUN: c_short = 0x0001;
     pub const IFF_TAP: c_short = 0x0002;
     pub const IFF_NO_PI: c_short = 0x1000;
-    const IFF_MULTI_QUEUE: c_short = 0x0100;
+    pub const IFF_MULTI_QUEUE: c_short = 0x0100;
 
     #[repr(C)]
     #[derive(Copy, Clone)]
@@ -109,7 +109,8 @@ mod tun_sys {
     ioctl_read_bad!(siocgifmtu, 0x8921, IfReq);
     ioctl_write_ptr_bad!(siocsifmtu, 0x8922, IfReq);
     ioctl_write_ptr_bad!(siocsifname, 0x8923, IfReq);
-    ioctl_write_ptr_bad!(siocgifhwaddr, 0x8927, IfReq);
+    ioctl_write_ptr_bad!(siocsifhwaddr, 0x8924, IfReq);
+    ioctl_read_bad!(siocgifhwaddr, 0x8927, IfReq);
 
     ioctl_write_ptr_bad!(
         tunsetiff,
@@ -129,33 +130,59 @@ use std::io;
 use std::io::{Read, Write};
 use std::mem;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::SystemTime;
+
+use crate::device::{Device, FRAME_SIZE};
+use crate::warn;
 
 pub struct TapDevice {
     ctl_sock_fd: RawFd,
     file: File,
+    /// Queues after the first, when opened with more than one.
+    extra_queues: Vec<File>,
     if_name_chars: Vec<c_char>,
     buffer: Vec<u8>,
+    down_on_drop: bool,
 }
 
-impl TapDevice {
-    pub const FRAME_SIZE: usize = 1514;
+/// Attach a new queue to the tap named `if_name_chars`, or create a tap if it's empty.
+fn open_queue(flags: libc::c_short, if_name_chars: &mut Vec<c_char>) -> AHResult<File> {
+    let dev_tap = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")?;
+
+    unsafe {
+        let mut ifr: tun_sys::IfReq = mem::zeroed();
+        ifr.ifrn.name[..if_name_chars.len()].copy_from_slice(if_name_chars);
+        ifr.ifru.flags = flags;
+        tun_sys::tunsetiff(dev_tap.as_raw_fd(), &ifr)?;
+        *if_name_chars = Vec::from(ifr.ifrn.name);
+        if_name_chars.truncate(libc::strlen(if_name_chars.as_ptr()) + 1);
+    }
+
+    Ok(dev_tap)
+}
 
+impl TapDevice {
     pub fn open() -> AHResult<Self> {
-        let dev_tap = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("/dev/net/tun")?;
-        let dev_tap_fd = dev_tap.as_raw_fd();
+        Self::open_with_queues(1)
+    }
 
-        let mut if_name_chars;
-        unsafe {
-            let mut ifr: tun_sys::IfReq = mem::zeroed();
-            ifr.ifru.flags = tun_sys::IFF_TAP | tun_sys::IFF_NO_PI;
-            tun_sys::tunsetiff(dev_tap_fd, &ifr)?;
-            if_name_chars = Vec::from(ifr.ifrn.name);
-            if_name_chars.truncate(libc::strlen(if_name_chars.as_ptr()) + 1);
+    /// Open a tap with `queues` file descriptors, which the kernel spreads received frames over
+    /// by flow.
+    pub fn open_with_queues(queues: usize) -> AHResult<Self> {
+        let mut flags = tun_sys::IFF_TAP | tun_sys::IFF_NO_PI;
+        if queues > 1 {
+            flags |= tun_sys::IFF_MULTI_QUEUE;
         }
 
+        let mut if_name_chars = Vec::new();
+        let dev_tap = open_queue(flags, &mut if_name_chars)?;
+        let extra_queues = (1..queues)
+            .map(|_| open_queue(flags, &mut if_name_chars))
+            .collect::<AHResult<_>>()?;
+
         let ctl_sock_fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
 
         if ctl_sock_fd == -1 {
@@ -164,16 +191,18 @@ impl TapDevice {
 
         let tap = Self {
             file: dev_tap,
+            extra_queues,
             if_name_chars,
             ctl_sock_fd,
             buffer: Vec::new(),
+            down_on_drop: true,
         };
 
         unsafe {
             let mut mtu_ifr = tap.new_ifreq()?;
 
-            mtu_ifr.ifru.mtu = Self::FRAME_SIZE as i32 - 6 - 6 - 2;
-            tun_sys::siocsifmtu(ctl_sock_fd, &mut mtu_ifr)?;
+            mtu_ifr.ifru.mtu = FRAME_SIZE as i32 - 6 - 6 - 2;
+            tun_sys::siocsifmtu(ctl_sock_fd, &mtu_ifr)?;
         }
 
         Ok(tap)
@@ -187,11 +216,25 @@ impl TapDevice {
         Ok(ifr)
     }
 
-    pub fn up(&mut self) -> AHResult<()> {
+    /// Take the interface down and close it, reporting errors that dropping it would ignore.
+    pub fn close(mut self) -> AHResult<()> {
+        self.down()?;
+        self.down_on_drop = false;
+
+        Ok(())
+    }
+}
+
+impl Device for TapDevice {
+    fn up(&mut self) -> AHResult<()> {
         unsafe {
             let mut flags_ifr = self.new_ifreq()?;
 
             tun_sys::siocgifflags(self.ctl_sock_fd, &mut flags_ifr)?;
+            // Setting flags needs privileges even when nothing changes, which might be gone.
+            if flags_ifr.ifru.flags & tun_sys::IFF_UP != 0 {
+                return Ok(());
+            }
             flags_ifr.ifru.flags |= tun_sys::IFF_UP;
             tun_sys::siocsifflags(self.ctl_sock_fd, &flags_ifr)?;
         }
@@ -199,14 +242,29 @@ impl TapDevice {
         Ok(())
     }
 
-    pub fn if_name(&self) -> AHResult<String> {
+    fn down(&mut self) -> AHResult<()> {
+        unsafe {
+            let mut flags_ifr = self.new_ifreq()?;
+
+            tun_sys::siocgifflags(self.ctl_sock_fd, &mut flags_ifr)?;
+            if flags_ifr.ifru.flags & tun_sys::IFF_UP == 0 {
+                return Ok(());
+            }
+            flags_ifr.ifru.flags &= !tun_sys::IFF_UP;
+            tun_sys::siocsifflags(self.ctl_sock_fd, &flags_ifr)?;
+        }
+
+        Ok(())
+    }
+
+    fn if_name(&self) -> AHResult<String> {
         let if_name_bytes: Vec<u8> = self.if_name_chars.iter().map(|x| *x as u8).collect();
         Ok(CStr::from_bytes_with_nul(&if_name_bytes)?
             .to_str()?
             .to_string())
     }
 
-    pub fn if_hwaddr(&self) -> AHResult<[u8; 6]> {
+    fn if_hwaddr(&self) -> AHResult<[u8; 6]> {
         unsafe {
             let mut flags_ifr = self.new_ifreq()?;
 
@@ -229,15 +287,73 @@ impl TapDevice {
         }
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> AHResult<usize> {
-        Ok(self.file.read(buf)?)
+    fn set_if_hwaddr(&mut self, address: [u8; 6]) -> AHResult<()> {
+        unsafe {
+            let mut hwaddr_ifr = self.new_ifreq()?;
+
+            hwaddr_ifr.ifru.hwaddr.sa_family = tun_sys::ARPHRD_ETHER;
+            for (i, byte) in address.iter().enumerate() {
+                hwaddr_ifr.ifru.hwaddr.sa_data[i] = *byte as c_char;
+            }
+            tun_sys::siocsifhwaddr(self.ctl_sock_fd, &hwaddr_ifr)?;
+        }
+
+        let set = self.if_hwaddr()?;
+        if set != address {
+            bail!(
+                "kernel kept hardware address {:02x?} instead of {:02x?}",
+                set,
+                address
+            );
+        }
+
+        Ok(())
+    }
+
+    fn read_frames(&mut self, f: &mut dyn FnMut(&[u8], SystemTime)) -> AHResult<()> {
+        self.buffer.resize(FRAME_SIZE, 0);
+        let num_read = self.file.read(&mut self.buffer)?;
+        // Taps don't timestamp what they queue.
+        f(&self.buffer[..num_read], SystemTime::now());
+
+        Ok(())
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> AHResult<()> {
+    fn write(&mut self, buf: &[u8]) -> AHResult<()> {
         Ok(self.file.write_all(buf)?)
     }
 
-    pub fn rawfd(&self) -> RawFd {
+    fn rawfd(&self) -> RawFd {
         self.file.as_raw_fd()
     }
+
+    fn extra_queues(&self) -> AHResult<Vec<File>> {
+        Ok(self
+            .extra_queues
+            .iter()
+            .map(File::try_clone)
+            .collect::<io::Result<_>>()?)
+    }
+
+    fn set_down_on_drop(&mut self, down_on_drop: bool) {
+        self.down_on_drop = down_on_drop;
+    }
+
+    fn down_on_drop(&self) -> bool {
+        self.down_on_drop
+    }
+}
+
+impl Drop for TapDevice {
+    fn drop(&mut self) {
+        if self.down_on_drop {
+            if let Err(e) = self.down() {
+                warn!("taking tap down failed: {}", e);
+            }
+        }
+
+        unsafe {
+            libc::close(self.ctl_sock_fd);
+        }
+    }
 }
@@ -0,0 +1,730 @@
+//! Several nodes joined by switches in one file: each switch is a bridge, links between switches
+//! are socket pairs, and each node runs in its own fakenet process attached to its switch over a
+//! Unix socket.
+//!
+//! Routers aren't supported yet, as nothing in the stack forwards packets between interfaces;
+//! they're left for a follow-up, and `[[routers]]` is rejected until then.
+
+use anyhow::{anyhow, bail, Context, Result as AHResult};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::net::Ipv4Addr;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::control;
+use crate::protocols::{bridge, ipv4};
+use crate::socket_device::SocketDevice;
+use crate::{resources, warn};
+
+/// How long a node gets to connect to its switch after starting.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub switches: Vec<Switch>,
+    #[serde(default)]
+    pub links: Vec<Link>,
+    #[serde(default)]
+    pub nodes: Vec<Node>,
+    /// Nodes that can be spawned any number of times while running, through the control socket.
+    #[serde(default)]
+    pub templates: Vec<Template>,
+    /// Path to listen for control commands on.
+    pub control_socket: Option<String>,
+    /// Where to put the sockets joining nodes to switches; a new temporary directory when unset.
+    pub socket_dir: Option<String>,
+    /// Only read to say they aren't supported.
+    #[serde(default)]
+    routers: Vec<IgnoredAny>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Switch {
+    pub name: String,
+    /// Seconds before forgetting where an address was seen.
+    #[serde(default = "default_aging")]
+    pub aging: f64,
+    /// Flood multicast to every port, like a switch without MLD and IGMP snooping.
+    #[serde(default)]
+    pub flood_multicast: bool,
+}
+
+fn default_aging() -> f64 {
+    bridge::DEFAULT_AGING.as_secs_f64()
+}
+
+/// Applied to frames in both directions.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct Impairments {
+    /// Seconds to hold back each frame.
+    #[serde(default)]
+    pub delay: f64,
+    /// Chance, from 0 to 1, of dropping each frame.
+    #[serde(default)]
+    pub loss: f64,
+}
+
+impl Impairments {
+    fn validate(&self) -> AHResult<()> {
+        if !(0.0..=1.0).contains(&self.loss) {
+            bail!("loss must be between 0 and 1, not {}", self.loss);
+        }
+        if !(self.delay.is_finite() && self.delay >= 0.0) {
+            bail!("delay must be 0 or more seconds, not {}", self.delay);
+        }
+
+        Ok(())
+    }
+
+    fn bridge(&self) -> bridge::Impairments {
+        bridge::Impairments {
+            delay: Duration::from_secs_f64(self.delay),
+            loss: self.loss,
+        }
+    }
+}
+
+/// A cable between two switches.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Link {
+    pub between: [String; 2],
+    #[serde(flatten)]
+    pub impairments: Impairments,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Node {
+    pub name: String,
+    /// Path to the node's own config, as given to `fakenet run`.
+    pub config: String,
+    /// Switch the node is plugged into.
+    pub switch: String,
+    /// Config overrides, as given to `--set`.
+    #[serde(default)]
+    pub set: Vec<String>,
+    #[serde(flatten)]
+    pub impairments: Impairments,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Template {
+    /// Instances are named after it, like `sensor-1`.
+    pub name: String,
+    pub config: String,
+    pub switch: String,
+    #[serde(default)]
+    pub set: Vec<String>,
+    /// IPv4 prefix to give instances addresses from, when the spawn command doesn't give one.
+    pub prefix: Option<String>,
+    /// Setting each instance's address is put in.
+    #[serde(default = "default_address_setting")]
+    pub address_setting: String,
+    #[serde(flatten)]
+    pub impairments: Impairments,
+}
+
+fn default_address_setting() -> String {
+    "node.ipv4_address".to_string()
+}
+
+/// Node names must be usable in socket paths.
+fn check_name(name: &str) -> AHResult<()> {
+    if name.is_empty() || name.contains('/') {
+        bail!("invalid node name \"{}\"", name);
+    }
+
+    Ok(())
+}
+
+/// Every host address in `prefix`, leaving out the network and broadcast addresses of prefixes
+/// that have them.
+fn hosts(prefix: &str) -> AHResult<impl Iterator<Item = ipv4::Address>> {
+    let (address, length) = ipv4::parse_prefix(prefix)?;
+    let network = u32::from_be_bytes(address.prefix(length as usize).0);
+    let last = network | (!0u32).checked_shr(length as u32).unwrap_or(0);
+    let range = if length >= 31 {
+        network..=last
+    } else {
+        network + 1..=last - 1
+    };
+
+    Ok(range.map(|bits| ipv4::Address(bits.to_be_bytes())))
+}
+
+impl Config {
+    pub fn validate(&self) -> AHResult<()> {
+        if !self.routers.is_empty() {
+            bail!(
+                "routers aren't supported yet, as nothing forwards packets between interfaces; \
+                 join switches with [[links]] instead"
+            );
+        }
+
+        let mut switches = HashSet::new();
+        for switch in &self.switches {
+            if !switches.insert(switch.name.as_str()) {
+                bail!("there's more than one switch named {}", switch.name);
+            }
+            // Addresses are expired every half aging time, which mustn't be a busy loop.
+            if !(switch.aging.is_finite() && switch.aging > 0.0) {
+                bail!(
+                    "switch {}: aging must be more than 0 seconds, not {}",
+                    switch.name,
+                    switch.aging
+                );
+            }
+        }
+        let find_switch = |name: &str| {
+            if switches.contains(name) {
+                Ok(())
+            } else {
+                Err(anyhow!("there's no switch named {}", name))
+            }
+        };
+
+        for link in &self.links {
+            let [a, b] = &link.between;
+            find_switch(a)?;
+            find_switch(b)?;
+            if a == b {
+                bail!("switch {} can't be linked to itself", a);
+            }
+            link.impairments
+                .validate()
+                .with_context(|| format!("link between {} and {}", a, b))?;
+        }
+
+        let mut nodes = HashSet::new();
+        for node in &self.nodes {
+            check_name(&node.name)?;
+            if !nodes.insert(node.name.as_str()) {
+                bail!("there's more than one node named {}", node.name);
+            }
+            find_switch(&node.switch)?;
+            node.impairments
+                .validate()
+                .with_context(|| format!("node {}", node.name))?;
+        }
+
+        let mut templates = HashSet::new();
+        for template in &self.templates {
+            check_name(&template.name)?;
+            if !templates.insert(template.name.as_str()) {
+                bail!("there's more than one template named {}", template.name);
+            }
+            find_switch(&template.switch)?;
+            template
+                .impairments
+                .validate()
+                .with_context(|| format!("template {}", template.name))?;
+            if let Some(prefix) = &template.prefix {
+                ipv4::parse_prefix(prefix)
+                    .with_context(|| format!("template {}", template.name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Arguments to run `node` attached to a switch at `socket_path`.
+    fn node_args(node: &Node, socket_path: &Path, extra: &[String]) -> Vec<String> {
+        let mut args = extra.to_vec();
+        args.extend(vec![
+            "--set".to_string(),
+            format!("node.socket.path={}", socket_path.display()),
+            "--set".to_string(),
+            "node.socket.listen=false".to_string(),
+        ]);
+        for o in &node.set {
+            args.extend(vec!["--set".to_string(), o.clone()]);
+        }
+        args.extend(vec!["run".to_string(), node.config.clone()]);
+
+        args
+    }
+}
+
+/// Wait for `child` to connect to `listener`, giving up if it exits first.
+fn accept_from(listener: &UnixListener, child: &mut Child) -> AHResult<UnixStream> {
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if let Some(status) = child.try_wait()? {
+            bail!("exited with {} before connecting", status);
+        }
+        if Instant::now() > deadline {
+            bail!("didn't connect within {:?}", CONNECT_TIMEOUT);
+        }
+        thread::sleep(CONNECT_POLL_INTERVAL);
+    }
+}
+
+type StatusHandler = Arc<dyn Fn(&str, &str) + Send + Sync>;
+
+/// A node process and where it's plugged in.
+struct Running {
+    name: String,
+    /// What it was spawned from, if it wasn't in the config.
+    template: Option<String>,
+    address: Option<ipv4::Address>,
+    switch: String,
+    port: usize,
+    child: Child,
+}
+
+/// Stop a node process, waiting for it to exit.
+fn stop(name: &str, child: &mut Child) {
+    if let Ok(None) = child.try_wait() {
+        let _ = kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM);
+    }
+    if let Err(e) = child.wait() {
+        warn!("waiting for node {} failed: {}", name, e);
+    }
+}
+
+struct Nodes {
+    exe: PathBuf,
+    node_args: Vec<String>,
+    socket_dir: PathBuf,
+    switches: HashMap<String, bridge::Handle>,
+    templates: Vec<Template>,
+    running: Vec<Running>,
+    /// Instances spawned from each template so far, for naming the next.
+    spawned: HashMap<String, usize>,
+    on_status: StatusHandler,
+}
+
+impl Nodes {
+    /// Start `node` and plug it into its switch.
+    fn start(
+        &mut self,
+        node: &Node,
+        template: Option<&str>,
+        address: Option<ipv4::Address>,
+    ) -> AHResult<()> {
+        let socket_path = self.socket_dir.join(format!("{}.sock", node.name));
+        let _ = fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path)
+            .with_context(|| format!("binding {}", socket_path.display()))?;
+
+        let mut child = Command::new(&self.exe)
+            .args(Config::node_args(node, &socket_path, &self.node_args))
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("starting node {}", node.name))?;
+        let stdout = child.stdout.take().unwrap();
+        let stream = match accept_from(&listener, &mut child) {
+            Ok(stream) => stream,
+            Err(e) => {
+                stop(&node.name, &mut child);
+                return Err(e.context(format!("node {}", node.name)));
+            }
+        };
+
+        let port = self.switches[&node.switch].add_port(
+            Box::new(SocketDevice::from_stream(node.name.clone(), stream)),
+            node.impairments.bridge(),
+        )?;
+        self.running.push(Running {
+            name: node.name.clone(),
+            template: template.map(str::to_string),
+            address,
+            switch: node.switch.clone(),
+            port,
+            child,
+        });
+
+        let name = node.name.clone();
+        let on_status = Arc::clone(&self.on_status);
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => on_status(&name, &line),
+                    Err(e) => {
+                        warn!("reading status of node {} failed: {}", name, e);
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn spawn(
+        &mut self,
+        template: &str,
+        count: usize,
+        prefix: Option<&str>,
+    ) -> AHResult<Vec<String>> {
+        let template = self
+            .templates
+            .iter()
+            .find(|t| t.name == template)
+            .ok_or_else(|| anyhow!("there's no template named {}", template))?
+            .clone();
+
+        let addresses: Vec<Option<ipv4::Address>> = match prefix.or(template.prefix.as_deref()) {
+            Some(prefix) => {
+                let used: HashSet<_> = self.running.iter().filter_map(|r| r.address).collect();
+                let free: Vec<_> = hosts(prefix)?
+                    .filter(|a| !used.contains(a))
+                    .take(count)
+                    .map(Some)
+                    .collect();
+                if free.len() < count {
+                    bail!("only {} addresses are free in {}", free.len(), prefix);
+                }
+
+                free
+            }
+            None => vec![None; count],
+        };
+
+        let mut names = Vec::new();
+        for address in addresses {
+            let spawned = self.spawned.entry(template.name.clone()).or_default();
+            let name = loop {
+                *spawned += 1;
+                let name = format!("{}-{}", template.name, spawned);
+                if !self.running.iter().any(|r| r.name == name) {
+                    break name;
+                }
+            };
+
+            let mut set = template.set.clone();
+            if let Some(address) = address {
+                set.push(format!(
+                    "{}={}",
+                    template.address_setting,
+                    Ipv4Addr::from(address)
+                ));
+            }
+            let node = Node {
+                name: name.clone(),
+                config: template.config.clone(),
+                switch: template.switch.clone(),
+                set,
+                impairments: template.impairments,
+            };
+
+            self.start(&node, Some(&template.name), address)?;
+            names.push(name);
+        }
+
+        Ok(names)
+    }
+
+    /// Stop spawned nodes: the one named `name`, or the newest `count` (or all) spawned from the
+    /// template named `name`.
+    fn despawn(&mut self, name: &str, count: Option<usize>) -> AHResult<Vec<String>> {
+        let mut chosen: Vec<usize> = match self.running.iter().position(|r| r.name == name) {
+            Some(i) if count.is_none() => {
+                if self.running[i].template.is_none() {
+                    bail!("node {} is from the config, not spawned", name);
+                }
+                vec![i]
+            }
+            _ => {
+                if !self.templates.iter().any(|t| t.name == name) {
+                    bail!("there's no spawned node or template named {}", name);
+                }
+                let from_template = (0..self.running.len())
+                    .rev()
+                    .filter(|&i| self.running[i].template.as_deref() == Some(name));
+
+                from_template.take(count.unwrap_or(usize::MAX)).collect()
+            }
+        };
+
+        chosen.sort_unstable();
+        let mut names = Vec::new();
+        for i in chosen.into_iter().rev() {
+            let mut running = self.running.remove(i);
+            self.switches[&running.switch].remove_port(running.port);
+            stop(&running.name, &mut running.child);
+            names.push(running.name);
+        }
+
+        Ok(names)
+    }
+
+    fn list(&self) -> serde_json::Value {
+        self.running
+            .iter()
+            .map(|r| {
+                // Gone if the node has exited.
+                let (memory_bytes, threads) = resources::process_usage(r.child.id())
+                    .map_or((None, None), |(memory, threads)| {
+                        (Some(memory), Some(threads))
+                    });
+
+                json!({
+                    "name": r.name,
+                    "template": r.template,
+                    "address": r.address.map(|a| Ipv4Addr::from(a).to_string()),
+                    "switch": r.switch,
+                    "memory_bytes": memory_bytes,
+                    "threads": threads,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A running topology; its nodes are stopped when dropped.
+pub struct Topology {
+    nodes: Arc<Mutex<Nodes>>,
+    /// Removed when dropped, if we made it.
+    temp_dir: Option<PathBuf>,
+}
+
+impl Topology {
+    /// Start every switch and node, running nodes with `exe` and `node_args` before the
+    /// subcommand. Each line of each node's status is passed to `on_status` with the node's name.
+    pub fn start(
+        config: &Config,
+        exe: &Path,
+        node_args: &[String],
+        on_status: impl Fn(&str, &str) + Send + Sync + 'static,
+    ) -> AHResult<Self> {
+        config.validate()?;
+
+        let mut bridges: HashMap<&str, bridge::Bridge> = config
+            .switches
+            .iter()
+            .map(|s| {
+                let mut bridge = bridge::Bridge::new(Duration::from_secs_f64(s.aging));
+                bridge.set_snooping(!s.flood_multicast);
+
+                (s.name.as_str(), bridge)
+            })
+            .collect();
+
+        for link in &config.links {
+            let [a, b] = &link.between;
+            let (a_end, b_end) = UnixStream::pair()?;
+            let impairments = link.impairments.bridge();
+            bridges.get_mut(a.as_str()).unwrap().add_port(
+                Box::new(SocketDevice::from_stream(format!("link to {}", b), a_end)),
+                impairments,
+            );
+            bridges.get_mut(b.as_str()).unwrap().add_port(
+                Box::new(SocketDevice::from_stream(format!("link to {}", a), b_end)),
+                impairments,
+            );
+        }
+
+        let (socket_dir, temp_dir) = match &config.socket_dir {
+            Some(dir) => (PathBuf::from(dir), None),
+            None => {
+                let dir =
+                    std::env::temp_dir().join(format!("fakenet-topology-{}", std::process::id()));
+                (dir.clone(), Some(dir))
+            }
+        };
+        fs::create_dir_all(&socket_dir)
+            .with_context(|| format!("creating {}", socket_dir.display()))?;
+
+        let mut switches = HashMap::new();
+        for (name, bridge) in bridges {
+            switches.insert(name.to_string(), bridge.start()?);
+        }
+
+        // Nodes started so far are stopped if a later one fails.
+        let topology = Self {
+            nodes: Arc::new(Mutex::new(Nodes {
+                exe: exe.to_path_buf(),
+                node_args: node_args.to_vec(),
+                socket_dir,
+                switches,
+                templates: config.templates.clone(),
+                running: Vec::new(),
+                spawned: HashMap::new(),
+                on_status: Arc::new(on_status),
+            })),
+            temp_dir,
+        };
+        for node in &config.nodes {
+            topology.nodes.lock().unwrap().start(node, None, None)?;
+        }
+
+        Ok(topology)
+    }
+
+    /// Add commands to spawn and despawn nodes from templates, and list running nodes.
+    pub fn add_control_commands(&self, server: &mut control::Server) {
+        let nodes = Arc::clone(&self.nodes);
+        server.add("spawn", move |args| {
+            let (template, count, prefix) = match args {
+                [template, count] => (template, count.parse()?, None),
+                [template, count, prefix] => (template, count.parse()?, Some(*prefix)),
+                _ => bail!("usage: spawn TEMPLATE COUNT [IPV4_PREFIX]"),
+            };
+
+            Ok(json!(nodes
+                .lock()
+                .unwrap()
+                .spawn(template, count, prefix)?))
+        });
+
+        let nodes = Arc::clone(&self.nodes);
+        server.add("despawn", move |args| {
+            let (name, count) = match args {
+                [name] => (name, None),
+                [template, count] => (template, Some(count.parse()?)),
+                _ => bail!("usage: despawn NODE | despawn TEMPLATE [COUNT]"),
+            };
+
+            Ok(json!(nodes.lock().unwrap().despawn(name, count)?))
+        });
+
+        let nodes = Arc::clone(&self.nodes);
+        server.add("show nodes", move |_| Ok(nodes.lock().unwrap().list()));
+    }
+}
+
+impl Drop for Topology {
+    fn drop(&mut self) {
+        let mut nodes = self.nodes.lock().unwrap();
+        for running in &mut nodes.running {
+            stop(&running.name, &mut running.child);
+        }
+        nodes.running.clear();
+
+        if let Some(dir) = &self.temp_dir {
+            if let Err(e) = fs::remove_dir_all(dir) {
+                warn!("removing {} failed: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+                [[switches]]
+                name = "lan"
+
+                [[switches]]
+                name = "wan"
+
+                [[links]]
+                between = ["lan", "wan"]
+                delay = 0.05
+
+                [[nodes]]
+                name = "server"
+                config = "server.toml"
+                switch = "wan"
+                set = ["node.ipv4_address=192.0.2.1"]
+
+                [[templates]]
+                name = "sensor"
+                config = "sensor.toml"
+                switch = "lan"
+                prefix = "10.0.5.0/24"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn references_are_checked() {
+        assert!(config().validate().is_ok());
+
+        let mut missing_switch = config();
+        missing_switch.nodes[0].switch = "dmz".to_string();
+        assert!(missing_switch.validate().is_err());
+
+        let mut loop_link = config();
+        loop_link.links[0].between[1] = "lan".to_string();
+        assert!(loop_link.validate().is_err());
+
+        let mut duplicate = config();
+        duplicate.nodes.push(duplicate.nodes[0].clone());
+        assert!(duplicate.validate().is_err());
+
+        let routers: Config = toml::from_str("[[routers]]\nname = \"gw\"").unwrap();
+        assert!(routers
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("routers aren't supported"));
+
+        let mut duplicate_template = config();
+        duplicate_template
+            .templates
+            .push(duplicate_template.templates[0].clone());
+        assert!(duplicate_template.validate().is_err());
+
+        let mut bad_prefix = config();
+        bad_prefix.templates[0].prefix = Some("10.0.5.0/33".to_string());
+        assert!(bad_prefix.validate().is_err());
+
+        let mut bad_aging = config();
+        bad_aging.switches[0].aging = -1.0;
+        assert!(bad_aging.validate().is_err());
+
+        let mut bad_delay = config();
+        bad_delay.links[0].impairments.delay = f64::NAN;
+        assert!(bad_delay.validate().is_err());
+    }
+
+    #[test]
+    fn instances_get_host_addresses() {
+        let addresses: Vec<_> = hosts("10.0.5.9/30")
+            .unwrap()
+            .map(|a| Ipv4Addr::from(a).to_string())
+            .collect();
+        assert_eq!(addresses, ["10.0.5.9", "10.0.5.10"]);
+
+        assert_eq!(hosts("10.0.5.9/32").unwrap().count(), 1);
+        assert_eq!(hosts("10.0.5.0/24").unwrap().count(), 254);
+    }
+
+    #[test]
+    fn nodes_are_pointed_at_their_switch() {
+        let config = config();
+        let args = Config::node_args(
+            &config.nodes[0],
+            Path::new("/tmp/fakenet/server.sock"),
+            &["--log-level".to_string(), "info".to_string()],
+        );
+
+        assert_eq!(
+            args.join(" "),
+            "--log-level info --set node.socket.path=/tmp/fakenet/server.sock \
+             --set node.socket.listen=false --set node.ipv4_address=192.0.2.1 run server.toml"
+        );
+    }
+}
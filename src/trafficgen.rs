@@ -0,0 +1,606 @@
+//! Streams of generated traffic at fixed rates, for load testing switches and intrusion detection
+//! systems: ARP request and neighbor solicitation floods, and UDP with a spread of frame sizes.
+//!
+//! A streams file looks like:
+//!
+//! ```toml
+//! duration = 10
+//!
+//! [[streams]]
+//! type = "arp_requests"
+//! rate = 1000
+//! sender = "192.0.2.1"
+//! targets = "192.0.2.0/24"
+//! random_sources = true
+//!
+//! [[streams]]
+//! type = "udp"
+//! rate = 5000
+//! source = "2001:db8::1"
+//! dest = "2001:db8::2"
+//! dest_ether = "02:00:00:00:00:02"
+//! sizes = [{ size = 64, weight = 7 }, { size = 1514 }]
+//! ```
+//!
+//! Targets are walked in order through the prefix, wrapping around at its end.
+
+use anyhow::{anyhow, bail, Context, Result as AHResult};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::protocols::ipv6::icmpv6;
+use crate::protocols::{arp, ether, ipv4, ipv6, udp};
+
+/// Ether, IPv6 and UDP headers, which every UDP frame needs room for.
+const UDP_OVERHEAD: usize = 14 + 40 + 8;
+const MAX_FRAME_SIZE: usize = 1514;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Seconds to generate for; until every stream runs out, or forever, when unset.
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub streams: Vec<Stream>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Stream {
+    /// Frames per second.
+    pub rate: f64,
+    /// Frames to send before stopping; unlimited when unset.
+    pub count: Option<u64>,
+    #[serde(flatten)]
+    pub kind: Kind,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Kind {
+    ArpRequests {
+        sender: String,
+        /// Prefix of addresses to ask for.
+        targets: String,
+        /// Send each from a new random ether address, to fill switches' address tables.
+        #[serde(default)]
+        random_sources: bool,
+    },
+    NeighborSolicitations {
+        source: String,
+        targets: String,
+        #[serde(default)]
+        random_sources: bool,
+    },
+    Udp {
+        source: String,
+        dest: String,
+        dest_ether: String,
+        #[serde(default = "default_port")]
+        src_port: u16,
+        #[serde(default = "default_port")]
+        dest_port: u16,
+        /// Frame sizes to pick from; the largest a tap can take when empty.
+        #[serde(default)]
+        sizes: Vec<Size>,
+    },
+}
+
+fn default_port() -> u16 {
+    // Discard.
+    9
+}
+
+/// An ether frame size, without the FCS, and how often to pick it relative to the others.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Size {
+    pub size: usize,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// The first address of a prefix in `address/length` form, and how many addresses it holds.
+fn prefix(prefix: &str, address_bits: u32) -> AHResult<(&str, u128)> {
+    let (address, length) = prefix
+        .split_once('/')
+        .ok_or_else(|| anyhow!("prefix {} is missing a length", prefix))?;
+    let length: u32 = length.parse()?;
+    if length > address_bits {
+        bail!("prefix length {} is longer than an address", length);
+    }
+
+    Ok((
+        address,
+        1u128.checked_shl(address_bits - length).unwrap_or(0),
+    ))
+}
+
+/// Addresses walked in order through a prefix.
+#[derive(Debug)]
+struct Targets {
+    first: u128,
+    /// Zero for the whole address space.
+    len: u128,
+}
+
+impl Targets {
+    fn ipv4(text: &str) -> AHResult<Self> {
+        let (address, len) = prefix(text, 32)?;
+        let address: ipv4::Address = address.parse()?;
+
+        Ok(Self {
+            first: u32::from_be_bytes(address.0) as u128 & !(len - 1),
+            len,
+        })
+    }
+
+    fn ipv6(text: &str) -> AHResult<Self> {
+        let (address, len) = prefix(text, 128)?;
+        let address: ipv6::Address = address.parse()?;
+
+        Ok(Self {
+            first: u128::from(address) & !len.wrapping_sub(1),
+            len,
+        })
+    }
+
+    fn nth(&self, n: u64) -> u128 {
+        match self.len {
+            0 => self.first.wrapping_add(n as u128),
+            len => self.first + n as u128 % len,
+        }
+    }
+}
+
+/// A stream ready to build frames from.
+#[derive(Debug)]
+enum Template {
+    ArpRequest {
+        sender: ipv4::Address,
+        targets: Targets,
+        random_sources: bool,
+    },
+    NeighborSolicitation {
+        source: ipv6::Address,
+        targets: Targets,
+        random_sources: bool,
+    },
+    Udp {
+        source: ipv6::Address,
+        dest: ipv6::Address,
+        dest_ether: ether::Address,
+        src_port: u16,
+        dest_port: u16,
+        sizes: Vec<Size>,
+    },
+}
+
+impl Template {
+    fn new(kind: &Kind) -> AHResult<Self> {
+        Ok(match kind {
+            Kind::ArpRequests {
+                sender,
+                targets,
+                random_sources,
+            } => Self::ArpRequest {
+                sender: sender.parse()?,
+                targets: Targets::ipv4(targets)?,
+                random_sources: *random_sources,
+            },
+            Kind::NeighborSolicitations {
+                source,
+                targets,
+                random_sources,
+            } => Self::NeighborSolicitation {
+                source: source.parse()?,
+                targets: Targets::ipv6(targets)?,
+                random_sources: *random_sources,
+            },
+            Kind::Udp {
+                source,
+                dest,
+                dest_ether,
+                src_port,
+                dest_port,
+                sizes,
+            } => {
+                for size in sizes {
+                    if !(UDP_OVERHEAD..=MAX_FRAME_SIZE).contains(&size.size) {
+                        bail!(
+                            "frame size {} isn't between {} and {}",
+                            size.size,
+                            UDP_OVERHEAD,
+                            MAX_FRAME_SIZE
+                        );
+                    }
+                }
+                if !sizes.is_empty() && sizes.iter().all(|s| s.weight == 0) {
+                    bail!("at least one frame size needs a weight");
+                }
+
+                Self::Udp {
+                    source: source.parse()?,
+                    dest: dest.parse()?,
+                    dest_ether: dest_ether.parse()?,
+                    src_port: *src_port,
+                    dest_port: *dest_port,
+                    sizes: sizes.clone(),
+                }
+            }
+        })
+    }
+
+    /// The `n`th frame of the stream.
+    fn frame(&self, n: u64, hw_address: ether::Address, rng: &mut impl Rng) -> ether::Frame {
+        let source_for = |random: bool, rng: &mut _| {
+            if random {
+                ether::Address::random_local(rng)
+            } else {
+                hw_address
+            }
+        };
+
+        match self {
+            Self::ArpRequest {
+                sender,
+                targets,
+                random_sources,
+            } => {
+                let src = source_for(*random_sources, rng);
+                let target = ipv4::Address((targets.nth(n) as u32).to_be_bytes());
+
+                ether::Frame {
+                    dest: ether::Address([0xff; 6]),
+                    src,
+                    ethertype: ether::Type::Arp,
+                    payload: arp::Packet {
+                        opcode: arp::PacketOpcode::Request,
+                        src_ether: src,
+                        src_ipv4: *sender,
+                        dest_ether: ether::Address([0; 6]),
+                        dest_ipv4: target,
+                    }
+                    .encode(),
+                    received: None,
+                }
+            }
+            Self::NeighborSolicitation {
+                source,
+                targets,
+                random_sources,
+            } => {
+                let src = source_for(*random_sources, rng);
+                let target = ipv6::Address::from(targets.nth(n));
+                let dest = target.solicited_nodes_multicast();
+                // Solicitations from the unspecified address mustn't say where to answer.
+                // Ref: https://datatracker.ietf.org/doc/html/rfc4861#section-4.3
+                let options = if *source == ipv6::Address::default() {
+                    vec![]
+                } else {
+                    vec![icmpv6::NeighborSolicitationOption::SourceLinkLayerAddress(
+                        src,
+                    )]
+                };
+                let payload = icmpv6::Packet::NeighborSolicitation(icmpv6::NeighborSolicitation {
+                    dest: target,
+                    options,
+                })
+                .encode(ipv6::PseudoHeader {
+                    src: *source,
+                    dest,
+                    length: 0,
+                });
+
+                ether::Frame {
+                    dest: dest.multicast_ether_dest(),
+                    src,
+                    ethertype: ether::Type::Ipv6,
+                    payload: ipv6::Packet::builder()
+                        .protocol(ipv4::ProtocolNumber::Ipv6Icmp)
+                        .hop_limit(255)
+                        .src(*source)
+                        .dest(dest)
+                        .payload(payload)
+                        .build()
+                        .encode(),
+                    received: None,
+                }
+            }
+            Self::Udp {
+                source,
+                dest,
+                dest_ether,
+                src_port,
+                dest_port,
+                sizes,
+            } => {
+                let size = pick_size(sizes, rng);
+                let payload = udp::Packet {
+                    src_port: *src_port,
+                    dest_port: *dest_port,
+                    payload: vec![0; size - UDP_OVERHEAD],
+                }
+                .encode(ipv6::PseudoHeader {
+                    src: *source,
+                    dest: *dest,
+                    length: 0,
+                });
+
+                ether::Frame {
+                    dest: *dest_ether,
+                    src: hw_address,
+                    ethertype: ether::Type::Ipv6,
+                    payload: ipv6::Packet::builder()
+                        .protocol(ipv4::ProtocolNumber::Udp)
+                        .hop_limit(64)
+                        .src(*source)
+                        .dest(*dest)
+                        .payload(payload)
+                        .build()
+                        .encode(),
+                    received: None,
+                }
+            }
+        }
+    }
+}
+
+fn pick_size(sizes: &[Size], rng: &mut impl Rng) -> usize {
+    let total: u32 = sizes.iter().map(|s| s.weight).sum();
+    if total == 0 {
+        return MAX_FRAME_SIZE;
+    }
+
+    let mut pick = rng.gen_range(0..total);
+    for size in sizes {
+        if pick < size.weight {
+            return size.size;
+        }
+        pick -= size.weight;
+    }
+
+    unreachable!()
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StreamReport {
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    /// Sends that failed, e.g. because the tap was full.
+    pub errors: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub elapsed_secs: f64,
+    pub streams: Vec<StreamReport>,
+}
+
+struct Scheduled {
+    template: Template,
+    interval: Duration,
+    remaining: Option<u64>,
+    next: Instant,
+    sent: u64,
+    report: StreamReport,
+}
+
+/// Paces every stream from one thread, sending each frame when it's due.
+pub struct Generator {
+    streams: Vec<Scheduled>,
+    duration: Option<Duration>,
+}
+
+impl Generator {
+    pub fn new(config: &Config) -> AHResult<Self> {
+        if let Some(duration) = config.duration {
+            if !(duration.is_finite() && duration >= 0.0) {
+                bail!("duration must be 0 or more seconds, not {}", duration);
+            }
+        }
+
+        let streams = config
+            .streams
+            .iter()
+            .enumerate()
+            .map(|(i, stream)| {
+                if !(stream.rate.is_finite() && stream.rate > 0.0) {
+                    bail!("stream {}: rate must be positive", i);
+                }
+
+                Ok(Scheduled {
+                    template: Template::new(&stream.kind)
+                        .with_context(|| format!("stream {}", i))?,
+                    interval: Duration::from_secs_f64(1.0 / stream.rate),
+                    remaining: stream.count,
+                    // Due as soon as `run()` starts.
+                    next: Instant::now(),
+                    sent: 0,
+                    report: StreamReport::default(),
+                })
+            })
+            .collect::<AHResult<_>>()?;
+
+        Ok(Self {
+            streams,
+            duration: config.duration.map(Duration::from_secs_f64),
+        })
+    }
+
+    /// Send frames from `hw_address` with `send` until the duration passes, every stream runs
+    /// out, or `stop` is set.
+    pub fn run(
+        mut self,
+        hw_address: ether::Address,
+        stop: &AtomicBool,
+        mut send: impl FnMut(&ether::Frame) -> AHResult<()>,
+    ) -> Report {
+        let mut rng = rand::thread_rng();
+        let start = Instant::now();
+        let end = self.duration.map(|d| start + d);
+        for stream in &mut self.streams {
+            stream.next = start;
+        }
+
+        while !stop.load(Ordering::Relaxed) {
+            let stream = match self
+                .streams
+                .iter_mut()
+                .filter(|s| s.remaining != Some(0))
+                .min_by_key(|s| s.next)
+            {
+                Some(stream) => stream,
+                None => break,
+            };
+            if end.is_some_and(|end| stream.next >= end) {
+                break;
+            }
+
+            let now = Instant::now();
+            if stream.next > now {
+                thread::sleep(stream.next - now);
+            }
+
+            let frame = stream.template.frame(stream.sent, hw_address, &mut rng);
+            match send(&frame) {
+                Ok(()) => {
+                    stream.report.frames_sent += 1;
+                    stream.report.bytes_sent += frame.encode().len() as u64;
+                }
+                Err(_) => stream.report.errors += 1,
+            }
+            stream.sent += 1;
+            stream.remaining = stream.remaining.map(|r| r - 1);
+            // From the schedule rather than now, so the rate holds when sends are slow.
+            stream.next += stream.interval;
+        }
+
+        Report {
+            elapsed_secs: start.elapsed().as_secs_f64(),
+            streams: self.streams.into_iter().map(|s| s.report).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hw_address() -> ether::Address {
+        "02:00:00:00:00:01".parse().unwrap()
+    }
+
+    #[test]
+    fn targets_walk_the_prefix() {
+        let template = Template::ArpRequest {
+            sender: "192.0.2.1".parse().unwrap(),
+            targets: Targets::ipv4("192.0.2.77/30").unwrap(),
+            random_sources: false,
+        };
+        let targets: Vec<_> = (0..5)
+            .map(|n| {
+                let frame = template.frame(n, hw_address(), &mut rand::thread_rng());
+                arp::packet(&frame.payload).unwrap().dest_ipv4
+            })
+            .collect();
+
+        assert_eq!(
+            targets,
+            [
+                "192.0.2.76",
+                "192.0.2.77",
+                "192.0.2.78",
+                "192.0.2.79",
+                "192.0.2.76"
+            ]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect::<Vec<ipv4::Address>>()
+        );
+        assert_eq!(Targets::ipv6("::/0").unwrap().nth(3), 3);
+    }
+
+    #[test]
+    fn solicitations_and_udp_are_valid() {
+        let mut rng = rand::thread_rng();
+        let solicitation = Template::NeighborSolicitation {
+            source: "fe80::1".parse().unwrap(),
+            targets: Targets::ipv6("2001:db8::/64").unwrap(),
+            random_sources: true,
+        }
+        .frame(5, hw_address(), &mut rng);
+        let packet = ipv6::packet(&solicitation.payload).unwrap();
+        assert_eq!(packet.dest, "ff02::1:ff00:5".parse().unwrap());
+        assert_ne!(solicitation.src, hw_address());
+
+        let config: Config = toml::from_str(
+            r#"
+                [[streams]]
+                type = "udp"
+                rate = 10
+                source = "2001:db8::1"
+                dest = "2001:db8::2"
+                dest_ether = "02:00:00:00:00:02"
+                sizes = [{ size = 100 }]
+            "#,
+        )
+        .unwrap();
+        let template = Template::new(&config.streams[0].kind).unwrap();
+        assert_eq!(
+            template.frame(0, hw_address(), &mut rng).encode().len(),
+            100
+        );
+    }
+
+    #[test]
+    fn streams_are_paced_and_counted() {
+        let config: Config = toml::from_str(
+            r#"
+                [[streams]]
+                type = "arp_requests"
+                rate = 200
+                count = 10
+                sender = "192.0.2.1"
+                targets = "192.0.2.0/24"
+            "#,
+        )
+        .unwrap();
+
+        let mut sent = 0;
+        let report =
+            Generator::new(&config)
+                .unwrap()
+                .run(hw_address(), &AtomicBool::new(false), |_| {
+                    sent += 1;
+                    Ok(())
+                });
+
+        assert_eq!(sent, 10);
+        assert_eq!(report.streams[0].frames_sent, 10);
+        assert!(report.elapsed_secs >= 0.045);
+    }
+
+    #[test]
+    fn negative_durations_are_rejected() {
+        let config: Config = toml::from_str(
+            r#"
+                duration = -1.0
+
+                [[streams]]
+                type = "arp_requests"
+                rate = 200
+                sender = "192.0.2.1"
+                targets = "192.0.2.0/24"
+            "#,
+        )
+        .unwrap();
+
+        assert!(Generator::new(&config).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Noticing threads that have stopped making progress, deadlocked or dead, which otherwise goes
+//! unseen while the rest of the node carries on without them.
+//!
+//! Threads register for a `Heartbeat` and beat it at least every `INTERVAL`, even when idle; the
+//! watchdog reports those that stop in status.
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{status, warn};
+
+/// How often registered threads beat.
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Exit status when exiting after a thread fails, so a supervisor can tell why.
+pub const EXIT_STATUS: i32 = 70;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Healthy,
+    /// Hasn't beaten within the timeout; it might be stuck or just very busy.
+    Unresponsive,
+    Panicked,
+}
+
+#[derive(Default)]
+struct Beats {
+    /// Milliseconds since `EPOCH`.
+    last: AtomicU64,
+    panicked: AtomicBool,
+}
+
+lazy_static! {
+    static ref EPOCH: Instant = Instant::now();
+    static ref THREADS: Mutex<BTreeMap<String, Arc<Beats>>> = Mutex::new(BTreeMap::new());
+}
+
+fn now_millis() -> u64 {
+    EPOCH.elapsed().as_millis() as u64
+}
+
+/// A thread's promise to beat regularly; held by the thread, so it's dropped if the thread dies.
+pub struct Heartbeat {
+    name: String,
+    beats: Arc<Beats>,
+}
+
+/// Start watching the calling thread as `name`, replacing anything watched under that name.
+pub fn register(name: &str) -> Heartbeat {
+    let beats = Arc::new(Beats::default());
+    beats.last.store(now_millis(), Ordering::Relaxed);
+    THREADS
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), Arc::clone(&beats));
+
+    Heartbeat {
+        name: name.to_string(),
+        beats,
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.beats.last.store(now_millis(), Ordering::Relaxed);
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.beats.panicked.store(true, Ordering::Relaxed);
+            return;
+        }
+
+        // Finishing normally isn't a failure, so it's no longer watched.
+        let mut threads = THREADS.lock().unwrap();
+        if threads
+            .get(&self.name)
+            .is_some_and(|beats| Arc::ptr_eq(beats, &self.beats))
+        {
+            threads.remove(&self.name);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    /// Seconds without a beat before a thread is reported unresponsive.
+    #[serde(default = "default_timeout")]
+    pub timeout: f64,
+    /// Exit when a thread fails, so a process supervisor can restart the whole node.
+    #[serde(default)]
+    pub exit_on_failure: bool,
+}
+
+fn default_timeout() -> f64 {
+    10.0
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout: default_timeout(),
+            exit_on_failure: false,
+        }
+    }
+}
+
+fn health(beats: &Beats, now: u64, timeout: Duration) -> Health {
+    if beats.panicked.load(Ordering::Relaxed) {
+        Health::Panicked
+    } else if now.saturating_sub(beats.last.load(Ordering::Relaxed)) > timeout.as_millis() as u64 {
+        Health::Unresponsive
+    } else {
+        Health::Healthy
+    }
+}
+
+/// Every thread being watched, and how it's doing.
+pub fn snapshot(timeout: Duration) -> BTreeMap<String, Health> {
+    let now = now_millis();
+
+    THREADS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, beats)| (name.clone(), health(beats, now, timeout)))
+        .collect()
+}
+
+/// Check on watched threads in the background, updating status when any of them changes.
+pub fn start(config: Config) {
+    let timeout = Duration::from_secs_f64(config.timeout);
+
+    thread::spawn(move || {
+        let mut last = BTreeMap::new();
+
+        loop {
+            thread::sleep(INTERVAL);
+
+            let threads = snapshot(timeout);
+            if threads == last {
+                continue;
+            }
+
+            let mut failed = false;
+            for (name, health) in &threads {
+                if *health != Health::Healthy && last.get(name) != Some(health) {
+                    warn!("thread {} is {:?}", name, health);
+                    failed = true;
+                }
+            }
+            status::update(|s| s.threads = threads.clone());
+            last = threads;
+
+            if failed && config.exit_on_failure {
+                warn!("exiting because a thread failed");
+                status::drain();
+                std::process::exit(EXIT_STATUS);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silence_is_unresponsive() {
+        let beats = Beats::default();
+        beats.last.store(1000, Ordering::Relaxed);
+        let timeout = Duration::from_secs(2);
+
+        assert_eq!(health(&beats, 3000, timeout), Health::Healthy);
+        assert_eq!(health(&beats, 3001, timeout), Health::Unresponsive);
+
+        beats.panicked.store(true, Ordering::Relaxed);
+        assert_eq!(health(&beats, 1000, timeout), Health::Panicked);
+    }
+
+    #[test]
+    fn heartbeats_dropped_by_panics_are_kept() {
+        let finished = thread::spawn(|| register("watchdog-test-finished").beat());
+        finished.join().unwrap();
+
+        let panicked = thread::spawn(|| {
+            let _heartbeat = register("watchdog-test-panicked");
+            panic!("deliberately");
+        });
+        assert!(panicked.join().is_err());
+
+        let threads = snapshot(Duration::from_secs(60));
+        assert_eq!(threads.get("watchdog-test-finished"), None);
+        assert_eq!(
+            threads.get("watchdog-test-panicked"),
+            Some(&Health::Panicked)
+        );
+    }
+}